@@ -24,4 +24,17 @@ struct Tunables {
     5: optional map<string, map<string, i64> (rust.type = "HashMap")> (rust.type = "HashMap") ints_by_repo,
     6: optional map<string, map<string, string> (rust.type = "HashMap")> (rust.type = "HashMap") strings_by_repo,
     7: optional map<string, map<string, list<string>> (rust.type = "HashMap")> (rust.type = "HashMap") vec_of_strings_by_repo,
+    8: optional map<string, double> (rust.type = "HashMap") floats,
+    // Durations are strings with an explicit unit, e.g. "500ms" or "30s".
+    9: optional map<string, string> (rust.type = "HashMap") durations,
+    // Same format as `durations`, by repo.
+    10: optional map<string, map<string, string> (rust.type = "HashMap")> (rust.type = "HashMap") durations_by_repo,
+    // Byte sizes are strings with an explicit unit, e.g. "512KB" or "2GiB".
+    11: optional map<string, map<string, string> (rust.type = "HashMap")> (rust.type = "HashMap") byte_sizes_by_repo,
+    // Values that only apply on hosts whose whole hostname matches the regex
+    // key. They take precedence over the base values and `by_tier`.
+    12: optional map<string, Tunables> (rust.type = "HashMap") by_host_regex,
+    // Values that only apply on the tier named by the key. They take
+    // precedence over the base values.
+    13: optional map<string, Tunables> (rust.type = "HashMap") by_tier,
 } (rust.exhaustive)
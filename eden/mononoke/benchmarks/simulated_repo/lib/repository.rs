@@ -24,7 +24,9 @@ use bonsai_svnrev_mapping::{
 use bookmarks::{ArcBookmarkUpdateLog, ArcBookmarks};
 use cacheblob::{dummy::DummyLease, new_cachelib_blobstore, CachelibBlobstoreOptions};
 use changeset_fetcher::{ArcChangesetFetcher, SimpleChangesetFetcher};
-use changesets::{ArcChangesets, ChangesetEntry, ChangesetInsert, Changesets, SortOrder};
+use changesets::{
+    ArcChangesets, ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
 use changesets_impl::{CachingChangesets, SqlChangesetsBuilder};
 use context::CoreContext;
 use dbbookmarks::{ArcSqlBookmarks, SqlBookmarksBuilder};
@@ -414,10 +416,40 @@ impl<C: Changesets> Changesets for DelayedChangesets<C> {
         self.inner.get_many_by_prefix(ctx, cs_prefix, limit).await
     }
 
+    async fn get_many_with_generation_bounds(
+        &self,
+        ctx: CoreContext,
+        min_gen: u64,
+        max_gen: u64,
+        limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        delay(self.get_dist).await;
+        self.inner
+            .get_many_with_generation_bounds(ctx, min_gen, max_gen, limit)
+            .await
+    }
+
+    async fn get_children(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        delay(self.get_dist).await;
+        self.inner.get_children(ctx, cs_id).await
+    }
+
     fn prime_cache(&self, ctx: &CoreContext, changesets: &[ChangesetEntry]) {
         self.inner.prime_cache(ctx, changesets)
     }
 
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        self.inner.get_sequence(ctx, cs_id).await
+    }
+
     async fn enumeration_bounds(
         &self,
         ctx: &CoreContext,
@@ -34,7 +34,7 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use slog::Logger;
 use sql_construct::SqlConstructFromDatabaseConfig;
 use sql_ext::facebook::MysqlOptions;
-use sqlblob::{CountedSqlblob, Sqlblob};
+use sqlblob::{CountedSqlblob, Sqlblob, SqlblobOptions};
 use std::num::{NonZeroU64, NonZeroUsize};
 use std::sync::Arc;
 use std::time::Duration;
@@ -54,6 +54,7 @@ pub struct BlobstoreOptions {
     pub put_behaviour: PutBehaviour,
     pub scrub_options: Option<ScrubOptions>,
     pub sqlblob_mysql_options: MysqlOptions,
+    pub sqlblob_options: SqlblobOptions,
 }
 
 impl BlobstoreOptions {
@@ -80,6 +81,14 @@ impl BlobstoreOptions {
             // These are added via the builder methods
             scrub_options: None,
             sqlblob_mysql_options,
+            sqlblob_options: SqlblobOptions::default(),
+        }
+    }
+
+    pub fn with_sqlblob_options(self, sqlblob_options: SqlblobOptions) -> Self {
+        Self {
+            sqlblob_options,
+            ..self
         }
     }
 
@@ -186,6 +195,7 @@ pub async fn make_sql_blobstore<'a>(
             readonly_storage.0,
             blobstore_options.put_behaviour,
             config_store,
+            blobstore_options.sqlblob_options.clone(),
         )
         .context(ErrorKind::StateOpen),
         Mysql { remote } => {
@@ -230,6 +240,7 @@ pub async fn make_sql_blobstore_xdb<'a>(
                 readonly_storage.0,
                 put_behaviour,
                 config_store,
+                blobstore_options.sqlblob_options.clone(),
             )
             .await
         }
@@ -242,6 +253,7 @@ pub async fn make_sql_blobstore_xdb<'a>(
                 readonly_storage.0,
                 put_behaviour,
                 config_store,
+                blobstore_options.sqlblob_options.clone(),
             )
             .await
         }
@@ -11,6 +11,9 @@ CREATE TABLE IF NOT EXISTS `data` (
   `chunk_id` VARCHAR(255) NOT NULL,
   `chunk_count` INT UNSIGNED NOT NULL,
   `chunking_method` INT UNSIGNED NOT NULL,
+  `expiry_time` BIGINT NULL,
+  `value_size` BIGINT NULL,
+  `checksum` BIGINT NULL,
   PRIMARY KEY (`id`)
 );
 
@@ -27,3 +30,10 @@ CREATE TABLE IF NOT EXISTS `chunk_generation` (
     `last_seen_generation` BIGINT UNSIGNED NOT NULL,
     PRIMARY KEY (`id`)
 );
+
+CREATE TABLE IF NOT EXISTS `key_prefix_usage` (
+    `key_prefix` VARCHAR(255) NOT NULL,
+    `blob_count` BIGINT NOT NULL,
+    `total_bytes` BIGINT NOT NULL,
+    PRIMARY KEY (`key_prefix`)
+);
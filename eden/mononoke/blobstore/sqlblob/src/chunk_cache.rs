@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Cache of decoded chunks, consulted before the chunk table is queried.
+//!
+//! A chunk id is the content hash of the blob it belongs to, so a chunk
+//! never changes once written and cached entries never need invalidating.
+//! Chunks are cached as they are read, and as they are written, including
+//! when a corrupt chunk is repaired.
+
+use std::sync::Mutex;
+
+use bytes::{Bytes, BytesMut};
+#[cfg(fbcode_build)]
+use cachelib::VolatileLruCachePool;
+use linked_hash_map::LinkedHashMap;
+
+/// Where decoded chunks are cached.
+#[derive(Clone)]
+pub enum ChunkCacheOptions {
+    Disabled,
+    /// Keep up to `max_bytes` of chunks in process memory, evicting the least
+    /// recently used chunks first.
+    InMemory {
+        max_bytes: usize,
+    },
+    /// Keep chunks in a cachelib pool, which does its own eviction.
+    #[cfg(fbcode_build)]
+    Cachelib(VolatileLruCachePool),
+}
+
+impl Default for ChunkCacheOptions {
+    fn default() -> Self {
+        ChunkCacheOptions::Disabled
+    }
+}
+
+impl std::fmt::Debug for ChunkCacheOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkCacheOptions::Disabled => write!(f, "Disabled"),
+            ChunkCacheOptions::InMemory { max_bytes } => f
+                .debug_struct("InMemory")
+                .field("max_bytes", max_bytes)
+                .finish(),
+            #[cfg(fbcode_build)]
+            ChunkCacheOptions::Cachelib(_) => write!(f, "Cachelib"),
+        }
+    }
+}
+
+pub(crate) enum ChunkCache {
+    InMemory(Mutex<InMemoryChunkCache>),
+    #[cfg(fbcode_build)]
+    Cachelib(VolatileLruCachePool),
+}
+
+impl ChunkCache {
+    pub(crate) fn new(options: ChunkCacheOptions) -> Option<Self> {
+        match options {
+            ChunkCacheOptions::Disabled => None,
+            ChunkCacheOptions::InMemory { max_bytes } => Some(ChunkCache::InMemory(Mutex::new(
+                InMemoryChunkCache::new(max_bytes),
+            ))),
+            #[cfg(fbcode_build)]
+            ChunkCacheOptions::Cachelib(pool) => Some(ChunkCache::Cachelib(pool)),
+        }
+    }
+
+    // The cache is best effort: cachelib errors are treated as misses, and
+    // the chunk is fetched from the database instead.
+    pub(crate) fn get(&self, id: &str, chunk_num: u32) -> Option<BytesMut> {
+        let key = cache_key(id, chunk_num);
+        let value = match self {
+            ChunkCache::InMemory(cache) => cache.lock().expect("lock poisoned").get(&key),
+            #[cfg(fbcode_build)]
+            ChunkCache::Cachelib(pool) => pool.get(&key).ok().flatten(),
+        };
+        value.map(|value| (&*value).into())
+    }
+
+    pub(crate) fn set(&self, id: &str, chunk_num: u32, value: &[u8]) {
+        let key = cache_key(id, chunk_num);
+        match self {
+            ChunkCache::InMemory(cache) => cache
+                .lock()
+                .expect("lock poisoned")
+                .set(key, Bytes::copy_from_slice(value)),
+            #[cfg(fbcode_build)]
+            ChunkCache::Cachelib(pool) => {
+                let _ = pool.set(&key, Bytes::copy_from_slice(value));
+            }
+        }
+    }
+}
+
+fn cache_key(id: &str, chunk_num: u32) -> String {
+    format!("sqlblob.chunk.{}.{}", id, chunk_num)
+}
+
+pub(crate) struct InMemoryChunkCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    entries: LinkedHashMap<String, Bytes>,
+}
+
+impl InMemoryChunkCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            entries: LinkedHashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Bytes> {
+        self.entries.get_refresh(key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: Bytes) {
+        if value.len() > self.max_bytes {
+            return;
+        }
+        self.used_bytes += value.len();
+        if let Some(old) = self.entries.insert(key, value) {
+            self.used_bytes -= old.len();
+        }
+        while self.used_bytes > self.max_bytes {
+            match self.entries.pop_front() {
+                Some((_, evicted)) => self.used_bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Encoding of chunks stored with `ChunkingMethod::ByContentHashBlake2WithCodec`.
+//!
+//! Each such chunk starts with a one byte header naming the codec used for the
+//! rest of the chunk. Chunks stored with the older chunking methods have no
+//! header and are always raw.
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use tunables::tunables;
+use zstd::block::{Compressor, Decompressor};
+
+const CODEC_RAW: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+// zstd::block needs an upper bound on the decompressed size. Chunks are at
+// most CHUNK_SIZE, so leave plenty of room for that.
+const MAX_DECOMPRESSED_CHUNK_SIZE: usize = 4 * crate::CHUNK_SIZE;
+
+/// How chunks are compressed when they are put.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCompression {
+    /// Store chunks uncompressed, without a codec header. Readers that
+    /// predate compression can read these.
+    Disabled,
+    /// Compress with zstd at the level given by the `zstd_compression_level`
+    /// tunable. A level of 0 disables compression.
+    FromTunable,
+    /// Compress with zstd at the given level.
+    Zstd(i32),
+}
+
+impl Default for ChunkCompression {
+    fn default() -> Self {
+        ChunkCompression::Disabled
+    }
+}
+
+impl ChunkCompression {
+    /// The zstd level to use for a put, or None if chunks should be stored
+    /// without a codec header.
+    pub(crate) fn zstd_level(&self) -> Option<i32> {
+        let level = match self {
+            ChunkCompression::Disabled => return None,
+            ChunkCompression::FromTunable => tunables().get_zstd_compression_level() as i32,
+            ChunkCompression::Zstd(level) => *level,
+        };
+        if level > 0 {
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
+
+/// Encode a chunk with a codec header. The chunk is compressed if a zstd level
+/// is given and compression makes it smaller.
+pub(crate) fn encode_chunk(zstd_level: Option<i32>, value: &[u8]) -> Result<Bytes> {
+    let compressed = match zstd_level {
+        Some(level) => Some(Compressor::new().compress(value, level)?),
+        None => None,
+    };
+    let (codec, payload) = match &compressed {
+        Some(compressed) if compressed.len() < value.len() => (CODEC_ZSTD, compressed.as_slice()),
+        _ => (CODEC_RAW, value),
+    };
+    let mut encoded = BytesMut::with_capacity(payload.len() + 1);
+    encoded.extend_from_slice(&[codec]);
+    encoded.extend_from_slice(payload);
+    Ok(encoded.freeze())
+}
+
+/// Decode a chunk written by `encode_chunk`.
+pub(crate) fn decode_chunk(encoded: &[u8]) -> Result<BytesMut> {
+    match encoded.split_first() {
+        Some((&CODEC_RAW, payload)) => Ok(payload.into()),
+        Some((&CODEC_ZSTD, payload)) => {
+            let decompressed =
+                Decompressor::new().decompress(payload, MAX_DECOMPRESSED_CHUNK_SIZE)?;
+            Ok(decompressed.as_slice().into())
+        }
+        Some((codec, _)) => bail!("Unknown sqlblob chunk codec {}", codec),
+        None => bail!("Sqlblob chunk is missing its codec header"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let compressible = vec![b'a'; 1024];
+        let encoded = encode_chunk(Some(3), &compressible)?;
+        assert_eq!(encoded[0], CODEC_ZSTD);
+        assert!(encoded.len() < compressible.len());
+        assert_eq!(decode_chunk(&encoded)?.as_ref(), compressible.as_slice());
+
+        // Tiny values grow when compressed, so they are stored raw.
+        let encoded = encode_chunk(Some(3), b"x")?;
+        assert_eq!(encoded.as_ref(), &[CODEC_RAW, b'x']);
+        assert_eq!(decode_chunk(&encoded)?.as_ref(), b"x");
+
+        let encoded = encode_chunk(None, &compressible)?;
+        assert_eq!(encoded[0], CODEC_RAW);
+        assert_eq!(decode_chunk(&encoded)?.as_ref(), compressible.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(decode_chunk(b"").is_err());
+        assert!(decode_chunk(&[42, 1, 2, 3]).is_err());
+    }
+}
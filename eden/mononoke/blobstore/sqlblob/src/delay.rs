@@ -8,6 +8,7 @@
 use std::{
     cmp::min,
     num::NonZeroUsize,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -16,6 +17,8 @@ use rand::{thread_rng, Rng};
 use stats::prelude::*;
 use tokio::sync::watch;
 
+use crate::throttle::{AdaptiveThrottle, AdaptiveThrottleConfig, ThrottleValues, WritePermit};
+
 // This can be tweaked later.
 pub(crate) const MAX_LAG: Duration = Duration::from_secs(5);
 
@@ -23,12 +26,15 @@ define_stats! {
     prefix = "mononoke.sqlblob.lag_delay";
     total_delay_ms: dynamic_timeseries("{}.total_delay_ms", (entity: String); Rate, Sum),
     raw_lag_ms: dynamic_timeseries("{}.raw_lag_ms", (entity: String); Rate, Sum),
+    throttle_concurrency: dynamic_timeseries("{}.{}.throttle_concurrency", (entity: String, shard: usize); Average),
+    throttle_put_delay_ms: dynamic_timeseries("{}.{}.throttle_put_delay_ms", (entity: String, shard: usize); Average),
 }
 
 #[derive(Clone)]
 pub struct BlobDelay {
     lag_receivers: Vec<watch::Receiver<Duration>>,
     entity: Option<String>,
+    throttle: Option<Arc<AdaptiveThrottle>>,
 }
 
 // Adds a small amount of random delay to desynchronise when waiting
@@ -50,19 +56,63 @@ impl BlobDelay {
         Self {
             lag_receivers,
             entity: None,
+            throttle: None,
         }
     }
 
-    #[cfg(fbcode_build)]
+    #[cfg(any(fbcode_build, test))]
     pub fn from_channels(lag_receivers: Vec<watch::Receiver<Duration>>, name: String) -> Self {
         let entity = Some(name);
         Self {
             lag_receivers,
             entity,
+            throttle: None,
+        }
+    }
+
+    /// Also adapt write concurrency and delays to the lag seen on each shard.
+    pub fn with_adaptive_throttle(self, config: AdaptiveThrottleConfig) -> Self {
+        let throttle = AdaptiveThrottle::new(config, &self.lag_receivers);
+        Self {
+            throttle: Some(Arc::new(throttle)),
+            ..self
+        }
+    }
+
+    /// The current adaptive throttle for a shard, if adaptive throttling is
+    /// enabled.
+    pub fn throttle_values(&self, shard_id: usize) -> Option<ThrottleValues> {
+        self.throttle
+            .as_ref()
+            .map(|throttle| throttle.values(shard_id))
+    }
+
+    /// Wait until a write to the shard may go ahead. The write should hold
+    /// on to the returned permit until it is done.
+    pub async fn delay(&self, shard_id: usize) -> WritePermit {
+        self.wait_for_lag(shard_id).await;
+        match &self.throttle {
+            Some(throttle) => {
+                let values = throttle.observe_lag(shard_id);
+                if let Some(entity) = &self.entity {
+                    STATS::throttle_concurrency
+                        .add_value(values.concurrency as i64, (entity.clone(), shard_id));
+                    STATS::throttle_put_delay_ms.add_value(
+                        values.put_delay.as_millis() as i64,
+                        (entity.clone(), shard_id),
+                    );
+                }
+                if values.put_delay > Duration::from_secs(0) {
+                    tokio::time::sleep(values.put_delay).await;
+                }
+                throttle.acquire(shard_id).await
+            }
+            None => WritePermit::unthrottled(),
         }
     }
 
-    pub async fn delay(&self, shard_id: usize) {
+    /// Wait for lag on the shard to drop below `MAX_LAG`.
+    async fn wait_for_lag(&self, shard_id: usize) {
         let mut lag_receiver =
             tokio_stream::wrappers::WatchStream::new(self.lag_receivers[shard_id].clone());
         let start_time = Instant::now();
@@ -0,0 +1,21 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use thiserror::Error;
+
+/// A blob read from a shard doesn't match the checksum stored with it, so
+/// the copy on the replica that served it is corrupt.
+#[derive(Debug, Error)]
+#[error(
+    "Sqlblob key {key} on shard {shard} is corrupt: checksum is {actual:#018x}, expected {expected:#018x}"
+)]
+pub struct SqlblobCorruption {
+    pub key: String,
+    pub shard: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Mark and sweep GC of chunks, driven by the generations in the XdbGc
+//! config. A cycle is:
+//!
+//! 1. Move `put_generation` and `mark_generation` on, keeping
+//!    `delete_generation`, so that `put > mark > delete + 1`.
+//! 2. `run_mark` every shard. This moves the chunks of every key up to the
+//!    mark generation; puts meanwhile move their chunks to the put generation.
+//! 3. Set `delete_generation` to `mark_generation - 1`.
+//! 4. `run_sweep` every shard, deleting the chunks that were not marked.
+//!
+//! Both steps check the config before they start and as they go, and refuse
+//! to run if it doesn't match the step.
+
+use anyhow::{ensure, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use xdb_gc_structs::XdbGc;
+
+use crate::Sqlblob;
+
+// Number of keys listed, and marked in parallel, at a time.
+const MARK_PAGE_SIZE: u64 = 1000;
+const MARK_CONCURRENCY: usize = 100;
+// Number of chunk ids deleted per statement.
+const SWEEP_BATCH_SIZE: u64 = 1000;
+
+/// How far marking a shard has got. Every key up to and including `last_key`
+/// has been marked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MarkReport {
+    pub last_key: Option<String>,
+    pub keys_marked: u64,
+}
+
+/// The outcome of sweeping a shard.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    /// Chunks at or below the delete generation when the sweep started, and
+    /// their total stored size.
+    pub reclaimable_chunks: u64,
+    pub reclaimable_bytes: u64,
+    /// Chunks deleted so far. Always zero for a dry run.
+    pub deleted_chunks: u64,
+}
+
+fn check_mark_allowed(gc: &XdbGc) -> Result<()> {
+    ensure!(
+        gc.put_generation > gc.mark_generation && gc.mark_generation > gc.delete_generation,
+        "GC generations must be ordered put > mark > delete, but are put {}, mark {}, delete {}",
+        gc.put_generation,
+        gc.mark_generation,
+        gc.delete_generation
+    );
+    Ok(())
+}
+
+fn check_sweep_allowed(gc: &XdbGc, delete_generation: u64) -> Result<()> {
+    check_mark_allowed(gc)?;
+    ensure!(
+        gc.delete_generation as u64 == delete_generation,
+        "Asked to sweep generation {}, but the delete generation is {}",
+        delete_generation,
+        gc.delete_generation
+    );
+    ensure!(
+        gc.mark_generation == gc.delete_generation + 1,
+        "Marking generation {} is in progress, and must finish before sweeping",
+        gc.mark_generation
+    );
+    Ok(())
+}
+
+/// Mark the chunks of every key in shard `shard`, calling `on_progress` each
+/// time a page of keys has been marked.
+///
+/// Chunks in the shard that have no generation yet, because they were put
+/// since they were last marked, are given the put generation first.
+pub async fn run_mark(
+    blobstore: &Sqlblob,
+    shard: usize,
+    mut on_progress: impl FnMut(&MarkReport),
+) -> Result<MarkReport> {
+    check_mark_allowed(&blobstore.chunk_store.gc_generations())?;
+    blobstore.chunk_store.set_initial_generation(shard).await?;
+
+    let mut report = MarkReport::default();
+    loop {
+        check_mark_allowed(&blobstore.chunk_store.gc_generations())?;
+        let after = report.last_key.as_deref().unwrap_or("");
+        let keys = blobstore
+            .data_store
+            .get_keys_page(shard, after, MARK_PAGE_SIZE)
+            .await?;
+        let last_key = match keys.last() {
+            Some(last_key) => last_key.clone(),
+            None => return Ok(report),
+        };
+        let marked = stream::iter(keys)
+            .map(|key| mark_key(blobstore, key))
+            .buffer_unordered(MARK_CONCURRENCY)
+            .try_fold(0, |marked, was_marked| async move {
+                Ok(marked + was_marked as u64)
+            })
+            .await?;
+
+        report.last_key = Some(last_key);
+        report.keys_marked += marked;
+        on_progress(&report);
+    }
+}
+
+/// Mark one key, returning false if it was removed before it was marked.
+async fn mark_key(blobstore: &Sqlblob, key: String) -> Result<bool> {
+    // Include expired keys, as they may have expired since they were listed.
+    let chunked = match blobstore.data_store.get_including_expired(&key).await? {
+        Some(chunked) => chunked,
+        None => return Ok(false),
+    };
+    for chunk_num in 0..chunked.count {
+        blobstore
+            .chunk_store
+            .set_generation(&chunked.id, chunk_num, chunked.chunking_method)
+            .await?;
+    }
+    Ok(true)
+}
+
+/// Delete the chunks in shard `shard` that are at or below `delete_generation`,
+/// which must be the configured delete generation, calling `on_progress` each
+/// time a batch has been deleted. With `dry_run`, only work out how much would
+/// be reclaimed.
+pub async fn run_sweep(
+    blobstore: &Sqlblob,
+    shard: usize,
+    delete_generation: u64,
+    dry_run: bool,
+    mut on_progress: impl FnMut(&SweepReport),
+) -> Result<SweepReport> {
+    check_sweep_allowed(&blobstore.chunk_store.gc_generations(), delete_generation)?;
+    let (reclaimable_chunks, reclaimable_bytes) = blobstore
+        .chunk_store
+        .get_sweepable_size(shard, delete_generation)
+        .await?;
+    let mut report = SweepReport {
+        reclaimable_chunks,
+        reclaimable_bytes,
+        deleted_chunks: 0,
+    };
+    on_progress(&report);
+    if dry_run {
+        return Ok(report);
+    }
+
+    loop {
+        check_sweep_allowed(&blobstore.chunk_store.gc_generations(), delete_generation)?;
+        let (found, deleted) = blobstore
+            .chunk_store
+            .sweep(shard, delete_generation, SWEEP_BATCH_SIZE)
+            .await?;
+        if found == 0 {
+            return Ok(report);
+        }
+        report.deleted_chunks += deleted;
+        on_progress(&report);
+    }
+}
@@ -7,25 +7,57 @@
 
 #![deny(warnings)]
 
+mod chunk_cache;
+mod codec;
 mod delay;
+mod errors;
 #[cfg(fbcode_build)]
 mod facebook;
+pub mod gc;
+mod metrics;
+pub mod migrate;
 #[cfg(not(fbcode_build))]
 mod myadmin_delay_dummy;
+mod recent_writes;
+mod replica;
+pub mod schema;
+mod scrub;
+mod sharding;
 mod store;
+mod telemetry;
 #[cfg(test)]
 mod tests;
+mod throttle;
 
+use crate::chunk_cache::ChunkCache;
+pub use crate::chunk_cache::ChunkCacheOptions;
+pub use crate::codec::ChunkCompression;
 use crate::delay::BlobDelay;
+pub use crate::errors::SqlblobCorruption;
 #[cfg(fbcode_build)]
 use crate::facebook::myadmin_delay;
+pub use crate::metrics::{ShardStats, SqlblobOperation, SqlblobStats};
 #[cfg(not(fbcode_build))]
 use crate::myadmin_delay_dummy as myadmin_delay;
-use crate::store::{ChunkSqlStore, ChunkingMethod, DataSqlStore};
+use crate::recent_writes::RecentWrites;
+pub use crate::recent_writes::RecentWritesOptions;
+pub use crate::replica::{CatchUpReport, SecondaryWriterOptions, SecondaryWriterStats};
+use crate::replica::{MirroredWrite, SecondaryWriter};
+pub use crate::scrub::ScrubReport;
+use crate::sharding::KeySharding;
+pub use crate::sharding::{ExplicitSharding, HashSharding, RangeSharding, ShardingStrategy};
+use crate::store::{
+    current_timestamp, value_checksum, ChunkSqlStore, Chunked, ChunkingMethod, DataSqlStore,
+    PutCondition,
+};
+pub use crate::store::{key_prefix, BlobMetadata, KeyPrefixUsage};
+pub use crate::telemetry::{SqlblobChunking, SqlblobEvent, SqlblobTelemetry};
+pub use crate::throttle::{AdaptiveThrottleConfig, ThrottleValues};
 use anyhow::{bail, format_err, Error, Result};
 use async_trait::async_trait;
 use blobstore::{
-    Blobstore, BlobstoreGetData, BlobstoreIsPresent, BlobstoreMetadata, BlobstorePutOps,
+    Blobstore, BlobstoreEnumerationData, BlobstoreGetData, BlobstoreIsPresent, BlobstoreKeyParam,
+    BlobstoreKeySource, BlobstoreKeyToken, BlobstoreMetadata, BlobstorePutOps, BlobstoreUnlinkOps,
     BlobstoreWithLink, CountedBlobstore, OverwriteStatus, PutBehaviour,
 };
 use bytes::{Bytes, BytesMut};
@@ -49,9 +81,10 @@ use std::{
     num::NonZeroUsize,
     path::PathBuf,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::task::spawn_blocking;
+use tunables::{rollout_hash, tunables};
 use xdb_gc_structs::XdbGc;
 
 // Leaving some space for metadata
@@ -68,14 +101,70 @@ const SQLBLOB_LABEL: &str = "blobstore";
 const UPDATE_FREQUENCY: Duration = Duration::from_millis(1);
 const INITIAL_VERSION: u64 = 0;
 
+// Number of keys returned by a single call to `enumerate`.
+const ENUMERATE_PAGE_SIZE: u64 = 10000;
+
 const COUNTED_ID: &str = "sqlblob";
 pub type CountedSqlblob = CountedBlobstore<Sqlblob>;
 
+/// Knobs for the behaviour of a `Sqlblob` that are chosen at construction.
+#[derive(Clone, Debug, Default)]
+pub struct SqlblobOptions {
+    pub chunk_compression: ChunkCompression,
+    /// Gets and puts slower than this are logged and counted.
+    pub slow_query_threshold: Option<Duration>,
+    pub chunk_cache: ChunkCacheOptions,
+    /// Adapt write concurrency and delays to replication lag, on top of the
+    /// hard stop at `MAX_LAG`.
+    pub adaptive_throttle: Option<AdaptiveThrottleConfig>,
+    /// Serve blobs written by this process from memory until replicas have
+    /// caught up, so that a get right after a put doesn't miss.
+    pub read_your_writes: Option<RecentWritesOptions>,
+    /// How keys are mapped to shards. Keys are hashed across all shards if
+    /// this is not set.
+    pub sharding: Option<Arc<dyn ShardingStrategy>>,
+    /// While keys are moved to the shards chosen by `sharding`, the strategy
+    /// they were written with. Keys missing from their shard are also looked
+    /// for on the shard this strategy picks.
+    pub previous_sharding: Option<Arc<dyn ShardingStrategy>>,
+    /// Receives an event for every put, get, link and unlink, on top of
+    /// the counters in `SqlblobStats`.
+    pub telemetry: Option<Arc<dyn SqlblobTelemetry>>,
+}
+
+impl SqlblobOptions {
+    fn throttle(&self, delay: BlobDelay) -> BlobDelay {
+        match &self.adaptive_throttle {
+            Some(config) => delay.with_adaptive_throttle(config.clone()),
+            None => delay,
+        }
+    }
+
+    fn key_sharding(&self, shard_count: NonZeroUsize) -> Result<KeySharding> {
+        KeySharding::new(
+            shard_count,
+            self.sharding.clone(),
+            self.previous_sharding.clone(),
+        )
+    }
+}
+
+/// How `Sqlblob::put_impl` decides whether to write a blob.
+#[derive(Clone, Copy, Debug)]
+enum PutMode {
+    Behaviour(PutBehaviour),
+    Conditional(PutCondition),
+}
+
 pub struct Sqlblob {
     data_store: Arc<DataSqlStore>,
     chunk_store: Arc<ChunkSqlStore>,
+    stats: Arc<SqlblobStats>,
     put_behaviour: PutBehaviour,
     allow_inline_put: bool,
+    secondary: Option<SecondaryWriter>,
+    recent_writes: Option<RecentWrites>,
+    telemetry: Option<Arc<dyn SqlblobTelemetry>>,
 }
 
 impl std::fmt::Display for Sqlblob {
@@ -90,6 +179,17 @@ fn get_gc_config_handle(config_store: &ConfigStore) -> Result<ConfigHandle<XdbGc
 
 const DEFAULT_ALLOW_INLINE_PUT: bool = true;
 
+/// The hash context used to derive chunk ids from blob contents.
+fn chunk_hash_context(chunking_method: ChunkingMethod) -> HashContext {
+    match chunking_method {
+        // Chunks with a codec header must not share ids with raw chunks of the
+        // same content, as readers rely on the chunking method to know whether
+        // there is a header.
+        ChunkingMethod::ByContentHashBlake2WithCodec => HashContext::new(b"sqlblob_codec"),
+        _ => HashContext::new(b"sqlblob"),
+    }
+}
+
 // base64 encoding for inline hash has an overhead
 pub const MAX_INLINE_LEN: usize = 255 * 3 / 4;
 
@@ -102,12 +202,14 @@ impl Sqlblob {
         readonly: bool,
         put_behaviour: PutBehaviour,
         config_store: &ConfigStore,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob, Error> {
         let delay = if readonly {
             BlobDelay::dummy(shard_num)
         } else {
             myadmin_delay::sharded(fb, shardmap.clone(), shard_num)?
         };
+        let delay = options.throttle(delay);
         let config_handle = get_gc_config_handle(config_store)?;
         let shard_count = shard_num.clone().get();
 
@@ -133,10 +235,11 @@ impl Sqlblob {
         let write_connections = Arc::new(write_connections);
         let read_connections = Arc::new(read_connections);
         let read_master_connections = Arc::new(read_master_connections);
+        let stats = Arc::new(SqlblobStats::new(shard_num, options.slow_query_threshold));
         Ok(Self::counted(
             Self {
                 data_store: Arc::new(DataSqlStore::new(
-                    shard_num,
+                    options.key_sharding(shard_num)?,
                     write_connections.clone(),
                     read_connections.clone(),
                     read_master_connections.clone(),
@@ -149,9 +252,16 @@ impl Sqlblob {
                     read_master_connections,
                     delay,
                     config_handle,
+                    options.chunk_compression,
+                    ChunkCache::new(options.chunk_cache),
+                    stats.clone(),
                 )),
+                stats,
                 put_behaviour,
                 allow_inline_put: DEFAULT_ALLOW_INLINE_PUT,
+                secondary: None,
+                recent_writes: options.read_your_writes.map(RecentWrites::new),
+                telemetry: options.telemetry,
             },
             shardmap,
         ))
@@ -164,6 +274,7 @@ impl Sqlblob {
         readonly: bool,
         put_behaviour: PutBehaviour,
         config_store: &ConfigStore,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob, Error> {
         let delay = if readonly {
             BlobDelay::dummy(SINGLE_SHARD_NUM)
@@ -187,6 +298,7 @@ impl Sqlblob {
             },
             config_store,
             DEFAULT_ALLOW_INLINE_PUT,
+            options,
         )
         .await
     }
@@ -199,12 +311,14 @@ impl Sqlblob {
         connection_factory: CF,
         config_store: &ConfigStore,
         allow_inline_put: bool,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob, Error>
     where
         CF: Fn(usize) -> SF,
         SF: Future<Output = Result<SqlConnections, Error>> + Sized,
     {
         let shard_count = shard_num.get();
+        let delay = options.throttle(delay);
 
         let config_handle = get_gc_config_handle(config_store)?;
 
@@ -228,10 +342,11 @@ impl Sqlblob {
         let read_connections = Arc::new(read_connections);
         let read_master_connections = Arc::new(read_master_connections);
 
+        let stats = Arc::new(SqlblobStats::new(shard_num, options.slow_query_threshold));
         Ok(Self::counted(
             Self {
                 data_store: Arc::new(DataSqlStore::new(
-                    shard_num,
+                    options.key_sharding(shard_num)?,
                     write_connections.clone(),
                     read_connections.clone(),
                     read_master_connections.clone(),
@@ -244,9 +359,16 @@ impl Sqlblob {
                     read_master_connections,
                     delay,
                     config_handle,
+                    options.chunk_compression,
+                    ChunkCache::new(options.chunk_cache),
+                    stats.clone(),
                 )),
+                stats,
                 put_behaviour,
                 allow_inline_put,
+                secondary: None,
+                recent_writes: options.read_your_writes.map(RecentWrites::new),
+                telemetry: options.telemetry,
             },
             label,
         ))
@@ -256,16 +378,18 @@ impl Sqlblob {
         put_behaviour: PutBehaviour,
         config_store: &ConfigStore,
         allow_inline_put: bool,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob> {
         Self::with_sqlite(
             put_behaviour,
             |_| {
                 let con = open_sqlite_in_memory()?;
-                con.execute_batch(Self::CREATION_QUERY)?;
+                schema::migrate_sqlite(&con)?;
                 Ok(con)
             },
             config_store,
             allow_inline_put,
+            options,
         )
     }
 
@@ -274,6 +398,7 @@ impl Sqlblob {
         readonly_storage: bool,
         put_behaviour: PutBehaviour,
         config_store: &ConfigStore,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob> {
         let pathbuf = path.into();
         Self::with_sqlite(
@@ -283,11 +408,14 @@ impl Sqlblob {
                     &pathbuf.join(format!("shard_{}.sqlite", shard_id)),
                     readonly_storage,
                 )?;
-                con.execute_batch(Self::CREATION_QUERY)?;
+                if !readonly_storage {
+                    schema::migrate_sqlite(&con)?;
+                }
                 Ok(con)
             },
             config_store,
             DEFAULT_ALLOW_INLINE_PUT,
+            options,
         )
     }
 
@@ -296,6 +424,7 @@ impl Sqlblob {
         mut constructor: F,
         config_store: &ConfigStore,
         allow_inline_put: bool,
+        options: SqlblobOptions,
     ) -> Result<CountedSqlblob>
     where
         F: FnMut(usize) -> Result<SqliteConnection>,
@@ -313,45 +442,126 @@ impl Sqlblob {
         let config_handle = get_gc_config_handle(config_store)
             .or_else(|_| get_gc_config_handle(&(get_test_config_store().1)))?;
 
+        let stats = Arc::new(SqlblobStats::new(
+            SQLITE_SHARD_NUM,
+            options.slow_query_threshold,
+        ));
+        let delay = options.throttle(BlobDelay::dummy(SQLITE_SHARD_NUM));
         Ok(Self::counted(
             Self {
                 data_store: Arc::new(DataSqlStore::new(
-                    SQLITE_SHARD_NUM,
+                    options.key_sharding(SQLITE_SHARD_NUM)?,
                     cons.clone(),
                     cons.clone(),
                     cons.clone(),
-                    BlobDelay::dummy(SQLITE_SHARD_NUM),
+                    delay.clone(),
                 )),
                 chunk_store: Arc::new(ChunkSqlStore::new(
                     SQLITE_SHARD_NUM,
                     cons.clone(),
                     cons.clone(),
                     cons,
-                    BlobDelay::dummy(SQLITE_SHARD_NUM),
+                    delay,
                     config_handle,
+                    options.chunk_compression,
+                    ChunkCache::new(options.chunk_cache),
+                    stats.clone(),
                 )),
+                stats,
                 put_behaviour,
                 allow_inline_put,
+                secondary: None,
+                recent_writes: options.read_your_writes.map(RecentWrites::new),
+                telemetry: options.telemetry,
             },
             "sqlite".into(),
         ))
     }
 
-    const CREATION_QUERY: &'static str = include_str!("../schema/sqlite-sqlblob.sql");
-
     fn counted(self, label: String) -> CountedBlobstore<Self> {
         CountedBlobstore::new(format!("{}.{}", COUNTED_ID, label), self)
     }
 
+    /// Per-shard metrics for this blobstore.
+    pub fn stats(&self) -> &SqlblobStats {
+        &self.stats
+    }
+
+    /// Mirror successful puts to `secondary`, usually a shardmap in another
+    /// region. Mirroring is asynchronous and best effort: use `catch_up` to
+    /// copy keys that it missed.
+    pub fn with_secondary(mut self, secondary: Sqlblob, options: SecondaryWriterOptions) -> Self {
+        self.secondary = Some(SecondaryWriter::new(secondary, options));
+        self
+    }
+
+    /// Counters of the secondary writer, if there is one.
+    pub fn secondary_stats(&self) -> Option<SecondaryWriterStats> {
+        self.secondary.as_ref().map(SecondaryWriter::stats)
+    }
+
+    /// Copy the keys of shard `shard_num` that are missing from the secondary.
+    pub async fn catch_up(&self, shard_num: usize) -> Result<CatchUpReport> {
+        match &self.secondary {
+            Some(secondary) => secondary.catch_up(self, shard_num).await,
+            None => bail!("Sqlblob has no secondary to catch up"),
+        }
+    }
+
     #[cfg(test)]
     pub(crate) fn get_data_store(&self) -> &DataSqlStore {
         &self.data_store
     }
 
+    /// The same blobstore, with keys sharded as `options` says.
+    #[cfg(test)]
+    pub(crate) fn with_sharding_options(&self, options: &SqlblobOptions) -> Result<Self> {
+        let sharding =
+            options.key_sharding(NonZeroUsize::new(self.data_store.shard_count()).unwrap())?;
+        Ok(Self {
+            data_store: Arc::new(self.data_store.with_sharding(sharding)),
+            chunk_store: self.chunk_store.clone(),
+            stats: self.stats.clone(),
+            put_behaviour: self.put_behaviour,
+            allow_inline_put: self.allow_inline_put,
+            secondary: None,
+            recent_writes: None,
+            telemetry: self.telemetry.clone(),
+        })
+    }
+
     pub fn get_keys_from_shard(&self, shard_num: usize) -> impl Stream<Item = Result<String>> {
         self.data_store.get_keys_from_shard(shard_num)
     }
 
+    /// The current adaptive write throttle for a shard, if enabled.
+    pub fn write_throttle(&self, shard_num: usize) -> Option<ThrottleValues> {
+        self.data_store.delay().throttle_values(shard_num)
+    }
+
+    /// Delete expired keys from a shard, returning how many were deleted.
+    pub async fn delete_expired(&self, shard_num: usize) -> Result<u64> {
+        self.data_store.delete_expired(shard_num).await
+    }
+
+    /// The storage used in a shard by each key prefix (see `key_prefix`),
+    /// as accounted on put and unlink. Blobs written before schema version 3
+    /// are not accounted.
+    pub async fn usage_by_prefix(&self, shard_num: usize) -> Result<Vec<KeyPrefixUsage>> {
+        self.data_store.usage_by_prefix(shard_num).await
+    }
+
+    /// Bring every shard up to `schema::SCHEMA_VERSION`, returning the
+    /// version each shard was at. SQLite shards are migrated when they are
+    /// opened; MySQL shards only when this is called.
+    pub async fn migrate_schema(&self) -> Result<Vec<u32>> {
+        let mut versions = Vec::with_capacity(self.data_store.shard_count());
+        for shard_num in 0..self.data_store.shard_count() {
+            versions.push(self.data_store.migrate_schema(shard_num).await?);
+        }
+        Ok(versions)
+    }
+
     pub async fn get_chunk_sizes_by_generation(
         &self,
         shard_num: usize,
@@ -382,7 +592,7 @@ impl Sqlblob {
     }
 
     pub async fn set_generation(&self, key: &str) -> Result<()> {
-        let chunked = self.data_store.get(key).await?;
+        let chunked = self.data_store.get_including_expired(key).await?;
         if let Some(chunked) = chunked {
             let set_chunk_generations: FuturesUnordered<_> = (0..chunked.count)
                 .map(|chunk_num| {
@@ -397,88 +607,147 @@ impl Sqlblob {
     }
 }
 
-impl fmt::Debug for Sqlblob {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Sqlblob").finish()
+impl Sqlblob {
+    /// Put a blob that expires after `ttl`. Once expired, the key is treated
+    /// as absent by reads, and its chunks are reclaimed by GC unless another
+    /// key refers to them.
+    pub async fn put_with_ttl(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        ttl: Duration,
+    ) -> Result<OverwriteStatus> {
+        let ttl: i64 = ttl.as_secs().try_into()?;
+        let expiry = current_timestamp().saturating_add(ttl);
+        let mode = PutMode::Behaviour(self.put_behaviour);
+        self.put_impl(ctx, key, value, mode, Some(expiry)).await
     }
-}
 
-#[async_trait]
-impl Blobstore for Sqlblob {
-    async fn get<'a>(
-        &'a self,
-        _ctx: &'a CoreContext,
-        key: &'a str,
-    ) -> Result<Option<BlobstoreGetData>> {
-        let chunked = self.data_store.get(&key).await?;
-        if let Some(chunked) = chunked {
-            let blob = match chunked.chunking_method {
-                ChunkingMethod::InlineBase64 => {
-                    let decoded = base64::decode_config(&chunked.id, base64::STANDARD_NO_PAD)?;
-                    Bytes::copy_from_slice(decoded.as_ref())
-                }
-                ChunkingMethod::ByContentHashBlake2 => {
-                    let chunks = (0..chunked.count)
-                        .map(|chunk_num| {
-                            self.chunk_store
-                                .get(&chunked.id, chunk_num, chunked.chunking_method)
-                        })
-                        .collect::<FuturesOrdered<_>>()
-                        .try_collect::<Vec<_>>()
-                        .await?;
+    /// Put a blob if `key` is absent or expired. Of concurrent conditional
+    /// puts of a key, at most one succeeds. Returns `New` if the blob was
+    /// written, and `Prevented` if the key was present.
+    pub async fn put_if_absent_atomic(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        let mode = PutMode::Conditional(PutCondition::Absent);
+        self.put_impl(ctx, key, value, mode, None).await
+    }
 
-                    let size = chunks.iter().map(|chunk| chunk.len()).sum();
-                    let mut blob = BytesMut::with_capacity(size);
-                    for chunk in chunks {
-                        blob.extend_from_slice(&chunk);
-                    }
-                    blob.freeze()
-                }
-            };
+    /// Put a blob if `key` is present, and its value has `expected_hash`
+    /// (see `value_hash`). This is a compare-and-swap: of concurrent
+    /// conditional puts of a key, at most one succeeds. Returns `Overwrote`
+    /// if the blob was written, and `Prevented` otherwise. Blobs written
+    /// before hashes were recorded never match.
+    pub async fn put_if_matches(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        expected_hash: i64,
+        value: BlobstoreBytes,
+    ) -> Result<OverwriteStatus> {
+        let mode = PutMode::Conditional(PutCondition::Matches(expected_hash));
+        self.put_impl(ctx, key, value, mode, None).await
+    }
 
-            let meta = BlobstoreMetadata::new(Some(chunked.ctime), None);
-            Ok(Some(BlobstoreGetData::new(
-                meta,
-                BlobstoreBytes::from_bytes(blob),
-            )))
-        } else {
-            Ok(None)
+    /// The hash `put_if_matches` compares the current value of a key with.
+    pub fn value_hash(value: &BlobstoreBytes) -> i64 {
+        value_checksum(value.as_bytes())
+    }
+
+    async fn put_impl(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        mode: PutMode,
+        expiry: Option<i64>,
+    ) -> Result<OverwriteStatus> {
+        let start = Instant::now();
+        let mirrored_value = self.secondary.as_ref().map(|_| value.clone());
+        let recent_value = self.recent_writes.as_ref().map(|_| value.clone());
+        let value_len = value.len();
+        let res = match mode {
+            PutMode::Behaviour(put_behaviour) => {
+                self.put_untimed(&key, value, put_behaviour, expiry).await
+            }
+            PutMode::Conditional(condition) => {
+                self.put_conditional_untimed(&key, value, condition).await
+            }
+        };
+        let shard = self.data_store.shard(&key);
+        let elapsed = start.elapsed();
+        self.stats.record(
+            ctx,
+            SqlblobOperation::Put,
+            shard,
+            &key,
+            elapsed,
+            res.is_ok(),
+        );
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.on_put(&SqlblobEvent {
+                key: &key,
+                shard,
+                bytes: Some(value_len as u64),
+                chunking: Some(self.put_chunking_method(value_len).into()),
+                latency: elapsed,
+                success: res.is_ok(),
+            });
+        }
+        if let (Some(recent_writes), Some(value), Ok(status)) =
+            (&self.recent_writes, recent_value, &res)
+        {
+            // A prevented put left the existing blob in place, which may differ.
+            if *status != OverwriteStatus::Prevented {
+                recent_writes.record(key.clone(), current_timestamp(), expiry, value);
+            }
         }
+        let mirror_behaviour = match (mode, &res) {
+            (PutMode::Behaviour(put_behaviour), Ok(_)) => Some(put_behaviour),
+            // The primary decided whether to write, so the secondary follows.
+            (PutMode::Conditional(_), Ok(OverwriteStatus::Prevented)) => None,
+            (PutMode::Conditional(_), Ok(_)) => Some(PutBehaviour::Overwrite),
+            (_, Err(_)) => None,
+        };
+        if let (Some(secondary), Some(value), Some(put_behaviour)) =
+            (&self.secondary, mirrored_value, mirror_behaviour)
+        {
+            secondary.mirror(MirroredWrite::Put {
+                key,
+                value,
+                put_behaviour,
+                expiry,
+            });
+        }
+        res
     }
 
-    async fn is_present<'a>(
-        &'a self,
-        _ctx: &'a CoreContext,
-        key: &'a str,
-    ) -> Result<BlobstoreIsPresent> {
-        let present = self.data_store.is_present(&key).await?;
-        Ok(if present {
-            BlobstoreIsPresent::Present
+    /// How a new blob of `len` bytes is stored.
+    fn put_chunking_method(&self, len: usize) -> ChunkingMethod {
+        if self.allow_inline_put && len <= MAX_INLINE_LEN {
+            ChunkingMethod::InlineBase64
         } else {
-            BlobstoreIsPresent::Absent
-        })
+            self.chunk_store.chunking_method()
+        }
     }
 
-    async fn put<'a>(
-        &'a self,
-        ctx: &'a CoreContext,
-        key: String,
-        value: BlobstoreBytes,
-    ) -> Result<()> {
-        BlobstorePutOps::put_with_status(self, ctx, key, value).await?;
-        Ok(())
+    /// A blob this process wrote recently enough that replicas may not have
+    /// it yet.
+    fn is_recent_write(&self, key: &str) -> bool {
+        self.get_recent_write(key).is_some()
     }
-}
 
-#[async_trait]
-impl BlobstorePutOps for Sqlblob {
-    async fn put_explicit<'a>(
-        &'a self,
-        _ctx: &'a CoreContext,
-        key: String,
-        value: BlobstoreBytes,
-        put_behaviour: PutBehaviour,
-    ) -> Result<OverwriteStatus> {
+    fn get_recent_write(&self, key: &str) -> Option<BlobstoreGetData> {
+        let (ctime, value) = self.recent_writes.as_ref()?.get(key, current_timestamp())?;
+        let meta = BlobstoreMetadata::new(Some(ctime), None);
+        Some(BlobstoreGetData::new(meta, value))
+    }
+
+    fn check_key_size(key: &str) -> Result<()> {
         if key.as_bytes().len() > MAX_KEY_SIZE {
             return Err(format_err!(
                 "Key {} exceeded max key size {}",
@@ -486,18 +755,103 @@ impl BlobstorePutOps for Sqlblob {
                 MAX_KEY_SIZE
             ));
         }
+        Ok(())
+    }
+
+    /// Write the chunks of a blob, and return the chunk id and count to
+    /// store in its data row.
+    async fn put_chunks(
+        &self,
+        key: &str,
+        value: &BlobstoreBytes,
+        chunking_method: ChunkingMethod,
+    ) -> Result<(String, u32)> {
+        match chunking_method {
+            ChunkingMethod::ByContentHashBlake2 | ChunkingMethod::ByContentHashBlake2WithCodec => {
+                let chunk_key = {
+                    let mut hash_context = chunk_hash_context(chunking_method);
+                    hash_context.update(value.as_bytes());
+                    hash_context.finish().to_hex().to_string()
+                };
+                let chunks = value.as_bytes().chunks(CHUNK_SIZE);
+                let chunk_count = chunks.len().try_into()?;
+                for (chunk_num, value) in chunks.enumerate() {
+                    self.chunk_store
+                        .put(
+                            chunk_key.as_str(),
+                            chunk_num.try_into()?,
+                            chunking_method,
+                            value,
+                        )
+                        .await?;
+                }
+                Ok((chunk_key, chunk_count))
+            }
+            ChunkingMethod::InlineBase64 => {
+                self.stats.record_inline_put(self.data_store.shard(key));
+                Ok((
+                    base64::encode_config(value.as_bytes().as_ref(), base64::STANDARD_NO_PAD),
+                    0,
+                ))
+            }
+        }
+    }
+
+    async fn put_conditional_untimed(
+        &self,
+        key: &str,
+        value: BlobstoreBytes,
+        condition: PutCondition,
+    ) -> Result<OverwriteStatus> {
+        Self::check_key_size(key)?;
+
+        if condition == PutCondition::Absent && self.data_store.is_present(key).await? {
+            // Short circuit as for IfAbsent puts. Otherwise the data store
+            // checks again with the row locked.
+            return Ok(OverwriteStatus::Prevented);
+        }
+
+        // The chunks are written first, as for other puts. If the condition
+        // does not hold, GC removes them unless another key refers to them.
+        let chunking_method = self.put_chunking_method(value.len());
+        let (chunk_key, chunk_count) = self.put_chunks(key, &value, chunking_method).await?;
+        let written = self
+            .data_store
+            .put_if(
+                key,
+                condition,
+                current_timestamp(),
+                chunk_key.as_str(),
+                chunk_count,
+                chunking_method,
+                value.len() as u64,
+                value_checksum(value.as_bytes()),
+            )
+            .await?;
+        Ok(match (written, condition) {
+            (false, _) => OverwriteStatus::Prevented,
+            (true, PutCondition::Absent) => OverwriteStatus::New,
+            (true, PutCondition::Matches(_)) => OverwriteStatus::Overwrote,
+        })
+    }
+
+    async fn put_untimed(
+        &self,
+        key: &str,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+        expiry: Option<i64>,
+    ) -> Result<OverwriteStatus> {
+        Self::check_key_size(key)?;
 
-        if put_behaviour == PutBehaviour::IfAbsent && self.data_store.is_present(&key).await? {
+        if put_behaviour == PutBehaviour::IfAbsent && self.data_store.is_present(key).await? {
             // Can short circuit here as key already exists, and is keeping its chunks live
             return Ok(OverwriteStatus::Prevented);
         }
 
-        let chunking_method = if self.allow_inline_put && value.len() <= MAX_INLINE_LEN {
-            ChunkingMethod::InlineBase64
-        } else {
-            ChunkingMethod::ByContentHashBlake2
-        };
+        let chunking_method = self.put_chunking_method(value.len());
 
+        let checksum = value_checksum(value.as_bytes());
         let put_fut = async {
             let ctime = {
                 match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
@@ -505,40 +859,18 @@ impl BlobstorePutOps for Sqlblob {
                     Err(negative) => negative.duration().as_secs().try_into().map(|v: i64| -v),
                 }
             }?;
-            let (chunk_key, chunk_count) = match chunking_method {
-                ChunkingMethod::ByContentHashBlake2 => {
-                    let chunk_key = {
-                        let mut hash_context = HashContext::new(b"sqlblob");
-                        hash_context.update(value.as_bytes());
-                        hash_context.finish().to_hex().to_string()
-                    };
-                    let chunks = value.as_bytes().chunks(CHUNK_SIZE);
-                    let chunk_count = chunks.len().try_into()?;
-                    for (chunk_num, value) in chunks.enumerate() {
-                        self.chunk_store
-                            .put(
-                                chunk_key.as_str(),
-                                chunk_num.try_into()?,
-                                chunking_method,
-                                value,
-                            )
-                            .await?;
-                    }
-                    (chunk_key, chunk_count)
-                }
-                ChunkingMethod::InlineBase64 => (
-                    base64::encode_config(value.as_bytes().as_ref(), base64::STANDARD_NO_PAD),
-                    0,
-                ),
-            };
+            let (chunk_key, chunk_count) = self.put_chunks(key, &value, chunking_method).await?;
 
             self.data_store
                 .put(
-                    &key,
+                    key,
                     ctime,
                     chunk_key.as_str(),
                     chunk_count,
                     chunking_method,
+                    expiry,
+                    Some(value.len() as u64),
+                    Some(checksum),
                 )
                 .await
                 .map(|()| OverwriteStatus::NotChecked)
@@ -547,7 +879,7 @@ impl BlobstorePutOps for Sqlblob {
         match put_behaviour {
             PutBehaviour::Overwrite => put_fut.await,
             PutBehaviour::IfAbsent | PutBehaviour::OverwriteAndLog => {
-                match self.data_store.get(&key).await? {
+                match self.data_store.get(key).await? {
                     None => {
                         put_fut.await?;
                         Ok(OverwriteStatus::New)
@@ -575,6 +907,258 @@ impl BlobstorePutOps for Sqlblob {
         }
     }
 
+    /// Fetch `key`, and how it is stored if it was read from the database.
+    async fn get_impl(
+        &self,
+        ctx: &CoreContext,
+        key: &str,
+    ) -> Result<Option<(BlobstoreGetData, Option<ChunkingMethod>)>> {
+        if let Some(data) = self.get_recent_write(key) {
+            return Ok(Some((data, None)));
+        }
+        let chunked = self.data_store.get(key).await?;
+        if let Some(chunked) = chunked {
+            let blob = match chunked.chunking_method {
+                ChunkingMethod::InlineBase64 => {
+                    let decoded = base64::decode_config(&chunked.id, base64::STANDARD_NO_PAD)?;
+                    Bytes::copy_from_slice(decoded.as_ref())
+                }
+                ChunkingMethod::ByContentHashBlake2
+                | ChunkingMethod::ByContentHashBlake2WithCodec => {
+                    let chunks = (0..chunked.count)
+                        .map(|chunk_num| {
+                            self.chunk_store
+                                .get(&chunked.id, chunk_num, chunked.chunking_method)
+                        })
+                        .collect::<FuturesOrdered<_>>()
+                        .try_collect::<Vec<_>>()
+                        .await?;
+
+                    let size = chunks.iter().map(|chunk| chunk.len()).sum();
+                    let mut blob = BytesMut::with_capacity(size);
+                    for chunk in chunks {
+                        blob.extend_from_slice(&chunk);
+                    }
+                    blob.freeze()
+                }
+            };
+            self.verify_checksum(ctx, key, &chunked, &blob)?;
+
+            let meta = BlobstoreMetadata::new(Some(chunked.ctime), None);
+            Ok(Some((
+                BlobstoreGetData::new(meta, BlobstoreBytes::from_bytes(blob)),
+                Some(chunked.chunking_method),
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check a blob read for `key` against the checksum stored with it. Only
+    /// the reads of the percentage of sessions set by the
+    /// `sqlblob_checksum_verify_percentage` tunable are checked, as hashing
+    /// every blob read is not free.
+    fn verify_checksum(
+        &self,
+        ctx: &CoreContext,
+        key: &str,
+        chunked: &Chunked,
+        blob: &[u8],
+    ) -> Result<()> {
+        let expected = match chunked.checksum {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let session_hash = rollout_hash(ctx.metadata().session_id().as_str());
+        if !tunables().get_sqlblob_checksum_verify_percentage_rollout(session_hash) {
+            return Ok(());
+        }
+        let shard = self.data_store.shard(key);
+        let actual = value_checksum(blob);
+        self.stats.record_checksum(shard, actual == expected);
+        if actual != expected {
+            return Err(SqlblobCorruption {
+                key: key.to_string(),
+                shard,
+                expected: expected as u64,
+                actual: actual as u64,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The size, ctime and storage of a blob, read from its data row alone.
+    /// Cheaper than `get` for callers that only need to know how big a blob
+    /// is, as no chunks are fetched.
+    pub async fn get_metadata(
+        &self,
+        _ctx: &CoreContext,
+        key: &str,
+    ) -> Result<Option<BlobMetadata>> {
+        let chunked = self.data_store.get(key).await?;
+        Ok(chunked.map(|chunked| chunked.metadata()))
+    }
+
+    /// Fetch many blobs at once. Keys are grouped by shard so that data rows
+    /// and chunks are fetched with a few queries per shard rather than a few
+    /// queries per key. Keys that are not present are missing from the result.
+    pub async fn get_many(
+        &self,
+        ctx: &CoreContext,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, BlobstoreGetData>> {
+        let mut recent = HashMap::new();
+        let keys = keys
+            .into_iter()
+            .filter(|key| match self.get_recent_write(key) {
+                Some(data) => {
+                    recent.insert(key.clone(), data);
+                    false
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        let data = self.data_store.get_many(&keys).await?;
+
+        let wanted_chunks = data
+            .values()
+            .flat_map(|chunked| {
+                (0..chunked.count)
+                    .map(move |chunk_num| (chunked.id.clone(), chunk_num, chunked.chunking_method))
+            })
+            .collect::<Vec<_>>();
+        let chunks = self.chunk_store.get_many(&wanted_chunks).await?;
+
+        data.into_iter()
+            .map(|(key, chunked)| {
+                let blob = match chunked.chunking_method {
+                    ChunkingMethod::InlineBase64 => {
+                        let decoded = base64::decode_config(&chunked.id, base64::STANDARD_NO_PAD)?;
+                        Bytes::copy_from_slice(decoded.as_ref())
+                    }
+                    ChunkingMethod::ByContentHashBlake2
+                    | ChunkingMethod::ByContentHashBlake2WithCodec => {
+                        let mut blob = BytesMut::new();
+                        for chunk_num in 0..chunked.count {
+                            let chunk =
+                                chunks
+                                    .get(&(chunked.id.clone(), chunk_num))
+                                    .ok_or_else(|| {
+                                        format_err!(
+                                            "Missing chunk {} of {} for key {}",
+                                            chunk_num,
+                                            chunked.id,
+                                            key
+                                        )
+                                    })?;
+                            blob.extend_from_slice(chunk);
+                        }
+                        blob.freeze()
+                    }
+                };
+                self.verify_checksum(ctx, &key, &chunked, &blob)?;
+                let meta = BlobstoreMetadata::new(Some(chunked.ctime), None);
+                Ok((
+                    key,
+                    BlobstoreGetData::new(meta, BlobstoreBytes::from_bytes(blob)),
+                ))
+            })
+            .chain(recent.into_iter().map(Ok))
+            .collect()
+    }
+
+    /// Check the presence of many keys at once, with a few queries per shard.
+    pub async fn is_present_many(
+        &self,
+        _ctx: &CoreContext,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, BlobstoreIsPresent>> {
+        let present = self.data_store.is_present_many(&keys).await?;
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let is_present = if present.contains(&key) || self.is_recent_write(&key) {
+                    BlobstoreIsPresent::Present
+                } else {
+                    BlobstoreIsPresent::Absent
+                };
+                (key, is_present)
+            })
+            .collect())
+    }
+}
+
+impl fmt::Debug for Sqlblob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sqlblob").finish()
+    }
+}
+
+#[async_trait]
+impl Blobstore for Sqlblob {
+    async fn get<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<Option<BlobstoreGetData>> {
+        let start = Instant::now();
+        let res = self.get_impl(ctx, key).await;
+        let shard = self.data_store.shard(key);
+        let elapsed = start.elapsed();
+        self.stats
+            .record(ctx, SqlblobOperation::Get, shard, key, elapsed, res.is_ok());
+        if let Some(telemetry) = &self.telemetry {
+            let found = res.as_ref().ok().and_then(Option::as_ref);
+            telemetry.on_get(&SqlblobEvent {
+                key,
+                shard,
+                bytes: found.map(|(data, _)| data.as_raw_bytes().len() as u64),
+                chunking: found.and_then(|(_, chunking_method)| chunking_method.map(Into::into)),
+                latency: elapsed,
+                success: res.is_ok(),
+            });
+        }
+        res.map(|found| found.map(|(data, _)| data))
+    }
+
+    async fn is_present<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        key: &'a str,
+    ) -> Result<BlobstoreIsPresent> {
+        let present = self.is_recent_write(key) || self.data_store.is_present(&key).await?;
+        Ok(if present {
+            BlobstoreIsPresent::Present
+        } else {
+            BlobstoreIsPresent::Absent
+        })
+    }
+
+    async fn put<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> Result<()> {
+        BlobstorePutOps::put_with_status(self, ctx, key, value).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobstorePutOps for Sqlblob {
+    async fn put_explicit<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+    ) -> Result<OverwriteStatus> {
+        self.put_impl(ctx, key, value, PutMode::Behaviour(put_behaviour), None)
+            .await
+    }
+
     async fn put_with_status<'a>(
         &'a self,
         ctx: &'a CoreContext,
@@ -593,29 +1177,193 @@ impl BlobstoreWithLink for Sqlblob {
         existing_key: &'a str,
         link_key: String,
     ) -> Result<()> {
+        let start = Instant::now();
+        let res = self.link_impl(existing_key, &link_key).await;
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.on_link(&event_for(
+                &link_key,
+                self.data_store.shard(&link_key),
+                &res,
+                start.elapsed(),
+            ));
+        }
+        if let (Some(secondary), Ok(_)) = (&self.secondary, &res) {
+            secondary.mirror(MirroredWrite::Link {
+                existing_key: existing_key.to_string(),
+                link_key,
+            });
+        }
+        res.map(|_| ())
+    }
+
+    async fn unlink<'a>(&'a self, _ctx: &'a CoreContext, key: &'a str) -> Result<()> {
+        let start = Instant::now();
+        let res = self.unlink_impl(key).await;
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.on_unlink(&event_for(
+                key,
+                self.data_store.shard(key),
+                &res,
+                start.elapsed(),
+            ));
+        }
+        if let (Some(secondary), Ok(_)) = (&self.secondary, &res) {
+            secondary.mirror(MirroredWrite::Unlink {
+                key: key.to_string(),
+            });
+        }
+        res.map(|_| ())
+    }
+}
+
+impl Sqlblob {
+    /// Link `link_key` to the blob of `existing_key`, returning its data row.
+    async fn link_impl(&self, existing_key: &str, link_key: &str) -> Result<Chunked> {
         let existing_data =
             self.data_store.get(existing_key).await?.ok_or_else(|| {
                 format_err!("Key {} does not exist in the blobstore", existing_key)
             })?;
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.remove(link_key);
+        }
         self.data_store
             .put(
-                &link_key,
+                link_key,
                 existing_data.ctime,
                 &existing_data.id,
                 existing_data.count,
                 existing_data.chunking_method,
+                existing_data.expiry,
+                existing_data.value_size,
+                existing_data.checksum,
             )
-            .await
+            .await?;
+        Ok(existing_data)
     }
 
-    async fn unlink<'a>(&'a self, _ctx: &'a CoreContext, key: &'a str) -> Result<()> {
-        if !self.data_store.is_present(key).await? {
-            bail!(
+    /// Unlink `key`, returning the data row it had.
+    async fn unlink_impl(&self, key: &str) -> Result<Chunked> {
+        let existing_data = match self.data_store.get(key).await? {
+            Some(existing_data) => existing_data,
+            None => bail!(
                 "Sqlblob::unlink: key {} does not exist in the blobstore",
                 key
-            )
+            ),
         };
-        self.data_store.unlink(&key).await
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.remove(key);
+        }
+        self.data_store.unlink(key).await?;
+        Ok(existing_data)
+    }
+}
+
+/// The telemetry event for an operation on `key` that returns the data row
+/// of the blob it refers to.
+fn event_for<'a>(
+    key: &'a str,
+    shard: usize,
+    res: &Result<Chunked>,
+    latency: Duration,
+) -> SqlblobEvent<'a> {
+    let chunked = res.as_ref().ok();
+    SqlblobEvent {
+        key,
+        shard,
+        bytes: chunked.and_then(|chunked| chunked.value_size),
+        chunking: chunked.map(|chunked| chunked.chunking_method.into()),
+        latency,
+        success: res.is_ok(),
+    }
+}
+
+#[async_trait]
+impl BlobstoreUnlinkOps for Sqlblob {
+    /// Removes the data rows for all of `keys`, then moves their chunks back to the mark
+    /// generation so that GC can reclaim them after one mark and sweep, rather than two. Chunks
+    /// still referenced by other keys are marked again by that mark phase, so they are kept.
+    ///
+    /// Refuses to run during a GC mark phase, as it could otherwise unmark chunks the phase has
+    /// already marked.
+    async fn unlink_many<'a>(&'a self, _ctx: &'a CoreContext, keys: Vec<String>) -> Result<()> {
+        if self.chunk_store.mark_in_progress() {
+            bail!("Sqlblob::unlink_many: refusing to unlink while a GC mark phase is running");
+        }
+        let existing = self.data_store.get_many(&keys).await?;
+        if let Some(missing) = keys.iter().find(|key| !existing.contains_key(*key)) {
+            bail!(
+                "Sqlblob::unlink_many: key {} does not exist in the blobstore",
+                missing
+            );
+        }
+        if let Some(recent_writes) = &self.recent_writes {
+            for key in &keys {
+                recent_writes.remove(key);
+            }
+        }
+        self.data_store.unlink_many(&keys).await?;
+        if let Some(secondary) = &self.secondary {
+            secondary.mirror(MirroredWrite::UnlinkMany { keys });
+        }
+        self.chunk_store
+            .lower_generations(existing.into_values().flat_map(|chunked| {
+                (0..chunked.count)
+                    .map(move |chunk_num| (chunked.id.clone(), chunk_num, chunked.chunking_method))
+            }))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobstoreKeySource for Sqlblob {
+    /// Enumerates one shard at a time, returning at most
+    /// `ENUMERATE_PAGE_SIZE` keys per call. The returned token resumes from
+    /// the last key returned, or from the start of the next shard.
+    async fn enumerate<'a>(
+        &'a self,
+        _ctx: &'a CoreContext,
+        range: &'a BlobstoreKeyParam,
+    ) -> Result<BlobstoreEnumerationData> {
+        let (shard, last_key, range) = match range {
+            BlobstoreKeyParam::Start(range) => (0, None, range),
+            BlobstoreKeyParam::Continuation(BlobstoreKeyToken::ShardToken {
+                shard,
+                last_key,
+                range,
+            }) => (*shard, last_key.as_deref(), range),
+            BlobstoreKeyParam::Continuation(token) => {
+                bail!("Sqlblob does not support token {:?}", token)
+            }
+        };
+        if shard >= self.data_store.shard_count() {
+            bail!("Sqlblob has no shard {}", shard);
+        }
+
+        let keys = self
+            .data_store
+            .get_keys_in_range(shard, range, last_key, ENUMERATE_PAGE_SIZE)
+            .await?;
+        let next_token = if keys.len() as u64 == ENUMERATE_PAGE_SIZE {
+            Some(BlobstoreKeyToken::ShardToken {
+                shard,
+                last_key: keys.last().cloned(),
+                range: range.clone(),
+            })
+        } else if shard + 1 < self.data_store.shard_count() {
+            Some(BlobstoreKeyToken::ShardToken {
+                shard: shard + 1,
+                last_key: None,
+                range: range.clone(),
+            })
+        } else {
+            None
+        };
+
+        Ok(BlobstoreEnumerationData {
+            keys: keys.into_iter().collect(),
+            next_token: next_token.map(BlobstoreKeyParam::Continuation),
+        })
     }
 }
 
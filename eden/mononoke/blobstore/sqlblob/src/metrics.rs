@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-shard metrics for a `Sqlblob`. Everything is exported to ODS, and
+//! also kept in memory so that callers can find hot or failing shards.
+
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use context::CoreContext;
+use slog::warn;
+use stats::prelude::*;
+
+define_stats! {
+    prefix = "mononoke.sqlblob.shard";
+    get_ms: dynamic_histogram("{}.get_ms", (shard: usize); 10, 0, 1_000, Average, Sum, Count; P 50; P 95; P 99),
+    put_ms: dynamic_histogram("{}.put_ms", (shard: usize); 10, 0, 1_000, Average, Sum, Count; P 50; P 95; P 99),
+    get_errors: dynamic_timeseries("{}.get_errors", (shard: usize); Rate, Sum),
+    put_errors: dynamic_timeseries("{}.put_errors", (shard: usize); Rate, Sum),
+    inline_puts: dynamic_timeseries("{}.inline_puts", (shard: usize); Rate, Sum),
+    chunk_cache_hits: dynamic_timeseries("{}.chunk_cache_hits", (shard: usize); Rate, Sum),
+    chunk_cache_misses: dynamic_timeseries("{}.chunk_cache_misses", (shard: usize); Rate, Sum),
+    slow_queries: dynamic_timeseries("{}.slow_queries", (shard: usize); Rate, Sum),
+    checksums_verified: dynamic_timeseries("{}.checksums_verified", (shard: usize); Rate, Sum),
+    checksum_mismatches: dynamic_timeseries("{}.checksum_mismatches", (shard: usize); Rate, Sum),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SqlblobOperation {
+    Get,
+    Put,
+}
+
+#[derive(Default)]
+struct ShardCounters {
+    gets: AtomicU64,
+    get_errors: AtomicU64,
+    get_time_us: AtomicU64,
+    puts: AtomicU64,
+    put_errors: AtomicU64,
+    put_time_us: AtomicU64,
+    inline_puts: AtomicU64,
+    chunk_cache_hits: AtomicU64,
+    chunk_cache_misses: AtomicU64,
+    slow_queries: AtomicU64,
+    checksums_verified: AtomicU64,
+    checksum_mismatches: AtomicU64,
+}
+
+/// A point in time copy of the counters for one shard.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    pub gets: u64,
+    pub get_errors: u64,
+    pub get_time: Duration,
+    pub puts: u64,
+    pub put_errors: u64,
+    pub put_time: Duration,
+    pub inline_puts: u64,
+    pub chunk_cache_hits: u64,
+    pub chunk_cache_misses: u64,
+    pub slow_queries: u64,
+    /// Gets whose value was checked against its stored checksum.
+    pub checksums_verified: u64,
+    pub checksum_mismatches: u64,
+}
+
+impl ShardStats {
+    /// Fraction of puts that were stored inline, if there were any puts.
+    pub fn inline_put_ratio(&self) -> Option<f64> {
+        ratio(self.inline_puts, self.puts)
+    }
+
+    /// Fraction of chunk fetches served from cache, if there were any.
+    pub fn chunk_cache_hit_rate(&self) -> Option<f64> {
+        ratio(
+            self.chunk_cache_hits,
+            self.chunk_cache_hits + self.chunk_cache_misses,
+        )
+    }
+}
+
+fn ratio(num: u64, denom: u64) -> Option<f64> {
+    if denom == 0 {
+        None
+    } else {
+        Some(num as f64 / denom as f64)
+    }
+}
+
+/// Tracks latency, errors and put kinds for each shard of a `Sqlblob`, and
+/// logs operations slower than a threshold.
+pub struct SqlblobStats {
+    shards: Vec<ShardCounters>,
+    slow_query_threshold: Option<Duration>,
+}
+
+impl SqlblobStats {
+    pub(crate) fn new(shard_count: NonZeroUsize, slow_query_threshold: Option<Duration>) -> Self {
+        Self {
+            shards: (0..shard_count.get())
+                .map(|_| ShardCounters::default())
+                .collect(),
+            slow_query_threshold,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Snapshot of the counters for one shard.
+    pub fn shard_stats(&self, shard: usize) -> ShardStats {
+        let counters = &self.shards[shard];
+        ShardStats {
+            gets: counters.gets.load(Ordering::Relaxed),
+            get_errors: counters.get_errors.load(Ordering::Relaxed),
+            get_time: Duration::from_micros(counters.get_time_us.load(Ordering::Relaxed)),
+            puts: counters.puts.load(Ordering::Relaxed),
+            put_errors: counters.put_errors.load(Ordering::Relaxed),
+            put_time: Duration::from_micros(counters.put_time_us.load(Ordering::Relaxed)),
+            inline_puts: counters.inline_puts.load(Ordering::Relaxed),
+            chunk_cache_hits: counters.chunk_cache_hits.load(Ordering::Relaxed),
+            chunk_cache_misses: counters.chunk_cache_misses.load(Ordering::Relaxed),
+            slow_queries: counters.slow_queries.load(Ordering::Relaxed),
+            checksums_verified: counters.checksums_verified.load(Ordering::Relaxed),
+            checksum_mismatches: counters.checksum_mismatches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record a completed get or put of `key` on `shard`, logging it if it
+    /// was slower than the configured threshold.
+    pub(crate) fn record(
+        &self,
+        ctx: &CoreContext,
+        operation: SqlblobOperation,
+        shard: usize,
+        key: &str,
+        elapsed: Duration,
+        success: bool,
+    ) {
+        let counters = &self.shards[shard];
+        let elapsed_us = elapsed.as_micros() as u64;
+        let elapsed_ms = elapsed.as_millis() as i64;
+        match operation {
+            SqlblobOperation::Get => {
+                counters.gets.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .get_time_us
+                    .fetch_add(elapsed_us, Ordering::Relaxed);
+                STATS::get_ms.add_value(elapsed_ms, (shard,));
+                if !success {
+                    counters.get_errors.fetch_add(1, Ordering::Relaxed);
+                    STATS::get_errors.add_value(1, (shard,));
+                }
+            }
+            SqlblobOperation::Put => {
+                counters.puts.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .put_time_us
+                    .fetch_add(elapsed_us, Ordering::Relaxed);
+                STATS::put_ms.add_value(elapsed_ms, (shard,));
+                if !success {
+                    counters.put_errors.fetch_add(1, Ordering::Relaxed);
+                    STATS::put_errors.add_value(1, (shard,));
+                }
+            }
+        }
+
+        if let Some(threshold) = self.slow_query_threshold {
+            if elapsed > threshold {
+                counters.slow_queries.fetch_add(1, Ordering::Relaxed);
+                STATS::slow_queries.add_value(1, (shard,));
+                warn!(
+                    ctx.logger(),
+                    "Slow sqlblob {:?} of {} on shard {} took {:?}", operation, key, shard, elapsed
+                );
+            }
+        }
+    }
+
+    pub(crate) fn record_inline_put(&self, shard: usize) {
+        self.shards[shard]
+            .inline_puts
+            .fetch_add(1, Ordering::Relaxed);
+        STATS::inline_puts.add_value(1, (shard,));
+    }
+
+    /// Record whether a chunk fetch from `shard` was served from cache.
+    pub fn record_chunk_cache(&self, shard: usize, hit: bool) {
+        let counters = &self.shards[shard];
+        if hit {
+            counters.chunk_cache_hits.fetch_add(1, Ordering::Relaxed);
+            STATS::chunk_cache_hits.add_value(1, (shard,));
+        } else {
+            counters.chunk_cache_misses.fetch_add(1, Ordering::Relaxed);
+            STATS::chunk_cache_misses.add_value(1, (shard,));
+        }
+    }
+
+    /// Record that a value read from `shard` was checked against its stored
+    /// checksum, and whether it matched.
+    pub(crate) fn record_checksum(&self, shard: usize, matched: bool) {
+        let counters = &self.shards[shard];
+        counters.checksums_verified.fetch_add(1, Ordering::Relaxed);
+        STATS::checksums_verified.add_value(1, (shard,));
+        if !matched {
+            counters.checksum_mismatches.fetch_add(1, Ordering::Relaxed);
+            STATS::checksum_mismatches.add_value(1, (shard,));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Copying of data between two `Sqlblob`s, e.g. to rebalance when changing
+//! the number of shards. Keys are copied with their ctime, expiry and chunk
+//! generations, and chunks are copied as stored, so no re-encoding happens.
+//!
+//! Copying never overwrites a key that is already in the destination, so
+//! writes made there while a shard is copied win. Keys are left in the source
+//! until `remove_copied_keys` deletes those that made it to the destination.
+
+use anyhow::{format_err, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use crate::store::ChunkingMethod;
+use crate::Sqlblob;
+
+// Number of keys listed, and checkpointed, at a time.
+const COPY_PAGE_SIZE: u64 = 1000;
+
+/// How far a copy of a shard has got. Every key up to and including
+/// `last_key` has been copied.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyCheckpoint {
+    pub last_key: Option<String>,
+    pub keys_copied: u64,
+}
+
+/// Copy every key in shard `shard` of `src` to `dst`, copying up to
+/// `concurrency` keys in parallel.
+pub async fn copy_shard(
+    src: &Sqlblob,
+    dst: &Sqlblob,
+    shard: usize,
+    concurrency: usize,
+) -> Result<CopyCheckpoint> {
+    copy_shard_from(
+        src,
+        dst,
+        shard,
+        concurrency,
+        CopyCheckpoint::default(),
+        |_| Ok(()),
+    )
+    .await
+}
+
+/// Like `copy_shard`, but resumes from `checkpoint`, and calls
+/// `on_checkpoint` each time a page of keys has been copied so that the
+/// caller can persist its progress.
+pub async fn copy_shard_from(
+    src: &Sqlblob,
+    dst: &Sqlblob,
+    shard: usize,
+    concurrency: usize,
+    mut checkpoint: CopyCheckpoint,
+    mut on_checkpoint: impl FnMut(&CopyCheckpoint) -> Result<()>,
+) -> Result<CopyCheckpoint> {
+    loop {
+        let after = checkpoint.last_key.as_deref().unwrap_or("");
+        let keys = src
+            .data_store
+            .get_keys_page(shard, after, COPY_PAGE_SIZE)
+            .await?;
+        let last_key = match keys.last() {
+            Some(last_key) => last_key.clone(),
+            None => return Ok(checkpoint),
+        };
+        let copied = stream::iter(keys)
+            .map(|key| copy_key(src, dst, shard, key))
+            .buffer_unordered(concurrency)
+            .try_fold(0, |copied, was_copied| async move {
+                Ok(copied + was_copied as u64)
+            })
+            .await?;
+
+        checkpoint.last_key = Some(last_key);
+        checkpoint.keys_copied += copied;
+        on_checkpoint(&checkpoint)?;
+    }
+}
+
+/// Copy one key from shard `shard` of `src`. Returns false if it was
+/// removed before it was copied, or if `dst` already has it.
+pub(crate) async fn copy_key(
+    src: &Sqlblob,
+    dst: &Sqlblob,
+    shard: usize,
+    key: String,
+) -> Result<bool> {
+    let chunked = match src.data_store.get_in_shard(shard, &key).await? {
+        Some(chunked) => chunked,
+        None => return Ok(false),
+    };
+
+    if chunked.chunking_method != ChunkingMethod::InlineBase64 {
+        for chunk_num in 0..chunked.count {
+            let value = src
+                .chunk_store
+                .get_stored(&chunked.id, chunk_num, chunked.chunking_method)
+                .await?
+                .ok_or_else(|| {
+                    format_err!(
+                        "Missing chunk {} of {} for key {}",
+                        chunk_num,
+                        chunked.id,
+                        key
+                    )
+                })?;
+            let generation = src
+                .chunk_store
+                .get_generation(&chunked.id, chunk_num, chunked.chunking_method)
+                .await?;
+            dst.chunk_store
+                .put_stored(
+                    &chunked.id,
+                    chunk_num,
+                    chunked.chunking_method,
+                    &value,
+                    generation,
+                )
+                .await?;
+        }
+    }
+
+    let inserted = dst
+        .data_store
+        .put_if_absent(
+            &key,
+            chunked.ctime,
+            &chunked.id,
+            chunked.count,
+            chunked.chunking_method,
+            chunked.expiry,
+            chunked.value_size,
+            chunked.checksum,
+        )
+        .await?;
+    if !inserted {
+        return Ok(false);
+    }
+
+    // If the key was unlinked since it was read, the unlink might have
+    // missed the copy, so take it back out unless it was overwritten since.
+    let still_there = src
+        .data_store
+        .get_in_shard(shard, &key)
+        .await?
+        .map_or(false, |now| {
+            now.ctime == chunked.ctime && now.id == chunked.id
+        });
+    if !still_there {
+        dst.data_store
+            .unlink_in_shard_if_matches(
+                dst.data_store.shard(&key),
+                &key,
+                chunked.ctime,
+                &chunked.id,
+            )
+            .await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Delete the keys in shard `shard` of `src` that have been copied to `dst`,
+/// deleting up to `concurrency` keys in parallel. Returns how many were
+/// deleted. Keys that are not in `dst` yet are kept, as are keys that `dst`
+/// keeps on the same shard of the same database.
+pub async fn remove_copied_keys(
+    src: &Sqlblob,
+    dst: &Sqlblob,
+    shard: usize,
+    concurrency: usize,
+) -> Result<u64> {
+    let same_database = src.data_store.same_database(&dst.data_store);
+    let mut removed = 0;
+    let mut after = String::new();
+    loop {
+        let keys = src
+            .data_store
+            .get_keys_page(shard, &after, COPY_PAGE_SIZE)
+            .await?;
+        after = match keys.last() {
+            Some(last_key) => last_key.clone(),
+            None => return Ok(removed),
+        };
+        removed += stream::iter(keys)
+            .map(|key| async move {
+                let dst_shard = dst.data_store.shard(&key);
+                if same_database && dst_shard == shard {
+                    return Ok(false);
+                }
+                match dst.data_store.get_in_shard(dst_shard, &key).await? {
+                    Some(_) => src.data_store.unlink_in_shard(shard, &key).await,
+                    None => Ok(false),
+                }
+            })
+            .buffer_unordered(concurrency)
+            .try_fold(0, |removed, was_removed| async move {
+                Ok(removed + was_removed as u64)
+            })
+            .await?;
+    }
+}
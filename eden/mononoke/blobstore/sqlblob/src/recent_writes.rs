@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Blobs written by this process that replicas may not have caught up with
+//! yet, consulted before reads go to the database.
+//!
+//! Reads go to replicas, so a `get` straight after a `put` can miss the blob
+//! while replication is lagging. Keeping recent writes around for the
+//! replica catch-up window gives callers read-your-writes semantics. Entries
+//! are dropped once they are older than that window, and the least recently
+//! written entries are evicted first when over the byte budget.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use linked_hash_map::LinkedHashMap;
+use mononoke_types::BlobstoreBytes;
+
+/// Bounds on the blobs kept for read-your-writes.
+#[derive(Clone, Debug)]
+pub struct RecentWritesOptions {
+    /// Keep up to `max_bytes` of recently written blobs.
+    pub max_bytes: usize,
+    /// How long replicas may take to catch up with a write.
+    pub max_age: Duration,
+}
+
+struct RecentWrite {
+    written: Instant,
+    ctime: i64,
+    expiry: Option<i64>,
+    value: BlobstoreBytes,
+}
+
+pub(crate) struct RecentWrites {
+    max_bytes: usize,
+    max_age: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    used_bytes: usize,
+    // Ordered by write time, oldest first.
+    entries: LinkedHashMap<String, RecentWrite>,
+}
+
+impl RecentWrites {
+    pub(crate) fn new(options: RecentWritesOptions) -> Self {
+        Self {
+            max_bytes: options.max_bytes,
+            max_age: options.max_age,
+            inner: Mutex::new(Inner {
+                used_bytes: 0,
+                entries: LinkedHashMap::new(),
+            }),
+        }
+    }
+
+    /// Remember that `value` was written to `key` at `ctime`.
+    pub(crate) fn record(
+        &self,
+        key: String,
+        ctime: i64,
+        expiry: Option<i64>,
+        value: BlobstoreBytes,
+    ) {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        inner.remove(&key);
+        if value.len() > self.max_bytes {
+            return;
+        }
+        inner.used_bytes += value.len();
+        inner.entries.insert(
+            key,
+            RecentWrite {
+                written: Instant::now(),
+                ctime,
+                expiry,
+                value,
+            },
+        );
+        while inner.used_bytes > self.max_bytes {
+            match inner.entries.pop_front() {
+                Some((_, evicted)) => inner.used_bytes -= evicted.value.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// The blob written to `key` and its ctime, if it was written within the
+    /// catch-up window and hasn't expired since. `now` is the current unix
+    /// timestamp, as used for expiry.
+    pub(crate) fn get(&self, key: &str, now: i64) -> Option<(i64, BlobstoreBytes)> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        inner.expire(self.max_age);
+        let entry = inner.entries.get(key)?;
+        if entry.expiry.map_or(false, |expiry| expiry <= now) {
+            inner.remove(key);
+            return None;
+        }
+        Some((entry.ctime, entry.value.clone()))
+    }
+
+    /// Forget a key, e.g. because it was unlinked.
+    pub(crate) fn remove(&self, key: &str) {
+        self.inner.lock().expect("lock poisoned").remove(key);
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.used_bytes -= old.value.len();
+        }
+    }
+
+    fn expire(&mut self, max_age: Duration) {
+        while let Some((_, oldest)) = self.entries.front() {
+            if oldest.written.elapsed() < max_age {
+                break;
+            }
+            if let Some((_, expired)) = self.entries.pop_front() {
+                self.used_bytes -= expired.value.len();
+            }
+        }
+    }
+}
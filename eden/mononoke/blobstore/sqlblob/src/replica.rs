@@ -0,0 +1,232 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Mirroring of writes to a secondary `Sqlblob`, usually a shardmap in another
+//! region. Puts, links and unlinks are queued after they succeed on the
+//! primary, and applied to the secondary in the background and in order, so
+//! the secondary never slows down or fails a write. When the queue is full,
+//! or a mirrored write fails, the write is only counted; `Sqlblob::catch_up`
+//! copies the missing keys later.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::Result;
+use blobstore::PutBehaviour;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use mononoke_types::BlobstoreBytes;
+use stats::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::migrate::copy_key;
+use crate::Sqlblob;
+
+define_stats! {
+    prefix = "mononoke.sqlblob.secondary";
+    mirrored: timeseries(Rate, Sum),
+    dropped: timeseries(Rate, Sum),
+    failed: timeseries(Rate, Sum),
+}
+
+// Number of primary keys checked against the secondary at a time.
+const CATCH_UP_PAGE_SIZE: u64 = 1000;
+
+/// Configuration of the secondary writer of a `Sqlblob`.
+#[derive(Clone, Debug)]
+pub struct SecondaryWriterOptions {
+    /// Writes waiting to be mirrored. Writes beyond this are dropped.
+    pub queue_size: usize,
+    /// Keys copied in parallel by `catch_up`.
+    pub catch_up_concurrency: usize,
+}
+
+impl Default for SecondaryWriterOptions {
+    fn default() -> Self {
+        Self {
+            queue_size: 10000,
+            catch_up_concurrency: 10,
+        }
+    }
+}
+
+/// A point in time copy of the counters of a secondary writer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecondaryWriterStats {
+    pub mirrored: u64,
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct SecondaryWriterCounters {
+    mirrored: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Result of reconciling one shard of the primary with the secondary.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CatchUpReport {
+    pub keys_checked: u64,
+    pub keys_copied: u64,
+}
+
+/// A write that succeeded on the primary, to be applied to the secondary.
+pub(crate) enum MirroredWrite {
+    Put {
+        key: String,
+        value: BlobstoreBytes,
+        put_behaviour: PutBehaviour,
+        expiry: Option<i64>,
+    },
+    Link {
+        existing_key: String,
+        link_key: String,
+    },
+    Unlink {
+        key: String,
+    },
+    /// Unlinked without lowering the generations of the chunks, so GC on the
+    /// secondary reclaims them one cycle later than on the primary.
+    UnlinkMany {
+        keys: Vec<String>,
+    },
+}
+
+impl MirroredWrite {
+    async fn apply(self, secondary: &Sqlblob) -> Result<()> {
+        match self {
+            MirroredWrite::Put {
+                key,
+                value,
+                put_behaviour,
+                expiry,
+            } => {
+                secondary
+                    .put_untimed(&key, value, put_behaviour, expiry)
+                    .await?;
+            }
+            MirroredWrite::Link {
+                existing_key,
+                link_key,
+            } => {
+                secondary.link_impl(&existing_key, &link_key).await?;
+            }
+            MirroredWrite::Unlink { key } => {
+                secondary.unlink_impl(&key).await?;
+            }
+            MirroredWrite::UnlinkMany { keys } => {
+                secondary.data_store.unlink_many(&keys).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct SecondaryWriter {
+    secondary: Arc<Sqlblob>,
+    sender: mpsc::Sender<MirroredWrite>,
+    // Taken by the background task when the first write is mirrored.
+    receiver: Mutex<Option<mpsc::Receiver<MirroredWrite>>>,
+    counters: Arc<SecondaryWriterCounters>,
+    options: SecondaryWriterOptions,
+}
+
+impl SecondaryWriter {
+    /// The background task writing to `secondary` starts with the first
+    /// mirrored write, on the runtime of that write. It stops once the writer
+    /// is dropped and the queue has drained.
+    pub(crate) fn new(secondary: Sqlblob, options: SecondaryWriterOptions) -> Self {
+        let (sender, receiver) = mpsc::channel(options.queue_size.max(1));
+        Self {
+            secondary: Arc::new(secondary),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            counters: Arc::new(SecondaryWriterCounters::default()),
+            options,
+        }
+    }
+
+    fn start(&self) {
+        let mut receiver = self.receiver.lock().expect("lock poisoned");
+        if receiver.is_none() {
+            return;
+        }
+        // Writes stay queued until there is a runtime to apply them on.
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let mut receiver = receiver.take().expect("checked above");
+        let secondary = self.secondary.clone();
+        let counters = self.counters.clone();
+        handle.spawn(async move {
+            while let Some(write) = receiver.recv().await {
+                if write.apply(&secondary).await.is_ok() {
+                    counters.mirrored.fetch_add(1, Ordering::Relaxed);
+                    STATS::mirrored.add_value(1);
+                } else {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    STATS::failed.add_value(1);
+                }
+            }
+        });
+    }
+
+    /// Queue a write that succeeded on the primary, dropping it if the queue
+    /// is full.
+    pub(crate) fn mirror(&self, write: MirroredWrite) {
+        self.start();
+        if self.sender.try_send(write).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            STATS::dropped.add_value(1);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn secondary(&self) -> &Sqlblob {
+        &self.secondary
+    }
+
+    pub(crate) fn stats(&self) -> SecondaryWriterStats {
+        SecondaryWriterStats {
+            mirrored: self.counters.mirrored.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Copy every key in shard `shard` of `primary` that is missing from the
+    /// secondary.
+    pub(crate) async fn catch_up(&self, primary: &Sqlblob, shard: usize) -> Result<CatchUpReport> {
+        let mut report = CatchUpReport::default();
+        let mut after = String::new();
+        loop {
+            let keys = primary
+                .data_store
+                .get_keys_page(shard, &after, CATCH_UP_PAGE_SIZE)
+                .await?;
+            after = match keys.last() {
+                Some(last_key) => last_key.clone(),
+                None => return Ok(report),
+            };
+            report.keys_checked += keys.len() as u64;
+
+            let present = self.secondary.data_store.is_present_many(&keys).await?;
+            let missing = keys.into_iter().filter(|key| !present.contains(key));
+            report.keys_copied += stream::iter(missing)
+                .map(|key| copy_key(primary, &self.secondary, shard, key))
+                .buffer_unordered(self.options.catch_up_concurrency.max(1))
+                .try_fold(0, |copied, was_copied| async move {
+                    Ok(copied + was_copied as u64)
+                })
+                .await?;
+        }
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Evolution of the sqlblob schema.
+//!
+//! Each shard records the migrations applied to it in a `schema_version`
+//! table. SQLite shards are brought up to date when they are opened. MySQL
+//! shards are only changed by an explicit `Sqlblob::migrate_schema`, so that
+//! schema changes are rolled out separately from binaries.
+//!
+//! Databases created before versions were recorded have no `schema_version`
+//! table; their version is worked out from the columns they have.
+//!
+//! To change the schema, update `schema/sqlite-sqlblob.sql`, bump
+//! `SCHEMA_VERSION` and add a migration from the previous version for both
+//! SQLite and MySQL.
+
+use anyhow::{bail, Result};
+use sql::{queries, rusqlite::Connection as SqliteConnection, Connection};
+
+/// The version of the schema this code expects.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// The schema as of `SCHEMA_VERSION`, used to create new SQLite shards.
+pub(crate) const CREATION_QUERY: &str = include_str!("../schema/sqlite-sqlblob.sql");
+
+const SQLITE_CREATE_SCHEMA_VERSION: &str = "CREATE TABLE IF NOT EXISTS `schema_version` (
+  `version` INT UNSIGNED NOT NULL,
+  PRIMARY KEY (`version`)
+);";
+
+/// Statements upgrading a SQLite shard to each version from the version
+/// before it.
+const SQLITE_MIGRATIONS: &[(u32, &str)] = &[
+    (
+        2,
+        "ALTER TABLE `data` ADD COLUMN `expiry_time` BIGINT NULL;",
+    ),
+    (
+        3,
+        "ALTER TABLE `data` ADD COLUMN `value_size` BIGINT NULL;
+         CREATE TABLE IF NOT EXISTS `key_prefix_usage` (
+           `key_prefix` VARCHAR(255) NOT NULL,
+           `blob_count` BIGINT NOT NULL,
+           `total_bytes` BIGINT NOT NULL,
+           PRIMARY KEY (`key_prefix`)
+         );",
+    ),
+    (4, "ALTER TABLE `data` ADD COLUMN `checksum` BIGINT NULL;"),
+];
+
+queries! {
+    write CreateSchemaVersion() {
+        none,
+        "CREATE TABLE IF NOT EXISTS `schema_version` (
+          `version` INT UNSIGNED NOT NULL,
+          PRIMARY KEY (`version`)
+        )"
+    }
+
+    read SelectSchemaVersion() -> (Option<u32>) {
+        "SELECT MAX(version) FROM schema_version"
+    }
+
+    write InsertSchemaVersion(version: u32) {
+        none,
+        "INSERT INTO schema_version (version) VALUES ({version})"
+    }
+
+    read CountColumns(table: &str, column: &str) -> (u64) {
+        "SELECT COUNT(*)
+         FROM information_schema.columns
+         WHERE table_schema = DATABASE()
+           AND table_name = {table}
+           AND column_name = {column}"
+    }
+
+    write AddExpiryTime() {
+        none,
+        "ALTER TABLE data ADD COLUMN expiry_time BIGINT NULL"
+    }
+
+    write AddValueSize() {
+        none,
+        "ALTER TABLE data ADD COLUMN value_size BIGINT NULL"
+    }
+
+    write CreateKeyPrefixUsage() {
+        none,
+        "CREATE TABLE IF NOT EXISTS key_prefix_usage (
+          `key_prefix` VARCHAR(255) NOT NULL,
+          `blob_count` BIGINT NOT NULL,
+          `total_bytes` BIGINT NOT NULL,
+          PRIMARY KEY (`key_prefix`)
+        )"
+    }
+
+    write AddChecksum() {
+        none,
+        "ALTER TABLE data ADD COLUMN checksum BIGINT NULL"
+    }
+}
+
+/// Bring a SQLite shard up to `SCHEMA_VERSION`, creating it if it's empty.
+/// Returns the version the shard was at.
+pub(crate) fn migrate_sqlite(con: &SqliteConnection) -> Result<u32> {
+    con.execute_batch(SQLITE_CREATE_SCHEMA_VERSION)?;
+    let recorded: Option<u32> =
+        con.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })?;
+    let from_version = match recorded {
+        Some(version) => version,
+        None => {
+            let has_column = |table: &str, column: &str| -> Result<bool> {
+                let count: u32 = con.query_row(
+                    "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+                    [table, column],
+                    |row| row.get(0),
+                )?;
+                Ok(count > 0)
+            };
+            if !has_column("data", "id")? {
+                con.execute_batch(CREATION_QUERY)?;
+                SCHEMA_VERSION
+            } else if has_column("data", "value_size")? {
+                3
+            } else if has_column("data", "expiry_time")? {
+                2
+            } else {
+                1
+            }
+        }
+    };
+    if from_version > SCHEMA_VERSION {
+        bail!(
+            "sqlblob schema version {} is newer than supported version {}",
+            from_version,
+            SCHEMA_VERSION
+        );
+    }
+
+    let tx = con.unchecked_transaction()?;
+    for (version, statements) in SQLITE_MIGRATIONS {
+        if *version > from_version {
+            tx.execute_batch(statements)?;
+        }
+    }
+    if recorded != Some(SCHEMA_VERSION) {
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            [SCHEMA_VERSION],
+        )?;
+    }
+    tx.commit()?;
+    Ok(from_version)
+}
+
+/// Bring a MySQL shard up to `SCHEMA_VERSION`. The shard must have been
+/// created with the sqlblob schema. Returns the version the shard was at.
+pub(crate) async fn migrate_mysql(conn: &Connection) -> Result<u32> {
+    CreateSchemaVersion::query(conn).await?;
+    let recorded = SelectSchemaVersion::query(conn)
+        .await?
+        .into_iter()
+        .next()
+        .and_then(|row| row.0);
+    let from_version = match recorded {
+        Some(version) => version,
+        None => {
+            let has_column = |table, column| async move {
+                let rows = CountColumns::query(conn, &table, &column).await?;
+                Ok::<_, anyhow::Error>(rows.first().map_or(false, |row| row.0 > 0))
+            };
+            if !has_column("data", "id").await? {
+                bail!("sqlblob schema has not been created");
+            } else if has_column("data", "value_size").await? {
+                3
+            } else if has_column("data", "expiry_time").await? {
+                2
+            } else {
+                1
+            }
+        }
+    };
+    if from_version > SCHEMA_VERSION {
+        bail!(
+            "sqlblob schema version {} is newer than supported version {}",
+            from_version,
+            SCHEMA_VERSION
+        );
+    }
+
+    // MySQL can't roll back schema changes, so record each version as it is
+    // reached.
+    for version in from_version + 1..=SCHEMA_VERSION {
+        match version {
+            2 => AddExpiryTime::query(conn).await?,
+            3 => {
+                AddValueSize::query(conn).await?;
+                CreateKeyPrefixUsage::query(conn).await?
+            }
+            4 => AddChecksum::query(conn).await?,
+            _ => bail!("no migration to sqlblob schema version {}", version),
+        };
+        InsertSchemaVersion::query(conn, &version).await?;
+    }
+    if recorded.is_none() && from_version == SCHEMA_VERSION {
+        InsertSchemaVersion::query(conn, &SCHEMA_VERSION).await?;
+    }
+    Ok(from_version)
+}
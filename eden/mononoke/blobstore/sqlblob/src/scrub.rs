@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Scrubbing of a shard: chunk ids are hashes of the blob contents, so every
+//! blob can be checked by rehashing its chunks.
+
+use anyhow::Result;
+use blobstore::Blobstore;
+use context::CoreContext;
+use futures::stream::{StreamExt, TryStreamExt};
+
+use crate::codec::decode_chunk;
+use crate::store::{Chunked, ChunkingMethod};
+use crate::{chunk_hash_context, Sqlblob, CHUNK_SIZE};
+
+// Number of keys scrubbed in parallel.
+const SCRUB_CONCURRENCY: usize = 100;
+
+/// The outcome of scrubbing a shard.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Keys whose chunks were checked.
+    pub keys_checked: u64,
+    /// Keys stored inline, which have no chunks to check.
+    pub inline_keys: u64,
+    /// Keys with missing, undecodable or mismatching chunks that could not
+    /// be repaired.
+    pub corrupt_keys: Vec<String>,
+    /// Keys that were corrupt and have been repaired.
+    pub repaired_keys: Vec<String>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_keys.is_empty() && self.repaired_keys.is_empty()
+    }
+}
+
+enum ScrubOutcome {
+    // The key was removed after it was listed.
+    Gone,
+    Inline,
+    Valid,
+    Corrupt,
+    Repaired,
+}
+
+impl Sqlblob {
+    /// Check every key in a shard by rehashing its chunks. If `repair_from`
+    /// is given (e.g. another blobstore in the same multiplex), corrupt keys
+    /// are re-fetched from it and their chunks are rewritten, provided the
+    /// fetched content has the expected hash.
+    pub async fn scrub_shard(
+        &self,
+        ctx: &CoreContext,
+        shard_num: usize,
+        repair_from: Option<&dyn Blobstore>,
+    ) -> Result<ScrubReport> {
+        self.get_keys_from_shard(shard_num)
+            .map_ok(|key| self.scrub_key(ctx, key, repair_from))
+            .try_buffer_unordered(SCRUB_CONCURRENCY)
+            .try_fold(
+                ScrubReport::default(),
+                |mut report, (key, outcome)| async move {
+                    match outcome {
+                        ScrubOutcome::Gone => {}
+                        ScrubOutcome::Inline => report.inline_keys += 1,
+                        ScrubOutcome::Valid => report.keys_checked += 1,
+                        ScrubOutcome::Corrupt => {
+                            report.keys_checked += 1;
+                            report.corrupt_keys.push(key);
+                        }
+                        ScrubOutcome::Repaired => {
+                            report.keys_checked += 1;
+                            report.repaired_keys.push(key);
+                        }
+                    }
+                    Ok(report)
+                },
+            )
+            .await
+    }
+
+    async fn scrub_key(
+        &self,
+        ctx: &CoreContext,
+        key: String,
+        repair_from: Option<&dyn Blobstore>,
+    ) -> Result<(String, ScrubOutcome)> {
+        let chunked = match self.data_store.get(&key).await? {
+            Some(chunked) => chunked,
+            None => return Ok((key, ScrubOutcome::Gone)),
+        };
+        if chunked.chunking_method == ChunkingMethod::InlineBase64 {
+            return Ok((key, ScrubOutcome::Inline));
+        }
+        if self.chunks_match(&chunked).await? {
+            return Ok((key, ScrubOutcome::Valid));
+        }
+
+        if let Some(repair_from) = repair_from {
+            if let Some(data) = repair_from.get(ctx, &key).await? {
+                let value = data.into_raw_bytes();
+                let mut hash_context = chunk_hash_context(chunked.chunking_method);
+                hash_context.update(&value);
+                if hash_context.finish().to_hex().as_str() == chunked.id {
+                    for (chunk_num, chunk) in value.chunks(CHUNK_SIZE).enumerate() {
+                        self.chunk_store
+                            .replace(
+                                &chunked.id,
+                                chunk_num.try_into()?,
+                                chunked.chunking_method,
+                                chunk,
+                            )
+                            .await?;
+                    }
+                    return Ok((key, ScrubOutcome::Repaired));
+                }
+            }
+        }
+        Ok((key, ScrubOutcome::Corrupt))
+    }
+
+    /// Rehash the stored chunks of a blob and compare with its chunk id.
+    async fn chunks_match(&self, chunked: &Chunked) -> Result<bool> {
+        let mut hash_context = chunk_hash_context(chunked.chunking_method);
+        for chunk_num in 0..chunked.count {
+            let stored = self
+                .chunk_store
+                .get_stored(&chunked.id, chunk_num, chunked.chunking_method)
+                .await?;
+            let stored = match stored {
+                Some(stored) => stored,
+                None => return Ok(false),
+            };
+            match chunked.chunking_method {
+                ChunkingMethod::ByContentHashBlake2WithCodec => match decode_chunk(&stored) {
+                    Ok(chunk) => hash_context.update(&chunk),
+                    Err(_) => return Ok(false),
+                },
+                _ => hash_context.update(&stored),
+            }
+        }
+        Ok(hash_context.finish().to_hex().as_str() == chunked.id)
+    }
+}
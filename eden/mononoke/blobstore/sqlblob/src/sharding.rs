@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Mapping of keys to the shards that hold their data rows.
+//!
+//! By default keys are hashed across all shards. Other strategies let keys of
+//! a repo be kept on dedicated shards. Chunks are shared by every key with
+//! the same content, so they are always sharded by a hash of the chunk id.
+//!
+//! To change the strategy of an existing blobstore, open it with the new
+//! strategy and the old one as `SqlblobOptions::previous_sharding`. Keys that
+//! are not on their new shard yet are then read from their old shard, while
+//! `migrate::copy_shard` from a blobstore opened with only the old strategy
+//! copies them over. Once every shard is copied, `migrate::remove_copied_keys`
+//! deletes them from their old shards.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hasher,
+    num::NonZeroUsize,
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use twox_hash::XxHash32;
+
+/// Chooses the shard of a key.
+pub trait ShardingStrategy: fmt::Debug + Send + Sync {
+    /// The shard of `key`, which must be less than `shard_count`.
+    fn shard(&self, key: &str, shard_count: NonZeroUsize) -> usize;
+
+    /// Check that the strategy can be used with `shard_count` shards.
+    fn validate(&self, _shard_count: NonZeroUsize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash keys across all shards.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HashSharding;
+
+impl ShardingStrategy for HashSharding {
+    fn shard(&self, key: &str, shard_count: NonZeroUsize) -> usize {
+        hash_shard(key, shard_count.get())
+    }
+}
+
+fn hash_shard(key: &str, shard_count: usize) -> usize {
+    let mut hasher = XxHash32::with_seed(0);
+    hasher.write(key.as_bytes());
+    (hasher.finish() % shard_count as u64) as usize
+}
+
+/// Split the key space into ranges. Each range starts at a key prefix, and
+/// holds every key from that prefix up to the start of the next range. Keys
+/// before the first range belong to the first range.
+#[derive(Clone, Debug)]
+pub struct RangeSharding {
+    ranges: BTreeMap<String, usize>,
+}
+
+impl RangeSharding {
+    /// `ranges` are pairs of the start of a range and its shard.
+    pub fn new(ranges: impl IntoIterator<Item = (String, usize)>) -> Result<Self> {
+        let ranges: BTreeMap<_, _> = ranges.into_iter().collect();
+        if ranges.is_empty() {
+            bail!("RangeSharding needs at least one range");
+        }
+        Ok(Self { ranges })
+    }
+}
+
+impl ShardingStrategy for RangeSharding {
+    fn shard(&self, key: &str, _shard_count: NonZeroUsize) -> usize {
+        match self.ranges.range::<str, _>(..=key).next_back() {
+            Some((_, shard)) => *shard,
+            None => *self.ranges.values().next().expect("ranges is not empty"),
+        }
+    }
+
+    fn validate(&self, shard_count: NonZeroUsize) -> Result<()> {
+        check_shards(self.ranges.values(), shard_count)
+    }
+}
+
+/// Put keys with a listed prefix, such as the prefix of a repo, on the shard
+/// given for that prefix. If several prefixes match, the longest wins. Other
+/// keys are hashed across `default_shards`, so that listed prefixes can have
+/// shards to themselves.
+#[derive(Clone, Debug)]
+pub struct ExplicitSharding {
+    prefixes: HashMap<String, usize>,
+    prefix_lengths: Vec<usize>,
+    default_shards: Vec<usize>,
+}
+
+impl ExplicitSharding {
+    pub fn new(prefixes: HashMap<String, usize>, default_shards: Vec<usize>) -> Result<Self> {
+        if default_shards.is_empty() {
+            bail!("ExplicitSharding needs at least one default shard");
+        }
+        let mut prefix_lengths: Vec<usize> = prefixes.keys().map(String::len).collect();
+        prefix_lengths.sort_unstable_by(|a, b| b.cmp(a));
+        prefix_lengths.dedup();
+        Ok(Self {
+            prefixes,
+            prefix_lengths,
+            default_shards,
+        })
+    }
+}
+
+impl ShardingStrategy for ExplicitSharding {
+    fn shard(&self, key: &str, _shard_count: NonZeroUsize) -> usize {
+        for len in &self.prefix_lengths {
+            if let Some(shard) = key.get(..*len).and_then(|prefix| self.prefixes.get(prefix)) {
+                return *shard;
+            }
+        }
+        self.default_shards[hash_shard(key, self.default_shards.len())]
+    }
+
+    fn validate(&self, shard_count: NonZeroUsize) -> Result<()> {
+        check_shards(
+            self.prefixes.values().chain(self.default_shards.iter()),
+            shard_count,
+        )
+    }
+}
+
+fn check_shards<'a>(
+    shards: impl IntoIterator<Item = &'a usize>,
+    shard_count: NonZeroUsize,
+) -> Result<()> {
+    for shard in shards {
+        if *shard >= shard_count.get() {
+            bail!(
+                "Sharding refers to shard {}, but there are only {} shards",
+                shard,
+                shard_count
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The sharding of keys of a blobstore, including the strategy being moved
+/// away from during resharding.
+#[derive(Clone, Debug)]
+pub(crate) struct KeySharding {
+    shard_count: NonZeroUsize,
+    current: Arc<dyn ShardingStrategy>,
+    previous: Option<Arc<dyn ShardingStrategy>>,
+}
+
+impl KeySharding {
+    pub(crate) fn new(
+        shard_count: NonZeroUsize,
+        current: Option<Arc<dyn ShardingStrategy>>,
+        previous: Option<Arc<dyn ShardingStrategy>>,
+    ) -> Result<Self> {
+        let current = current.unwrap_or_else(|| Arc::new(HashSharding));
+        current.validate(shard_count)?;
+        if let Some(previous) = &previous {
+            previous.validate(shard_count)?;
+        }
+        Ok(Self {
+            shard_count,
+            current,
+            previous,
+        })
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shard_count.get()
+    }
+
+    /// The shard `key` is written to.
+    pub(crate) fn shard(&self, key: &str) -> usize {
+        self.current.shard(key, self.shard_count)
+    }
+
+    /// The shard `key` was on before resharding, if it differs from its
+    /// current shard. Keys that have not been moved yet are found there.
+    pub(crate) fn previous_shard(&self, key: &str) -> Option<usize> {
+        let previous = self.previous.as_ref()?.shard(key, self.shard_count);
+        if previous != self.shard(key) {
+            Some(previous)
+        } else {
+            None
+        }
+    }
+}
@@ -5,20 +5,36 @@
  * GNU General Public License version 2.
  */
 
-use std::{collections::HashMap, hash::Hasher, num::NonZeroUsize, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{bail, format_err, Error};
+use blobstore::BlobstoreKeyRange;
 use bytes::BytesMut;
 use cached_config::ConfigHandle;
 use futures::{
-    future::TryFutureExt,
+    future::{join_all, try_join_all, TryFutureExt},
     stream::{self, Stream},
 };
-use sql::{queries, Connection};
-use twox_hash::XxHash32;
+use sql::{queries, Connection, Transaction};
+use twox_hash::{XxHash32, XxHash64};
 use xdb_gc_structs::XdbGc;
 
+use crate::chunk_cache::ChunkCache;
+use crate::codec::{decode_chunk, encode_chunk, ChunkCompression};
 use crate::delay::BlobDelay;
+use crate::metrics::SqlblobStats;
+use crate::schema;
+use crate::sharding::KeySharding;
+use crate::telemetry::SqlblobChunking;
 
 mod types {
     use sql::mysql;
@@ -33,6 +49,8 @@ mod types {
     pub enum ChunkingMethod {
         ByContentHashBlake2,
         InlineBase64,
+        /// Like ByContentHashBlake2, but every chunk starts with a codec header
+        ByContentHashBlake2WithCodec,
     }
 
     impl From<ChunkingMethod> for Value {
@@ -42,6 +60,7 @@ mod types {
                 // to impl ConvIr<ChunkingMethod> below
                 ChunkingMethod::ByContentHashBlake2 => Value::UInt(1),
                 ChunkingMethod::InlineBase64 => Value::UInt(2),
+                ChunkingMethod::ByContentHashBlake2WithCodec => Value::UInt(3),
             }
         }
     }
@@ -58,6 +77,11 @@ mod types {
                 Value::Int(2) => Ok(ChunkingMethod::InlineBase64),
                 Value::UInt(2) => Ok(ChunkingMethod::InlineBase64),
                 Value::Bytes(ref b) if b == b"2" => Ok(ChunkingMethod::InlineBase64),
+                Value::Int(3) => Ok(ChunkingMethod::ByContentHashBlake2WithCodec),
+                Value::UInt(3) => Ok(ChunkingMethod::ByContentHashBlake2WithCodec),
+                Value::Bytes(ref b) if b == b"3" => {
+                    Ok(ChunkingMethod::ByContentHashBlake2WithCodec)
+                }
                 // If you need to add to this error path, ensure that the type you are adding cannot be converted to an integer
                 // by MySQL
                 v @ Value::NULL
@@ -88,7 +112,7 @@ mod types {
 pub use self::types::ChunkingMethod;
 
 queries! {
-    write InsertData(values: (id: &str, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod)) {
+    write InsertData(values: (id: &str, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod, expiry_time: Option<i64>, value_size: Option<u64>, checksum: Option<i64>)) {
         insert_or_ignore,
         "{insert_or_ignore} INTO data (
             id
@@ -96,6 +120,9 @@ queries! {
             , chunk_id
             , chunk_count
             , chunking_method
+            , expiry_time
+            , value_size
+            , checksum
         ) VALUES {values}"
     }
 
@@ -104,16 +131,111 @@ queries! {
         "DELETE FROM data WHERE id = {id}"
     }
 
-    write UpdateData(id: &str, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod) {
+    write DeleteDataIfMatches(id: &str, ctime: i64, chunk_id: &str) {
+        none,
+        "DELETE FROM data WHERE id = {id} AND creation_time = {ctime} AND chunk_id = {chunk_id}"
+    }
+
+    write DeleteDataMany(>list ids: String) {
+        none,
+        "DELETE FROM data WHERE id IN {ids}"
+    }
+
+    write UpdateData(id: &str, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod, expiry_time: Option<i64>, value_size: Option<u64>, checksum: Option<i64>) {
         none,
         "UPDATE data SET
             creation_time = {ctime}
             , chunk_id = {chunk_id}
             , chunk_count = {chunk_count}
             , chunking_method = {chunking_method}
+            , expiry_time = {expiry_time}
+            , value_size = {value_size}
+            , checksum = {checksum}
         WHERE id = {id}"
     }
 
+    write UpdateDataIfExpired(id: &str, now: i64, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod, value_size: Option<u64>, checksum: Option<i64>) {
+        none,
+        "UPDATE data SET
+            creation_time = {ctime}
+            , chunk_id = {chunk_id}
+            , chunk_count = {chunk_count}
+            , chunking_method = {chunking_method}
+            , expiry_time = NULL
+            , value_size = {value_size}
+            , checksum = {checksum}
+        WHERE id = {id}
+          AND expiry_time IS NOT NULL AND expiry_time <= {now}"
+    }
+
+    write UpdateDataIfMatches(id: &str, now: i64, expected_checksum: i64, ctime: i64, chunk_id: &str, chunk_count: u32, chunking_method: ChunkingMethod, value_size: Option<u64>, checksum: Option<i64>) {
+        none,
+        "UPDATE data SET
+            creation_time = {ctime}
+            , chunk_id = {chunk_id}
+            , chunk_count = {chunk_count}
+            , chunking_method = {chunking_method}
+            , expiry_time = NULL
+            , value_size = {value_size}
+            , checksum = {checksum}
+        WHERE id = {id}
+          AND checksum = {expected_checksum}
+          AND (expiry_time IS NULL OR expiry_time > {now})"
+    }
+
+    write DeleteExpiredData(now: i64) {
+        none,
+        "DELETE FROM data WHERE expiry_time IS NOT NULL AND expiry_time <= {now}"
+    }
+
+    read SelectDataSize(id: &str) -> (Option<u64>) {
+        "SELECT value_size FROM data WHERE id = {id}"
+    }
+
+    read SelectDataStateForUpdate(id: &str) -> (Option<i64>, Option<u64>, Option<i64>) {
+        mysql("SELECT expiry_time, value_size, checksum FROM data WHERE id = {id} FOR UPDATE")
+        sqlite("SELECT expiry_time, value_size, checksum FROM data WHERE id = {id}")
+    }
+
+    read SelectDataSizeMany(>list ids: String) -> (Vec<u8>, Option<u64>) {
+        "SELECT id, value_size FROM data WHERE id IN {ids}"
+    }
+
+    read SelectExpiredDataSizeForUpdate(now: i64) -> (Vec<u8>, Option<u64>) {
+        mysql(
+            "SELECT id, value_size FROM data
+             WHERE expiry_time IS NOT NULL AND expiry_time <= {now}
+             FOR UPDATE"
+        )
+        sqlite(
+            "SELECT id, value_size FROM data
+             WHERE expiry_time IS NOT NULL AND expiry_time <= {now}"
+        )
+    }
+
+    write InsertKeyPrefixUsage(values: (key_prefix: &str, blob_count: i64, total_bytes: i64)) {
+        insert_or_ignore,
+        "{insert_or_ignore} INTO key_prefix_usage (
+            key_prefix
+            , blob_count
+            , total_bytes
+        ) VALUES {values}"
+    }
+
+    write UpdateKeyPrefixUsage(key_prefix: &str, blob_count: i64, total_bytes: i64) {
+        none,
+        "UPDATE key_prefix_usage SET
+            blob_count = blob_count + {blob_count}
+            , total_bytes = total_bytes + {total_bytes}
+        WHERE key_prefix = {key_prefix}"
+    }
+
+    read SelectKeyPrefixUsage() -> (Vec<u8>, i64, i64) {
+        "SELECT key_prefix, blob_count, total_bytes
+         FROM key_prefix_usage
+         ORDER BY key_prefix"
+    }
+
     write InsertChunk(values: (id: &str, chunk_num: u32, value: &[u8])) {
         insert_or_ignore,
         "{insert_or_ignore} INTO chunk (
@@ -123,6 +245,12 @@ queries! {
         ) VALUES {values}"
     }
 
+    write UpdateChunk(id: &str, chunk_num: u32, value: &[u8]) {
+        none,
+        "UPDATE chunk SET value = {value}
+            WHERE id = {id} AND chunk_num = {chunk_num}"
+    }
+
     write UpdateGeneration(id: &str, generation: u64) {
         none,
         "UPDATE chunk_generation
@@ -130,16 +258,39 @@ queries! {
             WHERE id = {id} AND last_seen_generation < {generation}"
     }
 
-    read SelectData(id: &str) -> (i64, Vec<u8>, u32, ChunkingMethod) {
-        "SELECT creation_time, chunk_id, chunk_count, chunking_method
+    write LowerGenerations(generation: u64, >list ids: String) {
+        none,
+        "UPDATE chunk_generation
+            SET last_seen_generation = {generation}
+            WHERE id IN {ids} AND last_seen_generation > {generation}"
+    }
+
+    read SelectData(id: &str, now: i64) -> (i64, Vec<u8>, u32, ChunkingMethod, Option<i64>, Option<u64>, Option<i64>) {
+        "SELECT creation_time, chunk_id, chunk_count, chunking_method, expiry_time, value_size, checksum
+         FROM data
+         WHERE id = {id}
+           AND (expiry_time IS NULL OR expiry_time > {now})"
+    }
+
+    read SelectDataMany(now: i64, >list ids: String) -> (Vec<u8>, i64, Vec<u8>, u32, ChunkingMethod, Option<i64>, Option<u64>, Option<i64>) {
+        "SELECT id, creation_time, chunk_id, chunk_count, chunking_method, expiry_time, value_size, checksum
          FROM data
-         WHERE id = {id}"
+         WHERE id IN {ids}
+           AND (expiry_time IS NULL OR expiry_time > {now})"
     }
 
-    read SelectIsDataPresent(id: &str) -> (i32) {
+    read SelectIsDataPresentMany(now: i64, >list ids: String) -> (Vec<u8>) {
+        "SELECT id
+         FROM data
+         WHERE id IN {ids}
+           AND (expiry_time IS NULL OR expiry_time > {now})"
+    }
+
+    read SelectIsDataPresent(id: &str, now: i64) -> (i32) {
         "SELECT 1
          FROM data
-         WHERE id = {id}"
+         WHERE id = {id}
+           AND (expiry_time IS NULL OR expiry_time > {now})"
     }
 
     read SelectChunk(id: &str, chunk_num: u32) -> (Vec<u8>) {
@@ -149,6 +300,12 @@ queries! {
            AND chunk_num = {chunk_num}"
     }
 
+    read SelectChunksMany(>list ids: String >list chunk_nums: u32) -> (Vec<u8>, u32, Vec<u8>) {
+        "SELECT id, chunk_num, value
+         FROM chunk
+         WHERE id IN {ids} AND chunk_num IN {chunk_nums}"
+    }
+
     read GetChunkGeneration(id: &str) -> (u64) {
         "SELECT last_seen_generation
         FROM chunk_generation
@@ -168,8 +325,34 @@ queries! {
             WHERE chunk_generation.last_seen_generation IS NULL"
     }
 
-    read GetAllKeys() -> (Vec<u8>) {
-        "SELECT id FROM data"
+    read GetAllKeys(now: i64) -> (Vec<u8>) {
+        "SELECT id FROM data WHERE expiry_time IS NULL OR expiry_time > {now}"
+    }
+
+    read GetKeysPage(after: &str, now: i64, limit: u64) -> (Vec<u8>) {
+        "SELECT id FROM data
+         WHERE id > {after}
+           AND (expiry_time IS NULL OR expiry_time > {now})
+         ORDER BY id
+         LIMIT {limit}"
+    }
+
+    read GetKeysRangeFrom(begin: &str, end: &str, now: i64, limit: u64) -> (Vec<u8>) {
+        "SELECT id FROM data
+         WHERE id >= {begin}
+           AND ({end} = '' OR id <= {end})
+           AND (expiry_time IS NULL OR expiry_time > {now})
+         ORDER BY id
+         LIMIT {limit}"
+    }
+
+    read GetKeysRangeAfter(after: &str, end: &str, now: i64, limit: u64) -> (Vec<u8>) {
+        "SELECT id FROM data
+         WHERE id > {after}
+           AND ({end} = '' OR id <= {end})
+           AND (expiry_time IS NULL OR expiry_time > {now})
+         ORDER BY id
+         LIMIT {limit}"
     }
 
     read GetGenerationSizes() -> (Option<u64>, u64) {
@@ -177,64 +360,611 @@ queries! {
         FROM chunk LEFT JOIN chunk_generation ON chunk.id = chunk_generation.id
         GROUP BY chunk_generation.last_seen_generation"
     }
+
+    read GetSweepableSize(delete_generation: u64) -> (u64, Option<u64>) {
+        "SELECT COUNT(*), CAST(SUM(LENGTH(chunk.value)) AS UNSIGNED)
+        FROM chunk JOIN chunk_generation ON chunk.id = chunk_generation.id
+        WHERE chunk_generation.last_seen_generation <= {delete_generation}"
+    }
+
+    read SelectSweepableIds(delete_generation: u64, limit: u64) -> (Vec<u8>) {
+        "SELECT id FROM chunk_generation
+         WHERE last_seen_generation <= {delete_generation}
+         LIMIT {limit}"
+    }
+
+    write DeleteSweepableChunks(delete_generation: u64, >list ids: String) {
+        none,
+        "DELETE FROM chunk WHERE id IN (
+            SELECT id FROM chunk_generation
+            WHERE id IN {ids} AND last_seen_generation <= {delete_generation}
+        )"
+    }
+
+    write DeleteSweepableGenerations(delete_generation: u64, >list ids: String) {
+        none,
+        "DELETE FROM chunk_generation
+            WHERE id IN {ids} AND last_seen_generation <= {delete_generation}"
+    }
+}
+
+/// Fetch the wanted (id, chunk_num) pairs present on this connection. The
+/// query filters on both the ids and the chunk numbers, so it can return a
+/// few pairs that were not asked for; those are dropped.
+async fn select_chunks(
+    connection: &Connection,
+    wanted: &[(String, u32)],
+) -> Result<Vec<((String, u32), Vec<u8>)>, Error> {
+    let ids = wanted
+        .iter()
+        .map(|(id, _)| id.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let chunk_nums = wanted
+        .iter()
+        .map(|(_, chunk_num)| *chunk_num)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let wanted = wanted.iter().collect::<HashSet<_>>();
+    let rows = SelectChunksMany::query(connection, &ids[..], &chunk_nums[..]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|(id, chunk_num, value)| {
+            ((String::from_utf8_lossy(&id).to_string(), chunk_num), value)
+        })
+        .filter(|(key, _)| wanted.contains(key))
+        .collect())
 }
 
+// Limits on the number of ids in a single batched query. Chunks are up to
+// CHUNK_SIZE each, so fetch fewer of them at a time.
+const MAX_DATA_IDS_PER_QUERY: usize = 500;
+const MAX_CHUNK_IDS_PER_QUERY: usize = 16;
+
 pub struct Chunked {
     pub id: String,
     pub count: u32,
     pub ctime: i64,
     pub chunking_method: ChunkingMethod,
+    /// Time after which the blob is treated as absent, in seconds since the
+    /// epoch, or None if it never expires.
+    pub expiry: Option<i64>,
+    /// Size of the blob in bytes, or None if it was written before sizes
+    /// were recorded.
+    pub value_size: Option<u64>,
+    /// Checksum of the blob, or None if it was written before checksums
+    /// were recorded. See `value_checksum`.
+    pub checksum: Option<i64>,
+}
+
+impl Chunked {
+    pub(crate) fn metadata(&self) -> BlobMetadata {
+        let size = match self.chunking_method {
+            // The inline value is in the data row, so its size is known even
+            // for blobs written before sizes were recorded.
+            ChunkingMethod::InlineBase64 => self
+                .value_size
+                .or_else(|| Some(self.id.len() as u64 * 3 / 4)),
+            ChunkingMethod::ByContentHashBlake2 | ChunkingMethod::ByContentHashBlake2WithCodec => {
+                self.value_size
+            }
+        };
+        BlobMetadata {
+            size,
+            ctime: self.ctime,
+            chunk_count: self.count,
+            chunking_method: self.chunking_method.into(),
+        }
+    }
+}
+
+/// What is known about a blob from its data row, without fetching its
+/// chunks. See `Sqlblob::get_metadata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobMetadata {
+    /// Size of the blob in bytes, or None if it is stored in chunks and was
+    /// written before sizes were recorded.
+    pub size: Option<u64>,
+    pub ctime: i64,
+    /// Number of chunks the blob is stored in. Inline blobs have none.
+    pub chunk_count: u32,
+    pub chunking_method: SqlblobChunking,
+}
+
+/// The checksum stored with a blob: the xxhash64 of its contents, stored as a
+/// signed integer as that is what the column holds.
+pub(crate) fn value_checksum(value: &[u8]) -> i64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(value);
+    hasher.finish() as i64
+}
+
+/// Storage used by the keys in a shard that share a key prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPrefixUsage {
+    pub key_prefix: String,
+    pub blob_count: u64,
+    /// Total size of the blobs before chunking and compression. Blobs that
+    /// share chunks are each counted in full.
+    pub total_bytes: u64,
+}
+
+/// The part of a key that usage is accounted by: its first two
+/// `.`-separated components, which for repo keys are the repo prefix and
+/// the type of the blob, e.g. `repo0001.content`.
+pub fn key_prefix(key: &str) -> &str {
+    match key.match_indices('.').nth(1) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
+
+/// How often usage changes accumulated by writes are flushed to the
+/// `key_prefix_usage` table.
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Changes to the usage of a shard made by a write, by key prefix. Blobs
+/// without a recorded size are not accounted.
+#[derive(Clone, Default)]
+struct UsageDelta(HashMap<String, (i64, i64)>);
+
+impl UsageDelta {
+    fn add(&mut self, key: &str, value_size: Option<u64>) {
+        self.record(key, value_size, 1);
+    }
+
+    fn remove(&mut self, key: &str, value_size: Option<u64>) {
+        self.record(key, value_size, -1);
+    }
+
+    fn record(&mut self, key: &str, value_size: Option<u64>, sign: i64) {
+        if let Some(value_size) = value_size {
+            let (blob_count, total_bytes) = self.0.entry(key_prefix(key).to_string()).or_default();
+            *blob_count += sign;
+            *total_bytes += sign * value_size as i64;
+        }
+    }
+
+    fn merge(&mut self, other: UsageDelta) {
+        for (prefix, (blob_count, total_bytes)) in other.0 {
+            let entry = self.0.entry(prefix).or_default();
+            entry.0 += blob_count;
+            entry.1 += total_bytes;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.values().all(|delta| *delta == (0, 0))
+    }
+
+    async fn apply(self, mut txn: Transaction) -> Result<Transaction, Error> {
+        for (prefix, (blob_count, total_bytes)) in self.0 {
+            if blob_count == 0 && total_bytes == 0 {
+                continue;
+            }
+            let (t, _) =
+                InsertKeyPrefixUsage::query_with_transaction(txn, &[(&prefix.as_str(), &0, &0)])
+                    .await?;
+            let (t, _) = UpdateKeyPrefixUsage::query_with_transaction(
+                t,
+                &prefix.as_str(),
+                &blob_count,
+                &total_bytes,
+            )
+            .await?;
+            txn = t;
+        }
+        Ok(txn)
+    }
+}
+
+/// Usage changes made by writes, kept in memory per shard so that writes
+/// don't all update the same few `key_prefix_usage` rows. They are flushed
+/// every `USAGE_FLUSH_INTERVAL` by a background task, and before usage is
+/// read. Changes that were not flushed yet are lost if the process exits,
+/// and writes read the sizes they replace or delete without locking the
+/// rows, so the usage table is an estimate.
+struct UsageAccounting {
+    write_connection: Arc<Vec<Connection>>,
+    pending: Vec<Mutex<UsageDelta>>,
+    flusher_started: AtomicBool,
+}
+
+impl UsageAccounting {
+    fn new(write_connection: Arc<Vec<Connection>>) -> Self {
+        let pending = write_connection
+            .iter()
+            .map(|_| Mutex::new(UsageDelta::default()))
+            .collect();
+        Self {
+            write_connection,
+            pending,
+            flusher_started: AtomicBool::new(false),
+        }
+    }
+
+    /// Record the usage changed by a committed write to `shard_id`.
+    fn record(self: &Arc<Self>, shard_id: usize, delta: UsageDelta) {
+        if delta.is_empty() {
+            return;
+        }
+        self.pending[shard_id]
+            .lock()
+            .expect("lock poisoned")
+            .merge(delta);
+        self.start_flusher();
+    }
+
+    /// Start the background flush on the first write. It stops once the
+    /// store is dropped. Outside of a runtime, changes are only flushed
+    /// when usage is read.
+    fn start_flusher(self: &Arc<Self>) {
+        if self.flusher_started.load(Ordering::Relaxed) {
+            return;
+        }
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        if self.flusher_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let usage = Arc::downgrade(self);
+        handle.spawn(async move {
+            let mut interval = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let usage = match Weak::upgrade(&usage) {
+                    Some(usage) => usage,
+                    None => break,
+                };
+                usage.flush().await;
+            }
+        });
+    }
+
+    /// Write the pending changes of `shard_id` to its usage table.
+    async fn flush_shard(&self, shard_id: usize) -> Result<(), Error> {
+        let delta = std::mem::take(&mut *self.pending[shard_id].lock().expect("lock poisoned"));
+        if delta.is_empty() {
+            return Ok(());
+        }
+        let res = async {
+            let txn = self.write_connection[shard_id].start_transaction().await?;
+            let txn = delta.clone().apply(txn).await?;
+            txn.commit().await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if res.is_err() {
+            self.pending[shard_id]
+                .lock()
+                .expect("lock poisoned")
+                .merge(delta);
+        }
+        res
+    }
+
+    /// Write the pending changes of every shard. Failed shards keep their
+    /// changes pending, to be retried on the next flush.
+    async fn flush(&self) {
+        join_all((0..self.pending.len()).map(|shard_id| self.flush_shard(shard_id))).await;
+    }
+}
+
+/// Seconds since the epoch, as stored in the `creation_time` and
+/// `expiry_time` columns.
+pub(crate) fn current_timestamp() -> i64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(offset) => offset.as_secs() as i64,
+        Err(negative) => -(negative.duration().as_secs() as i64),
+    }
+}
+
+/// The usage freed by deleting the data rows with the given ids and sizes.
+fn removed_usage(rows: Vec<(Vec<u8>, Option<u64>)>) -> UsageDelta {
+    let mut usage = UsageDelta::default();
+    for (id, value_size) in rows {
+        usage.remove(&String::from_utf8_lossy(&id), value_size);
+    }
+    usage
+}
+
+fn chunked_from_row(
+    (ctime, chunk_id, chunk_count, chunking_method, expiry, value_size, checksum): (
+        i64,
+        Vec<u8>,
+        u32,
+        ChunkingMethod,
+        Option<i64>,
+        Option<u64>,
+        Option<i64>,
+    ),
+) -> Chunked {
+    Chunked {
+        id: String::from_utf8_lossy(&chunk_id).to_string(),
+        count: chunk_count,
+        ctime,
+        chunking_method,
+        expiry,
+        value_size,
+        checksum,
+    }
+}
+
+fn group_by_shard<T>(items: impl IntoIterator<Item = (usize, T)>) -> HashMap<usize, Vec<T>> {
+    let mut by_shard: HashMap<usize, Vec<T>> = HashMap::new();
+    for (shard_id, item) in items {
+        by_shard.entry(shard_id).or_default().push(item);
+    }
+    by_shard
+}
+
+/// Split per-shard items into batches of at most `batch_size`.
+fn shard_batches<T: Clone>(
+    by_shard: HashMap<usize, Vec<T>>,
+    batch_size: usize,
+) -> Vec<(usize, Vec<T>)> {
+    by_shard
+        .into_iter()
+        .flat_map(|(shard_id, items)| {
+            items
+                .chunks(batch_size)
+                .map(|batch| (shard_id, batch.to_vec()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// What a conditional put checks before writing the data row of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PutCondition {
+    /// The key is absent or expired.
+    Absent,
+    /// The key is present, and its value has this checksum. See
+    /// `value_checksum`.
+    Matches(i64),
 }
 
 #[derive(Clone)]
 pub(crate) struct DataSqlStore {
-    shard_count: NonZeroUsize,
+    sharding: KeySharding,
     write_connection: Arc<Vec<Connection>>,
     read_connection: Arc<Vec<Connection>>,
     read_master_connection: Arc<Vec<Connection>>,
     delay: BlobDelay,
+    usage: Arc<UsageAccounting>,
 }
 
 impl DataSqlStore {
     pub(crate) fn new(
-        shard_count: NonZeroUsize,
+        sharding: KeySharding,
         write_connection: Arc<Vec<Connection>>,
         read_connection: Arc<Vec<Connection>>,
         read_master_connection: Arc<Vec<Connection>>,
         delay: BlobDelay,
     ) -> Self {
+        let usage = Arc::new(UsageAccounting::new(write_connection.clone()));
         Self {
-            shard_count,
+            sharding,
             write_connection,
             read_connection,
             read_master_connection,
             delay,
+            usage,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_sharding(&self, sharding: KeySharding) -> Self {
+        Self {
+            sharding,
+            ..self.clone()
         }
     }
 
     pub(crate) async fn get(&self, key: &str) -> Result<Option<Chunked>, Error> {
-        let shard_id = self.shard(key);
+        self.get_unexpired_at(key, current_timestamp()).await
+    }
 
-        let rows = {
-            let rows = SelectData::query(&self.read_connection[shard_id], &key).await?;
+    /// Like `get`, but also returns keys that have expired. GC uses this to
+    /// mark keys that expire after it has listed them.
+    pub(crate) async fn get_including_expired(&self, key: &str) -> Result<Option<Chunked>, Error> {
+        self.get_unexpired_at(key, i64::MIN).await
+    }
+
+    async fn get_unexpired_at(&self, key: &str, now: i64) -> Result<Option<Chunked>, Error> {
+        // During resharding, keys that were not moved yet are on their
+        // previous shard.
+        let shards = std::iter::once(self.shard(key)).chain(self.sharding.previous_shard(key));
+        let mut rows = Vec::new();
+        for shard_id in shards {
+            rows = SelectData::query(&self.read_connection[shard_id], &key, &now).await?;
             if rows.is_empty() {
-                SelectData::query(&self.read_master_connection[shard_id], &key).await?
-            } else {
-                rows
+                rows =
+                    SelectData::query(&self.read_master_connection[shard_id], &key, &now).await?;
             }
-        };
+            if !rows.is_empty() {
+                break;
+            }
+        }
 
+        Ok(rows.into_iter().next().map(chunked_from_row))
+    }
+
+    /// The unexpired data row of `key` on `shard_id`, read from the master
+    /// so that rows deleted or written concurrently are seen. Keys are
+    /// copied between shards with this.
+    pub(crate) async fn get_in_shard(
+        &self,
+        shard_id: usize,
+        key: &str,
+    ) -> Result<Option<Chunked>, Error> {
+        let rows = SelectData::query(
+            &self.read_master_connection[shard_id],
+            &key,
+            &current_timestamp(),
+        )
+        .await?;
+        Ok(rows.into_iter().next().map(chunked_from_row))
+    }
+
+    /// Fetch the data rows for many keys, with one query per shard per batch
+    /// of keys. Missing keys are absent from the result.
+    pub(crate) async fn get_many(
+        &self,
+        keys: &[String],
+    ) -> Result<HashMap<String, Chunked>, Error> {
+        let keys = keys.iter().collect::<HashSet<_>>();
+        let by_shard = group_by_shard(keys.iter().map(|key| (self.shard(key), (*key).clone())));
+        let mut found = self.get_many_by_shard(by_shard).await?;
+
+        let by_previous_shard = group_by_shard(keys.into_iter().filter_map(|key| {
+            if found.contains_key(key) {
+                return None;
+            }
+            let shard_id = self.sharding.previous_shard(key)?;
+            Some((shard_id, key.clone()))
+        }));
+        if !by_previous_shard.is_empty() {
+            found.extend(self.get_many_by_shard(by_previous_shard).await?);
+        }
+        Ok(found)
+    }
+
+    async fn get_many_by_shard(
+        &self,
+        by_shard: HashMap<usize, Vec<String>>,
+    ) -> Result<HashMap<String, Chunked>, Error> {
+        let batches = shard_batches(by_shard, MAX_DATA_IDS_PER_QUERY);
+        let results = try_join_all(
+            batches
+                .into_iter()
+                .map(|(shard_id, keys)| self.get_many_in_shard(shard_id, keys)),
+        )
+        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    async fn get_many_in_shard(
+        &self,
+        shard_id: usize,
+        keys: Vec<String>,
+    ) -> Result<Vec<(String, Chunked)>, Error> {
+        let now = current_timestamp();
+        let mut rows =
+            SelectDataMany::query(&self.read_connection[shard_id], &now, &keys[..]).await?;
+        if rows.len() < keys.len() {
+            let missing = {
+                let found: HashSet<&[u8]> = rows.iter().map(|row| row.0.as_slice()).collect();
+                keys.into_iter()
+                    .filter(|key| !found.contains(key.as_bytes()))
+                    .collect::<Vec<_>>()
+            };
+            if !missing.is_empty() {
+                rows.extend(
+                    SelectDataMany::query(
+                        &self.read_master_connection[shard_id],
+                        &now,
+                        &missing[..],
+                    )
+                    .await?,
+                );
+            }
+        }
         Ok(rows
             .into_iter()
-            .next()
-            .map(|(ctime, chunk_id, chunk_count, chunking_method)| Chunked {
-                id: String::from_utf8_lossy(&chunk_id).to_string(),
-                count: chunk_count,
-                ctime,
-                chunking_method,
-            }))
+            .map(
+                |(
+                    id,
+                    ctime,
+                    chunk_id,
+                    chunk_count,
+                    chunking_method,
+                    expiry,
+                    value_size,
+                    checksum,
+                )| {
+                    (
+                        String::from_utf8_lossy(&id).to_string(),
+                        Chunked {
+                            id: String::from_utf8_lossy(&chunk_id).to_string(),
+                            count: chunk_count,
+                            ctime,
+                            chunking_method,
+                            expiry,
+                            value_size,
+                            checksum,
+                        },
+                    )
+                },
+            )
+            .collect())
+    }
+
+    /// Returns the subset of `keys` that are present.
+    pub(crate) async fn is_present_many(&self, keys: &[String]) -> Result<HashSet<String>, Error> {
+        let keys = keys.iter().collect::<HashSet<_>>();
+        let by_shard = group_by_shard(keys.iter().map(|key| (self.shard(key), (*key).clone())));
+        let mut present = self.is_present_many_by_shard(by_shard).await?;
+
+        let by_previous_shard = group_by_shard(keys.into_iter().filter_map(|key| {
+            if present.contains(key) {
+                return None;
+            }
+            let shard_id = self.sharding.previous_shard(key)?;
+            Some((shard_id, key.clone()))
+        }));
+        if !by_previous_shard.is_empty() {
+            present.extend(self.is_present_many_by_shard(by_previous_shard).await?);
+        }
+        Ok(present)
+    }
+
+    async fn is_present_many_by_shard(
+        &self,
+        by_shard: HashMap<usize, Vec<String>>,
+    ) -> Result<HashSet<String>, Error> {
+        let batches = shard_batches(by_shard, MAX_DATA_IDS_PER_QUERY);
+        let now = current_timestamp();
+        let results = try_join_all(batches.into_iter().map(|(shard_id, keys)| async move {
+            let mut present: HashSet<String> =
+                SelectIsDataPresentMany::query(&self.read_connection[shard_id], &now, &keys[..])
+                    .await?
+                    .into_iter()
+                    .map(|(id,)| String::from_utf8_lossy(&id).to_string())
+                    .collect();
+            if present.len() < keys.len() {
+                let missing = keys
+                    .into_iter()
+                    .filter(|key| !present.contains(key))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    present.extend(
+                        SelectIsDataPresentMany::query(
+                            &self.read_master_connection[shard_id],
+                            &now,
+                            &missing[..],
+                        )
+                        .await?
+                        .into_iter()
+                        .map(|(id,)| String::from_utf8_lossy(&id).to_string()),
+                    );
+                }
+            }
+            Ok::<_, Error>(present)
+        }))
+        .await?;
+        Ok(results.into_iter().flatten().collect())
     }
 
+    /// Write the data row for a key, and account for its size in the usage
+    /// of its key prefix. The size of a row that is replaced is read without
+    /// locking it, so the usage change is approximate when the key is written
+    /// concurrently.
     pub(crate) async fn put(
         &self,
         key: &str,
@@ -242,17 +972,31 @@ impl DataSqlStore {
         chunk_id: &str,
         chunk_count: u32,
         chunking_method: ChunkingMethod,
+        expiry: Option<i64>,
+        value_size: Option<u64>,
+        checksum: Option<i64>,
     ) -> Result<(), Error> {
         let shard_id = self.shard(key);
 
-        self.delay.delay(shard_id).await;
+        let _permit = self.delay.delay(shard_id).await;
 
+        let mut usage = UsageDelta::default();
         let res = InsertData::query(
             &self.write_connection[shard_id],
-            &[(&key, &ctime, &chunk_id, &chunk_count, &chunking_method)],
+            &[(
+                &key,
+                &ctime,
+                &chunk_id,
+                &chunk_count,
+                &chunking_method,
+                &expiry,
+                &value_size,
+                &checksum,
+            )],
         )
         .await?;
         if res.affected_rows() == 0 {
+            let existing = SelectDataSize::query(&self.write_connection[shard_id], &key).await?;
             UpdateData::query(
                 &self.write_connection[shard_id],
                 &key,
@@ -260,36 +1004,289 @@ impl DataSqlStore {
                 &chunk_id,
                 &chunk_count,
                 &chunking_method,
+                &expiry,
+                &value_size,
+                &checksum,
             )
             .await?;
+            for (existing_size,) in existing {
+                usage.remove(key, existing_size);
+            }
         }
+        usage.add(key, value_size);
+        self.usage.record(shard_id, usage);
         Ok(())
     }
 
-    pub(crate) async fn unlink(&self, key: &str) -> Result<(), Error> {
+    /// Write the data row for a key unless its shard has one already, e.g.
+    /// one written since the key was read from where it is copied from.
+    /// Returns whether it was written.
+    pub(crate) async fn put_if_absent(
+        &self,
+        key: &str,
+        ctime: i64,
+        chunk_id: &str,
+        chunk_count: u32,
+        chunking_method: ChunkingMethod,
+        expiry: Option<i64>,
+        value_size: Option<u64>,
+        checksum: Option<i64>,
+    ) -> Result<bool, Error> {
         let shard_id = self.shard(key);
 
-        self.delay.delay(shard_id).await;
+        let _permit = self.delay.delay(shard_id).await;
 
-        // Deleting from data table does not remove the chunks as they are content addressed.  GC checks for orphaned chunks and removes them.
-        let res = DeleteData::query(&self.write_connection[shard_id], &key).await?;
-        if res.affected_rows() != 1 {
+        let txn = self.write_connection[shard_id].start_transaction().await?;
+        let (txn, res) = InsertData::query_with_transaction(
+            txn,
+            &[(
+                &key,
+                &ctime,
+                &chunk_id,
+                &chunk_count,
+                &chunking_method,
+                &expiry,
+                &value_size,
+                &checksum,
+            )],
+        )
+        .await?;
+        if res.affected_rows() == 0 {
+            txn.rollback().await?;
+            return Ok(false);
+        }
+
+        let mut usage = UsageDelta::default();
+        usage.add(key, value_size);
+        txn.commit().await?;
+        self.usage.record(shard_id, usage);
+        Ok(true)
+    }
+
+    /// Write the data row for a key that never expires, if `condition`
+    /// holds. Returns whether it was written.
+    ///
+    /// The row is locked while the condition is checked, and the write
+    /// checks it again, so of concurrent conditional puts of a key only one
+    /// can succeed. This only holds within a shard, so it fails while the
+    /// key is being moved from its previous shard.
+    pub(crate) async fn put_if(
+        &self,
+        key: &str,
+        condition: PutCondition,
+        ctime: i64,
+        chunk_id: &str,
+        chunk_count: u32,
+        chunking_method: ChunkingMethod,
+        value_size: u64,
+        checksum: i64,
+    ) -> Result<bool, Error> {
+        if self.sharding.previous_shard(key).is_some() {
             bail!(
-                "Unexpected row_count {} from sqlblob unlink for {}",
-                res.affected_rows(),
+                "Conditional put of {} is not supported while it is being resharded",
                 key
             );
         }
+        let shard_id = self.shard(key);
+
+        let _permit = self.delay.delay(shard_id).await;
+
+        let now = current_timestamp();
+        let value_size = Some(value_size);
+        let checksum = Some(checksum);
+        let txn = self.write_connection[shard_id].start_transaction().await?;
+        let (txn, existing) = SelectDataStateForUpdate::query_with_transaction(txn, &key).await?;
+        let existing = existing.into_iter().next();
+        let live = existing.filter(|(expiry, _, _)| expiry.map_or(true, |expiry| expiry > now));
+        let txn = match (condition, existing, live) {
+            (PutCondition::Absent, None, _) => {
+                let (txn, res) = InsertData::query_with_transaction(
+                    txn,
+                    &[(
+                        &key,
+                        &ctime,
+                        &chunk_id,
+                        &chunk_count,
+                        &chunking_method,
+                        &None,
+                        &value_size,
+                        &checksum,
+                    )],
+                )
+                .await?;
+                if res.affected_rows() == 0 {
+                    // Inserted concurrently.
+                    txn.rollback().await?;
+                    return Ok(false);
+                }
+                txn
+            }
+            (PutCondition::Absent, Some(_), None) => {
+                let (txn, _) = UpdateDataIfExpired::query_with_transaction(
+                    txn,
+                    &key,
+                    &now,
+                    &ctime,
+                    &chunk_id,
+                    &chunk_count,
+                    &chunking_method,
+                    &value_size,
+                    &checksum,
+                )
+                .await?;
+                txn
+            }
+            (PutCondition::Matches(expected), _, Some((_, _, Some(existing))))
+                if existing == expected =>
+            {
+                let (txn, _) = UpdateDataIfMatches::query_with_transaction(
+                    txn,
+                    &key,
+                    &now,
+                    &expected,
+                    &ctime,
+                    &chunk_id,
+                    &chunk_count,
+                    &chunking_method,
+                    &value_size,
+                    &checksum,
+                )
+                .await?;
+                txn
+            }
+            _ => {
+                txn.rollback().await?;
+                return Ok(false);
+            }
+        };
+
+        let mut usage = UsageDelta::default();
+        if let Some((_, existing_size, _)) = existing {
+            usage.remove(key, existing_size);
+        }
+        usage.add(key, value_size);
+        txn.commit().await?;
+        self.usage.record(shard_id, usage);
+        Ok(true)
+    }
+
+    pub(crate) async fn unlink(&self, key: &str) -> Result<(), Error> {
+        // During resharding, the key might also be on its previous shard.
+        // That copy goes first: a copy of the key to its new shard that
+        // finds it still there is then removed by the second delete.
+        let mut unlinked = false;
+        if let Some(shard_id) = self.sharding.previous_shard(key) {
+            unlinked |= self.unlink_in_shard(shard_id, key).await?;
+        }
+        unlinked |= self.unlink_in_shard(self.shard(key), key).await?;
+        if !unlinked {
+            bail!("Unexpected row_count 0 from sqlblob unlink for {}", key);
+        }
         Ok(())
     }
 
+    /// Delete the data row of `key` from `shard_id`. Returns whether there
+    /// was one.
+    pub(crate) async fn unlink_in_shard(&self, shard_id: usize, key: &str) -> Result<bool, Error> {
+        let _permit = self.delay.delay(shard_id).await;
+
+        // Deleting from data table does not remove the chunks as they are content addressed.  GC checks for orphaned chunks and removes them.
+        // The size is read without locking the row, so the usage change is
+        // approximate when the key is written concurrently.
+        let existing = SelectDataSize::query(&self.write_connection[shard_id], &key).await?;
+        let res = DeleteData::query(&self.write_connection[shard_id], &key).await?;
+        match res.affected_rows() {
+            0 => return Ok(false),
+            1 => {}
+            affected_rows => bail!(
+                "Unexpected row_count {} from sqlblob unlink for {}",
+                affected_rows,
+                key
+            ),
+        }
+
+        let mut usage = UsageDelta::default();
+        for (existing_size,) in existing {
+            usage.remove(key, existing_size);
+        }
+        self.usage.record(shard_id, usage);
+        Ok(true)
+    }
+
+    /// Delete the data row of `key` from `shard_id` if it is still the one
+    /// with `ctime` and `chunk_id`. Returns whether it was deleted.
+    pub(crate) async fn unlink_in_shard_if_matches(
+        &self,
+        shard_id: usize,
+        key: &str,
+        ctime: i64,
+        chunk_id: &str,
+    ) -> Result<bool, Error> {
+        let _permit = self.delay.delay(shard_id).await;
+
+        let existing = SelectDataSize::query(&self.write_connection[shard_id], &key).await?;
+        let res =
+            DeleteDataIfMatches::query(&self.write_connection[shard_id], &key, &ctime, &chunk_id)
+                .await?;
+        if res.affected_rows() == 0 {
+            return Ok(false);
+        }
+
+        let mut usage = UsageDelta::default();
+        for (existing_size,) in existing {
+            usage.remove(key, existing_size);
+        }
+        self.usage.record(shard_id, usage);
+        Ok(true)
+    }
+
+    /// Delete the data rows for many keys, with one statement per shard per
+    /// batch of keys. Returns how many rows were deleted.
+    pub(crate) async fn unlink_many(&self, keys: &[String]) -> Result<u64, Error> {
+        let keys = keys.iter().collect::<HashSet<_>>();
+        // During resharding, keys might be on either shard.
+        let by_shard = group_by_shard(keys.into_iter().flat_map(|key| {
+            let previous = self.sharding.previous_shard(key);
+            std::iter::once(self.shard(key))
+                .chain(previous)
+                .map(move |shard_id| (shard_id, key.clone()))
+        }));
+        let batches = shard_batches(by_shard, MAX_DATA_IDS_PER_QUERY);
+        let deleted = try_join_all(batches.into_iter().map(|(shard_id, keys)| async move {
+            let _permit = self.delay.delay(shard_id).await;
+            let existing =
+                SelectDataSizeMany::query(&self.write_connection[shard_id], &keys[..]).await?;
+            let res = DeleteDataMany::query(&self.write_connection[shard_id], &keys[..]).await?;
+            self.usage.record(shard_id, removed_usage(existing));
+            Ok::<_, Error>(res.affected_rows())
+        }))
+        .await?;
+        Ok(deleted.into_iter().sum())
+    }
+
     pub(crate) async fn is_present(&self, key: &str) -> Result<bool, Error> {
-        let shard_id = self.shard(key);
+        let now = current_timestamp();
+        if self.is_present_in_shard(self.shard(key), key, now).await? {
+            return Ok(true);
+        }
+        match self.sharding.previous_shard(key) {
+            Some(shard_id) => self.is_present_in_shard(shard_id, key, now).await,
+            None => Ok(false),
+        }
+    }
 
+    async fn is_present_in_shard(
+        &self,
+        shard_id: usize,
+        key: &str,
+        now: i64,
+    ) -> Result<bool, Error> {
         let rows = {
-            let rows = SelectIsDataPresent::query(&self.read_connection[shard_id], &key).await?;
+            let rows =
+                SelectIsDataPresent::query(&self.read_connection[shard_id], &key, &now).await?;
             if rows.is_empty() {
-                SelectIsDataPresent::query(&self.read_master_connection[shard_id], &key).await?
+                SelectIsDataPresent::query(&self.read_master_connection[shard_id], &key, &now)
+                    .await?
             } else {
                 rows
             }
@@ -297,13 +1294,72 @@ impl DataSqlStore {
         Ok(!rows.is_empty())
     }
 
+    /// Delete the data rows in a shard whose expiry time has passed. Their
+    /// chunks are not marked by the GC sweep, so they are reclaimed with the
+    /// rest of the unreferenced chunks.
+    pub(crate) async fn delete_expired(&self, shard_num: usize) -> Result<u64, Error> {
+        let _permit = self.delay.delay(shard_num).await;
+
+        // Both statements use the same `now`, so they see the same rows.
+        let now = current_timestamp();
+        let txn = self.write_connection[shard_num].start_transaction().await?;
+        let (txn, expired) =
+            SelectExpiredDataSizeForUpdate::query_with_transaction(txn, &now).await?;
+        let (txn, res) = DeleteExpiredData::query_with_transaction(txn, &now).await?;
+        txn.commit().await?;
+        self.usage.record(shard_num, removed_usage(expired));
+        Ok(res.affected_rows())
+    }
+
+    /// The storage used by each key prefix in a shard, including expired
+    /// keys that have not been deleted yet. Keys written before sizes were
+    /// recorded are not included. Changes made through this store are
+    /// flushed first; changes made by other processes show up once they
+    /// flush theirs.
+    pub(crate) async fn usage_by_prefix(
+        &self,
+        shard_num: usize,
+    ) -> Result<Vec<KeyPrefixUsage>, Error> {
+        self.usage.flush_shard(shard_num).await?;
+        let rows = SelectKeyPrefixUsage::query(&self.read_connection[shard_num]).await?;
+        Ok(rows
+            .into_iter()
+            .filter(|(_, blob_count, _)| *blob_count != 0)
+            .map(|(key_prefix, blob_count, total_bytes)| KeyPrefixUsage {
+                key_prefix: String::from_utf8_lossy(&key_prefix).to_string(),
+                blob_count: blob_count.max(0) as u64,
+                total_bytes: total_bytes.max(0) as u64,
+            })
+            .collect())
+    }
+
+    /// Whether some usage changes have not been written to the usage table
+    /// yet.
+    #[cfg(test)]
+    pub(crate) fn has_pending_usage(&self) -> bool {
+        self.usage
+            .pending
+            .iter()
+            .any(|delta| !delta.lock().expect("lock poisoned").is_empty())
+    }
+
+    /// Bring a shard up to the current schema, returning the version it was
+    /// at. SQLite shards are migrated when they are opened, so are left alone.
+    pub(crate) async fn migrate_schema(&self, shard_num: usize) -> Result<u32, Error> {
+        match &self.write_connection[shard_num] {
+            Connection::Sqlite(_) => Ok(schema::SCHEMA_VERSION),
+            conn => schema::migrate_mysql(conn).await,
+        }
+    }
+
+    /// All keys in a shard that have not expired.
     pub(crate) fn get_keys_from_shard(
         &self,
         shard_num: usize,
     ) -> impl Stream<Item = Result<String, Error>> {
         let conn = self.read_master_connection[shard_num].clone();
         async move {
-            let keys = GetAllKeys::query(&conn).await?;
+            let keys = GetAllKeys::query(&conn, &current_timestamp()).await?;
             Ok(stream::iter(
                 keys.into_iter()
                     .map(|(id,)| Ok(String::from_utf8_lossy(&id).to_string())),
@@ -312,10 +1368,76 @@ impl DataSqlStore {
         .try_flatten_stream()
     }
 
-    fn shard(&self, key: &str) -> usize {
-        let mut hasher = XxHash32::with_seed(0);
-        hasher.write(key.as_bytes());
-        (hasher.finish() % self.shard_count.get() as u64) as usize
+    /// Up to `limit` unexpired keys in a shard that sort after `after`, in
+    /// order. Unlike `get_keys_from_shard`, this can be resumed.
+    pub(crate) async fn get_keys_page(
+        &self,
+        shard_num: usize,
+        after: &str,
+        limit: u64,
+    ) -> Result<Vec<String>, Error> {
+        let keys = GetKeysPage::query(
+            &self.read_master_connection[shard_num],
+            &after,
+            &current_timestamp(),
+            &limit,
+        )
+        .await?;
+        Ok(keys
+            .into_iter()
+            .map(|(id,)| String::from_utf8_lossy(&id).to_string())
+            .collect())
+    }
+
+    /// Up to `limit` unexpired keys in a shard that are in `range`, in order.
+    /// If `after` is given, only keys that sort after it are returned.
+    pub(crate) async fn get_keys_in_range(
+        &self,
+        shard_num: usize,
+        range: &BlobstoreKeyRange,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<String>, Error> {
+        let conn = &self.read_master_connection[shard_num];
+        let now = current_timestamp();
+        let keys = match after {
+            Some(after) => {
+                GetKeysRangeAfter::query(conn, &after, &range.end_key.as_str(), &now, &limit)
+                    .await?
+            }
+            None => {
+                GetKeysRangeFrom::query(
+                    conn,
+                    &range.begin_key.as_str(),
+                    &range.end_key.as_str(),
+                    &now,
+                    &limit,
+                )
+                .await?
+            }
+        };
+        Ok(keys
+            .into_iter()
+            .map(|(id,)| String::from_utf8_lossy(&id).to_string())
+            .collect())
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.sharding.shard_count()
+    }
+
+    /// Whether `other` is the same database, e.g. the same blobstore opened
+    /// with another sharding strategy.
+    pub(crate) fn same_database(&self, other: &DataSqlStore) -> bool {
+        Arc::ptr_eq(&self.write_connection, &other.write_connection)
+    }
+
+    pub(crate) fn delay(&self) -> &BlobDelay {
+        &self.delay
+    }
+
+    pub(crate) fn shard(&self, key: &str) -> usize {
+        self.sharding.shard(key)
     }
 }
 
@@ -327,6 +1449,9 @@ pub(crate) struct ChunkSqlStore {
     read_master_connection: Arc<Vec<Connection>>,
     delay: BlobDelay,
     gc_generations: ConfigHandle<XdbGc>,
+    compression: ChunkCompression,
+    cache: Option<Arc<ChunkCache>>,
+    stats: Arc<SqlblobStats>,
 }
 
 impl ChunkSqlStore {
@@ -337,6 +1462,9 @@ impl ChunkSqlStore {
         read_master_connection: Arc<Vec<Connection>>,
         delay: BlobDelay,
         gc_generations: ConfigHandle<XdbGc>,
+        compression: ChunkCompression,
+        cache: Option<ChunkCache>,
+        stats: Arc<SqlblobStats>,
     ) -> Self {
         Self {
             shard_count,
@@ -345,6 +1473,19 @@ impl ChunkSqlStore {
             read_master_connection,
             delay,
             gc_generations,
+            compression,
+            cache: cache.map(Arc::new),
+            stats,
+        }
+    }
+
+    /// The chunking method to use for new non-inline blobs. Chunks are only
+    /// given a codec header if compression is enabled, so that readers
+    /// predating compression can still read blobs written with it disabled.
+    pub(crate) fn chunking_method(&self) -> ChunkingMethod {
+        match self.compression.zstd_level() {
+            Some(_) => ChunkingMethod::ByContentHashBlake2WithCodec,
+            None => ChunkingMethod::ByContentHashBlake2,
         }
     }
 
@@ -354,6 +1495,37 @@ impl ChunkSqlStore {
         chunk_num: u32,
         chunking_method: ChunkingMethod,
     ) -> Result<BytesMut, Error> {
+        if let Some(cache) = &self.cache {
+            let cached = cache.get(id, chunk_num);
+            if let Some(shard_id) = self.shard(id, chunk_num, chunking_method) {
+                self.stats.record_chunk_cache(shard_id, cached.is_some());
+            }
+            if let Some(value) = cached {
+                return Ok(value);
+            }
+        }
+
+        let value = self
+            .get_stored(id, chunk_num, chunking_method)
+            .await?
+            .ok_or_else(|| format_err!("Missing chunk with id {} chunk {}", id, chunk_num))?;
+        let value = match chunking_method {
+            ChunkingMethod::ByContentHashBlake2WithCodec => decode_chunk(&value)?,
+            _ => (&*value).into(),
+        };
+        if let Some(cache) = &self.cache {
+            cache.set(id, chunk_num, &value);
+        }
+        Ok(value)
+    }
+
+    /// Fetch a chunk as it is stored, without decoding it.
+    pub(crate) async fn get_stored(
+        &self,
+        id: &str,
+        chunk_num: u32,
+        chunking_method: ChunkingMethod,
+    ) -> Result<Option<Vec<u8>>, Error> {
         if let Some(shard_id) = self.shard(id, chunk_num, chunking_method) {
             let rows = {
                 let rows =
@@ -365,12 +1537,7 @@ impl ChunkSqlStore {
                     rows
                 }
             };
-            rows.into_iter()
-                .next()
-                .map(|(value,)| (&*value).into())
-                .ok_or_else(|| {
-                    format_err!("Missing chunk with id {} shard {}", chunk_num, shard_id)
-                })
+            Ok(rows.into_iter().next().map(|(value,)| value))
         } else {
             bail!(
                 "ChunkSqlStore::get() unexpectedly called for inline chunking_method {:?}",
@@ -379,6 +1546,86 @@ impl ChunkSqlStore {
         }
     }
 
+    /// Fetch many chunks. Chunks in the cache are not queried; the others are
+    /// fetched with one query per shard per batch of chunk ids, and cached.
+    /// Fails if any of the chunks is missing.
+    pub(crate) async fn get_many(
+        &self,
+        chunks: &[(String, u32, ChunkingMethod)],
+    ) -> Result<HashMap<(String, u32), BytesMut>, Error> {
+        let methods: HashMap<&str, ChunkingMethod> = chunks
+            .iter()
+            .map(|(id, _, chunking_method)| (id.as_str(), *chunking_method))
+            .collect();
+        let mut fetched = HashMap::new();
+        let wanted = chunks
+            .iter()
+            .filter_map(|(id, chunk_num, method)| {
+                let shard_id = self.shard(id, *chunk_num, *method)?;
+                if let Some(cache) = &self.cache {
+                    let cached = cache.get(id, *chunk_num);
+                    self.stats.record_chunk_cache(shard_id, cached.is_some());
+                    if let Some(value) = cached {
+                        fetched.insert((id.clone(), *chunk_num), value);
+                        return None;
+                    }
+                }
+                Some((shard_id, (id.clone(), *chunk_num)))
+            })
+            .collect::<HashSet<_>>();
+        if wanted.is_empty() {
+            return Ok(fetched);
+        }
+        let by_shard = group_by_shard(wanted);
+        let batches = shard_batches(by_shard, MAX_CHUNK_IDS_PER_QUERY);
+        let results = try_join_all(
+            batches
+                .into_iter()
+                .map(|(shard_id, wanted)| self.get_many_in_shard(shard_id, wanted)),
+        )
+        .await?;
+
+        for ((id, chunk_num), value) in results.into_iter().flatten() {
+            let value = match methods.get(id.as_str()) {
+                Some(ChunkingMethod::ByContentHashBlake2WithCodec) => decode_chunk(&value)?,
+                _ => (&*value).into(),
+            };
+            if let Some(cache) = &self.cache {
+                cache.set(&id, chunk_num, &value);
+            }
+            fetched.insert((id, chunk_num), value);
+        }
+        Ok(fetched)
+    }
+
+    async fn get_many_in_shard(
+        &self,
+        shard_id: usize,
+        wanted: Vec<(String, u32)>,
+    ) -> Result<Vec<((String, u32), Vec<u8>)>, Error> {
+        let mut found = select_chunks(&self.read_connection[shard_id], &wanted).await?;
+        if found.len() < wanted.len() {
+            let missing = {
+                let found: HashSet<&(String, u32)> = found.iter().map(|(key, _)| key).collect();
+                wanted
+                    .into_iter()
+                    .filter(|key| !found.contains(key))
+                    .collect::<Vec<_>>()
+            };
+            let from_master =
+                select_chunks(&self.read_master_connection[shard_id], &missing).await?;
+            if from_master.len() < missing.len() {
+                bail!(
+                    "Missing {} chunks in shard {}",
+                    missing.len() - from_master.len(),
+                    shard_id
+                );
+            }
+            found.extend(from_master);
+        }
+        Ok(found)
+    }
+
     pub(crate) async fn put(
         &self,
         key: &str,
@@ -387,13 +1634,94 @@ impl ChunkSqlStore {
         value: &[u8],
     ) -> Result<(), Error> {
         if let Some(shard_id) = self.shard(key, chunk_num, chunking_method) {
-            self.delay.delay(shard_id).await;
+            let decoded = value;
+            let encoded;
+            let value = match chunking_method {
+                ChunkingMethod::ByContentHashBlake2WithCodec => {
+                    encoded = encode_chunk(self.compression.zstd_level(), value)?;
+                    encoded.as_ref()
+                }
+                _ => value,
+            };
+            let _permit = self.delay.delay(shard_id).await;
+            UpdateGeneration::query(
+                &self.write_connection[shard_id],
+                &key,
+                &(self.gc_generations.get().put_generation as u64),
+            )
+            .await?;
+            InsertChunk::query(
+                &self.write_connection[shard_id],
+                &[(&key, &chunk_num, &value)],
+            )
+            .await?;
+            if let Some(cache) = &self.cache {
+                cache.set(key, chunk_num, decoded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrite a chunk that may already exist, e.g. to repair a corrupt one.
+    /// Unlike `put`, this replaces the stored value if the chunk is present.
+    pub(crate) async fn replace(
+        &self,
+        key: &str,
+        chunk_num: u32,
+        chunking_method: ChunkingMethod,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        if let Some(shard_id) = self.shard(key, chunk_num, chunking_method) {
+            let decoded = value;
+            let encoded;
+            let value = match chunking_method {
+                ChunkingMethod::ByContentHashBlake2WithCodec => {
+                    encoded = encode_chunk(self.compression.zstd_level(), value)?;
+                    encoded.as_ref()
+                }
+                _ => value,
+            };
+            let _permit = self.delay.delay(shard_id).await;
             UpdateGeneration::query(
                 &self.write_connection[shard_id],
                 &key,
                 &(self.gc_generations.get().put_generation as u64),
             )
             .await?;
+            let res = InsertChunk::query(
+                &self.write_connection[shard_id],
+                &[(&key, &chunk_num, &value)],
+            )
+            .await?;
+            if res.affected_rows() == 0 {
+                UpdateChunk::query(&self.write_connection[shard_id], &key, &chunk_num, &value)
+                    .await?;
+            }
+            if let Some(cache) = &self.cache {
+                cache.set(key, chunk_num, decoded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a chunk exactly as it was stored elsewhere, without encoding
+    /// it, and raise its generation to at least `generation`.
+    pub(crate) async fn put_stored(
+        &self,
+        key: &str,
+        chunk_num: u32,
+        chunking_method: ChunkingMethod,
+        value: &[u8],
+        generation: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some(shard_id) = self.shard(key, chunk_num, chunking_method) {
+            let _permit = self.delay.delay(shard_id).await;
+            if let Some(generation) = generation {
+                InsertGeneration::query(&self.write_connection[shard_id], &[(&key, &generation)])
+                    .await?;
+                UpdateGeneration::query(&self.write_connection[shard_id], &key, &generation)
+                    .await?;
+            }
             InsertChunk::query(
                 &self.write_connection[shard_id],
                 &[(&key, &chunk_num, &value)],
@@ -410,7 +1738,7 @@ impl ChunkSqlStore {
         chunking_method: ChunkingMethod,
     ) -> Result<(), Error> {
         if let Some(shard_id) = self.shard(key, chunk_num, chunking_method) {
-            self.delay.delay(shard_id).await;
+            let _permit = self.delay.delay(shard_id).await;
             UpdateGeneration::query(
                 &self.write_connection[shard_id],
                 &key,
@@ -421,7 +1749,6 @@ impl ChunkSqlStore {
         Ok(())
     }
 
-    #[cfg(test)]
     pub(crate) async fn get_generation(
         &self,
         key: &str,
@@ -464,7 +1791,7 @@ impl ChunkSqlStore {
                 return Ok(());
             }
 
-            self.delay.delay(shard_id).await;
+            let _permit = self.delay.delay(shard_id).await;
             // First set the generation if unset, so that future writers will update it.
             if replica_generation.is_none() {
                 InsertGeneration::query(
@@ -480,6 +1807,54 @@ impl ChunkSqlStore {
         Ok(())
     }
 
+    pub(crate) fn gc_generations(&self) -> Arc<XdbGc> {
+        self.gc_generations.get()
+    }
+
+    /// Whether GC is part way through a mark phase, i.e. it has moved on to a
+    /// new mark generation but not yet advanced the delete generation to just
+    /// below it.
+    pub(crate) fn mark_in_progress(&self) -> bool {
+        let gc_generations = self.gc_generations.get();
+        gc_generations.delete_generation + 1 < gc_generations.mark_generation
+    }
+
+    /// Move the given chunks back to the current mark generation, so that the
+    /// sweep after the next mark phase reclaims them unless that mark phase
+    /// finds them still referenced. Chunks without a generation are left
+    /// alone, as are chunks already at or below the mark generation.
+    ///
+    /// This must not run during a mark phase: a chunk the phase had already
+    /// marked would lose its mark while still referenced.
+    pub(crate) async fn lower_generations(
+        &self,
+        chunks: impl IntoIterator<Item = (String, u32, ChunkingMethod)>,
+    ) -> Result<u64, Error> {
+        let mark_generation = self.gc_generations.get().mark_generation as u64;
+        let by_shard = group_by_shard(
+            chunks
+                .into_iter()
+                .filter_map(|(id, chunk_num, chunking_method)| {
+                    self.shard(&id, chunk_num, chunking_method)
+                        .map(|shard_id| (shard_id, id))
+                })
+                .collect::<HashSet<_>>(),
+        );
+        let batches = shard_batches(by_shard, MAX_DATA_IDS_PER_QUERY);
+        let lowered = try_join_all(batches.into_iter().map(|(shard_id, ids)| async move {
+            let _permit = self.delay.delay(shard_id).await;
+            let res = LowerGenerations::query(
+                &self.write_connection[shard_id],
+                &mark_generation,
+                &ids[..],
+            )
+            .await?;
+            Ok::<_, Error>(res.affected_rows())
+        }))
+        .await?;
+        Ok(lowered.into_iter().sum())
+    }
+
     pub(crate) async fn get_chunk_sizes_by_generation(
         &self,
         shard_num: usize,
@@ -489,10 +1864,71 @@ impl ChunkSqlStore {
             .map(|s| s.into_iter().collect::<HashMap<_, _>>())
     }
 
+    /// The number and total stored size of the chunks in a shard that a
+    /// sweep of `delete_generation` would delete.
+    pub(crate) async fn get_sweepable_size(
+        &self,
+        shard_num: usize,
+        delete_generation: u64,
+    ) -> Result<(u64, u64), Error> {
+        let rows =
+            GetSweepableSize::query(&self.read_master_connection[shard_num], &delete_generation)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .map_or((0, 0), |(chunks, bytes)| (chunks, bytes.unwrap_or(0))))
+    }
+
+    /// Delete up to `limit` chunk ids in a shard whose generation is at or
+    /// below `delete_generation`, along with their generation rows. Returns
+    /// how many ids were found, and how many chunks were deleted.
+    ///
+    /// The generation is checked again as part of each delete, so chunks
+    /// that a put has moved to a newer generation since they were listed
+    /// are kept.
+    pub(crate) async fn sweep(
+        &self,
+        shard_num: usize,
+        delete_generation: u64,
+        limit: u64,
+    ) -> Result<(usize, u64), Error> {
+        let ids = SelectSweepableIds::query(
+            &self.read_master_connection[shard_num],
+            &delete_generation,
+            &limit,
+        )
+        .await?
+        .into_iter()
+        .map(|(id,)| String::from_utf8_lossy(&id).to_string())
+        .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let _permit = self.delay.delay(shard_num).await;
+        // Chunks first, so that if this fails part way through, the next
+        // sweep still finds the remaining chunks through their generations.
+        let deleted = DeleteSweepableChunks::query(
+            &self.write_connection[shard_num],
+            &delete_generation,
+            &ids[..],
+        )
+        .await?
+        .affected_rows();
+        DeleteSweepableGenerations::query(
+            &self.write_connection[shard_num],
+            &delete_generation,
+            &ids[..],
+        )
+        .await?;
+        Ok((ids.len(), deleted))
+    }
+
     pub(crate) async fn set_initial_generation(&self, shard_num: usize) -> Result<(), Error> {
         let put_generation = self.gc_generations.get().put_generation as u64;
 
-        self.delay.delay(shard_num).await;
+        let _permit = self.delay.delay(shard_num).await;
 
         SetInitialGeneration::query(&self.write_connection[shard_num], &put_generation).await?;
         Ok(())
@@ -502,7 +1938,7 @@ impl ChunkSqlStore {
     fn shard(&self, key: &str, chunk_id: u32, chunking_method: ChunkingMethod) -> Option<usize> {
         match chunking_method {
             ChunkingMethod::InlineBase64 => None,
-            ChunkingMethod::ByContentHashBlake2 => {
+            ChunkingMethod::ByContentHashBlake2 | ChunkingMethod::ByContentHashBlake2WithCodec => {
                 let mut hasher = XxHash32::with_seed(0);
                 hasher.write(key.as_bytes());
                 hasher.write_u32(chunk_id);
@@ -0,0 +1,71 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Hooks for exporting per-operation telemetry of a `Sqlblob`.
+//!
+//! `SqlblobStats` covers the counters every deployment wants. Exporters that
+//! need more, such as per-shard dashboards of bytes written by chunking
+//! method, implement `SqlblobTelemetry` and pass it in
+//! `SqlblobOptions::telemetry`, instead of wrapping the blobstore or its
+//! connections.
+
+use std::{fmt, time::Duration};
+
+use crate::store::ChunkingMethod;
+
+/// How a blob is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SqlblobChunking {
+    /// In the data row, without chunks.
+    Inline,
+    /// In content-addressed chunks, shared with other keys of the same
+    /// content.
+    Chunked,
+}
+
+impl From<ChunkingMethod> for SqlblobChunking {
+    fn from(chunking_method: ChunkingMethod) -> Self {
+        match chunking_method {
+            ChunkingMethod::InlineBase64 => Self::Inline,
+            ChunkingMethod::ByContentHashBlake2 | ChunkingMethod::ByContentHashBlake2WithCodec => {
+                Self::Chunked
+            }
+        }
+    }
+}
+
+/// A completed operation on one key.
+#[derive(Clone, Debug)]
+pub struct SqlblobEvent<'a> {
+    pub key: &'a str,
+    /// The shard of the data row of `key`.
+    pub shard: usize,
+    /// Size of the blob, if known. Unknown for missing keys, failed gets,
+    /// and blobs written before sizes were recorded.
+    pub bytes: Option<u64>,
+    /// How the blob is stored, if known.
+    pub chunking: Option<SqlblobChunking>,
+    pub latency: Duration,
+    pub success: bool,
+}
+
+/// Receives an event for every put, get, link and unlink of a `Sqlblob`.
+///
+/// Methods are called inline on the request path, so they should only
+/// update counters or queue the event.
+pub trait SqlblobTelemetry: fmt::Debug + Send + Sync {
+    fn on_put(&self, _event: &SqlblobEvent<'_>) {}
+
+    /// Gets of missing keys are reported with `bytes` and `chunking` unset.
+    fn on_get(&self, _event: &SqlblobEvent<'_>) {}
+
+    /// `key` is the new key, and the size and chunking are those of the
+    /// blob it now refers to.
+    fn on_link(&self, _event: &SqlblobEvent<'_>) {}
+
+    fn on_unlink(&self, _event: &SqlblobEvent<'_>) {}
+}
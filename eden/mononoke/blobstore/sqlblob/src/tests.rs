@@ -11,9 +11,11 @@ use blobstore::DEFAULT_PUT_BEHAVIOUR;
 use borrowed::borrowed;
 use bytes::Bytes;
 use fbinit::FacebookInit;
+use maplit::hashmap;
 use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
 use std::time::Duration;
 use strum::IntoEnumIterator;
+use tunables::{with_tunables_async, MononokeTunables};
 
 const UPDATE_WAIT_TIME: Duration = Duration::from_millis(3);
 
@@ -28,8 +30,12 @@ where
 {
     for allow_inline in &[true, false] {
         let (test_source, config_store) = get_test_config_store();
-        let blobstore =
-            Sqlblob::with_sqlite_in_memory(put_behaviour, &config_store, *allow_inline)?;
+        let blobstore = Sqlblob::with_sqlite_in_memory(
+            put_behaviour,
+            &config_store,
+            *allow_inline,
+            SqlblobOptions::default(),
+        )?;
         let ctx = CoreContext::test_mock(fb);
         do_test(ctx, blobstore, test_source)
             .await
@@ -344,3 +350,1675 @@ async fn generations(fb: FacebookInit) -> Result<(), Error> {
     )
     .await
 }
+
+#[fbinit::test]
+async fn unlink_many(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(
+        fb,
+        DEFAULT_PUT_BEHAVIOUR,
+        |ctx, bs, test_source| async move {
+            borrowed!(ctx);
+            let mut blobs = Vec::new();
+            for _ in 0..2 {
+                let mut bytes_in = [0u8; 1024];
+                thread_rng().fill_bytes(&mut bytes_in);
+                let bytes = Bytes::copy_from_slice(&bytes_in);
+                blobs.push(BlobstoreBytes::from_bytes(bytes));
+            }
+            bs.put(ctx, "key1".to_string(), blobs[0].clone()).await?;
+            bs.put(ctx, "key2".to_string(), blobs[1].clone()).await?;
+            // key3 shares its chunks with key1
+            bs.link(ctx, "key1", "key3".to_string()).await?;
+            for key in &["key1", "key2", "key3"] {
+                bs.set_generation(key).await?;
+            }
+            let data_store = bs.get_data_store();
+            let row1 = data_store.get("key1").await?.expect("key1 not found");
+            let row2 = data_store.get("key2").await?.expect("key2 not found");
+
+            bs.unlink_many(ctx, vec!["key1".to_string(), "key2".to_string()])
+                .await?;
+            assert!(bs.get(ctx, "key1").await?.is_none());
+            assert!(bs.get(ctx, "key2").await?.is_none());
+            let key3 = bs.get(ctx, "key3").await?.expect("key3 was unlinked");
+            assert_eq!(key3.as_bytes(), &blobs[0]);
+
+            // The chunks are moved back to the mark generation, including
+            // key1's, which key3 still refers to and the next mark will keep.
+            for row in &[row1, row2] {
+                let generation = bs
+                    .chunk_store
+                    .get_generation(&row.id, 0, row.chunking_method)
+                    .await?;
+                assert_eq!(generation, Some(1), "Chunk generation not lowered");
+            }
+
+            // Nothing is unlinked if one of the keys is missing.
+            let res = bs
+                .unlink_many(ctx, vec!["key3".to_string(), "key4".to_string()])
+                .await;
+            assert!(res.is_err(), "Unlinked a missing key");
+            assert!(bs.get(ctx, "key3").await?.is_some());
+
+            // Nor while a mark phase is running.
+            set_test_generations(test_source.as_ref(), 3, 2, 0, INITIAL_VERSION + 1);
+            tokio::time::sleep(UPDATE_WAIT_TIME).await;
+            let res = bs.unlink_many(ctx, vec!["key3".to_string()]).await;
+            assert!(res.is_err(), "Unlinked during a mark phase");
+            assert!(bs.get(ctx, "key3").await?.is_some());
+            Ok(())
+        },
+    )
+    .await
+}
+
+#[fbinit::test]
+async fn compressed_read_write(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            chunk_compression: ChunkCompression::Zstd(3),
+            ..Default::default()
+        },
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    // Compressible, incompressible and multi-chunk blobs all round trip.
+    let mut random = vec![0u8; 1024];
+    thread_rng().fill_bytes(&mut random);
+    let blobs = vec![
+        ("compressible", vec![b'a'; 4096]),
+        ("incompressible", random),
+        ("multichunk", vec![b'b'; CHUNK_SIZE * 2 + 1]),
+    ];
+    for (key, bytes_in) in blobs {
+        let blobstore_bytes = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(&bytes_in));
+        bs.put(ctx, key.to_string(), blobstore_bytes).await?;
+
+        let row = bs.get_data_store().get(key).await?.expect("Blob not found");
+        assert_eq!(
+            row.chunking_method,
+            ChunkingMethod::ByContentHashBlake2WithCodec
+        );
+
+        let bytes_out = bs.get(ctx, key).await?;
+        assert_eq!(&bytes_in, bytes_out.unwrap().as_raw_bytes());
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn get_many(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        let mut expected = HashMap::new();
+        for (i, size) in [0, 16, MAX_INLINE_LEN + 1, 1024, CHUNK_SIZE + 1]
+            .iter()
+            .enumerate()
+        {
+            let key = format!("get_many_test_{}", i);
+            let mut bytes_in = vec![0u8; *size];
+            thread_rng().fill_bytes(&mut bytes_in);
+            bs.put(
+                ctx,
+                key.clone(),
+                BlobstoreBytes::from_bytes(Bytes::copy_from_slice(&bytes_in)),
+            )
+            .await?;
+            expected.insert(key, bytes_in);
+        }
+        // The same content under a second key shares its chunks.
+        let shared = expected["get_many_test_3"].clone();
+        bs.link(ctx, "get_many_test_3", "get_many_test_linked".to_string())
+            .await?;
+        expected.insert("get_many_test_linked".to_string(), shared);
+
+        let mut keys: Vec<String> = expected.keys().cloned().collect();
+        keys.push("get_many_test_missing".to_string());
+
+        let fetched = bs.get_many(ctx, keys.clone()).await?;
+        assert_eq!(fetched.len(), expected.len());
+        for (key, bytes_in) in &expected {
+            assert_eq!(bytes_in, fetched[key].as_raw_bytes());
+        }
+
+        let present = bs.is_present_many(ctx, keys).await?;
+        assert_eq!(present.len(), expected.len() + 1);
+        for key in expected.keys() {
+            assert!(matches!(present[key], BlobstoreIsPresent::Present));
+        }
+        assert!(matches!(
+            present["get_many_test_missing"],
+            BlobstoreIsPresent::Absent
+        ));
+        Ok(())
+    })
+    .await
+}
+
+#[fbinit::test]
+async fn expiry(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        let mut bytes_in = [0u8; 1024];
+        thread_rng().fill_bytes(&mut bytes_in);
+        let blobstore_bytes = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(&bytes_in));
+
+        bs.put_with_ttl(
+            ctx,
+            "live".to_string(),
+            blobstore_bytes.clone(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+        // A TTL of zero expires immediately.
+        bs.put_with_ttl(
+            ctx,
+            "expired".to_string(),
+            blobstore_bytes.clone(),
+            Duration::from_secs(0),
+        )
+        .await?;
+
+        assert_eq!(
+            &bytes_in.to_vec(),
+            bs.get(ctx, "live").await?.unwrap().as_raw_bytes()
+        );
+        assert!(bs.get(ctx, "expired").await?.is_none());
+        assert!(!bs
+            .is_present(ctx, "expired")
+            .await?
+            .assume_not_found_if_unsure());
+        let fetched = bs
+            .get_many(ctx, vec!["live".to_string(), "expired".to_string()])
+            .await?;
+        assert_eq!(fetched.keys().collect::<Vec<_>>(), vec!["live"]);
+
+        // GC only sees the live key, and deletes the expired one.
+        let mut keys = Vec::new();
+        let mut deleted = 0;
+        for shard in 0..SQLITE_SHARD_NUM.get() {
+            keys.extend(
+                bs.get_keys_from_shard(shard)
+                    .try_collect::<Vec<_>>()
+                    .await?,
+            );
+            deleted += bs.delete_expired(shard).await?;
+        }
+        assert_eq!(keys, vec!["live".to_string()]);
+        assert_eq!(deleted, 1);
+
+        // A normal put over an expired key does not inherit its expiry.
+        bs.put_with_ttl(
+            ctx,
+            "reused".to_string(),
+            blobstore_bytes.clone(),
+            Duration::from_secs(0),
+        )
+        .await?;
+        bs.put(ctx, "reused".to_string(), blobstore_bytes).await?;
+        assert!(bs
+            .is_present(ctx, "reused")
+            .await?
+            .assume_not_found_if_unsure());
+        Ok(())
+    })
+    .await
+}
+
+#[fbinit::test]
+async fn conditional_put(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        let mut bytes_1 = [0u8; 64];
+        thread_rng().fill_bytes(&mut bytes_1);
+        let mut bytes_2 = [0u8; 1024];
+        thread_rng().fill_bytes(&mut bytes_2);
+        let blobstore_bytes_1 = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(&bytes_1));
+        let blobstore_bytes_2 = BlobstoreBytes::from_bytes(Bytes::copy_from_slice(&bytes_2));
+        let hash_1 = Sqlblob::value_hash(&blobstore_bytes_1);
+        let hash_2 = Sqlblob::value_hash(&blobstore_bytes_2);
+        let key = "counter".to_string();
+
+        // Nothing to compare with yet.
+        assert_eq!(
+            bs.put_if_matches(ctx, key.clone(), hash_1, blobstore_bytes_2.clone())
+                .await?,
+            OverwriteStatus::Prevented
+        );
+        assert_eq!(
+            bs.put_if_absent_atomic(ctx, key.clone(), blobstore_bytes_1.clone())
+                .await?,
+            OverwriteStatus::New
+        );
+        assert_eq!(
+            bs.put_if_absent_atomic(ctx, key.clone(), blobstore_bytes_2.clone())
+                .await?,
+            OverwriteStatus::Prevented
+        );
+
+        // Only the put that expects the current value succeeds.
+        assert_eq!(
+            bs.put_if_matches(ctx, key.clone(), hash_2, blobstore_bytes_2.clone())
+                .await?,
+            OverwriteStatus::Prevented
+        );
+        assert_eq!(
+            bs.put_if_matches(ctx, key.clone(), hash_1, blobstore_bytes_2.clone())
+                .await?,
+            OverwriteStatus::Overwrote
+        );
+        assert_eq!(
+            bs.put_if_matches(ctx, key.clone(), hash_1, blobstore_bytes_1.clone())
+                .await?,
+            OverwriteStatus::Prevented
+        );
+        assert_eq!(
+            bs.get(ctx, &key).await?.map(|get| get.into_bytes()),
+            Some(blobstore_bytes_2.clone()),
+        );
+
+        // Of concurrent puts expecting the same value, one succeeds.
+        let statuses = futures::future::try_join_all((0..4u8).map(|i| {
+            let value = BlobstoreBytes::from_bytes(vec![i; 16]);
+            bs.put_if_matches(ctx, key.clone(), hash_2, value)
+        }))
+        .await?;
+        assert_eq!(
+            statuses
+                .iter()
+                .filter(|status| **status == OverwriteStatus::Overwrote)
+                .count(),
+            1
+        );
+
+        // Expired keys are absent, and cannot be compared with.
+        bs.put_with_ttl(
+            ctx,
+            "expired".to_string(),
+            blobstore_bytes_1.clone(),
+            Duration::from_secs(0),
+        )
+        .await?;
+        assert_eq!(
+            bs.put_if_matches(
+                ctx,
+                "expired".to_string(),
+                hash_1,
+                blobstore_bytes_2.clone()
+            )
+            .await?,
+            OverwriteStatus::Prevented
+        );
+        assert_eq!(
+            bs.put_if_absent_atomic(ctx, "expired".to_string(), blobstore_bytes_2.clone())
+                .await?,
+            OverwriteStatus::New
+        );
+        assert_eq!(
+            bs.get(ctx, "expired").await?.map(|get| get.into_bytes()),
+            Some(blobstore_bytes_2),
+        );
+        Ok(())
+    })
+    .await
+}
+
+async fn usage_by_prefix_all_shards(bs: &Sqlblob) -> Result<Vec<(String, u64, u64)>, Error> {
+    let mut usage = Vec::new();
+    for shard in 0..SQLITE_SHARD_NUM.get() {
+        usage.extend(
+            bs.usage_by_prefix(shard)
+                .await?
+                .into_iter()
+                .map(|usage| (usage.key_prefix, usage.blob_count, usage.total_bytes)),
+        );
+    }
+    usage.sort();
+    Ok(usage)
+}
+
+#[fbinit::test]
+async fn usage_by_prefix(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, PutBehaviour::Overwrite, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        let bytes = |len: usize| BlobstoreBytes::from_bytes(vec![7u8; len]);
+
+        bs.put(ctx, "repo0000.content.blake2.a".to_string(), bytes(10))
+            .await?;
+        bs.put(ctx, "repo0000.content.blake2.b".to_string(), bytes(20))
+            .await?;
+        bs.put(ctx, "repo0001.hgfilenode.sha1.c".to_string(), bytes(5))
+            .await?;
+        // Writes only accumulate their usage, which is flushed before it is
+        // read.
+        assert!(bs.get_data_store().has_pending_usage());
+        assert_eq!(
+            usage_by_prefix_all_shards(&bs).await?,
+            vec![
+                ("repo0000.content".to_string(), 2, 30),
+                ("repo0001.hgfilenode".to_string(), 1, 5),
+            ]
+        );
+        assert!(!bs.get_data_store().has_pending_usage());
+
+        // Overwriting replaces the size of the old blob, and links are
+        // counted in full.
+        bs.put(ctx, "repo0000.content.blake2.a".to_string(), bytes(15))
+            .await?;
+        bs.link(
+            ctx,
+            "repo0000.content.blake2.b",
+            "repo0000.alias.sha1.b".to_string(),
+        )
+        .await?;
+        assert_eq!(
+            usage_by_prefix_all_shards(&bs).await?,
+            vec![
+                ("repo0000.alias".to_string(), 1, 20),
+                ("repo0000.content".to_string(), 2, 35),
+                ("repo0001.hgfilenode".to_string(), 1, 5),
+            ]
+        );
+
+        bs.unlink(ctx, "repo0000.content.blake2.a").await?;
+        bs.unlink_many(
+            ctx,
+            vec![
+                "repo0000.content.blake2.b".to_string(),
+                "repo0000.alias.sha1.b".to_string(),
+            ],
+        )
+        .await?;
+        assert_eq!(
+            usage_by_prefix_all_shards(&bs).await?,
+            vec![("repo0001.hgfilenode".to_string(), 1, 5)]
+        );
+
+        // Expired keys are counted until they are deleted.
+        bs.put_with_ttl(
+            ctx,
+            "repo0002.content.blake2.d".to_string(),
+            bytes(7),
+            Duration::from_secs(0),
+        )
+        .await?;
+        assert_eq!(
+            usage_by_prefix_all_shards(&bs).await?,
+            vec![
+                ("repo0001.hgfilenode".to_string(), 1, 5),
+                ("repo0002.content".to_string(), 1, 7),
+            ]
+        );
+        for shard in 0..SQLITE_SHARD_NUM.get() {
+            bs.delete_expired(shard).await?;
+        }
+        assert_eq!(
+            usage_by_prefix_all_shards(&bs).await?,
+            vec![("repo0001.hgfilenode".to_string(), 1, 5)]
+        );
+        Ok(())
+    })
+    .await
+}
+
+#[test]
+fn test_key_prefix() {
+    assert_eq!(
+        key_prefix("repo0000.content.blake2.abcd"),
+        "repo0000.content"
+    );
+    assert_eq!(key_prefix("repo0000.content"), "repo0000.content");
+    assert_eq!(key_prefix("nodots"), "nodots");
+}
+
+async fn scrub_all_shards(
+    ctx: &CoreContext,
+    bs: &Sqlblob,
+    repair_from: Option<&dyn Blobstore>,
+) -> Result<ScrubReport, Error> {
+    let mut report = ScrubReport::default();
+    for shard in 0..SQLITE_SHARD_NUM.get() {
+        let shard_report = bs.scrub_shard(ctx, shard, repair_from).await?;
+        report.keys_checked += shard_report.keys_checked;
+        report.inline_keys += shard_report.inline_keys;
+        report.corrupt_keys.extend(shard_report.corrupt_keys);
+        report.repaired_keys.extend(shard_report.repaired_keys);
+    }
+    Ok(report)
+}
+
+#[fbinit::test]
+async fn scrub(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        let mut good_bytes = vec![0u8; 1024];
+        thread_rng().fill_bytes(&mut good_bytes);
+        let mut bad_bytes = vec![0u8; 1024];
+        thread_rng().fill_bytes(&mut bad_bytes);
+        let bad_bytes = BlobstoreBytes::from_bytes(bad_bytes);
+
+        bs.put(
+            ctx,
+            "good".to_string(),
+            BlobstoreBytes::from_bytes(good_bytes),
+        )
+        .await?;
+        bs.put(ctx, "inline".to_string(), BlobstoreBytes::from_bytes("x"))
+            .await?;
+        bs.put(ctx, "bad".to_string(), bad_bytes.clone()).await?;
+
+        // Keep a good copy elsewhere, as a multiplex would.
+        let (_, config_store) = get_test_config_store();
+        let other = Sqlblob::with_sqlite_in_memory(
+            DEFAULT_PUT_BEHAVIOUR,
+            &config_store,
+            true,
+            SqlblobOptions::default(),
+        )?;
+        other.put(ctx, "bad".to_string(), bad_bytes.clone()).await?;
+
+        let report = scrub_all_shards(ctx, &bs, None).await?;
+        assert!(report.is_clean());
+        assert_eq!(report.keys_checked + report.inline_keys, 3);
+
+        let chunked = bs.data_store.get("bad").await?.expect("Blob not found");
+        bs.chunk_store
+            .replace(&chunked.id, 0, chunked.chunking_method, b"garbage")
+            .await?;
+
+        let report = scrub_all_shards(ctx, &bs, None).await?;
+        assert_eq!(report.corrupt_keys, vec!["bad".to_string()]);
+        assert!(report.repaired_keys.is_empty());
+
+        let report = scrub_all_shards(ctx, &bs, Some(&*other)).await?;
+        assert!(report.corrupt_keys.is_empty());
+        assert_eq!(report.repaired_keys, vec!["bad".to_string()]);
+
+        assert!(scrub_all_shards(ctx, &bs, None).await?.is_clean());
+        assert_eq!(
+            bad_bytes.as_bytes(),
+            bs.get(ctx, "bad").await?.unwrap().as_raw_bytes()
+        );
+        Ok(())
+    })
+    .await
+}
+
+#[fbinit::test]
+async fn stats(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    bs.put(ctx, "inline".to_string(), BlobstoreBytes::from_bytes("x"))
+        .await?;
+    bs.put(
+        ctx,
+        "chunked".to_string(),
+        BlobstoreBytes::from_bytes(vec![0u8; 1024]),
+    )
+    .await?;
+    bs.get(ctx, "inline").await?;
+    bs.get(ctx, "chunked").await?;
+    bs.get(ctx, "missing").await?;
+
+    let stats = bs.stats();
+    let mut total = ShardStats::default();
+    for shard in 0..stats.shard_count() {
+        let shard_stats = stats.shard_stats(shard);
+        total.gets += shard_stats.gets;
+        total.puts += shard_stats.puts;
+        total.get_errors += shard_stats.get_errors;
+        total.put_errors += shard_stats.put_errors;
+        total.inline_puts += shard_stats.inline_puts;
+    }
+    assert_eq!(total.gets, 3);
+    assert_eq!(total.puts, 2);
+    assert_eq!(total.get_errors + total.put_errors, 0);
+    assert_eq!(total.inline_put_ratio(), Some(0.5));
+
+    let shard = bs.get_data_store().shard("inline");
+    assert_eq!(stats.shard_stats(shard).chunk_cache_hit_rate(), None);
+    stats.record_chunk_cache(shard, true);
+    stats.record_chunk_cache(shard, false);
+    assert_eq!(stats.shard_stats(shard).chunk_cache_hit_rate(), Some(0.5));
+    Ok(())
+}
+
+/// Telemetry that keeps every event as (operation, key, bytes, chunking, success).
+#[derive(Debug, Default)]
+struct RecordingTelemetry {
+    events: std::sync::Mutex<
+        Vec<(
+            &'static str,
+            String,
+            Option<u64>,
+            Option<SqlblobChunking>,
+            bool,
+        )>,
+    >,
+}
+
+impl RecordingTelemetry {
+    fn push(&self, operation: &'static str, event: &SqlblobEvent<'_>) {
+        self.events.lock().unwrap().push((
+            operation,
+            event.key.to_string(),
+            event.bytes,
+            event.chunking,
+            event.success,
+        ));
+    }
+}
+
+impl SqlblobTelemetry for RecordingTelemetry {
+    fn on_put(&self, event: &SqlblobEvent<'_>) {
+        self.push("put", event)
+    }
+
+    fn on_get(&self, event: &SqlblobEvent<'_>) {
+        self.push("get", event)
+    }
+
+    fn on_link(&self, event: &SqlblobEvent<'_>) {
+        self.push("link", event)
+    }
+
+    fn on_unlink(&self, event: &SqlblobEvent<'_>) {
+        self.push("unlink", event)
+    }
+}
+
+#[fbinit::test]
+async fn telemetry(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let telemetry = Arc::new(RecordingTelemetry::default());
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            telemetry: Some(telemetry.clone()),
+            ..Default::default()
+        },
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    bs.put(ctx, "inline".to_string(), BlobstoreBytes::from_bytes("x"))
+        .await?;
+    bs.put(
+        ctx,
+        "chunked".to_string(),
+        BlobstoreBytes::from_bytes(vec![0u8; 1024]),
+    )
+    .await?;
+    bs.get(ctx, "chunked").await?;
+    bs.get(ctx, "missing").await?;
+    bs.link(ctx, "chunked", "linked".to_string()).await?;
+    bs.unlink(ctx, "inline").await?;
+    assert!(bs.unlink(ctx, "missing").await.is_err());
+
+    let inline = Some(SqlblobChunking::Inline);
+    let chunked = Some(SqlblobChunking::Chunked);
+    assert_eq!(
+        *telemetry.events.lock().unwrap(),
+        vec![
+            ("put", "inline".to_string(), Some(1), inline, true),
+            ("put", "chunked".to_string(), Some(1024), chunked, true),
+            ("get", "chunked".to_string(), Some(1024), chunked, true),
+            ("get", "missing".to_string(), None, None, true),
+            ("link", "linked".to_string(), Some(1024), chunked, true),
+            ("unlink", "inline".to_string(), Some(1), inline, true),
+            ("unlink", "missing".to_string(), None, None, false),
+        ]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+fn stats_slow_queries(fb: FacebookInit) {
+    let ctx = CoreContext::test_mock(fb);
+    let stats = SqlblobStats::new(nonzero!(1_usize), Some(Duration::from_millis(100)));
+    let ops = [
+        (SqlblobOperation::Get, 50, true),
+        (SqlblobOperation::Get, 100, true),
+        (SqlblobOperation::Get, 150, false),
+        (SqlblobOperation::Put, 200, true),
+    ];
+    for (operation, elapsed_ms, success) in ops.iter() {
+        stats.record(
+            &ctx,
+            *operation,
+            0,
+            "key",
+            Duration::from_millis(*elapsed_ms),
+            *success,
+        );
+    }
+
+    let shard_stats = stats.shard_stats(0);
+    assert_eq!(shard_stats.gets, 3);
+    assert_eq!(shard_stats.get_errors, 1);
+    assert_eq!(shard_stats.get_time, Duration::from_millis(300));
+    assert_eq!(shard_stats.puts, 1);
+    // Only operations slower than the threshold are counted.
+    assert_eq!(shard_stats.slow_queries, 2);
+
+    // Nothing is slow without a threshold.
+    let stats = SqlblobStats::new(nonzero!(1_usize), None);
+    stats.record(
+        &ctx,
+        SqlblobOperation::Get,
+        0,
+        "key",
+        Duration::from_secs(60),
+        true,
+    );
+    assert_eq!(stats.shard_stats(0).slow_queries, 0);
+}
+
+#[fbinit::test]
+async fn checksum(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let verify_all = || {
+        let tunables = MononokeTunables::default();
+        tunables.update_ints(&hashmap! {
+            "sqlblob_checksum_verify_percentage".to_string() => 100,
+        });
+        tunables
+    };
+
+    let value = BlobstoreBytes::from_bytes("value");
+    bs.put(ctx, "inline".to_string(), value.clone()).await?;
+    bs.put(
+        ctx,
+        "chunked".to_string(),
+        BlobstoreBytes::from_bytes(vec![0u8; 1024]),
+    )
+    .await?;
+    let chunked = bs.data_store.get("inline").await?.unwrap();
+    assert_eq!(chunked.checksum, Some(value_checksum(value.as_bytes())));
+
+    let got = with_tunables_async(verify_all(), bs.get(ctx, "inline")).await?;
+    assert_eq!(got.map(|data| data.into_bytes()), Some(value));
+    let got = with_tunables_async(
+        verify_all(),
+        bs.get_many(ctx, vec!["inline".to_string(), "chunked".to_string()]),
+    )
+    .await?;
+    assert_eq!(got.len(), 2);
+
+    // Change the inline value behind the checksum's back.
+    bs.data_store
+        .put(
+            "inline",
+            chunked.ctime,
+            &base64::encode_config(b"corrupt", base64::STANDARD_NO_PAD),
+            chunked.count,
+            chunked.chunking_method,
+            None,
+            chunked.value_size,
+            chunked.checksum,
+        )
+        .await?;
+
+    // Reads are not checked unless the tunable asks for it.
+    let got = bs.get(ctx, "inline").await?;
+    assert_eq!(got.unwrap().as_raw_bytes().as_ref(), b"corrupt");
+
+    let err = with_tunables_async(verify_all(), bs.get(ctx, "inline"))
+        .await
+        .unwrap_err();
+    let corruption = err
+        .downcast_ref::<SqlblobCorruption>()
+        .expect("corruption error");
+    assert_eq!(corruption.key, "inline");
+    assert_eq!(corruption.expected, chunked.checksum.unwrap() as u64);
+    let err = with_tunables_async(verify_all(), bs.get_many(ctx, vec!["inline".to_string()]))
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<SqlblobCorruption>().is_some());
+
+    let shard_stats = bs.stats().shard_stats(corruption.shard);
+    assert_eq!(shard_stats.checksum_mismatches, 2);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn get_metadata(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    assert_eq!(bs.get_metadata(ctx, "inline").await?, None);
+    bs.put(
+        ctx,
+        "inline".to_string(),
+        BlobstoreBytes::from_bytes("value"),
+    )
+    .await?;
+    bs.put(
+        ctx,
+        "chunked".to_string(),
+        BlobstoreBytes::from_bytes(vec![0u8; CHUNK_SIZE + 1]),
+    )
+    .await?;
+
+    let inline = bs.get_metadata(ctx, "inline").await?.unwrap();
+    assert_eq!(inline.size, Some(5));
+    assert_eq!(inline.chunk_count, 0);
+    assert_eq!(inline.chunking_method, SqlblobChunking::Inline);
+    let chunked = bs.get_metadata(ctx, "chunked").await?.unwrap();
+    assert_eq!(chunked.size, Some(CHUNK_SIZE as u64 + 1));
+    assert_eq!(chunked.chunk_count, 2);
+    assert_eq!(chunked.chunking_method, SqlblobChunking::Chunked);
+    assert_eq!(
+        Some(chunked.ctime),
+        bs.get(ctx, "chunked").await?.unwrap().as_meta().ctime()
+    );
+
+    // Rows written before sizes were recorded only know the size of inline
+    // blobs.
+    for key in ["inline", "chunked"] {
+        let data = bs.data_store.get(key).await?.unwrap();
+        bs.data_store
+            .put(
+                key,
+                data.ctime,
+                &data.id,
+                data.count,
+                data.chunking_method,
+                None,
+                None,
+                data.checksum,
+            )
+            .await?;
+    }
+    assert_eq!(bs.get_metadata(ctx, "inline").await?.unwrap().size, Some(5));
+    assert_eq!(bs.get_metadata(ctx, "chunked").await?.unwrap().size, None);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn chunk_cache(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            chunk_cache: ChunkCacheOptions::InMemory { max_bytes: 2048 },
+            ..Default::default()
+        },
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    // Puts write through to the cache, which only has room for two of these.
+    let mut blobs = Vec::new();
+    for i in 0..3 {
+        let key = format!("cached_{}", i);
+        let mut bytes_in = vec![0u8; 1024];
+        thread_rng().fill_bytes(&mut bytes_in);
+        bs.put(
+            ctx,
+            key.clone(),
+            BlobstoreBytes::from_bytes(bytes_in.clone()),
+        )
+        .await?;
+        blobs.push((key, bytes_in));
+    }
+    for (key, bytes_in) in blobs.iter().rev() {
+        let bytes_out = bs.get(ctx, key).await?.expect("Key was not put");
+        assert_eq!(bytes_in, bytes_out.as_raw_bytes());
+    }
+
+    let cache_stats = || {
+        let stats = bs.stats();
+        let mut total = ShardStats::default();
+        for shard in 0..stats.shard_count() {
+            let shard_stats = stats.shard_stats(shard);
+            total.chunk_cache_hits += shard_stats.chunk_cache_hits;
+            total.chunk_cache_misses += shard_stats.chunk_cache_misses;
+        }
+        (total.chunk_cache_hits, total.chunk_cache_misses)
+    };
+    assert_eq!(cache_stats(), (2, 1));
+
+    // Batched reads only query the chunks that are not cached: the last two
+    // read above.
+    let keys = blobs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+    let many = bs.get_many(ctx, keys).await?;
+    for (key, bytes_in) in blobs.iter() {
+        assert_eq!(bytes_in, many[key].as_raw_bytes());
+    }
+    assert_eq!(cache_stats(), (4, 2));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn read_your_writes(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            read_your_writes: Some(RecentWritesOptions {
+                max_bytes: 2048,
+                max_age: Duration::from_millis(200),
+            }),
+            ..Default::default()
+        },
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let mut blobs = Vec::new();
+    for i in 0..3 {
+        let key = format!("recent_{}", i);
+        let mut bytes_in = vec![0u8; 1024];
+        thread_rng().fill_bytes(&mut bytes_in);
+        bs.put(
+            ctx,
+            key.clone(),
+            BlobstoreBytes::from_bytes(bytes_in.clone()),
+        )
+        .await?;
+        blobs.push((key, bytes_in));
+    }
+
+    // Simulate replicas that haven't caught up by dropping the data rows
+    // behind the blobstore's back.
+    for (key, _) in &blobs {
+        bs.get_data_store().unlink(key).await?;
+    }
+
+    // Only the two most recent writes fit.
+    assert!(bs.get(ctx, &blobs[0].0).await?.is_none());
+    for (key, bytes_in) in &blobs[1..] {
+        let bytes_out = bs.get(ctx, key).await?.expect("Recent write was lost");
+        assert_eq!(bytes_in, bytes_out.as_raw_bytes());
+        assert!(bs.is_present(ctx, key).await?.assume_not_found_if_unsure());
+    }
+    let keys = blobs.iter().map(|(key, _)| key.clone()).collect();
+    let many = bs.get_many(ctx, keys).await?;
+    assert_eq!(many.len(), 2);
+    assert!(!many.contains_key(&blobs[0].0));
+
+    // Once the catch-up window has passed, reads go to the database.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    for (key, _) in &blobs {
+        assert!(bs.get(ctx, key).await?.is_none());
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn copy_shards(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, src, _| async move {
+        borrowed!(ctx);
+        let (_, config_store) = get_test_config_store();
+        let dst = Sqlblob::with_sqlite_in_memory(
+            DEFAULT_PUT_BEHAVIOUR,
+            &config_store,
+            true,
+            SqlblobOptions::default(),
+        )?;
+
+        let mut expected = HashMap::new();
+        for (i, size) in [1, 1024, CHUNK_SIZE + 1].iter().enumerate() {
+            let key = format!("copy_test_{}", i);
+            let mut bytes_in = vec![0u8; *size];
+            thread_rng().fill_bytes(&mut bytes_in);
+            src.put(
+                ctx,
+                key.clone(),
+                BlobstoreBytes::from_bytes(bytes_in.clone()),
+            )
+            .await?;
+            src.set_generation(&key).await?;
+            expected.insert(key, bytes_in);
+        }
+        // Backdate a key, to check that ctimes are preserved.
+        let chunked = src.data_store.get("copy_test_1").await?.unwrap();
+        src.data_store
+            .put(
+                "copy_test_1",
+                1,
+                &chunked.id,
+                chunked.count,
+                chunked.chunking_method,
+                None,
+                chunked.value_size,
+                chunked.checksum,
+            )
+            .await?;
+
+        let mut copied = 0;
+        for shard in 0..SQLITE_SHARD_NUM.get() {
+            copied += migrate::copy_shard(&src, &dst, shard, 10)
+                .await?
+                .keys_copied;
+        }
+        assert_eq!(copied, 3);
+
+        for (key, bytes_in) in &expected {
+            let bytes_out = dst.get(ctx, key).await?.expect("Key was not copied");
+            assert_eq!(bytes_in, bytes_out.as_raw_bytes());
+            assert_eq!(
+                src.get_chunk_generations(key).await?,
+                dst.get_chunk_generations(key).await?
+            );
+        }
+        let copied = dst.get(ctx, "copy_test_1").await?.unwrap();
+        assert_eq!(copied.as_meta().ctime(), Some(1));
+        Ok(())
+    })
+    .await
+}
+
+#[fbinit::test]
+async fn copy_shard_resume(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let src = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let dst = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let mut keys = Vec::new();
+    for i in 0..10 {
+        let key = format!("resume_test_{}", i);
+        src.put(ctx, key.clone(), BlobstoreBytes::from_bytes("x"))
+            .await?;
+        keys.push(key);
+    }
+    let shard = src.get_data_store().shard(&keys[0]);
+    let mut shard_keys: Vec<_> = keys
+        .into_iter()
+        .filter(|key| src.get_data_store().shard(key) == shard)
+        .collect();
+    shard_keys.sort();
+
+    // Resume after the first key, as if a previous run had copied it.
+    let resume_from = migrate::CopyCheckpoint {
+        last_key: Some(shard_keys[0].clone()),
+        keys_copied: 1,
+    };
+    let mut checkpoints = Vec::new();
+    let done = migrate::copy_shard_from(&src, &dst, shard, 2, resume_from, |checkpoint| {
+        checkpoints.push(checkpoint.clone());
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(done.keys_copied, shard_keys.len() as u64);
+    assert_eq!(done.last_key.as_ref(), shard_keys.last());
+    assert_eq!(checkpoints, vec![done]);
+    assert!(!dst
+        .is_present(ctx, &shard_keys[0])
+        .await?
+        .assume_not_found_if_unsure());
+    for key in &shard_keys[1..] {
+        assert!(dst.is_present(ctx, key).await?.assume_not_found_if_unsure());
+    }
+    Ok(())
+}
+
+#[test]
+fn sharding_strategies() -> Result<(), Error> {
+    let shard_count = nonzero!(4_usize);
+    let range = RangeSharding::new(vec![("b".to_string(), 1), ("m".to_string(), 2)])?;
+    assert_eq!(range.shard("a", shard_count), 1);
+    assert_eq!(range.shard("b", shard_count), 1);
+    assert_eq!(range.shard("lz", shard_count), 1);
+    assert_eq!(range.shard("m", shard_count), 2);
+    assert!(range.validate(nonzero!(2_usize)).is_err());
+
+    let explicit = ExplicitSharding::new(
+        hashmap! {
+            "repo0001.".to_string() => 3,
+            "repo0001.content.".to_string() => 2,
+        },
+        vec![0, 1],
+    )?;
+    assert_eq!(explicit.shard("repo0001.changeset.x", shard_count), 3);
+    assert_eq!(explicit.shard("repo0001.content.x", shard_count), 2);
+    for i in 0..100 {
+        let shard = explicit.shard(&format!("repo0002.content.{}", i), shard_count);
+        assert!(shard < 2);
+    }
+    assert!(explicit.validate(nonzero!(3_usize)).is_err());
+    assert!(ExplicitSharding::new(HashMap::new(), vec![]).is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn resharding(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let (_, config_store) = get_test_config_store();
+    let old_sharding: Arc<dyn ShardingStrategy> =
+        Arc::new(RangeSharding::new(vec![("".to_string(), 0)])?);
+    let old = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            sharding: Some(old_sharding.clone()),
+            ..Default::default()
+        },
+    )?
+    .into_inner();
+
+    let mut expected = HashMap::new();
+    for (key, size) in [("repo0001.small", 16), ("repo0001.large", CHUNK_SIZE + 1)] {
+        let mut bytes_in = vec![0u8; size];
+        thread_rng().fill_bytes(&mut bytes_in);
+        old.put(
+            ctx,
+            key.to_string(),
+            BlobstoreBytes::from_bytes(bytes_in.clone()),
+        )
+        .await?;
+        expected.insert(key.to_string(), bytes_in);
+    }
+    let keys: Vec<String> = expected.keys().cloned().collect();
+
+    // Move repo0001 to a shard of its own.
+    let new_sharding: Arc<dyn ShardingStrategy> = Arc::new(ExplicitSharding::new(
+        hashmap! { "repo0001.".to_string() => 1 },
+        vec![0],
+    )?);
+    let moved_only = old.with_sharding_options(&SqlblobOptions {
+        sharding: Some(new_sharding.clone()),
+        ..Default::default()
+    })?;
+    assert_eq!(moved_only.get_data_store().shard("repo0001.small"), 1);
+    assert!(moved_only.get(ctx, "repo0001.small").await?.is_none());
+
+    // Keys that were not moved yet are read from their previous shard.
+    let new = old.with_sharding_options(&SqlblobOptions {
+        sharding: Some(new_sharding),
+        previous_sharding: Some(old_sharding),
+        ..Default::default()
+    })?;
+    for (key, bytes_in) in &expected {
+        let bytes_out = new.get(ctx, key).await?.expect("Key should be found");
+        assert_eq!(bytes_in, bytes_out.as_raw_bytes());
+    }
+    assert_eq!(new.get_many(ctx, keys.clone()).await?.len(), 2);
+    assert_eq!(
+        new.is_present_many(ctx, keys.clone())
+            .await?
+            .values()
+            .filter(|present| matches!(present, BlobstoreIsPresent::Present))
+            .count(),
+        2
+    );
+
+    // Once copied, keys are found on their new shard.
+    assert_eq!(migrate::copy_shard(&old, &new, 0, 10).await?.keys_copied, 2);
+    for key in &keys {
+        assert!(moved_only.get(ctx, key).await?.is_some());
+    }
+
+    // Conditional puts cannot be atomic while a key may be on two shards.
+    let bytes = BlobstoreBytes::from_bytes(vec![1u8; 16]);
+    assert!(new
+        .put_if_absent_atomic(ctx, "repo0001.new".to_string(), bytes)
+        .await
+        .is_err());
+
+    // Unlinking removes the key from both shards.
+    new.unlink(ctx, "repo0001.small").await?;
+    assert!(new.get(ctx, "repo0001.small").await?.is_none());
+    assert!(old.get(ctx, "repo0001.small").await?.is_none());
+    assert!(new.unlink(ctx, "repo0001.small").await.is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn resharding_concurrent_writes(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+    let (_, config_store) = get_test_config_store();
+    let old_sharding: Arc<dyn ShardingStrategy> =
+        Arc::new(RangeSharding::new(vec![("".to_string(), 0)])?);
+    let old = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions {
+            sharding: Some(old_sharding.clone()),
+            ..Default::default()
+        },
+    )?
+    .into_inner();
+    let keys: Vec<String> = (0..20).map(|i| format!("repo0001.key{:02}", i)).collect();
+    for key in &keys {
+        old.put(ctx, key.clone(), BlobstoreBytes::from_bytes("old"))
+            .await?;
+    }
+
+    let new = old.with_sharding_options(&SqlblobOptions {
+        sharding: Some(Arc::new(ExplicitSharding::new(
+            hashmap! { "repo0001.".to_string() => 1 },
+            vec![0],
+        )?)),
+        previous_sharding: Some(old_sharding),
+        ..Default::default()
+    })?;
+
+    // A key written to its new shard before it is copied is not overwritten.
+    new.put(ctx, keys[0].clone(), BlobstoreBytes::from_bytes("new"))
+        .await?;
+
+    // Unlink some keys while the shard is copied.
+    let unlinked = &keys[10..];
+    let (copied, _) = futures::try_join!(migrate::copy_shard(&old, &new, 0, 4), async {
+        for key in unlinked {
+            new.unlink(ctx, key).await?;
+        }
+        Ok::<_, Error>(())
+    })?;
+    assert!(copied.keys_copied <= keys.len() as u64 - 1);
+
+    assert_eq!(
+        new.get(ctx, &keys[0]).await?.unwrap().as_raw_bytes(),
+        &Bytes::from("new")
+    );
+    for key in unlinked {
+        assert!(
+            new.get(ctx, key).await?.is_none(),
+            "{} was resurrected",
+            key
+        );
+    }
+
+    // Copied keys are deleted from their old shard in a separate step.
+    for key in &keys[..10] {
+        assert!(new.get_data_store().get_in_shard(0, key).await?.is_some());
+    }
+    assert_eq!(migrate::remove_copied_keys(&old, &new, 0, 4).await?, 10);
+    for key in &keys[..10] {
+        assert!(new.get_data_store().get_in_shard(0, key).await?.is_none());
+        assert!(new.get(ctx, key).await?.is_some());
+    }
+    // Keys that stay on the shard are kept.
+    old.put(
+        ctx,
+        "repo0002.key".to_string(),
+        BlobstoreBytes::from_bytes("x"),
+    )
+    .await?;
+    assert_eq!(migrate::remove_copied_keys(&old, &new, 0, 4).await?, 0);
+    assert!(new.get(ctx, "repo0002.key").await?.is_some());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn enumerate(fb: FacebookInit) -> Result<(), Error> {
+    test_chunking_methods(fb, DEFAULT_PUT_BEHAVIOUR, |ctx, bs, _| async move {
+        borrowed!(ctx);
+        for key in &["enum_a", "enum_b", "enum_c", "enum_d", "other"] {
+            bs.put(ctx, key.to_string(), BlobstoreBytes::from_bytes("x"))
+                .await?;
+        }
+
+        let mut keys = Vec::new();
+        let mut param = BlobstoreKeyParam::from("enum_b".to_string()..="enum_d".to_string());
+        loop {
+            let data = bs.enumerate(ctx, &param).await?;
+            keys.extend(data.keys);
+            match data.next_token {
+                Some(next) => param = next,
+                None => break,
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["enum_b", "enum_c", "enum_d"]);
+
+        let data = bs.enumerate(ctx, &BlobstoreKeyParam::from(..)).await?;
+        assert!(data.next_token.is_some());
+        Ok(())
+    })
+    .await
+}
+
+#[fbinit::test]
+async fn adaptive_throttle(_fb: FacebookInit) -> Result<(), Error> {
+    let (lag_sender, lag_receiver) = tokio::sync::watch::channel(Duration::from_secs(2));
+    let delay = BlobDelay::from_channels(vec![lag_receiver], "test".to_string())
+        .with_adaptive_throttle(AdaptiveThrottleConfig {
+            target_lag: Duration::from_secs(1),
+            min_concurrency: 1,
+            max_concurrency: 4,
+            initial_put_delay: Duration::from_millis(10),
+            max_put_delay: Duration::from_millis(40),
+        });
+    assert_eq!(
+        delay.throttle_values(0),
+        Some(ThrottleValues {
+            concurrency: 4,
+            put_delay: Duration::from_secs(0),
+        })
+    );
+
+    // Lag above the target backs off both concurrency and delay.
+    let permit = delay.delay(0).await;
+    assert_eq!(
+        delay.throttle_values(0),
+        Some(ThrottleValues {
+            concurrency: 2,
+            put_delay: Duration::from_millis(10),
+        })
+    );
+    // Writes that see the same sample don't back off further.
+    drop(delay.delay(0).await);
+    assert_eq!(
+        delay.throttle_values(0),
+        Some(ThrottleValues {
+            concurrency: 2,
+            put_delay: Duration::from_millis(10),
+        })
+    );
+
+    // Down to a single write at a time, which is already in flight.
+    lag_sender.send(Duration::from_secs(2))?;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), delay.delay(0))
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        delay.throttle_values(0),
+        Some(ThrottleValues {
+            concurrency: 1,
+            put_delay: Duration::from_millis(20),
+        })
+    );
+    drop(permit);
+
+    // Once lag is back under the target, the throttle recovers.
+    for _ in 0..3 {
+        lag_sender.send(Duration::from_millis(100))?;
+        let _permit = delay.delay(0).await;
+    }
+    assert_eq!(
+        delay.throttle_values(0),
+        Some(ThrottleValues {
+            concurrency: 4,
+            put_delay: Duration::from_secs(0),
+        })
+    );
+    Ok(())
+}
+
+async fn gc_mark_all(bs: &Sqlblob) -> Result<u64, Error> {
+    let mut marked = 0;
+    for shard in 0..SQLITE_SHARD_NUM.get() {
+        marked += gc::run_mark(bs, shard, |_| {}).await?.keys_marked;
+    }
+    Ok(marked)
+}
+
+async fn gc_sweep_all(
+    bs: &Sqlblob,
+    delete_generation: u64,
+    dry_run: bool,
+) -> Result<gc::SweepReport, Error> {
+    let mut total = gc::SweepReport::default();
+    for shard in 0..SQLITE_SHARD_NUM.get() {
+        let report = gc::run_sweep(bs, shard, delete_generation, dry_run, |_| {}).await?;
+        total.reclaimable_chunks += report.reclaimable_chunks;
+        total.reclaimable_bytes += report.reclaimable_bytes;
+        total.deleted_chunks += report.deleted_chunks;
+    }
+    Ok(total)
+}
+
+#[fbinit::test]
+async fn gc(fb: FacebookInit) -> Result<(), Error> {
+    let (test_source, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        false,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    let mut bytes_in = [0u8; 1024];
+    thread_rng().fill_bytes(&mut bytes_in);
+    bs.put(ctx, "live".to_string(), BlobstoreBytes::from_bytes("live"))
+        .await?;
+    let dead = Bytes::copy_from_slice(&bytes_in);
+    bs.put(ctx, "dead".to_string(), BlobstoreBytes::from_bytes(dead))
+        .await?;
+    assert_eq!(gc_mark_all(&bs).await?, 2);
+    bs.unlink(ctx, "dead").await?;
+
+    // Nothing can be swept while marking generation 2.
+    set_test_generations(test_source.as_ref(), 3, 2, 0, INITIAL_VERSION + 1);
+    tokio::time::sleep(UPDATE_WAIT_TIME).await;
+    assert!(gc_sweep_all(&bs, 0, true).await.is_err());
+    assert_eq!(gc_mark_all(&bs).await?, 1);
+    set_test_generations(test_source.as_ref(), 3, 2, 1, INITIAL_VERSION + 2);
+    tokio::time::sleep(UPDATE_WAIT_TIME).await;
+    // Only the configured delete generation can be swept.
+    assert!(gc_sweep_all(&bs, 0, true).await.is_err());
+    // Both chunks were given the put generation when first marked, so
+    // neither is old enough yet.
+    assert_eq!(
+        gc_sweep_all(&bs, 1, false).await?,
+        gc::SweepReport::default()
+    );
+
+    // The next cycle leaves the dead chunk behind.
+    set_test_generations(test_source.as_ref(), 4, 3, 1, INITIAL_VERSION + 3);
+    tokio::time::sleep(UPDATE_WAIT_TIME).await;
+    assert_eq!(gc_mark_all(&bs).await?, 1);
+    set_test_generations(test_source.as_ref(), 4, 3, 2, INITIAL_VERSION + 4);
+    tokio::time::sleep(UPDATE_WAIT_TIME).await;
+    let dry_run = gc_sweep_all(&bs, 2, true).await?;
+    assert_eq!(dry_run.reclaimable_chunks, 1);
+    assert!(dry_run.reclaimable_bytes >= 1024);
+    assert_eq!(dry_run.deleted_chunks, 0);
+
+    let swept = gc_sweep_all(&bs, 2, false).await?;
+    assert_eq!(swept.deleted_chunks, 1);
+    assert_eq!(
+        bs.get(ctx, "live")
+            .await?
+            .expect("live was swept")
+            .as_raw_bytes(),
+        &Bytes::from("live")
+    );
+    assert_eq!(
+        gc_sweep_all(&bs, 2, false).await?,
+        gc::SweepReport::default()
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn secondary(fb: FacebookInit) -> Result<(), Error> {
+    let (_, config_store) = get_test_config_store();
+    let secondary = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?
+    .into_inner()
+    .with_secondary(secondary.into_inner(), SecondaryWriterOptions::default());
+    let ctx = CoreContext::test_mock(fb);
+    borrowed!(ctx);
+
+    bs.put(ctx, "inline".to_string(), BlobstoreBytes::from_bytes("x"))
+        .await?;
+    bs.put(
+        ctx,
+        "chunked".to_string(),
+        BlobstoreBytes::from_bytes(vec![0u8; 1024]),
+    )
+    .await?;
+    // Skip the secondary, as if the put was dropped.
+    bs.put_untimed(
+        "missed",
+        BlobstoreBytes::from_bytes("y"),
+        DEFAULT_PUT_BEHAVIOUR,
+        None,
+    )
+    .await?;
+
+    wait_for_secondary(&bs, 2).await?;
+
+    let mut report = CatchUpReport::default();
+    for shard in 0..bs.data_store.shard_count() {
+        let shard_report = bs.catch_up(shard).await?;
+        report.keys_checked += shard_report.keys_checked;
+        report.keys_copied += shard_report.keys_copied;
+    }
+    assert_eq!(
+        report,
+        CatchUpReport {
+            keys_checked: 3,
+            keys_copied: 1,
+        }
+    );
+
+    // Everything is on the secondary now.
+    for shard in 0..bs.data_store.shard_count() {
+        assert_eq!(bs.catch_up(shard).await?.keys_copied, 0);
+    }
+
+    // Links and unlinks are mirrored too.
+    bs.link(ctx, "inline", "linked".to_string()).await?;
+    bs.unlink(ctx, "chunked").await?;
+    bs.unlink_many(ctx, vec!["missed".to_string()]).await?;
+    wait_for_secondary(&bs, 5).await?;
+    let secondary = bs.secondary.as_ref().unwrap().secondary();
+    assert!(secondary.data_store.get("linked").await?.is_some());
+    assert!(secondary.data_store.get("chunked").await?.is_none());
+    assert!(secondary.data_store.get("missed").await?.is_none());
+    Ok(())
+}
+
+async fn wait_for_secondary(bs: &Sqlblob, mirrored: u64) -> Result<(), Error> {
+    let expected = SecondaryWriterStats {
+        mirrored,
+        ..Default::default()
+    };
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while bs.secondary_stats() != Some(expected.clone()) {
+            tokio::time::sleep(UPDATE_WAIT_TIME).await;
+        }
+    })
+    .await
+    .with_context(|| format!("secondary stats: {:?}", bs.secondary_stats()))
+}
+
+// The schema before versions were recorded, without `expiry_time`.
+const SCHEMA_V1: &str = "
+CREATE TABLE `data` (
+  `id` VARCHAR(255) NOT NULL,
+  `creation_time` BIGINT NOT NULL,
+  `chunk_id` VARCHAR(255) NOT NULL,
+  `chunk_count` INT UNSIGNED NOT NULL,
+  `chunking_method` INT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`)
+);
+CREATE TABLE `chunk` (
+  `id` VARCHAR(255) NOT NULL,
+  `creation_time` TIMESTAMP DEFAULT CURRENT NOT NULL,
+  `chunk_num` INT UNSIGNED NOT NULL,
+  `value` BLOB NOT NULL,
+  PRIMARY KEY (`id`, `chunk_num`)
+);
+CREATE TABLE `chunk_generation` (
+  `id` VARCHAR(255) NOT NULL,
+  `last_seen_generation` BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`)
+);";
+
+// The last schema before versions were recorded, with `expiry_time`.
+const SCHEMA_V2: &str = "
+CREATE TABLE `data` (
+  `id` VARCHAR(255) NOT NULL,
+  `creation_time` BIGINT NOT NULL,
+  `chunk_id` VARCHAR(255) NOT NULL,
+  `chunk_count` INT UNSIGNED NOT NULL,
+  `chunking_method` INT UNSIGNED NOT NULL,
+  `expiry_time` BIGINT NULL,
+  PRIMARY KEY (`id`)
+);
+CREATE TABLE `chunk` (
+  `id` VARCHAR(255) NOT NULL,
+  `creation_time` TIMESTAMP DEFAULT CURRENT NOT NULL,
+  `chunk_num` INT UNSIGNED NOT NULL,
+  `value` BLOB NOT NULL,
+  PRIMARY KEY (`id`, `chunk_num`)
+);
+CREATE TABLE `chunk_generation` (
+  `id` VARCHAR(255) NOT NULL,
+  `last_seen_generation` BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`)
+);";
+
+fn recorded_schema_versions(con: &SqliteConnection) -> Result<Vec<u32>, Error> {
+    let mut stmt = con.prepare("SELECT version FROM schema_version ORDER BY version")?;
+    let versions = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<u32>, _>>()?;
+    Ok(versions)
+}
+
+#[test]
+fn schema_migrate_fresh() -> Result<(), Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    assert_eq!(schema::migrate_sqlite(&con)?, schema::SCHEMA_VERSION);
+    assert_eq!(
+        recorded_schema_versions(&con)?,
+        vec![schema::SCHEMA_VERSION]
+    );
+
+    // Migrating again changes nothing.
+    assert_eq!(schema::migrate_sqlite(&con)?, schema::SCHEMA_VERSION);
+    assert_eq!(
+        recorded_schema_versions(&con)?,
+        vec![schema::SCHEMA_VERSION]
+    );
+    Ok(())
+}
+
+#[test]
+fn schema_migrate_from_v1() -> Result<(), Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(SCHEMA_V1)?;
+    con.execute_batch(
+        "INSERT INTO data (id, creation_time, chunk_id, chunk_count, chunking_method)
+         VALUES ('key', 1, 'chunk', 1, 1);",
+    )?;
+
+    assert_eq!(schema::migrate_sqlite(&con)?, 1);
+    assert_eq!(
+        recorded_schema_versions(&con)?,
+        vec![schema::SCHEMA_VERSION]
+    );
+    let expiry: Option<i64> =
+        con.query_row("SELECT expiry_time FROM data WHERE id = 'key'", [], |row| {
+            row.get(0)
+        })?;
+    assert_eq!(expiry, None);
+    let value_size: Option<u64> =
+        con.query_row("SELECT value_size FROM data WHERE id = 'key'", [], |row| {
+            row.get(0)
+        })?;
+    assert_eq!(value_size, None);
+    let checksum: Option<i64> =
+        con.query_row("SELECT checksum FROM data WHERE id = 'key'", [], |row| {
+            row.get(0)
+        })?;
+    assert_eq!(checksum, None);
+
+    assert_eq!(schema::migrate_sqlite(&con)?, schema::SCHEMA_VERSION);
+    Ok(())
+}
+
+#[test]
+fn schema_migrate_from_unversioned_v2() -> Result<(), Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(SCHEMA_V2)?;
+
+    assert_eq!(schema::migrate_sqlite(&con)?, 2);
+    assert_eq!(
+        recorded_schema_versions(&con)?,
+        vec![schema::SCHEMA_VERSION]
+    );
+    Ok(())
+}
+
+#[test]
+fn schema_migrate_from_newer_version() -> Result<(), Error> {
+    let con = SqliteConnection::open_in_memory()?;
+    schema::migrate_sqlite(&con)?;
+    con.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        [schema::SCHEMA_VERSION + 1],
+    )?;
+
+    assert!(schema::migrate_sqlite(&con).is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn schema_migrate_blobstore(fb: FacebookInit) -> Result<(), Error> {
+    let (_test_source, config_store) = get_test_config_store();
+    let bs = Sqlblob::with_sqlite_in_memory(
+        DEFAULT_PUT_BEHAVIOUR,
+        &config_store,
+        true,
+        SqlblobOptions::default(),
+    )?;
+    let ctx = CoreContext::test_mock(fb);
+
+    // SQLite shards are already migrated by the time they are opened.
+    assert_eq!(
+        bs.migrate_schema().await?,
+        vec![schema::SCHEMA_VERSION; bs.data_store.shard_count()]
+    );
+    bs.put_with_ttl(
+        &ctx,
+        "key".to_string(),
+        BlobstoreBytes::from_bytes(Bytes::from_static(b"value")),
+        Duration::from_secs(3600),
+    )
+    .await?;
+    assert!(bs.get(&ctx, "key").await?.is_some());
+    Ok(())
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Adaptive throttling of writes, driven by replication lag.
+//!
+//! `BlobDelay` on its own only holds writes back once lag passes `MAX_LAG`,
+//! which lets a bulk import push a shard right up to that limit over and
+//! over. With an `AdaptiveThrottle`, every lag sample also adjusts how many
+//! writes may be in flight on the shard and how long each write waits
+//! before starting: both are backed off multiplicatively while lag is above
+//! the target, and recovered gradually once it is below. Each sample is
+//! applied once, however many writes see it, so the rate of adjustment
+//! follows the lag monitor and not the write rate.
+
+use std::{
+    cmp::{max, min},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{watch, Notify};
+
+/// Bounds for the adaptive write throttle.
+#[derive(Clone, Debug)]
+pub struct AdaptiveThrottleConfig {
+    /// Replication lag above which writes to a shard are slowed down.
+    pub target_lag: Duration,
+    pub min_concurrency: usize,
+    pub max_concurrency: usize,
+    /// Delay before each write once lag first exceeds the target. It doubles
+    /// each time lag is still above the target, up to `max_put_delay`.
+    pub initial_put_delay: Duration,
+    pub max_put_delay: Duration,
+}
+
+impl Default for AdaptiveThrottleConfig {
+    fn default() -> Self {
+        Self {
+            target_lag: Duration::from_secs(1),
+            min_concurrency: 1,
+            max_concurrency: 64,
+            initial_put_delay: Duration::from_millis(10),
+            max_put_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The current throttle for one shard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleValues {
+    /// How many writes may be in flight at once.
+    pub concurrency: usize,
+    /// How long each write waits before starting.
+    pub put_delay: Duration,
+}
+
+struct ShardThrottle {
+    values: Mutex<ThrottleValues>,
+    samples: Mutex<LagSamples>,
+    in_flight: AtomicUsize,
+    released: Notify,
+}
+
+/// The lag samples of a shard, and whether the throttle has adjusted to the
+/// one it started with.
+struct LagSamples {
+    receiver: watch::Receiver<Duration>,
+    initial_applied: bool,
+}
+
+pub(crate) struct AdaptiveThrottle {
+    config: AdaptiveThrottleConfig,
+    shards: Vec<ShardThrottle>,
+}
+
+impl AdaptiveThrottle {
+    pub(crate) fn new(
+        config: AdaptiveThrottleConfig,
+        lag_receivers: &[watch::Receiver<Duration>],
+    ) -> Self {
+        let initial = ThrottleValues {
+            concurrency: config.max_concurrency,
+            put_delay: Duration::from_secs(0),
+        };
+        Self {
+            config,
+            shards: lag_receivers
+                .iter()
+                .map(|receiver| ShardThrottle {
+                    values: Mutex::new(initial),
+                    samples: Mutex::new(LagSamples {
+                        receiver: receiver.clone(),
+                        initial_applied: false,
+                    }),
+                    in_flight: AtomicUsize::new(0),
+                    released: Notify::new(),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn values(&self, shard: usize) -> ThrottleValues {
+        *self.shards[shard].values.lock().expect("lock poisoned")
+    }
+
+    /// Adjust the throttle for a shard to its latest lag sample, if it has
+    /// not been adjusted to it yet, and return the current values.
+    pub(crate) fn observe_lag(&self, shard: usize) -> ThrottleValues {
+        let lag = {
+            let mut samples = self.shards[shard].samples.lock().expect("lock poisoned");
+            let changed = samples.receiver.has_changed().unwrap_or(false);
+            if samples.initial_applied && !changed {
+                return self.values(shard);
+            }
+            samples.initial_applied = true;
+            let lag = *samples.receiver.borrow_and_update();
+            lag
+        };
+        self.record_lag(shard, lag)
+    }
+
+    /// Adjust the throttle for a shard given a new lag sample, and return
+    /// the adjusted values.
+    fn record_lag(&self, shard: usize, lag: Duration) -> ThrottleValues {
+        let config = &self.config;
+        let mut values = self.shards[shard].values.lock().expect("lock poisoned");
+        if lag > config.target_lag {
+            values.concurrency = max(config.min_concurrency, values.concurrency / 2);
+            values.put_delay = min(
+                config.max_put_delay,
+                max(config.initial_put_delay, values.put_delay * 2),
+            );
+        } else {
+            values.concurrency = min(config.max_concurrency, values.concurrency + 1);
+            values.put_delay /= 2;
+            if values.put_delay < config.initial_put_delay {
+                values.put_delay = Duration::from_secs(0);
+            }
+        }
+        *values
+    }
+
+    /// Wait until another write may start on the shard.
+    pub(crate) async fn acquire(self: &Arc<Self>, shard: usize) -> WritePermit {
+        let shard_throttle = &self.shards[shard];
+        loop {
+            let limit = self.values(shard).concurrency;
+            let in_flight = shard_throttle.in_flight.load(Ordering::SeqCst);
+            if in_flight < limit {
+                if shard_throttle
+                    .in_flight
+                    .compare_exchange(in_flight, in_flight + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return WritePermit {
+                        throttle: Some((self.clone(), shard)),
+                    };
+                }
+            } else {
+                shard_throttle.released.notified().await;
+            }
+        }
+    }
+}
+
+/// Held while a write is in flight. Dropping it lets another write to the
+/// same shard start.
+#[must_use]
+pub struct WritePermit {
+    throttle: Option<(Arc<AdaptiveThrottle>, usize)>,
+}
+
+impl WritePermit {
+    pub(crate) fn unthrottled() -> Self {
+        Self { throttle: None }
+    }
+}
+
+impl Drop for WritePermit {
+    fn drop(&mut self) {
+        if let Some((throttle, shard)) = self.throttle.take() {
+            let shard_throttle = &throttle.shards[shard];
+            shard_throttle.in_flight.fetch_sub(1, Ordering::SeqCst);
+            shard_throttle.released.notify_one();
+        }
+    }
+}
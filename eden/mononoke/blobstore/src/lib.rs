@@ -459,6 +459,20 @@ pub trait BlobstoreWithLink: Blobstore + BlobstorePutOps {
     async fn unlink<'a>(&'a self, ctx: &'a CoreContext, key: &'a str) -> Result<()>;
 }
 
+/// Batch removal of keys, for blobstores that can do better than one `unlink()` per key
+#[async_trait]
+#[auto_impl(Arc, Box)]
+pub trait BlobstoreUnlinkOps: BlobstoreWithLink {
+    /// Removes all of `keys`. An error is returned if any of them does not exist, in which case
+    /// implementations that check up front remove none of them.
+    async fn unlink_many<'a>(&'a self, ctx: &'a CoreContext, keys: Vec<String>) -> Result<()> {
+        for key in &keys {
+            self.unlink(ctx, key).await?;
+        }
+        Ok(())
+    }
+}
+
 /// BlobstoreKeySource Interface
 /// Abstract for use with populate_healer
 #[async_trait]
@@ -483,7 +497,13 @@ pub struct BlobstoreKeyRange {
 pub enum BlobstoreKeyToken {
     // For fileblob and manifold
     StringToken(String),
-    // its an enum as other stores might have non-string tokens
+    // For sqlblob, which enumerates one shard at a time, in key order. The
+    // range is carried over to the shards that haven't been started yet.
+    ShardToken {
+        shard: usize,
+        last_key: Option<String>,
+        range: BlobstoreKeyRange,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
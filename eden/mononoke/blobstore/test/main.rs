@@ -24,7 +24,7 @@ use context::CoreContext;
 use fileblob::Fileblob;
 use memblob::Memblob;
 use mononoke_types::BlobstoreBytes;
-use sqlblob::{get_test_config_store, Sqlblob};
+use sqlblob::{get_test_config_store, Sqlblob, SqlblobOptions};
 
 async fn overwrite<B: Blobstore + BlobstorePutOps>(
     fb: FacebookInit,
@@ -245,7 +245,7 @@ blobstore_test_impl! {
 blobstore_test_impl! {
     sqlblob_test_no_inline => {
         state: (),
-        new: move |_, put_behaviour,| Sqlblob::with_sqlite_in_memory(put_behaviour, &(get_test_config_store().1), false),
+        new: move |_, put_behaviour,| Sqlblob::with_sqlite_in_memory(put_behaviour, &(get_test_config_store().1), false, SqlblobOptions::default()),
         persistent: true,
         has_ctime: true,
     }
@@ -254,7 +254,7 @@ blobstore_test_impl! {
 blobstore_test_impl! {
     sqlblob_test_allow_inline => {
         state: (),
-        new: move |_, put_behaviour,| Sqlblob::with_sqlite_in_memory(put_behaviour, &(get_test_config_store().1), true),
+        new: move |_, put_behaviour,| Sqlblob::with_sqlite_in_memory(put_behaviour, &(get_test_config_store().1), true, SqlblobOptions::default()),
         persistent: true,
         has_ctime: true,
     }
@@ -0,0 +1,211 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Benchmarks of the SQL changesets store on a synthetic repo.
+//!
+//! The repo is written to SQLite directly, as adding a million changesets
+//! one at a time through `Changesets::add` would take far longer than the
+//! benchmarks themselves.
+
+#![deny(warnings)]
+
+use anyhow::Result;
+use criterion::{BenchmarkId, Criterion, Throughput};
+use futures::TryStreamExt;
+use tokio::runtime::Runtime;
+
+use changesets::{Changesets, SortOrder};
+use changesets_impl::{SqlChangesets, SqlChangesetsBuilder};
+use context::CoreContext;
+use mononoke_types::{hash::Context, ChangesetId, ChangesetIdPrefix, RepositoryId};
+use rendezvous::RendezVousOptions;
+use sql::{
+    rusqlite::{params, Connection as SqliteConnection},
+    Connection,
+};
+use sql_construct::SqlConstruct;
+use sql_ext::SqlConnections;
+
+const REPO_ID: RepositoryId = RepositoryId::new(0);
+const COMMIT_COUNT: u64 = 1_000_000;
+/// Every `MERGE_EVERY`th commit also has a parent `MERGE_EVERY / 2` commits
+/// back.
+const MERGE_EVERY: u64 = 100;
+
+fn cs_id(index: u64) -> ChangesetId {
+    let mut context = Context::new(b"benchmark_changesets");
+    context.update(index.to_le_bytes());
+    ChangesetId::new(context.finish())
+}
+
+/// A linear history of `COMMIT_COUNT` commits with regular merges. The
+/// commit at `index` has id `index + 1`.
+fn synthetic_repo() -> Result<SqlChangesets> {
+    let mut con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(SqlChangesetsBuilder::CREATION_QUERY)?;
+    let txn = con.transaction()?;
+    {
+        let mut insert_changeset =
+            txn.prepare("INSERT INTO changesets (id, repo_id, cs_id, gen) VALUES (?, ?, ?, ?)")?;
+        let mut insert_parent =
+            txn.prepare("INSERT INTO csparents (cs_id, parent_id, seq) VALUES (?, ?, ?)")?;
+        for index in 0..COMMIT_COUNT {
+            let id = index + 1;
+            insert_changeset.execute(params![
+                id as i64,
+                REPO_ID.id(),
+                cs_id(index).as_ref(),
+                id as i64
+            ])?;
+            if index > 0 {
+                insert_parent.execute(params![id as i64, index as i64, 0])?;
+            }
+            if index >= MERGE_EVERY && index % MERGE_EVERY == 0 {
+                let merged = id - MERGE_EVERY / 2;
+                insert_parent.execute(params![id as i64, merged as i64, 1])?;
+            }
+        }
+    }
+    txn.commit()?;
+
+    Ok(
+        SqlChangesetsBuilder::from_sql_connections(SqlConnections::new_single(
+            Connection::with_sqlite(con),
+        ))
+        .build(RendezVousOptions::for_test(), REPO_ID),
+    )
+}
+
+fn get_many_benchmark(
+    c: &mut Criterion,
+    runtime: &Runtime,
+    ctx: &CoreContext,
+    changesets: &SqlChangesets,
+) {
+    let mut group = c.benchmark_group("get_many");
+    for batch_size in [1, 100, 1000] {
+        // Spread the batch over the whole repo.
+        let step = COMMIT_COUNT / batch_size;
+        let cs_ids: Vec<_> = (0..batch_size).map(|i| cs_id(i * step)).collect();
+        group.throughput(Throughput::Elements(batch_size));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(batch_size),
+            &cs_ids,
+            |b, cs_ids| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let entries = changesets
+                            .get_many(ctx.clone(), cs_ids.clone())
+                            .await
+                            .expect("get_many failed");
+                        assert_eq!(entries.len(), cs_ids.len());
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn get_many_by_prefix_benchmark(
+    c: &mut Criterion,
+    runtime: &Runtime,
+    ctx: &CoreContext,
+    changesets: &SqlChangesets,
+) {
+    let mut group = c.benchmark_group("get_many_by_prefix");
+    let target = cs_id(COMMIT_COUNT / 2);
+    // A 2 byte prefix matches about 15 commits, and a 4 byte prefix only one.
+    for prefix_len in [2, 4] {
+        let prefix =
+            ChangesetIdPrefix::from_bytes(&target.as_ref()[..prefix_len]).expect("invalid prefix");
+        group.bench_with_input(
+            BenchmarkId::from_parameter(prefix_len),
+            &prefix,
+            |b, prefix| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        changesets
+                            .get_many_by_prefix(ctx.clone(), *prefix, 10)
+                            .await
+                            .expect("get_many_by_prefix failed");
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn enumeration_benchmark(
+    c: &mut Criterion,
+    runtime: &Runtime,
+    ctx: &CoreContext,
+    changesets: &SqlChangesets,
+) {
+    let (min_id, max_id) = runtime
+        .block_on(changesets.enumeration_bounds(ctx, false))
+        .expect("enumeration_bounds failed")
+        .expect("repo is empty");
+
+    let mut group = c.benchmark_group("list_enumeration_range");
+    group.throughput(Throughput::Elements(COMMIT_COUNT));
+    group.bench_function("all", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let count = changesets
+                    .list_enumeration_range(ctx, min_id, max_id + 1, None, false)
+                    .try_fold(0u64, |count, _| async move { Ok(count + 1) })
+                    .await
+                    .expect("list_enumeration_range failed");
+                assert_eq!(count, COMMIT_COUNT);
+            })
+        })
+    });
+
+    // Pages of the size the enumeration jobs use, resuming after the last
+    // id of the previous page.
+    const PAGE_SIZE: u64 = 10_000;
+    group.bench_function("paged", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut next_id = min_id;
+                while next_id <= max_id {
+                    let page: Vec<_> = changesets
+                        .list_enumeration_range(
+                            ctx,
+                            next_id,
+                            max_id + 1,
+                            Some((SortOrder::Ascending, PAGE_SIZE)),
+                            false,
+                        )
+                        .try_collect()
+                        .await
+                        .expect("list_enumeration_range failed");
+                    match page.last() {
+                        Some((_, id)) => next_id = id + 1,
+                        None => break,
+                    }
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+#[fbinit::main]
+fn main(fb: fbinit::FacebookInit) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let ctx = CoreContext::test_mock(fb);
+    let changesets = synthetic_repo().expect("failed to create synthetic repo");
+
+    let mut criterion = Criterion::default().sample_size(10);
+    get_many_benchmark(&mut criterion, &runtime, &ctx, &changesets);
+    get_many_by_prefix_benchmark(&mut criterion, &runtime, &ctx, &changesets);
+    enumeration_benchmark(&mut criterion, &runtime, &ctx, &changesets);
+    criterion.final_summary();
+}
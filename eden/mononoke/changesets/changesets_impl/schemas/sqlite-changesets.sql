@@ -14,9 +14,13 @@ CREATE TABLE IF NOT EXISTS changesets (
   UNIQUE (repo_id, cs_id)
 );
 
+CREATE INDEX IF NOT EXISTS repo_id_gen ON changesets (repo_id, gen);
+
 CREATE TABLE IF NOT EXISTS csparents (
   cs_id BIGINT NOT NULL,
   parent_id BIGINT NOT NULL,
   seq INTEGER NOT NULL,
   PRIMARY KEY (cs_id, seq)
 );
+
+CREATE INDEX IF NOT EXISTS parent_id ON csparents (parent_id);
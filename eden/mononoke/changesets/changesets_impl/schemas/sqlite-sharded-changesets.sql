@@ -0,0 +1,36 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+-- Allocates ids for changesets in all shards. Only used in the first shard.
+CREATE TABLE IF NOT EXISTS changesets_ids (
+  -- Sqlite doesn't support autoincrement UNSIGNED BIGINT
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS changesets (
+  id BIGINT PRIMARY KEY NOT NULL,
+  repo_id INTEGER NOT NULL,
+  cs_id VARBINARY(32) NOT NULL,
+  gen BIGINT NOT NULL,
+  UNIQUE (repo_id, cs_id)
+);
+
+CREATE INDEX IF NOT EXISTS repo_id_gen ON changesets (repo_id, gen);
+CREATE INDEX IF NOT EXISTS repo_id_id ON changesets (repo_id, id);
+
+-- Parents are stored in the shard of the child, by changeset id, as they
+-- may be in another shard.
+CREATE TABLE IF NOT EXISTS csparents (
+  repo_id INTEGER NOT NULL,
+  cs_id VARBINARY(32) NOT NULL,
+  parent_cs_id VARBINARY(32) NOT NULL,
+  seq INTEGER NOT NULL,
+  PRIMARY KEY (repo_id, cs_id, seq)
+);
+
+CREATE INDEX IF NOT EXISTS repo_id_parent_cs_id ON csparents (repo_id, parent_cs_id);
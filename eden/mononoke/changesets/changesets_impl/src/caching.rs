@@ -14,11 +14,13 @@ use caching_ext::{
     MemcacheEntity, MemcacheHandler,
 };
 use changeset_entry_thrift as thrift;
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SortOrder};
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
 use context::CoreContext;
 use fbinit::FacebookInit;
 use fbthrift::compact_protocol;
-use futures::stream::BoxStream;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
 use maplit::hashset;
 use memcache::{KeyGen, MemcacheClient};
 use mononoke_types::{
@@ -27,7 +29,8 @@ use mononoke_types::{
 use ref_cast::RefCast;
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 #[cfg(test)]
@@ -37,10 +40,27 @@ pub fn get_cache_key(repo_id: RepositoryId, cs_id: &ChangesetId) -> String {
     format!("{}.{}", repo_id.prefix(), cs_id)
 }
 
+/// Above this many entries, expired entries are dropped from the negative
+/// cache, and if that isn't enough, it is emptied.
+const MAX_NEGATIVE_CACHE_ENTRIES: usize = 100_000;
+
 #[derive(Clone, Debug, Abomonation, RefCast)]
 #[repr(transparent)]
 pub struct ChangesetEntryWrapper(ChangesetEntry);
 
+/// How long `CachingChangesets` keeps the results of lookups.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CachingChangesetsOptions {
+    /// How long found changesets are cached for. Changesets never change
+    /// once added, so by default they don't expire.
+    pub ttl: Option<Duration>,
+    /// How long a changeset that wasn't found is remembered as missing, so
+    /// that repeated lookups of it don't reach the database. Changesets
+    /// added by other processes won't be seen until this has passed, so it
+    /// should be short. Negative results are only cached in process.
+    pub negative_ttl: Option<Duration>,
+}
+
 #[derive(Clone)]
 pub struct CachingChangesets {
     changesets: Arc<dyn Changesets>,
@@ -48,6 +68,8 @@ pub struct CachingChangesets {
     memcache: MemcacheHandler,
     keygen: KeyGen,
     repo_id: RepositoryId,
+    options: CachingChangesetsOptions,
+    negative_cache: Arc<Mutex<HashMap<ChangesetId, Instant>>>,
 }
 
 fn get_keygen() -> KeyGen {
@@ -74,9 +96,15 @@ impl CachingChangesets {
                 .expect("Memcache initialization failed")
                 .into(),
             keygen: get_keygen(),
+            options: CachingChangesetsOptions::default(),
+            negative_cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    pub fn with_options(self, options: CachingChangesetsOptions) -> Self {
+        Self { options, ..self }
+    }
+
     #[cfg(test)]
     pub fn mocked(changesets: Arc<dyn Changesets>) -> Self {
         let cachelib = CachelibHandler::create_mock();
@@ -88,6 +116,8 @@ impl CachingChangesets {
             cachelib,
             memcache,
             keygen: get_keygen(),
+            options: CachingChangesetsOptions::default(),
+            negative_cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -99,6 +129,8 @@ impl CachingChangesets {
             cachelib: CachelibHandler::create_mock(),
             memcache: self.memcache.clone(),
             keygen: self.keygen.clone(),
+            options: self.options,
+            negative_cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -110,6 +142,47 @@ impl CachingChangesets {
         }
     }
 
+    /// Drop the changesets that are known to be missing from `cs_ids`.
+    fn filter_known_missing(&self, cs_ids: Vec<ChangesetId>) -> Vec<ChangesetId> {
+        if self.options.negative_ttl.is_none() {
+            return cs_ids;
+        }
+        let now = Instant::now();
+        let negative_cache = self.negative_cache.lock().expect("lock poisoned");
+        cs_ids
+            .into_iter()
+            .filter(|cs_id| match negative_cache.get(cs_id) {
+                Some(expiry) => *expiry <= now,
+                None => true,
+            })
+            .collect()
+    }
+
+    fn record_missing(&self, cs_ids: impl IntoIterator<Item = ChangesetId>) {
+        if let Some(negative_ttl) = self.options.negative_ttl {
+            let now = Instant::now();
+            let mut negative_cache = self.negative_cache.lock().expect("lock poisoned");
+            if negative_cache.len() >= MAX_NEGATIVE_CACHE_ENTRIES {
+                negative_cache.retain(|_, expiry| *expiry > now);
+                if negative_cache.len() >= MAX_NEGATIVE_CACHE_ENTRIES {
+                    negative_cache.clear();
+                }
+            }
+            for cs_id in cs_ids {
+                negative_cache.insert(cs_id, now + negative_ttl);
+            }
+        }
+    }
+
+    fn forget_missing<'a>(&self, cs_ids: impl IntoIterator<Item = &'a ChangesetId>) {
+        if self.options.negative_ttl.is_some() {
+            let mut negative_cache = self.negative_cache.lock().expect("lock poisoned");
+            for cs_id in cs_ids {
+                negative_cache.remove(cs_id);
+            }
+        }
+    }
+
     #[cfg(test)]
     pub fn memcache_stats(&self) -> MockStoreStats {
         match self.memcache {
@@ -126,7 +199,10 @@ impl Changesets for CachingChangesets {
     }
 
     async fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> Result<bool, Error> {
-        self.changesets.add(ctx, cs).await
+        let cs_id = cs.cs_id;
+        let added = self.changesets.add(ctx, cs).await?;
+        self.forget_missing(&[cs_id]);
+        Ok(added)
     }
 
     async fn get(
@@ -134,9 +210,16 @@ impl Changesets for CachingChangesets {
         ctx: CoreContext,
         cs_id: ChangesetId,
     ) -> Result<Option<ChangesetEntry>, Error> {
+        if self.filter_known_missing(vec![cs_id]).is_empty() {
+            return Ok(None);
+        }
         let ctx = (&ctx, self);
         let mut map = get_or_fill(ctx, hashset![cs_id]).await?;
-        Ok(map.remove(&cs_id).map(|entry| entry.0))
+        let entry = map.remove(&cs_id).map(|entry| entry.0);
+        if entry.is_none() {
+            self.record_missing([cs_id]);
+        }
+        Ok(entry)
     }
 
     async fn get_many(
@@ -144,12 +227,17 @@ impl Changesets for CachingChangesets {
         ctx: CoreContext,
         cs_ids: Vec<ChangesetId>,
     ) -> Result<Vec<ChangesetEntry>, Error> {
+        let mut cs_ids: HashSet<_> = self.filter_known_missing(cs_ids).into_iter().collect();
         let ctx = (&ctx, self);
-        let res = get_or_fill(ctx, cs_ids.into_iter().collect())
+        let res: Vec<_> = get_or_fill(ctx, cs_ids.clone())
             .await?
             .into_iter()
             .map(|(_, val)| val.0)
             .collect();
+        for entry in &res {
+            cs_ids.remove(&entry.cs_id);
+        }
+        self.record_missing(cs_ids);
         Ok(res)
     }
 
@@ -172,7 +260,32 @@ impl Changesets for CachingChangesets {
             .await
     }
 
+    async fn get_many_with_generation_bounds(
+        &self,
+        ctx: CoreContext,
+        min_gen: u64,
+        max_gen: u64,
+        limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let entries = self
+            .changesets
+            .get_many_with_generation_bounds(ctx.clone(), min_gen, max_gen, limit)
+            .await?;
+        self.prime_cache(&ctx, &entries);
+        Ok(entries)
+    }
+
+    async fn get_children(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        // Children change as commits are added, so they aren't cached.
+        self.changesets.get_children(ctx, cs_id).await
+    }
+
     fn prime_cache(&self, _ctx: &CoreContext, changesets: &[ChangesetEntry]) {
+        self.forget_missing(changesets.iter().map(|cs| &cs.cs_id));
         for cs in changesets {
             assert_eq!(cs.repo_id, self.repo_id);
             let key = get_cache_key(self.repo_id, &cs.cs_id);
@@ -182,6 +295,22 @@ impl Changesets for CachingChangesets {
         }
     }
 
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        self.changesets.get_sequence(ctx, cs_id).await
+    }
+
+    async fn wait_for_sequence(
+        &self,
+        ctx: &CoreContext,
+        seq: ChangesetsSequenceNumber,
+    ) -> Result<(), Error> {
+        self.changesets.wait_for_sequence(ctx, seq).await
+    }
+
     async fn enumeration_bounds(
         &self,
         ctx: &CoreContext,
@@ -192,6 +321,22 @@ impl Changesets for CachingChangesets {
             .await
     }
 
+    /// Subscribe to the inner store. Its entries are not looked up through
+    /// the negative cache, which would drop changesets that were looked up
+    /// before they were added.
+    async fn subscribe(
+        &self,
+        ctx: &CoreContext,
+    ) -> Result<BoxStream<'_, Result<ChangesetEntry, Error>>, Error> {
+        let entries = self.changesets.subscribe(ctx).await?;
+        Ok(entries
+            .map_ok(move |entry| {
+                self.forget_missing(&[entry.cs_id]);
+                entry
+            })
+            .boxed())
+    }
+
     fn list_enumeration_range(
         &self,
         ctx: &CoreContext,
@@ -242,7 +387,11 @@ impl EntityStore<ChangesetEntryWrapper> for CacheRequest<'_> {
     }
 
     fn cache_determinator(&self, _: &ChangesetEntryWrapper) -> CacheDisposition {
-        CacheDisposition::Cache(CacheTtl::NoTtl)
+        let (_, mapping) = self;
+        match mapping.options.ttl {
+            Some(ttl) => CacheDisposition::Cache(CacheTtl::Ttl(ttl)),
+            None => CacheDisposition::Cache(CacheTtl::NoTtl),
+        }
     }
 
     caching_ext::impl_singleton_stats!("changesets");
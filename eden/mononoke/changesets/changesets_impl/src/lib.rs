@@ -8,9 +8,15 @@
 #![deny(warnings)]
 
 mod caching;
+mod sharded;
 mod sql;
 #[cfg(test)]
 mod test;
+mod visible;
 
-pub use crate::caching::{get_cache_key, CachingChangesets};
-pub use crate::sql::{SqlChangesets, SqlChangesetsBuilder};
+pub use crate::caching::{get_cache_key, CachingChangesets, CachingChangesetsOptions};
+pub use crate::sharded::{ShardedSqlChangesets, ShardedSqlChangesetsBuilder};
+pub use crate::sql::{
+    GenerationBackfillCheckpoint, RepoPurgeToken, SqlChangesets, SqlChangesetsBuilder,
+};
+pub use crate::visible::VisibleChangesets;
@@ -0,0 +1,731 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Changesets stored in several database shards.
+//!
+//! Rows are split across shards by changeset id, so that repos with more
+//! changesets than fit in one database can still be stored. Parents are
+//! stored with their children, by changeset id, as they may be in another
+//! shard.
+//!
+//! Enumeration ids are allocated by the first shard for all shards, so they
+//! are unique and increase in insertion order, as in `SqlChangesets`. This
+//! allows enumeration bounds and ranges to be merged from all shards.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use async_trait::async_trait;
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
+use context::{CoreContext, PerfCounterType};
+use futures::{
+    future::try_join_all,
+    stream::{self, BoxStream, StreamExt},
+    TryFutureExt,
+};
+use mononoke_types::{
+    ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix, RepositoryId,
+};
+use sql::{queries, Connection};
+use sql_construct::{SqlConstruct, SqlShardedConstruct};
+use sql_ext::{SqlConnections, SqlShardedConnections};
+use stats::prelude::*;
+
+use crate::sql::{increment_read_counter, SqlChangesetsError};
+
+/// How often `wait_for_sequence` checks whether the replicas have caught up.
+const WAIT_FOR_SEQUENCE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+define_stats! {
+    prefix = "mononoke.changesets.sharded";
+    gets: timeseries(Rate, Sum),
+    gets_master: timeseries(Rate, Sum),
+    get_many_by_prefix: timeseries(Rate, Sum),
+    get_many_with_generation_bounds: timeseries(Rate, Sum),
+    get_children: timeseries(Rate, Sum),
+    get_sequence: timeseries(Rate, Sum),
+    adds: timeseries(Rate, Sum),
+}
+
+queries! {
+    write AllocateId(repo_id: RepositoryId) {
+        none,
+        "INSERT INTO changesets_ids (repo_id) VALUES ({repo_id})"
+    }
+
+    write InsertChangeset(values: (id: u64, repo_id: RepositoryId, cs_id: ChangesetId, gen: u64)) {
+        insert_or_ignore,
+        "{insert_or_ignore} INTO changesets (id, repo_id, cs_id, gen) VALUES {values}"
+    }
+
+    write InsertParents(values: (repo_id: RepositoryId, cs_id: ChangesetId, parent_cs_id: ChangesetId, seq: i32)) {
+        none,
+        "INSERT INTO csparents (repo_id, cs_id, parent_cs_id, seq) VALUES {values}"
+    }
+
+    read SelectManyChangesets(repo_id: RepositoryId, >list cs_id: ChangesetId) -> (ChangesetId, u64, Option<ChangesetId>, Option<u64>) {
+        "SELECT changesets.cs_id, changesets.gen, csparents.parent_cs_id, csparents.seq
+         FROM changesets
+         LEFT JOIN csparents
+           ON csparents.repo_id = changesets.repo_id AND csparents.cs_id = changesets.cs_id
+         WHERE changesets.repo_id = {repo_id}
+           AND changesets.cs_id IN {cs_id}"
+    }
+
+    read SelectGenerations(repo_id: RepositoryId, >list cs_id: ChangesetId) -> (ChangesetId, u64) {
+        "SELECT cs_id, gen
+         FROM changesets
+         WHERE repo_id = {repo_id}
+           AND cs_id IN {cs_id}"
+    }
+
+    read SelectChangesetsRange(repo_id: RepositoryId, min: &[u8], max: &[u8], limit: usize) -> (ChangesetId) {
+        "SELECT cs_id
+         FROM changesets
+         WHERE repo_id = {repo_id}
+           AND cs_id >= {min} AND cs_id <= {max}
+         ORDER BY cs_id
+         LIMIT {limit}
+        "
+    }
+
+    read SelectAllChangesetsIdsInRange(repo_id: RepositoryId, min_id: u64, max_id: u64) -> (ChangesetId, u64) {
+        mysql(
+            "SELECT cs_id, id
+            FROM changesets FORCE INDEX(repo_id_id)
+            WHERE repo_id = {repo_id}
+            AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id"
+        )
+        sqlite(
+            "SELECT cs_id, id
+            FROM changesets
+            WHERE repo_id = {repo_id}
+            AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id"
+        )
+    }
+
+    read SelectAllChangesetsIdsInRangeLimitAsc(repo_id: RepositoryId, min_id: u64, max_id: u64, limit: u64) -> (ChangesetId, u64) {
+        mysql(
+            "SELECT cs_id, id
+            FROM changesets FORCE INDEX(repo_id_id)
+            WHERE repo_id = {repo_id}
+            AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id
+            LIMIT {limit}"
+        )
+        sqlite(
+            "SELECT cs_id, id
+            FROM changesets
+            WHERE repo_id = {repo_id}
+            AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id
+            LIMIT {limit}"
+        )
+    }
+
+    read SelectAllChangesetsIdsInRangeLimitDesc(repo_id: RepositoryId, min_id: u64, max_id: u64, limit: u64) -> (ChangesetId, u64) {
+        mysql(
+            "SELECT cs_id, id
+            FROM changesets FORCE INDEX(repo_id_id)
+            WHERE repo_id = {repo_id}
+              AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id DESC
+            LIMIT {limit}"
+        )
+        sqlite(
+            "SELECT cs_id, id
+            FROM changesets
+            WHERE repo_id = {repo_id}
+              AND id BETWEEN {min_id} AND {max_id}
+            ORDER BY id DESC
+            LIMIT {limit}"
+        )
+    }
+
+    read SelectChangesetsInGenerationRange(repo_id: RepositoryId, min_gen: u64, max_gen: u64, limit: u64) -> (ChangesetId, u64, u64) {
+        "SELECT cs_id, gen, id
+         FROM changesets
+         WHERE repo_id = {repo_id}
+           AND gen BETWEEN {min_gen} AND {max_gen}
+         ORDER BY gen, id
+         LIMIT {limit}"
+    }
+
+    read SelectChildren(repo_id: RepositoryId, parent_cs_id: ChangesetId) -> (ChangesetId, u64) {
+        "SELECT changesets.cs_id, changesets.id
+         FROM csparents
+         INNER JOIN changesets
+           ON changesets.repo_id = csparents.repo_id AND changesets.cs_id = csparents.cs_id
+         WHERE csparents.repo_id = {repo_id} AND csparents.parent_cs_id = {parent_cs_id}"
+    }
+
+    read SelectChangesetsIdsBounds(repo_id: RepositoryId) -> (Option<u64>, Option<u64>) {
+        "SELECT min(id), max(id)
+         FROM changesets
+         WHERE repo_id = {repo_id}"
+    }
+
+    read SelectChangesetSequence(repo_id: RepositoryId, cs_id: ChangesetId) -> (u64) {
+        "SELECT id
+         FROM changesets
+         WHERE repo_id = {repo_id} AND cs_id = {cs_id}"
+    }
+
+    read SelectSequenceExists(repo_id: RepositoryId, id: u64) -> (u64) {
+        "SELECT id
+         FROM changesets
+         WHERE repo_id = {repo_id} AND id = {id}"
+    }
+}
+
+#[derive(Clone)]
+pub struct ShardedSqlChangesets {
+    repo_id: RepositoryId,
+    write_connections: Arc<Vec<Connection>>,
+    read_connections: Arc<Vec<Connection>>,
+    read_master_connections: Arc<Vec<Connection>>,
+}
+
+pub struct ShardedSqlChangesetsBuilder {
+    write_connections: Vec<Connection>,
+    read_connections: Vec<Connection>,
+    read_master_connections: Vec<Connection>,
+}
+
+impl SqlShardedConstruct for ShardedSqlChangesetsBuilder {
+    const LABEL: &'static str = "shardedchangesets";
+
+    const CREATION_QUERY: &'static str = include_str!("../schemas/sqlite-sharded-changesets.sql");
+
+    fn from_sql_shard_connections(shard_connections: SqlShardedConnections) -> Self {
+        if shard_connections.is_empty() {
+            // It should be impossible for shard_connections to be empty, as the configured
+            // number of shards was required to be non-zero.
+            panic!("sharded database constructed with no shards");
+        }
+
+        let SqlShardedConnections {
+            read_connections,
+            read_master_connections,
+            write_connections,
+        } = shard_connections;
+
+        Self {
+            write_connections,
+            read_connections,
+            read_master_connections,
+        }
+    }
+}
+
+impl SqlConstruct for ShardedSqlChangesetsBuilder {
+    const LABEL: &'static str = "changesets";
+
+    const CREATION_QUERY: &'static str =
+        <ShardedSqlChangesetsBuilder as SqlShardedConstruct>::CREATION_QUERY;
+
+    fn from_sql_connections(connections: SqlConnections) -> Self {
+        let SqlConnections {
+            read_connection,
+            read_master_connection,
+            write_connection,
+        } = connections;
+
+        Self {
+            write_connections: vec![write_connection],
+            read_connections: vec![read_connection],
+            read_master_connections: vec![read_master_connection],
+        }
+    }
+}
+
+impl ShardedSqlChangesetsBuilder {
+    pub fn build(self, repo_id: RepositoryId) -> ShardedSqlChangesets {
+        ShardedSqlChangesets {
+            repo_id,
+            write_connections: Arc::new(self.write_connections),
+            read_connections: Arc::new(self.read_connections),
+            read_master_connections: Arc::new(self.read_master_connections),
+        }
+    }
+}
+
+#[async_trait]
+impl Changesets for ShardedSqlChangesets {
+    fn repo_id(&self) -> RepositoryId {
+        self.repo_id
+    }
+
+    async fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> Result<bool, Error> {
+        STATS::adds.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlWrites);
+
+        let parent_gens = try_join_all(self.group_by_shard(&cs.parents).into_iter().map(
+            |(shard, cs_ids)| async move {
+                SelectGenerations::query(&self.write_connections[shard], &self.repo_id, &cs_ids[..])
+                    .await
+            },
+        ))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect::<HashMap<_, _>>();
+        let missing: HashSet<_> = cs
+            .parents
+            .iter()
+            .filter(|parent| !parent_gens.contains_key(parent))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(SqlChangesetsError::MissingParents(missing.into_iter().collect()).into());
+        }
+        let gen = parent_gens.values().max().copied().unwrap_or(0) + 1;
+
+        // Check for duplicates before allocating an id, so that adding an
+        // existing changeset does not leave a hole in the ids.
+        let connection = &self.write_connections[self.shard(&cs.cs_id)];
+        if let Some(stored) = select_many_changesets(connection, self.repo_id, &[cs.cs_id])
+            .await?
+            .into_iter()
+            .next()
+        {
+            return check_duplicate_insertion(cs, Some(stored.parents));
+        }
+
+        let id = AllocateId::query(&self.write_connections[0], &self.repo_id)
+            .await?
+            .last_insert_id()
+            .ok_or_else(|| anyhow!("No id allocated for changeset {}", cs.cs_id))?;
+
+        let transaction = connection.start_transaction().await?;
+        let (transaction, result) = InsertChangeset::query_with_transaction(
+            transaction,
+            &[(&id, &self.repo_id, &cs.cs_id, &gen)],
+        )
+        .await?;
+
+        if result.affected_rows() == 1 {
+            let parent_inserts: Vec<_> = (0..(cs.parents.len() as i32))
+                .zip(cs.parents.iter())
+                .map(|(seq, parent)| (self.repo_id, cs.cs_id, *parent, seq))
+                .collect();
+            let ref_parent_inserts: Vec<_> = parent_inserts
+                .iter()
+                .map(|row| (&row.0, &row.1, &row.2, &row.3))
+                .collect();
+            let transaction = if ref_parent_inserts.is_empty() {
+                transaction
+            } else {
+                InsertParents::query_with_transaction(transaction, &ref_parent_inserts[..])
+                    .await?
+                    .0
+            };
+            transaction.commit().await?;
+            Ok(true)
+        } else {
+            // Added concurrently since the check above.
+            transaction.rollback().await?;
+            let stored_parents = select_many_changesets(connection, self.repo_id, &[cs.cs_id])
+                .await?
+                .into_iter()
+                .next()
+                .map(|entry| entry.parents);
+            check_duplicate_insertion(cs, stored_parents)
+        }
+    }
+
+    async fn get(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetEntry>, Error> {
+        let res = self.get_many(ctx, vec![cs_id]).await?.into_iter().next();
+        Ok(res)
+    }
+
+    async fn get_many(
+        &self,
+        ctx: CoreContext,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        if cs_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        STATS::gets.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+
+        let fetched_cs = self
+            .select_from_shards(&self.read_connections, &cs_ids)
+            .await?;
+        let fetched_set: HashSet<_> = fetched_cs.iter().map(|cs_entry| cs_entry.cs_id).collect();
+
+        let notfetched_cs_ids: Vec<_> = cs_ids
+            .into_iter()
+            .filter(|cs_id| !fetched_set.contains(cs_id))
+            .collect();
+        if notfetched_cs_ids.is_empty() {
+            Ok(fetched_cs)
+        } else {
+            STATS::gets_master.add_value(1);
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsMaster);
+            let mut master_fetched_cs = self
+                .select_from_shards(&self.read_master_connections, &notfetched_cs_ids)
+                .await?;
+            master_fetched_cs.extend(fetched_cs);
+            Ok(master_fetched_cs)
+        }
+    }
+
+    async fn get_many_by_prefix(
+        &self,
+        ctx: CoreContext,
+        cs_prefix: ChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+        STATS::get_many_by_prefix.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        let resolved_cs =
+            fetch_many_by_prefix(&self.read_connections, self.repo_id, &cs_prefix, limit).await?;
+        match resolved_cs {
+            ChangesetIdsResolvedFromPrefix::NoMatch => {
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsMaster);
+                fetch_many_by_prefix(
+                    &self.read_master_connections,
+                    self.repo_id,
+                    &cs_prefix,
+                    limit,
+                )
+                .await
+            }
+            _ => Ok(resolved_cs),
+        }
+    }
+
+    async fn get_many_with_generation_bounds(
+        &self,
+        ctx: CoreContext,
+        min_gen: u64,
+        max_gen: u64,
+        limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        STATS::get_many_with_generation_bounds.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        // Each shard returns up to `limit` rows in order, so the first `limit`
+        // rows of all shards are among them.
+        let mut rows: Vec<_> = try_join_all(self.read_connections.iter().map(|conn| {
+            SelectChangesetsInGenerationRange::query(
+                conn,
+                &self.repo_id,
+                &min_gen,
+                &max_gen,
+                &limit,
+            )
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+        rows.sort_by_key(|(_, gen, id)| (*gen, *id));
+        rows.truncate(limit as usize);
+        let cs_ids: Vec<_> = rows.into_iter().map(|row| row.0).collect();
+
+        // get_many doesn't preserve order, so restore it.
+        let mut entries: HashMap<_, _> = self
+            .get_many(ctx, cs_ids.clone())
+            .await?
+            .into_iter()
+            .map(|entry| (entry.cs_id, entry))
+            .collect();
+        Ok(cs_ids
+            .into_iter()
+            .filter_map(|cs_id| entries.remove(&cs_id))
+            .collect())
+    }
+
+    async fn get_children(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        STATS::get_children.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        // Children may be in any shard.
+        let mut rows: Vec<_> = try_join_all(
+            self.read_connections
+                .iter()
+                .map(|conn| SelectChildren::query(conn, &self.repo_id, &cs_id)),
+        )
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+        rows.sort_by_key(|(_, id)| *id);
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    fn prime_cache(&self, _ctx: &CoreContext, _changesets: &[ChangesetEntry]) {
+        // No-op
+    }
+
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        STATS::get_sequence.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = SelectChangesetSequence::query(
+            &self.read_master_connections[self.shard(&cs_id)],
+            &self.repo_id,
+            &cs_id,
+        )
+        .await?;
+        Ok(rows.first().map(|row| ChangesetsSequenceNumber(row.0)))
+    }
+
+    /// Shards are replicated independently, so this only waits for the
+    /// replica of the shard that stores the changeset.
+    async fn wait_for_sequence(
+        &self,
+        _ctx: &CoreContext,
+        seq: ChangesetsSequenceNumber,
+    ) -> Result<(), Error> {
+        loop {
+            let rows = try_join_all(
+                self.read_connections
+                    .iter()
+                    .map(|conn| SelectSequenceExists::query(conn, &self.repo_id, &seq.0)),
+            )
+            .await?;
+            if rows.iter().any(|rows| !rows.is_empty()) {
+                return Ok(());
+            }
+            tokio::time::sleep(WAIT_FOR_SEQUENCE_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn enumeration_bounds(
+        &self,
+        ctx: &CoreContext,
+        read_from_master: bool,
+    ) -> Result<Option<(u64, u64)>, Error> {
+        increment_read_counter(ctx, read_from_master);
+        let bounds = try_join_all(
+            self.read_conns(read_from_master)
+                .iter()
+                .map(|conn| SelectChangesetsIdsBounds::query(conn, &self.repo_id)),
+        )
+        .await?
+        .into_iter()
+        .flatten()
+        .filter_map(|(min_id, max_id)| Some((min_id?, max_id?)))
+        .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)));
+        Ok(bounds)
+    }
+
+    fn list_enumeration_range(
+        &self,
+        ctx: &CoreContext,
+        min_id: u64,
+        max_id: u64,
+        sort_and_limit: Option<(SortOrder, u64)>,
+        read_from_master: bool,
+    ) -> BoxStream<'_, Result<(ChangesetId, u64), Error>> {
+        // We expect the range [min_id, max_id), so subtract 1 from max_id as
+        // SQL request is BETWEEN, which means both bounds are inclusive.
+        let max_id = max_id - 1;
+        let conns = self.read_conns(read_from_master);
+        increment_read_counter(ctx, read_from_master);
+
+        try_join_all(conns.iter().map(move |conn| async move {
+            match sort_and_limit {
+                None => {
+                    SelectAllChangesetsIdsInRange::query(conn, &self.repo_id, &min_id, &max_id)
+                        .await
+                }
+                Some((SortOrder::Ascending, limit)) => {
+                    SelectAllChangesetsIdsInRangeLimitAsc::query(
+                        conn,
+                        &self.repo_id,
+                        &min_id,
+                        &max_id,
+                        &limit,
+                    )
+                    .await
+                }
+                Some((SortOrder::Descending, limit)) => {
+                    SelectAllChangesetsIdsInRangeLimitDesc::query(
+                        conn,
+                        &self.repo_id,
+                        &min_id,
+                        &max_id,
+                        &limit,
+                    )
+                    .await
+                }
+            }
+        }))
+        .map_ok(move |shard_rows| {
+            // Each shard's rows are sorted and limited, so merging them and
+            // limiting again gives the same rows as a single table would.
+            let mut rows: Vec<_> = shard_rows.into_iter().flatten().collect();
+            match sort_and_limit {
+                None | Some((SortOrder::Ascending, _)) => rows.sort_by_key(|(_, id)| *id),
+                Some((SortOrder::Descending, _)) => {
+                    rows.sort_by_key(|(_, id)| std::cmp::Reverse(*id))
+                }
+            }
+            if let Some((_, limit)) = sort_and_limit {
+                rows.truncate(limit as usize);
+            }
+            stream::iter(rows.into_iter().map(Ok))
+        })
+        .try_flatten_stream()
+        .boxed()
+    }
+}
+
+impl ShardedSqlChangesets {
+    fn read_conns(&self, read_from_master: bool) -> &[Connection] {
+        if read_from_master {
+            &self.read_master_connections
+        } else {
+            &self.read_connections
+        }
+    }
+
+    /// The shard storing `cs_id`. Changeset ids are hashes, so their first
+    /// bytes are evenly distributed.
+    fn shard(&self, cs_id: &ChangesetId) -> usize {
+        let prefix: [u8; 8] = cs_id.as_ref()[..8]
+            .try_into()
+            .expect("changeset ids are longer than 8 bytes");
+        (u64::from_be_bytes(prefix) % self.write_connections.len() as u64) as usize
+    }
+
+    fn group_by_shard(&self, cs_ids: &[ChangesetId]) -> HashMap<usize, Vec<ChangesetId>> {
+        let mut by_shard: HashMap<usize, Vec<ChangesetId>> = HashMap::new();
+        for cs_id in cs_ids {
+            by_shard.entry(self.shard(cs_id)).or_default().push(*cs_id);
+        }
+        by_shard
+    }
+
+    async fn select_from_shards(
+        &self,
+        connections: &[Connection],
+        cs_ids: &[ChangesetId],
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let entries = try_join_all(self.group_by_shard(cs_ids).into_iter().map(
+            |(shard, cs_ids)| async move {
+                select_many_changesets(&connections[shard], self.repo_id, &cs_ids).await
+            },
+        ))
+        .await?;
+        Ok(entries.into_iter().flatten().collect())
+    }
+}
+
+/// Result of adding `cs` if a changeset with the same id is stored, with
+/// `stored_parents`.
+fn check_duplicate_insertion(
+    cs: ChangesetInsert,
+    stored_parents: Option<Vec<ChangesetId>>,
+) -> Result<bool, Error> {
+    if Some(&cs.parents) == stored_parents.as_ref() {
+        Ok(false)
+    } else {
+        Err(SqlChangesetsError::DuplicateInsertionInconsistency(
+            cs.cs_id,
+            stored_parents.unwrap_or_default(),
+            cs.parents,
+        )
+        .into())
+    }
+}
+
+async fn fetch_many_by_prefix(
+    connections: &[Connection],
+    repo_id: RepositoryId,
+    cs_prefix: &ChangesetIdPrefix,
+    limit: usize,
+) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+    let mut fetched_cs: Vec<ChangesetId> = try_join_all(connections.iter().map(|conn| {
+        SelectChangesetsRange::query(
+            conn,
+            &repo_id,
+            &cs_prefix.min_as_ref(),
+            &cs_prefix.max_as_ref(),
+            &(limit + 1),
+        )
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .map(|row| row.0)
+    .collect();
+    fetched_cs.sort();
+    fetched_cs.truncate(limit + 1);
+    let result = match fetched_cs.len() {
+        0 => ChangesetIdsResolvedFromPrefix::NoMatch,
+        1 => ChangesetIdsResolvedFromPrefix::Single(fetched_cs[0]),
+        l if l <= limit => ChangesetIdsResolvedFromPrefix::Multiple(fetched_cs),
+        _ => ChangesetIdsResolvedFromPrefix::TooMany({
+            fetched_cs.pop();
+            fetched_cs
+        }),
+    };
+    Ok(result)
+}
+
+async fn select_many_changesets(
+    connection: &Connection,
+    repo_id: RepositoryId,
+    cs_ids: &[ChangesetId],
+) -> Result<Vec<ChangesetEntry>, Error> {
+    if cs_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let rows = SelectManyChangesets::query(connection, &repo_id, cs_ids).await?;
+
+    let mut parents_by_cs_id: HashMap<ChangesetId, (u64, Vec<(u64, ChangesetId)>)> = HashMap::new();
+    for (cs_id, gen, maybe_parent, maybe_seq) in rows {
+        let (_, parents) = parents_by_cs_id
+            .entry(cs_id)
+            .or_insert_with(|| (gen, Vec::new()));
+        if let (Some(parent), Some(seq)) = (maybe_parent, maybe_seq) {
+            parents.push((seq, parent));
+        }
+    }
+
+    Ok(parents_by_cs_id
+        .into_iter()
+        .map(|(cs_id, (gen, mut parents))| {
+            parents.sort();
+            ChangesetEntry {
+                repo_id,
+                cs_id,
+                parents: parents.into_iter().map(|(_, parent)| parent).collect(),
+                gen,
+            }
+        })
+        .collect())
+}
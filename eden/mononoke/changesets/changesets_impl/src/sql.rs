@@ -5,20 +5,23 @@
  * GNU General Public License version 2.
  */
 
-use anyhow::{Error, Result};
+use anyhow::{bail, format_err, Error, Result};
 use async_trait::async_trait;
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SortOrder};
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
 use context::{CoreContext, PerfCounterType};
 use fbinit::FacebookInit;
 use futures::{
     stream::{self, BoxStream, StreamExt},
-    TryFutureExt,
+    TryFutureExt, TryStreamExt,
 };
 use mononoke_types::{
     ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix, RepositoryId,
 };
 use rand::Rng;
 use rendezvous::{RendezVous, RendezVousOptions, RendezVousStats, TunablesRendezVousController};
+use slog::info;
 use sql::{queries, Connection, Transaction};
 use sql_construct::{SqlConstruct, SqlConstructFromMetadataDatabaseConfig};
 use sql_ext::SqlConnections;
@@ -32,7 +35,13 @@ define_stats! {
     gets: timeseries(Rate, Sum),
     gets_master: timeseries(Rate, Sum),
     get_many_by_prefix: timeseries(Rate, Sum),
+    get_many_with_parents: timeseries(Rate, Sum),
+    get_many_with_generation_bounds: timeseries(Rate, Sum),
+    get_children: timeseries(Rate, Sum),
+    get_sequence: timeseries(Rate, Sum),
     adds: timeseries(Rate, Sum),
+    purged: timeseries(Rate, Sum),
+    generations_backfilled: timeseries(Rate, Sum),
 }
 
 #[derive(Debug, Eq, Error, PartialEq)]
@@ -43,9 +52,36 @@ pub enum SqlChangesetsError {
     MissingParents(Vec<ChangesetId>),
 }
 
+/// Permission to delete every changeset of a repo with
+/// `SqlChangesets::delete_all_for_repo`. Deleting changesets can't be undone,
+/// so a token has to be made explicitly for the repo being decommissioned.
+#[derive(Debug)]
+pub struct RepoPurgeToken {
+    repo_id: RepositoryId,
+}
+
+impl RepoPurgeToken {
+    /// Acknowledge that all changesets of `repo_id` are to be deleted. Only
+    /// repo decommissioning tools should call this.
+    pub fn for_decommissioned_repo(repo_id: RepositoryId) -> Self {
+        Self { repo_id }
+    }
+}
+
+/// Progress of `SqlChangesets::backfill_generations_from`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GenerationBackfillCheckpoint {
+    /// Every changeset with a lower enumeration id has been backfilled.
+    pub next_id: u64,
+    /// Number of changesets whose generation number was updated.
+    pub updated: u64,
+}
+
 #[derive(Clone)]
 struct RendezVousConnection {
     rdv: RendezVous<ChangesetId, ChangesetEntry>,
+    // Maps a changeset to its entry, followed by the entries of its parents.
+    rdv_with_parents: RendezVous<ChangesetId, Vec<ChangesetEntry>>,
     conn: Connection,
 }
 
@@ -57,6 +93,13 @@ impl RendezVousConnection {
                 TunablesRendezVousController::new(opts),
                 Arc::new(RendezVousStats::new(format!("changesets.{}", name,))),
             ),
+            rdv_with_parents: RendezVous::new(
+                TunablesRendezVousController::new(opts),
+                Arc::new(RendezVousStats::new(format!(
+                    "changesets.{}.with_parents",
+                    name,
+                ))),
+            ),
         }
     }
 }
@@ -117,6 +160,39 @@ queries! {
         "
     }
 
+    // Like SelectManyChangesets, but also selects the parents of the changesets.
+    read SelectManyChangesetsWithParents(repo_id: RepositoryId, tok: i32, >list cs_id: ChangesetId) -> (ChangesetId, u64, Option<ChangesetId>, Option<u64>, i32) {
+        "
+        SELECT cs0.cs_id AS cs_id, cs0.gen AS gen, cs1.cs_id AS parent_id, csparents.seq AS seq, {tok}
+        FROM csparents
+        INNER JOIN changesets cs0 ON cs0.id = csparents.cs_id
+        INNER JOIN changesets cs1 ON cs1.id = csparents.parent_id
+        WHERE cs0.repo_id = {repo_id} AND cs1.repo_id = {repo_id} AND cs0.id IN (
+            SELECT cs.id FROM changesets cs
+            WHERE cs.repo_id = {repo_id} AND cs.cs_id IN {cs_id}
+            UNION
+            SELECT p.parent_id FROM csparents p
+            INNER JOIN changesets cs ON cs.id = p.cs_id
+            WHERE cs.repo_id = {repo_id} AND cs.cs_id IN {cs_id}
+        )
+
+        UNION
+
+        SELECT cs0.cs_id AS cs_id, cs0.gen AS gen, NULL AS parent_id, NULL as seq, {tok}
+        FROM changesets cs0
+        WHERE cs0.repo_id = {repo_id} AND cs0.id IN (
+            SELECT cs.id FROM changesets cs
+            WHERE cs.repo_id = {repo_id} AND cs.cs_id IN {cs_id}
+            UNION
+            SELECT p.parent_id FROM csparents p
+            INNER JOIN changesets cs ON cs.id = p.cs_id
+            WHERE cs.repo_id = {repo_id} AND cs.cs_id IN {cs_id}
+        )
+
+        ORDER BY seq ASC
+        "
+    }
+
     read SelectChangesets(repo_id: RepositoryId, >list cs_id: ChangesetId) -> (u64, ChangesetId, u64) {
         "SELECT id, cs_id, gen
          FROM changesets
@@ -188,12 +264,59 @@ queries! {
         )
     }
 
+    read SelectChangesetsInGenerationRange(repo_id: RepositoryId, min_gen: u64, max_gen: u64, limit: u64) -> (ChangesetId) {
+        "SELECT cs_id
+         FROM changesets
+         WHERE repo_id = {repo_id}
+           AND gen BETWEEN {min_gen} AND {max_gen}
+         ORDER BY gen, id
+         LIMIT {limit}"
+    }
+
+    read SelectChildren(repo_id: RepositoryId, cs_id: ChangesetId) -> (ChangesetId) {
+        "SELECT child.cs_id
+         FROM csparents
+         INNER JOIN changesets parent ON parent.id = csparents.parent_id
+         INNER JOIN changesets child ON child.id = csparents.cs_id
+         WHERE parent.repo_id = {repo_id} AND parent.cs_id = {cs_id}
+         ORDER BY child.id"
+    }
+
     read SelectChangesetsIdsBounds(repo_id: RepositoryId) -> (u64, u64) {
         "SELECT min(id), max(id)
          FROM changesets
          WHERE repo_id = {repo_id}"
     }
 
+    read SelectChangesetSequence(repo_id: RepositoryId, cs_id: ChangesetId) -> (u64) {
+        "SELECT id
+         FROM changesets
+         WHERE repo_id = {repo_id} AND cs_id = {cs_id}"
+    }
+
+    read SelectChangesetIdsForPurge(repo_id: RepositoryId, limit: u64) -> (u64) {
+        "SELECT id
+         FROM changesets
+         WHERE repo_id = {repo_id}
+         ORDER BY id
+         LIMIT {limit}"
+    }
+
+    write DeleteParents(>list cs_id: u64) {
+        none,
+        "DELETE FROM csparents WHERE cs_id IN {cs_id}"
+    }
+
+    write DeleteChangesets(repo_id: RepositoryId, >list id: u64) {
+        none,
+        "DELETE FROM changesets WHERE repo_id = {repo_id} AND id IN {id}"
+    }
+
+    write UpdateGeneration(repo_id: RepositoryId, cs_id: ChangesetId, gen: u64) {
+        none,
+        "UPDATE changesets SET gen = {gen} WHERE repo_id = {repo_id} AND cs_id = {cs_id}"
+    }
+
 }
 
 #[derive(Clone)]
@@ -329,6 +452,86 @@ impl Changesets for SqlChangesets {
         }
     }
 
+    /// Each query fetches two levels: the changesets asked for and their
+    /// parents, so walking `depth` levels takes about half as many queries as
+    /// calling `get_many` for each level.
+    async fn get_many_with_parents(
+        &self,
+        ctx: CoreContext,
+        cs_ids: Vec<ChangesetId>,
+        depth: usize,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        if depth == 0 {
+            return self.get_many(ctx, cs_ids).await;
+        }
+        STATS::get_many_with_parents.add_value(1);
+
+        let mut entries: HashMap<ChangesetId, ChangesetEntry> = HashMap::new();
+        let mut frontier = cs_ids;
+        let mut remaining = depth;
+        while !frontier.is_empty() {
+            if remaining == 0 {
+                for entry in self.get_many(ctx.clone(), frontier).await? {
+                    entries.insert(entry.cs_id, entry);
+                }
+                break;
+            }
+
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsReplica);
+            let mut fetched = select_many_changesets_with_parents(
+                ctx.fb,
+                &self.read_connection,
+                self.repo_id,
+                &frontier,
+            )
+            .await?;
+            let missing: Vec<_> = frontier
+                .iter()
+                .filter(|cs_id| !fetched.contains_key(cs_id))
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                STATS::gets_master.add_value(1);
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsMaster);
+                fetched.extend(
+                    select_many_changesets_with_parents(
+                        ctx.fb,
+                        &self.read_master_connection,
+                        self.repo_id,
+                        &missing,
+                    )
+                    .await?,
+                );
+            }
+
+            // The parents of the frontier were fetched with it, so the next
+            // frontier is their parents, if there are levels left to fetch.
+            let parents: HashSet<_> = frontier
+                .iter()
+                .filter_map(|cs_id| fetched.get(cs_id))
+                .flat_map(|entry| entry.parents.iter().copied())
+                .collect();
+            let next: HashSet<_> = if remaining > 1 {
+                parents
+                    .iter()
+                    .filter_map(|cs_id| fetched.get(cs_id))
+                    .flat_map(|entry| entry.parents.iter().copied())
+                    .collect()
+            } else {
+                HashSet::new()
+            };
+            entries.extend(fetched);
+            frontier = next
+                .into_iter()
+                .filter(|cs_id| !entries.contains_key(cs_id))
+                .collect();
+            remaining = remaining.saturating_sub(2);
+        }
+        Ok(entries.into_values().collect())
+    }
+
     async fn get_many_by_prefix(
         &self,
         ctx: CoreContext,
@@ -357,16 +560,81 @@ impl Changesets for SqlChangesets {
         }
     }
 
+    async fn get_many_with_generation_bounds(
+        &self,
+        ctx: CoreContext,
+        min_gen: u64,
+        max_gen: u64,
+        limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        STATS::get_many_with_generation_bounds.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        let cs_ids: Vec<_> = SelectChangesetsInGenerationRange::query(
+            &self.read_connection.conn,
+            &self.repo_id,
+            &min_gen,
+            &max_gen,
+            &limit,
+        )
+        .await?
+        .into_iter()
+        .map(|row| row.0)
+        .collect();
+
+        // get_many doesn't preserve order, so restore it.
+        let mut entries: HashMap<_, _> = self
+            .get_many(ctx, cs_ids.clone())
+            .await?
+            .into_iter()
+            .map(|entry| (entry.cs_id, entry))
+            .collect();
+        Ok(cs_ids
+            .into_iter()
+            .filter_map(|cs_id| entries.remove(&cs_id))
+            .collect())
+    }
+
+    async fn get_children(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        STATS::get_children.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsReplica);
+        let rows = SelectChildren::query(&self.read_connection.conn, &self.repo_id, &cs_id).await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
     fn prime_cache(&self, _ctx: &CoreContext, _changesets: &[ChangesetEntry]) {
         // No-op
     }
 
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        STATS::get_sequence.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = SelectChangesetSequence::query(
+            &self.read_master_connection.conn,
+            &self.repo_id,
+            &cs_id,
+        )
+        .await?;
+        Ok(rows.first().map(|row| ChangesetsSequenceNumber(row.0)))
+    }
+
     async fn enumeration_bounds(
         &self,
-        _ctx: &CoreContext,
+        ctx: &CoreContext,
         read_from_master: bool,
     ) -> Result<Option<(u64, u64)>, Error> {
         let conn = self.read_conn(read_from_master);
+        increment_read_counter(ctx, read_from_master);
         let rows = SelectChangesetsIdsBounds::query(conn, &self.repo_id).await?;
         if rows.is_empty() {
             Ok(None)
@@ -377,7 +645,7 @@ impl Changesets for SqlChangesets {
 
     fn list_enumeration_range(
         &self,
-        _ctx: &CoreContext,
+        ctx: &CoreContext,
         min_id: u64,
         max_id: u64,
         sort_and_limit: Option<(SortOrder, u64)>,
@@ -387,6 +655,7 @@ impl Changesets for SqlChangesets {
         // SQL request is BETWEEN, which means both bounds are inclusive.
         let max_id = max_id - 1;
         let conn = self.read_conn(read_from_master);
+        increment_read_counter(ctx, read_from_master);
 
         async move {
             match sort_and_limit {
@@ -460,6 +729,215 @@ impl SqlChangesets {
             &self.read_connection.conn
         }
     }
+
+    /// Delete every changeset of `repo_id`, and their parent rows, in batches
+    /// of `batch_size` changesets, logging progress after each batch. This is
+    /// for decommissioning repos, so that their rows stop taking up
+    /// enumeration ranges. Caches in front of this store are not invalidated.
+    ///
+    /// Returns the number of changesets deleted.
+    pub async fn delete_all_for_repo(
+        &self,
+        ctx: &CoreContext,
+        repo_id: RepositoryId,
+        batch_size: u64,
+        token: RepoPurgeToken,
+    ) -> Result<u64, Error> {
+        if token.repo_id != repo_id {
+            bail!(
+                "Purge token for repo {} cannot delete changesets of repo {}",
+                token.repo_id,
+                repo_id
+            );
+        }
+        if batch_size == 0 {
+            bail!("Purge batch size must be positive");
+        }
+
+        let mut deleted = 0;
+        loop {
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsMaster);
+            let ids =
+                SelectChangesetIdsForPurge::query(&self.write_connection, &repo_id, &batch_size)
+                    .await?
+                    .into_iter()
+                    .map(|(id,)| id)
+                    .collect::<Vec<_>>();
+            if ids.is_empty() {
+                return Ok(deleted);
+            }
+
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlWrites);
+            let txn = self.write_connection.start_transaction().await?;
+            let (txn, _) = DeleteParents::query_with_transaction(txn, &ids[..]).await?;
+            let (txn, _) =
+                DeleteChangesets::query_with_transaction(txn, &repo_id, &ids[..]).await?;
+            txn.commit().await?;
+
+            deleted += ids.len() as u64;
+            STATS::purged.add_value(ids.len() as i64);
+            info!(
+                ctx.logger(),
+                "Deleted {} changesets of repo {}", deleted, repo_id
+            );
+        }
+    }
+
+    /// Recompute the generation number of every changeset of this repo from
+    /// its parents, in batches of `batch_size` changesets in enumeration
+    /// order, and store those that differ. This fills in rows written before
+    /// generation numbers were stored (which have a generation of 0), and
+    /// fixes rows that were added on top of them.
+    ///
+    /// Returns the final checkpoint.
+    pub async fn backfill_generations(
+        &self,
+        ctx: &CoreContext,
+        batch_size: u64,
+    ) -> Result<GenerationBackfillCheckpoint, Error> {
+        self.backfill_generations_from(
+            ctx,
+            batch_size,
+            GenerationBackfillCheckpoint::default(),
+            |_| Ok(()),
+        )
+        .await
+    }
+
+    /// Like `backfill_generations`, but resumes from `checkpoint`, and calls
+    /// `on_checkpoint` after each batch so that the caller can persist its
+    /// progress.
+    pub async fn backfill_generations_from(
+        &self,
+        ctx: &CoreContext,
+        batch_size: u64,
+        mut checkpoint: GenerationBackfillCheckpoint,
+        mut on_checkpoint: impl FnMut(&GenerationBackfillCheckpoint) -> Result<()>,
+    ) -> Result<GenerationBackfillCheckpoint, Error> {
+        if batch_size == 0 {
+            bail!("Backfill batch size must be positive");
+        }
+
+        // Parents are added before their children, so they have lower ids
+        // and are backfilled first. Generations computed by this run are
+        // kept so that only parents backfilled by earlier runs are fetched.
+        let mut gens: HashMap<ChangesetId, u64> = HashMap::new();
+        loop {
+            let max_id = match self.enumeration_bounds(ctx, true).await? {
+                Some((_, max_id)) if max_id >= checkpoint.next_id => max_id,
+                _ => return Ok(checkpoint),
+            };
+            let batch: Vec<(ChangesetId, u64)> = self
+                .list_enumeration_range(
+                    ctx,
+                    checkpoint.next_id,
+                    max_id + 1,
+                    Some((SortOrder::Ascending, batch_size)),
+                    true,
+                )
+                .try_collect()
+                .await?;
+            let last_id = match batch.last() {
+                Some((_, id)) => *id,
+                None => return Ok(checkpoint),
+            };
+
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsMaster);
+            let cs_ids: Vec<_> = batch.iter().map(|(cs_id, _)| *cs_id).collect();
+            let entries: HashMap<_, _> =
+                select_many_changesets(ctx.fb, &self.read_master_connection, self.repo_id, &cs_ids)
+                    .await?
+                    .into_iter()
+                    .map(|entry| (entry.cs_id, entry))
+                    .collect();
+
+            let unknown_parents: HashSet<_> = entries
+                .values()
+                .flat_map(|entry| entry.parents.iter())
+                .filter(|parent| !gens.contains_key(*parent) && !entries.contains_key(*parent))
+                .copied()
+                .collect();
+            if !unknown_parents.is_empty() {
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlReadsMaster);
+                let unknown_parents: Vec<_> = unknown_parents.into_iter().collect();
+                let parents = select_many_changesets(
+                    ctx.fb,
+                    &self.read_master_connection,
+                    self.repo_id,
+                    &unknown_parents,
+                )
+                .await?;
+                for parent in parents {
+                    if parent.gen != 0 {
+                        gens.insert(parent.cs_id, parent.gen);
+                    }
+                }
+            }
+
+            let mut updates = Vec::new();
+            for (cs_id, _) in &batch {
+                // Skip changesets deleted since the batch was listed.
+                let entry = match entries.get(cs_id) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let mut gen = 1;
+                for parent in &entry.parents {
+                    let parent_gen = gens.get(parent).ok_or_else(|| {
+                        format_err!(
+                            "Generation number of parent {} of {} is unknown",
+                            parent,
+                            cs_id
+                        )
+                    })?;
+                    gen = gen.max(parent_gen + 1);
+                }
+                if gen != entry.gen {
+                    updates.push((*cs_id, gen));
+                }
+                gens.insert(*cs_id, gen);
+            }
+
+            if !updates.is_empty() {
+                ctx.perf_counters()
+                    .increment_counter(PerfCounterType::SqlWrites);
+                let mut txn = self.write_connection.start_transaction().await?;
+                for (cs_id, gen) in &updates {
+                    let (next_txn, _) =
+                        UpdateGeneration::query_with_transaction(txn, &self.repo_id, cs_id, gen)
+                            .await?;
+                    txn = next_txn;
+                }
+                txn.commit().await?;
+            }
+
+            checkpoint.next_id = last_id + 1;
+            checkpoint.updated += updates.len() as u64;
+            STATS::generations_backfilled.add_value(updates.len() as i64);
+            info!(
+                ctx.logger(),
+                "Backfilled generations of repo {} up to id {}, {} updated",
+                self.repo_id,
+                last_id,
+                checkpoint.updated
+            );
+            on_checkpoint(&checkpoint)?;
+        }
+    }
+}
+
+/// Count a read of the replica, or of the master if `read_from_master`.
+pub(crate) fn increment_read_counter(ctx: &CoreContext, read_from_master: bool) {
+    let counter = if read_from_master {
+        PerfCounterType::SqlReadsMaster
+    } else {
+        PerfCounterType::SqlReadsReplica
+    };
+    ctx.perf_counters().increment_counter(counter);
 }
 
 fn check_missing_rows(
@@ -556,6 +1034,74 @@ async fn select_changeset(
     Ok(result)
 }
 
+/// Select the changesets and their parents, by changeset id.
+async fn select_many_changesets_with_parents(
+    fb: FacebookInit,
+    connection: &RendezVousConnection,
+    repo_id: RepositoryId,
+    cs_ids: &[ChangesetId],
+) -> Result<HashMap<ChangesetId, ChangesetEntry>, Error> {
+    if cs_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let ret = connection
+        .rdv_with_parents
+        .dispatch(fb, cs_ids.iter().copied().collect(), || {
+            let conn = connection.conn.clone();
+            move |cs_ids| async move {
+                let cs_ids = cs_ids.into_iter().collect::<Vec<_>>();
+
+                let tok: i32 = rand::thread_rng().gen();
+
+                let fetched_changesets =
+                    SelectManyChangesetsWithParents::query(&conn, &repo_id, &tok, &cs_ids[..])
+                        .await?;
+
+                let mut cs_id_to_cs_entry = HashMap::new();
+                for (cs_id, gen, maybe_parent, _, _) in fetched_changesets {
+                    cs_id_to_cs_entry
+                        .entry(cs_id)
+                        .or_insert(ChangesetEntry {
+                            repo_id,
+                            cs_id,
+                            parents: vec![],
+                            gen,
+                        })
+                        .parents
+                        .extend(maybe_parent.into_iter());
+                }
+
+                // Rows of the parents are only found through the changesets
+                // asked for, so they are returned along with them.
+                let mut with_parents = HashMap::new();
+                for cs_id in cs_ids {
+                    if let Some(entry) = cs_id_to_cs_entry.get(&cs_id) {
+                        let mut entries = vec![entry.clone()];
+                        entries.extend(
+                            entry
+                                .parents
+                                .iter()
+                                .filter_map(|p| cs_id_to_cs_entry.get(p))
+                                .cloned(),
+                        );
+                        with_parents.insert(cs_id, entries);
+                    }
+                }
+
+                Ok(with_parents)
+            }
+        })
+        .await?;
+
+    Ok(ret
+        .into_iter()
+        .filter_map(|(_, v)| v)
+        .flatten()
+        .map(|entry| (entry.cs_id, entry))
+        .collect())
+}
+
 async fn select_many_changesets(
     fb: FacebookInit,
     connection: &RendezVousConnection,
@@ -6,21 +6,35 @@
  */
 
 //! Tests for the Changesets store.
-use super::{CachingChangesets, SqlChangesets, SqlChangesetsBuilder};
+use super::{
+    CachingChangesets, CachingChangesetsOptions, GenerationBackfillCheckpoint, RepoPurgeToken,
+    ShardedSqlChangesets, ShardedSqlChangesetsBuilder, SqlChangesets, SqlChangesetsBuilder,
+    VisibleChangesets,
+};
 use anyhow::Error;
 use assert_matches::assert_matches;
+use async_trait::async_trait;
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, SqlBonsaiHgMappingBuilder};
 use caching_ext::MockStoreStats;
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
-use context::CoreContext;
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, HiddenChangesets,
+    PrefixMatch, ResolvedPrefix, SortOrder,
+};
+use context::{CoreContext, PerfCounterType};
 use fbinit::FacebookInit;
-use futures::Future;
-use maplit::hashset;
-use mononoke_types::{ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix};
+use futures::{Future, StreamExt, TryStreamExt};
+use maplit::{hashmap, hashset};
+use mercurial_types_mocks::nodehash as hg;
+use mononoke_types::{
+    hash::Blake2, ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix,
+};
 use mononoke_types_mocks::changesetid::*;
 use mononoke_types_mocks::repo::*;
 use rendezvous::RendezVousOptions;
-use sql_construct::SqlConstruct;
-use std::{collections::HashSet, str::FromStr, sync::Arc};
+use sql::{rusqlite::Connection as SqliteConnection, Connection};
+use sql_construct::{SqlConstruct, SqlShardedConstruct};
+use sql_ext::{SqlConnections, SqlShardedConnections};
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
 
 use crate::sql::SqlChangesetsError;
 
@@ -39,6 +53,33 @@ where
     Ok(())
 }
 
+fn sharded_changesets(shard_count: usize) -> Result<ShardedSqlChangesets, Error> {
+    let shards = (0..shard_count)
+        .map(|_| {
+            let con = SqliteConnection::open_in_memory()?;
+            con.execute_batch(
+                <ShardedSqlChangesetsBuilder as SqlShardedConstruct>::CREATION_QUERY,
+            )?;
+            Ok(Connection::with_sqlite(con))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let connections = SqlShardedConnections {
+        read_connections: shards.clone(),
+        read_master_connections: shards.clone(),
+        write_connections: shards,
+    };
+    Ok(ShardedSqlChangesetsBuilder::from_sql_shard_connections(connections).build(REPO_ZERO))
+}
+
+async fn run_sharded_test<F, FO>(fb: FacebookInit, test_fn: F) -> Result<(), Error>
+where
+    F: FnOnce(FacebookInit, ShardedSqlChangesets) -> FO,
+    FO: Future<Output = Result<(), Error>>,
+{
+    test_fn(fb, sharded_changesets(4)?).await?;
+    Ok(())
+}
+
 async fn run_caching_test<F, FO>(fb: FacebookInit, test_fn: F) -> Result<(), Error>
 where
     F: FnOnce(FacebookInit, CachingChangesets) -> FO,
@@ -431,6 +472,187 @@ async fn get_many_missing<C: Changesets>(fb: FacebookInit, changesets: C) -> Res
     Ok(())
 }
 
+async fn exists_many<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![TWOS_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    assert_eq!(changesets.exists_many(&ctx, vec![]).await?, hashmap! {});
+    assert_eq!(
+        changesets
+            .exists_many(&ctx, vec![ONES_CSID, FOURS_CSID, TWOS_CSID, ONES_CSID])
+            .await?,
+        hashmap! {
+            ONES_CSID => true,
+            TWOS_CSID => true,
+            FOURS_CSID => false,
+        }
+    );
+    // Ancestors of changesets that exist are known to exist.
+    assert_eq!(
+        changesets
+            .exists_many(&ctx, vec![THREES_CSID, FOURS_CSID, ONES_CSID])
+            .await?,
+        hashmap! {
+            ONES_CSID => true,
+            THREES_CSID => true,
+            FOURS_CSID => false,
+        }
+    );
+
+    Ok(())
+}
+
+async fn get_many_stream<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![TWOS_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    let actual = changesets
+        .get_many_stream(&ctx, vec![ONES_CSID, FOURS_CSID, THREES_CSID])
+        .try_collect::<HashSet<_>>()
+        .await?;
+    assert_eq!(
+        actual,
+        hashset![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: ONES_CSID,
+                parents: vec![],
+                gen: 1,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: THREES_CSID,
+                parents: vec![TWOS_CSID],
+                gen: 3,
+            },
+        ]
+    );
+
+    let actual = changesets
+        .get_many_stream(&ctx, vec![])
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(actual, vec![]);
+
+    Ok(())
+}
+
+async fn get_many_with_parents<C: Changesets>(
+    fb: FacebookInit,
+    changesets: C,
+) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    // 1 - 2 - 3 - 5 - 6
+    //      \     /
+    //       - 4 -
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![TWOS_CSID]),
+        (FOURS_CSID, vec![TWOS_CSID]),
+        (FIVES_CSID, vec![THREES_CSID, FOURS_CSID]),
+        (SIXES_CSID, vec![FIVES_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    let get = |cs_ids: Vec<ChangesetId>, depth| {
+        let ctx = ctx.clone();
+        let changesets = &changesets;
+        async move {
+            let entries = changesets.get_many_with_parents(ctx, cs_ids, depth).await?;
+            let cs_ids: Vec<_> = entries.iter().map(|entry| entry.cs_id).collect();
+            let unique: HashSet<_> = cs_ids.iter().copied().collect();
+            assert_eq!(cs_ids.len(), unique.len(), "duplicate entries");
+            Result::<_, Error>::Ok(unique)
+        }
+    };
+
+    assert_eq!(get(vec![SIXES_CSID], 0).await?, hashset![SIXES_CSID]);
+    assert_eq!(
+        get(vec![SIXES_CSID], 1).await?,
+        hashset![SIXES_CSID, FIVES_CSID]
+    );
+    assert_eq!(
+        get(vec![SIXES_CSID], 2).await?,
+        hashset![SIXES_CSID, FIVES_CSID, THREES_CSID, FOURS_CSID]
+    );
+    assert_eq!(
+        get(vec![SIXES_CSID], 3).await?,
+        hashset![SIXES_CSID, FIVES_CSID, THREES_CSID, FOURS_CSID, TWOS_CSID]
+    );
+    assert_eq!(
+        get(vec![SIXES_CSID], 10).await?,
+        hashset![
+            SIXES_CSID,
+            FIVES_CSID,
+            THREES_CSID,
+            FOURS_CSID,
+            TWOS_CSID,
+            ONES_CSID
+        ]
+    );
+    assert_eq!(
+        get(vec![THREES_CSID, FOURS_CSID, TWOS_CSID], 1).await?,
+        hashset![THREES_CSID, FOURS_CSID, TWOS_CSID, ONES_CSID]
+    );
+    assert_eq!(
+        get(vec![SEVENS_CSID, TWOS_CSID], 1).await?,
+        hashset![TWOS_CSID, ONES_CSID]
+    );
+    assert_eq!(get(vec![], 3).await?, hashset![]);
+
+    // The entries are complete, with the parents in order.
+    let entries = changesets
+        .get_many_with_parents(ctx.clone(), vec![FIVES_CSID], 1)
+        .await?;
+    assert_eq!(
+        HashSet::from_iter(entries),
+        hashset![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: FIVES_CSID,
+                parents: vec![THREES_CSID, FOURS_CSID],
+                gen: 4,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: THREES_CSID,
+                parents: vec![TWOS_CSID],
+                gen: 3,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: FOURS_CSID,
+                parents: vec![TWOS_CSID],
+                gen: 3,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
 async fn get_many_by_prefix<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
     let ctx = CoreContext::test_mock(fb);
 
@@ -528,6 +750,91 @@ async fn get_many_by_prefix<C: Changesets>(fb: FacebookInit, changesets: C) -> R
     Ok(())
 }
 
+async fn get_many_by_hex_prefix<C: Changesets>(
+    fb: FacebookInit,
+    changesets: C,
+) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bonsai_hg_mapping =
+        SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()?.build(RendezVousOptions::for_test());
+
+    for cs_id in [ONES_CSID, FS_ES_CSID, FS_CSID] {
+        let row = ChangesetInsert {
+            cs_id,
+            parents: vec![],
+        };
+        changesets.add(ctx.clone(), row).await?;
+    }
+    bonsai_hg_mapping
+        .add(
+            &ctx,
+            BonsaiHgMappingEntry {
+                repo_id: REPO_ZERO,
+                hg_cs_id: hg::ONES_CSID,
+                bcs_id: ONES_CSID,
+            },
+        )
+        .await?;
+
+    // matches both bonsai and hg changesets
+    let actual = changesets
+        .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, "1111", 10)
+        .await?;
+    assert_eq!(
+        actual,
+        ResolvedPrefix::Multiple(vec![
+            PrefixMatch::Bonsai(ONES_CSID),
+            PrefixMatch::Hg(hg::ONES_CSID),
+        ]),
+    );
+
+    // the limit applies to the total
+    let actual = changesets
+        .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, "1111", 1)
+        .await?;
+    assert_eq!(
+        actual,
+        ResolvedPrefix::TooMany(vec![PrefixMatch::Bonsai(ONES_CSID)]),
+    );
+
+    // only bonsai changesets match
+    let actual = changesets
+        .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, "fff", 10)
+        .await?;
+    assert_eq!(
+        actual,
+        ResolvedPrefix::Multiple(vec![
+            PrefixMatch::Bonsai(FS_ES_CSID),
+            PrefixMatch::Bonsai(FS_CSID),
+        ]),
+    );
+
+    // too long to be an hg changeset id
+    let actual = changesets
+        .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, &ONES_CSID.to_string(), 10)
+        .await?;
+    assert_eq!(
+        actual,
+        ResolvedPrefix::Single(PrefixMatch::Bonsai(ONES_CSID))
+    );
+
+    // not found
+    let actual = changesets
+        .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, "2222", 10)
+        .await?;
+    assert_eq!(actual, ResolvedPrefix::NoMatch);
+
+    // not hex
+    assert!(
+        changesets
+            .get_many_by_hex_prefix(&ctx, &bonsai_hg_mapping, "xyz", 10)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
 async fn caching_fill<C: Changesets + 'static>(
     fb: FacebookInit,
     changesets: C,
@@ -799,11 +1106,590 @@ async fn caching_shared<C: Changesets + 'static>(
     Ok(())
 }
 
-// NOTE: Use this wrapper macro to make sure tests are executed both with Changesets and
-// CachingChangesets. Define tests using #[test] if you need to only execute them for Changesets or
-// CachingChangesets.
+async fn negative_caching<C: Changesets + 'static>(
+    fb: FacebookInit,
+    changesets: C,
+) -> Result<(), Error> {
+    let changesets = Arc::new(changesets);
+    let cc = CachingChangesets::mocked(changesets.clone()).with_options(CachingChangesetsOptions {
+        negative_ttl: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    });
+    let ctx = CoreContext::test_mock(fb);
+    let row = |cs_id| ChangesetInsert {
+        cs_id,
+        parents: vec![],
+    };
+
+    let subscription = cc.subscribe(&ctx).await?;
+    assert_eq!(cc.get(ctx.clone(), ONES_CSID).await?, None);
+    assert!(!cc.exists(&ctx, TWOS_CSID).await?);
+
+    // Changesets added behind the cache's back stay missing until the
+    // negative entries expire.
+    changesets.add(ctx.clone(), row(ONES_CSID)).await?;
+    changesets.add(ctx.clone(), row(TWOS_CSID)).await?;
+    assert_eq!(cc.get(ctx.clone(), ONES_CSID).await?, None);
+    assert_eq!(
+        cc.get_many(ctx.clone(), vec![ONES_CSID, TWOS_CSID]).await?,
+        vec![]
+    );
+
+    // Subscribers see them regardless.
+    let entries = subscription
+        .map_ok(|entry| entry.cs_id)
+        .take(2)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(entries, vec![ONES_CSID, TWOS_CSID]);
+    assert!(cc.exists(&ctx, TWOS_CSID).await?);
+
+    // Changesets added through the cache are seen straight away.
+    cc.add(ctx.clone(), row(ONES_CSID)).await?;
+    assert!(cc.exists(&ctx, ONES_CSID).await?);
+    assert!(!cc.exists(&ctx, TWOS_CSID).await?);
+    Ok(())
+}
+
+async fn subscribe<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    changesets
+        .add(
+            ctx.clone(),
+            ChangesetInsert {
+                cs_id: ONES_CSID,
+                parents: vec![],
+            },
+        )
+        .await?;
+
+    // Only changesets added after subscribing are returned.
+    let subscription = changesets.subscribe(&ctx).await?;
+    for (cs_id, parents) in [(TWOS_CSID, vec![ONES_CSID]), (THREES_CSID, vec![TWOS_CSID])] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    let entries = subscription.take(2).try_collect::<Vec<_>>().await?;
+    assert_eq!(
+        entries,
+        vec![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: TWOS_CSID,
+                parents: vec![ONES_CSID],
+                gen: 2,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: THREES_CSID,
+                parents: vec![TWOS_CSID],
+                gen: 3,
+            },
+        ]
+    );
+    Ok(())
+}
+
+async fn get_many_with_generation_bounds<C: Changesets>(
+    fb: FacebookInit,
+    changesets: C,
+) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![]),
+        (THREES_CSID, vec![TWOS_CSID]),
+        (FOURS_CSID, vec![ONES_CSID, THREES_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    let gens = |entries: Vec<ChangesetEntry>| {
+        entries
+            .into_iter()
+            .map(|entry| (entry.cs_id, entry.gen))
+            .collect::<Vec<_>>()
+    };
+
+    let actual = changesets
+        .get_many_with_generation_bounds(ctx.clone(), 1, 2, 10)
+        .await?;
+    assert_eq!(
+        gens(actual),
+        vec![(ONES_CSID, 1), (TWOS_CSID, 1), (THREES_CSID, 2)]
+    );
+
+    let actual = changesets
+        .get_many_with_generation_bounds(ctx.clone(), 2, 3, 1)
+        .await?;
+    assert_eq!(gens(actual), vec![(THREES_CSID, 2)]);
+
+    let actual = changesets
+        .get_many_with_generation_bounds(ctx.clone(), 4, 10, 10)
+        .await?;
+    assert_eq!(gens(actual), vec![]);
+    Ok(())
+}
+
+async fn get_children<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![ONES_CSID]),
+        (FOURS_CSID, vec![TWOS_CSID, THREES_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    assert_eq!(
+        changesets.get_children(ctx.clone(), ONES_CSID).await?,
+        vec![TWOS_CSID, THREES_CSID]
+    );
+    assert_eq!(
+        changesets.get_children(ctx.clone(), THREES_CSID).await?,
+        vec![FOURS_CSID]
+    );
+    assert_eq!(
+        changesets.get_children(ctx.clone(), FOURS_CSID).await?,
+        vec![]
+    );
+    assert_eq!(
+        changesets.get_children(ctx.clone(), FIVES_CSID).await?,
+        vec![]
+    );
+    Ok(())
+}
+
+async fn sequence<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![TWOS_CSID]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    let (min_id, max_id) = changesets
+        .enumeration_bounds(&ctx, true)
+        .await?
+        .expect("changesets should not be empty");
+    let enumerated: Vec<_> = changesets
+        .list_enumeration_range(
+            &ctx,
+            min_id,
+            max_id + 1,
+            Some((SortOrder::Ascending, 10)),
+            true,
+        )
+        .try_collect()
+        .await?;
+
+    let mut sequence = Vec::new();
+    for cs_id in [ONES_CSID, TWOS_CSID, THREES_CSID] {
+        let seq = changesets
+            .get_sequence(&ctx, cs_id)
+            .await?
+            .expect("changeset should have a sequence number");
+        sequence.push((cs_id, seq.0));
+    }
+    assert_eq!(sequence, enumerated);
+    assert!(sequence.windows(2).all(|w| w[0].1 < w[1].1));
+
+    assert_eq!(changesets.get_sequence(&ctx, FOURS_CSID).await?, None);
+
+    // Waiting for sequence numbers that have already been assigned returns
+    // immediately.
+    for (_, seq) in sequence {
+        changesets
+            .wait_for_sequence(&ctx, ChangesetsSequenceNumber(seq))
+            .await?;
+    }
+    Ok(())
+}
+
+struct HiddenSet(HashSet<ChangesetId>);
+
+#[async_trait]
+impl HiddenChangesets for HiddenSet {
+    async fn hidden(
+        &self,
+        _ctx: &CoreContext,
+        cs_ids: &[ChangesetId],
+    ) -> Result<HashSet<ChangesetId>, Error> {
+        Ok(cs_ids
+            .iter()
+            .filter(|cs_id| self.0.contains(cs_id))
+            .copied()
+            .collect())
+    }
+}
+
+#[fbinit::test]
+async fn test_visible_changesets(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let sql_changesets = Arc::new(
+        SqlChangesetsBuilder::with_sqlite_in_memory()
+            .unwrap()
+            .build(RendezVousOptions::for_test(), REPO_ZERO),
+    );
+    let changesets = VisibleChangesets::new(
+        sql_changesets,
+        Arc::new(HiddenSet(hashset![TWOS_CSID, FS_CSID])),
+    );
+    for (cs_id, parents) in [
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (FS_ES_CSID, vec![]),
+        (FS_CSID, vec![]),
+    ] {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+    }
+
+    assert!(changesets.get(ctx.clone(), ONES_CSID).await?.is_some());
+    assert_eq!(changesets.get(ctx.clone(), TWOS_CSID).await?, None);
+    assert!(!changesets.exists(&ctx, TWOS_CSID).await?);
+    assert_eq!(
+        changesets
+            .exists_many(&ctx, vec![ONES_CSID, TWOS_CSID])
+            .await?,
+        hashmap! { ONES_CSID => true, TWOS_CSID => false }
+    );
+
+    let cs_ids = changesets
+        .get_many(ctx.clone(), vec![ONES_CSID, TWOS_CSID])
+        .await?
+        .into_iter()
+        .map(|entry| entry.cs_id)
+        .collect::<Vec<_>>();
+    assert_eq!(cs_ids, vec![ONES_CSID]);
+
+    let (min_id, max_id) = changesets.enumeration_bounds(&ctx, false).await?.unwrap();
+    let enumerated = changesets
+        .list_enumeration_range(
+            &ctx,
+            min_id,
+            max_id,
+            Some((SortOrder::Ascending, 10)),
+            false,
+        )
+        .map_ok(|(cs_id, _)| cs_id)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(enumerated, vec![ONES_CSID, FS_ES_CSID]);
+
+    // The prefix matches FS_ES_CSID and FS_CSID, but FS_CSID is hidden.
+    let prefix = ChangesetIdPrefix::from_bytes(&FS_CSID.as_ref()[0..3]).unwrap();
+    assert_eq!(
+        changesets
+            .get_many_by_prefix(ctx.clone(), prefix, 10)
+            .await?,
+        ChangesetIdsResolvedFromPrefix::Single(FS_ES_CSID)
+    );
+
+    // Sessions can opt in to seeing hidden changesets.
+    let mut admin_ctx = ctx.clone();
+    admin_ctx
+        .session_mut()
+        .override_show_hidden_changesets(true);
+    let entry = changesets.get(admin_ctx.clone(), TWOS_CSID).await?;
+    assert_eq!(entry.map(|entry| entry.cs_id), Some(TWOS_CSID));
+    assert_matches!(
+        changesets.get_many_by_prefix(admin_ctx, prefix, 10).await?,
+        ChangesetIdsResolvedFromPrefix::Multiple(_)
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_sharded_enumeration(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let changesets = sharded_changesets(4)?;
+
+    // These are stored in all four shards. Each is added twice.
+    let cs_ids = [ONES_CSID, TWOS_CSID, THREES_CSID, FOURS_CSID, FIVES_CSID];
+    let mut parents = vec![];
+    for cs_id in cs_ids {
+        let cs = ChangesetInsert { cs_id, parents };
+        assert!(changesets.add(ctx.clone(), cs.clone()).await?);
+        assert!(!changesets.add(ctx.clone(), cs).await?);
+        parents = vec![cs_id];
+    }
+
+    // Enumeration bounds and ranges cover all shards, in insertion order.
+    // Duplicates are not allocated ids.
+    let (min_id, max_id) = changesets.enumeration_bounds(&ctx, false).await?.unwrap();
+    assert_eq!(max_id - min_id, 4);
+    let list = |sort_and_limit: Option<(SortOrder, u64)>| {
+        changesets
+            .list_enumeration_range(&ctx, min_id, max_id + 1, sort_and_limit, false)
+            .map_ok(|(cs_id, _)| cs_id)
+            .try_collect::<Vec<_>>()
+    };
+    assert_eq!(list(None).await?, cs_ids);
+    assert_eq!(
+        list(Some((SortOrder::Ascending, 2))).await?,
+        [ONES_CSID, TWOS_CSID]
+    );
+    assert_eq!(
+        list(Some((SortOrder::Descending, 2))).await?,
+        [FIVES_CSID, FOURS_CSID]
+    );
+
+    // Parents and children are found in other shards.
+    let entry = changesets.get(ctx.clone(), THREES_CSID).await?.unwrap();
+    assert_eq!((entry.parents, entry.gen), (vec![TWOS_CSID], 3));
+    assert_eq!(
+        changesets.get_children(ctx.clone(), THREES_CSID).await?,
+        vec![FOURS_CSID]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_delete_all_for_repo(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let builder = SqlChangesetsBuilder::with_sqlite_in_memory()?;
+    let purged = builder
+        .clone()
+        .build(RendezVousOptions::for_test(), REPO_ZERO);
+    let kept = builder.build(RendezVousOptions::for_test(), REPO_ONE);
+
+    let cs_ids = [ONES_CSID, TWOS_CSID, THREES_CSID, FOURS_CSID, FIVES_CSID];
+    let mut parents = vec![];
+    for cs_id in cs_ids {
+        let cs = ChangesetInsert { cs_id, parents };
+        purged.add(ctx.clone(), cs.clone()).await?;
+        kept.add(ctx.clone(), cs).await?;
+        parents = vec![cs_id];
+    }
+
+    // A token for another repo is refused.
+    assert!(
+        purged
+            .delete_all_for_repo(
+                &ctx,
+                REPO_ZERO,
+                2,
+                RepoPurgeToken::for_decommissioned_repo(REPO_ONE)
+            )
+            .await
+            .is_err()
+    );
+    assert!(purged.get(ctx.clone(), ONES_CSID).await?.is_some());
+
+    let deleted = purged
+        .delete_all_for_repo(
+            &ctx,
+            REPO_ZERO,
+            2,
+            RepoPurgeToken::for_decommissioned_repo(REPO_ZERO),
+        )
+        .await?;
+    assert_eq!(deleted, 5);
+    for cs_id in cs_ids {
+        assert_eq!(purged.get(ctx.clone(), cs_id).await?, None);
+    }
+
+    // Other repos are untouched.
+    let entry = kept.get(ctx.clone(), FIVES_CSID).await?.unwrap();
+    assert_eq!((entry.parents, entry.gen), (vec![FOURS_CSID], 5));
+    assert_eq!(
+        kept.get_children(ctx.clone(), FOURS_CSID).await?,
+        vec![FIVES_CSID]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_backfill_generations(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+
+    // Rows written before generation numbers were stored have a generation
+    // of 0. FOURS_CSID is a merge of TWOS_CSID and THREES_CSID, which are
+    // both children of ONES_CSID.
+    let con = SqliteConnection::open_in_memory()?;
+    con.execute_batch(<SqlChangesetsBuilder as SqlConstruct>::CREATION_QUERY)?;
+    for cs_id in [ONES_CSID, TWOS_CSID, THREES_CSID, FOURS_CSID] {
+        con.execute_batch(&format!(
+            "INSERT INTO changesets (repo_id, cs_id, gen) VALUES ({}, X'{}', 0)",
+            REPO_ZERO.id(),
+            cs_id
+        ))?;
+    }
+    con.execute_batch(
+        "INSERT INTO csparents (cs_id, parent_id, seq)
+         VALUES (2, 1, 0), (3, 1, 0), (4, 2, 0), (4, 3, 1)",
+    )?;
+    let changesets = SqlChangesetsBuilder::from_sql_connections(SqlConnections::new_single(
+        Connection::with_sqlite(con),
+    ))
+    .build(RendezVousOptions::for_test(), REPO_ZERO);
+
+    // Stop after the first batch, then resume from its checkpoint.
+    let mut saved = None;
+    let res = changesets
+        .backfill_generations_from(
+            &ctx,
+            2,
+            GenerationBackfillCheckpoint::default(),
+            |checkpoint| {
+                saved = Some(*checkpoint);
+                Err(anyhow::anyhow!("interrupted"))
+            },
+        )
+        .await;
+    assert!(res.is_err());
+    let saved = saved.unwrap();
+    assert_eq!(saved.next_id, 3);
+    assert_eq!(saved.updated, 2);
+    assert_eq!(
+        changesets.get(ctx.clone(), FOURS_CSID).await?.unwrap().gen,
+        0
+    );
+
+    let checkpoint = changesets
+        .backfill_generations_from(&ctx, 2, saved, |_| Ok(()))
+        .await?;
+    assert_eq!(checkpoint.next_id, 5);
+    assert_eq!(checkpoint.updated, 4);
+    for (cs_id, gen) in [
+        (ONES_CSID, 1),
+        (TWOS_CSID, 2),
+        (THREES_CSID, 2),
+        (FOURS_CSID, 3),
+    ] {
+        assert_eq!(changesets.get(ctx.clone(), cs_id).await?.unwrap().gen, gen);
+    }
+
+    // Changesets added afterwards get the right generation, so a new run
+    // has nothing to update.
+    let cs = ChangesetInsert {
+        cs_id: FIVES_CSID,
+        parents: vec![FOURS_CSID],
+    };
+    changesets.add(ctx.clone(), cs).await?;
+    assert_eq!(
+        changesets.get(ctx.clone(), FIVES_CSID).await?.unwrap().gen,
+        4
+    );
+    let checkpoint = changesets.backfill_generations(&ctx, 2).await?;
+    assert_eq!(checkpoint.next_id, 6);
+    assert_eq!(checkpoint.updated, 0);
+    Ok(())
+}
+
+/// Number of SQL reads recorded in `ctx`. Sharded stores query their shards
+/// concurrently, and count each round of queries as one read.
+fn sql_reads(ctx: &CoreContext) -> i64 {
+    ctx.perf_counters()
+        .get_counter(PerfCounterType::SqlReadsReplica)
+        + ctx
+            .perf_counters()
+            .get_counter(PerfCounterType::SqlReadsMaster)
+}
+
+async fn query_fan_out<C: Changesets>(fb: FacebookInit, changesets: C) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let cs_ids: Vec<_> = (0..100u8)
+        .map(|i| ChangesetId::from(Blake2::from_byte_array([i; 32])))
+        .collect();
+    let mut parents = vec![];
+    for cs_id in &cs_ids {
+        let cs = ChangesetInsert {
+            cs_id: *cs_id,
+            parents,
+        };
+        changesets.add(ctx.clone(), cs).await?;
+        parents = vec![*cs_id];
+    }
+
+    // The number of reads must not grow with the number of changesets asked
+    // for: one read of the replica, and one of the master for the missing.
+    let ctx = CoreContext::test_mock(fb);
+    let entries = changesets.get_many(ctx.clone(), cs_ids.clone()).await?;
+    assert_eq!(entries.len(), cs_ids.len());
+    assert!(sql_reads(&ctx) <= 2, "get_many: {} reads", sql_reads(&ctx));
+
+    let ctx = CoreContext::test_mock(fb);
+    let mut with_missing = cs_ids.clone();
+    with_missing.push(FS_CSID);
+    changesets.get_many(ctx.clone(), with_missing).await?;
+    assert!(
+        sql_reads(&ctx) <= 2,
+        "get_many with missing: {} reads",
+        sql_reads(&ctx)
+    );
+
+    let ctx = CoreContext::test_mock(fb);
+    changesets
+        .get_many_by_prefix(ctx.clone(), ChangesetIdPrefix::from_bytes(&[0u8])?, 10)
+        .await?;
+    assert!(
+        sql_reads(&ctx) <= 2,
+        "get_many_by_prefix: {} reads",
+        sql_reads(&ctx)
+    );
+
+    let ctx = CoreContext::test_mock(fb);
+    let entries = changesets
+        .get_many_with_generation_bounds(ctx.clone(), 1, 100, 100)
+        .await?;
+    assert_eq!(entries.len(), 100);
+    assert!(
+        sql_reads(&ctx) <= 3,
+        "get_many_with_generation_bounds: {} reads",
+        sql_reads(&ctx)
+    );
+
+    let ctx = CoreContext::test_mock(fb);
+    let entries = changesets
+        .get_many_with_parents(ctx.clone(), vec![cs_ids[99]], 9)
+        .await?;
+    assert_eq!(entries.len(), 10);
+    assert!(
+        sql_reads(&ctx) <= 10,
+        "get_many_with_parents: {} reads",
+        sql_reads(&ctx)
+    );
+
+    let ctx = CoreContext::test_mock(fb);
+    let (min_id, max_id) = changesets
+        .enumeration_bounds(&ctx, false)
+        .await?
+        .expect("changesets were added");
+    assert_eq!(sql_reads(&ctx), 1);
+
+    let ctx = CoreContext::test_mock(fb);
+    let listed: Vec<_> = changesets
+        .list_enumeration_range(&ctx, min_id, max_id + 1, None, false)
+        .try_collect()
+        .await?;
+    assert_eq!(listed.len(), cs_ids.len());
+    assert_eq!(sql_reads(&ctx), 1);
+    Ok(())
+}
+
+// NOTE: Use this wrapper macro to make sure tests are executed with Changesets,
+// CachingChangesets and ShardedSqlChangesets. Define tests using #[test] if you need to only
+// execute them for one of them.
 macro_rules! testify {
-    ($plain_name: ident, $caching_name: ident, $input: ident) => {
+    ($plain_name: ident, $caching_name: ident, $sharded_name: ident, $input: ident) => {
         #[fbinit::test]
         async fn $plain_name(fb: FacebookInit) -> Result<(), Error> {
             run_test(fb, $input).await
@@ -813,34 +1699,148 @@ macro_rules! testify {
         async fn $caching_name(fb: FacebookInit) -> Result<(), Error> {
             run_caching_test(fb, $input).await
         }
+
+        #[fbinit::test]
+        async fn $sharded_name(fb: FacebookInit) -> Result<(), Error> {
+            run_sharded_test(fb, $input).await
+        }
     };
 }
 
-testify!(test_add_and_get, test_caching_add_and_get, add_and_get);
+testify!(
+    test_add_and_get,
+    test_caching_add_and_get,
+    test_sharded_add_and_get,
+    add_and_get
+);
 testify!(
     test_add_missing_parents,
     test_caching_add_missing_parents,
+    test_sharded_add_missing_parents,
     add_missing_parents
 );
-testify!(test_missing, test_caching_missing, missing);
-testify!(test_duplicate, test_caching_duplicate, duplicate);
+testify!(
+    test_missing,
+    test_caching_missing,
+    test_sharded_missing,
+    missing
+);
+testify!(
+    test_duplicate,
+    test_caching_duplicate,
+    test_sharded_duplicate,
+    duplicate
+);
 testify!(
     test_broken_duplicate,
     test_caching_broken_duplicate,
+    test_sharded_broken_duplicate,
     broken_duplicate
 );
-testify!(test_complex, test_caching_complex, complex);
-testify!(test_get_many, test_caching_get_many, get_many);
+testify!(
+    test_complex,
+    test_caching_complex,
+    test_sharded_complex,
+    complex
+);
+testify!(
+    test_get_many,
+    test_caching_get_many,
+    test_sharded_get_many,
+    get_many
+);
+testify!(
+    test_exists_many,
+    test_caching_exists_many,
+    test_sharded_exists_many,
+    exists_many
+);
+testify!(
+    test_get_many_stream,
+    test_caching_get_many_stream,
+    test_sharded_get_many_stream,
+    get_many_stream
+);
+testify!(
+    test_get_many_with_parents,
+    test_caching_get_many_with_parents,
+    test_sharded_get_many_with_parents,
+    get_many_with_parents
+);
 testify!(
     test_get_many_by_prefix,
     test_caching_get_many_by_prefix,
+    test_sharded_get_many_by_prefix,
     get_many_by_prefix
 );
+testify!(
+    test_get_many_by_hex_prefix,
+    test_caching_get_many_by_hex_prefix,
+    test_sharded_get_many_by_hex_prefix,
+    get_many_by_hex_prefix
+);
 testify!(
     test_get_many_missing,
     test_caching_get_many_missing,
+    test_sharded_get_many_missing,
     get_many_missing
 );
+testify!(
+    test_get_many_with_generation_bounds,
+    test_caching_get_many_with_generation_bounds,
+    test_sharded_get_many_with_generation_bounds,
+    get_many_with_generation_bounds
+);
+testify!(
+    test_get_children,
+    test_caching_get_children,
+    test_sharded_get_children,
+    get_children
+);
+testify!(
+    test_sequence,
+    test_caching_sequence,
+    test_sharded_sequence,
+    sequence
+);
+testify!(
+    test_subscribe,
+    test_caching_subscribe,
+    test_sharded_subscribe,
+    subscribe
+);
+testify!(
+    test_query_fan_out,
+    test_caching_query_fan_out,
+    test_sharded_query_fan_out,
+    query_fan_out
+);
+
+#[fbinit::test]
+async fn test_get_many_with_parents_round_trips(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let changesets = SqlChangesetsBuilder::with_sqlite_in_memory()?
+        .build(RendezVousOptions::for_test(), REPO_ZERO);
+    let cs_ids = [ONES_CSID, TWOS_CSID, THREES_CSID, FOURS_CSID, FIVES_CSID];
+    let mut parents = vec![];
+    for cs_id in cs_ids {
+        changesets
+            .add(ctx.clone(), ChangesetInsert { cs_id, parents })
+            .await?;
+        parents = vec![cs_id];
+    }
+
+    // Each query fetches two levels of history.
+    for (depth, reads) in [(1, 1), (2, 2), (3, 2), (4, 3)] {
+        let ctx = CoreContext::test_mock(fb);
+        let entries = changesets
+            .get_many_with_parents(ctx.clone(), vec![FIVES_CSID], depth)
+            .await?;
+        assert_eq!(entries.len(), depth + 1);
+        assert_eq!(sql_reads(&ctx), reads, "depth {}", depth);
+    }
+    Ok(())
+}
 
 #[fbinit::test]
 async fn test_caching_fill(fb: FacebookInit) -> Result<(), Error> {
@@ -851,3 +1851,8 @@ async fn test_caching_fill(fb: FacebookInit) -> Result<(), Error> {
 async fn test_caching_shared(fb: FacebookInit) -> Result<(), Error> {
     run_test(fb, caching_shared).await
 }
+
+#[fbinit::test]
+async fn test_negative_caching(fb: FacebookInit) -> Result<(), Error> {
+    run_test(fb, negative_caching).await
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashSet;
+
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use changesets::{
+    ArcChangesets, ArcHiddenChangesets, ChangesetEntry, ChangesetInsert, Changesets,
+    ChangesetsSequenceNumber, SortOrder,
+};
+use context::CoreContext;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use mononoke_types::{
+    ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix, RepositoryId,
+};
+
+/// Number of enumerated changesets checked against `HiddenChangesets` at once.
+const ENUMERATION_HIDDEN_CHECK_BATCH: usize = 1000;
+
+/// Changesets that filters out the changesets marked as hidden, unless the
+/// session asks for them with `show_hidden_changesets`.
+///
+/// Writes, cache priming and enumeration bounds are passed through, so hidden
+/// changesets keep their place in the repo; they just can't be looked up.
+pub struct VisibleChangesets {
+    changesets: ArcChangesets,
+    hidden: ArcHiddenChangesets,
+}
+
+impl VisibleChangesets {
+    pub fn new(changesets: ArcChangesets, hidden: ArcHiddenChangesets) -> Self {
+        Self { changesets, hidden }
+    }
+
+    async fn hidden(
+        &self,
+        ctx: &CoreContext,
+        cs_ids: &[ChangesetId],
+    ) -> Result<HashSet<ChangesetId>, Error> {
+        if cs_ids.is_empty() || ctx.session().show_hidden_changesets() {
+            return Ok(HashSet::new());
+        }
+        self.hidden.hidden(ctx, cs_ids).await
+    }
+
+    async fn filter_entries(
+        &self,
+        ctx: &CoreContext,
+        entries: Vec<ChangesetEntry>,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let cs_ids: Vec<_> = entries.iter().map(|entry| entry.cs_id).collect();
+        let hidden = self.hidden(ctx, &cs_ids).await?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !hidden.contains(&entry.cs_id))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Changesets for VisibleChangesets {
+    fn repo_id(&self) -> RepositoryId {
+        self.changesets.repo_id()
+    }
+
+    async fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> Result<bool, Error> {
+        self.changesets.add(ctx, cs).await
+    }
+
+    async fn get(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetEntry>, Error> {
+        if !self.hidden(&ctx, &[cs_id]).await?.is_empty() {
+            return Ok(None);
+        }
+        self.changesets.get(ctx, cs_id).await
+    }
+
+    async fn exists(&self, ctx: &CoreContext, cs_id: ChangesetId) -> Result<bool, Error> {
+        if !self.hidden(ctx, &[cs_id]).await?.is_empty() {
+            return Ok(false);
+        }
+        self.changesets.exists(ctx, cs_id).await
+    }
+
+    async fn get_many(
+        &self,
+        ctx: CoreContext,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let hidden = self.hidden(&ctx, &cs_ids).await?;
+        let cs_ids = cs_ids
+            .into_iter()
+            .filter(|cs_id| !hidden.contains(cs_id))
+            .collect();
+        self.changesets.get_many(ctx, cs_ids).await
+    }
+
+    async fn get_many_with_generation_bounds(
+        &self,
+        ctx: CoreContext,
+        min_gen: u64,
+        max_gen: u64,
+        limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        // Hidden changesets count towards the limit, so fewer than `limit`
+        // entries may be returned even if there are more in the range.
+        let entries = self
+            .changesets
+            .get_many_with_generation_bounds(ctx.clone(), min_gen, max_gen, limit)
+            .await?;
+        self.filter_entries(&ctx, entries).await
+    }
+
+    async fn get_children(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        self.changesets.get_children(ctx, cs_id).await
+    }
+
+    async fn get_many_by_prefix(
+        &self,
+        ctx: CoreContext,
+        cs_prefix: ChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+        let resolved = self
+            .changesets
+            .get_many_by_prefix(ctx.clone(), cs_prefix, limit)
+            .await?;
+        let filter = |cs_ids: Vec<ChangesetId>, hidden: HashSet<ChangesetId>| {
+            cs_ids
+                .into_iter()
+                .filter(|cs_id| !hidden.contains(cs_id))
+                .collect::<Vec<_>>()
+        };
+        Ok(match resolved {
+            ChangesetIdsResolvedFromPrefix::Single(cs_id) => {
+                if self.hidden(&ctx, &[cs_id]).await?.is_empty() {
+                    ChangesetIdsResolvedFromPrefix::Single(cs_id)
+                } else {
+                    ChangesetIdsResolvedFromPrefix::NoMatch
+                }
+            }
+            ChangesetIdsResolvedFromPrefix::Multiple(cs_ids) => {
+                let hidden = self.hidden(&ctx, &cs_ids).await?;
+                let mut cs_ids = filter(cs_ids, hidden);
+                match cs_ids.len() {
+                    0 => ChangesetIdsResolvedFromPrefix::NoMatch,
+                    1 => ChangesetIdsResolvedFromPrefix::Single(cs_ids.remove(0)),
+                    _ => ChangesetIdsResolvedFromPrefix::Multiple(cs_ids),
+                }
+            }
+            // There are more matches than `limit`, so even with hidden
+            // changesets removed the prefix remains ambiguous.
+            ChangesetIdsResolvedFromPrefix::TooMany(cs_ids) => {
+                let hidden = self.hidden(&ctx, &cs_ids).await?;
+                ChangesetIdsResolvedFromPrefix::TooMany(filter(cs_ids, hidden))
+            }
+            ChangesetIdsResolvedFromPrefix::NoMatch => ChangesetIdsResolvedFromPrefix::NoMatch,
+        })
+    }
+
+    fn prime_cache(&self, ctx: &CoreContext, changesets: &[ChangesetEntry]) {
+        self.changesets.prime_cache(ctx, changesets)
+    }
+
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        self.changesets.get_sequence(ctx, cs_id).await
+    }
+
+    async fn wait_for_sequence(
+        &self,
+        ctx: &CoreContext,
+        seq: ChangesetsSequenceNumber,
+    ) -> Result<(), Error> {
+        self.changesets.wait_for_sequence(ctx, seq).await
+    }
+
+    async fn enumeration_bounds(
+        &self,
+        ctx: &CoreContext,
+        read_from_master: bool,
+    ) -> Result<Option<(u64, u64)>, Error> {
+        self.changesets
+            .enumeration_bounds(ctx, read_from_master)
+            .await
+    }
+
+    fn list_enumeration_range(
+        &self,
+        ctx: &CoreContext,
+        min_id: u64,
+        max_id: u64,
+        sort_and_limit: Option<(SortOrder, u64)>,
+        read_from_master: bool,
+    ) -> BoxStream<'_, Result<(ChangesetId, u64), Error>> {
+        let ctx = ctx.clone();
+        self.changesets
+            .list_enumeration_range(&ctx, min_id, max_id, sort_and_limit, read_from_master)
+            .chunks(ENUMERATION_HIDDEN_CHECK_BATCH)
+            .then(move |chunk| {
+                let ctx = ctx.clone();
+                async move {
+                    let chunk = chunk.into_iter().collect::<Result<Vec<_>, Error>>()?;
+                    let cs_ids: Vec<_> = chunk.iter().map(|(cs_id, _)| *cs_id).collect();
+                    let hidden = self.hidden(&ctx, &cs_ids).await?;
+                    let visible = chunk
+                        .into_iter()
+                        .filter(move |(cs_id, _)| !hidden.contains(cs_id))
+                        .map(Ok::<_, Error>);
+                    Ok::<_, Error>(stream::iter(visible))
+                }
+            })
+            .try_flatten()
+            .boxed()
+    }
+}
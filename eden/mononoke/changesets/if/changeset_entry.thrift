@@ -23,3 +23,10 @@ struct ChangesetEntry {
   3: required list<mononoke_types_thrift.ChangesetId> parents;
   4: required GenerationNum gen;
 } (rust.exhaustive)
+
+// Entry in version 2 of serialized changeset entries, which also carries the
+// generation numbers of the parents, in the same order as `entry.parents`.
+struct ChangesetEntryV2 {
+  1: required ChangesetEntry entry;
+  2: optional list<GenerationNum> parent_gens;
+} (rust.exhaustive)
@@ -5,12 +5,20 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashMap;
+
 use abomonation_derive::Abomonation;
-use anyhow::Result;
-use bytes::Bytes;
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
 use fbthrift::compact_protocol;
 use mononoke_types::{ChangesetId, RepositoryId};
 
+// Version 1 blobs are a bare compact protocol list of structs, so their first
+// byte always has 0xC (struct) as its low nibble. Later versions start with a
+// version byte that can't be mistaken for that.
+const V1_LIST_HEADER_TYPE: u8 = 0x0C;
+const V2_HEADER: u8 = 0x02;
+
 #[derive(Abomonation, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ChangesetEntry {
     pub repo_id: RepositoryId,
@@ -45,6 +53,41 @@ impl ChangesetEntry {
     }
 }
 
+/// A changeset entry, with the generation numbers of its parents if known.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangesetEntryWithParentGens {
+    pub entry: ChangesetEntry,
+    /// Generation numbers of `entry.parents`, in the same order. `None` if
+    /// they are not known, e.g. because the entry was read from a version 1
+    /// blob.
+    pub parent_gens: Option<Vec<u64>>,
+}
+
+impl ChangesetEntryWithParentGens {
+    /// Pair up entries with their parents' generation numbers, taken from
+    /// the other entries. Entries with a parent that isn't in `cs_entries`
+    /// are given no parent generations.
+    pub fn from_entries(cs_entries: Vec<ChangesetEntry>) -> Vec<Self> {
+        let gens: HashMap<ChangesetId, u64> = cs_entries
+            .iter()
+            .map(|entry| (entry.cs_id, entry.gen))
+            .collect();
+        cs_entries
+            .into_iter()
+            .map(|entry| {
+                let parent_gens = entry
+                    .parents
+                    .iter()
+                    .map(|parent| gens.get(parent).copied())
+                    .collect();
+                Self { entry, parent_gens }
+            })
+            .collect()
+    }
+}
+
+/// Serialize changeset entries in the original (version 1) format, which is
+/// understood by all readers.
 pub fn serialize_cs_entries(cs_entries: Vec<ChangesetEntry>) -> Bytes {
     let mut thrift_entries = vec![];
     for entry in cs_entries {
@@ -60,7 +103,71 @@ pub fn serialize_cs_entries(cs_entries: Vec<ChangesetEntry>) -> Bytes {
     compact_protocol::serialize(&thrift_entries)
 }
 
+/// Serialize changeset entries in the version 2 format, which also carries
+/// the generation numbers of their parents. Readers that predate it can't
+/// read it.
+pub fn serialize_cs_entries_v2(cs_entries: Vec<ChangesetEntryWithParentGens>) -> Bytes {
+    let thrift_entries: Vec<_> = cs_entries
+        .into_iter()
+        .map(|entry| changeset_entry_thrift::ChangesetEntryV2 {
+            entry: entry.entry.into_thrift(),
+            parent_gens: entry.parent_gens.map(|gens| {
+                gens.into_iter()
+                    .map(|gen| changeset_entry_thrift::GenerationNum(gen as i64))
+                    .collect()
+            }),
+        })
+        .collect();
+
+    let serialized = compact_protocol::serialize(&thrift_entries);
+    let mut blob = BytesMut::with_capacity(serialized.len() + 1);
+    blob.put_u8(V2_HEADER);
+    blob.put(serialized);
+    blob.freeze()
+}
+
+/// Deserialize changeset entries written in any format.
 pub fn deserialize_cs_entries(blob: &Bytes) -> Result<Vec<ChangesetEntry>> {
+    Ok(deserialize_cs_entries_with_parent_gens(blob)?
+        .into_iter()
+        .map(|entry| entry.entry)
+        .collect())
+}
+
+/// Deserialize changeset entries written in any format, along with their
+/// parents' generation numbers if the format has them.
+pub fn deserialize_cs_entries_with_parent_gens(
+    blob: &Bytes,
+) -> Result<Vec<ChangesetEntryWithParentGens>> {
+    match blob.first() {
+        Some(&V2_HEADER) => {
+            let thrift_entries: Vec<changeset_entry_thrift::ChangesetEntryV2> =
+                compact_protocol::deserialize(blob.slice(1..))?;
+            thrift_entries
+                .into_iter()
+                .map(|thrift_entry| {
+                    Ok(ChangesetEntryWithParentGens {
+                        entry: ChangesetEntry::from_thrift(thrift_entry.entry)?,
+                        parent_gens: thrift_entry
+                            .parent_gens
+                            .map(|gens| gens.into_iter().map(|gen| gen.0 as u64).collect()),
+                    })
+                })
+                .collect()
+        }
+        Some(first) if first & 0x0F == V1_LIST_HEADER_TYPE => Ok(deserialize_cs_entries_v1(blob)?
+            .into_iter()
+            .map(|entry| ChangesetEntryWithParentGens {
+                entry,
+                parent_gens: None,
+            })
+            .collect()),
+        Some(first) => bail!("Unknown changeset entries format {:#04x}", first),
+        None => bail!("Empty changeset entries blob"),
+    }
+}
+
+fn deserialize_cs_entries_v1(blob: &Bytes) -> Result<Vec<ChangesetEntry>> {
     let thrift_entries: Vec<changeset_entry_thrift::ChangesetEntry> =
         compact_protocol::deserialize(blob)?;
     let mut entries = vec![];
@@ -98,4 +205,54 @@ mod tests {
             .unwrap();
         assert_eq!(vec![entry.clone(), entry], res);
     }
+
+    #[test]
+    fn serialize_deserialize_v2() {
+        let root = ChangesetEntry {
+            repo_id: RepositoryId::new(0),
+            cs_id: mononoke_types_mocks::changesetid::ONES_CSID,
+            parents: vec![],
+            gen: 1,
+        };
+        let child = ChangesetEntry {
+            repo_id: RepositoryId::new(0),
+            cs_id: mononoke_types_mocks::changesetid::TWOS_CSID,
+            parents: vec![
+                mononoke_types_mocks::changesetid::ONES_CSID,
+                mononoke_types_mocks::changesetid::THREES_CSID,
+            ],
+            gen: 2,
+        };
+        let entries = ChangesetEntryWithParentGens::from_entries(vec![root.clone(), child.clone()]);
+        assert_eq!(entries[0].parent_gens, Some(vec![]));
+        // The second parent isn't known.
+        assert_eq!(entries[1].parent_gens, None);
+
+        let with_gens = ChangesetEntryWithParentGens {
+            entry: child.clone(),
+            parent_gens: Some(vec![1, 1]),
+        };
+        let blob = serialize_cs_entries_v2(vec![entries[0].clone(), with_gens.clone()]);
+        assert_eq!(
+            deserialize_cs_entries_with_parent_gens(&blob).unwrap(),
+            vec![entries[0].clone(), with_gens]
+        );
+        assert_eq!(
+            deserialize_cs_entries(&blob).unwrap(),
+            vec![root.clone(), child]
+        );
+
+        // Version 1 blobs are still readable, without parent generations.
+        let blob = serialize_cs_entries(vec![root.clone()]);
+        assert_eq!(
+            deserialize_cs_entries_with_parent_gens(&blob).unwrap(),
+            vec![ChangesetEntryWithParentGens {
+                entry: root,
+                parent_gens: None,
+            }]
+        );
+        assert!(deserialize_cs_entries(&serialize_cs_entries(vec![]))
+            .unwrap()
+            .is_empty());
+    }
 }
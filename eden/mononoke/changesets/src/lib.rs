@@ -7,18 +7,40 @@
 
 #![deny(warnings)]
 
-use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Error, Result};
 use async_trait::async_trait;
 use auto_impl::auto_impl;
+use bonsai_hg_mapping::BonsaiHgMapping;
 use context::CoreContext;
-use futures::stream::BoxStream;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use mononoke_types::{
     ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix, RepositoryId,
 };
 
 mod entry;
+mod prefix;
+mod subscribe;
+
+pub use crate::entry::{
+    deserialize_cs_entries, deserialize_cs_entries_with_parent_gens, serialize_cs_entries,
+    serialize_cs_entries_v2, ChangesetEntry, ChangesetEntryWithParentGens,
+};
+pub use crate::prefix::{PrefixMatch, ResolvedPrefix};
+use crate::subscribe::SubscribeState;
 
-pub use crate::entry::{deserialize_cs_entries, serialize_cs_entries, ChangesetEntry};
+/// How often `subscribe` polls for new changesets when it has caught up.
+const SUBSCRIBE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of new changesets fetched by `subscribe` per poll.
+const SUBSCRIBE_BATCH_SIZE: u64 = 1000;
+/// Number of changesets fetched by each `get_many` call in `get_many_stream`,
+/// and how many of these calls are in flight at once.
+const GET_MANY_STREAM_CHUNK_SIZE: usize = 1000;
+const GET_MANY_STREAM_CONCURRENCY: usize = 10;
+/// How often `wait_for_sequence` checks whether replicas have caught up.
+const WAIT_FOR_SEQUENCE_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ChangesetInsert {
@@ -26,6 +48,12 @@ pub struct ChangesetInsert {
     pub parents: Vec<ChangesetId>,
 }
 
+/// The position of a changeset in the order changesets were inserted. This is
+/// the unique id that `list_enumeration_range` returns, and can be used as a
+/// logical clock to wait for replicas to catch up with an insert.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ChangesetsSequenceNumber(pub u64);
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SortOrder {
     Ascending,
@@ -56,6 +84,51 @@ pub trait Changesets: Send + Sync {
         Ok(self.get(ctx.clone(), cs_id).await?.is_some())
     }
 
+    /// Return whether each of the changesets is stored in the backend.
+    ///
+    /// A changeset is only stored after its parents, so the ancestors of a
+    /// changeset that exists exist too. The changesets are looked up a chunk
+    /// at a time with `get_many`, which also fills any caches with them, and
+    /// those that are ancestors of changesets found in earlier chunks are not
+    /// looked up at all. Descendants should come first for this to help.
+    async fn exists_many(
+        &self,
+        ctx: &CoreContext,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<HashMap<ChangesetId, bool>, Error> {
+        let mut exists: HashMap<_, _> = cs_ids.iter().map(|cs_id| (*cs_id, false)).collect();
+        let mut parents = HashMap::new();
+        let mut queried = HashSet::new();
+        for chunk in cs_ids.chunks(GET_MANY_STREAM_CHUNK_SIZE) {
+            let chunk: Vec<_> = chunk
+                .iter()
+                .filter(|cs_id| !exists[*cs_id] && queried.insert(**cs_id))
+                .copied()
+                .collect();
+            if chunk.is_empty() {
+                continue;
+            }
+            let entries = self.get_many(ctx.clone(), chunk).await?;
+            let mut found = Vec::with_capacity(entries.len());
+            for entry in entries {
+                found.push(entry.cs_id);
+                parents.insert(entry.cs_id, entry.parents);
+            }
+            // Mark the changesets found and their ancestors, as far as the
+            // parents fetched so far go.
+            while let Some(cs_id) = found.pop() {
+                if let Some(exists) = exists.get_mut(&cs_id) {
+                    if *exists {
+                        continue;
+                    }
+                    *exists = true;
+                }
+                found.extend(parents.get(&cs_id).into_iter().flatten().copied());
+            }
+        }
+        Ok(exists)
+    }
+
     /// Retrieve the rows for all the commits if available
     async fn get_many(
         &self,
@@ -63,6 +136,88 @@ pub trait Changesets: Send + Sync {
         cs_ids: Vec<ChangesetId>,
     ) -> Result<Vec<ChangesetEntry>, Error>;
 
+    /// Retrieve the rows for all the commits if available, in no particular order.
+    ///
+    /// Unlike `get_many`, the rows are fetched a chunk at a time and yielded as they
+    /// arrive, so fetching many commits doesn't need them all in memory at once.
+    fn get_many_stream(
+        &self,
+        ctx: &CoreContext,
+        cs_ids: Vec<ChangesetId>,
+    ) -> BoxStream<'_, Result<ChangesetEntry, Error>> {
+        let ctx = ctx.clone();
+        stream::iter(cs_ids)
+            .chunks(GET_MANY_STREAM_CHUNK_SIZE)
+            .map(move |chunk| self.get_many(ctx.clone(), chunk))
+            .buffered(GET_MANY_STREAM_CONCURRENCY)
+            .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Retrieve the rows for the commits and their ancestors up to `depth` parents
+    /// away, if available, in no particular order. A `depth` of 0 is the same as
+    /// `get_many`. Each commit is returned once, however many paths lead to it.
+    ///
+    /// By default this calls `get_many` once per level of ancestors. Stores that
+    /// can fetch a commit's parents along with it should do better.
+    async fn get_many_with_parents(
+        &self,
+        ctx: CoreContext,
+        cs_ids: Vec<ChangesetId>,
+        depth: usize,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let mut entries = HashMap::new();
+        let mut frontier: HashSet<_> = cs_ids.into_iter().collect();
+        for level in 0..=depth {
+            if frontier.is_empty() {
+                break;
+            }
+            let fetched = self
+                .get_many(ctx.clone(), frontier.into_iter().collect())
+                .await?;
+            frontier = HashSet::new();
+            for entry in fetched {
+                if level < depth {
+                    frontier.extend(entry.parents.iter().copied());
+                }
+                entries.insert(entry.cs_id, entry);
+            }
+            frontier.retain(|cs_id| !entries.contains_key(cs_id));
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    /// Retrieve the rows for commits with generation numbers between `min_gen` and `max_gen`
+    /// inclusive, in ascending order of generation number, up to the given limit
+    ///
+    /// Not every store can look commits up by generation number, so by default
+    /// this returns an error.
+    async fn get_many_with_generation_bounds(
+        &self,
+        _ctx: CoreContext,
+        _min_gen: u64,
+        _max_gen: u64,
+        _limit: u64,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        bail!(
+            "get_many_with_generation_bounds is not supported for repo {}",
+            self.repo_id()
+        )
+    }
+
+    /// Retrieve the ids of the commits that have the given commit as a parent
+    ///
+    /// Not every store indexes commits by parent, so by default this returns
+    /// an error.
+    async fn get_children(
+        &self,
+        _ctx: CoreContext,
+        _cs_id: ChangesetId,
+    ) -> Result<Vec<ChangesetId>, Error> {
+        bail!("get_children is not supported for repo {}", self.repo_id())
+    }
+
     /// Retrieve the rows for all the commits with the given prefix up to the given limit
     async fn get_many_by_prefix(
         &self,
@@ -71,6 +226,19 @@ pub trait Changesets: Send + Sync {
         limit: usize,
     ) -> Result<ChangesetIdsResolvedFromPrefix, Error>;
 
+    /// Retrieve the bonsai and hg changeset ids that start with a user-supplied hex
+    /// prefix, up to the given limit in total. Hg changeset ids are looked up in
+    /// `bonsai_hg_mapping`.
+    async fn get_many_by_hex_prefix(
+        &self,
+        ctx: &CoreContext,
+        bonsai_hg_mapping: &dyn BonsaiHgMapping,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<ResolvedPrefix, Error> {
+        prefix::resolve_hex_prefix(self, ctx, bonsai_hg_mapping, prefix, limit).await
+    }
+
     /// Prime any caches with known changeset entries.  The changeset entries
     /// must be for the repository associated with this `Changesets`.
     fn prime_cache(&self, ctx: &CoreContext, changesets: &[ChangesetEntry]);
@@ -110,4 +278,105 @@ pub trait Changesets: Send + Sync {
         sort_and_limit: Option<(SortOrder, u64)>,
         read_from_master: bool,
     ) -> BoxStream<'_, Result<(ChangesetId, u64), Error>>;
+
+    /// Retrieve the sequence number of a changeset, if it is stored. This reads
+    /// from the master, so it is known as soon as `add` returns.
+    ///
+    /// Not every store numbers its changesets, so by default this returns an
+    /// error.
+    async fn get_sequence(
+        &self,
+        _ctx: &CoreContext,
+        _cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        bail!("get_sequence is not supported for repo {}", self.repo_id())
+    }
+
+    /// Wait until the replicas that reads go to have caught up with the
+    /// insert of the changeset with sequence number `seq`. This waits
+    /// indefinitely, so callers should add a timeout.
+    async fn wait_for_sequence(
+        &self,
+        ctx: &CoreContext,
+        seq: ChangesetsSequenceNumber,
+    ) -> Result<(), Error> {
+        loop {
+            let bounds = self.enumeration_bounds(ctx, false).await?;
+            if bounds.map_or(false, |(_, max_id)| max_id >= seq.0) {
+                return Ok(());
+            }
+            tokio::time::sleep(WAIT_FOR_SEQUENCE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Tail changesets inserted after this call, in insertion order. The
+    /// stream never ends; new changesets are found by polling the enumeration
+    /// range. Ids below the last one seen are still returned if they are
+    /// committed later, unless that takes longer than `SUBSCRIBE_GAP_TIMEOUT`.
+    async fn subscribe(
+        &self,
+        ctx: &CoreContext,
+    ) -> Result<BoxStream<'_, Result<ChangesetEntry, Error>>, Error> {
+        let start = self
+            .enumeration_bounds(ctx, true)
+            .await?
+            .map_or(0, |(_, max_id)| max_id + 1);
+        let ctx = ctx.clone();
+        let entries = stream::try_unfold(SubscribeState::new(start), move |mut state| {
+            let ctx = ctx.clone();
+            async move {
+                loop {
+                    // Ids already seen are listed again, so fetch enough
+                    // to also find new ones.
+                    let limit = SUBSCRIBE_BATCH_SIZE + state.seen_count() as u64;
+                    let ids = self
+                        .list_enumeration_range(
+                            &ctx,
+                            state.low(),
+                            // The ids are signed in some backends.
+                            i64::MAX as u64,
+                            Some((SortOrder::Ascending, limit)),
+                            true,
+                        )
+                        .try_collect::<Vec<_>>()
+                        .await?;
+                    let ids: Vec<_> = ids
+                        .into_iter()
+                        .filter(|(_, id)| state.insert(*id))
+                        .collect();
+                    state.advance(Instant::now());
+                    if !ids.is_empty() {
+                        let mut entries: HashMap<_, _> = self
+                            .get_many(ctx.clone(), ids.iter().map(|(cs_id, _)| *cs_id).collect())
+                            .await?
+                            .into_iter()
+                            .map(|entry| (entry.cs_id, entry))
+                            .collect();
+                        let entries = ids
+                            .into_iter()
+                            .filter_map(|(cs_id, _)| entries.remove(&cs_id))
+                            .map(Ok::<_, Error>)
+                            .collect::<Vec<_>>();
+                        return Ok::<_, Error>(Some((stream::iter(entries), state)));
+                    }
+                    tokio::time::sleep(SUBSCRIBE_POLL_INTERVAL).await;
+                }
+            }
+        });
+        Ok(entries.try_flatten().boxed())
+    }
+}
+
+/// Changesets that should not be visible to readers, for example because
+/// they were redacted. Consulted by `VisibleChangesets`.
+#[facet::facet]
+#[async_trait]
+#[auto_impl(&, Arc)]
+pub trait HiddenChangesets: Send + Sync {
+    /// Return the subset of `cs_ids` that is hidden.
+    async fn hidden(
+        &self,
+        ctx: &CoreContext,
+        cs_ids: &[ChangesetId],
+    ) -> Result<HashSet<ChangesetId>, Error>;
 }
@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Resolution of user-supplied hex prefixes that may be of either a bonsai or
+//! an hg changeset id.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use bonsai_hg_mapping::BonsaiHgMapping;
+use context::CoreContext;
+use futures::future;
+use mercurial_types::{HgChangesetId, HgChangesetIdPrefix, HgChangesetIdsResolvedFromPrefix};
+use mononoke_types::{ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix};
+
+use crate::Changesets;
+
+/// A changeset id that matches a hex prefix.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum PrefixMatch {
+    Bonsai(ChangesetId),
+    Hg(HgChangesetId),
+}
+
+/// The result of resolving a hex prefix against both bonsai and hg changeset ids.
+/// Bonsai matches come first.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ResolvedPrefix {
+    /// Found no changesets
+    NoMatch,
+    /// Found a single changeset
+    Single(PrefixMatch),
+    /// Found several changesets within the limit provided
+    Multiple(Vec<PrefixMatch>),
+    /// Found too many changesets exceeding the limit provided
+    TooMany(Vec<PrefixMatch>),
+}
+
+impl ResolvedPrefix {
+    pub fn into_list(self) -> Vec<PrefixMatch> {
+        match self {
+            ResolvedPrefix::NoMatch => Vec::new(),
+            ResolvedPrefix::Single(m) => vec![m],
+            ResolvedPrefix::Multiple(ms) => ms,
+            ResolvedPrefix::TooMany(ms) => ms,
+        }
+    }
+}
+
+pub(crate) async fn resolve_hex_prefix<C: Changesets + ?Sized>(
+    changesets: &C,
+    ctx: &CoreContext,
+    bonsai_hg_mapping: &dyn BonsaiHgMapping,
+    prefix: &str,
+    limit: usize,
+) -> Result<ResolvedPrefix, Error> {
+    // Hg changeset ids are shorter, so a long prefix may only parse as bonsai.
+    let bonsai_prefix = ChangesetIdPrefix::from_str(prefix).ok();
+    let hg_prefix = HgChangesetIdPrefix::from_str(prefix).ok();
+    if bonsai_prefix.is_none() && hg_prefix.is_none() {
+        bail!("invalid hex prefix: {:?}", prefix);
+    }
+
+    let bonsai = async {
+        match bonsai_prefix {
+            Some(bonsai_prefix) => changesets
+                .get_many_by_prefix(ctx.clone(), bonsai_prefix, limit)
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    };
+    let hg = async {
+        match hg_prefix {
+            Some(hg_prefix) => bonsai_hg_mapping
+                .get_many_hg_by_prefix(ctx, changesets.repo_id(), hg_prefix, limit)
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    };
+    let (bonsai, hg) = future::try_join(bonsai, hg).await?;
+
+    let mut matches = Vec::new();
+    let mut too_many = false;
+    match bonsai {
+        Some(ChangesetIdsResolvedFromPrefix::Single(cs_id)) => {
+            matches.push(PrefixMatch::Bonsai(cs_id));
+        }
+        Some(ChangesetIdsResolvedFromPrefix::Multiple(cs_ids)) => {
+            matches.extend(cs_ids.into_iter().map(PrefixMatch::Bonsai));
+        }
+        Some(ChangesetIdsResolvedFromPrefix::TooMany(cs_ids)) => {
+            too_many = true;
+            matches.extend(cs_ids.into_iter().map(PrefixMatch::Bonsai));
+        }
+        Some(ChangesetIdsResolvedFromPrefix::NoMatch) | None => {}
+    }
+    match hg {
+        Some(HgChangesetIdsResolvedFromPrefix::Single(hg_cs_id)) => {
+            matches.push(PrefixMatch::Hg(hg_cs_id));
+        }
+        Some(HgChangesetIdsResolvedFromPrefix::Multiple(hg_cs_ids)) => {
+            matches.extend(hg_cs_ids.into_iter().map(PrefixMatch::Hg));
+        }
+        Some(HgChangesetIdsResolvedFromPrefix::TooMany(hg_cs_ids)) => {
+            too_many = true;
+            matches.extend(hg_cs_ids.into_iter().map(PrefixMatch::Hg));
+        }
+        Some(HgChangesetIdsResolvedFromPrefix::NoMatch) | None => {}
+    }
+
+    let res = if too_many || matches.len() > limit {
+        matches.truncate(limit);
+        ResolvedPrefix::TooMany(matches)
+    } else {
+        match matches.len() {
+            0 => ResolvedPrefix::NoMatch,
+            1 => ResolvedPrefix::Single(matches.remove(0)),
+            _ => ResolvedPrefix::Multiple(matches),
+        }
+    };
+    Ok(res)
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// How long `subscribe` waits for an id below ids it has already seen. Ids
+/// are allocated in insertion order, but the transactions inserting them can
+/// commit out of order, or not at all.
+pub(crate) const SUBSCRIBE_GAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The ids `subscribe` has returned. All ids below `low` were returned or
+/// given up on; `seen` has the ids returned at or above `low`.
+pub(crate) struct SubscribeState {
+    low: u64,
+    seen: BTreeSet<u64>,
+    // When the gap at `low` was found.
+    gap_since: Option<Instant>,
+}
+
+impl SubscribeState {
+    pub(crate) fn new(low: u64) -> Self {
+        Self {
+            low,
+            seen: BTreeSet::new(),
+            gap_since: None,
+        }
+    }
+
+    /// The lowest id that might not have been returned yet.
+    pub(crate) fn low(&self) -> u64 {
+        self.low
+    }
+
+    /// Number of ids at or above `low` that were already returned.
+    pub(crate) fn seen_count(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Record that `id` is committed. Return false if it was already seen.
+    pub(crate) fn insert(&mut self, id: u64) -> bool {
+        id >= self.low && self.seen.insert(id)
+    }
+
+    /// Move `low` past the ids returned, and past gaps that have not been
+    /// filled for `SUBSCRIBE_GAP_TIMEOUT`.
+    pub(crate) fn advance(&mut self, now: Instant) {
+        loop {
+            while self.seen.remove(&self.low) {
+                self.low += 1;
+                self.gap_since = None;
+            }
+            let next = match self.seen.iter().next() {
+                Some(&next) => next,
+                None => return,
+            };
+            let since = *self.gap_since.get_or_insert(now);
+            if now.duration_since(since) < SUBSCRIBE_GAP_TIMEOUT {
+                return;
+            }
+            self.low = next;
+            self.gap_since = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_ids() {
+        let now = Instant::now();
+        let mut state = SubscribeState::new(10);
+        assert!(!state.insert(9));
+        assert!(state.insert(10));
+        assert!(state.insert(12));
+        assert!(!state.insert(12));
+        state.advance(now);
+        assert_eq!((state.low(), state.seen_count()), (11, 1));
+
+        // 11 is committed after 12.
+        assert!(state.insert(11));
+        state.advance(now);
+        assert_eq!((state.low(), state.seen_count()), (13, 0));
+
+        // 13 never commits. It is given up on after a while.
+        assert!(state.insert(14));
+        state.advance(now);
+        assert_eq!(state.low(), 13);
+        state.advance(now + SUBSCRIBE_GAP_TIMEOUT / 2);
+        assert_eq!(state.low(), 13);
+        state.advance(now + SUBSCRIBE_GAP_TIMEOUT);
+        assert_eq!((state.low(), state.seen_count()), (15, 0));
+        assert!(!state.insert(13));
+    }
+}
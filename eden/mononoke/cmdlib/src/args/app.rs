@@ -49,6 +49,11 @@ pub const MYSQL_SQLBLOB_POOL_IDLE_TIMEOUT: &str = "mysql-sqblob-pool-idle-timeou
 pub const RUNTIME_THREADS: &str = "runtime-threads";
 pub const TUNABLES_CONFIG: &str = "tunables-config";
 pub const DISABLE_TUNABLES: &str = "disable-tunables";
+pub const TUNABLES_REFRESH_INTERVAL_SECS: &str = "tunables-refresh-interval-secs";
+pub const TUNABLES_TIER: &str = "tunables-tier";
+pub const TUNABLES_FALLBACK_PATH: &str = "tunables-fallback-path";
+pub const TUNABLES_FALLBACK_MAX_AGE_SECS: &str = "tunables-fallback-max-age-secs";
+pub const TUNABLE_OVERRIDE: &str = "tunable";
 pub const SCRIBE_LOGGING_DIRECTORY: &str = "scribe-logging-directory";
 pub const RENDEZVOUS_FREE_CONNECTIONS: &str = "rendezvous-free-connections";
 
@@ -843,6 +848,46 @@ fn add_tunables_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
             .long(DISABLE_TUNABLES)
             .help("Use the default values for all tunables (useful for tests)"),
     )
+    .arg(
+        Arg::with_name(TUNABLES_REFRESH_INTERVAL_SECS)
+            .long(TUNABLES_REFRESH_INTERVAL_SECS)
+            .takes_value(true)
+            .help("How often to check the tunables config for changes, in seconds"),
+    )
+    .arg(
+        Arg::with_name(TUNABLES_TIER)
+            .long(TUNABLES_TIER)
+            .takes_value(true)
+            .help("The tier this process runs on, for tier-specific tunables overrides"),
+    )
+    .arg(
+        Arg::with_name(TUNABLES_FALLBACK_PATH)
+            .long(TUNABLES_FALLBACK_PATH)
+            .takes_value(true)
+            .help(
+                "A local file to save the tunables config to, and to load it from at startup \
+                 in case the config can't be fetched",
+            ),
+    )
+    .arg(
+        Arg::with_name(TUNABLES_FALLBACK_MAX_AGE_SECS)
+            .long(TUNABLES_FALLBACK_MAX_AGE_SECS)
+            .takes_value(true)
+            .requires(TUNABLES_FALLBACK_PATH)
+            .help("How old the tunables fallback file can be to be used, in seconds"),
+    )
+    .arg(
+        Arg::with_name(TUNABLE_OVERRIDE)
+            .long(TUNABLE_OVERRIDE)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME=VALUE")
+            .help(
+                "Pin a tunable to a value, whatever the tunables config says. \
+                 Meant for mitigations on hosts that can't get config updates",
+            ),
+    )
 }
 fn add_runtime_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
     app.arg(
@@ -23,7 +23,7 @@ use maybe_owned::MaybeOwned;
 use megarepo_config::MononokeMegarepoConfigsOptions;
 use panichandler::{self, Fate};
 use rendezvous::RendezVousOptions;
-use slog::{debug, o, Level, Logger, Never, SendSyncRefUnwindSafeDrain};
+use slog::{debug, o, warn, Level, Logger, Never, SendSyncRefUnwindSafeDrain};
 use slog_glog_fmt::{kv_categorizer::FacebookCategorizer, kv_defaults::FacebookKV, GlogFormat};
 use slog_term::TermDecorator;
 use std::panic::{RefUnwindSafe, UnwindSafe};
@@ -40,7 +40,10 @@ use repo_factory::ReadOnlyStorage;
 use scuba_ext::MononokeScubaSampleBuilder;
 use slog_ext::make_tag_filter_drain;
 use sql_ext::facebook::{MysqlOptions, PoolConfig, ReadConnectionType};
-use tunables::init_tunables_worker;
+use tunables::{
+    apply_cli_overrides, init_tunables_worker, parse_tunable_arg, pinned_tunables,
+    TunablesFallback, TunablesTarget, DEFAULT_FALLBACK_MAX_AGE, DEFAULT_REFRESH_INTERVAL,
+};
 
 pub type Normal = rand_distr::Normal<f64>;
 use crate::helpers::create_runtime;
@@ -63,9 +66,11 @@ use super::{
         NO_DEFAULT_SCUBA_DATASET_ARG, PUT_MEAN_DELAY_SECS_ARG, PUT_STDDEV_DELAY_SECS_ARG,
         READ_BURST_BYTES_ARG, READ_BYTES_ARG, READ_CHAOS_ARG, READ_QPS_ARG,
         RENDEZVOUS_FREE_CONNECTIONS, RUNTIME_THREADS, SCUBA_DATASET_ARG, SCUBA_LOG_FILE_ARG,
-        TUNABLES_CONFIG, WITH_DYNAMIC_OBSERVABILITY, WITH_READONLY_STORAGE_ARG,
-        WITH_TEST_MEGAREPO_CONFIGS_CLIENT, WRITE_BURST_BYTES_ARG, WRITE_BYTES_ARG, WRITE_CHAOS_ARG,
-        WRITE_QPS_ARG, WRITE_ZSTD_ARG, WRITE_ZSTD_LEVEL_ARG,
+        TUNABLES_CONFIG, TUNABLES_FALLBACK_MAX_AGE_SECS, TUNABLES_FALLBACK_PATH,
+        TUNABLES_REFRESH_INTERVAL_SECS, TUNABLES_TIER, TUNABLE_OVERRIDE,
+        WITH_DYNAMIC_OBSERVABILITY, WITH_READONLY_STORAGE_ARG, WITH_TEST_MEGAREPO_CONFIGS_CLIENT,
+        WRITE_BURST_BYTES_ARG, WRITE_BYTES_ARG, WRITE_CHAOS_ARG, WRITE_QPS_ARG, WRITE_ZSTD_ARG,
+        WRITE_ZSTD_LEVEL_ARG,
     },
     cache::parse_and_init_cachelib,
 };
@@ -117,7 +122,7 @@ impl<'a> MononokeMatches<'a> {
 
         let runtime = init_runtime(&matches).context("Failed to create Tokio runtime")?;
 
-        init_tunables(&matches, &config_store, logger.clone())
+        init_tunables(&matches, &config_store, logger.clone(), runtime.handle())
             .context("Failed to initialize tunables")?;
 
         let mysql_options =
@@ -772,7 +777,25 @@ fn init_tunables<'a>(
     matches: &'a ArgMatches<'a>,
     config_store: &'a ConfigStore,
     logger: Logger,
+    runtime: &Handle,
 ) -> Result<()> {
+    // Pinned tunables apply even if tunables are disabled, and are applied
+    // before the config so they are in effect as soon as it is.
+    let pinned = matches
+        .values_of(TUNABLE_OVERRIDE)
+        .into_iter()
+        .flatten()
+        .map(parse_tunable_arg)
+        .collect::<Result<Vec<_>>>()?;
+    if !pinned.is_empty() {
+        apply_cli_overrides(&pinned).with_context(|| format!("Invalid --{}", TUNABLE_OVERRIDE))?;
+        warn!(
+            logger,
+            "Tunables pinned from the command line: {:?}",
+            pinned_tunables()
+        );
+    }
+
     if matches.is_present(DISABLE_TUNABLES) {
         debug!(logger, "Tunables are disabled");
         return Ok(());
@@ -785,7 +808,45 @@ fn init_tunables<'a>(
     let config_handle =
         config_store.get_config_handle(parse_config_spec_to_path(tunables_spec)?)?;
 
-    init_tunables_worker(logger, config_handle)
+    let refresh_interval = matches
+        .value_of(TUNABLES_REFRESH_INTERVAL_SECS)
+        .map(|v| v.parse().map(Duration::from_secs))
+        .transpose()
+        .with_context(|| {
+            format!(
+                "Provided {} is not an integer",
+                TUNABLES_REFRESH_INTERVAL_SECS
+            )
+        })?
+        .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+
+    let target = TunablesTarget::local(matches.value_of(TUNABLES_TIER).map(String::from));
+
+    let fallback_max_age = matches
+        .value_of(TUNABLES_FALLBACK_MAX_AGE_SECS)
+        .map(|v| v.parse().map(Duration::from_secs))
+        .transpose()
+        .with_context(|| {
+            format!(
+                "Provided {} is not an integer",
+                TUNABLES_FALLBACK_MAX_AGE_SECS
+            )
+        })?
+        .unwrap_or(DEFAULT_FALLBACK_MAX_AGE);
+    let fallback = matches
+        .value_of(TUNABLES_FALLBACK_PATH)
+        .map(|path| TunablesFallback::new(path, fallback_max_age));
+
+    // The worker keeps running for the lifetime of the runtime.
+    init_tunables_worker(
+        logger,
+        config_handle,
+        refresh_interval,
+        target,
+        fallback,
+        runtime,
+    )?;
+    Ok(())
 }
 
 /// Initialize a new `Runtime` with thread number parsed from the CLI
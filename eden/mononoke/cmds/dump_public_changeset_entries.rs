@@ -8,7 +8,7 @@
 use anyhow::{anyhow, Error};
 use blobrepo::BlobRepo;
 use bulkops::{Direction, PublicChangesetBulkFetch};
-use changesets::serialize_cs_entries;
+use changesets::{serialize_cs_entries, serialize_cs_entries_v2, ChangesetEntryWithParentGens};
 use clap::Arg;
 use cmdlib::args::{self, RepoRequirement};
 use context::CoreContext;
@@ -16,6 +16,7 @@ use fbinit::FacebookInit;
 use futures::TryStreamExt;
 
 const ARG_OUT_FILENAME: &str = "out-filename";
+const ARG_WITH_PARENT_GENS: &str = "with-parent-gens";
 
 #[fbinit::main]
 fn main(fb: FacebookInit) -> Result<(), Error> {
@@ -34,6 +35,14 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 .takes_value(true)
                 .required(true)
                 .help("file name where commits will be saved"),
+        )
+        .arg(
+            Arg::with_name(ARG_WITH_PARENT_GENS)
+                .long(ARG_WITH_PARENT_GENS)
+                .help(
+                    "also save generation numbers of parents. Older readers can't read \
+                    files written with this option",
+                ),
         );
     let matches = app.get_matches(fb)?;
     let runtime = matches.runtime();
@@ -44,6 +53,7 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
         .value_of(ARG_OUT_FILENAME)
         .ok_or_else(|| anyhow!("missing required argument: {}", ARG_OUT_FILENAME))?
         .to_string();
+    let with_parent_gens = matches.is_present(ARG_WITH_PARENT_GENS);
     let blob_repo_fut = args::open_repo(fb, &logger, &matches);
 
     runtime.block_on(async move {
@@ -57,7 +67,13 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
             .try_collect()
             .await?;
 
-        let serialized = serialize_cs_entries(css);
+        // Public changesets only have public parents, which are fetched
+        // before their children, so all parent generations are known.
+        let serialized = if with_parent_gens {
+            serialize_cs_entries_v2(ChangesetEntryWithParentGens::from_entries(css))
+        } else {
+            serialize_cs_entries(css)
+        };
         tokio::fs::write(out_filename, serialized).await?;
 
         Ok(())
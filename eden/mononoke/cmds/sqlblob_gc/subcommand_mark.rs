@@ -134,6 +134,12 @@ pub async fn subcommand_mark<'a>(
 
     // Foreach shard in shard_range
     for shard in shard_range {
+        // Expired keys are deleted first, so that the sweep does not keep their chunks alive.
+        let expired = sqlblob.delete_expired(shard).await?;
+        info!(
+            logger,
+            "Deleted {} expired keys from shard {}", expired, shard
+        );
         info!(logger, "Starting sweep on data keys from shard {}", shard);
         let res = sqlblob
             .get_keys_from_shard(shard)
@@ -0,0 +1,22 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+CREATE TABLE IF NOT EXISTS backsync_outcomes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  source_repo_id INT UNSIGNED NOT NULL,
+  target_repo_id INT UNSIGNED NOT NULL,
+  entry_id BIGINT NOT NULL,
+  bookmark VARCHAR(512) NOT NULL,
+  to_changeset_id BINARY(32),
+  outcome VARCHAR(32) NOT NULL,
+  rewritten_commits BIGINT NOT NULL,
+  message TEXT,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS backsync_outcomes_repos_entry
+  ON backsync_outcomes (source_repo_id, target_repo_id, entry_id);
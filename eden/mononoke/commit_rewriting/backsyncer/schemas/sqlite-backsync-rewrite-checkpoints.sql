@@ -0,0 +1,15 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+CREATE TABLE IF NOT EXISTS backsync_rewrite_checkpoints (
+  source_repo_id INT UNSIGNED NOT NULL,
+  target_repo_id INT UNSIGNED NOT NULL,
+  source_cs_id BINARY(32) NOT NULL,
+  version_name VARCHAR(255) NOT NULL,
+  content_id BINARY(32) NOT NULL,
+  PRIMARY KEY (source_repo_id, target_repo_id, source_cs_id, version_name, content_id)
+);
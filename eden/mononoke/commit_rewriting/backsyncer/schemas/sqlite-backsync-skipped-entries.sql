@@ -0,0 +1,17 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+CREATE TABLE IF NOT EXISTS backsync_skipped_entries (
+  source_repo_id INT UNSIGNED NOT NULL,
+  target_repo_id INT UNSIGNED NOT NULL,
+  entry_id BIGINT NOT NULL,
+  bookmark VARCHAR(512) NOT NULL,
+  to_changeset_id BINARY(32),
+  conflict VARCHAR(32) NOT NULL,
+  reason TEXT NOT NULL,
+  PRIMARY KEY (source_repo_id, target_repo_id, entry_id)
+);
@@ -0,0 +1,196 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checkpoints of uploads of rewritten merge commits, kept in the target repo dbs. See
+//! `cross_repo_sync::RewriteCheckpoints`.
+
+use anyhow::Error;
+use async_trait::async_trait;
+use context::{CoreContext, PerfCounterType};
+use cross_repo_sync::{RewriteCheckpointKey, RewriteCheckpoints};
+use metaconfig_types::CommitSyncConfigVersion;
+use mononoke_types::{ChangesetId, ContentId, RepositoryId};
+use sql::{queries, Connection};
+use sql_construct::{SqlConstruct, SqlConstructFromMetadataDatabaseConfig};
+use sql_ext::SqlConnections;
+use std::collections::HashSet;
+
+/// How many contents are recorded per insert.
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// How many contents are forgotten per delete, so that clearing the checkpoint of a huge
+/// merge doesn't hold locks on all of its rows at once.
+const DELETE_CHUNK_SIZE: u64 = 1000;
+
+queries! {
+    write AddCopiedContents(values: (
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        source_cs_id: ChangesetId,
+        version_name: CommitSyncConfigVersion,
+        content_id: &[u8],
+    )) {
+        insert_or_ignore,
+        "{insert_or_ignore} INTO backsync_rewrite_checkpoints
+         (source_repo_id, target_repo_id, source_cs_id, version_name, content_id)
+         VALUES {values}"
+    }
+
+    read ListCopiedContents(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        source_cs_id: ChangesetId,
+        version_name: CommitSyncConfigVersion,
+    ) -> (Vec<u8>,) {
+        "SELECT content_id
+         FROM backsync_rewrite_checkpoints
+         WHERE source_repo_id = {source_repo_id}
+           AND target_repo_id = {target_repo_id}
+           AND source_cs_id = {source_cs_id}
+           AND version_name = {version_name}"
+    }
+
+    read ListCopiedContentsPage(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        source_cs_id: ChangesetId,
+        version_name: CommitSyncConfigVersion,
+        limit: u64,
+    ) -> (Vec<u8>,) {
+        "SELECT content_id
+         FROM backsync_rewrite_checkpoints
+         WHERE source_repo_id = {source_repo_id}
+           AND target_repo_id = {target_repo_id}
+           AND source_cs_id = {source_cs_id}
+           AND version_name = {version_name}
+         LIMIT {limit}"
+    }
+
+    write DeleteCopiedContents(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        source_cs_id: ChangesetId,
+        version_name: CommitSyncConfigVersion,
+        >list content_ids: Vec<u8>
+    ) {
+        none,
+        "DELETE FROM backsync_rewrite_checkpoints
+         WHERE source_repo_id = {source_repo_id}
+           AND target_repo_id = {target_repo_id}
+           AND source_cs_id = {source_cs_id}
+           AND version_name = {version_name}
+           AND content_id IN {content_ids}"
+    }
+}
+
+/// Contents already copied to the target repo for rewritten merge commits that haven't
+/// been synced yet, keyed by the source commit and the sync config version.
+#[derive(Clone)]
+pub struct SqlRewriteCheckpoints {
+    write_connection: Connection,
+    read_master_connection: Connection,
+}
+
+impl SqlConstruct for SqlRewriteCheckpoints {
+    const LABEL: &'static str = "backsync_rewrite_checkpoints";
+
+    const CREATION_QUERY: &'static str =
+        include_str!("../schemas/sqlite-backsync-rewrite-checkpoints.sql");
+
+    fn from_sql_connections(connections: SqlConnections) -> Self {
+        Self {
+            write_connection: connections.write_connection,
+            read_master_connection: connections.read_master_connection,
+        }
+    }
+}
+
+impl SqlConstructFromMetadataDatabaseConfig for SqlRewriteCheckpoints {}
+
+#[async_trait]
+impl RewriteCheckpoints for SqlRewriteCheckpoints {
+    async fn load(
+        &self,
+        ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+    ) -> Result<HashSet<ContentId>, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = ListCopiedContents::query(
+            &self.read_master_connection,
+            &key.source_repo_id,
+            &key.target_repo_id,
+            &key.source_cs_id,
+            &key.version,
+        )
+        .await?;
+        rows.into_iter()
+            .map(|(content_id,)| ContentId::from_bytes(content_id))
+            .collect()
+    }
+
+    async fn save(
+        &self,
+        ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+        content_ids: &[ContentId],
+    ) -> Result<(), Error> {
+        for chunk in content_ids.chunks(INSERT_CHUNK_SIZE) {
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlWrites);
+            let content_ids: Vec<&[u8]> = chunk.iter().map(|id| id.as_ref()).collect();
+            let rows: Vec<_> = content_ids
+                .iter()
+                .map(|content_id| {
+                    (
+                        &key.source_repo_id,
+                        &key.target_repo_id,
+                        &key.source_cs_id,
+                        &key.version,
+                        content_id,
+                    )
+                })
+                .collect();
+            AddCopiedContents::query(&self.write_connection, &rows[..]).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, ctx: &CoreContext, key: &RewriteCheckpointKey) -> Result<(), Error> {
+        loop {
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlReadsMaster);
+            let rows = ListCopiedContentsPage::query(
+                &self.write_connection,
+                &key.source_repo_id,
+                &key.target_repo_id,
+                &key.source_cs_id,
+                &key.version,
+                &DELETE_CHUNK_SIZE,
+            )
+            .await?;
+            if rows.is_empty() {
+                return Ok(());
+            }
+            let content_ids: Vec<_> = rows.into_iter().map(|(content_id,)| content_id).collect();
+            ctx.perf_counters()
+                .increment_counter(PerfCounterType::SqlWrites);
+            DeleteCopiedContents::query(
+                &self.write_connection,
+                &key.source_repo_id,
+                &key.target_repo_id,
+                &key.source_cs_id,
+                &key.version,
+                &content_ids[..],
+            )
+            .await?;
+            if (content_ids.len() as u64) < DELETE_CHUNK_SIZE {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! What backsync does when a log entry can't be applied to the target repo as is: either the
+//! commits it points to fail to rewrite, or the target repo bookmark isn't where the entry
+//! expects it to be, e.g. because it was moved by something other than the backsyncer.
+
+use anyhow::Error;
+use async_trait::async_trait;
+use bookmarks::{BookmarkName, BookmarkUpdateLogEntry};
+use context::{CoreContext, PerfCounterType};
+use cross_repo_sync::CommitSyncer;
+use mononoke_types::{ChangesetId, RepositoryId};
+use sql::{queries, Connection, Transaction};
+use sql_construct::{SqlConstruct, SqlConstructFromMetadataDatabaseConfig};
+use sql_ext::SqlConnections;
+use synced_commit_mapping::SyncedCommitMapping;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Rewriting the commits the entry moves its bookmark to failed.
+    Rewrite,
+    /// The bookmark couldn't be moved because it isn't where the entry expects it to be.
+    BookmarkMove,
+}
+
+impl ConflictKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConflictKind::Rewrite => "rewrite",
+            ConflictKind::BookmarkMove => "bookmark_move",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BacksyncConflict {
+    pub source_repo_id: RepositoryId,
+    pub target_repo_id: RepositoryId,
+    pub entry_id: i64,
+    pub bookmark: BookmarkName,
+    pub to_changeset_id: Option<ChangesetId>,
+    pub kind: ConflictKind,
+    pub reason: String,
+}
+
+impl BacksyncConflict {
+    pub(crate) fn new<M>(
+        commit_syncer: &CommitSyncer<M>,
+        entry: &BookmarkUpdateLogEntry,
+        kind: ConflictKind,
+        error: &Error,
+    ) -> Self
+    where
+        M: SyncedCommitMapping + Clone + 'static,
+    {
+        Self {
+            source_repo_id: commit_syncer.get_source_repo().get_repoid(),
+            target_repo_id: commit_syncer.get_target_repo().get_repoid(),
+            entry_id: entry.id,
+            bookmark: entry.bookmark_name.clone(),
+            to_changeset_id: entry.to_changeset_id,
+            kind,
+            reason: format!("{:#}", error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Stop backsyncing and return the error.
+    Fail,
+    /// Move the counter past the entry without applying it.
+    Skip,
+    /// Force the target repo bookmark to where the source repo bookmark points. Only
+    /// applies to `ConflictKind::BookmarkMove`; other conflicts fail.
+    PreferSource,
+}
+
+#[async_trait]
+pub trait ConflictPolicy: Send + Sync {
+    async fn resolve(
+        &self,
+        ctx: &CoreContext,
+        conflict: &BacksyncConflict,
+    ) -> Result<ConflictResolution, Error>;
+
+    /// Called with the transaction that moves the counter past an entry skipped because of
+    /// `conflict`. Whatever is written to `txn` is committed together with the counter.
+    async fn on_skip(
+        &self,
+        _ctx: &CoreContext,
+        _conflict: &BacksyncConflict,
+        txn: Transaction,
+    ) -> Result<Transaction, Error> {
+        Ok(txn)
+    }
+}
+
+/// Fail on any conflict. This is the default.
+pub struct FailOnConflict;
+
+#[async_trait]
+impl ConflictPolicy for FailOnConflict {
+    async fn resolve(
+        &self,
+        _ctx: &CoreContext,
+        _conflict: &BacksyncConflict,
+    ) -> Result<ConflictResolution, Error> {
+        Ok(ConflictResolution::Fail)
+    }
+}
+
+/// Skip entries that conflict, and record them so they can be reconciled later. Skips are
+/// recorded in the transaction that moves the counter, so `skipped` must be in the same db
+/// as the counters of the target repo.
+pub struct SkipAndRecordConflicts {
+    skipped: SqlSkippedBacksyncEntries,
+}
+
+impl SkipAndRecordConflicts {
+    pub fn new(skipped: SqlSkippedBacksyncEntries) -> Self {
+        Self { skipped }
+    }
+}
+
+#[async_trait]
+impl ConflictPolicy for SkipAndRecordConflicts {
+    async fn resolve(
+        &self,
+        _ctx: &CoreContext,
+        _conflict: &BacksyncConflict,
+    ) -> Result<ConflictResolution, Error> {
+        Ok(ConflictResolution::Skip)
+    }
+
+    async fn on_skip(
+        &self,
+        ctx: &CoreContext,
+        conflict: &BacksyncConflict,
+        txn: Transaction,
+    ) -> Result<Transaction, Error> {
+        self.skipped.add_on_txn(ctx, conflict, txn).await
+    }
+}
+
+/// Force bookmarks to the position they have in the source repo. Rewrite conflicts fail.
+pub struct PreferSourceOnConflict;
+
+#[async_trait]
+impl ConflictPolicy for PreferSourceOnConflict {
+    async fn resolve(
+        &self,
+        _ctx: &CoreContext,
+        conflict: &BacksyncConflict,
+    ) -> Result<ConflictResolution, Error> {
+        match conflict.kind {
+            ConflictKind::BookmarkMove => Ok(ConflictResolution::PreferSource),
+            ConflictKind::Rewrite => Ok(ConflictResolution::Fail),
+        }
+    }
+}
+
+/// A log entry that was skipped because of a conflict.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedBacksyncEntry {
+    pub entry_id: i64,
+    pub bookmark: BookmarkName,
+    pub to_changeset_id: Option<ChangesetId>,
+    pub conflict: String,
+    pub reason: String,
+}
+
+queries! {
+    write AddSkippedEntry(values: (
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        entry_id: i64,
+        bookmark: BookmarkName,
+        to_changeset_id: Option<ChangesetId>,
+        conflict: &str,
+        reason: &str,
+    )) {
+        none,
+        "REPLACE INTO backsync_skipped_entries
+         (source_repo_id, target_repo_id, entry_id, bookmark, to_changeset_id, conflict, reason)
+         VALUES {values}"
+    }
+
+    read ListSkippedEntries(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+    ) -> (i64, BookmarkName, Option<ChangesetId>, String, String) {
+        "SELECT entry_id, bookmark, to_changeset_id, conflict, reason
+         FROM backsync_skipped_entries
+         WHERE source_repo_id = {source_repo_id} AND target_repo_id = {target_repo_id}
+         ORDER BY entry_id"
+    }
+}
+
+/// Side table of log entries that were skipped because of conflicts.
+#[derive(Clone)]
+pub struct SqlSkippedBacksyncEntries {
+    write_connection: Connection,
+    read_master_connection: Connection,
+}
+
+impl SqlConstruct for SqlSkippedBacksyncEntries {
+    const LABEL: &'static str = "backsync_skipped_entries";
+
+    const CREATION_QUERY: &'static str =
+        include_str!("../schemas/sqlite-backsync-skipped-entries.sql");
+
+    fn from_sql_connections(connections: SqlConnections) -> Self {
+        Self {
+            write_connection: connections.write_connection,
+            read_master_connection: connections.read_master_connection,
+        }
+    }
+}
+
+impl SqlConstructFromMetadataDatabaseConfig for SqlSkippedBacksyncEntries {}
+
+impl SqlSkippedBacksyncEntries {
+    pub async fn add(&self, ctx: &CoreContext, conflict: &BacksyncConflict) -> Result<(), Error> {
+        let txn = self.write_connection.start_transaction().await?;
+        self.add_on_txn(ctx, conflict, txn).await?.commit().await?;
+        Ok(())
+    }
+
+    /// Like `add`, in an existing transaction, e.g. the one that moves the counter past the
+    /// skipped entry.
+    pub async fn add_on_txn(
+        &self,
+        ctx: &CoreContext,
+        conflict: &BacksyncConflict,
+        txn: Transaction,
+    ) -> Result<Transaction, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlWrites);
+        let (txn, _) = AddSkippedEntry::query_with_transaction(
+            txn,
+            &[(
+                &conflict.source_repo_id,
+                &conflict.target_repo_id,
+                &conflict.entry_id,
+                &conflict.bookmark,
+                &conflict.to_changeset_id,
+                &conflict.kind.as_str(),
+                &conflict.reason.as_str(),
+            )],
+        )
+        .await?;
+        Ok(txn)
+    }
+
+    /// List the entries skipped while backsyncing from `source_repo_id` into
+    /// `target_repo_id`, oldest first.
+    pub async fn list(
+        &self,
+        ctx: &CoreContext,
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+    ) -> Result<Vec<SkippedBacksyncEntry>, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = ListSkippedEntries::query(
+            &self.read_master_connection,
+            &source_repo_id,
+            &target_repo_id,
+        )
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(entry_id, bookmark, to_changeset_id, conflict, reason)| SkippedBacksyncEntry {
+                    entry_id,
+                    bookmark,
+                    to_changeset_id,
+                    conflict,
+                    reason,
+                },
+            )
+            .collect())
+    }
+}
@@ -0,0 +1,487 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Forward sync replays the log of one small repo bookmark onto the large repo, so that simple
+//! mirrors don't need the full x-repo sync service. It only handles history without merges,
+//! where every commit has a single parent that was synced before it. The large repo bookmark
+//! is expected to only be moved by forward sync, so if it isn't where the synced commits
+//! expect it to be, the entry is a `ConflictKind::BookmarkMove` conflict.
+
+use std::sync::Arc;
+
+use anyhow::{bail, format_err, Error};
+use bookmarks::{BookmarkName, BookmarkUpdateLogEntry, Freshness};
+use context::CoreContext;
+use cross_repo_sync::{
+    find_toposorted_unsynced_ancestors, CandidateSelectionHint, CommitSyncContext,
+    CommitSyncOutcome, CommitSyncer,
+};
+use futures::{compat::Future01CompatExt, TryStreamExt};
+use mononoke_types::{ChangesetId, RepositoryId};
+use slog::{debug, warn};
+use synced_commit_mapping::SyncedCommitMapping;
+
+use crate::hooks::run_changeset_hooks;
+use crate::metadata::rename_bookmark;
+use crate::{
+    backsync_bookmark, check_rewrite_error, record_outcome, skip_entry, BacksyncConflict,
+    BacksyncLimit, BacksyncOutcome, BacksyncOutcomeKind, ConflictKind, ConflictPolicy,
+    ConflictResolution, FailOnConflict, PostSyncHook, SyncDirection, TargetRepoDbs,
+};
+
+#[derive(Clone)]
+pub struct ForwardSyncOptions {
+    /// The small repo bookmark to sync. Entries for other bookmarks are skipped, but the
+    /// counter still moves past them.
+    pub bookmark: BookmarkName,
+    /// Decides what to do with entries that can't be applied to the large repo as is.
+    pub conflict_policy: Arc<dyn ConflictPolicy>,
+}
+
+impl ForwardSyncOptions {
+    pub fn new(bookmark: BookmarkName) -> Self {
+        Self {
+            bookmark,
+            conflict_policy: Arc::new(FailOnConflict),
+        }
+    }
+}
+
+/// Sync the new log entries of `options.bookmark` from the small repo to the large repo.
+/// `commit_syncer` must sync from the small repo to the large repo, and `target_repo_dbs`
+/// must be the dbs of the large repo.
+pub async fn forward_sync_latest<M>(
+    ctx: CoreContext,
+    commit_syncer: CommitSyncer<M>,
+    target_repo_dbs: TargetRepoDbs,
+    limit: BacksyncLimit,
+    options: ForwardSyncOptions,
+) -> Result<(), Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let source_repo_id = commit_syncer.get_source_repo().get_repoid();
+    let target_repo_id = commit_syncer.get_target_repo().get_repoid();
+    if source_repo_id != commit_syncer.get_small_repo().get_repoid() {
+        bail!(
+            "forward sync goes from a small repo to the large repo, but {} is the large repo",
+            source_repo_id
+        );
+    }
+
+    let counter_name = format_forward_counter(&source_repo_id);
+    let mut counter = target_repo_dbs
+        .counters
+        .get_counter(ctx.clone(), target_repo_id, &counter_name)
+        .compat()
+        .await?
+        .unwrap_or(0);
+    debug!(ctx.logger(), "fetched forward sync counter {}", counter);
+
+    let log_entries_limit = match limit {
+        BacksyncLimit::Limit(limit) => limit,
+        BacksyncLimit::NoLimit => u64::max_value(),
+    };
+    let next_entries: Vec<_> = commit_syncer
+        .get_source_repo()
+        .read_next_bookmark_log_entries(
+            ctx.clone(),
+            counter as u64,
+            log_entries_limit,
+            Freshness::MostRecent,
+        )
+        .try_collect()
+        .await?;
+    let last_entry_id = match next_entries.last() {
+        Some(entry) => entry.id,
+        None => {
+            debug!(ctx.logger(), "nothing to sync");
+            return Ok(());
+        }
+    };
+
+    for entry in next_entries {
+        if counter >= entry.id || entry.bookmark_name != options.bookmark {
+            continue;
+        }
+        debug!(ctx.logger(), "forward syncing {} ...", entry.id);
+        let res = forward_sync_entry(
+            &ctx,
+            &commit_syncer,
+            &target_repo_dbs,
+            &entry,
+            counter,
+            &options,
+        )
+        .await;
+        let (new_counter, outcome) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                let outcome =
+                    BacksyncOutcome::new(&commit_syncer, &entry, BacksyncOutcomeKind::Error, 0)
+                        .with_error(&err);
+                record_outcome(&ctx, &target_repo_dbs, &outcome).await;
+                return Err(err);
+            }
+        };
+        record_outcome(&ctx, &target_repo_dbs, &outcome).await;
+        counter = new_counter;
+    }
+
+    // Move the counter past entries for other bookmarks after the last synced one, so that
+    // they aren't read again.
+    if counter < last_entry_id {
+        let updated = skip_entry(
+            &ctx,
+            &commit_syncer,
+            &target_repo_dbs,
+            SyncDirection::Forward,
+            last_entry_id,
+            counter,
+            None,
+        )
+        .await?;
+        if !updated {
+            debug!(
+                ctx.logger(),
+                "failed to move counter past skipped entries, most likely another process already did"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Forward sync one log entry, given that the counter is at `counter`. Returns the new
+/// counter and what happened to the entry.
+async fn forward_sync_entry<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: &TargetRepoDbs,
+    entry: &BookmarkUpdateLogEntry,
+    counter: i64,
+    options: &ForwardSyncOptions,
+) -> Result<(i64, BacksyncOutcome), Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let hooks = &target_repo_dbs.post_sync_hooks;
+    let rewritten = match sync_merge_free_commits(ctx, commit_syncer, hooks, entry).await {
+        Ok(Some(rewritten)) => rewritten,
+        Ok(None) => {
+            warn!(
+                ctx.logger(),
+                "skipping {}, entry id {}: none of its ancestors was synced",
+                entry.bookmark_name,
+                entry.id
+            );
+            skip_entry(
+                ctx,
+                commit_syncer,
+                target_repo_dbs,
+                SyncDirection::Forward,
+                entry.id,
+                counter,
+                None,
+            )
+            .await?;
+            let outcome =
+                BacksyncOutcome::new(commit_syncer, entry, BacksyncOutcomeKind::Unrelated, 0);
+            return Ok((entry.id, outcome));
+        }
+        Err(err) => {
+            let err = check_rewrite_error(err)?;
+            let conflict = BacksyncConflict::new(commit_syncer, entry, ConflictKind::Rewrite, &err);
+            return match options.conflict_policy.resolve(ctx, &conflict).await? {
+                ConflictResolution::Skip => {
+                    skip_conflicting_entry(
+                        ctx,
+                        commit_syncer,
+                        target_repo_dbs,
+                        entry,
+                        counter,
+                        0,
+                        options,
+                        &conflict,
+                        err,
+                    )
+                    .await
+                }
+                ConflictResolution::Fail | ConflictResolution::PreferSource => Err(err),
+            };
+        }
+    };
+
+    let large_bookmark = rename_bookmark(
+        commit_syncer,
+        &entry.bookmark_name,
+        target_repo_dbs.sync_metadata_entries,
+    )
+    .await?;
+    let large_bookmark = match large_bookmark {
+        Some(large_bookmark) => large_bookmark,
+        None => {
+            // Only the counter moves, so there is nothing to conflict with.
+            backsync_bookmark(
+                ctx.clone(),
+                commit_syncer,
+                target_repo_dbs.clone(),
+                SyncDirection::Forward,
+                Some(counter),
+                entry.clone(),
+                false,
+            )
+            .await?;
+            let outcome = BacksyncOutcome::new(
+                commit_syncer,
+                entry,
+                BacksyncOutcomeKind::RenamedAway,
+                rewritten,
+            );
+            return Ok((entry.id, outcome));
+        }
+    };
+
+    let err = match find_divergence(ctx, commit_syncer, target_repo_dbs, &large_bookmark, entry)
+        .await?
+    {
+        Some(err) => err,
+        None => {
+            let success = backsync_bookmark(
+                ctx.clone(),
+                commit_syncer,
+                target_repo_dbs.clone(),
+                SyncDirection::Forward,
+                Some(counter),
+                entry.clone(),
+                false,
+            )
+            .await?;
+            if success {
+                let outcome = BacksyncOutcome::new(
+                    commit_syncer,
+                    entry,
+                    BacksyncOutcomeKind::Synced,
+                    rewritten,
+                );
+                return Ok((entry.id, outcome));
+            }
+
+            // The bookmark was where we expected it, so most likely another process synced
+            // this entry in the meantime.
+            let new_counter = target_repo_dbs
+                .counters
+                .get_counter(
+                    ctx.clone(),
+                    commit_syncer.get_target_repo().get_repoid(),
+                    &format_forward_counter(&commit_syncer.get_source_repo().get_repoid()),
+                )
+                .compat()
+                .await?
+                .unwrap_or(0);
+            if new_counter > counter {
+                let outcome = BacksyncOutcome::new(
+                    commit_syncer,
+                    entry,
+                    BacksyncOutcomeKind::AlreadySynced,
+                    rewritten,
+                );
+                return Ok((new_counter, outcome));
+            }
+            format_err!(
+                "forward sync transaction failed, but the counter didn't move forward. Was {}, became {}",
+                counter,
+                new_counter,
+            )
+        }
+    };
+
+    let conflict = BacksyncConflict::new(commit_syncer, entry, ConflictKind::BookmarkMove, &err);
+    match options.conflict_policy.resolve(ctx, &conflict).await? {
+        ConflictResolution::Fail => Err(err),
+        ConflictResolution::Skip => {
+            skip_conflicting_entry(
+                ctx,
+                commit_syncer,
+                target_repo_dbs,
+                entry,
+                counter,
+                rewritten,
+                options,
+                &conflict,
+                err,
+            )
+            .await
+        }
+        ConflictResolution::PreferSource => {
+            warn!(
+                ctx.logger(),
+                "forcing {} to its small repo position, entry id {}", large_bookmark, entry.id
+            );
+            let success = backsync_bookmark(
+                ctx.clone(),
+                commit_syncer,
+                target_repo_dbs.clone(),
+                SyncDirection::Forward,
+                Some(counter),
+                entry.clone(),
+                true,
+            )
+            .await?;
+            if !success {
+                return Err(err);
+            }
+            let outcome =
+                BacksyncOutcome::new(commit_syncer, entry, BacksyncOutcomeKind::Synced, rewritten);
+            Ok((entry.id, outcome))
+        }
+    }
+}
+
+/// Sync the commits that a log entry moves its bookmark to, and return how many there were.
+/// Returns `None` if none of their ancestors was ever synced, and fails if any of them is
+/// a merge.
+async fn sync_merge_free_commits<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    post_sync_hooks: &[Arc<dyn PostSyncHook>],
+    entry: &BookmarkUpdateLogEntry,
+) -> Result<Option<u64>, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let to_cs_id = match entry.to_changeset_id {
+        Some(to_cs_id) => to_cs_id,
+        None => return Ok(Some(0)),
+    };
+    let (unsynced_ancestors, unsynced_ancestors_versions) =
+        find_toposorted_unsynced_ancestors(ctx, commit_syncer, to_cs_id).await?;
+    if !unsynced_ancestors_versions.has_ancestor_with_a_known_outcome() {
+        return Ok(None);
+    }
+
+    let source_repo = commit_syncer.get_source_repo();
+    for cs_id in &unsynced_ancestors {
+        let parents = source_repo
+            .get_changeset_parents_by_bonsai(ctx.clone(), *cs_id)
+            .await?;
+        if parents.len() > 1 {
+            bail!(
+                "{} is a merge, forward sync only supports history without merges",
+                cs_id
+            );
+        }
+    }
+
+    // The ancestors are synced parents first, and each of them has a single parent,
+    // so `CandidateSelectionHint::Only` is a safe choice. Small repo commits on a bookmark
+    // are public, which `sync_commit` refuses to sync, hence `unsafe_sync_commit`.
+    for cs_id in &unsynced_ancestors {
+        commit_syncer
+            .unsafe_sync_commit(
+                ctx,
+                *cs_id,
+                CandidateSelectionHint::Only,
+                CommitSyncContext::XRepoSyncJob,
+            )
+            .await?;
+    }
+    run_changeset_hooks(ctx, commit_syncer, post_sync_hooks, &unsynced_ancestors).await;
+    Ok(Some(unsynced_ancestors.len() as u64))
+}
+
+/// An error describing how `large_bookmark` diverged, if it doesn't point to the large repo
+/// commit that the entry's bookmark moves from was synced to.
+async fn find_divergence<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: &TargetRepoDbs,
+    large_bookmark: &BookmarkName,
+    entry: &BookmarkUpdateLogEntry,
+) -> Result<Option<Error>, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let expected = match entry.from_changeset_id {
+        Some(cs_id) => Some(synced_changeset(ctx, commit_syncer, cs_id).await?),
+        None => None,
+    };
+    let actual = target_repo_dbs
+        .bookmarks
+        .get(ctx.clone(), large_bookmark)
+        .await?;
+    if actual == expected {
+        return Ok(None);
+    }
+    Ok(Some(format_err!(
+        "large repo bookmark {} diverged: expected it at {:?}, but it is at {:?}",
+        large_bookmark,
+        expected,
+        actual
+    )))
+}
+
+/// The large repo commit that `cs_id` was synced to.
+async fn synced_changeset<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    cs_id: ChangesetId,
+) -> Result<ChangesetId, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    match commit_syncer.get_commit_sync_outcome(ctx, cs_id).await? {
+        Some(CommitSyncOutcome::RewrittenAs(synced, _))
+        | Some(CommitSyncOutcome::EquivalentWorkingCopyAncestor(synced, _)) => Ok(synced),
+        Some(CommitSyncOutcome::NotSyncCandidate) => Err(format_err!(
+            "{} should not be synced to the large repo",
+            cs_id
+        )),
+        None => Err(format_err!("{} hasn't been synced yet", cs_id)),
+    }
+}
+
+async fn skip_conflicting_entry<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: &TargetRepoDbs,
+    entry: &BookmarkUpdateLogEntry,
+    counter: i64,
+    rewritten: u64,
+    options: &ForwardSyncOptions,
+    conflict: &BacksyncConflict,
+    err: Error,
+) -> Result<(i64, BacksyncOutcome), Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    warn!(
+        ctx.logger(),
+        "skipping {}, entry id {} because of a conflict: {:#}", entry.bookmark_name, entry.id, err
+    );
+    skip_entry(
+        ctx,
+        commit_syncer,
+        target_repo_dbs,
+        SyncDirection::Forward,
+        entry.id,
+        counter,
+        Some((options.conflict_policy.as_ref(), conflict)),
+    )
+    .await?;
+    let outcome = BacksyncOutcome::new(
+        commit_syncer,
+        entry,
+        BacksyncOutcomeKind::SkippedConflict,
+        rewritten,
+    )
+    .with_error(&err);
+    Ok((entry.id, outcome))
+}
+
+pub fn format_forward_counter(repo_to_sync_from: &RepositoryId) -> String {
+    format!("forward_sync_from_{}", repo_to_sync_from.id())
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Hooks that are told about every commit and bookmark move that was synced, so that e.g.
+//! caches of the target repo can be invalidated or other systems notified without changing
+//! the sync loop. Hooks are registered with `TargetRepoDbs::post_sync_hooks`.
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use bookmarks::{BookmarkName, BookmarkUpdateLogEntry};
+use context::CoreContext;
+use cross_repo_sync::{CommitSyncOutcome, CommitSyncer};
+use metaconfig_types::CommitSyncConfigVersion;
+use mononoke_types::{ChangesetId, RepositoryId};
+use slog::warn;
+use synced_commit_mapping::SyncedCommitMapping;
+
+/// A source repo commit that was synced to the target repo.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncedChangeset {
+    pub source_repo_id: RepositoryId,
+    pub target_repo_id: RepositoryId,
+    pub source_cs_id: ChangesetId,
+    /// The commit it was rewritten as, or its closest synced ancestor if the rewrite was
+    /// empty.
+    pub target_cs_id: ChangesetId,
+    pub version: CommitSyncConfigVersion,
+}
+
+/// A bookmark move of the source repo that was applied to the target repo.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncedBookmarkMove {
+    pub source_repo_id: RepositoryId,
+    pub target_repo_id: RepositoryId,
+    pub entry_id: i64,
+    pub source_bookmark: BookmarkName,
+    pub target_bookmark: BookmarkName,
+    pub source_from_cs_id: Option<ChangesetId>,
+    pub source_to_cs_id: Option<ChangesetId>,
+    pub target_from_cs_id: Option<ChangesetId>,
+    pub target_to_cs_id: Option<ChangesetId>,
+    /// The version the commit the bookmark moved to was synced with, or for deletions the
+    /// commit it moved from.
+    pub version: Option<CommitSyncConfigVersion>,
+}
+
+/// Called once a commit or bookmark move has been synced. The sync already happened, so
+/// errors are logged and otherwise ignored. Hooks are called while syncing, so they should
+/// be quick, and hand anything slow off to somewhere else.
+#[async_trait]
+pub trait PostSyncHook: Send + Sync {
+    async fn changeset_synced(
+        &self,
+        _ctx: &CoreContext,
+        _synced: &SyncedChangeset,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn bookmark_moved(
+        &self,
+        _ctx: &CoreContext,
+        _moved: &SyncedBookmarkMove,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Call the hooks for each of the commits, which must have been synced. Commits that
+/// weren't sync candidates have nothing in the target repo, and are left out.
+pub(crate) async fn run_changeset_hooks<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    hooks: &[Arc<dyn PostSyncHook>],
+    cs_ids: &[ChangesetId],
+) where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    if hooks.is_empty() {
+        return;
+    }
+    for cs_id in cs_ids {
+        let outcome = match commit_syncer.get_commit_sync_outcome(ctx, *cs_id).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                warn!(
+                    ctx.logger(),
+                    "failed to get sync outcome of {} for post-sync hooks: {:#}", cs_id, err
+                );
+                continue;
+            }
+        };
+        let (target_cs_id, version) = match outcome {
+            Some(CommitSyncOutcome::RewrittenAs(target_cs_id, version))
+            | Some(CommitSyncOutcome::EquivalentWorkingCopyAncestor(target_cs_id, version)) => {
+                (target_cs_id, version)
+            }
+            Some(CommitSyncOutcome::NotSyncCandidate) | None => continue,
+        };
+        let synced = SyncedChangeset {
+            source_repo_id: commit_syncer.get_source_repo().get_repoid(),
+            target_repo_id: commit_syncer.get_target_repo().get_repoid(),
+            source_cs_id: *cs_id,
+            target_cs_id,
+            version,
+        };
+        for hook in hooks {
+            if let Err(err) = hook.changeset_synced(ctx, &synced).await {
+                warn!(
+                    ctx.logger(),
+                    "post-sync hook failed for commit {}: {:#}", cs_id, err
+                );
+            }
+        }
+    }
+}
+
+/// Call the hooks for a bookmark move that was applied to the target repo.
+pub(crate) async fn run_bookmark_hooks<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    hooks: &[Arc<dyn PostSyncHook>],
+    entry: &BookmarkUpdateLogEntry,
+    target_bookmark: BookmarkName,
+    (target_from_cs_id, target_to_cs_id): (Option<ChangesetId>, Option<ChangesetId>),
+    version: Option<CommitSyncConfigVersion>,
+) where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let moved = SyncedBookmarkMove {
+        source_repo_id: commit_syncer.get_source_repo().get_repoid(),
+        target_repo_id: commit_syncer.get_target_repo().get_repoid(),
+        entry_id: entry.id,
+        source_bookmark: entry.bookmark_name.clone(),
+        target_bookmark,
+        source_from_cs_id: entry.from_changeset_id,
+        source_to_cs_id: entry.to_changeset_id,
+        target_from_cs_id,
+        target_to_cs_id,
+        version,
+    };
+    for hook in hooks {
+        if let Err(err) = hook.bookmark_moved(ctx, &moved).await {
+            warn!(
+                ctx.logger(),
+                "post-sync hook failed for entry {}: {:#}", entry.id, err
+            );
+        }
+    }
+}
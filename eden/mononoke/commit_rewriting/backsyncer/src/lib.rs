@@ -24,12 +24,27 @@
 /// 2) Rewrite these commits and create rewritten commits in target repo
 /// 3) In the same transaction try to update a bookmark in the source repo AND latest backsynced
 ///    log id.
+///
+/// To catch up faster, phase 2 can be run concurrently for entries that move different
+/// bookmarks (see `BacksyncOptions::parallelism`). Phase 3 always happens in log order.
+///
+/// Uploads of rewritten merge commits are checkpointed in the target repo dbs, so that
+/// retrying a failed backsync of a merge that touches many files doesn't start over.
+///
+/// Backsync can be throttled with the `backsyncer_commits_per_second` and
+/// `backsyncer_entries_per_iteration` tunables of the target repo.
+///
+/// Post-sync hooks registered with `TargetRepoDbs::post_sync_hooks` are told about every
+/// commit and bookmark move that was synced, e.g. to invalidate caches of the target repo.
+///
+/// For simple mirrors, `forward_sync_latest` syncs a single bookmark in the other direction,
+/// from a small repo to a large repo, as long as it only moves over merge-free history.
 use anyhow::{bail, format_err, Error};
 use blobrepo::BlobRepo;
 use blobstore_factory::{make_metadata_sql_factory, ReadOnlyStorage};
 use bookmarks::{
-    ArcBookmarkUpdateLog, ArcBookmarks, BookmarkTransactionError, BookmarkUpdateLogEntry,
-    BookmarkUpdateReason, BundleReplay, Freshness,
+    ArcBookmarkUpdateLog, ArcBookmarks, BookmarkName, BookmarkTransactionError,
+    BookmarkUpdateLogEntry, BookmarkUpdateReason, BundleReplay, Freshness,
 };
 use cloned::cloned;
 use context::CoreContext;
@@ -37,8 +52,8 @@ use cross_repo_sync::{
     find_toposorted_unsynced_ancestors, CandidateSelectionHint, CommitSyncContext,
     CommitSyncOutcome, CommitSyncer,
 };
-use futures::{compat::Future01CompatExt, FutureExt, TryStreamExt};
-use metaconfig_types::MetadataDatabaseConfig;
+use futures::{compat::Future01CompatExt, future, FutureExt, TryStreamExt};
+use metaconfig_types::{BookmarkOrRegex, CommitSyncConfigVersion, MetadataDatabaseConfig};
 use mononoke_types::{ChangesetId, RepositoryId};
 use mutable_counters::{MutableCounters, SqlMutableCounters};
 use slog::{debug, warn};
@@ -46,19 +61,142 @@ use sql::Transaction;
 use sql_construct::SqlConstruct;
 use sql_ext::facebook::MysqlOptions;
 use sql_ext::{SqlConnections, TransactionResult};
-use std::{sync::Arc, time::Instant};
+use std::{collections::HashSet, sync::Arc, time::Instant};
 use synced_commit_mapping::SyncedCommitMapping;
 use thiserror::Error;
 
+use crate::hooks::{run_bookmark_hooks, run_changeset_hooks};
+use crate::metadata::rename_bookmark;
+use crate::throttle::{entries_per_iteration, BacksyncThrottle};
+
+mod checkpoints;
+mod conflicts;
+mod forward;
+mod hooks;
+mod metadata;
+mod outcomes;
+mod progress;
 #[cfg(test)]
 mod tests;
+mod throttle;
+mod verify;
+
+pub use crate::checkpoints::SqlRewriteCheckpoints;
+pub use crate::conflicts::{
+    BacksyncConflict, ConflictKind, ConflictPolicy, ConflictResolution, FailOnConflict,
+    PreferSourceOnConflict, SkipAndRecordConflicts, SkippedBacksyncEntry,
+    SqlSkippedBacksyncEntries,
+};
+pub use crate::forward::{format_forward_counter, forward_sync_latest, ForwardSyncOptions};
+pub use crate::hooks::{PostSyncHook, SyncedBookmarkMove, SyncedChangeset};
+pub use crate::metadata::{MetadataEntry, MetadataKind};
+pub use crate::outcomes::{BacksyncOutcome, BacksyncOutcomeKind, SqlBacksyncOutcomes};
+pub use crate::progress::{BacksyncProgress, BacksyncProgressSnapshot};
+pub use crate::verify::{verify_and_fix_bookmarks, BookmarkDiff};
 
 #[derive(Debug, Error)]
 pub enum BacksyncError {
     #[error("BacksyncError::LogEntryNotFound: {latest_log_id} not found")]
     LogEntryNotFound { latest_log_id: u64 },
-    #[error("BacksyncError::Other")]
-    Other(#[from] Error),
+    /// Reading or writing the bookmark update log, counters, bookmarks or the blobstore
+    /// failed, e.g. because a db connection was lost.
+    #[error(transparent)]
+    TransientStorage(Error),
+    /// Rewriting the commits that a log entry moves its bookmark to failed.
+    #[error("failed to rewrite commits for log entry {entry_id}")]
+    RewriteFailed {
+        entry_id: i64,
+        #[source]
+        source: Error,
+    },
+    /// A commit that a log entry moves its bookmark from or to has no sync outcome.
+    #[error("{cs_id} hasn't been backsynced yet")]
+    MappingMissing { cs_id: ChangesetId },
+    /// Moving the bookmark failed, but no other process moved the counter either.
+    /// Another writer most likely moved the bookmark at the same time.
+    #[error(
+        "backsync transaction failed, but the counter didn't move forward. Was {counter}, became {new_counter}"
+    )]
+    BookmarkRace { counter: i64, new_counter: i64 },
+    #[error(transparent)]
+    Other(Error),
+}
+
+impl BacksyncError {
+    /// Whether backsync is likely to succeed if it is retried as is. Other errors need
+    /// the repos or the sync config to be fixed first.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BacksyncError::TransientStorage(_) | BacksyncError::BookmarkRace { .. } => true,
+            BacksyncError::LogEntryNotFound { .. }
+            | BacksyncError::RewriteFailed { .. }
+            | BacksyncError::MappingMissing { .. }
+            | BacksyncError::Other(_) => false,
+        }
+    }
+}
+
+/// Check an error that failed rewriting the commits of a log entry before it is handled as a
+/// `ConflictKind::Rewrite` conflict. Failures to read or write storage, e.g. a lost db
+/// connection or a blobstore timeout, are returned as `TransientStorage` to be retried:
+/// conflict policies may skip entries for good. Other errors are returned as is.
+pub(crate) fn check_rewrite_error(err: Error) -> Result<Error, BacksyncError> {
+    let is_storage_error = err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<BacksyncError>() {
+            return err.is_transient();
+        }
+        if let Some(err) = cause.downcast_ref::<multiplexedblob::base::ErrorKind>() {
+            use multiplexedblob::base::ErrorKind::*;
+            return matches!(
+                err,
+                AllFailed(_) | SomeFailedOthersNone(_) | MultiplePutFailures(_)
+            );
+        }
+        cause.is::<sql::mysql_async::Error>()
+            || cause.is::<sql::rusqlite::Error>()
+            || cause.is::<std::io::Error>()
+            || cause.is::<tokio::time::error::Elapsed>()
+    });
+    if is_storage_error {
+        Err(BacksyncError::TransientStorage(err))
+    } else {
+        Ok(err)
+    }
+}
+
+impl From<Error> for BacksyncError {
+    fn from(err: Error) -> Self {
+        match err.downcast::<BacksyncError>() {
+            Ok(err) => err,
+            Err(err) => BacksyncError::Other(err),
+        }
+    }
+}
+
+/// Which way commits are synced. This decides the counter that keeps track of the synced
+/// log entries, and the reason recorded for bookmark moves in the target repo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncDirection {
+    /// From the large repo to a small repo.
+    Backsync,
+    /// From a small repo to the large repo, see `forward_sync_latest`.
+    Forward,
+}
+
+impl SyncDirection {
+    fn counter_name(&self, source_repo_id: &RepositoryId) -> String {
+        match self {
+            SyncDirection::Backsync => format_counter(source_repo_id),
+            SyncDirection::Forward => format_forward_counter(source_repo_id),
+        }
+    }
+
+    fn bookmark_update_reason(&self) -> BookmarkUpdateReason {
+        match self {
+            SyncDirection::Backsync => BookmarkUpdateReason::Backsyncer,
+            SyncDirection::Forward => BookmarkUpdateReason::XRepoSync,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -72,12 +210,101 @@ pub async fn backsync_latest<M>(
     commit_syncer: CommitSyncer<M>,
     target_repo_dbs: TargetRepoDbs,
     limit: BacksyncLimit,
+) -> Result<(), BacksyncError>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    backsync_latest_with_options(
+        ctx,
+        commit_syncer,
+        target_repo_dbs,
+        limit,
+        BacksyncOptions::default(),
+    )
+    .await
+}
+
+#[derive(Clone)]
+pub struct BacksyncOptions {
+    /// How many log entries to rewrite commits for at once. Only entries that move
+    /// different bookmarks are rewritten concurrently, and bookmark moves and counter
+    /// updates still happen one entry at a time, in log order.
+    pub parallelism: usize,
+    /// Updated with the counter, the remaining entries, the rewrite rate and errors as
+    /// backsync runs.
+    pub progress: Option<BacksyncProgress>,
+    /// If set, only log entries for bookmarks that match one of these are backsynced.
+    /// Other entries are skipped, but the counter still moves past them.
+    pub bookmark_filter: Option<Vec<BookmarkOrRegex>>,
+    /// Decides what to do with entries that can't be applied to the target repo as is.
+    pub conflict_policy: Arc<dyn ConflictPolicy>,
+}
+
+impl BacksyncOptions {
+    fn should_backsync(&self, bookmark: &BookmarkName) -> bool {
+        match &self.bookmark_filter {
+            Some(filter) => filter.iter().any(|matcher| matcher.matches(bookmark)),
+            None => true,
+        }
+    }
+}
+
+impl Default for BacksyncOptions {
+    fn default() -> Self {
+        Self {
+            parallelism: 1,
+            progress: None,
+            bookmark_filter: None,
+            conflict_policy: Arc::new(FailOnConflict),
+        }
+    }
+}
+
+pub async fn backsync_latest_with_options<M>(
+    ctx: CoreContext,
+    commit_syncer: CommitSyncer<M>,
+    target_repo_dbs: TargetRepoDbs,
+    limit: BacksyncLimit,
+    options: BacksyncOptions,
+) -> Result<(), BacksyncError>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let progress = options.progress.clone().unwrap_or_default();
+    let res = backsync_latest_impl(
+        ctx,
+        commit_syncer,
+        target_repo_dbs,
+        limit,
+        &options,
+        &progress,
+    )
+    .await;
+    if let Err(err) = &res {
+        progress.record_error(err);
+    }
+    Ok(res?)
+}
+
+async fn backsync_latest_impl<M>(
+    ctx: CoreContext,
+    commit_syncer: CommitSyncer<M>,
+    target_repo_dbs: TargetRepoDbs,
+    limit: BacksyncLimit,
+    options: &BacksyncOptions,
+    progress: &BacksyncProgress,
 ) -> Result<(), Error>
 where
     M: SyncedCommitMapping + Clone + 'static,
 {
     // TODO(ikostia): start borrowing `CommitSyncer`, no reason to consume it
-    let TargetRepoDbs { ref counters, .. } = target_repo_dbs;
+    let TargetRepoDbs {
+        ref counters,
+        ref rewrite_checkpoints,
+        ..
+    } = target_repo_dbs;
+    let commit_syncer =
+        commit_syncer.with_rewrite_checkpoints(Arc::new(rewrite_checkpoints.clone()));
     let target_repo_id = commit_syncer.get_target_repo().get_repoid();
     let source_repo_id = commit_syncer.get_source_repo().get_repoid();
     let counter_name = format_counter(&source_repo_id);
@@ -85,10 +312,12 @@ where
     let counter = counters
         .get_counter(ctx.clone(), target_repo_id, &counter_name)
         .compat()
-        .await?
+        .await
+        .map_err(BacksyncError::TransientStorage)?
         .unwrap_or(0);
 
     debug!(ctx.logger(), "fetched counter {}", counter);
+    progress.start_run(counter);
 
     let log_entries_limit = match limit {
         BacksyncLimit::Limit(limit) => limit,
@@ -97,6 +326,17 @@ where
             u64::max_value()
         }
     };
+    let target_repo_name = commit_syncer.get_target_repo().name();
+    let log_entries_limit = match entries_per_iteration(target_repo_name) {
+        Some(entries) if entries < log_entries_limit => {
+            debug!(
+                ctx.logger(),
+                "reading at most {} entries in this iteration", entries
+            );
+            entries
+        }
+        _ => log_entries_limit,
+    };
     let next_entries: Vec<_> = commit_syncer
         .get_source_repo()
         .read_next_bookmark_log_entries(
@@ -106,7 +346,12 @@ where
             Freshness::MostRecent,
         )
         .try_collect()
-        .await?;
+        .await
+        .map_err(BacksyncError::TransientStorage)?;
+    progress.set_pending_entries(
+        next_entries.last().map(|entry| entry.id),
+        next_entries.len() as u64,
+    );
 
     if next_entries.is_empty() {
         debug!(ctx.logger(), "nothing to sync");
@@ -118,160 +363,545 @@ where
             target_repo_dbs,
             next_entries,
             counter as i64,
+            options,
+            progress,
         )
         .await
     }
 }
 
+/// Split log entries into batches whose commits can be rewritten concurrently: each
+/// batch has at most `parallelism` entries, and no two entries for the same bookmark.
+fn split_into_batches(
+    entries: Vec<BookmarkUpdateLogEntry>,
+    parallelism: usize,
+) -> Vec<Vec<BookmarkUpdateLogEntry>> {
+    let parallelism = std::cmp::max(parallelism, 1);
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut bookmarks = HashSet::new();
+    for entry in entries {
+        if batch.len() >= parallelism || bookmarks.contains(&entry.bookmark_name) {
+            batches.push(std::mem::take(&mut batch));
+            bookmarks.clear();
+        }
+        bookmarks.insert(entry.bookmark_name.clone());
+        batch.push(entry);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Rewrite the commits that a log entry moves its bookmark to, and return how many there
+/// were. Returns `None` if the entry can't be synced because none of the ancestors of its
+/// target was ever synced.
+async fn rewrite_entry<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    post_sync_hooks: &[Arc<dyn PostSyncHook>],
+    entry: &BookmarkUpdateLogEntry,
+    progress: &BacksyncProgress,
+) -> Result<Option<u64>, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    if let Some(to_cs_id) = entry.to_changeset_id {
+        let (unsynced_ancestors, unsynced_ancestors_versions) =
+            find_toposorted_unsynced_ancestors(ctx, commit_syncer, to_cs_id).await?;
+
+        if !unsynced_ancestors_versions.has_ancestor_with_a_known_outcome() {
+            return Ok(None);
+        }
+
+        // Backsyncer is always used in the large-to-small direction,
+        // therefore there can be at most one remapped candidate,
+        // so `CandidateSelectionHint::Only` is a safe choice
+        commit_syncer
+            .sync_commit(
+                ctx,
+                to_cs_id,
+                CandidateSelectionHint::Only,
+                CommitSyncContext::Backsyncer,
+            )
+            .await?;
+        run_changeset_hooks(ctx, commit_syncer, post_sync_hooks, &unsynced_ancestors).await;
+
+        let rewritten = unsynced_ancestors.len() as u64;
+        progress.record_rewritten_commits(rewritten);
+        return Ok(Some(rewritten));
+    }
+    Ok(Some(0))
+}
+
 async fn sync_entries<M>(
     ctx: CoreContext,
     commit_syncer: &CommitSyncer<M>,
     target_repo_dbs: TargetRepoDbs,
     entries: Vec<BookmarkUpdateLogEntry>,
     mut counter: i64,
+    options: &BacksyncOptions,
+    progress: &BacksyncProgress,
 ) -> Result<(), Error>
 where
     M: SyncedCommitMapping + Clone + 'static,
 {
-    for entry in entries {
-        let entry_id = entry.id;
-        if counter >= entry_id {
-            continue;
-        }
-        debug!(ctx.logger(), "backsyncing {} ...", entry_id);
+    let last_entry_id = entries.last().map(|entry| entry.id);
+    let total_entries = entries.len();
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| entry.id > counter && options.should_backsync(&entry.bookmark_name))
+        .collect();
+    if entries.len() < total_entries {
+        debug!(
+            ctx.logger(),
+            "skipping {} entries that were already synced or don't match the bookmark filter",
+            total_entries - entries.len()
+        );
+    }
+    let entry_ids: Vec<_> = entries.iter().map(|entry| entry.id).collect();
+    let advance_counter = |new_counter: i64| {
+        let remaining = entry_ids.len() - entry_ids.partition_point(|id| *id <= new_counter);
+        progress.record_counter(new_counter, remaining as u64);
+        new_counter
+    };
+    advance_counter(counter);
+    let mut throttle = BacksyncThrottle::new(commit_syncer.get_target_repo().name().clone());
+    for batch in split_into_batches(entries, options.parallelism) {
+        let start_instant = Instant::now();
+        let batch_rewritten = if batch.len() > 1 {
+            debug!(
+                ctx.logger(),
+                "rewriting commits for {} entries concurrently",
+                batch.len()
+            );
+            future::join_all(batch.iter().map(|entry| {
+                rewrite_entry(
+                    &ctx,
+                    commit_syncer,
+                    &target_repo_dbs.post_sync_hooks,
+                    entry,
+                    progress,
+                )
+            }))
+            .await
+        } else {
+            vec![]
+        };
 
-        let mut scuba_sample = ctx.scuba().clone();
-        scuba_sample.add("backsyncer_bookmark_log_entry_id", entry.id);
+        for (idx, entry) in batch.into_iter().enumerate() {
+            if counter >= entry.id {
+                continue;
+            }
+            debug!(ctx.logger(), "backsyncing {} ...", entry.id);
 
-        let start_instant = Instant::now();
+            // An entry that failed or looked unsyncable may succeed once the entries
+            // before it were synced, so try again now that they have been.
+            let rewritten = match batch_rewritten.get(idx) {
+                Some(Ok(Some(rewritten))) => Some(*rewritten),
+                _ => None,
+            };
+            let res = sync_entry(
+                &ctx,
+                commit_syncer,
+                &target_repo_dbs,
+                &entry,
+                rewritten,
+                counter,
+                start_instant,
+                options,
+                progress,
+            )
+            .await;
+            let (new_counter, outcome) = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    let outcome =
+                        BacksyncOutcome::new(commit_syncer, &entry, BacksyncOutcomeKind::Error, 0)
+                            .with_error(&err);
+                    record_outcome(&ctx, &target_repo_dbs, &outcome).await;
+                    return Err(err);
+                }
+            };
+            record_outcome(&ctx, &target_repo_dbs, &outcome).await;
+            counter = advance_counter(new_counter);
+            throttle
+                .record_entry(&ctx, outcome.rewritten_commits, progress)
+                .await;
+        }
+    }
 
-        if let Some(to_cs_id) = entry.to_changeset_id {
-            let (_, unsynced_ancestors_versions) =
-                find_toposorted_unsynced_ancestors(&ctx, commit_syncer, to_cs_id).await?;
-
-            if !unsynced_ancestors_versions.has_ancestor_with_a_known_outcome() {
-                // Not a single ancestor of to_cs_id was ever synced.
-                // That means that we can't figure out which commit sync mapping version
-                // to use. In that case we just skip this entry and not sync it at all.
-                // This seems the safest option (i.e. we won't rewrite a commit with
-                // an incorrect version) but it also has a downside that the bookmark that points
-                // to this commit is not going to be synced.
-                warn!(
+    // Entries after the last backsynced one may all have been filtered out. Move the
+    // counter past them so that they aren't read again.
+    if let Some(last_entry_id) = last_entry_id {
+        if counter < last_entry_id {
+            let updated = skip_entry(
+                &ctx,
+                commit_syncer,
+                &target_repo_dbs,
+                SyncDirection::Backsync,
+                last_entry_id,
+                counter,
+                None,
+            )
+            .await?;
+            if !updated {
+                debug!(
                     ctx.logger(),
-                    "skipping {}, entry id {}", entry.bookmark_name, entry.id
-                );
-                scuba_sample.log_with_msg(
-                    "Skipping entry because there are no synced ancestors",
-                    Some(format!("{}", entry.id)),
+                    "failed to move counter past skipped entries, most likely another process already did"
                 );
-                target_repo_dbs
-                    .counters
-                    .set_counter(
-                        ctx.clone(),
-                        commit_syncer.get_target_repo().get_repoid(),
-                        &format_counter(&commit_syncer.get_source_repo().get_repoid()),
+            }
+            advance_counter(last_entry_id);
+        }
+    }
+    Ok(())
+}
+
+/// Backsync one log entry, given that the counter is at `counter`. `rewritten` is the
+/// number of commits rewritten for the entry if that was already done. Returns the new
+/// counter and what happened to the entry.
+async fn sync_entry<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: &TargetRepoDbs,
+    entry: &BookmarkUpdateLogEntry,
+    rewritten: Option<u64>,
+    counter: i64,
+    start_instant: Instant,
+    options: &BacksyncOptions,
+    progress: &BacksyncProgress,
+) -> Result<(i64, BacksyncOutcome), Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let entry_id = entry.id;
+    let mut scuba_sample = ctx.scuba().clone();
+    scuba_sample.add("backsyncer_bookmark_log_entry_id", entry.id);
+
+    let rewritten = match rewritten {
+        Some(rewritten) => Ok(Some(rewritten)),
+        None => {
+            rewrite_entry(
+                ctx,
+                commit_syncer,
+                &target_repo_dbs.post_sync_hooks,
+                entry,
+                progress,
+            )
+            .await
+        }
+    };
+    let rewritten = match rewritten {
+        Ok(rewritten) => rewritten,
+        Err(err) => {
+            let err = check_rewrite_error(err)?;
+            let conflict = BacksyncConflict::new(commit_syncer, entry, ConflictKind::Rewrite, &err);
+            match options.conflict_policy.resolve(ctx, &conflict).await? {
+                ConflictResolution::Skip => {
+                    warn!(
+                        ctx.logger(),
+                        "skipping {}, entry id {} because of a conflict: {:#}",
+                        entry.bookmark_name,
                         entry.id,
-                        Some(counter),
+                        err
+                    );
+                    scuba_sample.log_with_msg(
+                        "Skipping entry because of a conflict",
+                        Some(format!("{:#}", err)),
+                    );
+                    skip_entry(
+                        ctx,
+                        commit_syncer,
+                        target_repo_dbs,
+                        SyncDirection::Backsync,
+                        entry.id,
+                        counter,
+                        Some((options.conflict_policy.as_ref(), &conflict)),
                     )
-                    .compat()
                     .await?;
-                counter = entry.id;
-                continue;
+                    let outcome = BacksyncOutcome::new(
+                        commit_syncer,
+                        entry,
+                        BacksyncOutcomeKind::SkippedConflict,
+                        0,
+                    )
+                    .with_error(&err);
+                    return Ok((entry_id, outcome));
+                }
+                ConflictResolution::Fail | ConflictResolution::PreferSource => {
+                    return Err(BacksyncError::RewriteFailed {
+                        entry_id: entry.id,
+                        source: err,
+                    }
+                    .into());
+                }
             }
+        }
+    };
 
-            // Backsyncer is always used in the large-to-small direction,
-            // therefore there can be at most one remapped candidate,
-            // so `CandidateSelectionHint::Only` is a safe choice
-            commit_syncer
-                .sync_commit(
-                    &ctx,
-                    to_cs_id,
-                    CandidateSelectionHint::Only,
-                    CommitSyncContext::Backsyncer,
-                )
-                .await?;
+    let rewritten = match rewritten {
+        Some(rewritten) => rewritten,
+        None => {
+            // Not a single ancestor of to_cs_id was ever synced.
+            // That means that we can't figure out which commit sync mapping version
+            // to use. In that case we just skip this entry and not sync it at all.
+            // This seems the safest option (i.e. we won't rewrite a commit with
+            // an incorrect version) but it also has a downside that the bookmark that points
+            // to this commit is not going to be synced.
+            warn!(
+                ctx.logger(),
+                "skipping {}, entry id {}", entry.bookmark_name, entry.id
+            );
+            scuba_sample.log_with_msg(
+                "Skipping entry because there are no synced ancestors",
+                Some(format!("{}", entry.id)),
+            );
+            skip_entry(
+                ctx,
+                commit_syncer,
+                target_repo_dbs,
+                SyncDirection::Backsync,
+                entry.id,
+                counter,
+                None,
+            )
+            .await?;
+            let outcome =
+                BacksyncOutcome::new(commit_syncer, entry, BacksyncOutcomeKind::Unrelated, 0);
+            return Ok((entry_id, outcome));
         }
+    };
 
-        let new_counter = entry.id;
-        let success = backsync_bookmark(
-            ctx.clone(),
+    let success = backsync_bookmark(
+        ctx.clone(),
+        commit_syncer,
+        target_repo_dbs.clone(),
+        SyncDirection::Backsync,
+        Some(counter),
+        entry.clone(),
+        false,
+    )
+    .await?;
+
+    scuba_sample.add(
+        "backsync_duration_ms",
+        u64::try_from(start_instant.elapsed().as_millis()).unwrap_or(u64::max_value()),
+    );
+    scuba_sample.add("backsync_previously_done", !success);
+    scuba_sample.log_with_msg("Backsyncing", None);
+
+    if success {
+        let renamed_bookmark = rename_bookmark(
             commit_syncer,
-            target_repo_dbs.clone(),
-            Some(counter),
-            entry,
+            &entry.bookmark_name,
+            target_repo_dbs.sync_metadata_entries,
         )
         .await?;
+        let kind = match renamed_bookmark {
+            Some(_) => BacksyncOutcomeKind::Synced,
+            None => BacksyncOutcomeKind::RenamedAway,
+        };
+        let outcome = BacksyncOutcome::new(commit_syncer, entry, kind, rewritten);
+        return Ok((entry_id, outcome));
+    }
 
-        scuba_sample.add(
-            "backsync_duration_ms",
-            u64::try_from(start_instant.elapsed().as_millis()).unwrap_or(u64::max_value()),
+    debug!(
+        ctx.logger(),
+        "failed to backsync {}, most likely another process already synced it ", entry_id
+    );
+    // Transaction failed, it could be because another process already backsynced it
+    // Verify that counter was moved and continue if that's the case
+
+    let source_repo_id = commit_syncer.get_source_repo().get_repoid();
+    let target_repo_id = commit_syncer.get_target_repo().get_repoid();
+    let counter_name = format_counter(&source_repo_id);
+    let new_counter = target_repo_dbs
+        .counters
+        .get_counter(ctx.clone(), target_repo_id, &counter_name)
+        .compat()
+        .await
+        .map_err(BacksyncError::TransientStorage)?
+        .unwrap_or(0);
+    if new_counter > counter {
+        debug!(
+            ctx.logger(),
+            "verified that another process has already synced {}", entry_id
         );
-        scuba_sample.add("backsync_previously_done", !success);
-        scuba_sample.log_with_msg("Backsyncing", None);
+        let outcome = BacksyncOutcome::new(
+            commit_syncer,
+            entry,
+            BacksyncOutcomeKind::AlreadySynced,
+            rewritten,
+        );
+        return Ok((new_counter, outcome));
+    }
 
-        if success {
-            counter = new_counter;
-        } else {
-            debug!(
+    // Nobody else synced it, so the bookmark in the target repo isn't
+    // where this entry expects it to be.
+    let err = Error::from(BacksyncError::BookmarkRace {
+        counter,
+        new_counter,
+    });
+    let conflict = BacksyncConflict::new(commit_syncer, entry, ConflictKind::BookmarkMove, &err);
+    match options.conflict_policy.resolve(ctx, &conflict).await? {
+        ConflictResolution::Fail => Err(err),
+        ConflictResolution::Skip => {
+            warn!(
                 ctx.logger(),
-                "failed to backsync {}, most likely another process already synced it ", entry_id
+                "skipping {}, entry id {} because of a conflict: {:#}",
+                entry.bookmark_name,
+                entry_id,
+                err
             );
-            // Transaction failed, it could be because another process already backsynced it
-            // Verify that counter was moved and continue if that's the case
-
-            let source_repo_id = commit_syncer.get_source_repo().get_repoid();
-            let target_repo_id = commit_syncer.get_target_repo().get_repoid();
-            let counter_name = format_counter(&source_repo_id);
-            let new_counter = target_repo_dbs
-                .counters
-                .get_counter(ctx.clone(), target_repo_id, &counter_name)
-                .compat()
-                .await?
-                .unwrap_or(0);
-            if new_counter <= counter {
-                return Err(format_err!(
-                    "backsync transaction failed, but the counter didn't move forward. Was {}, became {}",
-                    counter,
-                    new_counter,
-                ));
-            } else {
-                debug!(
-                    ctx.logger(),
-                    "verified that another process has already synced {}", entry_id
-                );
-                counter = new_counter;
+            skip_entry(
+                ctx,
+                commit_syncer,
+                target_repo_dbs,
+                SyncDirection::Backsync,
+                entry_id,
+                counter,
+                Some((options.conflict_policy.as_ref(), &conflict)),
+            )
+            .await?;
+            let outcome = BacksyncOutcome::new(
+                commit_syncer,
+                entry,
+                BacksyncOutcomeKind::SkippedConflict,
+                rewritten,
+            )
+            .with_error(&err);
+            Ok((entry_id, outcome))
+        }
+        ConflictResolution::PreferSource => {
+            warn!(
+                ctx.logger(),
+                "forcing {} to its source repo position, entry id {}",
+                entry.bookmark_name,
+                entry_id
+            );
+            let success = backsync_bookmark(
+                ctx.clone(),
+                commit_syncer,
+                target_repo_dbs.clone(),
+                SyncDirection::Backsync,
+                Some(counter),
+                entry.clone(),
+                true,
+            )
+            .await?;
+            if !success {
+                return Err(err);
             }
+            let outcome =
+                BacksyncOutcome::new(commit_syncer, entry, BacksyncOutcomeKind::Synced, rewritten);
+            Ok((entry_id, outcome))
         }
     }
-    Ok(())
 }
 
+/// Outcomes are only recorded for observability, so failing to record one doesn't fail
+/// backsync.
+async fn record_outcome(
+    ctx: &CoreContext,
+    target_repo_dbs: &TargetRepoDbs,
+    outcome: &BacksyncOutcome,
+) {
+    if let Err(err) = target_repo_dbs.outcomes.add(ctx, outcome).await {
+        warn!(
+            ctx.logger(),
+            "failed to record backsync outcome of entry {}: {:#}", outcome.entry_id, err
+        );
+    }
+}
+
+/// Move the counter past an entry without backsyncing it. If the entry is skipped because
+/// of a conflict, the conflict policy records the skip in the same transaction, so that
+/// there is a record for every conflicting entry the counter moved past. Returns false if
+/// the counter wasn't at `counter`, i.e. another process has moved it.
+async fn skip_entry<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: &TargetRepoDbs,
+    direction: SyncDirection,
+    entry_id: i64,
+    counter: i64,
+    conflict: Option<(&dyn ConflictPolicy, &BacksyncConflict)>,
+) -> Result<bool, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let txn = target_repo_dbs
+        .connections
+        .write_connection
+        .start_transaction()
+        .await
+        .map_err(BacksyncError::TransientStorage)?;
+    let txn = SqlMutableCounters::set_counter_on_txn(
+        ctx.clone(),
+        commit_syncer.get_target_repo().get_repoid(),
+        &direction.counter_name(&commit_syncer.get_source_repo().get_repoid()),
+        entry_id,
+        Some(counter),
+        txn,
+    )
+    .await
+    .map_err(BacksyncError::TransientStorage)?;
+    let txn = match txn {
+        TransactionResult::Succeeded(txn) => txn,
+        TransactionResult::Failed => return Ok(false),
+    };
+    let txn = match conflict {
+        Some((conflict_policy, conflict)) => conflict_policy
+            .on_skip(ctx, conflict, txn)
+            .await
+            .map_err(BacksyncError::TransientStorage)?,
+        None => txn,
+    };
+    txn.commit()
+        .await
+        .map_err(BacksyncError::TransientStorage)?;
+    Ok(true)
+}
+
+/// Move the bookmark in the target repo and the counter in one transaction. With `force`,
+/// the bookmark is moved regardless of where it currently points.
 async fn backsync_bookmark<M>(
     ctx: CoreContext,
     commit_syncer: &CommitSyncer<M>,
     target_repo_dbs: TargetRepoDbs,
+    direction: SyncDirection,
     prev_counter: Option<i64>,
     log_entry: BookmarkUpdateLogEntry,
+    force: bool,
 ) -> Result<bool, Error>
 where
     M: SyncedCommitMapping + Clone + 'static,
 {
     let target_repo_id = commit_syncer.get_target_repo().get_repoid();
-    let source_repo_id = commit_syncer.get_source_repo().get_repoid();
+    let counter_name = direction.counter_name(&commit_syncer.get_source_repo().get_repoid());
+    let reason = direction.bookmark_update_reason();
     let TargetRepoDbs {
         connections,
         bookmarks,
+        sync_metadata_entries,
+        post_sync_hooks,
         ..
     } = target_repo_dbs;
 
     debug!(ctx.logger(), "preparing to backsync {:?}", log_entry);
 
     let new_counter = log_entry.id;
-    let bookmark = commit_syncer.get_bookmark_renamer().await?(&log_entry.bookmark_name);
+    let bookmark = rename_bookmark(
+        commit_syncer,
+        &log_entry.bookmark_name,
+        sync_metadata_entries,
+    )
+    .await?;
     debug!(ctx.logger(), "bookmark was renamed into {:?}", bookmark);
     let from_cs_id = log_entry.from_changeset_id;
     let to_cs_id = log_entry.to_changeset_id;
-    let bundle_replay_data = log_entry.bundle_replay_data;
+    let bundle_replay_data = &log_entry.bundle_replay_data;
 
     let get_commit_sync_outcome = |maybe_cs_id: Option<ChangesetId>| {
         cloned!(ctx);
@@ -281,7 +911,7 @@ where
                     let maybe_outcome = commit_syncer.get_commit_sync_outcome(&ctx, cs_id).await?;
                     match maybe_outcome {
                         Some(outcome) => Ok(Some((outcome, cs_id))),
-                        None => Err(format_err!("{} hasn't been backsynced yet", cs_id)),
+                        None => Err(Error::from(BacksyncError::MappingMissing { cs_id })),
                     }
                 }
                 None => Ok(None),
@@ -307,12 +937,14 @@ where
         };
 
     let txn_hook = Arc::new({
+        cloned!(counter_name);
         move |ctx: CoreContext, txn: Transaction| {
+            cloned!(counter_name);
             async move {
                 let txn = SqlMutableCounters::set_counter_on_txn(
                     ctx.clone(),
                     target_repo_id,
-                    &format_counter(&source_repo_id),
+                    &counter_name,
                     new_counter,
                     prev_counter,
                     txn,
@@ -337,6 +969,8 @@ where
             "commit sync outcomes: from_cs: {:?}, to_cs: {:?}", from_sync_outcome, to_sync_outcome
         );
 
+        let version = sync_outcome_version(&to_sync_outcome)
+            .or_else(|| sync_outcome_version(&from_sync_outcome));
         let from_cs_id = get_remapped_cs_id(from_sync_outcome)?;
         let to_cs_id = get_remapped_cs_id(to_sync_outcome)?;
 
@@ -351,49 +985,60 @@ where
                 .as_ref()
                 .map(|data| data as &dyn BundleReplay);
             match (from_cs_id, to_cs_id) {
+                (_, Some(to)) if force => {
+                    debug!(
+                        ctx.logger(),
+                        "force setting bookmark {:?} to {:?}", bookmark, to
+                    );
+                    bookmark_txn.force_set(&bookmark, to, reason, bundle_replay)?;
+                }
+                (Some(_), None) if force => {
+                    debug!(ctx.logger(), "force deleting bookmark {:?}", bookmark);
+                    bookmark_txn.force_delete(&bookmark, reason, bundle_replay)?;
+                }
                 (Some(from), Some(to)) => {
                     debug!(
                         ctx.logger(),
                         "updating bookmark {:?} from {:?} to {:?}", bookmark, from, to
                     );
-                    bookmark_txn.update(
-                        &bookmark,
-                        to,
-                        from,
-                        BookmarkUpdateReason::Backsyncer,
-                        bundle_replay,
-                    )?;
+                    bookmark_txn.update(&bookmark, to, from, reason, bundle_replay)?;
                 }
                 (Some(from), None) => {
                     debug!(
                         ctx.logger(),
                         "deleting bookmark {:?} with original position {:?}", bookmark, from
                     );
-                    bookmark_txn.delete(
-                        &bookmark,
-                        from,
-                        BookmarkUpdateReason::Backsyncer,
-                        bundle_replay,
-                    )?;
+                    bookmark_txn.delete(&bookmark, from, reason, bundle_replay)?;
                 }
                 (None, Some(to)) => {
                     debug!(
                         ctx.logger(),
                         "creating bookmark {:?} to point to {:?}", bookmark, to
                     );
-                    bookmark_txn.create(
-                        &bookmark,
-                        to,
-                        BookmarkUpdateReason::Backsyncer,
-                        bundle_replay,
-                    )?;
+                    bookmark_txn.create(&bookmark, to, reason, bundle_replay)?;
                 }
                 (None, None) => {
                     bail!("unexpected bookmark move");
                 }
             };
 
-            return bookmark_txn.commit_with_hook(txn_hook).await;
+            let success = bookmark_txn
+                .commit_with_hook(txn_hook)
+                .await
+                .map_err(BacksyncError::TransientStorage)?;
+            if success {
+                run_bookmark_hooks(
+                    &ctx,
+                    commit_syncer,
+                    &post_sync_hooks,
+                    &log_entry,
+                    bookmark,
+                    (from_cs_id, to_cs_id),
+                    version,
+                )
+                .await;
+            }
+            return Ok(success);
         } else {
             debug!(
                 ctx.logger(),
@@ -410,16 +1055,31 @@ where
         .set_counter(
             ctx.clone(),
             target_repo_id,
-            &format_counter(&source_repo_id),
+            &counter_name,
             new_counter,
             prev_counter,
         )
         .compat()
-        .await?;
+        .await
+        .map_err(BacksyncError::TransientStorage)?;
 
     Ok(updated)
 }
 
+/// The version of the sync config a commit was synced with, if it has a counterpart in the
+/// target repo.
+fn sync_outcome_version(
+    outcome: &Option<(CommitSyncOutcome, ChangesetId)>,
+) -> Option<CommitSyncConfigVersion> {
+    match outcome {
+        Some((CommitSyncOutcome::RewrittenAs(_, version), _))
+        | Some((CommitSyncOutcome::EquivalentWorkingCopyAncestor(_, version), _)) => {
+            Some(version.clone())
+        }
+        Some((CommitSyncOutcome::NotSyncCandidate, _)) | None => None,
+    }
+}
+
 // TODO(stash): T56228235 - consider removing SqlMutableCounters and SqlBookmarks and use static
 // methods instead
 #[derive(Clone)]
@@ -428,6 +1088,14 @@ pub struct TargetRepoDbs {
     pub bookmarks: ArcBookmarks,
     pub bookmark_update_log: ArcBookmarkUpdateLog,
     pub counters: SqlMutableCounters,
+    pub outcomes: SqlBacksyncOutcomes,
+    pub rewrite_checkpoints: SqlRewriteCheckpoints,
+    /// Whether the target repo takes metadata entries, like tags. If it does, they are
+    /// renamed by their name without the prefix of their kind, otherwise they are renamed
+    /// like any other bookmark.
+    pub sync_metadata_entries: bool,
+    /// Called after each commit and bookmark move that was synced into the target repo.
+    pub post_sync_hooks: Vec<Arc<dyn PostSyncHook>>,
 }
 
 pub async fn open_backsyncer_dbs(
@@ -446,12 +1114,18 @@ pub async fn open_backsyncer_dbs(
         .into();
 
     let counters = SqlMutableCounters::from_sql_connections(connections.clone());
+    let outcomes = SqlBacksyncOutcomes::from_sql_connections(connections.clone());
+    let rewrite_checkpoints = SqlRewriteCheckpoints::from_sql_connections(connections.clone());
 
     Ok(TargetRepoDbs {
         connections,
         bookmarks: blobrepo.bookmarks().clone(),
         bookmark_update_log: blobrepo.bookmark_update_log().clone(),
         counters,
+        outcomes,
+        rewrite_checkpoints,
+        sync_metadata_entries: false,
+        post_sync_hooks: vec![],
     })
 }
 
@@ -9,11 +9,13 @@
 
 use anyhow::{bail, format_err, Error};
 use backsyncer::{
-    backsync_latest, format_counter, open_backsyncer_dbs, BacksyncLimit, TargetRepoDbs,
+    backsync_latest_with_options, format_counter, open_backsyncer_dbs, verify_and_fix_bookmarks,
+    BacksyncLimit, BacksyncOptions, BacksyncProgress, ConflictPolicy, FailOnConflict,
+    PreferSourceOnConflict, SkipAndRecordConflicts, SqlSkippedBacksyncEntries, TargetRepoDbs,
 };
 use blobrepo_hg::BlobRepoHg;
-use bookmarks::Freshness;
-use clap::{Arg, SubCommand};
+use bookmarks::{BookmarkName, Freshness};
+use clap::{Arg, ArgMatches, SubCommand};
 use cloned::cloned;
 use cmdlib::{args, helpers, monitoring};
 use cmdlib_x_repo::create_commit_syncer_from_matches;
@@ -28,10 +30,13 @@ use futures::{
 };
 use live_commit_sync_config::{CfgrLiveCommitSyncConfig, LiveCommitSyncConfig};
 use mercurial_types::HgChangesetId;
+use metaconfig_types::BookmarkOrRegex;
 use mononoke_types::ChangesetId;
 use mutable_counters::MutableCounters;
+use regex::Regex;
 use scuba_ext::MononokeScubaSampleBuilder;
-use slog::{debug, info};
+use slog::{debug, info, warn};
+use sql_construct::SqlConstruct;
 use stats::prelude::*;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -43,10 +48,26 @@ use synced_commit_mapping::{SqlSyncedCommitMapping, SyncedCommitMapping};
 const ARG_MODE_BACKSYNC_FOREVER: &str = "backsync-forever";
 const ARG_MODE_BACKSYNC_ALL: &str = "backsync-all";
 const ARG_MODE_BACKSYNC_COMMITS: &str = "backsync-commits";
+const ARG_MODE_VERIFY_BOOKMARKS: &str = "verify-bookmarks";
 const ARG_BATCH_SIZE: &str = "batch-size";
+const ARG_PARALLELISM: &str = "parallelism";
+const ARG_BOOKMARK: &str = "bookmark";
+const ARG_BOOKMARK_REGEX: &str = "bookmark-regex";
+const ARG_ON_CONFLICT: &str = "on-conflict";
+const ARG_SYNC_METADATA_ENTRIES: &str = "sync-metadata-entries";
+const ON_CONFLICT_FAIL: &str = "fail";
+const ON_CONFLICT_SKIP: &str = "skip";
+const ON_CONFLICT_PREFER_SOURCE: &str = "prefer-source";
 const ARG_INPUT_FILE: &str = "INPUT_FILE";
+const ARG_FIX: &str = "fix";
 const SCUBA_TABLE: &str = "mononoke_xrepo_backsync";
 
+/// Backsync is retried after a transient error, waiting twice as long after each
+/// consecutive failure, until it has failed this many times in a row.
+const MAX_TRANSIENT_RETRIES: u32 = 10;
+const TRANSIENT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const TRANSIENT_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
 define_stats! {
     prefix = "mononoke.backsyncer";
     remaining_entries: dynamic_singleton_counter(
@@ -57,6 +78,10 @@ define_stats! {
         "{}.{}.delay_secs",
         (source_repo_name: String, target_repo_name: String)
     ),
+    commits_rewritten_per_sec: dynamic_singleton_counter(
+        "{}.{}.commits_rewritten_per_sec",
+        (source_repo_name: String, target_repo_name: String)
+    ),
 }
 
 fn extract_cs_id_from_sync_outcome(
@@ -104,12 +129,18 @@ pub async fn backsync_forever<M>(
     source_repo_name: String,
     target_repo_name: String,
     live_commit_sync_config: CfgrLiveCommitSyncConfig,
+    mut options: BacksyncOptions,
 ) -> Result<(), Error>
 where
     M: SyncedCommitMapping + Clone + 'static,
 {
     let target_repo_id = commit_syncer.get_target_repo_id();
     let live_commit_sync_config = Arc::new(live_commit_sync_config);
+    let progress = options
+        .progress
+        .get_or_insert_with(BacksyncProgress::new)
+        .clone();
+    let mut transient_failures = 0;
 
     loop {
         // We only care about public pushes because draft pushes are not in the bookmark
@@ -125,13 +156,34 @@ where
             } else {
                 debug!(ctx.logger(), "backsyncing...");
 
-                backsync_latest(
+                let res = backsync_latest_with_options(
                     ctx.clone(),
                     commit_syncer.clone(),
                     target_repo_dbs.clone(),
                     BacksyncLimit::NoLimit,
+                    options.clone(),
                 )
-                .await?
+                .await;
+                STATS::commits_rewritten_per_sec.set_value(
+                    ctx.fb,
+                    progress.snapshot().commits_rewritten_per_sec as i64,
+                    (source_repo_name.clone(), target_repo_name.clone()),
+                );
+                match res {
+                    Ok(()) => transient_failures = 0,
+                    Err(err)
+                        if err.is_transient() && transient_failures < MAX_TRANSIENT_RETRIES =>
+                    {
+                        let delay = transient_retry_delay(transient_failures);
+                        transient_failures += 1;
+                        warn!(
+                            ctx.logger(),
+                            "backsync failed, retrying in {:?}: {:#}", delay, err
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
             }
         } else {
             debug!(ctx.logger(), "push redirector is disabled");
@@ -142,6 +194,13 @@ where
     }
 }
 
+/// How long to wait before retrying backsync after `failures` consecutive transient
+/// errors.
+fn transient_retry_delay(failures: u32) -> Duration {
+    let delay = TRANSIENT_RETRY_BASE_DELAY.saturating_mul(1 << failures.min(16));
+    std::cmp::min(delay, TRANSIENT_RETRY_MAX_DELAY)
+}
+
 struct Delay {
     delay_secs: i64,
     remaining_entries: u64,
@@ -212,6 +271,39 @@ fn log_delay(
     );
 }
 
+fn get_backsync_options(
+    sub_m: &ArgMatches<'_>,
+    target_repo_dbs: &TargetRepoDbs,
+) -> Result<BacksyncOptions, Error> {
+    let mut bookmark_filter: Vec<BookmarkOrRegex> = vec![];
+    for bookmark in sub_m.values_of(ARG_BOOKMARK).into_iter().flatten() {
+        bookmark_filter.push(BookmarkName::new(bookmark)?.into());
+    }
+    for regex in sub_m.values_of(ARG_BOOKMARK_REGEX).into_iter().flatten() {
+        bookmark_filter.push(Regex::new(regex)?.into());
+    }
+
+    let conflict_policy: Arc<dyn ConflictPolicy> = match sub_m.value_of(ARG_ON_CONFLICT) {
+        None | Some(ON_CONFLICT_FAIL) => Arc::new(FailOnConflict),
+        Some(ON_CONFLICT_SKIP) => Arc::new(SkipAndRecordConflicts::new(
+            SqlSkippedBacksyncEntries::from_sql_connections(target_repo_dbs.connections.clone()),
+        )),
+        Some(ON_CONFLICT_PREFER_SOURCE) => Arc::new(PreferSourceOnConflict),
+        Some(other) => bail!("unknown conflict policy {}", other),
+    };
+
+    Ok(BacksyncOptions {
+        parallelism: args::get_usize(sub_m, ARG_PARALLELISM, 1),
+        conflict_policy,
+        bookmark_filter: if bookmark_filter.is_empty() {
+            None
+        } else {
+            Some(bookmark_filter)
+        },
+        ..Default::default()
+    })
+}
+
 #[fbinit::main]
 fn main(fb: FacebookInit) -> Result<(), Error> {
     let app_name = "backsyncer cmd-line tool";
@@ -219,8 +311,63 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
         .with_fb303_args()
         .with_source_and_target_repos()
         .build();
-    let backsync_forever_subcommand =
-        SubCommand::with_name(ARG_MODE_BACKSYNC_FOREVER).about("Backsyncs all new bookmark moves");
+    let parallelism_arg = Arg::with_name(ARG_PARALLELISM)
+        .long(ARG_PARALLELISM)
+        .takes_value(true)
+        .required(false)
+        .help(
+            "how many bookmark update log entries to rewrite commits for at once. \
+            Entries for the same bookmark are never rewritten concurrently",
+        );
+    let bookmark_arg = Arg::with_name(ARG_BOOKMARK)
+        .long(ARG_BOOKMARK)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(false)
+        .help(
+            "only backsync bookmark update log entries for this bookmark. \
+            Can be combined with --bookmark-regex",
+        );
+    let bookmark_regex_arg = Arg::with_name(ARG_BOOKMARK_REGEX)
+        .long(ARG_BOOKMARK_REGEX)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(false)
+        .help(
+            "only backsync bookmark update log entries for bookmarks matching this regex. \
+            Can be combined with --bookmark",
+        );
+    let on_conflict_arg = Arg::with_name(ARG_ON_CONFLICT)
+        .long(ARG_ON_CONFLICT)
+        .takes_value(true)
+        .possible_values(&[
+            ON_CONFLICT_FAIL,
+            ON_CONFLICT_SKIP,
+            ON_CONFLICT_PREFER_SOURCE,
+        ])
+        .default_value(ON_CONFLICT_FAIL)
+        .help(
+            "what to do with bookmark update log entries that can't be applied to the \
+            target repo: fail, skip them and record them for later reconciliation, or \
+            force the target repo bookmark to the source repo position",
+        );
+    let sync_metadata_entries_arg = Arg::with_name(ARG_SYNC_METADATA_ENTRIES)
+        .long(ARG_SYNC_METADATA_ENTRIES)
+        .takes_value(false)
+        .required(false)
+        .help(
+            "rename metadata entries, like tags/NAME, by NAME only, for target repos that \
+            take them. Otherwise they are renamed like any other bookmark",
+        );
+    let backsync_forever_subcommand = SubCommand::with_name(ARG_MODE_BACKSYNC_FOREVER)
+        .about("Backsyncs all new bookmark moves")
+        .arg(parallelism_arg.clone())
+        .arg(bookmark_arg.clone())
+        .arg(bookmark_regex_arg.clone())
+        .arg(on_conflict_arg.clone())
+        .arg(sync_metadata_entries_arg.clone());
 
     let sync_loop = SubCommand::with_name(ARG_MODE_BACKSYNC_COMMITS)
         .about("Syncs all commits from the file")
@@ -238,12 +385,27 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 .help("how many commits to backsync at once"),
         );
 
-    let backsync_all_subcommand =
-        SubCommand::with_name(ARG_MODE_BACKSYNC_ALL).about("Backsyncs all new bookmark moves once");
+    let backsync_all_subcommand = SubCommand::with_name(ARG_MODE_BACKSYNC_ALL)
+        .about("Backsyncs all new bookmark moves once")
+        .arg(parallelism_arg)
+        .arg(bookmark_arg)
+        .arg(bookmark_regex_arg)
+        .arg(on_conflict_arg)
+        .arg(sync_metadata_entries_arg);
+    let verify_bookmarks_subcommand = SubCommand::with_name(ARG_MODE_VERIFY_BOOKMARKS)
+        .about("Checks that target repo bookmarks match the backsynced source repo bookmarks")
+        .arg(
+            Arg::with_name(ARG_FIX)
+                .long(ARG_FIX)
+                .takes_value(false)
+                .required(false)
+                .help("move the inconsistent target repo bookmarks to the backsynced commits"),
+        );
     let app = app
         .subcommand(backsync_all_subcommand)
         .subcommand(backsync_forever_subcommand)
-        .subcommand(sync_loop);
+        .subcommand(sync_loop)
+        .subcommand(verify_bookmarks_subcommand);
     let matches = app.get_matches(fb)?;
 
     let logger = matches.logger();
@@ -276,11 +438,11 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
     let live_commit_sync_config = CfgrLiveCommitSyncConfig::new(&logger, config_store)?;
 
     match matches.subcommand() {
-        (ARG_MODE_BACKSYNC_ALL, _) => {
+        (ARG_MODE_BACKSYNC_ALL, Some(sub_m)) => {
             let scuba_sample = MononokeScubaSampleBuilder::with_discard();
             let ctx = session_container.new_context(logger.clone(), scuba_sample);
             let db_config = target_repo_config.storage_config.metadata;
-            let target_repo_dbs = runtime.block_on(
+            let mut target_repo_dbs = runtime.block_on(
                 open_backsyncer_dbs(
                     ctx.clone(),
                     commit_syncer.get_target_repo().clone(),
@@ -290,18 +452,27 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 )
                 .boxed(),
             )?;
+            target_repo_dbs.sync_metadata_entries = sub_m.is_present(ARG_SYNC_METADATA_ENTRIES);
+
+            let options = get_backsync_options(sub_m, &target_repo_dbs)?;
 
             // TODO(ikostia): why do we use discarding ScubaSample for BACKSYNC_ALL?
             runtime.block_on(
-                backsync_latest(ctx, commit_syncer, target_repo_dbs, BacksyncLimit::NoLimit)
-                    .boxed(),
+                backsync_latest_with_options(
+                    ctx,
+                    commit_syncer,
+                    target_repo_dbs,
+                    BacksyncLimit::NoLimit,
+                    options,
+                )
+                .boxed(),
             )?;
         }
-        (ARG_MODE_BACKSYNC_FOREVER, _) => {
+        (ARG_MODE_BACKSYNC_FOREVER, Some(sub_m)) => {
             let db_config = target_repo_config.storage_config.metadata;
             let ctx = session_container
                 .new_context(logger.clone(), MononokeScubaSampleBuilder::with_discard());
-            let target_repo_dbs = runtime.block_on(
+            let mut target_repo_dbs = runtime.block_on(
                 open_backsyncer_dbs(
                     ctx,
                     commit_syncer.get_target_repo().clone(),
@@ -311,6 +482,8 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 )
                 .boxed(),
             )?;
+            target_repo_dbs.sync_metadata_entries = sub_m.is_present(ARG_SYNC_METADATA_ENTRIES);
+            let options = get_backsync_options(sub_m, &target_repo_dbs)?;
 
             let mut scuba_sample = MononokeScubaSampleBuilder::new(fb, SCUBA_TABLE);
             scuba_sample.add("source_repo", source_repo_id.id());
@@ -327,6 +500,7 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 source_repo_name,
                 target_repo_name,
                 live_commit_sync_config,
+                options,
             )
             .boxed();
 
@@ -412,6 +586,29 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
 
             runtime.block_on(f)?;
         }
+        (ARG_MODE_VERIFY_BOOKMARKS, Some(sub_m)) => {
+            let ctx = session_container
+                .new_context(logger.clone(), MononokeScubaSampleBuilder::with_discard());
+            let db_config = target_repo_config.storage_config.metadata;
+            let target_repo_dbs = runtime.block_on(
+                open_backsyncer_dbs(
+                    ctx.clone(),
+                    commit_syncer.get_target_repo().clone(),
+                    db_config,
+                    mysql_options.clone(),
+                    *readonly_storage,
+                )
+                .boxed(),
+            )?;
+
+            let fix = sub_m.is_present(ARG_FIX);
+            let diff = runtime.block_on(
+                verify_and_fix_bookmarks(ctx, &commit_syncer, target_repo_dbs, !fix).boxed(),
+            )?;
+            if !diff.is_empty() && !fix {
+                bail!("found {} inconsistencies", diff.len());
+            }
+        }
         _ => {
             bail!("unknown subcommand");
         }
@@ -0,0 +1,78 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Metadata entries are bookmark update log entries for refs that label commits rather
+//! than track a line of development, like tags and notes. They live under a fixed prefix
+//! in both repos, so only the part of their name after the prefix is renamed on backsync.
+
+use anyhow::Error;
+use bookmarks::BookmarkName;
+use cross_repo_sync::CommitSyncer;
+use synced_commit_mapping::SyncedCommitMapping;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataKind {
+    Tag,
+    Note,
+}
+
+impl MetadataKind {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            MetadataKind::Tag => "tags/",
+            MetadataKind::Note => "notes/",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetadataEntry {
+    pub kind: MetadataKind,
+    /// The name of the entry, without the prefix of its kind.
+    pub name: BookmarkName,
+}
+
+impl MetadataEntry {
+    /// The metadata entry that `bookmark` refers to, if it refers to one.
+    pub fn from_bookmark(bookmark: &BookmarkName) -> Option<Self> {
+        [MetadataKind::Tag, MetadataKind::Note]
+            .iter()
+            .find_map(|kind| {
+                let name = bookmark.as_str().strip_prefix(kind.prefix())?;
+                Some(MetadataEntry {
+                    kind: *kind,
+                    name: BookmarkName::new(name).ok()?,
+                })
+            })
+    }
+
+    pub fn to_bookmark(&self) -> Result<BookmarkName, Error> {
+        BookmarkName::new(format!("{}{}", self.kind.prefix(), self.name))
+    }
+}
+
+/// The name of the bookmark that a log entry for `bookmark` moves in the target repo, or
+/// `None` if it is renamed away. With `sync_metadata_entries`, metadata entries keep their
+/// prefix and only their name is renamed, so that e.g. a tag on a large repo bookmark
+/// becomes the same tag on the small repo bookmark.
+pub(crate) async fn rename_bookmark<M>(
+    commit_syncer: &CommitSyncer<M>,
+    bookmark: &BookmarkName,
+    sync_metadata_entries: bool,
+) -> Result<Option<BookmarkName>, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let renamer = commit_syncer.get_bookmark_renamer().await?;
+    match MetadataEntry::from_bookmark(bookmark) {
+        Some(entry) if sync_metadata_entries => match renamer(&entry.name) {
+            Some(name) => Ok(Some(MetadataEntry { name, ..entry }.to_bookmark()?)),
+            None => Ok(None),
+        },
+        _ => Ok(renamer(bookmark)),
+    }
+}
@@ -0,0 +1,278 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! What happened to each bookmark update log entry that backsync processed. This answers
+//! questions like "why didn't commit X show up in the small repo" after the fact.
+
+use anyhow::{format_err, Error};
+use bookmarks::{BookmarkName, BookmarkUpdateLogEntry};
+use context::{CoreContext, PerfCounterType};
+use cross_repo_sync::CommitSyncer;
+use mononoke_types::{ChangesetId, RepositoryId, Timestamp};
+use sql::{queries, Connection};
+use sql_construct::{SqlConstruct, SqlConstructFromMetadataDatabaseConfig};
+use sql_ext::SqlConnections;
+use std::str::FromStr;
+use synced_commit_mapping::SyncedCommitMapping;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BacksyncOutcomeKind {
+    /// The entry's commits were rewritten and its bookmark was moved in the target repo.
+    Synced,
+    /// None of the ancestors of the commit the entry points to was ever synced, so the
+    /// entry was skipped.
+    Unrelated,
+    /// The commits were rewritten, but the bookmark is renamed away in the target repo,
+    /// so no bookmark was moved.
+    RenamedAway,
+    /// The entry was skipped by the conflict policy.
+    SkippedConflict,
+    /// Another process backsynced the entry first.
+    AlreadySynced,
+    /// Backsyncing the entry failed.
+    Error,
+}
+
+impl BacksyncOutcomeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BacksyncOutcomeKind::Synced => "synced",
+            BacksyncOutcomeKind::Unrelated => "unrelated",
+            BacksyncOutcomeKind::RenamedAway => "renamed_away",
+            BacksyncOutcomeKind::SkippedConflict => "skipped_conflict",
+            BacksyncOutcomeKind::AlreadySynced => "already_synced",
+            BacksyncOutcomeKind::Error => "error",
+        }
+    }
+}
+
+impl FromStr for BacksyncOutcomeKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "synced" => Ok(BacksyncOutcomeKind::Synced),
+            "unrelated" => Ok(BacksyncOutcomeKind::Unrelated),
+            "renamed_away" => Ok(BacksyncOutcomeKind::RenamedAway),
+            "skipped_conflict" => Ok(BacksyncOutcomeKind::SkippedConflict),
+            "already_synced" => Ok(BacksyncOutcomeKind::AlreadySynced),
+            "error" => Ok(BacksyncOutcomeKind::Error),
+            _ => Err(format_err!("unknown backsync outcome: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BacksyncOutcome {
+    pub source_repo_id: RepositoryId,
+    pub target_repo_id: RepositoryId,
+    pub entry_id: i64,
+    pub bookmark: BookmarkName,
+    pub to_changeset_id: Option<ChangesetId>,
+    pub kind: BacksyncOutcomeKind,
+    /// Number of commits rewritten for the entry.
+    pub rewritten_commits: u64,
+    /// Why the entry was skipped or failed.
+    pub message: Option<String>,
+    pub created_at: Timestamp,
+}
+
+impl BacksyncOutcome {
+    pub(crate) fn new<M>(
+        commit_syncer: &CommitSyncer<M>,
+        entry: &BookmarkUpdateLogEntry,
+        kind: BacksyncOutcomeKind,
+        rewritten_commits: u64,
+    ) -> Self
+    where
+        M: SyncedCommitMapping + Clone + 'static,
+    {
+        Self {
+            source_repo_id: commit_syncer.get_source_repo().get_repoid(),
+            target_repo_id: commit_syncer.get_target_repo().get_repoid(),
+            entry_id: entry.id,
+            bookmark: entry.bookmark_name.clone(),
+            to_changeset_id: entry.to_changeset_id,
+            kind,
+            rewritten_commits,
+            message: None,
+            created_at: Timestamp::now(),
+        }
+    }
+
+    pub(crate) fn with_error(mut self, error: &Error) -> Self {
+        self.message = Some(format!("{:#}", error));
+        self
+    }
+}
+
+queries! {
+    write AddOutcome(values: (
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        entry_id: i64,
+        bookmark: BookmarkName,
+        to_changeset_id: Option<ChangesetId>,
+        outcome: &str,
+        rewritten_commits: u64,
+        message: Option<&str>,
+        created_at: Timestamp,
+    )) {
+        none,
+        "INSERT INTO backsync_outcomes
+         (source_repo_id, target_repo_id, entry_id, bookmark, to_changeset_id, outcome,
+          rewritten_commits, message, created_at)
+         VALUES {values}"
+    }
+
+    read ListRecentOutcomes(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        limit: u64,
+    ) -> (i64, BookmarkName, Option<ChangesetId>, String, u64, Option<String>, Timestamp) {
+        "SELECT entry_id, bookmark, to_changeset_id, outcome, rewritten_commits, message,
+                created_at
+         FROM backsync_outcomes
+         WHERE source_repo_id = {source_repo_id} AND target_repo_id = {target_repo_id}
+         ORDER BY id DESC
+         LIMIT {limit}"
+    }
+
+    read ListEntryOutcomes(
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        entry_id: i64,
+    ) -> (i64, BookmarkName, Option<ChangesetId>, String, u64, Option<String>, Timestamp) {
+        "SELECT entry_id, bookmark, to_changeset_id, outcome, rewritten_commits, message,
+                created_at
+         FROM backsync_outcomes
+         WHERE source_repo_id = {source_repo_id}
+           AND target_repo_id = {target_repo_id}
+           AND entry_id = {entry_id}
+         ORDER BY id"
+    }
+}
+
+/// Side table with the outcome of every log entry processed by backsync. An entry that
+/// is retried after an error gets one row per attempt.
+#[derive(Clone)]
+pub struct SqlBacksyncOutcomes {
+    write_connection: Connection,
+    read_master_connection: Connection,
+}
+
+impl SqlConstruct for SqlBacksyncOutcomes {
+    const LABEL: &'static str = "backsync_outcomes";
+
+    const CREATION_QUERY: &'static str = include_str!("../schemas/sqlite-backsync-outcomes.sql");
+
+    fn from_sql_connections(connections: SqlConnections) -> Self {
+        Self {
+            write_connection: connections.write_connection,
+            read_master_connection: connections.read_master_connection,
+        }
+    }
+}
+
+impl SqlConstructFromMetadataDatabaseConfig for SqlBacksyncOutcomes {}
+
+type OutcomeRow = (
+    i64,
+    BookmarkName,
+    Option<ChangesetId>,
+    String,
+    u64,
+    Option<String>,
+    Timestamp,
+);
+
+impl SqlBacksyncOutcomes {
+    pub async fn add(&self, ctx: &CoreContext, outcome: &BacksyncOutcome) -> Result<(), Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlWrites);
+        AddOutcome::query(
+            &self.write_connection,
+            &[(
+                &outcome.source_repo_id,
+                &outcome.target_repo_id,
+                &outcome.entry_id,
+                &outcome.bookmark,
+                &outcome.to_changeset_id,
+                &outcome.kind.as_str(),
+                &outcome.rewritten_commits,
+                &outcome.message.as_deref(),
+                &outcome.created_at,
+            )],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The last `limit` outcomes recorded while backsyncing from `source_repo_id` into
+    /// `target_repo_id`, newest first.
+    pub async fn list_recent(
+        &self,
+        ctx: &CoreContext,
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        limit: u64,
+    ) -> Result<Vec<BacksyncOutcome>, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = ListRecentOutcomes::query(
+            &self.read_master_connection,
+            &source_repo_id,
+            &target_repo_id,
+            &limit,
+        )
+        .await?;
+        rows.into_iter()
+            .map(|row| outcome_from_row(source_repo_id, target_repo_id, row))
+            .collect()
+    }
+
+    /// All outcomes recorded for log entry `entry_id`, oldest first.
+    pub async fn list_for_entry(
+        &self,
+        ctx: &CoreContext,
+        source_repo_id: RepositoryId,
+        target_repo_id: RepositoryId,
+        entry_id: i64,
+    ) -> Result<Vec<BacksyncOutcome>, Error> {
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlReadsMaster);
+        let rows = ListEntryOutcomes::query(
+            &self.read_master_connection,
+            &source_repo_id,
+            &target_repo_id,
+            &entry_id,
+        )
+        .await?;
+        rows.into_iter()
+            .map(|row| outcome_from_row(source_repo_id, target_repo_id, row))
+            .collect()
+    }
+}
+
+fn outcome_from_row(
+    source_repo_id: RepositoryId,
+    target_repo_id: RepositoryId,
+    row: OutcomeRow,
+) -> Result<BacksyncOutcome, Error> {
+    let (entry_id, bookmark, to_changeset_id, kind, rewritten_commits, message, created_at) = row;
+    Ok(BacksyncOutcome {
+        source_repo_id,
+        target_repo_id,
+        entry_id,
+        bookmark,
+        to_changeset_id,
+        kind: kind.parse()?,
+        rewritten_commits,
+        message,
+        created_at,
+    })
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Error;
+
+/// Shared handle that backsync updates as it goes, so that the health of a backsync loop
+/// can be exported while it runs.
+#[derive(Clone, Default)]
+pub struct BacksyncProgress {
+    inner: Arc<Mutex<ProgressState>>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    snapshot: BacksyncProgressSnapshot,
+    run_started: Option<Instant>,
+    run_rewritten_commits: u64,
+}
+
+/// Point-in-time view of a `BacksyncProgress`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BacksyncProgressSnapshot {
+    /// Latest backsynced log id, if the counter has been read.
+    pub counter: Option<i64>,
+    /// Id of the newest log entry seen by the current run. With a `BacksyncLimit` this
+    /// is only the newest entry within the limit.
+    pub latest_log_id: Option<i64>,
+    /// Log entries seen by the current run that haven't been backsynced yet.
+    pub remaining_entries: u64,
+    /// Commits rewritten per second, averaged over the current run.
+    pub commits_rewritten_per_sec: f64,
+    /// Total number of commits rewritten through this handle.
+    pub total_rewritten_commits: u64,
+    /// The error that most recently stopped a run, if any. It is kept after later runs
+    /// succeed.
+    pub last_error: Option<String>,
+}
+
+impl BacksyncProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> BacksyncProgressSnapshot {
+        self.inner.lock().expect("lock poisoned").snapshot.clone()
+    }
+
+    pub(crate) fn start_run(&self, counter: i64) {
+        let mut state = self.inner.lock().expect("lock poisoned");
+        state.run_started = Some(Instant::now());
+        state.run_rewritten_commits = 0;
+        state.snapshot.counter = Some(counter);
+        state.snapshot.latest_log_id = None;
+        state.snapshot.remaining_entries = 0;
+        state.snapshot.commits_rewritten_per_sec = 0.0;
+    }
+
+    pub(crate) fn set_pending_entries(&self, latest_log_id: Option<i64>, remaining_entries: u64) {
+        let mut state = self.inner.lock().expect("lock poisoned");
+        state.snapshot.latest_log_id = latest_log_id;
+        state.snapshot.remaining_entries = remaining_entries;
+    }
+
+    pub(crate) fn record_rewritten_commits(&self, commits: u64) {
+        let mut state = self.inner.lock().expect("lock poisoned");
+        state.run_rewritten_commits += commits;
+        state.snapshot.total_rewritten_commits += commits;
+        if let Some(run_started) = state.run_started {
+            let elapsed = run_started.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                state.snapshot.commits_rewritten_per_sec =
+                    state.run_rewritten_commits as f64 / elapsed;
+            }
+        }
+    }
+
+    pub(crate) fn record_counter(&self, counter: i64, remaining_entries: u64) {
+        let mut state = self.inner.lock().expect("lock poisoned");
+        state.snapshot.counter = Some(counter);
+        state.snapshot.remaining_entries = remaining_entries;
+    }
+
+    pub(crate) fn record_error(&self, error: &Error) {
+        let mut state = self.inner.lock().expect("lock poisoned");
+        state.snapshot.last_error = Some(format!("{:#}", error));
+    }
+}
@@ -7,19 +7,20 @@
 
 use anyhow::{anyhow, Error};
 use assert_matches::assert_matches;
+use async_trait::async_trait;
 use blobrepo::{save_bonsai_changesets, BlobRepo};
 use blobrepo_hg::BlobRepoHg;
 use blobstore::Loadable;
 use bookmark_renaming::BookmarkRenamer;
-use bookmarks::{BookmarkName, BookmarkUpdateReason, Freshness};
+use bookmarks::{BookmarkName, BookmarkUpdateLogEntry, BookmarkUpdateReason, Freshness};
 use cloned::cloned;
 use commit_transformation::upload_commits;
 use context::CoreContext;
 use cross_repo_sync::types::{Source, Target};
 use cross_repo_sync::{rewrite_commit, CommitSyncOutcome, CommitSyncer};
 use cross_repo_sync::{
-    CandidateSelectionHint, CommitSyncContext, CommitSyncDataProvider, CommitSyncRepos, SyncData,
-    CHANGE_XREPO_MAPPING_EXTRA,
+    CandidateSelectionHint, CommitSyncContext, CommitSyncDataProvider, CommitSyncRepos,
+    RewriteCheckpointKey, RewriteCheckpoints, SyncData, CHANGE_XREPO_MAPPING_EXTRA,
 };
 use fbinit::FacebookInit;
 use fixtures::linear;
@@ -29,19 +30,20 @@ use futures::{
 };
 use futures_ext::FbTryFutureExt;
 use manifest::{Entry, ManifestOps};
-use maplit::{btreemap, hashmap};
+use maplit::{btreemap, hashmap, hashset};
 use mercurial_types::HgChangesetId;
 use metaconfig_types::CommitSyncConfigVersion;
 use mononoke_types::RepositoryId;
-use mononoke_types::{ChangesetId, MPath};
+use mononoke_types::{hash::Blake2, ChangesetId, ContentId, MPath, Timestamp};
 use movers::Mover;
 use mutable_counters::{MutableCounters, SqlMutableCounters};
 use revset::DifferenceOfUnionsOfAncestorsNodeStream;
 use skiplist::SkiplistIndex;
 use sql_construct::SqlConstruct;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use synced_commit_mapping::{
     EquivalentWorkingCopyEntry, SqlSyncedCommitMapping, SyncedCommitMapping,
     SyncedCommitMappingEntry, SyncedCommitSourceRepo,
@@ -55,7 +57,15 @@ use tunables::with_tunables_async;
 
 use pretty_assertions::assert_eq;
 
-use crate::{backsync_latest, format_counter, sync_entries, BacksyncLimit, TargetRepoDbs};
+use crate::throttle::throttle_delay;
+use crate::{
+    backsync_latest, backsync_latest_with_options, check_rewrite_error, format_counter,
+    format_forward_counter, forward_sync_latest, split_into_batches, sync_entries,
+    verify_and_fix_bookmarks, BacksyncError, BacksyncLimit, BacksyncOptions, BacksyncOutcomeKind,
+    BacksyncProgress, BookmarkDiff, ForwardSyncOptions, MetadataEntry, MetadataKind, PostSyncHook,
+    PreferSourceOnConflict, SkipAndRecordConflicts, SqlBacksyncOutcomes, SqlRewriteCheckpoints,
+    SqlSkippedBacksyncEntries, SyncedBookmarkMove, SyncedChangeset, TargetRepoDbs,
+};
 
 const REPOMERGE_FOLDER: &str = "repomerge";
 const REPOMERGE_FILE: &str = "repomergefile";
@@ -109,6 +119,8 @@ fn test_sync_entries(fb: FacebookInit) -> Result<(), Error> {
             target_repo_dbs.clone(),
             next_log_entries.clone(),
             0,
+            &BacksyncOptions::default(),
+            &BacksyncProgress::new(),
         )
         .await?;
 
@@ -374,6 +386,324 @@ async fn backsync_unrelated_branch(fb: FacebookInit) -> Result<(), Error> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn backsync_records_outcomes(fb: FacebookInit) -> Result<(), Error> {
+    let master = BookmarkName::new("master")?;
+    let (commit_syncer, target_repo_dbs) = init_repos(
+        fb,
+        MoverType::Noop,
+        BookmarkRenamerType::Only(master.clone()),
+    )
+    .await?;
+    let source_repo = commit_syncer.get_source_repo();
+    let target_repo = commit_syncer.get_target_repo();
+
+    let ctx = CoreContext::test_mock(fb);
+    let unrelated = build_unrelated_branch(ctx.clone(), &source_repo).await;
+    let unrelated_bookmark = BookmarkName::new("otherrepo/somebook")?;
+    move_bookmark(
+        ctx.clone(),
+        source_repo.clone(),
+        &unrelated_bookmark,
+        unrelated,
+    )
+    .await?;
+    let new_master = CreateCommitContext::new(&ctx, &source_repo, vec!["master"])
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), source_repo.clone(), &master, new_master).await?;
+    let renamed_away = BookmarkName::new("renamed_away")?;
+    move_bookmark(ctx.clone(), source_repo.clone(), &renamed_away, new_master).await?;
+
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+
+    let outcomes = target_repo_dbs
+        .outcomes
+        .list_recent(&ctx, source_repo.get_repoid(), target_repo.get_repoid(), 3)
+        .await?;
+    let outcomes: Vec<_> = outcomes
+        .into_iter()
+        .map(|outcome| (outcome.bookmark, outcome.kind, outcome.rewritten_commits))
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            (renamed_away, BacksyncOutcomeKind::RenamedAway, 0),
+            (master, BacksyncOutcomeKind::Synced, 1),
+            (unrelated_bookmark, BacksyncOutcomeKind::Unrelated, 0),
+        ]
+    );
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordingHook {
+    changesets: Mutex<Vec<SyncedChangeset>>,
+    bookmark_moves: Mutex<Vec<SyncedBookmarkMove>>,
+}
+
+#[async_trait]
+impl PostSyncHook for RecordingHook {
+    async fn changeset_synced(
+        &self,
+        _ctx: &CoreContext,
+        synced: &SyncedChangeset,
+    ) -> Result<(), Error> {
+        self.changesets.lock().unwrap().push(synced.clone());
+        Ok(())
+    }
+
+    async fn bookmark_moved(
+        &self,
+        _ctx: &CoreContext,
+        moved: &SyncedBookmarkMove,
+    ) -> Result<(), Error> {
+        self.bookmark_moves.lock().unwrap().push(moved.clone());
+        Err(anyhow!("hook errors don't fail backsync"))
+    }
+}
+
+#[fbinit::test]
+async fn backsync_runs_post_sync_hooks(fb: FacebookInit) -> Result<(), Error> {
+    let master = BookmarkName::new("master")?;
+    let (commit_syncer, mut target_repo_dbs) = init_repos(
+        fb,
+        MoverType::Noop,
+        BookmarkRenamerType::Only(master.clone()),
+    )
+    .await?;
+    let source_repo = commit_syncer.get_source_repo();
+    let target_repo = commit_syncer.get_target_repo();
+
+    // Sync the history the repos were set up with before registering the hook.
+    let ctx = CoreContext::test_mock(fb);
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+    let hook = Arc::new(RecordingHook::default());
+    target_repo_dbs.post_sync_hooks.push(hook.clone());
+
+    let old_master = source_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found"))?;
+    let first = CreateCommitContext::new(&ctx, &source_repo, vec![old_master])
+        .add_file("hooked", "first")
+        .commit()
+        .await?;
+    let second = CreateCommitContext::new(&ctx, &source_repo, vec![first])
+        .add_file("hooked", "second")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), source_repo.clone(), &master, second).await?;
+    // Renamed away, so the bookmark doesn't move in the target repo.
+    let renamed_away = BookmarkName::new("renamed_away")?;
+    move_bookmark(ctx.clone(), source_repo.clone(), &renamed_away, second).await?;
+
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+
+    let target_cs_id = |cs_id| {
+        cloned!(ctx, commit_syncer);
+        async move {
+            match commit_syncer.get_commit_sync_outcome(&ctx, cs_id).await? {
+                Some(CommitSyncOutcome::RewrittenAs(target_cs_id, _))
+                | Some(CommitSyncOutcome::EquivalentWorkingCopyAncestor(target_cs_id, _)) => {
+                    Ok(target_cs_id)
+                }
+                outcome => Err(anyhow!("unexpected outcome of {}: {:?}", cs_id, outcome)),
+            }
+        }
+    };
+    let version = CommitSyncConfigVersion("TEST_VERSION_NAME".to_string());
+    let changesets = hook.changesets.lock().unwrap().clone();
+    assert_eq!(
+        changesets,
+        vec![
+            SyncedChangeset {
+                source_repo_id: source_repo.get_repoid(),
+                target_repo_id: target_repo.get_repoid(),
+                source_cs_id: first,
+                target_cs_id: target_cs_id(first).await?,
+                version: version.clone(),
+            },
+            SyncedChangeset {
+                source_repo_id: source_repo.get_repoid(),
+                target_repo_id: target_repo.get_repoid(),
+                source_cs_id: second,
+                target_cs_id: target_cs_id(second).await?,
+                version: version.clone(),
+            },
+        ]
+    );
+
+    let bookmark_moves = hook.bookmark_moves.lock().unwrap().clone();
+    assert_eq!(bookmark_moves.len(), 1);
+    let moved = &bookmark_moves[0];
+    assert_eq!(moved.source_bookmark, master);
+    assert_eq!(moved.target_bookmark, master);
+    assert_eq!(moved.source_from_cs_id, Some(old_master));
+    assert_eq!(moved.source_to_cs_id, Some(second));
+    assert_eq!(
+        moved.target_from_cs_id,
+        Some(target_cs_id(old_master).await?)
+    );
+    assert_eq!(moved.target_to_cs_id, Some(target_cs_id(second).await?));
+    assert_eq!(moved.version, Some(version));
+
+    // The failing hook didn't stop the bookmark from moving.
+    assert_eq!(
+        target_repo
+            .get_bonsai_bookmark(ctx.clone(), &master)
+            .await?,
+        moved.target_to_cs_id
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_rewrite_checkpoints(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let checkpoints = SqlRewriteCheckpoints::with_sqlite_in_memory()?;
+    let key = RewriteCheckpointKey {
+        source_repo_id: RepositoryId::new(1),
+        target_repo_id: RepositoryId::new(2),
+        source_cs_id: ChangesetId::new(Blake2::from_byte_array([1; 32])),
+        version: CommitSyncConfigVersion("TEST_VERSION_NAME".to_string()),
+    };
+    let other_version = RewriteCheckpointKey {
+        version: CommitSyncConfigVersion("TEST_VERSION_NAME_2".to_string()),
+        ..key.clone()
+    };
+    let content_ids: Vec<_> = (0..3)
+        .map(|i| ContentId::new(Blake2::from_byte_array([i; 32])))
+        .collect();
+
+    assert_eq!(checkpoints.load(&ctx, &key).await?, hashset! {});
+
+    // Saving a content again, e.g. after a failure to save the checkpoint, is fine.
+    checkpoints.save(&ctx, &key, &content_ids[..2]).await?;
+    checkpoints.save(&ctx, &key, &content_ids[1..]).await?;
+    checkpoints
+        .save(&ctx, &other_version, &content_ids[..1])
+        .await?;
+    assert_eq!(
+        checkpoints.load(&ctx, &key).await?,
+        content_ids.iter().copied().collect()
+    );
+
+    // Checkpoints of other versions are kept.
+    checkpoints.clear(&ctx, &key).await?;
+    assert_eq!(checkpoints.load(&ctx, &key).await?, hashset! {});
+    assert_eq!(
+        checkpoints.load(&ctx, &other_version).await?,
+        hashset! {content_ids[0]}
+    );
+
+    // Big checkpoints are cleared over several deletes.
+    let many_content_ids: Vec<_> = (0..2500u32)
+        .map(|i| {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&i.to_be_bytes());
+            ContentId::new(Blake2::from_byte_array(bytes))
+        })
+        .collect();
+    checkpoints.save(&ctx, &key, &many_content_ids).await?;
+    assert_eq!(checkpoints.load(&ctx, &key).await?.len(), 2500);
+    checkpoints.clear(&ctx, &key).await?;
+    assert_eq!(checkpoints.load(&ctx, &key).await?, hashset! {});
+    Ok(())
+}
+
+#[fbinit::test]
+async fn backsync_metadata_entries(fb: FacebookInit) -> Result<(), Error> {
+    let (commit_syncer, mut target_repo_dbs) = init_repos(
+        fb,
+        MoverType::Noop,
+        BookmarkRenamerType::Prefix("prefix".to_string()),
+    )
+    .await?;
+    target_repo_dbs.sync_metadata_entries = true;
+    let source_repo = commit_syncer.get_source_repo();
+    let target_repo = commit_syncer.get_target_repo();
+
+    let ctx = CoreContext::test_mock(fb);
+    let master = source_repo
+        .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("master")?)
+        .await?
+        .unwrap();
+    let tag = BookmarkName::new("tags/v1")?;
+    move_bookmark(ctx.clone(), source_repo.clone(), &tag, master).await?;
+
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+
+    // The tag keeps its prefix, and only its name is renamed.
+    let synced_master = commit_syncer
+        .get_commit_sync_outcome(&ctx, master)
+        .await?
+        .unwrap();
+    let synced_master = match synced_master {
+        CommitSyncOutcome::RewrittenAs(cs_id, _) => cs_id,
+        outcome => panic!("unexpected sync outcome {:?}", outcome),
+    };
+    assert_eq!(
+        target_repo
+            .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("tags/prefix/v1")?)
+            .await?,
+        Some(synced_master)
+    );
+    assert_eq!(
+        target_repo
+            .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("prefix/tags/v1")?)
+            .await?,
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_metadata_entry_from_bookmark() -> Result<(), Error> {
+    assert_eq!(
+        MetadataEntry::from_bookmark(&BookmarkName::new("tags/v1")?),
+        Some(MetadataEntry {
+            kind: MetadataKind::Tag,
+            name: BookmarkName::new("v1")?,
+        })
+    );
+    let note = MetadataEntry::from_bookmark(&BookmarkName::new("notes/release/v1")?).unwrap();
+    assert_eq!(note.kind, MetadataKind::Note);
+    assert_eq!(note.to_bookmark()?, BookmarkName::new("notes/release/v1")?);
+    assert_eq!(
+        MetadataEntry::from_bookmark(&BookmarkName::new("master")?),
+        None
+    );
+    Ok(())
+}
+
 #[fbinit::test]
 async fn backsync_change_mapping(fb: FacebookInit) -> Result<(), Error> {
     // Initialize source and target repos
@@ -390,6 +720,10 @@ async fn backsync_change_mapping(fb: FacebookInit) -> Result<(), Error> {
         bookmarks: target_repo.bookmarks().clone(),
         bookmark_update_log: target_repo.bookmark_update_log().clone(),
         counters: SqlMutableCounters::from_sql_connections(factory.metadata_db().clone().into()),
+        outcomes: SqlBacksyncOutcomes::with_sqlite_in_memory()?,
+        rewrite_checkpoints: SqlRewriteCheckpoints::with_sqlite_in_memory()?,
+        sync_metadata_entries: false,
+        post_sync_hooks: vec![],
     };
     init_target_repo(&ctx, &target_repo_dbs, source_repo_id, target_repo_id).await?;
 
@@ -571,32 +905,773 @@ fn noop_book_renamer(bookmark_name: &BookmarkName) -> Option<BookmarkName> {
     Some(bookmark_name.clone())
 }
 
-async fn backsync_and_verify_master_wc(
-    fb: FacebookInit,
-    commit_syncer: CommitSyncer<SqlSyncedCommitMapping>,
-    target_repo_dbs: TargetRepoDbs,
-) -> Result<(), Error> {
+#[test]
+fn test_split_into_batches() -> Result<(), Error> {
+    let entry = |id: i64, bookmark: &str| -> Result<_, Error> {
+        Ok(BookmarkUpdateLogEntry {
+            id,
+            repo_id: RepositoryId::new(0),
+            bookmark_name: BookmarkName::new(bookmark)?,
+            from_changeset_id: None,
+            to_changeset_id: None,
+            reason: BookmarkUpdateReason::TestMove,
+            timestamp: Timestamp::now(),
+            bundle_replay_data: None,
+        })
+    };
+    let entries = vec![
+        entry(1, "a")?,
+        entry(2, "b")?,
+        entry(3, "a")?,
+        entry(4, "c")?,
+        entry(5, "d")?,
+        entry(6, "e")?,
+    ];
+    let batch_ids = |parallelism| {
+        split_into_batches(entries.clone(), parallelism)
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|entry| entry.id).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        batch_ids(1),
+        vec![vec![1], vec![2], vec![3], vec![4], vec![5], vec![6]]
+    );
+    // A batch is cut short when an entry moves a bookmark already in it.
+    assert_eq!(batch_ids(3), vec![vec![1, 2], vec![3, 4, 5], vec![6]]);
+    assert_eq!(batch_ids(10), vec![vec![1, 2], vec![3, 4, 5, 6]]);
+    Ok(())
+}
+
+#[test]
+fn test_backsync_error_classification() {
+    // Classified errors are found through the context added on the way up.
+    let err = BacksyncError::from(
+        Error::from(BacksyncError::BookmarkRace {
+            counter: 1,
+            new_counter: 1,
+        })
+        .context("while backsyncing"),
+    );
+    assert_matches!(err, BacksyncError::BookmarkRace { .. });
+    assert!(err.is_transient());
+
+    let err = BacksyncError::from(Error::from(BacksyncError::TransientStorage(anyhow!(
+        "lost connection"
+    ))));
+    assert!(err.is_transient());
+    assert_eq!(err.to_string(), "lost connection");
+
+    let err = BacksyncError::from(Error::from(BacksyncError::RewriteFailed {
+        entry_id: 1,
+        source: anyhow!("bad mapping"),
+    }));
+    assert!(!err.is_transient());
+
+    let err = BacksyncError::from(anyhow!("unclassified"));
+    assert_matches!(err, BacksyncError::Other(_));
+    assert!(!err.is_transient());
+}
+
+#[test]
+fn test_check_rewrite_error() {
+    // Storage failures are retried instead of being handled as conflicts, wherever they
+    // are in the chain.
+    let io_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "blobstore timed out");
+    let err = check_rewrite_error(Error::from(io_err).context("failed to upload"));
+    assert_matches!(err, Err(BacksyncError::TransientStorage(_)));
+    assert!(err.unwrap_err().is_transient());
+
+    let err = check_rewrite_error(
+        Error::from(BacksyncError::TransientStorage(anyhow!("lost connection")))
+            .context("failed to read mapping"),
+    );
+    assert_matches!(err, Err(BacksyncError::TransientStorage(_)));
+
+    // Other failures are conflicts.
+    let err = check_rewrite_error(anyhow!("path conflict"));
+    assert_eq!(err.unwrap().to_string(), "path conflict");
+
+    let err = check_rewrite_error(Error::from(BacksyncError::MappingMissing {
+        cs_id: ChangesetId::from_bytes([1; 32]).unwrap(),
+    }));
+    assert_matches!(err, Ok(_));
+}
+
+#[test]
+fn test_throttle_delay() {
+    assert_eq!(
+        throttle_delay(10, Duration::from_secs(1), 5),
+        Duration::from_secs(1)
+    );
+    assert_eq!(
+        throttle_delay(10, Duration::from_millis(500), 100),
+        Duration::ZERO
+    );
+    assert_eq!(throttle_delay(0, Duration::ZERO, 1), Duration::ZERO);
+}
+
+#[fbinit::test]
+async fn backsync_entries_per_iteration(fb: FacebookInit) -> Result<(), Error> {
+    let (commit_syncer, target_repo_dbs) =
+        init_repos(fb, MoverType::Noop, BookmarkRenamerType::Noop).await?;
+    let ctx = CoreContext::test_mock(fb);
     let source_repo = commit_syncer.get_source_repo();
     let target_repo = commit_syncer.get_target_repo();
+    let get_counter = || {
+        target_repo_dbs
+            .counters
+            .get_counter(
+                ctx.clone(),
+                target_repo.get_repoid(),
+                &format_counter(&source_repo.get_repoid()),
+            )
+            .compat()
+    };
 
-    let ctx = CoreContext::test_mock(fb);
-    let next_log_entries: Vec<_> = commit_syncer
-        .get_source_repo()
-        .read_next_bookmark_log_entries(ctx.clone(), 0, 1000, Freshness::MaybeStale)
+    let tunables = tunables::MononokeTunables::default();
+    tunables.update_by_repo_ints(&hashmap! {
+        target_repo.name().clone() => hashmap! {
+            "backsyncer_entries_per_iteration".to_string() => 2,
+            "backsyncer_commits_per_second".to_string() => 1000,
+        },
+    });
+    let f = backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    );
+    with_tunables_async(tunables, f.boxed()).await?;
+    assert_eq!(get_counter().await?, Some(2));
+
+    // Without the tunables, the rest of the log is backsynced.
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+    let log_entries: Vec<_> = source_repo
+        .read_next_bookmark_log_entries(ctx.clone(), 0, 1000, Freshness::MostRecent)
         .try_collect()
         .await?;
+    assert!(log_entries.len() > 2);
+    assert_eq!(get_counter().await?, Some(log_entries.len() as i64));
 
-    let latest_log_id = next_log_entries.len() as i64;
+    Ok(())
+}
 
-    let mut futs = vec![];
-    // Run syncs in parallel
-    for _ in 1..5 {
-        let f = tokio::task::spawn(backsync_latest(
+#[fbinit::test]
+async fn backsync_merged_repos_concurrently(fb: FacebookInit) -> Result<(), Error> {
+    let (small_repos, _large_repo, latest_log_id, dont_verify_commits) =
+        init_merged_repos(fb, 2).await?;
+
+    let ctx = CoreContext::test_mock(fb);
+
+    for (commit_syncer, target_repo_dbs) in small_repos {
+        let progress = BacksyncProgress::new();
+        backsync_latest_with_options(
             ctx.clone(),
             commit_syncer.clone(),
             target_repo_dbs.clone(),
             BacksyncLimit::NoLimit,
-        ))
+            BacksyncOptions {
+                parallelism: 4,
+                progress: Some(progress.clone()),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.counter, Some(latest_log_id));
+        assert_eq!(snapshot.latest_log_id, Some(latest_log_id));
+        assert_eq!(snapshot.remaining_entries, 0);
+        assert!(snapshot.total_rewritten_commits > 0);
+        assert_eq!(snapshot.last_error, None);
+
+        let fetched_value = target_repo_dbs
+            .counters
+            .get_counter(
+                ctx.clone(),
+                commit_syncer.get_target_repo().get_repoid(),
+                &format_counter(&commit_syncer.get_source_repo().get_repoid()),
+            )
+            .compat()
+            .await?;
+        assert_eq!(fetched_value, Some(latest_log_id));
+
+        verify_mapping_and_all_wc(
+            ctx.clone(),
+            commit_syncer.clone(),
+            dont_verify_commits.clone(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn backsync_with_bookmark_filter(fb: FacebookInit) -> Result<(), Error> {
+    let (small_repos, _large_repo, latest_log_id, _dont_verify_commits) =
+        init_merged_repos(fb, 2).await?;
+
+    let ctx = CoreContext::test_mock(fb);
+    let master = BookmarkName::new("master")?;
+
+    for (commit_syncer, target_repo_dbs) in small_repos {
+        backsync_latest_with_options(
+            ctx.clone(),
+            commit_syncer.clone(),
+            target_repo_dbs.clone(),
+            BacksyncLimit::NoLimit,
+            BacksyncOptions {
+                bookmark_filter: Some(vec![master.clone().into()]),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // Entries for other bookmarks are skipped, but the counter moves past them
+        let target_repo = commit_syncer.get_target_repo();
+        let fetched_value = target_repo_dbs
+            .counters
+            .get_counter(
+                ctx.clone(),
+                target_repo.get_repoid(),
+                &format_counter(&commit_syncer.get_source_repo().get_repoid()),
+            )
+            .compat()
+            .await?;
+        assert_eq!(fetched_value, Some(latest_log_id));
+
+        assert!(
+            target_repo
+                .get_bookmark(ctx.clone(), &master)
+                .await?
+                .is_some()
+        );
+        assert!(
+            target_repo
+                .get_bookmark(ctx.clone(), &BookmarkName::new("premerge_book")?)
+                .await?
+                .is_none()
+        );
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn backsync_skip_and_record_conflicts(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs, latest_log_id) =
+        init_repos_with_moved_target_master(fb).await?;
+    let skipped =
+        SqlSkippedBacksyncEntries::from_sql_connections(target_repo_dbs.connections.clone());
+
+    backsync_latest_with_options(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        BacksyncOptions {
+            conflict_policy: Arc::new(SkipAndRecordConflicts::new(skipped.clone())),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let target_repo = commit_syncer.get_target_repo();
+    let source_repo = commit_syncer.get_source_repo();
+    let fetched_value = target_repo_dbs
+        .counters
+        .get_counter(
+            ctx.clone(),
+            target_repo.get_repoid(),
+            &format_counter(&source_repo.get_repoid()),
+        )
+        .compat()
+        .await?;
+    assert_eq!(fetched_value, Some(latest_log_id));
+
+    let skipped_entries = skipped
+        .list(&ctx, source_repo.get_repoid(), target_repo.get_repoid())
+        .await?;
+    assert!(!skipped_entries.is_empty());
+    for entry in skipped_entries {
+        assert_eq!(entry.bookmark, BookmarkName::new("master")?);
+        assert_eq!(entry.conflict, "bookmark_move");
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn backsync_prefer_source_on_conflict(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs, latest_log_id) =
+        init_repos_with_moved_target_master(fb).await?;
+
+    backsync_latest_with_options(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        BacksyncOptions {
+            conflict_policy: Arc::new(PreferSourceOnConflict),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let target_repo = commit_syncer.get_target_repo();
+    let source_repo = commit_syncer.get_source_repo();
+    let fetched_value = target_repo_dbs
+        .counters
+        .get_counter(
+            ctx.clone(),
+            target_repo.get_repoid(),
+            &format_counter(&source_repo.get_repoid()),
+        )
+        .compat()
+        .await?;
+    assert_eq!(fetched_value, Some(latest_log_id));
+
+    // Master was forced back to where it is in the source repo
+    let master = BookmarkName::new("master")?;
+    let source_master = source_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found in source repo"))?;
+    let outcome = commit_syncer
+        .get_commit_sync_outcome(&ctx, source_master)
+        .await?;
+    let target_master = target_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?;
+    assert_matches!(
+        outcome,
+        Some(CommitSyncOutcome::RewrittenAs(cs_id, _)) if Some(cs_id) == target_master
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn backsync_verify_and_fix_bookmarks(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs) =
+        init_repos(fb, MoverType::Noop, BookmarkRenamerType::Noop).await?;
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+    )
+    .await?;
+
+    let diff = verify_and_fix_bookmarks(ctx.clone(), &commit_syncer, target_repo_dbs.clone(), true)
+        .await?;
+    assert_eq!(diff, vec![]);
+
+    // Move master, and create a bookmark that the source repo doesn't have
+    let source_repo = commit_syncer.get_source_repo();
+    let target_repo = commit_syncer.get_target_repo();
+    let master = BookmarkName::new("master")?;
+    let extra = BookmarkName::new("extra")?;
+    let unrelated = CreateCommitContext::new_root(&ctx, target_repo)
+        .add_file("unrelated", "content")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), target_repo.clone(), &master, unrelated).await?;
+    move_bookmark(ctx.clone(), target_repo.clone(), &extra, unrelated).await?;
+
+    let source_master = source_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found in source repo"))?;
+    let expected_diff = hashset! {
+        BookmarkDiff::InconsistentValue {
+            target_bookmark: master.clone(),
+            target_cs_id: unrelated,
+            source_cs_id: Some(source_master),
+        },
+        BookmarkDiff::InconsistentValue {
+            target_bookmark: extra.clone(),
+            target_cs_id: unrelated,
+            source_cs_id: None,
+        },
+    };
+
+    // A dry run only reports
+    let diff = verify_and_fix_bookmarks(ctx.clone(), &commit_syncer, target_repo_dbs.clone(), true)
+        .await?;
+    assert_eq!(diff.into_iter().collect::<HashSet<_>>(), expected_diff);
+    let target_master = target_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?;
+    assert_eq!(target_master, Some(unrelated));
+
+    // Otherwise the target repo bookmarks are fixed
+    let diff =
+        verify_and_fix_bookmarks(ctx.clone(), &commit_syncer, target_repo_dbs.clone(), false)
+            .await?;
+    assert_eq!(diff.into_iter().collect::<HashSet<_>>(), expected_diff);
+    let diff = verify_and_fix_bookmarks(ctx.clone(), &commit_syncer, target_repo_dbs, true).await?;
+    assert_eq!(diff, vec![]);
+    let target_extra = target_repo.get_bonsai_bookmark(ctx.clone(), &extra).await?;
+    assert_eq!(target_extra, None);
+    verify_bookmarks(ctx.clone(), commit_syncer.clone()).await?;
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn forward_sync_linear(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs) = init_forward_sync_repos(fb).await?;
+    let small_repo = commit_syncer.get_small_repo();
+    let large_repo = commit_syncer.get_large_repo();
+    let master = BookmarkName::new("master")?;
+
+    forward_sync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions::new(master.clone()),
+    )
+    .await?;
+
+    let fetched_value = target_repo_dbs
+        .counters
+        .get_counter(
+            ctx.clone(),
+            large_repo.get_repoid(),
+            &format_forward_counter(&small_repo.get_repoid()),
+        )
+        .compat()
+        .await?;
+    assert_eq!(fetched_value, Some(3));
+
+    let small_master = small_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found in small repo"))?;
+    let outcome = commit_syncer
+        .get_commit_sync_outcome(&ctx, small_master)
+        .await?;
+    let large_master = large_repo.get_bonsai_bookmark(ctx.clone(), &master).await?;
+    assert_matches!(
+        outcome,
+        Some(CommitSyncOutcome::RewrittenAs(cs_id, _)) if Some(cs_id) == large_master
+    );
+    // Only master is forward synced
+    let large_other = large_repo
+        .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("other")?)
+        .await?;
+    assert_eq!(large_other, None);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn forward_sync_diverged_large_bookmark(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs) = init_forward_sync_repos(fb).await?;
+    let small_repo = commit_syncer.get_small_repo();
+    let large_repo = commit_syncer.get_large_repo();
+    let master = BookmarkName::new("master")?;
+
+    forward_sync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions::new(master.clone()),
+    )
+    .await?;
+
+    // Move master in both repos
+    let small_master = small_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found in small repo"))?;
+    let small_commit = CreateCommitContext::new(&ctx, small_repo, vec![small_master])
+        .add_file("file", "small content")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), small_repo.clone(), &master, small_commit).await?;
+    let unrelated = CreateCommitContext::new_root(&ctx, large_repo)
+        .add_file("unrelated", "content")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), large_repo.clone(), &master, unrelated).await?;
+
+    let get_counter = || {
+        target_repo_dbs
+            .counters
+            .get_counter(
+                ctx.clone(),
+                large_repo.get_repoid(),
+                &format_forward_counter(&small_repo.get_repoid()),
+            )
+            .compat()
+    };
+
+    let res = forward_sync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions::new(master.clone()),
+    )
+    .await;
+    assert!(res.is_err());
+    assert_eq!(get_counter().await?, Some(3));
+
+    let skipped =
+        SqlSkippedBacksyncEntries::from_sql_connections(target_repo_dbs.connections.clone());
+    forward_sync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions {
+            conflict_policy: Arc::new(SkipAndRecordConflicts::new(skipped.clone())),
+            ..ForwardSyncOptions::new(master.clone())
+        },
+    )
+    .await?;
+    assert_eq!(get_counter().await?, Some(4));
+    let large_master = large_repo.get_bonsai_bookmark(ctx.clone(), &master).await?;
+    assert_eq!(large_master, Some(unrelated));
+
+    let skipped_entries = skipped
+        .list(&ctx, small_repo.get_repoid(), large_repo.get_repoid())
+        .await?;
+    assert_eq!(skipped_entries.len(), 1);
+    assert_eq!(skipped_entries[0].conflict, "bookmark_move");
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn forward_sync_rejects_merges(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs) = init_forward_sync_repos(fb).await?;
+    let small_repo = commit_syncer.get_small_repo();
+    let master = BookmarkName::new("master")?;
+
+    let small_master = small_repo
+        .get_bonsai_bookmark(ctx.clone(), &master)
+        .await?
+        .ok_or_else(|| anyhow!("master not found in small repo"))?;
+    let side = CreateCommitContext::new(&ctx, small_repo, vec![small_master])
+        .add_file("side", "content")
+        .commit()
+        .await?;
+    let merge = CreateCommitContext::new(&ctx, small_repo, vec![small_master, side])
+        .add_file("merge", "content")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), small_repo.clone(), &master, merge).await?;
+
+    let res = forward_sync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions::new(master.clone()),
+    )
+    .await;
+    assert!(res.is_err());
+    let outcome = commit_syncer.get_commit_sync_outcome(&ctx, side).await?;
+    assert_eq!(outcome, None);
+
+    // Backsync commit syncers are rejected
+    let (backsync_commit_syncer, backsync_dbs) =
+        init_repos(fb, MoverType::Noop, BookmarkRenamerType::Noop).await?;
+    let res = forward_sync_latest(
+        ctx.clone(),
+        backsync_commit_syncer,
+        backsync_dbs,
+        BacksyncLimit::NoLimit,
+        ForwardSyncOptions::new(master),
+    )
+    .await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+/// Backsync the first log entry, then move master in the target repo behind the
+/// backsyncer's back, so that later moves of master conflict.
+async fn init_repos_with_moved_target_master(
+    fb: FacebookInit,
+) -> Result<(CommitSyncer<SqlSyncedCommitMapping>, TargetRepoDbs, i64), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (commit_syncer, target_repo_dbs) =
+        init_repos(fb, MoverType::Noop, BookmarkRenamerType::Noop).await?;
+
+    backsync_latest(
+        ctx.clone(),
+        commit_syncer.clone(),
+        target_repo_dbs.clone(),
+        BacksyncLimit::Limit(1),
+    )
+    .await?;
+
+    let target_repo = commit_syncer.get_target_repo();
+    let unrelated = CreateCommitContext::new_root(&ctx, target_repo)
+        .add_file("unrelated", "content")
+        .commit()
+        .await?;
+    move_bookmark(
+        ctx.clone(),
+        target_repo.clone(),
+        &BookmarkName::new("master")?,
+        unrelated,
+    )
+    .await?;
+
+    let latest_log_id = commit_syncer
+        .get_source_repo()
+        .read_next_bookmark_log_entries(ctx.clone(), 0, 1000, Freshness::MostRecent)
+        .try_collect::<Vec<_>>()
+        .await?
+        .len() as i64;
+
+    Ok((commit_syncer, target_repo_dbs, latest_log_id))
+}
+
+/// A small repo whose master moved over linear history, a large repo without bookmarks, and
+/// a commit syncer from the small repo to the large repo, where only the small repo root
+/// commit is synced. The small repo log has three entries: the creation of master, a move of
+/// master over two commits, and the creation of an `other` bookmark.
+async fn init_forward_sync_repos(
+    fb: FacebookInit,
+) -> Result<(CommitSyncer<SqlSyncedCommitMapping>, TargetRepoDbs), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let mut factory = TestRepoFactory::new()?;
+    let small_repo: BlobRepo = factory.with_id(RepositoryId::new(1)).build()?;
+    let large_repo: BlobRepo = factory.with_id(RepositoryId::new(2)).build()?;
+
+    // Skipped entries are recorded in the transaction that moves the counter.
+    SqlSkippedBacksyncEntries::from_connections_with_schema(factory.metadata_db().clone())?;
+    let target_repo_dbs = TargetRepoDbs {
+        connections: factory.metadata_db().clone().into(),
+        bookmarks: large_repo.bookmarks().clone(),
+        bookmark_update_log: large_repo.bookmark_update_log().clone(),
+        counters: SqlMutableCounters::from_sql_connections(factory.metadata_db().clone().into()),
+        outcomes: SqlBacksyncOutcomes::with_sqlite_in_memory()?,
+        rewrite_checkpoints: SqlRewriteCheckpoints::with_sqlite_in_memory()?,
+        sync_metadata_entries: false,
+        post_sync_hooks: vec![],
+    };
+
+    let mapping = SqlSyncedCommitMapping::with_sqlite_in_memory()?;
+    let repos = CommitSyncRepos::SmallToLarge {
+        small_repo: small_repo.clone(),
+        large_repo: large_repo.clone(),
+    };
+    let current_version = CommitSyncConfigVersion("TEST_VERSION_NAME".to_string());
+    let commit_sync_data_provider = CommitSyncDataProvider::test_new(
+        current_version.clone(),
+        Source(small_repo.get_repoid()),
+        Target(large_repo.get_repoid()),
+        hashmap! {
+            current_version.clone() => SyncData {
+                mover: MoverType::Noop.get_mover(),
+                reverse_mover: MoverType::Noop.get_reverse_mover(),
+            }
+        },
+        vec![BookmarkName::new("master")?],
+        BookmarkRenamerType::Noop.get_bookmark_renamer(),
+        BookmarkRenamerType::Noop.get_reverse_bookmark_renamer(),
+    );
+    let commit_syncer =
+        CommitSyncer::new_with_provider(&ctx, mapping.clone(), repos, commit_sync_data_provider);
+
+    // The root commit is synced manually
+    let small_root = CreateCommitContext::new_root(&ctx, &small_repo)
+        .add_file("file", "content")
+        .commit()
+        .await?;
+    let large_root = CreateCommitContext::new_root(&ctx, &large_repo)
+        .add_file("file", "content")
+        .commit()
+        .await?;
+    mapping
+        .add(
+            &ctx,
+            SyncedCommitMappingEntry::new(
+                large_repo.get_repoid(),
+                large_root,
+                small_repo.get_repoid(),
+                small_root,
+                current_version,
+                SyncedCommitSourceRepo::Small,
+            ),
+        )
+        .await?;
+
+    let master = BookmarkName::new("master")?;
+    move_bookmark(ctx.clone(), small_repo.clone(), &master, small_root).await?;
+    let first = CreateCommitContext::new(&ctx, &small_repo, vec![small_root])
+        .add_file("file", "first content")
+        .commit()
+        .await?;
+    let second = CreateCommitContext::new(&ctx, &small_repo, vec![first])
+        .add_file("second", "second content")
+        .commit()
+        .await?;
+    move_bookmark(ctx.clone(), small_repo.clone(), &master, second).await?;
+    move_bookmark(
+        ctx.clone(),
+        small_repo.clone(),
+        &BookmarkName::new("other")?,
+        first,
+    )
+    .await?;
+
+    Ok((commit_syncer, target_repo_dbs))
+}
+
+async fn backsync_and_verify_master_wc(
+    fb: FacebookInit,
+    commit_syncer: CommitSyncer<SqlSyncedCommitMapping>,
+    target_repo_dbs: TargetRepoDbs,
+) -> Result<(), Error> {
+    let source_repo = commit_syncer.get_source_repo();
+    let target_repo = commit_syncer.get_target_repo();
+
+    let ctx = CoreContext::test_mock(fb);
+    let next_log_entries: Vec<_> = commit_syncer
+        .get_source_repo()
+        .read_next_bookmark_log_entries(ctx.clone(), 0, 1000, Freshness::MaybeStale)
+        .try_collect()
+        .await?;
+
+    let latest_log_id = next_log_entries.len() as i64;
+
+    let mut futs = vec![];
+    // Run syncs in parallel
+    for _ in 1..5 {
+        let f = tokio::task::spawn(
+            backsync_latest(
+                ctx.clone(),
+                commit_syncer.clone(),
+                target_repo_dbs.clone(),
+                BacksyncLimit::NoLimit,
+            )
+            .map_err(Error::from),
+        )
         .flatten_err();
         futs.push(f);
     }
@@ -987,11 +2062,17 @@ async fn init_repos(
     let target_repo_id = RepositoryId::new(2);
     let target_repo: BlobRepo = factory.with_id(target_repo_id).build()?;
 
+    // Skipped entries are recorded in the transaction that moves the counter.
+    SqlSkippedBacksyncEntries::from_connections_with_schema(factory.metadata_db().clone())?;
     let target_repo_dbs = TargetRepoDbs {
         connections: factory.metadata_db().clone().into(),
         bookmarks: target_repo.bookmarks().clone(),
         bookmark_update_log: target_repo.bookmark_update_log().clone(),
         counters: SqlMutableCounters::from_sql_connections(factory.metadata_db().clone().into()),
+        outcomes: SqlBacksyncOutcomes::with_sqlite_in_memory()?,
+        rewrite_checkpoints: SqlRewriteCheckpoints::with_sqlite_in_memory()?,
+        sync_metadata_entries: false,
+        post_sync_hooks: vec![],
     };
     init_target_repo(&ctx, &target_repo_dbs, source_repo_id, target_repo_id).await?;
 
@@ -1288,6 +2369,10 @@ async fn init_merged_repos(
             counters: SqlMutableCounters::from_sql_connections(
                 factory.metadata_db().clone().into(),
             ),
+            outcomes: SqlBacksyncOutcomes::with_sqlite_in_memory()?,
+            rewrite_checkpoints: SqlRewriteCheckpoints::with_sqlite_in_memory()?,
+            sync_metadata_entries: false,
+            post_sync_hooks: vec![],
         };
 
         // Init counters
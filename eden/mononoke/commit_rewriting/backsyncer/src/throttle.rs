@@ -0,0 +1,112 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Throttling of backsync, so that a large backlog is replayed without overwhelming the
+//! blobstore and bookmarks db of the target repo. The limits are tunables by target repo,
+//! and are read again as backsync runs so that they can be changed without a restart.
+
+use std::time::{Duration, Instant};
+
+use context::CoreContext;
+use rand::Rng;
+use slog::{debug, info};
+use tunables::tunables;
+
+use crate::progress::BacksyncProgress;
+
+/// How often to log progress while backsync is throttled.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Sleeps are made up to this fraction longer at random, so that backsyncers of
+/// different repos don't wake up in lockstep.
+const MAX_JITTER: f64 = 0.1;
+
+/// Maximum number of log entries one backsync iteration reads for `repo_name`, from the
+/// `backsyncer_entries_per_iteration` tunable.
+pub(crate) fn entries_per_iteration(repo_name: &str) -> Option<u64> {
+    positive_tunable(tunables().get_by_repo_backsyncer_entries_per_iteration(repo_name))
+}
+
+fn commits_per_second(repo_name: &str) -> Option<u64> {
+    positive_tunable(tunables().get_by_repo_backsyncer_commits_per_second(repo_name))
+}
+
+fn positive_tunable(value: Option<i64>) -> Option<u64> {
+    value
+        .and_then(|value| u64::try_from(value).ok())
+        .filter(|value| *value > 0)
+}
+
+/// How long to wait for `commits` rewritten over `elapsed` to be within
+/// `commits_per_second`.
+pub(crate) fn throttle_delay(commits: u64, elapsed: Duration, commits_per_second: u64) -> Duration {
+    let target = Duration::from_secs_f64(commits as f64 / commits_per_second as f64);
+    target.saturating_sub(elapsed)
+}
+
+/// Keeps the commits rewritten by a backsync run within the `backsyncer_commits_per_second`
+/// tunable of the target repo.
+pub(crate) struct BacksyncThrottle {
+    repo_name: String,
+    started: Instant,
+    last_logged: Instant,
+    commits: u64,
+    entries: u64,
+}
+
+impl BacksyncThrottle {
+    pub(crate) fn new(repo_name: String) -> Self {
+        let now = Instant::now();
+        Self {
+            repo_name,
+            started: now,
+            last_logged: now,
+            commits: 0,
+            entries: 0,
+        }
+    }
+
+    /// Record that an entry was backsynced with `commits` rewritten commits, and sleep
+    /// if the run is going faster than the limit.
+    pub(crate) async fn record_entry(
+        &mut self,
+        ctx: &CoreContext,
+        commits: u64,
+        progress: &BacksyncProgress,
+    ) {
+        self.entries += 1;
+        self.commits += commits;
+        let commits_per_second = match commits_per_second(&self.repo_name) {
+            Some(commits_per_second) => commits_per_second,
+            None => return,
+        };
+
+        let delay = throttle_delay(self.commits, self.started.elapsed(), commits_per_second);
+        if delay > Duration::ZERO {
+            let delay = delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..MAX_JITTER));
+            debug!(
+                ctx.logger(),
+                "throttling backsync for {:?} to {} commits per second", delay, commits_per_second
+            );
+            tokio::time::sleep(delay).await;
+        }
+
+        if self.last_logged.elapsed() >= PROGRESS_LOG_INTERVAL {
+            self.last_logged = Instant::now();
+            let elapsed = self.started.elapsed().as_secs_f64();
+            info!(
+                ctx.logger(),
+                "backsynced {} entries and {} commits ({:.1} commits per second, limit {}), {} entries remaining",
+                self.entries,
+                self.commits,
+                self.commits as f64 / elapsed,
+                commits_per_second,
+                progress.snapshot().remaining_entries,
+            );
+        }
+    }
+}
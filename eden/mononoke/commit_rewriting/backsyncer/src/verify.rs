@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::{bail, format_err, Error};
+use bookmarks::BookmarkUpdateReason;
+use context::CoreContext;
+use cross_repo_sync::{validation::find_bookmark_diff, CommitSyncOutcome, CommitSyncer};
+use mononoke_types::ChangesetId;
+use slog::{info, warn};
+use synced_commit_mapping::SyncedCommitMapping;
+
+pub use cross_repo_sync::validation::BookmarkDiff;
+
+use crate::TargetRepoDbs;
+
+/// Compare the target repo bookmarks with the renamed source repo bookmarks, and return
+/// where they diverge. Unless `dry_run` is set, also move the target repo bookmarks to
+/// the changesets that the source repo bookmarks were synced as, or delete them if the
+/// source repo has no such bookmark.
+///
+/// Bookmarks are only moved from the values they were compared at, so if something else
+/// moves a target repo bookmark in the meantime, nothing is changed and an error is
+/// returned. Rerunning compares the bookmarks again.
+///
+/// `BookmarkDiff::NoSyncOutcome` can't be fixed here: the source repo bookmark points
+/// to a commit that hasn't been backsynced yet. These are only reported.
+pub async fn verify_and_fix_bookmarks<M>(
+    ctx: CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    target_repo_dbs: TargetRepoDbs,
+    dry_run: bool,
+) -> Result<Vec<BookmarkDiff>, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    let diff = find_bookmark_diff(ctx.clone(), commit_syncer).await?;
+    if diff.is_empty() {
+        info!(ctx.logger(), "target repo bookmarks are consistent");
+        return Ok(diff);
+    }
+
+    let mut bookmark_txn = target_repo_dbs.bookmarks.create_transaction(ctx.clone());
+    let mut fixed = 0;
+    for d in &diff {
+        use BookmarkDiff::*;
+        let (target_bookmark, old_target_cs_id, source_cs_id) = match d {
+            InconsistentValue {
+                target_bookmark,
+                target_cs_id,
+                source_cs_id,
+            } => {
+                warn!(
+                    ctx.logger(),
+                    "target repo bookmark {} points to {}, but the source repo bookmark \
+                     points to {:?}",
+                    target_bookmark,
+                    target_cs_id,
+                    source_cs_id,
+                );
+                (target_bookmark, Some(*target_cs_id), *source_cs_id)
+            }
+            MissingInTarget {
+                target_bookmark,
+                source_cs_id,
+            } => {
+                warn!(
+                    ctx.logger(),
+                    "target repo bookmark {} is missing, but the source repo bookmark \
+                     points to {}",
+                    target_bookmark,
+                    source_cs_id,
+                );
+                (target_bookmark, None, Some(*source_cs_id))
+            }
+            NoSyncOutcome { target_bookmark } => {
+                warn!(
+                    ctx.logger(),
+                    "source repo bookmark for {} points to a commit that hasn't been backsynced, \
+                     not fixing it",
+                    target_bookmark,
+                );
+                continue;
+            }
+        };
+        if dry_run {
+            continue;
+        }
+
+        match (old_target_cs_id, source_cs_id) {
+            (Some(old_target_cs_id), Some(source_cs_id)) => {
+                let target_cs_id = get_remapped_cs_id(&ctx, commit_syncer, source_cs_id).await?;
+                info!(
+                    ctx.logger(),
+                    "moving bookmark {} from {} to {}",
+                    target_bookmark,
+                    old_target_cs_id,
+                    target_cs_id
+                );
+                bookmark_txn.update(
+                    target_bookmark,
+                    target_cs_id,
+                    old_target_cs_id,
+                    BookmarkUpdateReason::Backsyncer,
+                    None,
+                )?;
+            }
+            (None, Some(source_cs_id)) => {
+                let target_cs_id = get_remapped_cs_id(&ctx, commit_syncer, source_cs_id).await?;
+                info!(
+                    ctx.logger(),
+                    "creating bookmark {} at {}", target_bookmark, target_cs_id
+                );
+                bookmark_txn.create(
+                    target_bookmark,
+                    target_cs_id,
+                    BookmarkUpdateReason::Backsyncer,
+                    None,
+                )?;
+            }
+            (Some(old_target_cs_id), None) => {
+                info!(
+                    ctx.logger(),
+                    "deleting bookmark {} at {}", target_bookmark, old_target_cs_id
+                );
+                bookmark_txn.delete(
+                    target_bookmark,
+                    old_target_cs_id,
+                    BookmarkUpdateReason::Backsyncer,
+                    None,
+                )?;
+            }
+            (None, None) => continue,
+        }
+        fixed += 1;
+    }
+
+    if fixed > 0 {
+        if !bookmark_txn.commit().await? {
+            bail!(
+                "failed to fix {} target repo bookmarks: some of them moved while they were \
+                 being compared, rerun to compare them again",
+                fixed
+            );
+        }
+        info!(ctx.logger(), "fixed {} target repo bookmarks", fixed);
+    }
+
+    Ok(diff)
+}
+
+async fn get_remapped_cs_id<M>(
+    ctx: &CoreContext,
+    commit_syncer: &CommitSyncer<M>,
+    source_cs_id: ChangesetId,
+) -> Result<ChangesetId, Error>
+where
+    M: SyncedCommitMapping + Clone + 'static,
+{
+    use CommitSyncOutcome::*;
+    match commit_syncer
+        .get_commit_sync_outcome(ctx, source_cs_id)
+        .await?
+    {
+        Some(RewrittenAs(cs_id, _)) | Some(EquivalentWorkingCopyAncestor(cs_id, _)) => Ok(cs_id),
+        Some(NotSyncCandidate) => Err(format_err!(
+            "{} should not be synced to target repo",
+            source_cs_id
+        )),
+        None => Err(format_err!("{} hasn't been backsynced yet", source_cs_id)),
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checkpoints of uploading rewritten merge commits.
+//!
+//! A merge that brings in a whole repo can touch hundreds of thousands of
+//! files, and copying their contents into the target repo is most of the
+//! work of syncing it. With checkpoints, the contents copied so far are
+//! recorded as the upload goes, so that a sync that failed halfway through
+//! resumes where it stopped instead of starting over.
+
+use std::collections::HashSet;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use blobrepo::{save_bonsai_changesets, BlobRepo};
+use commit_transformation::copy_file_contents;
+use context::CoreContext;
+use metaconfig_types::CommitSyncConfigVersion;
+use mononoke_types::{BonsaiChangeset, ChangesetId, ContentId, FileChange, RepositoryId};
+use slog::debug;
+
+/// How many file contents are copied between checkpoints.
+const CHECKPOINT_CHUNK_SIZE: usize = 10_000;
+
+/// Identifies the upload of one rewritten commit. The rewritten commit, and so
+/// the contents it needs, depend on the version of the sync config used.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RewriteCheckpointKey {
+    pub source_repo_id: RepositoryId,
+    pub target_repo_id: RepositoryId,
+    pub source_cs_id: ChangesetId,
+    pub version: CommitSyncConfigVersion,
+}
+
+/// Storage of checkpoints, usually in the dbs of the target repo.
+#[async_trait]
+pub trait RewriteCheckpoints: Send + Sync {
+    /// Contents already copied to the target repo for the upload of `key`.
+    async fn load(
+        &self,
+        ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+    ) -> Result<HashSet<ContentId>, Error>;
+
+    /// Record that `content_ids` were copied to the target repo.
+    async fn save(
+        &self,
+        ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+        content_ids: &[ContentId],
+    ) -> Result<(), Error>;
+
+    /// Forget the checkpoint of an upload once the commit was synced.
+    async fn clear(&self, ctx: &CoreContext, key: &RewriteCheckpointKey) -> Result<(), Error>;
+}
+
+/// Like `commit_transformation::upload_commits` for a single commit, but skip
+/// the contents that `checkpoints` has for `key` and checkpoint the rest as
+/// they are copied.
+pub(crate) async fn upload_commit_with_checkpoints(
+    ctx: &CoreContext,
+    checkpoints: &dyn RewriteCheckpoints,
+    key: &RewriteCheckpointKey,
+    rewritten: BonsaiChangeset,
+    source_repo: &BlobRepo,
+    target_repo: &BlobRepo,
+) -> Result<(), Error> {
+    let copied = checkpoints.load(ctx, key).await?;
+    let mut seen = HashSet::new();
+    let to_copy: Vec<_> = rewritten
+        .file_changes()
+        .filter_map(|(_, change)| match change {
+            FileChange::Change(tc) => Some(tc.content_id()),
+            FileChange::UntrackedChange(uc) => Some(uc.content_id()),
+            FileChange::Deletion | FileChange::UntrackedDeletion => None,
+        })
+        .filter(|content_id| !copied.contains(content_id) && seen.insert(*content_id))
+        .collect();
+    if !copied.is_empty() {
+        debug!(
+            ctx.logger(),
+            "resuming upload of {} from checkpoint: {} contents already copied, {} left",
+            key.source_cs_id,
+            copied.len(),
+            to_copy.len()
+        );
+    }
+
+    for chunk in to_copy.chunks(CHECKPOINT_CHUNK_SIZE) {
+        copy_file_contents(ctx, source_repo, target_repo, chunk.iter().copied()).await?;
+        checkpoints.save(ctx, key, chunk).await?;
+    }
+    save_bonsai_changesets(vec![rewritten], ctx.clone(), target_repo.clone()).await?;
+    Ok(())
+}
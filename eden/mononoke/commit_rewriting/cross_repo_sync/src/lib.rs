@@ -37,7 +37,7 @@ use movers::Mover;
 use pushrebase::{do_pushrebase_bonsai, PushrebaseError};
 use reachabilityindex::LeastCommonAncestorsHint;
 use scuba_ext::MononokeScubaSampleBuilder;
-use slog::{debug, info};
+use slog::{debug, info, warn};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::sync::Arc;
@@ -50,6 +50,7 @@ use thiserror::Error;
 use topo_sort::sort_topological;
 use tunables::tunables;
 
+use crate::checkpoints::upload_commit_with_checkpoints;
 use crate::pushrebase_hook::CrossRepoSyncPushrebaseHook;
 use reporting::log_rewrite;
 pub use reporting::CommitSyncContext;
@@ -57,6 +58,7 @@ pub use sync_config_version_utils::CHANGE_XREPO_MAPPING_EXTRA;
 use sync_config_version_utils::{get_mapping_change_version, get_version, get_version_for_merge};
 use types::{Source, Target};
 
+mod checkpoints;
 mod commit_sync_data_provider;
 pub mod commit_sync_outcome;
 mod pushrebase_hook;
@@ -65,6 +67,7 @@ mod sync_config_version_utils;
 pub mod types;
 pub mod validation;
 
+pub use crate::checkpoints::{RewriteCheckpointKey, RewriteCheckpoints};
 pub use crate::commit_sync_outcome::{
     commit_sync_outcome_exists, get_commit_sync_outcome, get_commit_sync_outcome_with_hint,
     get_plural_commit_sync_outcome, CandidateSelectionHint, CommitSyncOutcome,
@@ -370,6 +373,9 @@ pub struct CommitSyncer<M> {
     pub commit_sync_data_provider: CommitSyncDataProvider,
     pub scuba_sample: MononokeScubaSampleBuilder,
     pub x_repo_sync_lease: Arc<dyn LeaseOps>,
+    /// If set, uploads of rewritten merge commits are checkpointed here, so
+    /// that they resume where they stopped if syncing the merge is retried.
+    pub rewrite_checkpoints: Option<Arc<dyn RewriteCheckpoints>>,
 }
 
 impl<M> fmt::Debug for CommitSyncer<M>
@@ -431,9 +437,15 @@ where
             commit_sync_data_provider,
             scuba_sample,
             x_repo_sync_lease,
+            rewrite_checkpoints: None,
         }
     }
 
+    pub fn with_rewrite_checkpoints(mut self, checkpoints: Arc<dyn RewriteCheckpoints>) -> Self {
+        self.rewrite_checkpoints = Some(checkpoints);
+        self
+    }
+
     pub fn get_source_repo(&self) -> &BlobRepo {
         self.repos.get_source_repo()
     }
@@ -1453,7 +1465,30 @@ where
 
         let frozen = rewritten.freeze()?;
         let target_cs_id = frozen.get_changeset_id();
-        upload_commits(ctx, vec![frozen], &source_repo, &target_repo).await?;
+        let checkpoint = match &self.rewrite_checkpoints {
+            Some(checkpoints) => {
+                let key = RewriteCheckpointKey {
+                    source_repo_id: self.get_source_repo_id(),
+                    target_repo_id: self.get_target_repo_id(),
+                    source_cs_id,
+                    version: version.clone(),
+                };
+                upload_commit_with_checkpoints(
+                    ctx,
+                    checkpoints.as_ref(),
+                    &key,
+                    frozen,
+                    &source_repo,
+                    &target_repo,
+                )
+                .await?;
+                Some((checkpoints, key))
+            }
+            None => {
+                upload_commits(ctx, vec![frozen], &source_repo, &target_repo).await?;
+                None
+            }
+        };
 
         // update_mapping also updates working copy equivalence, so no need
         // to do it separately
@@ -1464,6 +1499,16 @@ where
             &version,
         )
         .await?;
+
+        // The commit is synced now, so a leftover checkpoint is only wasted space.
+        if let Some((checkpoints, key)) = checkpoint {
+            if let Err(err) = checkpoints.clear(ctx, &key).await {
+                warn!(
+                    ctx.logger(),
+                    "failed to clear rewrite checkpoint of {}: {:#}", source_cs_id, err
+                );
+            }
+        }
         return Ok(target_cs_id);
     }
 
@@ -12,11 +12,12 @@
 use anyhow::{anyhow, bail, Error};
 use ascii::AsciiString;
 use assert_matches::assert_matches;
+use async_trait::async_trait;
 use bytes::Bytes;
 use fbinit::FacebookInit;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use blobrepo::{save_bonsai_changesets, BlobRepo};
 use blobrepo_hg::BlobRepoHg;
@@ -27,7 +28,8 @@ use cloned::cloned;
 use context::CoreContext;
 use cross_repo_sync::{
     update_mapping_with_version, validation::verify_working_copy, CommitSyncContext,
-    CommitSyncDataProvider, CommitSyncOutcome, ErrorKind, SyncData,
+    CommitSyncDataProvider, CommitSyncOutcome, ErrorKind, RewriteCheckpointKey, RewriteCheckpoints,
+    SyncData,
 };
 use cross_repo_sync_test_utils::rebase_root_on_master;
 use fixtures::{linear, many_files_dirs};
@@ -41,8 +43,8 @@ use metaconfig_types::{
     DefaultSmallToLargeCommitSyncPathAction, SmallRepoCommitSyncConfig, SmallRepoPermanentConfig,
 };
 use mononoke_types::{
-    BlobstoreValue, BonsaiChangesetMut, ChangesetId, DateTime, FileChange, FileContents, FileType,
-    MPath, RepositoryId,
+    BlobstoreValue, BonsaiChangesetMut, ChangesetId, ContentId, DateTime, FileChange, FileContents,
+    FileType, MPath, RepositoryId,
 };
 use pushrebase::PushrebaseError;
 use reachabilityindex::LeastCommonAncestorsHint;
@@ -2244,6 +2246,111 @@ async fn test_sync_merge_fails_when_parents_have_different_versions(
     Ok(())
 }
 
+/// Checkpoints kept in memory, which also remember every content saved.
+#[derive(Default)]
+struct TestRewriteCheckpoints {
+    copied: Mutex<HashMap<RewriteCheckpointKey, HashSet<ContentId>>>,
+    saved: Mutex<Vec<ContentId>>,
+}
+
+#[async_trait]
+impl RewriteCheckpoints for TestRewriteCheckpoints {
+    async fn load(
+        &self,
+        _ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+    ) -> Result<HashSet<ContentId>, Error> {
+        let copied = self.copied.lock().unwrap();
+        Ok(copied.get(key).cloned().unwrap_or_default())
+    }
+
+    async fn save(
+        &self,
+        _ctx: &CoreContext,
+        key: &RewriteCheckpointKey,
+        content_ids: &[ContentId],
+    ) -> Result<(), Error> {
+        self.copied
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .extend(content_ids.iter().copied());
+        self.saved
+            .lock()
+            .unwrap()
+            .extend(content_ids.iter().copied());
+        Ok(())
+    }
+
+    async fn clear(&self, _ctx: &CoreContext, key: &RewriteCheckpointKey) -> Result<(), Error> {
+        self.copied.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[fbinit::test]
+async fn test_sync_merge_resumes_from_checkpoint(fb: FacebookInit) -> Result<(), Error> {
+    let v1 = CommitSyncConfigVersion("v1".to_string());
+    let (ctx, lts_syncer, heads_with_versions) = merge_test_setup(fb).await?;
+    let checkpoints = Arc::new(TestRewriteCheckpoints::default());
+    let lts_syncer = lts_syncer.with_rewrite_checkpoints(checkpoints.clone());
+    let large_repo = lts_syncer.get_source_repo();
+    let small_repo = lts_syncer.get_target_repo();
+
+    let heads = heads_with_versions[&Some(v1.clone())].clone();
+    let merge_bcs_id = CreateCommitContext::new(&ctx, large_repo, heads)
+        .add_file("merged_1", "content 1")
+        .add_file("merged_2", "content 2")
+        .commit()
+        .await?;
+    let merge = merge_bcs_id.load(&ctx, large_repo.blobstore()).await?;
+    let content_id = |path: &str| match merge.file_changes_map().get(&mpath(path)) {
+        Some(FileChange::Change(tc)) => tc.content_id(),
+        _ => panic!("{} is not changed by the merge", path),
+    };
+
+    // A previous attempt to sync the merge copied one of the contents before failing.
+    let key = RewriteCheckpointKey {
+        source_repo_id: large_repo.get_repoid(),
+        target_repo_id: small_repo.get_repoid(),
+        source_cs_id: merge_bcs_id,
+        version: v1.clone(),
+    };
+    commit_transformation::copy_file_contents(
+        &ctx,
+        large_repo,
+        small_repo,
+        vec![content_id("merged_1")],
+    )
+    .await?;
+    checkpoints
+        .save(&ctx, &key, &[content_id("merged_1")])
+        .await?;
+    checkpoints.saved.lock().unwrap().clear();
+
+    // Retrying only copies the rest, and forgets the checkpoint once the merge is synced.
+    lts_syncer
+        .sync_commit(
+            &ctx,
+            merge_bcs_id,
+            CandidateSelectionHint::Only,
+            CommitSyncContext::Tests,
+        )
+        .await?
+        .expect("merge was not synced");
+    assert_eq!(
+        *checkpoints.saved.lock().unwrap(),
+        vec![content_id("merged_2")]
+    );
+    assert_eq!(checkpoints.load(&ctx, &key).await?, HashSet::new());
+    assert_matches!(
+        lts_syncer.get_commit_sync_outcome(&ctx, merge_bcs_id).await?,
+        Some(CommitSyncOutcome::RewrittenAs(_, version)) if version == v1
+    );
+    Ok(())
+}
+
 async fn assert_working_copy(
     ctx: &CoreContext,
     repo: &BlobRepo,
@@ -7,7 +7,9 @@
 
 use anyhow::Error;
 use async_trait::async_trait;
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SortOrder};
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
 use context::CoreContext;
 use futures::future;
 use futures::stream::BoxStream;
@@ -112,6 +114,14 @@ impl<T: Changesets + Clone + 'static> Changesets for MemWritesChangesets<T> {
         self.inner.prime_cache(ctx, changesets)
     }
 
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        self.inner.get_sequence(ctx, cs_id).await
+    }
+
     async fn enumeration_bounds(
         &self,
         ctx: &CoreContext,
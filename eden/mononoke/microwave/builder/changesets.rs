@@ -7,7 +7,9 @@
 
 use anyhow::Error;
 use async_trait::async_trait;
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SortOrder};
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsSequenceNumber, SortOrder,
+};
 use cloned::cloned;
 use context::CoreContext;
 use futures::channel::mpsc::Sender;
@@ -95,6 +97,14 @@ impl Changesets for MicrowaveChangesets {
         self.inner.prime_cache(ctx, changesets)
     }
 
+    async fn get_sequence(
+        &self,
+        ctx: &CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetsSequenceNumber>, Error> {
+        self.inner.get_sequence(ctx, cs_id).await
+    }
+
     async fn enumeration_bounds(
         &self,
         ctx: &CoreContext,
@@ -54,6 +54,7 @@ use blobrepo_hg::BlobRepoHg;
 use blobrepo_utils::convert_diff_result_into_file_change_for_diamond_merge;
 use blobstore::Loadable;
 use bookmarks::{BookmarkName, BookmarkUpdateReason, BundleReplay};
+use changesets::Changesets;
 use cloned::cloned;
 use context::CoreContext;
 use derived_data::BonsaiDerived;
@@ -273,6 +274,7 @@ pub async fn do_pushrebase_bonsai(
 ) -> Result<PushrebaseOutcome, PushrebaseError> {
     let head = find_only_head_or_fail(&pushed)?;
     let roots = find_roots(&pushed);
+    check_roots_exist(&ctx, &repo, &roots).await?;
 
     let root = find_closest_root(&ctx, &repo, &config, onto_bookmark, &roots).await?;
 
@@ -547,6 +549,27 @@ fn find_roots(commits: &HashSet<BonsaiChangeset>) -> HashMap<ChangesetId, ChildI
     roots
 }
 
+/// Fail early if some of the roots are not in the repo, instead of only once
+/// the ancestors of the bookmark have been searched for them.
+async fn check_roots_exist(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    roots: &HashMap<ChangesetId, ChildIndex>,
+) -> Result<(), PushrebaseError> {
+    let exists = repo
+        .get_changesets_object()
+        .exists_many(ctx, roots.keys().copied().collect())
+        .await?;
+    let missing = exists
+        .into_iter()
+        .filter_map(|(root, exists)| if exists { None } else { Some(root) })
+        .min();
+    match missing {
+        Some(root) => Err(PushrebaseInternalError::RootNotFound(root).into()),
+        None => Ok(()),
+    }
+}
+
 async fn find_closest_root(
     ctx: &CoreContext,
     repo: &BlobRepo,
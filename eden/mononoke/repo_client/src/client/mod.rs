@@ -60,7 +60,6 @@ use metaconfig_types::{RepoClientKnobs, RepoReadOnly};
 use mononoke_repo::{MononokeRepo, SqlStreamingCloneConfig};
 use mononoke_types::{hash::GitSha1, ChangesetId};
 use nonzero_ext::nonzero;
-use rand::{self, Rng};
 use rate_limiting::Metric;
 use regex::Regex;
 use remotefilelog::{
@@ -84,7 +83,7 @@ use std::sync::{
 use std::time::{Duration, Instant};
 use streaming_clone::RevlogStreamingChunks;
 use time_ext::DurationExt;
-use tunables::tunables;
+use tunables::{rollout_hash, tunables};
 
 mod logging;
 mod monitor;
@@ -631,8 +630,9 @@ impl RepoClient {
         ctx: CoreContext,
         params: GettreepackArgs,
     ) -> BoxStream<BytesOld, Error> {
-        let hash_validation_percentage = tunables().get_hash_validation_percentage();
-        let validate_hash = ((rand::random::<usize>() % 100) as i64) < hash_validation_percentage;
+        let validate_hash = tunables().get_hash_validation_percentage_rollout(rollout_hash(
+            ctx.metadata().session_id().as_str(),
+        ));
 
         let undesired_path_logger =
             try_boxstream!(UndesiredPathLogger::new(ctx.clone(), self.repo.blobrepo()));
@@ -697,9 +697,9 @@ impl RepoClient {
 
             let lfs_params = self.lfs_params();
 
-            let hash_validation_percentage = tunables().get_hash_validation_percentage();
-            let validate_hash =
-                rand::thread_rng().gen_ratio(hash_validation_percentage as u32, 100);
+            let validate_hash = tunables().get_hash_validation_percentage_rollout(rollout_hash(
+                ctx.metadata().session_id().as_str(),
+            ));
             let getpack_buffer_size = 500;
 
             let request_stream = move || {
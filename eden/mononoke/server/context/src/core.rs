@@ -11,7 +11,9 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use slog::Logger;
 use slog_glog_fmt::logger_that_can_work_in_tests;
 use sshrelay::Metadata;
+use std::future::Future;
 use std::sync::Arc;
+use tunables::{tunables, with_tunables_async_arc, TunablesReference};
 
 use crate::logging::{LoggingContainer, SamplingKey};
 use crate::perf_counters::PerfCounters;
@@ -119,4 +121,84 @@ impl CoreContext {
     pub fn fork_perf_counters(&mut self) -> Arc<PerfCounters> {
         self.logging.fork_perf_counters()
     }
+
+    /// The tunables for this context: the tunables in effect where this is
+    /// called, i.e. those of the enclosing `with_tunables` scope if any, or
+    /// the process-wide ones, with the session's override on top. The
+    /// overridden tunables are only rebuilt when those tunables change.
+    pub fn tunables(&self) -> TunablesReference {
+        let base = tunables();
+        match self.session.tunables_override() {
+            Some(tunables_override) => {
+                TunablesReference::Override(tunables_override.apply_to(base))
+            }
+            None => base,
+        }
+    }
+
+    /// Run `fut` with the tunables of this context in effect, so that
+    /// `tunables::tunables()` calls made by code that has no access to the
+    /// context see the session's override too. Request handlers should wrap
+    /// themselves in this.
+    pub async fn scope_tunables<Out>(&self, fut: impl Future<Output = Out>) -> Out {
+        match self.session.tunables_override() {
+            Some(tunables_override) => {
+                let tunables = tunables_override.apply_to(tunables());
+                with_tunables_async_arc(tunables, fut).await
+            }
+            None => fut.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::{btreemap, hashmap};
+    use tunables::{with_tunables_async, MononokeTunables, TunableValue, TunablesOverride};
+
+    fn test_session(fb: FacebookInit) -> SessionContainer {
+        let tunables_override = TunablesOverride::new(btreemap! {
+            "filenodes_disabled".to_string() => TunableValue::Bool(true),
+        })
+        .unwrap();
+        SessionContainer::builder(fb)
+            .tunables_override(Arc::new(tunables_override))
+            .build()
+    }
+
+    #[fbinit::test]
+    fn test_tunables_override(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock(fb);
+        assert!(!ctx.tunables().get_filenodes_disabled());
+
+        let ctx = CoreContext::test_mock_session(test_session(fb));
+        assert!(ctx.tunables().get_filenodes_disabled());
+        // The process-wide tunables are unaffected.
+        assert!(!tunables().get_filenodes_disabled());
+    }
+
+    #[fbinit::test]
+    async fn test_scope_tunables(fb: FacebookInit) {
+        let ctx = CoreContext::test_mock_session(test_session(fb));
+
+        let base = MononokeTunables::default();
+        base.update_ints(&hashmap! { "warm_bookmark_cache_delay".to_string() => 5 });
+        with_tunables_async(base, async {
+            // The override is layered over the tunables of the enclosing
+            // scope.
+            assert!(ctx.tunables().get_filenodes_disabled());
+            assert_eq!(ctx.tunables().get_warm_bookmark_cache_delay(), 5);
+            assert!(!tunables().get_filenodes_disabled());
+
+            // Within `scope_tunables`, code without the context sees the
+            // override too.
+            ctx.scope_tunables(async {
+                assert!(tunables().get_filenodes_disabled());
+                assert_eq!(tunables().get_warm_bookmark_cache_delay(), 5);
+            })
+            .await;
+        })
+        .await;
+    }
 }
@@ -12,6 +12,7 @@ use ratelimit_meter::{algorithms::LeakyBucket, DirectRateLimiter};
 use sshrelay::Metadata;
 use std::num::NonZeroU32;
 use std::sync::Arc;
+use tunables::TunablesOverride;
 
 use super::{SessionClass, SessionContainer, SessionContainerInner};
 
@@ -19,6 +20,8 @@ pub struct SessionContainerBuilder {
     fb: FacebookInit,
     inner: SessionContainerInner,
     session_class: SessionClass,
+    show_hidden_changesets: bool,
+    tunables_override: Option<Arc<TunablesOverride>>,
 }
 
 impl SessionContainerBuilder {
@@ -27,6 +30,8 @@ impl SessionContainerBuilder {
             fb: self.fb,
             inner: Arc::new(self.inner),
             session_class: self.session_class,
+            show_hidden_changesets: self.show_hidden_changesets,
+            tunables_override: self.tunables_override,
         }
     }
 
@@ -40,6 +45,8 @@ impl SessionContainerBuilder {
                 blobstore_read_limiter: None,
             },
             session_class: SessionClass::UserWaiting,
+            show_hidden_changesets: false,
+            tunables_override: None,
         }
     }
 
@@ -82,4 +89,14 @@ impl SessionContainerBuilder {
         self.session_class = value;
         self
     }
+
+    pub fn show_hidden_changesets(mut self, value: bool) -> Self {
+        self.show_hidden_changesets = value;
+        self
+    }
+
+    pub fn tunables_override(mut self, value: impl Into<Option<Arc<TunablesOverride>>>) -> Self {
+        self.tunables_override = value.into();
+        self
+    }
 }
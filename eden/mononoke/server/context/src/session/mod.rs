@@ -14,6 +14,7 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use slog::Logger;
 use sshrelay::Metadata;
 use std::sync::Arc;
+use tunables::TunablesOverride;
 
 pub use self::builder::SessionContainerBuilder;
 use crate::core::CoreContext;
@@ -26,6 +27,8 @@ pub struct SessionContainer {
     fb: FacebookInit,
     inner: Arc<SessionContainerInner>,
     session_class: SessionClass,
+    show_hidden_changesets: bool,
+    tunables_override: Option<Arc<TunablesOverride>>,
 }
 
 /// Represents the reason this session is running
@@ -141,4 +144,24 @@ impl SessionContainer {
     pub fn override_session_class(&mut self, session_class: SessionClass) {
         self.session_class = session_class;
     }
+
+    /// Whether changesets that are hidden (e.g. redacted) should still be
+    /// returned by changeset lookups. Only admin tooling should set this.
+    pub fn show_hidden_changesets(&self) -> bool {
+        self.show_hidden_changesets
+    }
+
+    pub fn override_show_hidden_changesets(&mut self, show_hidden_changesets: bool) {
+        self.show_hidden_changesets = show_hidden_changesets;
+    }
+
+    /// Tunables to override for this session, on top of the process-wide
+    /// ones, e.g. to roll a killswitch out to some requests only.
+    pub fn tunables_override(&self) -> Option<&Arc<TunablesOverride>> {
+        self.tunables_override.as_ref()
+    }
+
+    pub fn override_tunables(&mut self, tunables_override: Option<Arc<TunablesOverride>>) {
+        self.tunables_override = tunables_override;
+    }
 }
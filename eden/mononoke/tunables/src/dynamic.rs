@@ -0,0 +1,228 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Tunables that are registered at runtime rather than declared as fields of
+//! `MononokeTunables`. They are read from the same config source and updated
+//! by the same worker as the static tunables.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use tunables_structs::Tunables as TunablesStruct;
+
+use crate::TunableString;
+
+static DYNAMIC_TUNABLES: Lazy<DynamicTunables> = Lazy::new(DynamicTunables::default);
+
+/// Handle to a bool tunable registered with `register_tunable_bool`.
+#[derive(Clone, Debug)]
+pub struct DynamicTunableBool(Arc<AtomicBool>);
+
+impl DynamicTunableBool {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle to an int tunable registered with `register_tunable_int`.
+#[derive(Clone, Debug)]
+pub struct DynamicTunableI64(Arc<AtomicI64>);
+
+impl DynamicTunableI64 {
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle to a string tunable registered with `register_tunable_string`.
+#[derive(Clone, Debug)]
+pub struct DynamicTunableString(Arc<TunableString>);
+
+impl DynamicTunableString {
+    pub fn get(&self) -> Arc<String> {
+        self.0.load_full()
+    }
+}
+
+#[derive(Default)]
+struct DynamicTunablesInner {
+    bools: HashMap<String, Arc<AtomicBool>>,
+    ints: HashMap<String, Arc<AtomicI64>>,
+    strings: HashMap<String, Arc<TunableString>>,
+    // Last config that was applied, used to initialize tunables that are
+    // registered after the worker has already started.
+    latest: Option<Arc<TunablesStruct>>,
+}
+
+/// Registry of tunables defined at runtime.
+#[derive(Default)]
+pub struct DynamicTunables {
+    inner: Mutex<DynamicTunablesInner>,
+}
+
+impl DynamicTunables {
+    pub fn register_bool(&self, name: &str) -> DynamicTunableBool {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let initial = inner
+            .latest
+            .as_ref()
+            .and_then(|t| t.killswitches.get(name).cloned())
+            .unwrap_or_default();
+        let tunable = inner
+            .bools
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(initial)));
+        DynamicTunableBool(tunable.clone())
+    }
+
+    pub fn register_int(&self, name: &str) -> DynamicTunableI64 {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let initial = inner
+            .latest
+            .as_ref()
+            .and_then(|t| t.ints.get(name).cloned())
+            .unwrap_or_default();
+        let tunable = inner
+            .ints
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AtomicI64::new(initial)));
+        DynamicTunableI64(tunable.clone())
+    }
+
+    pub fn register_string(&self, name: &str) -> DynamicTunableString {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let initial = inner
+            .latest
+            .as_ref()
+            .and_then(|t| t.strings.get(name).cloned())
+            .unwrap_or_default();
+        let tunable = inner
+            .strings
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(ArcSwap::from_pointee(initial)));
+        DynamicTunableString(tunable.clone())
+    }
+
+    /// Apply a new config to all registered tunables. Tunables missing from
+    /// the config are reset to their default value, like static tunables.
+    pub fn update(&self, new_tunables: Arc<TunablesStruct>) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        for (name, tunable) in inner.bools.iter() {
+            tunable.store(
+                new_tunables
+                    .killswitches
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default(),
+                Ordering::Relaxed,
+            );
+        }
+        for (name, tunable) in inner.ints.iter() {
+            tunable.store(
+                new_tunables.ints.get(name).cloned().unwrap_or_default(),
+                Ordering::Relaxed,
+            );
+        }
+        for (name, tunable) in inner.strings.iter() {
+            tunable.store(Arc::new(
+                new_tunables.strings.get(name).cloned().unwrap_or_default(),
+            ));
+        }
+        inner.latest = Some(new_tunables);
+    }
+
+    /// Whether a tunable called `name` has been registered, of any type.
+    pub fn is_registered(&self, name: &str) -> bool {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        inner.bools.contains_key(name)
+            || inner.ints.contains_key(name)
+            || inner.strings.contains_key(name)
+    }
+}
+
+pub(crate) fn dynamic_tunables() -> &'static DynamicTunables {
+    &DYNAMIC_TUNABLES
+}
+
+/// Register a bool tunable that is read from the `killswitches` section of
+/// the tunables config. Registering the same name twice returns handles to
+/// the same value.
+pub fn register_tunable_bool(name: &str) -> DynamicTunableBool {
+    dynamic_tunables().register_bool(name)
+}
+
+/// Register an int tunable that is read from the `ints` section of the
+/// tunables config.
+pub fn register_tunable_int(name: &str) -> DynamicTunableI64 {
+    dynamic_tunables().register_int(name)
+}
+
+/// Register a string tunable that is read from the `strings` section of the
+/// tunables config.
+pub fn register_tunable_string(name: &str) -> DynamicTunableString {
+    dynamic_tunables().register_string(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    fn s(a: &str) -> String {
+        a.to_string()
+    }
+
+    #[test]
+    fn test_register_and_update() {
+        let registry = DynamicTunables::default();
+        let flag = registry.register_bool("flag");
+        let num = registry.register_int("num");
+        let string = registry.register_string("string");
+        assert!(!flag.get());
+        assert_eq!(num.get(), 0);
+        assert_eq!(string.get().as_str(), "");
+
+        registry.update(Arc::new(TunablesStruct {
+            killswitches: hashmap! { s("flag") => true },
+            ints: hashmap! { s("num") => 5 },
+            strings: hashmap! { s("string") => s("value") },
+            ..Default::default()
+        }));
+        assert!(flag.get());
+        assert_eq!(num.get(), 5);
+        assert_eq!(string.get().as_str(), "value");
+
+        registry.update(Arc::new(TunablesStruct::default()));
+        assert!(!flag.get());
+        assert_eq!(num.get(), 0);
+        assert_eq!(string.get().as_str(), "");
+    }
+
+    #[test]
+    fn test_register_after_update() {
+        let registry = DynamicTunables::default();
+        registry.update(Arc::new(TunablesStruct {
+            ints: hashmap! { s("num") => 7 },
+            ..Default::default()
+        }));
+
+        let num = registry.register_int("num");
+        assert_eq!(num.get(), 7);
+
+        // Registering again shares the underlying value.
+        let num2 = registry.register_int("num");
+        registry.update(Arc::new(TunablesStruct {
+            ints: hashmap! { s("num") => 8 },
+            ..Default::default()
+        }));
+        assert_eq!(num.get(), 8);
+        assert_eq!(num2.get(), 8);
+    }
+}
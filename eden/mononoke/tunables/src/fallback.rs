@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A local copy of the last tunables config that was applied, so that a
+//! process that starts while the config can't be fetched doesn't run with
+//! default tunables. The copy is the config as fetched, before overrides are
+//! resolved, so it can be shared by processes on different tiers.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tunables_structs::Tunables as TunablesStruct;
+
+/// Where to keep the last applied tunables config, and how old it may be to
+/// still be used at startup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunablesFallback {
+    pub path: PathBuf,
+    pub max_age: Duration,
+}
+
+impl TunablesFallback {
+    pub fn new(path: impl Into<PathBuf>, max_age: Duration) -> Self {
+        Self {
+            path: path.into(),
+            max_age,
+        }
+    }
+
+    /// The saved config, or `None` if there is none or it is older than
+    /// `max_age`.
+    pub(crate) fn load(&self) -> Result<Option<Arc<TunablesStruct>>> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to stat {}", self.path.display()));
+            }
+        };
+        // A modification time in the future counts as fresh.
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if age > self.max_age {
+            return Ok(None);
+        }
+        let content = fs::read(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let tunables = serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse {}", self.path.display()))?;
+        Ok(Some(Arc::new(tunables)))
+    }
+
+    /// Replace the saved config with `tunables`. The config is written to a
+    /// temporary file that is renamed over the old one, so a crash never
+    /// leaves a partially written config behind.
+    pub(crate) fn save(&self, tunables: &TunablesStruct) -> Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let content = serde_json::to_vec(tunables)?;
+        let mut file = fs::File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fallback = TunablesFallback::new(dir.path().join("tunables.json"), Duration::MAX);
+        assert_eq!(fallback.load()?, None);
+
+        let tunables = TunablesStruct {
+            ints: hashmap! { "num".to_string() => 1 },
+            ..Default::default()
+        };
+        fallback.save(&tunables)?;
+        assert_eq!(fallback.load()?.as_deref(), Some(&tunables));
+
+        // A stale config is not used.
+        std::thread::sleep(Duration::from_millis(10));
+        let stale = TunablesFallback::new(&fallback.path, Duration::from_millis(1));
+        assert_eq!(stale.load()?, None);
+
+        std::fs::write(&fallback.path, "not json")?;
+        assert!(fallback.load().is_err());
+        Ok(())
+    }
+}
@@ -6,31 +6,94 @@
  */
 
 use std::cell::RefCell;
+use std::fmt::Display;
 use std::ops::Deref;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::thread_local;
 use std::time::Duration;
 
-use anyhow::Result;
-use arc_swap::ArcSwap;
+use anyhow::{anyhow, Context, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
 use cached_config::ConfigHandle;
-use futures::{future::poll_fn, Future, FutureExt};
-use once_cell::sync::OnceCell;
+use futures::{Future, FutureExt};
+use once_cell::sync::{Lazy, OnceCell};
 use slog::{debug, warn, Logger};
-use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 
 use tunables_derive::Tunables;
 use tunables_structs::Tunables as TunablesStruct;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+mod dynamic;
+mod fallback;
+mod overrides;
+mod pinned;
+mod rollout;
+mod schema;
+mod units;
+mod validation;
+
+pub use crate::fallback::TunablesFallback;
+pub use crate::overrides::TunablesTarget;
+pub use crate::pinned::parse_tunable_arg;
+pub use crate::rollout::{in_rollout, rollout_hash};
+pub use crate::schema::TunableKind;
+pub use crate::units::{parse_byte_size, parse_duration};
+pub use crate::validation::{
+    register_int_range_validator, register_tunables_validator, TunablesValidatorFn,
+    TunablesValidators,
+};
+
+pub use crate::dynamic::{
+    register_tunable_bool, register_tunable_int, register_tunable_string, DynamicTunableBool,
+    DynamicTunableI64, DynamicTunableString,
+};
 
 static TUNABLES: OnceCell<MononokeTunables> = OnceCell::new();
-static TUNABLES_WORKER_STATE: OnceCell<Mutex<TunablesWorkerState>> = OnceCell::new();
-const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// The state of the last tunables worker started, kept after it is shut down
+// so that tunables can still be refreshed on demand.
+static TUNABLES_WORKER_STATE: OnceCell<Mutex<Option<TunablesWorkerState>>> = OnceCell::new();
+/// How often the tunables worker checks for config changes by default.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// How old a saved config can be to be used by default, see `TunablesFallback`.
+pub const DEFAULT_FALLBACK_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
 
 thread_local! {
-    static TUNABLES_OVERRIDE: RefCell<Option<Arc<MononokeTunables>>> = RefCell::new(None);
+    static TUNABLES_OVERRIDE: RefCell<Option<ScopedTunables>> = RefCell::new(None);
+}
+
+tokio::task_local! {
+    // Overrides for async code. Unlike the thread-local above, this follows
+    // the future when it's moved between threads.
+    static TUNABLES_TASK_OVERRIDE: ScopedTunables;
+}
+
+/// Incremented each time an override comes into effect, so that when both a
+/// thread-local and a task-local override are set, the innermost one wins.
+static OVERRIDE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Incremented each time the tunables are updated from config, so that
+/// tunables built from them can tell when to be rebuilt.
+static UPDATE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+struct ScopedTunables {
+    sequence: u64,
+    tunables: Arc<MononokeTunables>,
+}
+
+impl ScopedTunables {
+    fn new(tunables: Arc<MononokeTunables>) -> Self {
+        Self {
+            sequence: OVERRIDE_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            tunables,
+        }
+    }
 }
 
 pub enum TunablesReference {
@@ -50,19 +113,223 @@ impl Deref for TunablesReference {
 }
 
 pub fn tunables() -> TunablesReference {
-    TUNABLES_OVERRIDE.with(|tunables_override| match *tunables_override.borrow() {
-        Some(ref arc) => TunablesReference::Override(arc.clone()),
+    match current_override() {
+        Some(arc) => TunablesReference::Override(arc),
         None => TunablesReference::Static(TUNABLES.get_or_init(MononokeTunables::default)),
-    })
+    }
+}
+
+fn current_override() -> Option<Arc<MononokeTunables>> {
+    let task_override = TUNABLES_TASK_OVERRIDE
+        .try_with(|tunables_override| tunables_override.clone())
+        .ok();
+    let thread_override =
+        TUNABLES_OVERRIDE.with(|tunables_override| tunables_override.borrow().clone());
+    let innermost = match (task_override, thread_override) {
+        (Some(task), Some(thread)) if thread.sequence > task.sequence => Some(thread),
+        (Some(task), _) => Some(task),
+        (None, thread) => thread,
+    };
+    innermost.map(|scoped| scoped.tunables)
 }
 
 // This type exists to simplify code generation in tunables-derive
 pub type TunableString = ArcSwap<String>;
 
-pub type TunableBoolByRepo = ArcSwap<HashMap<String, bool>>;
-pub type TunableStringByRepo = ArcSwap<HashMap<String, String>>;
-pub type TunableVecOfStringsByRepo = ArcSwap<HashMap<String, Vec<String>>>;
-pub type TunableI64ByRepo = ArcSwap<HashMap<String, i64>>;
+pub type TunableBoolByRepo = TunableByRepo<bool>;
+pub type TunableStringByRepo = TunableByRepo<String>;
+pub type TunableVecOfStringsByRepo = TunableByRepo<Vec<String>>;
+pub type TunableI64ByRepo = TunableByRepo<i64>;
+/// Configured in `durations_by_repo`, with the same format as `durations`.
+pub type TunableDurationByRepo = TunableByRepo<Duration>;
+/// A size in bytes, configured in `byte_sizes_by_repo` with an explicit unit,
+/// e.g. "512KB" or "2GiB" (see `parse_byte_size`).
+pub type TunableByteSizeByRepo = TunableByRepo<u64>;
+
+/// Key of the entry in a by-repo map that applies to all repos that don't
+/// have an entry of their own.
+pub const BY_REPO_WILDCARD: &str = "*";
+
+/// A tunable whose value depends on the repo. Besides the per-repo values, it
+/// holds the value of the global tunable with the same name (if any), which
+/// is used as the last fallback by `get_by_repo_<name>_or_default`.
+#[derive(Debug)]
+pub struct TunableByRepo<T> {
+    by_repo: ArcSwap<HashMap<String, T>>,
+    global: ArcSwapOption<T>,
+}
+
+impl<T> Default for TunableByRepo<T> {
+    fn default() -> Self {
+        Self {
+            by_repo: ArcSwap::from_pointee(HashMap::new()),
+            global: ArcSwapOption::empty(),
+        }
+    }
+}
+
+impl<T: Clone> TunableByRepo<T> {
+    pub fn load_by_repo(&self) -> Arc<HashMap<String, T>> {
+        self.by_repo.load_full()
+    }
+
+    pub fn store_by_repo(&self, values: HashMap<String, T>) {
+        self.by_repo.store(Arc::new(values));
+    }
+
+    pub fn load_global(&self) -> Option<T> {
+        self.global.load_full().map(|val| (*val).clone())
+    }
+
+    pub fn store_global(&self, value: Option<T>) {
+        self.global.store(value.map(Arc::new));
+    }
+
+    /// Value for this repo, falling back to the wildcard entry, then to the
+    /// global tunable of the same name.
+    pub fn get_or_fallback(&self, repo: &str) -> Option<T> {
+        let by_repo = self.by_repo.load();
+        by_repo
+            .get(repo)
+            .or_else(|| by_repo.get(BY_REPO_WILDCARD))
+            .cloned()
+            .or_else(|| self.load_global())
+    }
+
+    /// Per-repo values in a stable order, for snapshots.
+    pub fn sorted_by_repo(&self) -> BTreeMap<String, T> {
+        self.by_repo
+            .load()
+            .iter()
+            .map(|(repo, val)| (repo.clone(), val.clone()))
+            .collect()
+    }
+}
+
+/// An f64 tunable. There is no `AtomicF64` in std, so the value is stored as
+/// its bit pattern in an `AtomicU64`.
+#[derive(Debug, Default)]
+pub struct TunableF64(AtomicU64);
+
+impl TunableF64 {
+    pub fn new(value: f64) -> Self {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+
+    pub fn load(&self, ordering: Ordering) -> f64 {
+        f64::from_bits(self.0.load(ordering))
+    }
+
+    pub fn store(&self, value: f64, ordering: Ordering) {
+        self.0.store(value.to_bits(), ordering)
+    }
+}
+
+/// A duration tunable, stored as a number of nanoseconds. In the config it
+/// is written with an explicit unit, e.g. "500ms" (see `parse_duration`).
+#[derive(Debug, Default)]
+pub struct TunableDuration(AtomicU64);
+
+impl TunableDuration {
+    pub fn new(value: Duration) -> Self {
+        Self(AtomicU64::new(Self::to_nanos(value)))
+    }
+
+    pub fn load(&self, ordering: Ordering) -> Duration {
+        Duration::from_nanos(self.0.load(ordering))
+    }
+
+    pub fn store(&self, value: Duration, ordering: Ordering) {
+        self.0.store(Self::to_nanos(value), ordering)
+    }
+
+    fn to_nanos(value: Duration) -> u64 {
+        value.as_nanos().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+/// A tunable that takes one of a fixed set of values, like a rollout stage.
+/// It is configured in `strings` and parsed with `FromStr`, and has the
+/// `Default` value while it isn't set. A config where it doesn't parse is
+/// rejected as a whole.
+#[derive(Debug)]
+pub struct TunableEnum<T>(ArcSwap<T>);
+
+impl<T: Default> Default for TunableEnum<T> {
+    fn default() -> Self {
+        Self(ArcSwap::from_pointee(T::default()))
+    }
+}
+
+impl<T> TunableEnum<T>
+where
+    T: FromStr + Default + Display + Clone,
+    T::Err: Display,
+{
+    pub fn new(value: T) -> Self {
+        Self(ArcSwap::from_pointee(value))
+    }
+
+    pub fn load(&self) -> T {
+        (**self.0.load()).clone()
+    }
+
+    pub fn store(&self, value: T) {
+        self.0.store(Arc::new(value))
+    }
+
+    pub fn parse(name: &str, value: &str) -> Result<T> {
+        value
+            .parse()
+            .map_err(|e| anyhow!("Failed to parse tunable {} from {:?}: {}", name, value, e))
+    }
+
+    /// Set the tunable to a configured value. A value that doesn't parse
+    /// resets it to the default, like a missing one.
+    pub fn update(&self, name: &str, value: Option<&String>) {
+        let value = value
+            .and_then(|value| Self::parse(name, value).ok())
+            .unwrap_or_default();
+        self.store(value)
+    }
+}
+
+/// The value of a single tunable at a point in time, as returned by the
+/// `snapshot` and `diff` methods generated for a tunables struct.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunableValue {
+    Bool(bool),
+    I64(i64),
+    F64(f64),
+    Duration(Duration),
+    String(String),
+    /// The value of a `TunableEnum`, as it is written in configs.
+    Enum(String),
+    BoolByRepo {
+        global: Option<bool>,
+        by_repo: BTreeMap<String, bool>,
+    },
+    I64ByRepo {
+        global: Option<i64>,
+        by_repo: BTreeMap<String, i64>,
+    },
+    StringByRepo {
+        global: Option<String>,
+        by_repo: BTreeMap<String, String>,
+    },
+    VecOfStringsByRepo {
+        global: Option<Vec<String>>,
+        by_repo: BTreeMap<String, Vec<String>>,
+    },
+    DurationByRepo {
+        global: Option<Duration>,
+        by_repo: BTreeMap<String, Duration>,
+    },
+    ByteSizeByRepo {
+        global: Option<u64>,
+        by_repo: BTreeMap<String, u64>,
+    },
+}
 
 #[derive(Tunables, Default, Debug)]
 pub struct MononokeTunables {
@@ -128,6 +395,7 @@ pub struct MononokeTunables {
     // All blobstore read request with size bigger than
     // this threshold will be logged to scuba
     blobstore_read_size_logging_threshold: AtomicI64,
+    #[rollout]
     hash_validation_percentage: AtomicI64,
     // Filter out commits that we already have in infinitepush. Shouldn't be needed if we have a
     // client exchanging commits with us, but when processing bundled uploads (i.e. commit cloud
@@ -139,6 +407,11 @@ pub struct MononokeTunables {
     xrepo_sync_disable_all_syncs: AtomicBool,
     xrepo_disable_commit_sync_lease: AtomicBool,
 
+    // Throttling of backsync, by target repo, so that a large backlog doesn't
+    // overwhelm the target repo. Zero or unset means no limit.
+    backsyncer_commits_per_second: TunableI64ByRepo,
+    backsyncer_entries_per_iteration: TunableI64ByRepo,
+
     // Use Background session class while deriving data. This makes derived data not write
     // data to blobstore sync queue if a write was successful to the main blobstore.
     derived_data_use_background_session_class: TunableBoolByRepo,
@@ -178,6 +451,11 @@ pub struct MononokeTunables {
     // Set to 0 to disable compression
     zstd_compression_level: AtomicI64,
 
+    // Percentage of sessions whose sqlblob gets check the value against the
+    // checksum stored with it
+    #[rollout]
+    sqlblob_checksum_verify_percentage: AtomicI64,
+
     // Commits that aren't related (i.e. that are not ancestors of each other)
     // can be derived in parallel, and that's what derived data does.
     // derived_data_disable_parallel_derivation is a killswitch to disable
@@ -226,40 +504,127 @@ pub struct MononokeTunables {
     is_present_timeout_ms: AtomicI64,
 }
 
+/// Names of the tunables in `MononokeTunables`.
+pub type TunableKey = MononokeTunablesKey;
+
 fn log_tunables(tunables: &TunablesStruct) -> String {
     serde_json::to_string(tunables)
         .unwrap_or_else(|e| format!("failed to serialize tunables: {}", e))
 }
 
+/// Handle to the tunables worker started by `init_tunables_worker`.
+///
+/// Dropping the handle leaves the worker running until the runtime shuts
+/// down. Use `shutdown` to stop it explicitly, after which a new worker can
+/// be started.
+pub struct TunablesWorker {
+    shutdown: Arc<Notify>,
+    join_handle: JoinHandle<()>,
+}
+
+impl TunablesWorker {
+    /// Stop refreshing tunables, and wait for the worker to exit.
+    /// The current tunables are kept.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.notify_one();
+        let res = self
+            .join_handle
+            .await
+            .context("Tunables worker failed to shut down");
+        if let Some(state) = worker_state().lock().expect("Poisoned lock").as_mut() {
+            state.running = false;
+        }
+        res
+    }
+}
+
+fn worker_state() -> &'static Mutex<Option<TunablesWorkerState>> {
+    TUNABLES_WORKER_STATE.get_or_init(|| Mutex::new(None))
+}
+
+/// Load tunables from `config_handle`, and spawn a worker on `runtime` that
+/// refreshes them every `refresh_interval`. Overrides in the config are
+/// resolved against `target`.
+///
+/// With a `fallback`, each config that is applied is saved to it, and a saved
+/// config that is recent enough is applied first at startup. Until
+/// `config_handle` has a config, the tunables then keep their saved values
+/// instead of the defaults.
+///
+/// Only one worker can run at a time: this fails if the previous one was not
+/// shut down.
 pub fn init_tunables_worker(
     logger: Logger,
     config_handle: ConfigHandle<TunablesStruct>,
-) -> Result<()> {
-    let init_tunables = config_handle.get();
-    debug!(
+    refresh_interval: Duration,
+    target: TunablesTarget,
+    fallback: Option<TunablesFallback>,
+    runtime: &Handle,
+) -> Result<TunablesWorker> {
+    let mut current_state = worker_state().lock().expect("Poisoned lock");
+    if current_state.as_ref().map_or(false, |state| state.running) {
+        return Err(anyhow!("A tunables worker is already running"));
+    }
+
+    let mut state = TunablesWorkerState {
+        config_handle,
+        old_tunables: None,
+        target,
         logger,
+        fallback,
+        on_fallback: false,
+        applied: None,
+        running: true,
+    };
+
+    if let Some(fallback) = &state.fallback {
+        match fallback.load() {
+            Ok(Some(fallback_tunables)) => {
+                debug!(
+                    state.logger,
+                    "Initializing tunables from {}: {}",
+                    fallback.path.display(),
+                    log_tunables(&fallback_tunables)
+                );
+                match update_tunables(&state.logger, fallback_tunables.clone(), &state.target) {
+                    Ok(()) => {
+                        state.on_fallback = true;
+                        state.applied = Some(fallback_tunables);
+                    }
+                    Err(e) => warn!(state.logger, "Failed to apply fallback tunables: {:#}", e),
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!(state.logger, "Failed to load fallback tunables: {:#}", e),
+        }
+    }
+
+    let init_tunables = state.config_handle.get();
+    debug!(
+        state.logger,
         "Initializing tunables: {}",
         log_tunables(&init_tunables)
     );
-    update_tunables(init_tunables.clone())?;
-
-    if TUNABLES_WORKER_STATE
-        .set(Mutex::new(TunablesWorkerState {
-            config_handle,
-            old_tunables: Some(init_tunables),
-            logger,
-        }))
-        .is_err()
-    {
-        panic!("Two or more tunables update threads exist at the same time");
+    if let Err(e) = state.apply(init_tunables) {
+        if !state.on_fallback {
+            return Err(e);
+        }
+        warn!(
+            state.logger,
+            "Failed to initialize tunables, keeping fallback tunables: {:#}", e
+        );
     }
 
-    thread::Builder::new()
-        .name("mononoke-tunables".into())
-        .spawn(worker)
-        .expect("Can't spawn tunables updater");
+    *current_state = Some(state);
+    drop(current_state);
 
-    Ok(())
+    let shutdown = Arc::new(Notify::new());
+    let join_handle = runtime.spawn(worker(refresh_interval, shutdown.clone()));
+
+    Ok(TunablesWorker {
+        shutdown,
+        join_handle,
+    })
 }
 
 /// Tunables are updated in loop with sleeps. Call this to force update them.
@@ -274,24 +639,100 @@ struct TunablesWorkerState {
     // Previous value of the tunables.  If we fail to update tunables,
     // this will be `None`.
     old_tunables: Option<Arc<TunablesStruct>>,
+    target: TunablesTarget,
     logger: Logger,
+    fallback: Option<TunablesFallback>,
+    // Whether the tunables are still the ones loaded from `fallback`.
+    on_fallback: bool,
+    // The config the tunables were last updated from, to update them again
+    // when the pinned tunables change.
+    applied: Option<Arc<TunablesStruct>>,
+    // Whether the worker using this state was not shut down yet.
+    running: bool,
+}
+
+impl TunablesWorkerState {
+    fn apply(&mut self, new_tunables: Arc<TunablesStruct>) -> Result<()> {
+        // The config handle has an empty config until the config is fetched.
+        // Keep the fallback tunables rather than resetting them to defaults.
+        if self.on_fallback && *new_tunables == TunablesStruct::default() {
+            self.old_tunables = Some(new_tunables);
+            return Ok(());
+        }
+
+        if let Err(e) = update_tunables(&self.logger, new_tunables.clone(), &self.target) {
+            self.old_tunables = None;
+            return Err(e);
+        }
+        self.on_fallback = false;
+        if let Some(fallback) = &self.fallback {
+            if let Err(e) = fallback.save(&new_tunables) {
+                warn!(self.logger, "Failed to save fallback tunables: {:#}", e);
+            }
+        }
+        self.applied = Some(new_tunables.clone());
+        self.old_tunables = Some(new_tunables);
+        Ok(())
+    }
+}
+
+/// Pin tunables to values given on the command line, as `(name, value)`
+/// pairs (see `parse_tunable_arg`). Pinned values take precedence over the
+/// config, including its host and tier overrides, and are applied again
+/// each time the config changes.
+///
+/// Each call replaces the tunables pinned by the previous one. The tunables
+/// are updated right away, whether the tunables worker was started yet or
+/// not. Invalid values are rejected, and leave the tunables unchanged.
+pub fn apply_cli_overrides(pairs: &[(String, String)]) -> Result<()> {
+    let pinned = pinned::parse_pinned_tunables(&MononokeTunables::schema(), pairs)?;
+    let previous = pinned::set_pinned_tunables(Some(Arc::new(pinned)));
+    if let Err(e) = reapply_tunables() {
+        pinned::set_pinned_tunables(previous);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The tunables pinned by `apply_cli_overrides`, with their values as they
+/// were given.
+pub fn pinned_tunables() -> BTreeMap<String, String> {
+    pinned::pinned_tunables()
+        .map(|pinned| pinned.args.clone())
+        .unwrap_or_default()
 }
 
-fn worker() {
+/// Update the tunables from the config they were last updated from, or from
+/// an empty config if the tunables worker wasn't started.
+fn reapply_tunables() -> Result<()> {
+    match worker_state().lock().expect("Poisoned lock").as_ref() {
+        Some(state) => {
+            let applied = state.applied.clone().unwrap_or_default();
+            update_tunables(&state.logger, applied, &state.target)
+        }
+        None => update_tunables(
+            &Logger::root(slog::Discard, slog::o!()),
+            Arc::new(TunablesStruct::default()),
+            &TunablesTarget::default(),
+        ),
+    }
+}
+
+async fn worker(refresh_interval: Duration, shutdown: Arc<Notify>) {
     loop {
         // TODO: Instead of refreshing tunables every loop iteration,
         // update cached_config to notify us when our config has changed.
         worker_iteration();
-        thread::sleep(REFRESH_INTERVAL);
+        tokio::select! {
+            _ = tokio::time::sleep(refresh_interval) => {}
+            _ = shutdown.notified() => break,
+        }
     }
 }
 
 fn worker_iteration() {
-    let mut state = TUNABLES_WORKER_STATE
-        .get()
-        .expect("Tunables worker state uninitialised")
-        .lock()
-        .expect("Poisoned lock");
+    let mut state = worker_state().lock().expect("Poisoned lock");
+    let state = state.as_mut().expect("Tunables worker state uninitialised");
 
     let new_tunables = state.config_handle.get();
     if Some(&new_tunables) != state.old_tunables.as_ref() {
@@ -304,80 +745,414 @@ fn worker_iteration() {
                 .map_or_else(|| String::from("unknown"), log_tunables),
             log_tunables(&new_tunables),
         );
-        match update_tunables(new_tunables.clone()) {
-            Ok(_) => {
-                state.old_tunables = Some(new_tunables);
-            }
-            Err(e) => {
-                warn!(state.logger, "Failed to refresh tunables: {}", e);
-                state.old_tunables = None;
-            }
+        if let Err(e) = state.apply(new_tunables) {
+            warn!(state.logger, "Failed to refresh tunables: {:#}", e);
         }
     }
 }
 
-fn update_tunables(new_tunables: Arc<TunablesStruct>) -> Result<()> {
+fn parse_values<T>(
+    values: &HashMap<String, String>,
+    parse: fn(&str) -> Result<T>,
+) -> Result<HashMap<String, T>> {
+    values
+        .iter()
+        .map(|(name, value)| {
+            let value =
+                parse(value).with_context(|| format!("Failed to parse tunable {}", name))?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+fn parse_values_by_repo<T>(
+    values_by_repo: &HashMap<String, HashMap<String, String>>,
+    parse: fn(&str) -> Result<T>,
+) -> Result<HashMap<String, HashMap<String, T>>> {
+    values_by_repo
+        .iter()
+        .map(|(repo, values)| {
+            let values = parse_values(values, parse)
+                .with_context(|| format!("Invalid tunables for repo {}", repo))?;
+            Ok((repo.clone(), values))
+        })
+        .collect()
+}
+
+/// The warning for `name`, found in the section of the config for tunables of
+/// type `kind` but not a tunable of that type in `schema`. Names of dynamic
+/// tunables are not in `schema`, and are not warned about.
+fn unknown_tunable_warning(
+    schema: &BTreeMap<&'static str, TunableKind>,
+    kind: TunableKind,
+    name: &str,
+) -> Option<String> {
+    if dynamic::dynamic_tunables().is_registered(name) {
+        return None;
+    }
+    let section = kind.config_section();
+    let warning = match schema.get(name) {
+        Some(actual) => format!(
+            "Ignoring tunable {} in {}, it is read from {}",
+            name,
+            section,
+            actual.config_section()
+        ),
+        None => match schema::nearest_name(name, schema.keys().copied()) {
+            Some(nearest) => format!(
+                "Ignoring unknown tunable {} in {}, did you mean {}?",
+                name, section, nearest
+            ),
+            None => format!("Ignoring unknown tunable {} in {}", name, section),
+        },
+    };
+    Some(warning)
+}
+
+fn collect_unknown_tunables(
+    warnings: &mut BTreeSet<String>,
+    kind: TunableKind,
+    unknown: BTreeSet<String>,
+) {
+    let schema = MononokeTunables::schema();
+    warnings.extend(
+        unknown
+            .iter()
+            .filter_map(|name| unknown_tunable_warning(&schema, kind, name)),
+    );
+}
+
+/// Log the warnings about unknown tunables that were not already there on
+/// the previous update, so that a config that is refreshed over and over
+/// doesn't warn about the same tunables each time.
+fn warn_unknown_tunables(logger: &Logger, warnings: BTreeSet<String>) {
+    static PREVIOUS_WARNINGS: Lazy<Mutex<BTreeSet<String>>> = Lazy::new(Default::default);
+    let mut previous = PREVIOUS_WARNINGS.lock().expect("Poisoned lock");
+    for warning in replace_warnings(&mut previous, warnings) {
+        warn!(logger, "{}", warning);
+    }
+}
+
+/// Replace `previous` with `current`, and return the warnings that are new.
+fn replace_warnings(previous: &mut BTreeSet<String>, current: BTreeSet<String>) -> Vec<String> {
+    let new = current.difference(previous).cloned().collect();
+    *previous = current;
+    new
+}
+
+fn update_tunables(
+    logger: &Logger,
+    new_tunables: Arc<TunablesStruct>,
+    target: &TunablesTarget,
+) -> Result<()> {
+    let pinned = pinned::pinned_tunables();
+    update_tunables_with_pinned(
+        logger,
+        new_tunables,
+        target,
+        pinned.as_deref().map(|pinned| &pinned.values),
+    )
+}
+
+fn update_tunables_with_pinned(
+    logger: &Logger,
+    new_tunables: Arc<TunablesStruct>,
+    target: &TunablesTarget,
+    pinned: Option<&TunablesStruct>,
+) -> Result<()> {
+    // Parse and validate everything before applying anything, so that an
+    // invalid config leaves the previous values in place.
+    let mut new_tunables = overrides::resolve_overrides(&new_tunables, target)?;
+    if let Some(pinned) = pinned {
+        overrides::merge_overrides(&mut new_tunables, pinned);
+    }
+    let new_tunables = Arc::new(new_tunables);
+    let durations = new_tunables
+        .durations
+        .as_ref()
+        .map(|durations| parse_values(durations, parse_duration))
+        .transpose()?;
+    let durations_by_repo = new_tunables
+        .durations_by_repo
+        .as_ref()
+        .map(|durations| parse_values_by_repo(durations, parse_duration))
+        .transpose()?;
+    let byte_sizes_by_repo = new_tunables
+        .byte_sizes_by_repo
+        .as_ref()
+        .map(|byte_sizes| parse_values_by_repo(byte_sizes, parse_byte_size))
+        .transpose()?;
+    MononokeTunables::validate_enums(&new_tunables.strings)?;
+    validation::validators().validate(&new_tunables)?;
+
     let tunables = tunables();
-    tunables.update_bools(&new_tunables.killswitches);
-    tunables.update_ints(&new_tunables.ints);
-    tunables.update_strings(&new_tunables.strings);
+    let mut warnings = BTreeSet::new();
+    let unknown = tunables.update_bools(&new_tunables.killswitches);
+    collect_unknown_tunables(&mut warnings, TunableKind::Bool, unknown);
+    let unknown = tunables.update_ints(&new_tunables.ints);
+    collect_unknown_tunables(&mut warnings, TunableKind::I64, unknown);
+    let unknown = tunables.update_strings(&new_tunables.strings);
+    collect_unknown_tunables(&mut warnings, TunableKind::String, unknown);
+
+    if let Some(floats) = &new_tunables.floats {
+        let unknown = tunables.update_floats(floats);
+        collect_unknown_tunables(&mut warnings, TunableKind::F64, unknown);
+    }
+
+    if let Some(durations) = &durations {
+        let unknown = tunables.update_durations(durations);
+        collect_unknown_tunables(&mut warnings, TunableKind::Duration, unknown);
+    }
 
     if let Some(killswitches_by_repo) = &new_tunables.killswitches_by_repo {
-        tunables.update_by_repo_bools(killswitches_by_repo);
+        let unknown = tunables.update_by_repo_bools(killswitches_by_repo);
+        collect_unknown_tunables(&mut warnings, TunableKind::BoolByRepo, unknown);
     }
 
     if let Some(ints_by_repo) = &new_tunables.ints_by_repo {
-        tunables.update_by_repo_ints(ints_by_repo);
+        let unknown = tunables.update_by_repo_ints(ints_by_repo);
+        collect_unknown_tunables(&mut warnings, TunableKind::I64ByRepo, unknown);
     }
 
     if let Some(vec_of_strings_by_repo) = &new_tunables.vec_of_strings_by_repo {
-        tunables.update_by_repo_vec_of_strings(vec_of_strings_by_repo);
+        let unknown = tunables.update_by_repo_vec_of_strings(vec_of_strings_by_repo);
+        collect_unknown_tunables(&mut warnings, TunableKind::VecOfStringsByRepo, unknown);
+    }
+
+    // By-repo string tunables are not updated from configs, but their names
+    // are still checked.
+    if let Some(strings_by_repo) = &new_tunables.strings_by_repo {
+        let schema = MononokeTunables::schema();
+        let unknown = strings_by_repo
+            .values()
+            .flat_map(|values| values.keys())
+            .filter(|name| schema.get(name.as_str()) != Some(&TunableKind::StringByRepo))
+            .cloned()
+            .collect();
+        collect_unknown_tunables(&mut warnings, TunableKind::StringByRepo, unknown);
+    }
+
+    if let Some(durations_by_repo) = &durations_by_repo {
+        let unknown = tunables.update_by_repo_durations(durations_by_repo);
+        collect_unknown_tunables(&mut warnings, TunableKind::DurationByRepo, unknown);
+    }
+
+    if let Some(byte_sizes_by_repo) = &byte_sizes_by_repo {
+        let unknown = tunables.update_by_repo_byte_sizes(byte_sizes_by_repo);
+        collect_unknown_tunables(&mut warnings, TunableKind::ByteSizeByRepo, unknown);
     }
+    warn_unknown_tunables(logger, warnings);
+
+    dynamic::dynamic_tunables().update(new_tunables.clone());
+    UPDATE_GENERATION.fetch_add(1, Ordering::AcqRel);
     Ok(())
 }
 
+/// Values of some tunables to use instead of the current ones, e.g. for a
+/// single session. Unlike the overrides set by `with_tunables` and friends,
+/// which replace every tunable, tunables that are not in a `TunablesOverride`
+/// keep their current values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TunablesOverride {
+    values: BTreeMap<String, TunableValue>,
+    applied: AppliedOverrideCache,
+}
+
+/// The last result of `TunablesOverride::apply_to`. It is not shared by
+/// clones, and is ignored when comparing overrides.
+#[derive(Default)]
+struct AppliedOverrideCache(Mutex<Option<AppliedOverride>>);
+
+struct AppliedOverride {
+    /// The tunables the override was applied to, or `None` for the
+    /// process-wide ones.
+    base: Option<Arc<MononokeTunables>>,
+    generation: u64,
+    tunables: Arc<MononokeTunables>,
+}
+
+impl Clone for AppliedOverrideCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for AppliedOverrideCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AppliedOverrideCache")
+    }
+}
+
+impl PartialEq for AppliedOverrideCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl TunablesOverride {
+    /// Override the tunables in `values`, keyed by name. Fails if one of them
+    /// is not a tunable of the type of its value.
+    pub fn new(values: BTreeMap<String, TunableValue>) -> Result<Self> {
+        let check = MononokeTunables::default();
+        for (name, value) in &values {
+            if !check.set_value_by_name(name, value.clone()) {
+                return Err(anyhow!("Invalid override of tunable {}: {:?}", name, value));
+            }
+        }
+        Ok(Self {
+            values,
+            applied: AppliedOverrideCache::default(),
+        })
+    }
+
+    pub fn values(&self) -> &BTreeMap<String, TunableValue> {
+        &self.values
+    }
+
+    /// A copy of `base` with the overridden tunables set to their values.
+    pub fn apply(&self, base: &MononokeTunables) -> MononokeTunables {
+        let tunables = MononokeTunables::default();
+        for (name, value) in base.snapshot().into_iter().chain(self.values.clone()) {
+            tunables.set_value_by_name(&name, value);
+        }
+        tunables
+    }
+
+    /// Like `apply`, but reuse the result of the previous call if it was
+    /// applied to the same tunables and they weren't updated since. If `base`
+    /// already is that result, e.g. in a scope it was passed to
+    /// `with_tunables_async_arc`, it is returned as is.
+    pub fn apply_to(&self, base: TunablesReference) -> Arc<MononokeTunables> {
+        let base = match base {
+            TunablesReference::Override(base) => Some(base),
+            TunablesReference::Static(_) => None,
+        };
+        let generation = UPDATE_GENERATION.load(Ordering::Acquire);
+        let mut applied = self.applied.0.lock().expect("lock poisoned");
+        if let Some(applied) = applied.as_ref() {
+            let same_base = match (&applied.base, &base) {
+                (None, None) => true,
+                (Some(applied_base), Some(base)) => Arc::ptr_eq(applied_base, base),
+                _ => false,
+            };
+            if same_base && applied.generation == generation {
+                return applied.tunables.clone();
+            }
+            if let Some(base) = &base {
+                if Arc::ptr_eq(base, &applied.tunables) {
+                    return base.clone();
+                }
+            }
+        }
+        let tunables = match &base {
+            Some(base) => self.apply(base),
+            None => self.apply(TUNABLES.get_or_init(MononokeTunables::default)),
+        };
+        let tunables = Arc::new(tunables);
+        *applied = Some(AppliedOverride {
+            base,
+            generation,
+            tunables: tunables.clone(),
+        });
+        tunables
+    }
+}
+
 /// A helper function to override tunables during a closure's execution.
 /// This is useful for unit tests.
 pub fn with_tunables<T>(new_tunables: MononokeTunables, f: impl FnOnce() -> T) -> T {
-    TUNABLES_OVERRIDE.with(|t| *t.borrow_mut() = Some(Arc::new(new_tunables)));
+    let _guard = TunablesGuard::new(new_tunables);
+    f()
+}
 
-    let res = f();
+/// Overrides tunables on the current thread until it is dropped, even if the
+/// code it guards panics. The previous override, if any, is restored, so
+/// guards can be nested.
+///
+/// This is for test bodies with several `.await`s that would otherwise all
+/// need to go into a `with_tunables_async` block. The override is
+/// thread-local, so it's only seen across `.await`s on a current-thread
+/// runtime, which is what `#[tokio::test]` uses by default.
+#[must_use = "the override is removed when the guard is dropped"]
+pub struct TunablesGuard {
+    previous: Option<ScopedTunables>,
+}
 
-    TUNABLES_OVERRIDE.with(|tunables| *tunables.borrow_mut() = None);
+impl TunablesGuard {
+    pub fn new(new_tunables: MononokeTunables) -> Self {
+        Self::new_arc(Arc::new(new_tunables))
+    }
 
-    res
+    pub fn new_arc(new_tunables: Arc<MononokeTunables>) -> Self {
+        let new_tunables = ScopedTunables::new(new_tunables);
+        let previous = TUNABLES_OVERRIDE.with(|t| t.borrow_mut().replace(new_tunables));
+        Self { previous }
+    }
 }
 
-pub fn with_tunables_async<Out, Fut: Future<Output = Out> + Unpin>(
+impl Drop for TunablesGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        TUNABLES_OVERRIDE.with(|t| *t.borrow_mut() = previous);
+    }
+}
+
+/// Override tunables while `fut` runs. The override is visible across
+/// `.await` points, even if the future moves between threads. Use
+/// `with_current_tunables` to carry it into spawned tasks.
+pub fn with_tunables_async<Out, Fut: Future<Output = Out>>(
     new_tunables: MononokeTunables,
     fut: Fut,
 ) -> impl Future<Output = Out> {
     with_tunables_async_arc(Arc::new(new_tunables), fut)
 }
 
-pub fn with_tunables_async_arc<Out, Fut: Future<Output = Out> + Unpin>(
+pub fn with_tunables_async_arc<Out, Fut: Future<Output = Out>>(
     new_tunables: Arc<MononokeTunables>,
-    mut fut: Fut,
+    fut: Fut,
 ) -> impl Future<Output = Out> {
-    poll_fn(move |cx| {
-        TUNABLES_OVERRIDE.with(|t| *t.borrow_mut() = Some(new_tunables.clone()));
-
-        let res = fut.poll_unpin(cx);
+    // The override comes into effect when the future is first polled, not
+    // when it's created.
+    async move {
+        TUNABLES_TASK_OVERRIDE
+            .scope(ScopedTunables::new(new_tunables), fut)
+            .await
+    }
+}
 
-        TUNABLES_OVERRIDE.with(|tunables| *tunables.borrow_mut() = None);
+/// Override tunables while `fut` runs, for tests. `fut` doesn't need to be
+/// boxed or `Unpin`, so a test body can be passed as a plain `async` block.
+/// The override is removed when this returns, and also if `fut` panics or is
+/// dropped before it completes.
+pub async fn with_tunables_async_scoped<Out>(
+    new_tunables: MononokeTunables,
+    fut: impl Future<Output = Out>,
+) -> Out {
+    TUNABLES_TASK_OVERRIDE
+        .scope(ScopedTunables::new(Arc::new(new_tunables)), fut)
+        .await
+}
 
-        res
-    })
+/// Carry the tunables override of the current scope, if any, into `fut`.
+/// Task-local overrides are not inherited by spawned tasks, so wrap futures
+/// with this before passing them to `tokio::spawn`.
+pub fn with_current_tunables<Out, Fut: Future<Output = Out>>(
+    fut: Fut,
+) -> impl Future<Output = Out> {
+    match current_override() {
+        Some(tunables) => with_tunables_async_arc(tunables, fut).left_future(),
+        None => fut.right_future(),
+    }
 }
 
 pub fn override_tunables(new_tunables: Option<Arc<MononokeTunables>>) {
-    TUNABLES_OVERRIDE.with(|t| *t.borrow_mut() = new_tunables);
+    TUNABLES_OVERRIDE.with(|t| *t.borrow_mut() = new_tunables.map(ScopedTunables::new));
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use maplit::hashmap;
+    use maplit::{btreemap, hashmap};
     use std::collections::HashMap;
     use std::sync::atomic::AtomicBool;
 
@@ -385,6 +1160,8 @@ mod test {
     struct TestTunables {
         boolean: AtomicBool,
         num: AtomicI64,
+        float: TunableF64,
+        duration: TunableDuration,
         string: TunableString,
 
         repobool: TunableBoolByRepo,
@@ -397,11 +1174,65 @@ mod test {
         repostr2: TunableStringByRepo,
 
         repovecofstrings: TunableVecOfStringsByRepo,
+
+        duration_by_repo: TunableDurationByRepo,
+        repobytes: TunableByteSizeByRepo,
     }
 
     #[derive(Tunables, Default)]
     struct EmptyTunables {}
 
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum TestStage {
+        Off,
+        Shadow,
+        Enforce,
+    }
+
+    impl Default for TestStage {
+        fn default() -> Self {
+            Self::Off
+        }
+    }
+
+    impl FromStr for TestStage {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "off" => Ok(Self::Off),
+                "shadow" => Ok(Self::Shadow),
+                "enforce" => Ok(Self::Enforce),
+                _ => Err(format!("unknown stage {}", s)),
+            }
+        }
+    }
+
+    impl Display for TestStage {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let s = match self {
+                Self::Off => "off",
+                Self::Shadow => "shadow",
+                Self::Enforce => "enforce",
+            };
+            f.write_str(s)
+        }
+    }
+
+    #[derive(Tunables, Default)]
+    struct EnumTunables {
+        stage: TunableEnum<TestStage>,
+        string: TunableString,
+    }
+
+    #[derive(Tunables, Default)]
+    struct RolloutTunables {
+        #[rollout]
+        rollout: AtomicI64,
+        #[rollout]
+        other_rollout: AtomicI64,
+    }
+
     fn s(a: &str) -> String {
         a.to_string()
     }
@@ -422,6 +1253,102 @@ mod test {
         assert_eq!(tunables().get_wishlist_write_qps(), 0);
     }
 
+    #[test]
+    fn test_invalid_update_is_rejected() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        with_tunables(MononokeTunables::default(), || {
+            update_tunables(
+                &logger,
+                Arc::new(TunablesStruct {
+                    ints: hashmap! { s("zstd_compression_level") => 3 },
+                    ..Default::default()
+                }),
+                &TunablesTarget::default(),
+            )
+            .unwrap();
+            assert_eq!(tunables().get_zstd_compression_level(), 3);
+
+            let res = update_tunables(
+                &logger,
+                Arc::new(TunablesStruct {
+                    ints: hashmap! {
+                        s("zstd_compression_level") => 5,
+                        s("wishlist_read_qps") => -1,
+                    },
+                    ..Default::default()
+                }),
+                &TunablesTarget::default(),
+            );
+            assert!(res.is_err());
+            assert_eq!(tunables().get_zstd_compression_level(), 3);
+            assert_eq!(tunables().get_wishlist_read_qps(), 0);
+        })
+    }
+
+    #[test]
+    fn test_pinned_tunables() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let pinned = pinned::parse_pinned_tunables(
+            &MononokeTunables::schema(),
+            &[(s("zstd_compression_level"), s("7"))],
+        )
+        .unwrap();
+        let config = Arc::new(TunablesStruct {
+            ints: hashmap! {
+                s("zstd_compression_level") => 3,
+                s("wishlist_read_qps") => 10,
+            },
+            by_tier: Some(hashmap! {
+                s("canary") => TunablesStruct {
+                    ints: hashmap! { s("zstd_compression_level") => 5 },
+                    ..Default::default()
+                },
+            }),
+            ..Default::default()
+        });
+        let canary = TunablesTarget {
+            hostname: None,
+            tier: Some(s("canary")),
+        };
+
+        with_tunables(MononokeTunables::default(), || {
+            // Pinned values take precedence over the config and its overrides.
+            update_tunables_with_pinned(&logger, config.clone(), &canary, Some(&pinned.values))
+                .unwrap();
+            assert_eq!(tunables().get_zstd_compression_level(), 7);
+            assert_eq!(tunables().get_wishlist_read_qps(), 10);
+
+            // They are applied again on updates, even to an empty config.
+            update_tunables_with_pinned(
+                &logger,
+                Arc::new(TunablesStruct::default()),
+                &canary,
+                Some(&pinned.values),
+            )
+            .unwrap();
+            assert_eq!(tunables().get_zstd_compression_level(), 7);
+            assert_eq!(tunables().get_wishlist_read_qps(), 0);
+
+            // Pinned values are validated like the config.
+            let invalid = pinned::parse_pinned_tunables(
+                &MononokeTunables::schema(),
+                &[(s("zstd_compression_level"), s("20"))],
+            )
+            .unwrap();
+            let res = update_tunables_with_pinned(
+                &logger,
+                config.clone(),
+                &canary,
+                Some(&invalid.values),
+            );
+            assert!(res.is_err());
+            assert_eq!(tunables().get_zstd_compression_level(), 7);
+
+            update_tunables_with_pinned(&logger, config, &canary, None).unwrap();
+            assert_eq!(tunables().get_zstd_compression_level(), 5);
+        })
+    }
+
     #[test]
     fn test_empty_tunables() {
         let bools = HashMap::new();
@@ -433,6 +1360,297 @@ mod test {
         empty.update_strings(&HashMap::new());
     }
 
+    #[test]
+    fn test_snapshot_and_diff() {
+        let test = TestTunables::default();
+        test.update_ints(&hashmap! { s("num") => 3 });
+        test.update_by_repo_bools(&hashmap! {
+            s("repo") => hashmap! { s("repobool") => true },
+        });
+
+        let snapshot = test.snapshot();
+        assert_eq!(snapshot.len(), 14);
+        assert_eq!(snapshot["num"], TunableValue::I64(3));
+        assert_eq!(snapshot["string"], TunableValue::String(s("")));
+        assert_eq!(
+            snapshot["repobool"],
+            TunableValue::BoolByRepo {
+                global: None,
+                by_repo: btreemap! { s("repo") => true },
+            }
+        );
+
+        let diff = test.diff(&TestTunables::default());
+        assert_eq!(
+            diff.keys().collect::<Vec<_>>(),
+            vec![&s("num"), &s("repobool")]
+        );
+        assert_eq!(diff["num"], (TunableValue::I64(3), TunableValue::I64(0)));
+
+        assert!(EmptyTunables::default().snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_set_value_by_name() {
+        let test = TestTunables::default();
+        test.update_ints(&hashmap! { s("num") => 3 });
+        test.update_strings(&hashmap! { s("string") => s("value") });
+        test.update_by_repo_bools(&hashmap! {
+            s("repo") => hashmap! { s("repobool") => true },
+        });
+
+        // Every value of a snapshot can be set back.
+        let copy = TestTunables::default();
+        for (name, value) in test.snapshot() {
+            assert!(copy.set_value_by_name(&name, value));
+        }
+        assert!(copy.diff(&test).is_empty());
+
+        assert!(!copy.set_value_by_name("num", TunableValue::Bool(true)));
+        assert!(!copy.set_value_by_name("no_such_tunable", TunableValue::I64(1)));
+        assert_eq!(copy.get_num(), 3);
+
+        let test = EnumTunables::default();
+        assert!(test.set_value_by_name("stage", TunableValue::Enum(s("shadow"))));
+        assert_eq!(test.get_stage(), TestStage::Shadow);
+        assert!(!test.set_value_by_name("stage", TunableValue::Enum(s("nope"))));
+        assert_eq!(test.get_stage(), TestStage::Shadow);
+    }
+
+    #[test]
+    fn test_tunables_override() {
+        let base = MononokeTunables::default();
+        base.update_bools(&hashmap! { s("filenodes_disabled") => true });
+        base.update_ints(&hashmap! { s("warm_bookmark_cache_delay") => 5 });
+
+        let tunables_override = TunablesOverride::new(btreemap! {
+            s("warm_bookmark_cache_delay") => TunableValue::I64(10),
+        })
+        .unwrap();
+        let tunables = tunables_override.apply(&base);
+        assert_eq!(tunables.get_warm_bookmark_cache_delay(), 10);
+        // Tunables that are not overridden keep their values.
+        assert!(tunables.get_filenodes_disabled());
+        // The base is unchanged.
+        assert_eq!(base.get_warm_bookmark_cache_delay(), 5);
+
+        // Applying to the same tunables reuses the result, until they are
+        // updated.
+        let logger = Logger::root(slog::Discard, slog::o!());
+        with_tunables(base, || {
+            let tunables = tunables_override.apply_to(super::tunables());
+            assert_eq!(tunables.get_warm_bookmark_cache_delay(), 10);
+            assert!(tunables.get_filenodes_disabled());
+            assert!(Arc::ptr_eq(
+                &tunables,
+                &tunables_override.apply_to(super::tunables())
+            ));
+            // Within a scope of the result, it is returned as is.
+            with_tunables_async_arc(tunables.clone(), async {
+                assert!(Arc::ptr_eq(
+                    &tunables,
+                    &tunables_override.apply_to(super::tunables())
+                ));
+            })
+            .now_or_never()
+            .unwrap();
+
+            update_tunables(&logger, Arc::default(), &TunablesTarget::default()).unwrap();
+            let updated = tunables_override.apply_to(super::tunables());
+            assert!(!Arc::ptr_eq(&tunables, &updated));
+            assert_eq!(updated.get_warm_bookmark_cache_delay(), 10);
+            assert!(!updated.get_filenodes_disabled());
+        });
+
+        assert!(TunablesOverride::new(btreemap! {
+            s("warm_bookmark_cache_delay") => TunableValue::Bool(true),
+        })
+        .is_err());
+        assert!(TunablesOverride::new(btreemap! {
+            s("no_such_tunable") => TunableValue::I64(1),
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_by_name() {
+        let test = TestTunables::default();
+        test.update_bools(&hashmap! { s("boolean") => true });
+        test.update_ints(&hashmap! { s("num") => 3 });
+        test.update_strings(&hashmap! { s("string") => s("value") });
+
+        assert_eq!(test.get_bool_by_name("boolean"), Some(true));
+        assert_eq!(test.get_int_by_name("num"), Some(3));
+        assert_eq!(test.get_float_by_name("float"), Some(0.0));
+        assert_eq!(
+            test.get_duration_by_name("duration"),
+            Some(Duration::default())
+        );
+        assert_eq!(
+            test.get_string_by_name("string").as_deref(),
+            Some(&s("value"))
+        );
+        // Wrong type, or no such tunable.
+        assert_eq!(test.get_int_by_name("boolean"), None);
+        assert_eq!(test.get_bool_by_name("missing"), None);
+
+        assert_eq!(test.get_value_by_name("num"), Some(TunableValue::I64(3)));
+        assert_eq!(test.get_value_by_name("missing"), None);
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(TestTunablesKey::ALL.len(), 14);
+        assert_eq!(
+            TestTunablesKey::from_name("duration_by_repo"),
+            Some(TestTunablesKey::DurationByRepo)
+        );
+        assert_eq!(TestTunablesKey::from_name("missing"), None);
+        for key in TestTunablesKey::ALL {
+            assert_eq!(TestTunablesKey::from_name(key.name()), Some(*key));
+        }
+        assert!(EmptyTunablesKey::ALL.is_empty());
+
+        assert_eq!(
+            TunableKey::from_name("wishlist_read_qps"),
+            Some(TunableKey::WishlistReadQps)
+        );
+    }
+
+    #[test]
+    fn test_enum_tunables() {
+        let test = EnumTunables::default();
+        assert_eq!(test.get_stage(), TestStage::Off);
+
+        test.update_strings(&hashmap! {
+            s("stage") => s("shadow"),
+            s("string") => s("value"),
+        });
+        assert_eq!(test.get_stage(), TestStage::Shadow);
+        assert_eq!(test.get_string().as_str(), "value");
+        assert_eq!(
+            test.get_value_by_name("stage"),
+            Some(TunableValue::Enum(s("shadow")))
+        );
+        assert_eq!(test.get_string_by_name("stage"), None);
+
+        // Unset and invalid values fall back to the default.
+        test.update_strings(&hashmap! {});
+        assert_eq!(test.get_stage(), TestStage::Off);
+        test.stage.store(TestStage::Enforce);
+        test.update_strings(&hashmap! { s("stage") => s("enforced") });
+        assert_eq!(test.get_stage(), TestStage::Off);
+
+        assert!(EnumTunables::validate_enums(&hashmap! { s("stage") => s("enforce") }).is_ok());
+        assert!(EnumTunables::validate_enums(&hashmap! { s("stage") => s("enforced") }).is_err());
+        assert!(EnumTunables::validate_enums(&hashmap! { s("string") => s("any") }).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_tunable_names() {
+        let test = TestTunables::default();
+        let unknown = test.update_bools(&hashmap! {
+            s("boolean") => true,
+            s("repobool") => true,
+            s("num") => true,
+            s("booleans") => true,
+        });
+        // By-repo bools take their global value from the same section.
+        assert_eq!(
+            unknown.into_iter().collect::<Vec<_>>(),
+            vec![s("booleans"), s("num")]
+        );
+        assert!(test.get_boolean());
+
+        let unknown = test.update_by_repo_ints(&hashmap! {
+            s("repo") => hashmap! {
+                s("repoint") => 1,
+                s("no_such_int") => 1,
+            },
+            s("repo2") => hashmap! {
+                s("no_such_int") => 2,
+            },
+        });
+        assert_eq!(
+            unknown.into_iter().collect::<Vec<_>>(),
+            vec![s("no_such_int")]
+        );
+
+        let unknown = EnumTunables::default().update_strings(&hashmap! {
+            s("stage") => s("shadow"),
+            s("string") => s("string"),
+        });
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_tunable_warning() {
+        register_tunable_bool("dynamic_test_known_bool");
+        let schema = TestTunables::schema();
+        let warning = |kind, name| unknown_tunable_warning(&schema, kind, name);
+
+        assert_eq!(warning(TunableKind::Bool, "dynamic_test_known_bool"), None);
+        assert_eq!(
+            warning(TunableKind::Bool, "booleans"),
+            Some(s(
+                "Ignoring unknown tunable booleans in killswitches, did you mean boolean?"
+            ))
+        );
+        assert_eq!(
+            warning(TunableKind::Bool, "num"),
+            Some(s(
+                "Ignoring tunable num in killswitches, it is read from ints"
+            ))
+        );
+        assert_eq!(
+            warning(TunableKind::I64ByRepo, "no_such_int"),
+            Some(s("Ignoring unknown tunable no_such_int in ints_by_repo"))
+        );
+    }
+
+    #[test]
+    fn test_replace_warnings() {
+        let mut previous = BTreeSet::new();
+        let warnings = |names: &[&str]| names.iter().map(|name| s(name)).collect();
+        assert_eq!(
+            replace_warnings(&mut previous, warnings(&["a", "b"])),
+            vec![s("a"), s("b")]
+        );
+        // Warnings are not repeated while they are still there.
+        assert!(replace_warnings(&mut previous, warnings(&["a", "b"])).is_empty());
+        assert_eq!(
+            replace_warnings(&mut previous, warnings(&["b", "c"])),
+            vec![s("c")]
+        );
+        // A warning that went away is new again when it comes back.
+        assert_eq!(
+            replace_warnings(&mut previous, warnings(&["a", "b", "c"])),
+            vec![s("a")]
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = TestTunables::schema();
+        assert_eq!(schema.len(), 14);
+        assert_eq!(schema.get("boolean"), Some(&TunableKind::Bool));
+        assert_eq!(schema.get("duration"), Some(&TunableKind::Duration));
+        assert_eq!(schema.get("repobytes"), Some(&TunableKind::ByteSizeByRepo));
+        assert_eq!(schema.get("missing"), None);
+        assert_eq!(
+            EnumTunables::schema(),
+            btreemap! {
+                "stage" => TunableKind::Enum,
+                "string" => TunableKind::String,
+            }
+        );
+        assert!(EmptyTunables::schema().is_empty());
+
+        // Every tunable has a value in the snapshot.
+        let test = TestTunables::default();
+        assert!(schema.keys().eq(test.snapshot().keys()));
+    }
+
     #[test]
     fn test_update_bool() {
         let mut d = HashMap::new();
@@ -505,6 +1723,35 @@ mod test {
         assert_eq!(test.get_num(), 10);
     }
 
+    #[test]
+    fn test_rollout() {
+        let test = RolloutTunables::default();
+        let hashes = (0..1000)
+            .map(|i| rollout_hash(format!("session{}", i)))
+            .collect::<Vec<_>>();
+        let rolled_out = |percentage, other_percentage| {
+            test.update_ints(&hashmap! {
+                s("rollout") => percentage,
+                s("other_rollout") => other_percentage,
+            });
+            hashes
+                .iter()
+                .filter(|hash| test.get_rollout_rollout(**hash))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(rolled_out(0, 100).is_empty());
+        assert_eq!(rolled_out(100, 0).len(), hashes.len());
+        let half = rolled_out(50, 0);
+        assert!(half.len() > 400 && half.len() < 600, "{}", half.len());
+        // The same sessions are in on every call, whatever other rollouts
+        // are set to.
+        assert_eq!(rolled_out(50, 100), half);
+        // Raising the percentage only adds sessions.
+        let more = rolled_out(80, 0);
+        assert!(half.iter().all(|hash| more.contains(hash)));
+    }
+
     #[test]
     fn test_missing_int() {
         let mut d = HashMap::new();
@@ -512,8 +1759,33 @@ mod test {
 
         let test = TestTunables::default();
         assert_eq!(test.get_num(), 0);
-        test.update_ints(&d);
+        let unknown = test.update_ints(&d);
         assert_eq!(test.get_num(), 0);
+        assert_eq!(unknown.into_iter().collect::<Vec<_>>(), vec![s("missing")]);
+    }
+
+    #[test]
+    fn test_update_float() {
+        let test = TestTunables::default();
+        assert_eq!(test.get_float(), 0.0);
+
+        test.update_floats(&hashmap! { s("float") => 0.25 });
+        assert_eq!(test.get_float(), 0.25);
+
+        test.update_floats(&hashmap! {});
+        assert_eq!(test.get_float(), 0.0);
+    }
+
+    #[test]
+    fn test_update_duration() {
+        let test = TestTunables::default();
+        assert_eq!(test.get_duration(), Duration::from_secs(0));
+
+        test.update_durations(&hashmap! { s("duration") => Duration::from_millis(500) });
+        assert_eq!(test.get_duration(), Duration::from_millis(500));
+
+        test.update_durations(&hashmap! {});
+        assert_eq!(test.get_duration(), Duration::from_secs(0));
     }
 
     #[test]
@@ -693,6 +1965,39 @@ mod test {
         assert_eq!(test.get_by_repo_repoint2("repo"), None);
     }
 
+    #[test]
+    fn by_repo_or_default() {
+        let test = TestTunables::default();
+        assert_eq!(test.get_by_repo_repoint_or_default("repo"), 0);
+
+        // Fall back to the global tunable of the same name.
+        test.update_ints(&hashmap! { s("repoint") => 1 });
+        assert_eq!(test.get_by_repo_repoint_or_default("repo"), 1);
+        assert_eq!(test.get_by_repo_repoint("repo"), None);
+
+        // The wildcard entry takes priority over the global tunable.
+        test.update_by_repo_ints(&hashmap! {
+            s("*") => hashmap! {
+                s("repoint") => 2,
+            },
+            s("repo") => hashmap! {
+                s("repoint") => 3,
+            },
+        });
+        assert_eq!(test.get_by_repo_repoint_or_default("repo"), 3);
+        assert_eq!(test.get_by_repo_repoint_or_default("repo2"), 2);
+        assert_eq!(test.get_by_repo_repoint2_or_default("repo"), 0);
+
+        test.update_by_repo_ints(&hashmap! {});
+        test.update_ints(&hashmap! {});
+        assert_eq!(test.get_by_repo_repoint_or_default("repo"), 0);
+
+        test.update_strings(&hashmap! { s("repostr") => s("global") });
+        assert_eq!(test.get_by_repo_repostr_or_default("repo"), s("global"));
+        test.update_bools(&hashmap! { s("repobool") => true });
+        assert!(test.get_by_repo_repobool_or_default("repo"));
+    }
+
     #[test]
     fn update_by_repo_vec_of_strings() {
         let test = TestTunables::default();
@@ -717,6 +2022,105 @@ mod test {
         );
     }
 
+    #[test]
+    fn update_by_repo_duration() {
+        let test = TestTunables::default();
+        assert_eq!(test.get_by_repo_duration_by_repo("repo"), None);
+
+        test.update_by_repo_durations(&hashmap! {
+            s("repo") => hashmap! {
+                s("duration_by_repo") => Duration::from_millis(500),
+            }
+        });
+        assert_eq!(
+            test.get_by_repo_duration_by_repo("repo"),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            test.get_by_repo_duration_by_repo_or_default("other"),
+            Duration::from_secs(0)
+        );
+
+        // The global duration of the same name is the fallback.
+        test.update_durations(&hashmap! { s("duration_by_repo") => Duration::from_secs(2) });
+        assert_eq!(
+            test.get_by_repo_duration_by_repo_or_default("other"),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn update_by_repo_byte_size() {
+        let test = TestTunables::default();
+        assert_eq!(test.get_by_repo_repobytes("repo"), None);
+
+        let byte_sizes = parse_values_by_repo(
+            &hashmap! {
+                s("repo") => hashmap! { s("repobytes") => s("2GiB") },
+                s("*") => hashmap! { s("repobytes") => s("512KB") },
+            },
+            parse_byte_size,
+        )
+        .unwrap();
+        test.update_by_repo_byte_sizes(&byte_sizes);
+
+        assert_eq!(test.get_by_repo_repobytes("repo"), Some(2 << 30));
+        assert_eq!(test.get_by_repo_repobytes_or_default("other"), 512_000);
+    }
+
+    #[test]
+    fn parse_values_by_repo_rejects_missing_unit() {
+        let res = parse_values_by_repo(
+            &hashmap! { s("repo") => hashmap! { s("repobytes") => s("1024") } },
+            parse_byte_size,
+        );
+        assert!(res.is_err());
+    }
+
+    #[fbinit::test]
+    async fn test_tunables_worker_shutdown(_fb: fbinit::FacebookInit) {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let config_handle = ConfigHandle::from(TunablesStruct::default());
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = TunablesFallback::new(dir.path().join("tunables.json"), Duration::MAX);
+        let worker = init_tunables_worker(
+            logger,
+            config_handle,
+            Duration::from_millis(10),
+            TunablesTarget::default(),
+            Some(fallback.clone()),
+            &Handle::current(),
+        )
+        .unwrap();
+        // The applied config is saved for the next start.
+        assert_eq!(
+            fallback.load().unwrap().as_deref(),
+            Some(&TunablesStruct::default())
+        );
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // Only one worker runs at a time.
+        let start_worker = || {
+            init_tunables_worker(
+                Logger::root(slog::Discard, slog::o!()),
+                ConfigHandle::from(TunablesStruct::default()),
+                Duration::from_millis(10),
+                TunablesTarget::default(),
+                None,
+                &Handle::current(),
+            )
+        };
+        assert!(start_worker().is_err());
+        worker.shutdown().await.unwrap();
+
+        // Tunables can still be refreshed on demand.
+        force_update_tunables();
+
+        // Another worker can be started once the previous one is shut down.
+        let worker = start_worker().unwrap();
+        worker.shutdown().await.unwrap();
+    }
+
     #[fbinit::test]
     async fn test_with_tunables_async(_fb: fbinit::FacebookInit) {
         let res = with_tunables_async(
@@ -730,4 +2134,118 @@ mod test {
 
         assert_eq!(res, 2);
     }
+
+    #[fbinit::test]
+    async fn test_with_tunables_async_propagation(_fb: fbinit::FacebookInit) {
+        let res = with_tunables_async(
+            MononokeTunables {
+                wishlist_write_qps: AtomicI64::new(2),
+                ..MononokeTunables::default()
+            },
+            async {
+                tokio::task::yield_now().await;
+                let spawned = tokio::spawn(with_current_tunables(async {
+                    tokio::task::yield_now().await;
+                    tunables().get_wishlist_write_qps()
+                }))
+                .await
+                .unwrap();
+                (tunables().get_wishlist_write_qps(), spawned)
+            },
+        )
+        .await;
+
+        assert_eq!(res, (2, 2));
+        assert_eq!(tunables().get_wishlist_write_qps(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_with_tunables_async_scoped() {
+        let res = with_tunables_async_scoped(
+            MononokeTunables {
+                wishlist_write_qps: AtomicI64::new(2),
+                ..MononokeTunables::default()
+            },
+            async {
+                let before = tunables().get_wishlist_write_qps();
+                tokio::task::yield_now().await;
+                (before, tunables().get_wishlist_write_qps())
+            },
+        )
+        .await;
+        assert_eq!(res, (2, 2));
+
+        let res = std::panic::AssertUnwindSafe(with_tunables_async_scoped(
+            MononokeTunables {
+                wishlist_write_qps: AtomicI64::new(2),
+                ..MononokeTunables::default()
+            },
+            async {
+                tokio::task::yield_now().await;
+                panic!("test body failed");
+            },
+        ))
+        .catch_unwind()
+        .await;
+        assert!(res.is_err());
+        assert_eq!(tunables().get_wishlist_write_qps(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tunables_guard() {
+        {
+            let _guard = TunablesGuard::new(MononokeTunables {
+                wishlist_write_qps: AtomicI64::new(2),
+                ..MononokeTunables::default()
+            });
+            tokio::task::yield_now().await;
+            assert_eq!(tunables().get_wishlist_write_qps(), 2);
+            {
+                let _inner = TunablesGuard::new(MononokeTunables {
+                    wishlist_write_qps: AtomicI64::new(3),
+                    ..MononokeTunables::default()
+                });
+                assert_eq!(tunables().get_wishlist_write_qps(), 3);
+            }
+            assert_eq!(tunables().get_wishlist_write_qps(), 2);
+        }
+        assert_eq!(tunables().get_wishlist_write_qps(), 0);
+
+        let res = std::panic::catch_unwind(|| {
+            with_tunables(
+                MononokeTunables {
+                    wishlist_write_qps: AtomicI64::new(2),
+                    ..MononokeTunables::default()
+                },
+                || panic!("test body failed"),
+            )
+        });
+        assert!(res.is_err());
+        assert_eq!(tunables().get_wishlist_write_qps(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_innermost_override_wins() {
+        let qps = |value| MononokeTunables {
+            wishlist_write_qps: AtomicI64::new(value),
+            ..MononokeTunables::default()
+        };
+
+        // A thread-local override within a task-local one.
+        let res = with_tunables_async(qps(2), async {
+            let inner = with_tunables(qps(3), || tunables().get_wishlist_write_qps());
+            (inner, tunables().get_wishlist_write_qps())
+        })
+        .await;
+        assert_eq!(res, (3, 2));
+
+        // A task-local override within a thread-local one.
+        let _guard = TunablesGuard::new(qps(2));
+        let inner = with_tunables_async(qps(3), async { tunables().get_wishlist_write_qps() });
+        // The override takes effect when the future runs, so the guard
+        // created after it is still outer to it.
+        let _later_guard = TunablesGuard::new(qps(4));
+        assert_eq!(inner.await, 3);
+        assert_eq!(tunables().get_wishlist_write_qps(), 4);
+    }
 }
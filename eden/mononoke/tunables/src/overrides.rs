@@ -0,0 +1,203 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Overrides of tunables for some hosts or tiers. A config's `by_tier` and
+//! `by_host_regex` sections are resolved against the local host when the
+//! config is applied, so that e.g. a killswitch can be canaried on one tier
+//! without a separate config.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use hostname::get_hostname;
+use regex::Regex;
+use tunables_structs::Tunables as TunablesStruct;
+
+/// Where this process runs, for matching tunables overrides.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TunablesTarget {
+    pub hostname: Option<String>,
+    pub tier: Option<String>,
+}
+
+impl TunablesTarget {
+    /// The local hostname, and `tier` if the process knows which tier it
+    /// runs on.
+    pub fn local(tier: Option<String>) -> Self {
+        Self {
+            hostname: get_hostname().ok(),
+            tier,
+        }
+    }
+}
+
+/// The values of `tunables` that apply to `target`. Overrides for the tier
+/// take precedence over the base values, and overrides for matching host
+/// regexes take precedence over both. Several matching host regexes are
+/// applied in lexicographic order of the regexes. The overrides' own
+/// `by_tier` and `by_host_regex` sections are ignored.
+pub(crate) fn resolve_overrides(
+    tunables: &TunablesStruct,
+    target: &TunablesTarget,
+) -> Result<TunablesStruct> {
+    let mut resolved = TunablesStruct {
+        by_host_regex: None,
+        by_tier: None,
+        ..tunables.clone()
+    };
+
+    if let (Some(by_tier), Some(tier)) = (&tunables.by_tier, &target.tier) {
+        if let Some(overrides) = by_tier.get(tier) {
+            merge_overrides(&mut resolved, overrides);
+        }
+    }
+
+    if let Some(by_host_regex) = &tunables.by_host_regex {
+        let mut host_regexes: Vec<_> = by_host_regex.iter().collect();
+        host_regexes.sort_by_key(|(host_regex, _)| *host_regex);
+        for (host_regex, overrides) in host_regexes {
+            // Invalid regexes are rejected on every host, not only the ones
+            // that would have matched.
+            let regex = Regex::new(&format!("^(?:{})$", host_regex))
+                .with_context(|| format!("Invalid host regex in tunables: {}", host_regex))?;
+            if matches!(&target.hostname, Some(hostname) if regex.is_match(hostname)) {
+                merge_overrides(&mut resolved, overrides);
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+pub(crate) fn merge_overrides(base: &mut TunablesStruct, overrides: &TunablesStruct) {
+    merge_values(&mut base.killswitches, &overrides.killswitches);
+    merge_values(&mut base.ints, &overrides.ints);
+    merge_values(&mut base.strings, &overrides.strings);
+    merge_optional_values(&mut base.floats, &overrides.floats);
+    merge_optional_values(&mut base.durations, &overrides.durations);
+    merge_values_by_repo(
+        &mut base.killswitches_by_repo,
+        &overrides.killswitches_by_repo,
+    );
+    merge_values_by_repo(&mut base.ints_by_repo, &overrides.ints_by_repo);
+    merge_values_by_repo(&mut base.strings_by_repo, &overrides.strings_by_repo);
+    merge_values_by_repo(
+        &mut base.vec_of_strings_by_repo,
+        &overrides.vec_of_strings_by_repo,
+    );
+    merge_values_by_repo(&mut base.durations_by_repo, &overrides.durations_by_repo);
+    merge_values_by_repo(&mut base.byte_sizes_by_repo, &overrides.byte_sizes_by_repo);
+}
+
+fn merge_values<T: Clone>(base: &mut HashMap<String, T>, overrides: &HashMap<String, T>) {
+    base.extend(
+        overrides
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone())),
+    );
+}
+
+fn merge_optional_values<T: Clone>(
+    base: &mut Option<HashMap<String, T>>,
+    overrides: &Option<HashMap<String, T>>,
+) {
+    if let Some(overrides) = overrides {
+        merge_values(base.get_or_insert_with(HashMap::new), overrides);
+    }
+}
+
+fn merge_values_by_repo<T: Clone>(
+    base: &mut Option<HashMap<String, HashMap<String, T>>>,
+    overrides: &Option<HashMap<String, HashMap<String, T>>>,
+) {
+    if let Some(overrides) = overrides {
+        let base = base.get_or_insert_with(HashMap::new);
+        for (repo, values) in overrides {
+            merge_values(base.entry(repo.clone()).or_default(), values);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    fn target(hostname: &str, tier: &str) -> TunablesTarget {
+        TunablesTarget {
+            hostname: Some(s(hostname)),
+            tier: Some(s(tier)),
+        }
+    }
+
+    fn config() -> TunablesStruct {
+        TunablesStruct {
+            killswitches: hashmap! { s("flag") => false },
+            ints: hashmap! { s("num") => 1, s("other") => 10 },
+            ints_by_repo: Some(hashmap! { s("repo") => hashmap! { s("num") => 1 } }),
+            by_tier: Some(hashmap! {
+                s("canary") => TunablesStruct {
+                    killswitches: hashmap! { s("flag") => true },
+                    ints: hashmap! { s("num") => 2 },
+                    ..Default::default()
+                },
+            }),
+            by_host_regex: Some(hashmap! {
+                s(r"host\d+\.west") => TunablesStruct {
+                    ints: hashmap! { s("num") => 3 },
+                    ints_by_repo: Some(hashmap! { s("repo") => hashmap! { s("num") => 3 } }),
+                    ..Default::default()
+                },
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_matching_overrides() {
+        let resolved = resolve_overrides(&config(), &target("host1.east", "prod")).unwrap();
+        assert_eq!(resolved.killswitches, hashmap! { s("flag") => false });
+        assert_eq!(resolved.ints, hashmap! { s("num") => 1, s("other") => 10 });
+        assert_eq!(resolved.by_tier, None);
+        assert_eq!(resolved.by_host_regex, None);
+    }
+
+    #[test]
+    fn test_tier_override() {
+        let resolved = resolve_overrides(&config(), &target("host1.east", "canary")).unwrap();
+        assert_eq!(resolved.killswitches, hashmap! { s("flag") => true });
+        assert_eq!(resolved.ints, hashmap! { s("num") => 2, s("other") => 10 });
+    }
+
+    #[test]
+    fn test_host_override_wins_over_tier() {
+        let resolved = resolve_overrides(&config(), &target("host1.west", "canary")).unwrap();
+        assert_eq!(resolved.killswitches, hashmap! { s("flag") => true });
+        assert_eq!(resolved.ints, hashmap! { s("num") => 3, s("other") => 10 });
+        assert_eq!(
+            resolved.ints_by_repo,
+            Some(hashmap! { s("repo") => hashmap! { s("num") => 3 } })
+        );
+
+        // The regex must match the whole hostname.
+        let resolved = resolve_overrides(&config(), &target("host1.westus", "canary")).unwrap();
+        assert_eq!(resolved.ints, hashmap! { s("num") => 2, s("other") => 10 });
+    }
+
+    #[test]
+    fn test_invalid_host_regex() {
+        let config = TunablesStruct {
+            by_host_regex: Some(hashmap! { s("host(") => TunablesStruct::default() }),
+            ..Default::default()
+        };
+        assert!(resolve_overrides(&config, &TunablesTarget::default()).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Tunables pinned from the command line with `--tunable name=value`. Pinned
+//! values are applied on top of every config, including its host and tier
+//! overrides, so that a host can be mitigated locally when deploying configs
+//! is broken.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use tunables_structs::Tunables as TunablesStruct;
+
+use crate::schema::{self, TunableKind};
+use crate::units::parse_duration;
+
+static PINNED_TUNABLES: Lazy<ArcSwapOption<PinnedTunables>> = Lazy::new(ArcSwapOption::empty);
+
+/// Tunables pinned from the command line.
+#[derive(Debug, Default)]
+pub(crate) struct PinnedTunables {
+    /// The pinned values, in the sections of the config they are read from.
+    pub(crate) values: TunablesStruct,
+    /// The pinned values, as they were given.
+    pub(crate) args: BTreeMap<String, String>,
+}
+
+/// Split a `name=value` argument of `--tunable`.
+pub fn parse_tunable_arg(arg: &str) -> Result<(String, String)> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(anyhow!(
+            "Invalid tunable override {:?}, expected name=value",
+            arg
+        )),
+    }
+}
+
+/// Parse `(name, value)` pairs of tunables in `schema`. Later values of the
+/// same tunable take precedence. By-repo tunables can't be pinned.
+pub(crate) fn parse_pinned_tunables(
+    schema: &BTreeMap<&'static str, TunableKind>,
+    pairs: &[(String, String)],
+) -> Result<PinnedTunables> {
+    let mut pinned = PinnedTunables::default();
+    for (name, value) in pairs {
+        let kind = match schema.get(name.as_str()) {
+            Some(kind) => *kind,
+            None => match schema::nearest_name(name, schema.keys().copied()) {
+                Some(nearest) => bail!("Unknown tunable {}, did you mean {}?", name, nearest),
+                None => bail!("Unknown tunable {}", name),
+            },
+        };
+        let values = &mut pinned.values;
+        let parse_error = || format!("Failed to parse tunable {} from {:?}", name, value);
+        match kind {
+            TunableKind::Bool => {
+                let value = value.parse().with_context(parse_error)?;
+                values.killswitches.insert(name.clone(), value);
+            }
+            TunableKind::I64 => {
+                let value = value.parse().with_context(parse_error)?;
+                values.ints.insert(name.clone(), value);
+            }
+            TunableKind::F64 => {
+                let value = value.parse().with_context(parse_error)?;
+                values
+                    .floats
+                    .get_or_insert_with(HashMap::new)
+                    .insert(name.clone(), value);
+            }
+            TunableKind::Duration => {
+                parse_duration(value).with_context(parse_error)?;
+                values
+                    .durations
+                    .get_or_insert_with(HashMap::new)
+                    .insert(name.clone(), value.clone());
+            }
+            // Enum values are checked with the rest of the config.
+            TunableKind::String | TunableKind::Enum => {
+                values.strings.insert(name.clone(), value.clone());
+            }
+            TunableKind::BoolByRepo
+            | TunableKind::I64ByRepo
+            | TunableKind::StringByRepo
+            | TunableKind::VecOfStringsByRepo
+            | TunableKind::DurationByRepo
+            | TunableKind::ByteSizeByRepo => {
+                bail!(
+                    "Tunable {} is configured by repo, and can't be pinned",
+                    name
+                )
+            }
+        }
+        pinned.args.insert(name.clone(), value.clone());
+    }
+    Ok(pinned)
+}
+
+pub(crate) fn pinned_tunables() -> Option<Arc<PinnedTunables>> {
+    PINNED_TUNABLES.load_full()
+}
+
+/// Replace the pinned tunables, and return the previous ones.
+pub(crate) fn set_pinned_tunables(
+    pinned: Option<Arc<PinnedTunables>>,
+) -> Option<Arc<PinnedTunables>> {
+    PINNED_TUNABLES.swap(pinned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::{btreemap, hashmap};
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    fn schema() -> BTreeMap<&'static str, TunableKind> {
+        btreemap! {
+            "flag" => TunableKind::Bool,
+            "num" => TunableKind::I64,
+            "ratio" => TunableKind::F64,
+            "timeout" => TunableKind::Duration,
+            "name" => TunableKind::String,
+            "repoflag" => TunableKind::BoolByRepo,
+        }
+    }
+
+    #[test]
+    fn test_parse_tunable_arg() {
+        assert_eq!(parse_tunable_arg("num=1").unwrap(), (s("num"), s("1")));
+        assert_eq!(
+            parse_tunable_arg("name=a=b").unwrap(),
+            (s("name"), s("a=b"))
+        );
+        assert_eq!(parse_tunable_arg("name=").unwrap(), (s("name"), s("")));
+        assert!(parse_tunable_arg("num").is_err());
+        assert!(parse_tunable_arg("=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_pinned_tunables() {
+        let pairs = [
+            (s("flag"), s("true")),
+            (s("num"), s("1")),
+            (s("ratio"), s("0.5")),
+            (s("timeout"), s("30s")),
+            (s("name"), s("value")),
+            (s("num"), s("2")),
+        ];
+        let pinned = parse_pinned_tunables(&schema(), &pairs).unwrap();
+        assert_eq!(pinned.values.killswitches, hashmap! { s("flag") => true });
+        assert_eq!(pinned.values.ints, hashmap! { s("num") => 2 });
+        assert_eq!(pinned.values.floats, Some(hashmap! { s("ratio") => 0.5 }));
+        assert_eq!(
+            pinned.values.durations,
+            Some(hashmap! { s("timeout") => s("30s") })
+        );
+        assert_eq!(pinned.values.strings, hashmap! { s("name") => s("value") });
+        assert_eq!(
+            pinned.args,
+            btreemap! {
+                s("flag") => s("true"),
+                s("name") => s("value"),
+                s("num") => s("2"),
+                s("ratio") => s("0.5"),
+                s("timeout") => s("30s"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_pinned_tunables() {
+        let parse = |name: &str, value: &str| {
+            parse_pinned_tunables(&schema(), &[(s(name), s(value))])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(parse("nmu", "1"), "Unknown tunable nmu, did you mean num?");
+        assert_eq!(
+            parse("no_such_tunable", "1"),
+            "Unknown tunable no_such_tunable"
+        );
+        assert_eq!(
+            parse("num", "one"),
+            "Failed to parse tunable num from \"one\""
+        );
+        assert_eq!(
+            parse("timeout", "30"),
+            "Failed to parse tunable timeout from \"30\""
+        );
+        assert_eq!(
+            parse("repoflag", "true"),
+            "Tunable repoflag is configured by repo, and can't be pinned"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Percentage rollouts.
+//!
+//! An `AtomicI64` tunable marked `#[rollout]` is a percentage from 0 to 100,
+//! and gets a `get_<name>_rollout(ctx_hash)` method. Whether a session, repo
+//! or anything else is in the rollout is decided by hashing it together with
+//! the name of the tunable, so that:
+//!  - the same key is in or out on every call, in every process,
+//!  - raising the percentage only adds keys, and lowering it only removes
+//!    them,
+//!  - different tunables pick different keys at the same percentage.
+
+/// A stable hash of `key`, e.g. a session id or repo name, to pass as the
+/// `ctx_hash` of a rollout. Unlike the std hashers, it doesn't change
+/// between builds.
+pub fn rollout_hash(key: impl AsRef<[u8]>) -> u64 {
+    fnv1a(key.as_ref())
+}
+
+/// Whether `ctx_hash` is in the rollout of `percentage` percent of the
+/// tunable called `tunable`. Percentages outside of 0 to 100 are clamped.
+pub fn in_rollout(tunable: &str, percentage: i64, ctx_hash: u64) -> bool {
+    if percentage <= 0 {
+        return false;
+    }
+    if percentage >= 100 {
+        return true;
+    }
+    let bucket = mix(fnv1a(tunable.as_bytes()) ^ ctx_hash) % 100;
+    bucket < percentage as u64
+}
+
+/// 64-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The splitmix64 finalizer, so that similar hashes land in unrelated
+/// buckets.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_in_rollout() {
+        let hashes = (0..10_000u64)
+            .map(|i| rollout_hash(format!("session{}", i)))
+            .collect::<Vec<_>>();
+        let count = |tunable, percentage| {
+            hashes
+                .iter()
+                .filter(|hash| in_rollout(tunable, percentage, **hash))
+                .count()
+        };
+
+        assert_eq!(count("a", 0), 0);
+        assert_eq!(count("a", -5), 0);
+        assert_eq!(count("a", 100), hashes.len());
+        assert_eq!(count("a", 200), hashes.len());
+        for percentage in [1, 10, 50, 90] {
+            let expected = (hashes.len() as i64) * percentage / 100;
+            let actual = count("a", percentage) as i64;
+            assert!(
+                (actual - expected).abs() < hashes.len() as i64 / 50,
+                "{} in a {}% rollout",
+                actual,
+                percentage
+            );
+        }
+
+        // Raising the percentage keeps everything that was already in.
+        assert!(hashes
+            .iter()
+            .filter(|hash| in_rollout("a", 10, **hash))
+            .all(|hash| in_rollout("a", 20, *hash)));
+
+        // Other tunables pick other keys.
+        let both = hashes
+            .iter()
+            .filter(|hash| in_rollout("a", 50, **hash) && in_rollout("b", 50, **hash))
+            .count();
+        assert!(both < hashes.len() * 3 / 10, "{} in both rollouts", both);
+    }
+
+    #[test]
+    fn test_rollout_hash_is_stable() {
+        // Stored hashes and rollouts across hosts rely on this not changing.
+        assert_eq!(rollout_hash(""), 0xcbf29ce484222325);
+        assert_eq!(rollout_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Types of tunables, so that configs can be checked against the tunables
+//! that exist.
+
+/// The type of a tunable, as returned by the `schema` method generated for a
+/// tunables struct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TunableKind {
+    Bool,
+    I64,
+    F64,
+    Duration,
+    String,
+    /// A `TunableEnum`. Its values are configured as strings.
+    Enum,
+    BoolByRepo,
+    I64ByRepo,
+    StringByRepo,
+    VecOfStringsByRepo,
+    DurationByRepo,
+    ByteSizeByRepo,
+}
+
+impl TunableKind {
+    /// The section of the tunables config that tunables of this type are read
+    /// from.
+    pub fn config_section(self) -> &'static str {
+        match self {
+            Self::Bool => "killswitches",
+            Self::I64 => "ints",
+            Self::F64 => "floats",
+            Self::Duration => "durations",
+            Self::String | Self::Enum => "strings",
+            Self::BoolByRepo => "killswitches_by_repo",
+            Self::I64ByRepo => "ints_by_repo",
+            Self::StringByRepo => "strings_by_repo",
+            Self::VecOfStringsByRepo => "vec_of_strings_by_repo",
+            Self::DurationByRepo => "durations_by_repo",
+            Self::ByteSizeByRepo => "byte_sizes_by_repo",
+        }
+    }
+}
+
+/// The name in `candidates` closest to `name`, if it is close enough to be a
+/// likely typo of it.
+pub(crate) fn nearest_name<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = prev[j] + (a_char != *b_char) as usize;
+            current.push(substitution.min(prev[j + 1] + 1).min(current[j] + 1));
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("num", ""), 3);
+        assert_eq!(edit_distance("num", "num"), 0);
+        assert_eq!(edit_distance("num", "nums"), 1);
+        assert_eq!(edit_distance("repobool", "repoobol"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_nearest_name() {
+        let candidates = ["repobool", "repobool2", "repoint", "num"];
+        assert_eq!(nearest_name("repobol", candidates), Some("repobool"));
+        assert_eq!(nearest_name("repoint3", candidates), Some("repoint"));
+        // Short names only match with a single edit.
+        assert_eq!(nearest_name("nu", candidates), Some("num"));
+        assert_eq!(nearest_name("n", candidates), None);
+        assert_eq!(nearest_name("no_such_tunable", candidates), None);
+    }
+}
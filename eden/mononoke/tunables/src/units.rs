@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Parsing of human-readable values in tunables configs.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Split a value like "500ms" into its numeric part and its unit suffix.
+fn split_unit(value: &str) -> (&str, &str) {
+    let value = value.trim();
+    let idx = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    (value[..idx].trim(), value[idx..].trim())
+}
+
+/// Parse a duration such as "500ms", "30s", "5m" or "1h". A unit is
+/// required, so that configs can't silently disagree with callers about it.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, unit) = split_unit(value);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration: {:?}", value))?;
+    let secs = match unit {
+        "ns" => number / 1_000_000_000.0,
+        "us" => number / 1_000_000.0,
+        "ms" => number / 1_000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        "d" => number * 60.0 * 60.0 * 24.0,
+        "" => return Err(anyhow!("Duration {:?} is missing a unit", value)),
+        _ => return Err(anyhow!("Unknown unit in duration {:?}", value)),
+    };
+    Duration::try_from_secs_f64(secs).with_context(|| format!("Invalid duration: {:?}", value))
+}
+
+/// Parse a byte size such as "100B", "512KB" or "2GiB". Units without an
+/// "i" are powers of 1000, units with one are powers of 1024. As with
+/// durations, a unit is required.
+pub fn parse_byte_size(value: &str) -> Result<u64> {
+    let (number, unit) = split_unit(value);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid byte size: {:?}", value))?;
+    let multiplier: u64 = match unit {
+        "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        "" => return Err(anyhow!("Byte size {:?} is missing a unit", value)),
+        _ => return Err(anyhow!("Unknown unit in byte size {:?}", value)),
+    };
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes < 0.0 || bytes > u64::MAX as f64 {
+        return Err(anyhow!("Invalid byte size: {:?}", value));
+    }
+    Ok(bytes.round() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration(" 5 m ").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("10us").unwrap(), Duration::from_micros(10));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10 weeks").is_err());
+        assert!(parse_duration("ms").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("100B").unwrap(), 100);
+        assert_eq!(parse_byte_size("512KB").unwrap(), 512_000);
+        assert_eq!(parse_byte_size("1.5 MiB").unwrap(), 3 << 19);
+        assert_eq!(parse_byte_size("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_byte_size("1TB").unwrap(), 1_000_000_000_000);
+        assert!(parse_byte_size("100").is_err());
+        assert!(parse_byte_size("100 bytes").is_err());
+        assert!(parse_byte_size("-1KB").is_err());
+        assert!(parse_byte_size("GiB").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Validation of tunables configs before they are applied. If any validator
+//! rejects a config, none of it is applied and the previous values remain.
+
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::Lazy;
+use tunables_structs::Tunables as TunablesStruct;
+
+pub type TunablesValidatorFn = Box<dyn Fn(&TunablesStruct) -> Result<()> + Send + Sync>;
+
+static VALIDATORS: Lazy<TunablesValidators> = Lazy::new(|| {
+    let validators = TunablesValidators::default();
+    validators.register_int_range("zstd_compression_level", 0..=19);
+    validators.register_int_range("wishlist_read_qps", 0..);
+    validators.register_int_range("wishlist_write_qps", 0..);
+    validators
+});
+
+/// A set of named validators for tunables configs.
+#[derive(Default)]
+pub struct TunablesValidators {
+    validators: Mutex<Vec<(String, TunablesValidatorFn)>>,
+}
+
+impl TunablesValidators {
+    pub fn register(&self, name: &str, validator: TunablesValidatorFn) {
+        self.validators
+            .lock()
+            .expect("Poisoned lock")
+            .push((name.to_string(), validator));
+    }
+
+    /// Require the int tunable `tunable`, if set, to be within `range`.
+    pub fn register_int_range<R>(&self, tunable: &str, range: R)
+    where
+        R: RangeBounds<i64> + Debug + Send + Sync + 'static,
+    {
+        let name = tunable.to_string();
+        self.register(
+            &format!("{} in range {:?}", tunable, range),
+            Box::new(move |tunables| match tunables.ints.get(&name) {
+                Some(value) if !range.contains(value) => Err(anyhow!(
+                    "{} is {}, which is outside of {:?}",
+                    name,
+                    value,
+                    range
+                )),
+                _ => Ok(()),
+            }),
+        );
+    }
+
+    /// Run all validators, returning the first failure.
+    pub fn validate(&self, tunables: &TunablesStruct) -> Result<()> {
+        let validators = self.validators.lock().expect("Poisoned lock");
+        for (name, validator) in validators.iter() {
+            validator(tunables).with_context(|| format!("Tunables validator {} failed", name))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn validators() -> &'static TunablesValidators {
+    &VALIDATORS
+}
+
+/// Register a validator that every new tunables config must pass before it
+/// is applied.
+pub fn register_tunables_validator(
+    name: &str,
+    validator: impl Fn(&TunablesStruct) -> Result<()> + Send + Sync + 'static,
+) {
+    validators().register(name, Box::new(validator))
+}
+
+/// Register a validator requiring the int tunable `tunable` to be within
+/// `range` whenever it is set.
+pub fn register_int_range_validator<R>(tunable: &str, range: R)
+where
+    R: RangeBounds<i64> + Debug + Send + Sync + 'static,
+{
+    validators().register_int_range(tunable, range)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_int_range() {
+        let validators = TunablesValidators::default();
+        validators.register_int_range("level", 0..=19);
+
+        let config = |level| TunablesStruct {
+            ints: hashmap! { "level".to_string() => level },
+            ..Default::default()
+        };
+        assert!(validators.validate(&TunablesStruct::default()).is_ok());
+        assert!(validators.validate(&config(19)).is_ok());
+        assert!(validators.validate(&config(20)).is_err());
+        assert!(validators.validate(&config(-1)).is_err());
+    }
+
+    #[test]
+    fn test_custom_validator() {
+        let validators = TunablesValidators::default();
+        validators.register(
+            "no_empty_strings",
+            Box::new(|tunables| {
+                if tunables.strings.values().any(|s| s.is_empty()) {
+                    Err(anyhow!("empty string"))
+                } else {
+                    Ok(())
+                }
+            }),
+        );
+
+        let valid = TunablesStruct {
+            strings: hashmap! { "a".to_string() => "b".to_string() },
+            ..Default::default()
+        };
+        let invalid = TunablesStruct {
+            strings: hashmap! { "a".to_string() => "".to_string() },
+            ..Default::default()
+        };
+        assert!(validators.validate(&valid).is_ok());
+        assert!(validators.validate(&invalid).is_err());
+    }
+}
@@ -9,41 +9,72 @@ extern crate proc_macro;
 
 use proc_macro2::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Type};
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, Ident, PathArguments, Type,
+    Visibility,
+};
 
-const UNIMPLEMENTED_MSG: &str = "Only AtomicBool and AtomicI64 are supported";
+const UNIMPLEMENTED_MSG: &str = "Only AtomicBool, AtomicI64, TunableF64, TunableDuration, TunableString and TunableEnum are supported";
 const STRUCT_FIELD_MSG: &str = "Only implemented for named fields of a struct";
+const ROLLOUT_MSG: &str = "Only AtomicI64 tunables can be marked #[rollout]";
 
 #[derive(Clone, PartialEq)]
 enum TunableType {
     Bool,
     I64,
+    F64,
+    Duration,
     String,
+    /// A `TunableEnum<T>`, with the type of its values.
+    Enum(Box<Type>),
     ByRepoBool,
     ByRepoString,
     ByRepoI64,
     ByRepoVecOfStrings,
+    ByRepoDuration,
+    ByRepoByteSize,
 }
 
-#[proc_macro_derive(Tunables)]
+#[proc_macro_derive(Tunables, attributes(rollout))]
 // This proc macro accepts a struct and provides methods that get the atomic
 // values stored inside of it. It does this by generating methods
-// named get_<field>(). The macro also generates methods that update the
-// atomic values inside of the struct, using a provided HashMap.
+// named get_<field>(), and get_<type>_by_name() methods that look them up by
+// name. The macro also generates methods that update the atomic values
+// inside of the struct, using a provided HashMap, snapshot() / diff()
+// methods that report every tunable by name, a schema() method with the type
+// of every tunable, and a <struct>Key enum with a variant per tunable.
+// AtomicI64 tunables marked #[rollout] are percentages, and also get a
+// get_<field>_rollout(ctx_hash) method.
 pub fn derive_tunables(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let parsed_input = parse_macro_input!(input as DeriveInput);
 
     let struct_name = parsed_input.ident;
+    let key_name = quote::format_ident!("{}Key", struct_name);
+    let vis = parsed_input.vis;
+    let rollout_names = parse_rollout_names(&parsed_input.data);
     let names_and_types = parse_names_and_types(parsed_input.data).into_iter();
 
     let getter_methods = generate_getter_methods(names_and_types.clone());
-    let updater_methods = generate_updater_methods(names_and_types);
+    let rollout_methods = generate_rollout_methods(&rollout_names);
+    let by_name_methods = generate_by_name_methods(names_and_types.clone());
+    let updater_methods = generate_updater_methods(names_and_types.clone());
+    let enum_methods = generate_enum_methods(names_and_types.clone());
+    let snapshot_methods = generate_snapshot_methods(names_and_types.clone());
+    let schema_method = generate_schema_method(names_and_types.clone());
+    let key_enum = generate_key_enum(&key_name, &vis, names_and_types);
 
     let expanded = quote! {
         impl #struct_name {
             #updater_methods
+            #enum_methods
             #getter_methods
+            #rollout_methods
+            #by_name_methods
+            #snapshot_methods
+            #schema_method
         }
+
+        #key_enum
     };
 
     expanded.into()
@@ -54,21 +85,59 @@ impl TunableType {
         match self {
             Self::Bool => quote! { bool },
             Self::I64 => quote! { i64 },
+            Self::F64 => quote! { f64 },
+            Self::Duration => quote! { std::time::Duration },
             Self::String => quote! { Arc<String> },
+            Self::Enum(ty) => quote! { #ty },
             Self::ByRepoBool => quote! { Option<bool> },
             Self::ByRepoString => quote! { Option<String> },
             Self::ByRepoI64 => quote! { Option<i64> },
             Self::ByRepoVecOfStrings => quote! { Option<Vec<String>> },
+            Self::ByRepoDuration => quote! { Option<std::time::Duration> },
+            Self::ByRepoByteSize => quote! { Option<u64> },
+        }
+    }
+
+    fn kind(&self) -> TokenStream {
+        match self {
+            Self::Bool => quote! { TunableKind::Bool },
+            Self::I64 => quote! { TunableKind::I64 },
+            Self::F64 => quote! { TunableKind::F64 },
+            Self::Duration => quote! { TunableKind::Duration },
+            Self::String => quote! { TunableKind::String },
+            Self::Enum(_) => quote! { TunableKind::Enum },
+            Self::ByRepoBool => quote! { TunableKind::BoolByRepo },
+            Self::ByRepoString => quote! { TunableKind::StringByRepo },
+            Self::ByRepoI64 => quote! { TunableKind::I64ByRepo },
+            Self::ByRepoVecOfStrings => quote! { TunableKind::VecOfStringsByRepo },
+            Self::ByRepoDuration => quote! { TunableKind::DurationByRepo },
+            Self::ByRepoByteSize => quote! { TunableKind::ByteSizeByRepo },
         }
     }
 
     fn by_repo_value_type(&self) -> TokenStream {
         match self {
-            Self::Bool | Self::I64 | Self::String => panic!("Expected ByRepo flavor of tunable"),
+            Self::Bool | Self::I64 | Self::F64 | Self::Duration | Self::String | Self::Enum(_) => {
+                panic!("Expected ByRepo flavor of tunable")
+            }
             Self::ByRepoBool => quote! { bool },
             Self::ByRepoI64 => quote! { i64 },
             Self::ByRepoString => quote! { String },
             Self::ByRepoVecOfStrings => quote! { Vec<String> },
+            Self::ByRepoDuration => quote! { std::time::Duration },
+            Self::ByRepoByteSize => quote! { u64 },
+        }
+    }
+
+    /// The non-ByRepo flavor of a ByRepo tunable. Global tunables of this type
+    /// with the same name are used as fallback for repos without a value.
+    fn global_flavor(&self) -> Option<TunableType> {
+        match self {
+            Self::ByRepoBool => Some(Self::Bool),
+            Self::ByRepoI64 => Some(Self::I64),
+            Self::ByRepoString => Some(Self::String),
+            Self::ByRepoDuration => Some(Self::Duration),
+            _ => None,
         }
     }
 
@@ -76,22 +145,178 @@ impl TunableType {
         match self {
             Self::Bool => quote! { HashMap<String, bool> },
             Self::I64 => quote! { HashMap<String, i64> },
-            Self::String => quote! { HashMap<String, String> },
+            Self::F64 => quote! { HashMap<String, f64> },
+            Self::Duration => quote! { HashMap<String, std::time::Duration> },
+            Self::String | Self::Enum(_) => quote! { HashMap<String, String> },
             Self::ByRepoBool => quote! { HashMap<String, HashMap<String, bool>> },
             Self::ByRepoString => quote! { HashMap<String, HashMap<String, String>> },
             Self::ByRepoI64 => quote! { HashMap<String, HashMap<String, i64>> },
             Self::ByRepoVecOfStrings => quote! { HashMap<String, HashMap<String, Vec<String>>> },
+            Self::ByRepoDuration => {
+                quote! { HashMap<String, HashMap<String, std::time::Duration>> }
+            }
+            Self::ByRepoByteSize => quote! { HashMap<String, HashMap<String, u64>> },
+        }
+    }
+
+    fn generate_snapshot_value(&self, name: &Ident) -> TokenStream {
+        match self {
+            Self::Bool => quote! {
+                TunableValue::Bool(self.#name.load(std::sync::atomic::Ordering::Relaxed))
+            },
+            Self::I64 => quote! {
+                TunableValue::I64(self.#name.load(std::sync::atomic::Ordering::Relaxed))
+            },
+            Self::F64 => quote! {
+                TunableValue::F64(self.#name.load(std::sync::atomic::Ordering::Relaxed))
+            },
+            Self::Duration => quote! {
+                TunableValue::Duration(self.#name.load(std::sync::atomic::Ordering::Relaxed))
+            },
+            Self::String => quote! {
+                TunableValue::String((*self.#name.load_full()).clone())
+            },
+            Self::Enum(_) => quote! {
+                TunableValue::Enum(self.#name.load().to_string())
+            },
+            Self::ByRepoBool => quote! {
+                TunableValue::BoolByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+            Self::ByRepoI64 => quote! {
+                TunableValue::I64ByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+            Self::ByRepoString => quote! {
+                TunableValue::StringByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+            Self::ByRepoVecOfStrings => quote! {
+                TunableValue::VecOfStringsByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+            Self::ByRepoDuration => quote! {
+                TunableValue::DurationByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+            Self::ByRepoByteSize => quote! {
+                TunableValue::ByteSizeByRepo {
+                    global: self.#name.load_global(),
+                    by_repo: self.#name.sorted_by_repo(),
+                }
+            },
+        }
+    }
+
+    /// An arm of a match on `TunableValue` that stores `value` in the tunable
+    /// `name`, if the value is of the type of the tunable.
+    fn generate_set_value_arm(&self, name: &Ident) -> TokenStream {
+        match self {
+            Self::Bool => quote! {
+                (stringify!(#name), TunableValue::Bool(value)) => {
+                    self.#name.store(value, std::sync::atomic::Ordering::Relaxed);
+                    true
+                }
+            },
+            Self::I64 => quote! {
+                (stringify!(#name), TunableValue::I64(value)) => {
+                    self.#name.store(value, std::sync::atomic::Ordering::Relaxed);
+                    true
+                }
+            },
+            Self::F64 => quote! {
+                (stringify!(#name), TunableValue::F64(value)) => {
+                    self.#name.store(value, std::sync::atomic::Ordering::Relaxed);
+                    true
+                }
+            },
+            Self::Duration => quote! {
+                (stringify!(#name), TunableValue::Duration(value)) => {
+                    self.#name.store(value, std::sync::atomic::Ordering::Relaxed);
+                    true
+                }
+            },
+            Self::String => quote! {
+                (stringify!(#name), TunableValue::String(value)) => {
+                    self.#name.store(Arc::new(value));
+                    true
+                }
+            },
+            Self::Enum(ty) => quote! {
+                (stringify!(#name), TunableValue::Enum(value)) => {
+                    match TunableEnum::<#ty>::parse(stringify!(#name), &value) {
+                        Ok(value) => {
+                            self.#name.store(value);
+                            true
+                        }
+                        Err(_) => false,
+                    }
+                }
+            },
+            Self::ByRepoBool => quote! {
+                (stringify!(#name), TunableValue::BoolByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
+            Self::ByRepoI64 => quote! {
+                (stringify!(#name), TunableValue::I64ByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
+            Self::ByRepoString => quote! {
+                (stringify!(#name), TunableValue::StringByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
+            Self::ByRepoVecOfStrings => quote! {
+                (stringify!(#name), TunableValue::VecOfStringsByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
+            Self::ByRepoDuration => quote! {
+                (stringify!(#name), TunableValue::DurationByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
+            Self::ByRepoByteSize => quote! {
+                (stringify!(#name), TunableValue::ByteSizeByRepo { global, by_repo }) => {
+                    self.#name.store_global(global);
+                    self.#name.store_by_repo(by_repo.into_iter().collect());
+                    true
+                }
+            },
         }
     }
 
     fn generate_getter_method(&self, name: Ident) -> TokenStream {
         let method = quote::format_ident!("get_{}", name);
         let by_repo_method = quote::format_ident!("get_by_repo_{}", name);
+        let by_repo_or_default_method = quote::format_ident!("get_by_repo_{}_or_default", name);
 
         let external_type = self.external_type();
 
         match &self {
-            Self::Bool | Self::I64 => {
+            Self::Bool | Self::I64 | Self::F64 | Self::Duration => {
                 quote! {
                     pub fn #method(&self) -> #external_type {
                         return self.#name.load(std::sync::atomic::Ordering::Relaxed)
@@ -105,10 +330,27 @@ impl TunableType {
                     }
                 }
             }
-            Self::ByRepoBool | Self::ByRepoI64 | Self::ByRepoString | Self::ByRepoVecOfStrings => {
+            Self::Enum(_) => {
+                quote! {
+                    pub fn #method(&self) -> #external_type {
+                        self.#name.load()
+                    }
+                }
+            }
+            Self::ByRepoBool
+            | Self::ByRepoI64
+            | Self::ByRepoString
+            | Self::ByRepoVecOfStrings
+            | Self::ByRepoDuration
+            | Self::ByRepoByteSize => {
+                let by_repo_value_type = self.by_repo_value_type();
                 quote! {
                     pub fn #by_repo_method(&self, repo: &str) -> #external_type {
-                        self.#name.load_full().get(repo).map(|val| (*val).clone())
+                        self.#name.load_by_repo().get(repo).cloned()
+                    }
+
+                    pub fn #by_repo_or_default_method(&self, repo: &str) -> #by_repo_value_type {
+                        self.#name.get_or_fallback(repo).unwrap_or_default()
                     }
                 }
             }
@@ -129,6 +371,202 @@ where
     methods
 }
 
+fn generate_rollout_methods(names: &[Ident]) -> TokenStream {
+    let methods = names
+        .iter()
+        .map(|n| quote::format_ident!("get_{}_rollout", n));
+    let getters = names.iter().map(|n| quote::format_ident!("get_{}", n));
+
+    quote! {
+        #(
+            /// Whether `ctx_hash` is in the percentage rollout set by this
+            /// tunable. See `in_rollout`.
+            pub fn #methods(&self, ctx_hash: u64) -> bool {
+                in_rollout(stringify!(#names), self.#getters(), ctx_hash)
+            }
+        )*
+    }
+}
+
+fn generate_by_name_methods<I>(names_and_types: I) -> TokenStream
+where
+    I: Iterator<Item = (Ident, TunableType)> + std::clone::Clone,
+{
+    let mut methods = TokenStream::new();
+
+    for (method, ty) in [
+        ("get_bool_by_name", TunableType::Bool),
+        ("get_int_by_name", TunableType::I64),
+        ("get_float_by_name", TunableType::F64),
+        ("get_duration_by_name", TunableType::Duration),
+        ("get_string_by_name", TunableType::String),
+    ] {
+        let method = quote::format_ident!("{}", method);
+        let external_type = ty.external_type();
+        let names = names_and_types
+            .clone()
+            .filter(|(_, t)| *t == ty)
+            .map(|(n, _)| n);
+        let getters = names.clone().map(|n| quote::format_ident!("get_{}", n));
+        methods.extend(quote! {
+            /// The value of the tunable called `name`, or `None` if there is
+            /// no tunable of this type with that name.
+            pub fn #method(&self, name: &str) -> Option<#external_type> {
+                match name {
+                    #(stringify!(#names) => Some(self.#getters()),)*
+                    _ => None,
+                }
+            }
+        });
+    }
+
+    let names = names_and_types.clone().map(|(n, _)| n);
+    let values = names_and_types.map(|(n, ty)| ty.generate_snapshot_value(&n));
+    methods.extend(quote! {
+        /// The value of the tunable called `name`, of any type.
+        pub fn get_value_by_name(&self, name: &str) -> Option<TunableValue> {
+            match name {
+                #(stringify!(#names) => Some(#values),)*
+                _ => None,
+            }
+        }
+    });
+
+    methods
+}
+
+fn generate_key_enum<I>(key_name: &Ident, vis: &Visibility, names_and_types: I) -> TokenStream
+where
+    I: Iterator<Item = (Ident, TunableType)>,
+{
+    let names = names_and_types.map(|(n, _)| n).collect::<Vec<_>>();
+    let variants = names
+        .iter()
+        .map(|n| {
+            let camel_case = n
+                .to_string()
+                .split('_')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<String>();
+            Ident::new(&camel_case, n.span())
+        })
+        .collect::<Vec<_>>();
+
+    quote! {
+        /// Names of the tunables, to refer to them without a getter per tunable.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #vis enum #key_name {
+            #(#variants,)*
+        }
+
+        impl #key_name {
+            /// Every tunable, in declaration order.
+            pub const ALL: &'static [Self] = &[#(Self::#variants,)*];
+
+            /// The name of the tunable, as used in configs.
+            pub fn name(self) -> &'static str {
+                match self {
+                    #(Self::#variants => stringify!(#names),)*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    #(stringify!(#names) => Some(Self::#variants),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl std::fmt::Display for #key_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    }
+}
+
+fn generate_snapshot_methods<I>(names_and_types: I) -> TokenStream
+where
+    I: Iterator<Item = (Ident, TunableType)> + std::clone::Clone,
+{
+    let mut inserts = TokenStream::new();
+    let mut set_value_arms = TokenStream::new();
+
+    for (name, ty) in names_and_types {
+        let value = ty.generate_snapshot_value(&name);
+        inserts.extend(quote! {
+            snapshot.insert(stringify!(#name).to_string(), #value);
+        });
+        set_value_arms.extend(ty.generate_set_value_arm(&name));
+    }
+
+    quote! {
+        /// The current value of every tunable, keyed by name.
+        pub fn snapshot(&self) -> std::collections::BTreeMap<String, TunableValue> {
+            #[allow(unused_mut)]
+            let mut snapshot = std::collections::BTreeMap::new();
+            #inserts
+            snapshot
+        }
+
+        /// Tunables whose values differ between `self` and `other`, keyed by
+        /// name, with the value in `self` first.
+        pub fn diff(
+            &self,
+            other: &Self,
+        ) -> std::collections::BTreeMap<String, (TunableValue, TunableValue)> {
+            let mut other = other.snapshot();
+            self.snapshot()
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    let other_value = other.remove(&name)?;
+                    if value != other_value {
+                        Some((name, (value, other_value)))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        }
+
+        /// Set the tunable called `name` to `value`, the way `snapshot`
+        /// reports it. Returns false, and changes nothing, if there is no
+        /// tunable of the type of `value` with that name.
+        pub fn set_value_by_name(&self, name: &str, value: TunableValue) -> bool {
+            match (name, value) {
+                #set_value_arms
+                _ => false,
+            }
+        }
+    }
+}
+
+fn generate_schema_method<I>(names_and_types: I) -> TokenStream
+where
+    I: Iterator<Item = (Ident, TunableType)>,
+{
+    let (names, kinds): (Vec<_>, Vec<_>) = names_and_types.map(|(n, ty)| (n, ty.kind())).unzip();
+
+    quote! {
+        /// The type of every tunable, keyed by name, so that configs can be
+        /// checked for names that are not tunables of the type of their
+        /// section.
+        pub fn schema() -> std::collections::BTreeMap<&'static str, TunableKind> {
+            #[allow(unused_mut)]
+            let mut schema = std::collections::BTreeMap::new();
+            #(schema.insert(stringify!(#names), #kinds);)*
+            schema
+        }
+    }
+}
+
 fn generate_updater_methods<I>(names_and_types: I) -> TokenStream
 where
     I: Iterator<Item = (Ident, TunableType)> + std::clone::Clone,
@@ -147,6 +585,18 @@ where
         quote::format_ident!("update_ints"),
     ));
 
+    methods.extend(generate_updater_method(
+        names_and_types.clone(),
+        TunableType::F64,
+        quote::format_ident!("update_floats"),
+    ));
+
+    methods.extend(generate_updater_method(
+        names_and_types.clone(),
+        TunableType::Duration,
+        quote::format_ident!("update_durations"),
+    ));
+
     methods.extend(generate_updater_method(
         names_and_types.clone(),
         TunableType::String,
@@ -172,11 +622,23 @@ where
     ));
 
     methods.extend(generate_updater_method(
-        names_and_types,
+        names_and_types.clone(),
         TunableType::ByRepoVecOfStrings,
         quote::format_ident!("update_by_repo_vec_of_strings"),
     ));
 
+    methods.extend(generate_updater_method(
+        names_and_types.clone(),
+        TunableType::ByRepoDuration,
+        quote::format_ident!("update_by_repo_durations"),
+    ));
+
+    methods.extend(generate_updater_method(
+        names_and_types,
+        TunableType::ByRepoByteSize,
+        quote::format_ident!("update_by_repo_byte_sizes"),
+    ));
+
     methods
 }
 
@@ -188,14 +650,27 @@ fn generate_updater_method<I>(
 where
     I: Iterator<Item = (Ident, TunableType)> + std::clone::Clone,
 {
-    let names = names_and_types.filter(|(_, t)| *t == ty).map(|(n, _)| n);
+    let fallback_names = names_and_types
+        .clone()
+        .filter(|(_, t)| t.global_flavor().as_ref() == Some(&ty))
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
+    // Enum tunables are configured as strings.
+    let enum_names = names_and_types
+        .clone()
+        .filter(|(_, t)| ty == TunableType::String && matches!(t, TunableType::Enum(_)))
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
+    let names = names_and_types
+        .filter(|(_, t)| *t == ty)
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
 
-    let mut names = names.peekable();
     let mut body = TokenStream::new();
 
-    if names.peek().is_some() {
+    if !names.is_empty() {
         match ty {
-            TunableType::I64 | TunableType::Bool => {
+            TunableType::I64 | TunableType::Bool | TunableType::F64 | TunableType::Duration => {
                 body.extend(quote! {
                     #(self.#names.store(
                       tunables.get(stringify!(#names)).cloned().unwrap_or_default(),
@@ -210,10 +685,13 @@ where
                     );)*
                 });
             }
+            TunableType::Enum(_) => panic!("Enum tunables are updated with strings"),
             TunableType::ByRepoBool
             | TunableType::ByRepoString
             | TunableType::ByRepoI64
-            | TunableType::ByRepoVecOfStrings => {
+            | TunableType::ByRepoVecOfStrings
+            | TunableType::ByRepoDuration
+            | TunableType::ByRepoByteSize => {
                 let by_repo_value_type = ty.by_repo_value_type();
                 body.extend(quote! {
                     #(
@@ -228,21 +706,77 @@ where
                                     }
                                 }
                         }
-                        self.#names.swap(Arc::new(new_values_by_repo));
+                        self.#names.store_by_repo(new_values_by_repo);
                     )*
                 });
             }
         }
     }
 
+    body.extend(quote! {
+        #(self.#fallback_names.store_global(tunables.get(stringify!(#fallback_names)).cloned());)*
+        #(self.#enum_names.update(stringify!(#enum_names), tunables.get(stringify!(#enum_names)));)*
+    });
+
+    let configured_names = match ty {
+        TunableType::ByRepoBool
+        | TunableType::ByRepoString
+        | TunableType::ByRepoI64
+        | TunableType::ByRepoVecOfStrings
+        | TunableType::ByRepoDuration
+        | TunableType::ByRepoByteSize => quote! {
+            tunables.values().flat_map(|val_by_tunable| val_by_tunable.keys())
+        },
+        _ => quote! { tunables.keys() },
+    };
+    body.extend(quote! {
+        let known: &[&str] = &[
+            #(stringify!(#names),)*
+            #(stringify!(#fallback_names),)*
+            #(stringify!(#enum_names),)*
+        ];
+        #configured_names
+            .filter(|name| !known.contains(&name.as_str()))
+            .cloned()
+            .collect()
+    });
+
     let update_container_type = ty.update_container_type();
     quote! {
-        pub fn #method_name(&self, tunables: &#update_container_type) {
+        /// Returns the names in `tunables` that are not tunables of this type.
+        /// Their values are ignored.
+        pub fn #method_name(
+            &self,
+            tunables: &#update_container_type,
+        ) -> std::collections::BTreeSet<String> {
             #body
         }
     }
 }
 
+fn generate_enum_methods<I>(names_and_types: I) -> TokenStream
+where
+    I: Iterator<Item = (Ident, TunableType)>,
+{
+    let (names, types): (Vec<_>, Vec<_>) = names_and_types
+        .filter_map(|(n, t)| match t {
+            TunableType::Enum(ty) => Some((n, ty)),
+            _ => None,
+        })
+        .unzip();
+
+    quote! {
+        /// Check that every enum tunable in `tunables` has a valid value, so
+        /// that a config with a misspelled value can be rejected as a whole.
+        pub fn validate_enums(tunables: &HashMap<String, String>) -> anyhow::Result<()> {
+            #(if let Some(value) = tunables.get(stringify!(#names)) {
+                TunableEnum::<#types>::parse(stringify!(#names), value)?;
+            })*
+            Ok(())
+        }
+    }
+}
+
 fn parse_names_and_types(data: Data) -> Vec<(Ident, TunableType)> {
     match data {
         Data::Struct(data) => match data.fields {
@@ -257,14 +791,40 @@ fn parse_names_and_types(data: Data) -> Vec<(Ident, TunableType)> {
     }
 }
 
+/// The names of the fields marked `#[rollout]`.
+fn parse_rollout_names(data: &Data) -> Vec<Ident> {
+    match data {
+        Data::Struct(data) => data
+            .fields
+            .iter()
+            .filter(|f| f.attrs.iter().any(|attr| attr.path.is_ident("rollout")))
+            .filter_map(|f| {
+                if resolve_type(f.ty.clone()) != TunableType::I64 {
+                    unimplemented!("{}", ROLLOUT_MSG);
+                }
+                f.ident.clone()
+            })
+            .collect(),
+        _ => unimplemented!("{}", STRUCT_FIELD_MSG),
+    }
+}
+
 fn resolve_type(ty: Type) -> TunableType {
     // TODO: Handle full paths to the types, such as
     // std::sync::atomic::AtomicBool, rather than just the type name.
     if let Type::Path(p) = ty {
+        if let Some(ty) = enum_value_type(&p.path) {
+            return TunableType::Enum(Box::new(ty));
+        }
         if let Some(ident) = p.path.get_ident() {
             match &ident.to_string()[..] {
                 "AtomicBool" => return TunableType::Bool,
                 "AtomicI64" => return TunableType::I64,
+                // There are no std atomics for these types, so they are
+                // newtypes in the tunables crate with the same load/store
+                // interface as the std atomics.
+                "TunableF64" => return TunableType::F64,
+                "TunableDuration" => return TunableType::Duration,
                 // TunableString is a type alias of ArcSwap<String>.
                 // p.path.get_ident() returns None for ArcSwap<String>
                 // and it makes it harder to parse it.
@@ -274,6 +834,8 @@ fn resolve_type(ty: Type) -> TunableType {
                 "TunableI64ByRepo" => return TunableType::ByRepoI64,
                 "TunableStringByRepo" => return TunableType::ByRepoString,
                 "TunableVecOfStringsByRepo" => return TunableType::ByRepoVecOfStrings,
+                "TunableDurationByRepo" => return TunableType::ByRepoDuration,
+                "TunableByteSizeByRepo" => return TunableType::ByRepoByteSize,
                 _ => unimplemented!("{}, found: {}", UNIMPLEMENTED_MSG, &ident.to_string()[..]),
             }
         }
@@ -281,3 +843,19 @@ fn resolve_type(ty: Type) -> TunableType {
 
     unimplemented!("{}", UNIMPLEMENTED_MSG);
 }
+
+/// The type of the values of a `TunableEnum<T>`, or `None` if `path` is some
+/// other type.
+fn enum_value_type(path: &syn::Path) -> Option<Type> {
+    let segment = path.segments.last()?;
+    if segment.ident != "TunableEnum" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(GenericArgument::Type(ty)) if args.args.len() == 1 => Some(ty.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -133,10 +133,32 @@ impl Group {
     ///   will refer to a bounded subset in this group.
     pub const NON_MASTER: Self = Self(1);
 
-    pub const ALL: [Self; 2] = [Self::MASTER, Self::NON_MASTER];
+    /// The "hidden" group.
+    /// - Obsoleted or otherwise invisible commits that are still kept
+    ///   locally. For example, predecessors of amended commits.
+    /// - Keeping them apart from `NON_MASTER` means visibility calculation
+    ///   does not need to scan obsolete drafts mixed with visible ones.
+    /// - Like `NON_MASTER`, ids in this group are re-assigned when they
+    ///   become ancestors of a head in a lower group.
+    pub const HIDDEN: Self = Self(2);
+
+    pub const ALL: [Self; 3] = [Self::MASTER, Self::NON_MASTER, Self::HIDDEN];
 
     pub const COUNT: usize = Self::ALL.len();
 
+    /// The maximum number of groups. Limited by `hex_bytes`, which encodes
+    /// the group as a single digit.
+    pub const MAX_COUNT: usize = 10;
+
+    /// The last group. Ids in any group are at most `Group::MAX.max_id()`.
+    pub const MAX: Self = Self::ALL[Self::COUNT - 1];
+
+    /// Groups other than `MASTER`. They are removed and rebuilt together by
+    /// `remove_non_master`.
+    pub fn non_master() -> impl Iterator<Item = Self> {
+        Self::ALL.into_iter().skip(1)
+    }
+
     // 1 byte for Group so it's easier to remove everything in a group.
     pub const BITS: u32 = 8;
     pub const BYTES: usize = 1;
@@ -158,7 +180,7 @@ impl Group {
 
     /// Convert to hex array.
     pub fn hex_bytes(self) -> [u8; 2] {
-        if self.0 < 10 {
+        if self.0 < Self::MAX_COUNT {
             [b'0', b'0' + (self.0 as u8)]
         } else {
             unreachable!()
@@ -166,6 +188,8 @@ impl Group {
     }
 }
 
+const _: () = assert!(Group::COUNT <= Group::MAX_COUNT);
+
 impl Id {
     /// The [`Group`] of an Id.
     pub fn group(self) -> Group {
@@ -195,7 +219,7 @@ impl Id {
         [prefix, a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]]
     }
 
-    pub const MAX: Self = Group::ALL[Group::COUNT - 1].max_id();
+    pub const MAX: Self = Group::MAX.max_id();
     pub const MIN: Self = Group::ALL[0].min_id();
 }
 
@@ -204,6 +228,8 @@ impl fmt::Display for Id {
         let group = self.group();
         if group == Group::NON_MASTER {
             write!(f, "N")?;
+        } else if group == Group::HIDDEN {
+            write!(f, "H")?;
         }
         write!(f, "{}", self.0 - group.min_id().0)
     }
@@ -220,6 +246,7 @@ impl fmt::Display for Group {
         match *self {
             Group::MASTER => write!(f, "Group Master"),
             Group::NON_MASTER => write!(f, "Group Non-Master"),
+            Group::HIDDEN => write!(f, "Group Hidden"),
             _ => write!(f, "Group {}", self.0),
         }
     }
@@ -269,7 +296,7 @@ use quickcheck::Gen;
 #[cfg(any(test, feature = "for-tests"))]
 impl Arbitrary for Id {
     fn arbitrary(g: &mut Gen) -> Self {
-        let group = Group((u32::arbitrary(g) & 1) as usize);
+        let group = Group(u32::arbitrary(g) as usize % Group::COUNT);
         group.min_id() + u64::arbitrary(g) % (group.max_id().0 - group.min_id().0)
     }
 }
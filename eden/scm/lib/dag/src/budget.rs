@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Limits on the cost of dag operations.
+//!
+//! A server answering queries from clients cannot predict how expensive a
+//! query is. A [`QueryBudget`] set on a graph makes operations fail with
+//! [`BudgetExceeded`] once they read too many segments, or talk to the
+//! remote too often, instead of running unbounded.
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::errors::DagError;
+use crate::Result;
+
+/// Limits on the work of dag operations. See `NameDag::set_query_budget`.
+///
+/// The budget is consumed by every operation using it, and is not reset.
+/// Use a new budget for each request to limit requests separately.
+#[derive(Debug, Default)]
+pub struct QueryBudget {
+    max_segments: Option<u64>,
+    max_remote_round_trips: Option<u64>,
+    segments: AtomicU64,
+    remote_round_trips: AtomicU64,
+}
+
+/// What a [`QueryBudget`] limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetResource {
+    /// Segments read from the `IdDag`.
+    Segments,
+    /// Round-trips to the remote protocol.
+    RemoteRoundTrips,
+}
+
+/// An operation was stopped because it went over its [`QueryBudget`].
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("query budget exceeded: more than {limit} {resource}")]
+pub struct BudgetExceeded {
+    pub resource: BudgetResource,
+    pub limit: u64,
+}
+
+impl QueryBudget {
+    /// A budget without limits. Useful to measure the cost of operations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of segments read.
+    pub fn with_max_segments(mut self, max_segments: u64) -> Self {
+        self.max_segments = Some(max_segments);
+        self
+    }
+
+    /// Limit the number of round-trips to the remote protocol.
+    pub fn with_max_remote_round_trips(mut self, max_remote_round_trips: u64) -> Self {
+        self.max_remote_round_trips = Some(max_remote_round_trips);
+        self
+    }
+
+    /// Segments read so far.
+    pub fn segments(&self) -> u64 {
+        self.segments.load(Ordering::Relaxed)
+    }
+
+    /// Round-trips to the remote protocol so far.
+    pub fn remote_round_trips(&self) -> u64 {
+        self.remote_round_trips.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn charge_segments(&self, count: u64) -> Result<()> {
+        charge(
+            &self.segments,
+            count,
+            self.max_segments,
+            BudgetResource::Segments,
+        )
+    }
+
+    pub(crate) fn charge_remote_round_trip(&self) -> Result<()> {
+        charge(
+            &self.remote_round_trips,
+            1,
+            self.max_remote_round_trips,
+            BudgetResource::RemoteRoundTrips,
+        )
+    }
+}
+
+fn charge(
+    counter: &AtomicU64,
+    count: u64,
+    limit: Option<u64>,
+    resource: BudgetResource,
+) -> Result<()> {
+    let total = counter.fetch_add(count, Ordering::Relaxed) + count;
+    match limit {
+        Some(limit) if total > limit => {
+            Err(DagError::BudgetExceeded(BudgetExceeded { resource, limit }))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl fmt::Display for BudgetResource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetResource::Segments => f.write_str("segments"),
+            BudgetResource::RemoteRoundTrips => f.write_str("remote round-trips"),
+        }
+    }
+}
+
+/// Charges segments read from an `IdDagStore` to its budget, if it has one.
+#[derive(Clone, Default)]
+pub(crate) struct SegmentMeter {
+    budget: Option<Arc<QueryBudget>>,
+}
+
+impl SegmentMeter {
+    pub(crate) fn set_budget(&mut self, budget: Option<Arc<QueryBudget>>) {
+        self.budget = budget;
+    }
+
+    pub(crate) fn charge(&self, count: usize) -> Result<()> {
+        match &self.budget {
+            Some(budget) => budget.charge_segments(count as u64),
+            None => Ok(()),
+        }
+    }
+
+    /// Charge each item of `iter` as it is read.
+    pub(crate) fn charge_each<'a, T: 'a>(
+        &self,
+        iter: impl Iterator<Item = Result<T>> + 'a,
+    ) -> impl Iterator<Item = Result<T>> + 'a {
+        let meter = self.clone();
+        iter.map(move |item| {
+            meter.charge(1)?;
+            item
+        })
+    }
+}
@@ -16,8 +16,11 @@ use futures::TryStreamExt;
 
 use crate::namedag::MemNameDag;
 use crate::nameset::hints::Hints;
+use crate::nameset::BoxVertexStream;
 use crate::ops::DagAddHeads;
 use crate::ops::Parents;
+use crate::render::TopoColumns;
+use crate::render::TopoRow;
 use crate::DagAlgorithm;
 use crate::NameSet;
 use crate::Result;
@@ -254,6 +257,58 @@ pub(crate) async fn merges(this: &(impl DagAlgorithm + ?Sized), set: NameSet) ->
     })))
 }
 
+pub(crate) async fn ancestors_stream(
+    this: &(impl DagAlgorithm + ?Sized),
+    set: NameSet,
+) -> Result<BoxVertexStream> {
+    let ancestors = this.ancestors(set).await?;
+    this.sort(&ancestors).await?.iter().await
+}
+
+pub(crate) async fn descendants_within(
+    this: &(impl DagAlgorithm + ?Sized),
+    set: NameSet,
+    depth: u64,
+) -> Result<NameSet> {
+    let mut result = set.clone();
+    let mut frontier = set;
+    for _ in 0..depth {
+        frontier = this.children(frontier).await? - result.clone();
+        if frontier.count().await? == 0 {
+            break;
+        }
+        result = result | frontier.clone();
+    }
+    Ok(result)
+}
+
+pub(crate) async fn iter_topo_between(
+    this: &(impl DagAlgorithm + ?Sized),
+    roots: NameSet,
+    heads: NameSet,
+) -> Result<Vec<TopoRow>> {
+    let set = this.range(roots, heads).await?;
+    let vertexes: Vec<VertexName> = this.sort(&set).await?.iter().await?.try_collect().await?;
+    let window: HashSet<VertexName> = vertexes.iter().cloned().collect();
+    let mut columns = TopoColumns::default();
+    let mut rows = Vec::with_capacity(vertexes.len());
+    for vertex in vertexes {
+        let (parents, outside): (Vec<VertexName>, Vec<VertexName>) = this
+            .parent_names(vertex.clone())
+            .await?
+            .into_iter()
+            .partition(|p| window.contains(p));
+        let column = columns.assign(&vertex, &parents);
+        rows.push(TopoRow {
+            vertex,
+            parents,
+            parents_outside: outside.len(),
+            column,
+        });
+    }
+    Ok(rows)
+}
+
 pub(crate) async fn reachable_roots(
     this: &(impl DagAlgorithm + ?Sized),
     roots: NameSet,
@@ -272,6 +327,17 @@ pub(crate) async fn heads_ancestors(
     this.heads(this.ancestors(set).await?).await
 }
 
+pub(crate) async fn visible_heads(
+    this: &(impl DagAlgorithm + ?Sized),
+    heads: NameSet,
+    hidden: NameSet,
+) -> Result<NameSet> {
+    let ancestors = this.ancestors(heads.clone()).await?;
+    let visible = this.ancestors(heads - hidden.clone()).await?;
+    let hidden = this.descendants(hidden).await? - visible;
+    this.heads(ancestors - hidden).await
+}
+
 pub(crate) async fn only(
     this: &(impl DagAlgorithm + ?Sized),
     reachable: NameSet,
@@ -354,6 +420,22 @@ pub(crate) async fn is_ancestor(
     Ok(false)
 }
 
+pub(crate) async fn is_ancestor_batch(
+    this: &(impl DagAlgorithm + ?Sized),
+    pairs: &[(VertexName, VertexName)],
+) -> Result<Vec<bool>> {
+    let mut ancestors_of: HashMap<VertexName, NameSet> = HashMap::new();
+    let mut result = Vec::with_capacity(pairs.len());
+    for (ancestor, descendant) in pairs {
+        if !ancestors_of.contains_key(descendant) {
+            let set = this.ancestors(NameSet::from(descendant.clone())).await?;
+            ancestors_of.insert(descendant.clone(), set);
+        }
+        result.push(ancestors_of[descendant].contains(ancestor).await?);
+    }
+    Ok(result)
+}
+
 #[tracing::instrument(skip(this), level=tracing::Level::DEBUG)]
 pub(crate) async fn hint_subdag_for_insertion(
     this: &(impl Parents + ?Sized),
@@ -44,7 +44,7 @@ macro_rules! delegate {
                         $crate::Result<Option<$crate::Id>>
                     > + Send + 's>> where Self: 's
             {
-                self.$($t)*.vertex_id_with_max_group(name, $crate::Group::NON_MASTER)
+                self.$($t)*.vertex_id_with_max_group(name, $crate::Group::MAX)
             }
             fn contains_vertex_id_locally<'a: 's, 'b: 's, 's>(&'a self, ids: &'b [$crate::Id])
                 -> std::pin::Pin<Box<dyn std::future::Future<Output=
@@ -74,6 +74,20 @@ macro_rules! delegate {
             {
                 self.$($t)*.vertex_id_batch(names)
             }
+            fn prefetch_names<'a: 's, 'b: 's, 's>(&'a self, names: &'b [$crate::VertexName])
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<()>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.prefetch_names(names)
+            }
+            fn prefetch_ids<'a: 's, 'b: 's, 's>(&'a self, ids: &'b [$crate::Id])
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<()>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.prefetch_ids(ids)
+            }
             fn map_id(&self) -> &str {
                 self.$($t)*.map_id()
             }
@@ -229,6 +243,13 @@ macro_rules! delegate {
             {
                 self.$($t)*.is_ancestor(ancestor, descendant)
             }
+            fn is_ancestor_batch<'a: 's, 'b: 's, 's>(&'a self, pairs: &'b [($crate::Vertex, $crate::Vertex)])
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<Vec<bool>>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.is_ancestor_batch(pairs)
+            }
             fn heads_ancestors<'a: 's, 's>(&'a self, set: $crate::Set)
                 -> std::pin::Pin<Box<dyn std::future::Future<Output=
                         $crate::Result<$crate::Set>
@@ -257,6 +278,13 @@ macro_rules! delegate {
             {
                 self.$($t)*.only_both(reachable, unreachable)
             }
+            fn ancestors_stream<'a: 's, 's>(&'a self, set: $crate::Set)
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<$crate::nameset::BoxVertexStream>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.ancestors_stream(set)
+            }
             fn descendants<'a: 's, 's>(&'a self, set: $crate::Set)
                 -> std::pin::Pin<Box<dyn std::future::Future<Output=
                         $crate::Result<$crate::Set>
@@ -264,6 +292,13 @@ macro_rules! delegate {
             {
                 self.$($t)*.descendants(set)
             }
+            fn descendants_within<'a: 's, 's>(&'a self, set: $crate::Set, depth: u64)
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<$crate::Set>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.descendants_within(set, depth)
+            }
             fn reachable_roots<'a: 's, 's>(&'a self, roots: $crate::Set, heads: $crate::Set)
                 -> std::pin::Pin<Box<dyn std::future::Future<Output=
                         $crate::Result<$crate::Set>
@@ -271,6 +306,13 @@ macro_rules! delegate {
             {
                 self.$($t)*.reachable_roots(roots, heads)
             }
+            fn iter_topo_between<'a: 's, 's>(&'a self, roots: $crate::Set, heads: $crate::Set)
+                -> std::pin::Pin<Box<dyn std::future::Future<Output=
+                        $crate::Result<Vec<$crate::render::TopoRow>>
+                    > + Send + 's>> where Self: 's
+            {
+                self.$($t)*.iter_topo_between(roots, heads)
+            }
             fn dirty<'a: 's, 's>(&'a self)
                 -> std::pin::Pin<Box<dyn std::future::Future<Output=
                         $crate::Result<$crate::Set>
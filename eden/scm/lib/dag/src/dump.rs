@@ -0,0 +1,391 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Dumps of the segment structure for debugging.
+//!
+//! A dump describes the segments, which ids have names in the local IdMap,
+//! and which ids are lazy (covered by segments but without a local name),
+//! for selected groups and levels. It can be written as JSON, or as DOT to
+//! be rendered by graphviz.
+//!
+//! Names are only read from the local IdMap, so dumping a lazy graph does
+//! not trigger remote fetches.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+use nonblocking::non_blocking_result;
+use serde::Serialize;
+use serde::Serializer;
+
+use crate::iddag::IdDag;
+use crate::iddagstore::IdDagStore;
+use crate::idmap::IdMapEntries;
+use crate::namedag::AbstractNameDag;
+use crate::ops::DagAlgorithm;
+use crate::ops::IdConvert;
+use crate::ops::TryClone;
+use crate::segment::SegmentFlags;
+use crate::Group;
+use crate::Id;
+use crate::IdSet;
+use crate::Level;
+use crate::Result;
+use crate::VertexName;
+
+/// What to include in a dump.
+#[derive(Clone, Debug)]
+pub struct DumpOptions {
+    /// Groups to dump.
+    pub groups: Vec<Group>,
+    /// Levels to dump. `None` dumps all levels.
+    pub levels: Option<Vec<Level>>,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            groups: Group::ALL.to_vec(),
+            levels: None,
+        }
+    }
+}
+
+/// Segments and IdMap coverage of a dag.
+#[derive(Clone, Debug, Serialize)]
+pub struct DagDump {
+    /// Whether names can be resolved remotely.
+    pub lazy: bool,
+    pub max_level: Level,
+    pub groups: Vec<GroupDump>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupDump {
+    #[serde(serialize_with = "serialize_display")]
+    pub group: Group,
+    #[serde(serialize_with = "serialize_display")]
+    pub next_free_id: Id,
+    pub levels: Vec<LevelDump>,
+    /// Ids covered by segments that have a name in the local IdMap.
+    pub named: Vec<SpanDump>,
+    /// Ids covered by segments that have no name in the local IdMap. In a
+    /// lazy graph, names of these ids are resolved remotely on demand.
+    /// Otherwise, the IdMap is missing them.
+    pub lazy: Vec<SpanDump>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LevelDump {
+    pub level: Level,
+    pub segments: Vec<SegmentDump>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SegmentDump {
+    #[serde(serialize_with = "serialize_display")]
+    pub low: Id,
+    #[serde(serialize_with = "serialize_display")]
+    pub high: Id,
+    /// Hex name of `low`, if it is known locally.
+    pub low_name: Option<String>,
+    /// Hex name of `high`, if it is known locally.
+    pub high_name: Option<String>,
+    #[serde(serialize_with = "serialize_display_seq")]
+    pub parents: Vec<Id>,
+    pub flags: Vec<&'static str>,
+    /// Whether some ids in the segment have no local name.
+    pub lazy: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SpanDump {
+    #[serde(serialize_with = "serialize_display")]
+    pub low: Id,
+    #[serde(serialize_with = "serialize_display")]
+    pub high: Id,
+}
+
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore,
+    IdDag<IS>: TryClone + 'static,
+    M: TryClone + IdConvert + IdMapEntries + Sync + Send + 'static,
+    P: TryClone + Sync + Send + 'static,
+    S: TryClone + Sync + Send + 'static,
+{
+    /// Describe the segments and IdMap coverage selected by `options`.
+    pub fn dump(&self, options: &DumpOptions) -> Result<DagDump> {
+        dump(&self.dag, &self.map, self.is_vertex_lazy(), options)
+    }
+}
+
+pub(crate) fn dump<S: IdDagStore>(
+    iddag: &IdDag<S>,
+    idmap: &dyn IdMapEntries,
+    lazy: bool,
+    options: &DumpOptions,
+) -> Result<DagDump> {
+    let max_level = iddag.max_level()?;
+    let levels: Vec<Level> = match &options.levels {
+        Some(levels) => levels
+            .iter()
+            .copied()
+            .filter(|&lv| lv <= max_level)
+            .collect(),
+        None => (0..=max_level).collect(),
+    };
+
+    let mut groups = Vec::with_capacity(options.groups.len());
+    for &group in &options.groups {
+        let covered = iddag.all_ids_in_groups(&[group])?;
+        let names: BTreeMap<Id, VertexName> = idmap
+            .local_entries_in_range(group.min_id(), group.max_id())?
+            .into_iter()
+            .collect();
+        let named = IdSet::from_spans(names.keys().copied()).intersection(&covered);
+        let unnamed = covered.difference(&named);
+        let hex_name = |id: Id| names.get(&id).map(|name| name.to_hex());
+
+        let mut level_dumps = Vec::with_capacity(levels.len());
+        for &level in &levels {
+            let mut segments = Vec::new();
+            for segment in iddag.next_segments(group.min_id(), level)? {
+                let span = segment.span()?;
+                segments.push(SegmentDump {
+                    low: span.low,
+                    high: span.high,
+                    low_name: hex_name(span.low),
+                    high_name: hex_name(span.high),
+                    parents: segment.parents()?,
+                    flags: flag_names(segment.flags()?),
+                    lazy: !unnamed.intersection(&IdSet::from(span)).is_empty(),
+                });
+            }
+            level_dumps.push(LevelDump { level, segments });
+        }
+
+        groups.push(GroupDump {
+            group,
+            next_free_id: iddag.next_free_id(0, group)?,
+            levels: level_dumps,
+            named: span_dumps(&named),
+            lazy: span_dumps(&unnamed),
+        });
+    }
+
+    Ok(DagDump {
+        lazy,
+        max_level,
+        groups,
+    })
+}
+
+impl DagDump {
+    /// Pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("DagDump should serialize to JSON")
+    }
+
+    /// A graphviz digraph with a cluster per group and level. Edges point
+    /// from segments to the segments containing their parents. Segments
+    /// with lazy ids are dashed.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String does not fail.
+        let _ = self.write_dot(&mut out);
+        out
+    }
+
+    fn write_dot(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "digraph dag {{")?;
+        writeln!(out, "  rankdir=BT;")?;
+        writeln!(out, "  node [shape=box];")?;
+        for group in &self.groups {
+            for level in &group.levels {
+                writeln!(
+                    out,
+                    "  subgraph \"cluster_{}_{}\" {{",
+                    group.group.0, level.level
+                )?;
+                writeln!(out, "    label=\"{}, Level {}\";", group.group, level.level)?;
+                for segment in &level.segments {
+                    let mut label = format!("{}..{}", segment.low, segment.high);
+                    if let (Some(low), Some(high)) = (&segment.low_name, &segment.high_name) {
+                        label += &format!("\\n{:.12}..{:.12}", low, high);
+                    }
+                    if !segment.flags.is_empty() {
+                        label += &format!("\\n{}", segment.flags.join(" "));
+                    }
+                    let style = if segment.lazy { ", style=dashed" } else { "" };
+                    writeln!(
+                        out,
+                        "    \"{}\" [label=\"{}\"{}];",
+                        node_name(level.level, segment.low),
+                        label,
+                        style
+                    )?;
+                }
+                writeln!(out, "  }}")?;
+            }
+        }
+        for group in &self.groups {
+            for level in &group.levels {
+                for segment in &level.segments {
+                    for &parent in &segment.parents {
+                        let target = match self.find_segment(level.level, parent) {
+                            Some(parent_segment) => node_name(level.level, parent_segment.low),
+                            None => {
+                                // The parent is in a group that was not dumped.
+                                let name = format!("{}", parent);
+                                writeln!(out, "  \"{}\" [shape=plaintext];", name)?;
+                                name
+                            }
+                        };
+                        writeln!(
+                            out,
+                            "  \"{}\" -> \"{}\";",
+                            node_name(level.level, segment.low),
+                            target
+                        )?;
+                    }
+                }
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn find_segment(&self, level: Level, id: Id) -> Option<&SegmentDump> {
+        self.groups
+            .iter()
+            .filter(|group| group.group == id.group())
+            .flat_map(|group| group.levels.iter())
+            .filter(|lv| lv.level == level)
+            .flat_map(|lv| lv.segments.iter())
+            .find(|segment| segment.low <= id && id <= segment.high)
+    }
+}
+
+fn node_name(level: Level, low: Id) -> String {
+    format!("L{}:{}", level, low)
+}
+
+fn flag_names(flags: SegmentFlags) -> Vec<&'static str> {
+    let mut result = Vec::new();
+    if flags.contains(SegmentFlags::HAS_ROOT) {
+        result.push("Root");
+    }
+    if flags.contains(SegmentFlags::ONLY_HEAD) {
+        result.push("OnlyHead");
+    }
+    result
+}
+
+fn span_dumps(set: &IdSet) -> Vec<SpanDump> {
+    set.as_spans()
+        .iter()
+        .rev()
+        .map(|span| SpanDump {
+            low: span.low,
+            high: span.high,
+        })
+        .collect()
+}
+
+fn serialize_display<T: fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_display_seq<T: fmt::Display, S: Serializer>(
+    values: &[T],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|value| value.to_string()))
+}
+
+/// Describe segments at the given level and group, one line per segment.
+pub(crate) fn debug_segments_by_level_group<S: IdDagStore>(
+    iddag: &IdDag<S>,
+    idmap: &dyn IdConvert,
+    level: Level,
+    group: Group,
+) -> Vec<String> {
+    let mut result = Vec::new();
+    // Show Id, with optional hash.
+    let show = |id: Id| DebugId {
+        id,
+        name: non_blocking_result(idmap.vertex_name(id)).ok(),
+    };
+
+    if let Ok(segments) = iddag.next_segments(group.min_id(), level) {
+        for segment in segments.into_iter().rev() {
+            if let (Ok(span), Ok(parents), Ok(flags)) =
+                (segment.span(), segment.parents(), segment.flags())
+            {
+                let mut line = format!(
+                    "{:.12?} : {:.12?} {:.12?}",
+                    show(span.low),
+                    show(span.high),
+                    parents.into_iter().map(show).collect::<Vec<_>>(),
+                );
+                let flags = flag_names(flags).join(" ");
+                if !flags.is_empty() {
+                    line += &format!(" {}", flags);
+                }
+                result.push(line);
+            }
+        }
+    }
+    result
+}
+
+pub(crate) fn debug<S: IdDagStore>(
+    iddag: &IdDag<S>,
+    idmap: &dyn IdConvert,
+    f: &mut fmt::Formatter,
+) -> fmt::Result {
+    if let Ok(max_level) = iddag.max_level() {
+        writeln!(f, "Max Level: {}", max_level)?;
+        for lv in (0..=max_level).rev() {
+            writeln!(f, " Level {}", lv)?;
+            for group in Group::ALL.iter().cloned() {
+                writeln!(f, "  {}:", group)?;
+                if let Ok(id) = iddag.next_free_id(0, group) {
+                    writeln!(f, "   Next Free Id: {}", id)?;
+                }
+                if let Ok(segments) = iddag.next_segments(group.min_id(), lv) {
+                    writeln!(f, "   Segments: {}", segments.len())?;
+                    for line in debug_segments_by_level_group(iddag, idmap, lv, group) {
+                        writeln!(f, "    {}", line)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct DebugId {
+    id: Id,
+    name: Option<VertexName>,
+}
+
+impl fmt::Debug for DebugId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            fmt::Debug::fmt(&name, f)?;
+            f.write_str("+")?;
+        }
+        write!(f, "{:?}", self.id)?;
+        Ok(())
+    }
+}
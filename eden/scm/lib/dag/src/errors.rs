@@ -9,6 +9,7 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::BudgetExceeded;
 use crate::Group;
 use crate::Id;
 use crate::VertexName;
@@ -44,6 +45,20 @@ pub enum DagError {
     /// No space for new Ids.
     #[error("out of space for group {0:?}")]
     IdOverflow(Group),
+
+    /// The operation went over its `QueryBudget`.
+    #[error(transparent)]
+    BudgetExceeded(BudgetExceeded),
+
+    /// Another writer held the write lease for too long.
+    #[cfg(any(test, feature = "indexedlog-backend"))]
+    #[error(transparent)]
+    LeaseTimeout(Box<crate::namedag::LeaseTimeout>),
+
+    /// The write lock was held for too long, by a writer without a lease.
+    #[cfg(any(test, feature = "indexedlog-backend"))]
+    #[error(transparent)]
+    LockTimeout(crate::namedag::LockTimeout),
 }
 
 #[derive(Debug, Error)]
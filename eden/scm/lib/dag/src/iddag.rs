@@ -5,6 +5,7 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::hash_map::Entry;
 use std::collections::BTreeSet;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
@@ -15,6 +16,7 @@ use std::fmt::{self};
 use std::ops::Deref;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 use std::path::Path;
+use std::sync::Arc;
 
 use indexmap::set::IndexSet;
 use serde::Deserialize;
@@ -26,6 +28,8 @@ use crate::errors::bug;
 use crate::errors::NotFoundError;
 use crate::id::Group;
 use crate::id::Id;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+use crate::iddagstore::indexedlog_store::ParentBloomStats;
 use crate::iddagstore::IdDagStore;
 use crate::iddagstore::InProcessStore;
 #[cfg(any(test, feature = "indexedlog-backend"))]
@@ -42,6 +46,7 @@ use crate::Error::Programming;
 use crate::IdSet;
 use crate::IdSpan;
 use crate::Level;
+use crate::QueryBudget;
 use crate::Result;
 use crate::VerLink;
 
@@ -66,6 +71,8 @@ pub struct IdDag<Store> {
     store: Store,
     #[serde(skip, default = "default_seg_size")]
     new_seg_size: usize,
+    #[serde(skip)]
+    incremental_high_level: bool,
     #[serde(skip, default = "VerLink::new")]
     version: VerLink,
 }
@@ -85,6 +92,19 @@ impl IdDag<IndexedLogStore> {
         let store = IndexedLogStore::open(path)?;
         Self::open_from_store(store)
     }
+
+    /// Open [`IdDag`] with a bloom filter over parents, to speed up
+    /// `children` of vertexes without children. See
+    /// [`IndexedLogStore::log_open_options_with_parent_bloom`].
+    pub fn open_with_parent_bloom(path: impl AsRef<Path>) -> Result<Self> {
+        let store = IndexedLogStore::open_with_parent_bloom(path)?;
+        Self::open_from_store(store)
+    }
+
+    /// Statistics of the parent bloom filter, if the store has one.
+    pub fn parent_bloom_stats(&self) -> Option<ParentBloomStats> {
+        self.store.parent_bloom_stats()
+    }
 }
 
 impl<S> IdDag<S> {
@@ -102,6 +122,25 @@ impl<S> IdDag<S> {
     pub(crate) fn get_new_segment_size(&self) -> usize {
         self.new_seg_size
     }
+
+    /// Set whether high-level segments are built incrementally.
+    ///
+    /// By default, only the last high-level segment per level per group is
+    /// left unbuilt. Other trailing segments that are smaller than they
+    /// could be, because there were not enough lower level segments, are
+    /// kept as-is and cannot grow later. With the incremental mode, those
+    /// segments are left unbuilt too, so they are built together with new
+    /// flat segments on the next flush.
+    ///
+    /// This does not affect existing segments. Use `defragment` for them.
+    pub fn set_incremental_high_level_segments(&mut self, incremental: bool) {
+        self.incremental_high_level = incremental;
+    }
+
+    /// Get whether high-level segments are built incrementally.
+    pub(crate) fn get_incremental_high_level_segments(&self) -> bool {
+        self.incremental_high_level
+    }
 }
 
 #[cfg(any(test, feature = "indexedlog-backend"))]
@@ -112,6 +151,7 @@ impl TryClone for IdDag<IndexedLogStore> {
         Ok(Self {
             store,
             new_seg_size: self.new_seg_size,
+            incremental_high_level: self.incremental_high_level,
             version: self.version.clone(),
         })
     }
@@ -125,6 +165,7 @@ impl IdDag<InProcessStore> {
         Self {
             store,
             new_seg_size: DEFAULT_SEG_SIZE,
+            incremental_high_level: false,
             version: VerLink::new(),
         }
     }
@@ -135,6 +176,7 @@ impl<Store: IdDagStore> IdDag<Store> {
         let dag = Self {
             store,
             new_seg_size: DEFAULT_SEG_SIZE, // see D16660078 for this default setting
+            incremental_high_level: false,
             version: VerLink::new(),
         };
         Ok(dag)
@@ -169,6 +211,98 @@ impl<Store: IdDagStore> IdDag<Store> {
     pub(crate) fn version(&self) -> &VerLink {
         &self.version
     }
+
+    /// Charge segments read by queries to `budget`. Queries fail with
+    /// `BudgetExceeded` once it runs out. `None` removes the limit.
+    pub fn set_query_budget(&mut self, budget: Option<Arc<QueryBudget>>) {
+        self.store.set_query_budget(budget);
+    }
+}
+
+/// Find the flat segments covering `low..=high`, fetching parents with
+/// `get_parents_batch`. Each batch is split into runs of ids whose parent is
+/// the previous id, in parallel. A run starting a batch is then merged with
+/// the last run of the previous batch if it continues it, so the result is
+/// the same as walking the ids one by one.
+fn prepare_flat_segments_parallel<F>(
+    low: Id,
+    high: Id,
+    get_parents_batch: &F,
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<PreparedFlatSegments>
+where
+    F: Fn(&[Id]) -> Result<Vec<Vec<Id>>> + Sync,
+{
+    let batch_size = batch_size.max(1) as u64;
+    let concurrency = concurrency.max(1);
+    let batches: Vec<(Id, Id)> = if low > high {
+        Vec::new()
+    } else {
+        (0..=(high.0 - low.0) / batch_size)
+            .map(|i| {
+                let batch_low = low + i * batch_size;
+                let batch_high = (batch_low + (batch_size - 1)).min(high);
+                (batch_low, batch_high)
+            })
+            .collect()
+    };
+
+    let find_runs = |(batch_low, batch_high): (Id, Id)| -> Result<Vec<FlatSegment>> {
+        let ids: Vec<Id> = batch_low.to(batch_high).collect();
+        let parents = get_parents_batch(&ids)?;
+        if parents.len() != ids.len() {
+            let msg = format!(
+                "get_parents_batch returned {} results for {} ids",
+                parents.len(),
+                ids.len()
+            );
+            return bug(msg);
+        }
+        let mut runs: Vec<FlatSegment> = Vec::new();
+        for (id, parents) in ids.into_iter().zip(parents) {
+            match runs.last_mut() {
+                Some(run) if parents.len() == 1 && parents[0] + 1 == id => run.high = id,
+                _ => runs.push(FlatSegment {
+                    low: id,
+                    high: id,
+                    parents,
+                }),
+            }
+        }
+        Ok(runs)
+    };
+
+    let mut segments: Vec<FlatSegment> = Vec::new();
+    for chunk in batches.chunks(concurrency) {
+        let chunk_runs: Vec<Result<Vec<FlatSegment>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|&batch| scope.spawn(move || find_runs(batch)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("flat segment builder panicked"))
+                .collect()
+        });
+        for runs in chunk_runs {
+            let mut runs = runs?.into_iter();
+            if let Some(first) = runs.next() {
+                match segments.last_mut() {
+                    Some(last)
+                        if first.parents.len() == 1
+                            && first.parents[0] == last.high
+                            && last.high + 1 == first.low =>
+                    {
+                        last.high = first.high
+                    }
+                    _ => segments.push(first),
+                }
+            }
+            segments.extend(runs);
+        }
+    }
+    Ok(PreparedFlatSegments { segments })
 }
 
 // Build segments.
@@ -203,6 +337,29 @@ impl<Store: IdDagStore> IdDag<Store> {
         Ok(count)
     }
 
+    /// Similar to `build_segments_volatile`, but `get_parents_batch` takes
+    /// `batch_size` ids at a time, and returns their parents in the same
+    /// order. Up to `concurrency` batches are fetched and turned into flat
+    /// segments in parallel, then the segments are inserted in order.
+    ///
+    /// This is intended for the initial import of large graphs, where
+    /// fetching parents one id at a time is the bottleneck.
+    pub fn build_segments_volatile_parallel<F>(
+        &mut self,
+        high: Id,
+        get_parents_batch: &F,
+        batch_size: usize,
+        concurrency: usize,
+    ) -> Result<usize>
+    where
+        F: Fn(&[Id]) -> Result<Vec<Vec<Id>>> + Sync,
+    {
+        let low = self.next_free_id(0, high.group())?;
+        let outcome =
+            prepare_flat_segments_parallel(low, high, get_parents_batch, batch_size, concurrency)?;
+        self.build_segments_volatile_from_prepared_flat_segments(&outcome)
+    }
+
     /// Build flat segments using the outcome from `add_head`.
     /// This is not public because it does not keep high-level segments in sync.
     fn build_flat_segments_from_prepared_flat_segments(
@@ -380,9 +537,11 @@ impl<Store: IdDagStore> IdDag<Store> {
 
                 // find_segment scans low level segments (segments[low_idx..]),
                 // merges them on the fly, and returns a high-level segment:
-                // (new_idx, low, high, parents, has_root).
+                // (new_idx, low, high, parents, has_root, complete).
                 // new_idx + 1 is the next low_idx that should be passed to find_segment
                 // to calculate the next high-level segment.
+                // complete is false if more lower level segments could extend
+                // the high-level segment.
                 let find_segment = |low_idx: usize| -> Result<_> {
                     let segment_low = segments[low_idx].span()?.low;
                     let mut heads = BTreeSet::new();
@@ -425,7 +584,8 @@ impl<Store: IdDagStore> IdDag<Store> {
                     // because `segments[low_idx]` is such a high-level segment.
                     let (new_idx, low, high, parent_count, has_root) = candidate.unwrap();
                     let parents = parents.into_iter().take(parent_count).collect::<Vec<Id>>();
-                    Ok((new_idx, low, high, parents, has_root))
+                    let complete = low_idx + size <= segments.len();
+                    Ok((new_idx, low, high, parents, has_root, complete))
                 };
 
                 let mut idx = 0;
@@ -457,9 +617,17 @@ impl<Store: IdDagStore> IdDag<Store> {
             // Drop the last segment. It could be incomplete.
             new_segments.pop();
 
+            if self.incremental_high_level {
+                // Drop other incomplete segments. They will be rebuilt with
+                // more lower level segments.
+                while let Some(&(.., false)) = new_segments.last() {
+                    new_segments.pop();
+                }
+            }
+
             insert_count += new_segments.len();
 
-            for (_, low, high, parents, has_root) in new_segments {
+            for (_, low, high, parents, has_root, _) in new_segments {
                 let flags = if has_root {
                     SegmentFlags::HAS_ROOT
                 } else {
@@ -546,6 +714,38 @@ impl<Store: IdDagStore> IdDag<Store> {
 
         Ok(PreparedFlatSegments { segments })
     }
+
+    /// Like [`IdDag::idset_to_flat_segments`], but keeps the flat segments
+    /// covering each span of `set` apart, so they can be attributed to the
+    /// span they came from. Spans, and segments of each span, are in
+    /// ascending order.
+    pub fn idset_to_flat_segments_per_span(
+        &self,
+        set: IdSet,
+    ) -> Result<Vec<(IdSpan, Vec<FlatSegment>)>> {
+        let (min, max) = if let (Some(min), Some(max)) = (set.min(), set.max()) {
+            (min, max)
+        } else {
+            return Ok(Vec::new());
+        };
+        let segs = self.flat_segments_range(min, max)?;
+
+        let mut result = Vec::with_capacity(set.as_spans().len());
+        for span in set.as_spans().iter().rev() {
+            // `segs` is sorted and non-overlapping, so the segments overlapping
+            // `span` are a contiguous range of it.
+            let start = segs.partition_point(|seg| seg.high < span.low);
+            let end = segs.partition_point(|seg| seg.low <= span.high);
+            let mut segments = Vec::new();
+            let push = |seg: FlatSegment| segments.push(seg);
+            let seg_iter = segs[start..end].iter().cloned().rev();
+            spanset::intersect_iter(seg_iter, std::iter::once(*span), push);
+            segments.reverse();
+            result.push((*span, segments));
+        }
+
+        Ok(result)
+    }
 }
 
 // User-facing DAG-related algorithms.
@@ -1237,6 +1437,62 @@ pub trait IdDagAlgorithm: IdDagStore {
         self.heads_ancestors(self.common_ancestors(set)?)
     }
 
+    /// Calculate how many first parents need to be followed from
+    /// `descendant_id` to reach `ancestor_id`.
+    ///
+    /// Return `None` if `ancestor_id` is not a first ancestor of
+    /// `descendant_id`.
+    fn first_parent_distance(&self, ancestor_id: Id, descendant_id: Id) -> Result<Option<u64>> {
+        let mut id = descendant_id;
+        let mut distance = 0;
+        while id >= ancestor_id {
+            let seg = self
+                .find_flat_segment_including_id(id)?
+                .ok_or_else(|| id.not_found_error())?;
+            let low = seg.span()?.low;
+            if low <= ancestor_id {
+                return Ok(Some(distance + id.0 - ancestor_id.0));
+            }
+            distance += id.0 - low.0 + 1;
+            id = match seg.parents()?.first() {
+                None => break,
+                Some(&id) => id,
+            };
+        }
+        Ok(None)
+    }
+
+    /// Calculate one path from `descendant_id` to `ancestor_id`, including
+    /// both ends. First parents are followed where possible.
+    ///
+    /// Return an empty set if `ancestor_id` is not an ancestor of
+    /// `descendant_id`.
+    fn path(&self, ancestor_id: Id, descendant_id: Id) -> Result<IdSet> {
+        let range = self.range(ancestor_id.into(), descendant_id.into())?;
+        if !range.contains(descendant_id) {
+            return Ok(IdSet::empty());
+        }
+        let mut spans = Vec::new();
+        let mut id = descendant_id;
+        loop {
+            let seg = self
+                .find_flat_segment_including_id(id)?
+                .ok_or_else(|| id.not_found_error())?;
+            let low = seg.span()?.low;
+            if low <= ancestor_id {
+                spans.push(IdSpan::from(ancestor_id..=id));
+                break;
+            }
+            spans.push(IdSpan::from(low..=id));
+            // Some parent is in the range, since `low` is.
+            id = match seg.parents()?.into_iter().find(|&p| range.contains(p)) {
+                None => return bug(format!("{} has no parents in range {:?}", low, &range)),
+                Some(id) => id,
+            };
+        }
+        Ok(IdSet::from_spans(spans))
+    }
+
     /// Calculate all common ancestors of the given set.
     ///
     /// ```plain,ignore
@@ -1273,6 +1529,27 @@ pub trait IdDagAlgorithm: IdDagStore {
         Ok(set.contains(ancestor_id))
     }
 
+    /// Test `is_ancestor` for each `(ancestor_id, descendant_id)` pair.
+    /// The ancestors of a descendant are calculated once, however many
+    /// pairs share it, and not at all if a pair can be answered without them.
+    fn is_ancestor_batch(&self, pairs: &[(Id, Id)]) -> Result<Vec<bool>> {
+        let mut ancestors_of: HashMap<Id, IdSet> = HashMap::new();
+        let mut result = Vec::with_capacity(pairs.len());
+        for &(ancestor_id, descendant_id) in pairs {
+            // Parents have smaller ids than their children.
+            if ancestor_id > descendant_id {
+                result.push(false);
+                continue;
+            }
+            let ancestors = match ancestors_of.entry(descendant_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.ancestors(descendant_id.into())?),
+            };
+            result.push(ancestors.contains(ancestor_id));
+        }
+        Ok(result)
+    }
+
     /// Calculate "heads" of the ancestors of the given [`IdSet`]. That is,
     /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
     /// `ancestors(X)`.
@@ -1293,6 +1570,50 @@ pub trait IdDagAlgorithm: IdDagStore {
         Ok(result)
     }
 
+    /// Like `heads_ancestors`, but also count the ancestors each head
+    /// contributes, in the same traversal.
+    ///
+    /// Heads are in descending id order. An ancestor shared by several
+    /// heads is only counted for the first of them, so the counts add up to
+    /// the size of `ancestors(set)`.
+    fn heads_ancestors_with_counts(&self, set: IdSet) -> Result<Vec<(Id, u64)>> {
+        let mut remaining = set;
+        let mut counted = IdSet::empty();
+        let mut result = Vec::new();
+        while let Some(id) = remaining.max() {
+            let ancestors = self.ancestors(id.into())?;
+            result.push((id, ancestors.difference(&counted).count()));
+            remaining = remaining.difference(&ancestors);
+            counted = counted.union(&ancestors);
+        }
+        Ok(result)
+    }
+
+    /// Calculate the heads of `ancestors(heads) - (descendants(hidden) -
+    /// ancestors(heads - hidden))`. That is, the heads that remain after
+    /// removing `hidden` and their descendants, except those that are still
+    /// reachable from heads not in `hidden`.
+    ///
+    /// This is faster than calculating the sets separately, as descendants
+    /// of `hidden` are only calculated within `ancestors(heads)`.
+    ///
+    /// This is O(flat segments), or O(merges).
+    fn visible_heads(&self, heads: IdSet, hidden: IdSet) -> Result<IdSet> {
+        debug!(
+            target: "dag::algo::visible_heads",
+            "visible_heads({:?}, {:?})", &heads, &hidden
+        );
+        let ancestors = self.ancestors(heads.clone())?;
+        let visible = self.ancestors(heads.difference(&hidden))?;
+        let hidden = self
+            .descendants_intersection(&hidden, &ancestors)?
+            .difference(&visible);
+        let visible = ancestors.difference(&hidden);
+        let result = self.heads(visible)?;
+        trace!(target: "dag::algo::visible_heads", " result: {:?}", &result);
+        Ok(result)
+    }
+
     /// Calculate the "dag range" - ids reachable from both sides.
     ///
     /// ```plain,ignore
@@ -1335,6 +1656,41 @@ pub trait IdDagAlgorithm: IdDagStore {
         Ok(result)
     }
 
+    /// Like `range`, but stop once more than `max_count` ids are found.
+    ///
+    /// Return the range, and whether it was truncated. A truncated range
+    /// contains the `max_count` smallest ids of the full range.
+    fn range_limited(
+        &self,
+        roots: IdSet,
+        mut heads: IdSet,
+        max_count: u64,
+    ) -> Result<(IdSet, bool)> {
+        if roots.is_empty() || heads.is_empty() {
+            return Ok((IdSet::empty(), false));
+        }
+        debug!(
+            target: "dag::algo::range_limited",
+            "range_limited({:?}, {:?}, {})", &roots, &heads, max_count
+        );
+
+        // Remove uninteresting heads, like `range`.
+        let min_root_id = roots.min().unwrap();
+        if heads.min().unwrap() < min_root_id {
+            let span = min_root_id..=Id::MAX;
+            heads = heads.intersection(&span.into());
+        }
+
+        let ancestors_of_heads = self.ancestors(heads)?;
+        let (result, truncated) =
+            self.descendants_intersection_limited(&roots, &ancestors_of_heads, Some(max_count))?;
+        trace!(
+            target: "dag::algo::range_limited",
+            " result: {:?} (truncated: {})", &result, truncated
+        );
+        Ok((result, truncated))
+    }
+
     /// Calculate the descendants of the given set.
     ///
     /// Logically equivalent to `range(set, all())`.
@@ -1348,12 +1704,50 @@ pub trait IdDagAlgorithm: IdDagStore {
         Ok(result)
     }
 
+    /// Calculate descendants of `set` that are at most `depth` generations
+    /// away from it. `depth = 0` returns `set`.
+    ///
+    /// This is O(depth * children), so it is cheaper than `descendants` if
+    /// `depth` is small and `set` has lots of descendants.
+    fn descendants_within(&self, set: IdSet, depth: u64) -> Result<IdSet> {
+        debug!(
+            target: "dag::algo::descendants_within",
+            "descendants_within({:?}, {})", &set, depth
+        );
+        let mut result = set.clone();
+        let mut frontier = set;
+        for _ in 0..depth {
+            frontier = self.children(frontier)?.difference(&result);
+            if frontier.is_empty() {
+                break;
+            }
+            result = result.union(&frontier);
+        }
+        trace!(target: "dag::algo::descendants_within", " result: {:?}", &result);
+        Ok(result)
+    }
+
     /// Calculate (descendants(roots) & ancestors).
     ///
     /// This is O(flat segments), or O(merges).
     ///
     /// `ancestors(ancestors)` must be equal to `ancestors`.
     fn descendants_intersection(&self, roots: &IdSet, ancestors: &IdSet) -> Result<IdSet> {
+        let (result, _truncated) = self.descendants_intersection_limited(roots, ancestors, None)?;
+        Ok(result)
+    }
+
+    /// Calculate (descendants(roots) & ancestors), stopping once more than
+    /// `limit` ids are found.
+    ///
+    /// Return the result, and whether it was truncated. A truncated result
+    /// contains the `limit` smallest ids.
+    fn descendants_intersection_limited(
+        &self,
+        roots: &IdSet,
+        ancestors: &IdSet,
+        limit: Option<u64>,
+    ) -> Result<(IdSet, bool)> {
         fn trace(msg: &dyn Fn() -> String) {
             trace!(target: "dag::algo::descendants_intersection", "{}", msg());
         }
@@ -1367,7 +1761,7 @@ pub trait IdDagAlgorithm: IdDagStore {
         let roots = ancestors.intersection(roots);
         let min_root = match roots.min() {
             Some(id) => id,
-            None => return Ok(IdSet::empty()),
+            None => return Ok((IdSet::empty(), false)),
         };
         let max_root = roots.max().unwrap();
 
@@ -1375,6 +1769,14 @@ pub trait IdDagAlgorithm: IdDagStore {
         // (cannot use `result.push_span_asc` below).
         let mut result = IdSet::empty();
 
+        // Spans are pushed without intersecting with `ancestors`. So only
+        // calculate the intersection if the pushed spans exceed the limit.
+        let exceeds_limit = |result: &IdSet| match limit {
+            Some(limit) => result.count() > limit && result.intersection(ancestors).count() > limit,
+            None => false,
+        };
+        let mut truncated = false;
+
         // For the master group, use linear scan for flat segments. This is
         // usually more efficient, because the master group usually only has 1
         // head, and most segments will be included.
@@ -1383,6 +1785,10 @@ pub trait IdDagAlgorithm: IdDagStore {
             .unwrap_or(Id::MIN)
             .min(Group::MASTER.max_id());
         for seg in self.iter_segments_ascending(min_root, 0)? {
+            if exceeds_limit(&result) {
+                truncated = true;
+                break;
+            }
             let seg = seg?;
             let span = seg.span()?;
             if span.low > master_max_id {
@@ -1416,7 +1822,7 @@ pub trait IdDagAlgorithm: IdDagStore {
             result.push_span_asc(result_span);
         }
 
-        // For the non-master group, only check flat segments covered by
+        // For the non-master groups, only check flat segments covered by
         // `ancestors`.
         //
         // This is usually more efficient, because the non-master group can
@@ -1424,13 +1830,16 @@ pub trait IdDagAlgorithm: IdDagStore {
         // or interesting. For a typical query like `x::y`, it might just select
         // a few heads in the non-master group. It's a waste of time to iterate
         // through lots of invisible segments.
-        let non_master_spans = ancestors.intersection(
-            &IdSpan::from(Group::NON_MASTER.min_id()..=Group::NON_MASTER.max_id()).into(),
-        );
+        let non_master_spans = ancestors
+            .intersection(&IdSpan::from(Group::NON_MASTER.min_id()..=Group::MAX.max_id()).into());
         // Visit in ascending order.
         let mut span_iter = non_master_spans.as_spans().iter().rev().cloned();
         let mut next_optional_span = span_iter.next();
         while let Some(next_span) = next_optional_span {
+            if exceeds_limit(&result) {
+                truncated = true;
+                break;
+            }
             // The "next_span" could be larger than a flat segment.
             let seg = match self.find_flat_segment_including_id(next_span.low)? {
                 Some(seg) => seg,
@@ -1501,7 +1910,17 @@ pub trait IdDagAlgorithm: IdDagStore {
         trace(&|| format!(" intersect with {:?}", &ancestors));
         result = result.intersection(ancestors);
 
-        Ok(result)
+        if let Some(limit) = limit {
+            let count = result.count();
+            if count > limit {
+                truncated = true;
+                // Keep the smallest ids.
+                result = result.skip(count - limit);
+                trace(&|| format!(" truncated to {:?}", &result));
+            }
+        }
+
+        Ok((result, truncated))
     }
 }
 
@@ -1587,12 +2006,13 @@ impl<Store: IdDagStore> IdDag<Store> {
     /// id re-assignment.
     pub fn non_master_parent_ids(&self) -> Result<HashMap<Id, Vec<Id>>> {
         let mut parents = HashMap::new();
-        let start = Group::NON_MASTER.min_id();
-        for seg in self.next_segments(start, 0)? {
-            let span = seg.span()?;
-            parents.insert(span.low, seg.parents()?);
-            for i in (span.low + 1).to(span.high) {
-                parents.insert(i, vec![i - 1]);
+        for group in Group::non_master() {
+            for seg in self.next_segments(group.min_id(), 0)? {
+                let span = seg.span()?;
+                parents.insert(span.low, seg.parents()?);
+                for i in (span.low + 1).to(span.high) {
+                    parents.insert(i, vec![i - 1]);
+                }
             }
         }
         Ok(parents)
@@ -1604,6 +2024,142 @@ impl<Store: IdDagStore> IdDag<Store> {
         self.version = VerLink::new();
         self.store.remove_non_master()
     }
+
+    /// Rebuild all high-level segments from flat segments.
+    ///
+    /// High-level segments built by many small flushes can be fragmented.
+    /// This rebuilds them as if all flat segments were inserted at once.
+    /// The graph is unchanged.
+    ///
+    /// Return number of high-level segments inserted.
+    pub fn defragment(&mut self) -> Result<usize> {
+        self.store.remove_high_level_segments()?;
+        let count = self.build_all_high_level_segments(Level::MAX)?;
+        tracing::debug!("defragmented high-level segments: {} inserted", count);
+        Ok(count)
+    }
+
+    /// Merge trailing flat segments of the master group that continue each
+    /// other, examining at most `max_segments` of them.
+    ///
+    /// A flat segment continues the previous one if its ids follow and its
+    /// only parent is the previous segment's head. Such segments are merged
+    /// when inserted, but stores written before that can have many small
+    /// ones at the end of the master group, which slow down queries.
+    ///
+    /// If segments are merged, high-level segments overlapping the merged
+    /// segments are rebuilt. The graph is unchanged.
+    ///
+    /// Return number of flat segments removed by merging.
+    pub fn compact_master_tail(&mut self, max_segments: usize) -> Result<usize> {
+        let next_id = self.next_free_id(0, Group::MASTER)?;
+        if next_id == Group::MASTER.min_id() {
+            return Ok(0);
+        }
+        let mut tail = Vec::new();
+        for seg in self.store.iter_segments_descending(next_id - 1, 0)? {
+            if tail.len() >= max_segments {
+                break;
+            }
+            tail.push(seg?);
+        }
+        tail.reverse();
+
+        let mut merge_count = 0;
+        for pair in tail.windows(2) {
+            let (last, seg) = (&pair[0], &pair[1]);
+            let last_high = last.high()?;
+            if !seg.has_root()? && seg.span()?.low == last_high + 1 && seg.parents()? == [last_high]
+            {
+                merge_count += 1;
+            }
+        }
+        if merge_count == 0 {
+            return Ok(0);
+        }
+        tracing::debug!(
+            "compact master tail: merging {} of {} flat segments",
+            merge_count,
+            tail.len()
+        );
+
+        // Ids and the graph are unchanged. Existing sets are still valid.
+        self.version.bump();
+        // High-level segments ending before the first segment of the tail
+        // do not refer to its heads. Others are removed, highest level first.
+        let low = tail[0].span()?.low;
+        for level in (1..=self.max_level()?).rev() {
+            let mut segments = Vec::new();
+            for seg in self.store.iter_segments_ascending(low, level)? {
+                let seg = seg?;
+                if seg.high()?.group() != Group::MASTER {
+                    break;
+                }
+                segments.push(seg);
+            }
+            for seg in segments {
+                self.store.remove_high_level_segment(&seg)?;
+            }
+        }
+        // The first segment is kept. The others are removed, and inserted
+        // again in ascending order, which merges them.
+        for seg in tail[1..].iter().rev() {
+            self.store.remove_flat_segment(seg)?;
+        }
+        for seg in tail.into_iter().skip(1) {
+            self.store.insert_segment(seg)?;
+        }
+        self.build_all_high_level_segments(Level::MAX)?;
+        Ok(merge_count)
+    }
+
+    /// Remove `set` and their descendants from the DAG.
+    ///
+    /// Flat segments covering removed ids are truncated or removed.
+    /// High-level segments are rebuilt.
+    ///
+    /// Return the removed ids.
+    pub fn strip(&mut self, set: IdSet) -> Result<IdSet> {
+        let to_remove = self.descendants(set)?;
+        if to_remove.is_empty() {
+            return Ok(to_remove);
+        }
+        tracing::debug!("strip: {:?}", &to_remove);
+
+        // Find flat segments to remove. Since descendants are removed too,
+        // if a segment has a removed id, then its `high` is also removed.
+        let mut removed_segments = Vec::new();
+        let mut truncated_segments = Vec::new();
+        for span in to_remove.as_spans() {
+            for seg in self.store.iter_segments_descending(span.high, 0)? {
+                let seg = seg?;
+                let seg_span = seg.span()?;
+                if seg_span.high < span.low {
+                    break;
+                }
+                if seg_span.low < span.low {
+                    let truncated =
+                        Segment::new(seg.flags()?, 0, seg_span.low, span.low - 1, &seg.parents()?);
+                    truncated_segments.push(truncated);
+                }
+                removed_segments.push(seg);
+            }
+        }
+
+        // Non-append-only change. Use a new incompatible version.
+        self.version = VerLink::new();
+        self.store.remove_high_level_segments()?;
+        for seg in removed_segments {
+            self.store.remove_flat_segment(&seg)?;
+        }
+        // Insert in ascending order, so they can be merged.
+        for seg in truncated_segments.into_iter().rev() {
+            self.store.insert_segment(seg)?;
+        }
+        self.build_all_high_level_segments(Level::MAX)?;
+
+        Ok(to_remove)
+    }
 }
 
 impl<Store: Persist> Persist for IdDag<Store> {
@@ -1763,6 +2319,81 @@ mod tests {
         assert_eq!(low_by_id(151), -1);
     }
 
+    #[test]
+    fn test_compact_master_tail() {
+        // Flat segments inserted in descending order are not merged.
+        fn insert_fragmented(dag: &mut IdDag<impl IdDagStore>, covered: bool) {
+            let flags = SegmentFlags::ONLY_HEAD;
+            dag.insert(flags, 0, Id(50), Id(59), &[Id(49)]).unwrap();
+            dag.insert(flags, 0, Id(40), Id(49), &[Id(39), Id(5)])
+                .unwrap();
+            for low in [30, 20, 10] {
+                dag.insert(flags, 0, Id(low), Id(low + 9), &[Id(low - 1)])
+                    .unwrap();
+            }
+            let root = flags | SegmentFlags::HAS_ROOT;
+            dag.insert(root, 0, Id(0), Id(9), &[]).unwrap();
+            if covered {
+                dag.insert(root, 1, Id(0), Id(19), &[]).unwrap();
+            }
+        }
+        let compacted = "Lv0: RH0-39[] H40-59[39, 5]";
+
+        let mut dag = IdDag::new_in_process();
+        insert_fragmented(&mut dag, false);
+        let ancestors = dag.ancestors(Id(59).into()).unwrap();
+        let version = dag.version().clone();
+        assert_eq!(dag.compact_master_tail(usize::MAX).unwrap(), 4);
+        assert_eq!(format!("{:?}", &dag), compacted);
+        assert_eq!(
+            dag.ancestors(Id(59).into()).unwrap().as_spans(),
+            ancestors.as_spans()
+        );
+        assert_eq!(
+            format!("{:?}", dag.ancestors(Id(25).into()).unwrap()),
+            "0..=25"
+        );
+        assert!(&version < dag.version());
+        assert_eq!(dag.compact_master_tail(usize::MAX).unwrap(), 0);
+
+        // The on-disk store merges them the same way.
+        let dir = tempdir().unwrap();
+        let mut dag = IdDag::open(dir.path()).unwrap();
+        insert_fragmented(&mut dag, false);
+        assert_eq!(dag.compact_master_tail(usize::MAX).unwrap(), 4);
+        assert_eq!(format!("{:?}", &dag), compacted);
+        assert_eq!(format!("{:?}", dag.children(Id(39).into()).unwrap()), "40");
+
+        // At most `max_segments` trailing segments are examined.
+        let mut dag = IdDag::new_in_process();
+        insert_fragmented(&mut dag, false);
+        assert_eq!(dag.compact_master_tail(2).unwrap(), 1);
+        assert_eq!(
+            format!("{:?}", &dag),
+            "Lv0: RH0-9[] H10-19[9] H20-29[19] H30-39[29] H40-59[39, 5]"
+        );
+
+        // High-level segments are rebuilt. The one ending in the middle of a
+        // merged segment is gone.
+        let mut dag = IdDag::new_in_process();
+        insert_fragmented(&mut dag, true);
+        assert_eq!(dag.max_level().unwrap(), 1);
+        assert_eq!(dag.compact_master_tail(usize::MAX).unwrap(), 4);
+        assert_eq!(format!("{:?}", &dag), compacted);
+
+        // High-level segments before the merged segments are kept.
+        let dir = tempdir().unwrap();
+        let mut dag = IdDag::open(dir.path()).unwrap();
+        insert_fragmented(&mut dag, true);
+        dag.insert(SegmentFlags::ONLY_HEAD, 1, Id(20), Id(39), &[Id(19)])
+            .unwrap();
+        assert_eq!(dag.compact_master_tail(4).unwrap(), 2);
+        assert_eq!(
+            format!("{:?}", &dag),
+            "Lv0: RH0-9[] H10-19[9] H20-39[19] H40-59[39, 5]\nLv1: RH0-19[]"
+        );
+    }
+
     fn get_parents(id: Id) -> Result<Vec<Id>> {
         match id.0 {
             0 | 1 | 2 => Ok(Vec::new()),
@@ -1828,5 +2459,71 @@ mod tests {
             .idset_to_flat_segments(IdSet::from_spans(vec![2..=4]))
             .unwrap();
         assert_eq!(subset_flat_segments.segments.len(), 3);
+
+        let set = IdSet::from_spans(vec![2..=4, 10..=30, 500..=600]);
+        let per_span = dag.idset_to_flat_segments_per_span(set.clone()).unwrap();
+        assert_eq!(
+            per_span.iter().map(|(span, _)| *span).collect::<Vec<_>>(),
+            set.as_spans().iter().rev().cloned().collect::<Vec<_>>()
+        );
+        for (span, segments) in &per_span {
+            assert_eq!(segments.first().unwrap().low, span.low);
+            assert_eq!(segments.last().unwrap().high, span.high);
+        }
+        let flattened: Vec<_> = per_span
+            .into_iter()
+            .flat_map(|(_, segments)| segments)
+            .collect();
+        assert_eq!(flattened, dag.idset_to_flat_segments(set).unwrap().segments);
+        let empty = dag.idset_to_flat_segments_per_span(IdSet::empty());
+        assert!(empty.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_build_segments_volatile_parallel() {
+        // Linear runs with a merge every 7 ids.
+        let get_parents = |id: Id| -> Result<Vec<Id>> {
+            match id.0 {
+                0 => Ok(Vec::new()),
+                n if n % 7 == 0 => Ok(vec![id - 1, Id(n / 2)]),
+                _ => Ok(vec![id - 1]),
+            }
+        };
+        let get_parents_batch = |ids: &[Id]| -> Result<Vec<Vec<Id>>> {
+            ids.iter().map(|&id| get_parents(id)).collect()
+        };
+
+        let mut dag = IdDag::new_in_process();
+        dag.build_segments_volatile(Id(1001), &get_parents).unwrap();
+        let expected = dag.flat_segments(Group::MASTER).unwrap();
+
+        for &(batch_size, concurrency) in &[(1, 1), (5, 3), (7, 4), (64, 2), (2000, 8)] {
+            let mut parallel_dag = IdDag::new_in_process();
+            parallel_dag
+                .build_segments_volatile_parallel(
+                    Id(1001),
+                    &get_parents_batch,
+                    batch_size,
+                    concurrency,
+                )
+                .unwrap();
+            let flat_segments = parallel_dag.flat_segments(Group::MASTER).unwrap();
+            assert_eq!(flat_segments, expected);
+
+            // Incremental builds continue from the next free id.
+            let mut incremental_dag = IdDag::new_in_process();
+            for &high in &[Id(500), Id(1001)] {
+                incremental_dag
+                    .build_segments_volatile_parallel(
+                        high,
+                        &get_parents_batch,
+                        batch_size,
+                        concurrency,
+                    )
+                    .unwrap();
+            }
+            let ancestors = incremental_dag.ancestors(Id(1001).into()).unwrap();
+            assert_eq!(ancestors.count(), 1002);
+        }
     }
 }
@@ -6,6 +6,7 @@
  */
 
 use std::fmt;
+use std::sync::Arc;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -19,10 +20,13 @@ use crate::segment::SegmentFlags;
 use crate::spanset::Span;
 use crate::IdSet;
 use crate::Level;
+use crate::QueryBudget;
 use crate::Result;
 
 mod in_process_store;
 
+#[cfg(any(test, feature = "indexedlog-backend"))]
+mod bloom;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub(crate) mod indexedlog_store;
 
@@ -179,6 +183,28 @@ pub trait IdDagStore: Send + Sync + 'static {
     /// Remove all non master Group identifiers from the DAG.
     fn remove_non_master(&mut self) -> Result<()>;
 
+    /// Remove all high-level segments. Flat segments are kept.
+    ///
+    /// High-level segments are an index of flat segments. Removing them does
+    /// not change the graph. They can be rebuilt afterwards.
+    fn remove_high_level_segments(&mut self) -> Result<()>;
+
+    /// Remove a high-level segment. The segment must exist in the store.
+    ///
+    /// Other segments are kept. Callers should remove the segments covering
+    /// it on higher levels too, and rebuild them afterwards.
+    fn remove_high_level_segment(&mut self, segment: &Segment) -> Result<()>;
+
+    /// Remove a flat segment. The segment must exist in the store.
+    ///
+    /// High-level segments are not updated. Callers should remove them
+    /// first (`remove_high_level_segments`), and rebuild them afterwards.
+    fn remove_flat_segment(&mut self, segment: &Segment) -> Result<()>;
+
+    /// Charge segments read from the store to `budget`. Reads fail with
+    /// `BudgetExceeded` once it runs out.
+    fn set_query_budget(&mut self, budget: Option<Arc<QueryBudget>>);
+
     /// Attempt to merge the flat `segment` with the last flat segment to reduce
     /// fragmentation.
     ///
@@ -259,6 +285,54 @@ pub trait IdDagStore: Send + Sync + 'static {
     }
 }
 
+/// Version of the formats of `IdDagStore`s, on disk and serialized.
+///
+/// Entries that older readers would misread are only written after a format
+/// marker (see `format_marker`) of the version that introduced them. Readers
+/// refuse markers of versions newer than `FORMAT_VERSION`. Readers that
+/// predate markers fail to parse them as segments, and refuse them too.
+///
+/// - 1: No markers.
+/// - 2: Cleared high-level segments (`MAGIC_CLEAR_HIGH_LEVEL` in `IndexedLogStore`).
+/// - 3: Removed flat segments (`MAGIC_REMOVE_FLAT`).
+/// - 4: Segments in the `HIDDEN` group.
+/// - 5: Removed high-level segments (`MAGIC_REMOVE_HIGH_LEVEL`).
+pub(crate) const FORMAT_VERSION: u8 = 5;
+
+/// The format version that introduced cleared high-level segments.
+pub(crate) const FORMAT_CLEAR_HIGH_LEVEL: u8 = 2;
+
+/// The format version that introduced removed flat segments.
+pub(crate) const FORMAT_REMOVE_FLAT: u8 = 3;
+
+/// The format version that introduced segments in the `HIDDEN` group.
+pub(crate) const FORMAT_HIDDEN_GROUP: u8 = 4;
+
+/// The format version that introduced removed high-level segments.
+pub(crate) const FORMAT_REMOVE_HIGH_LEVEL: u8 = 5;
+
+/// Format marker without the version byte. The first byte does not conflict
+/// with possible segment flags.
+const FORMAT_MARKER_PREFIX: &[u8] = &[0xf2, 0xff, b'F', b'O', b'R', b'M', b'A', b'T', 0];
+
+/// The format marker of `version`. It is as long as the fixed part of a
+/// segment, but has no delta, so `Segment::span` fails on it.
+pub(crate) fn format_marker(version: u8) -> [u8; Segment::OFFSET_DELTA] {
+    let mut marker = [0u8; Segment::OFFSET_DELTA];
+    marker[..FORMAT_MARKER_PREFIX.len()].copy_from_slice(FORMAT_MARKER_PREFIX);
+    marker[FORMAT_MARKER_PREFIX.len()] = version;
+    marker
+}
+
+/// The version of a format marker, or `None` if `data` is not a marker.
+pub(crate) fn parse_format_marker(data: &[u8]) -> Option<u8> {
+    if data.len() == Segment::OFFSET_DELTA && data.starts_with(FORMAT_MARKER_PREFIX) {
+        Some(data[FORMAT_MARKER_PREFIX.len()])
+    } else {
+        None
+    }
+}
+
 /// Wrapper for `Segment` that prevents access to `high`.
 #[derive(Eq, PartialEq)]
 pub struct SegmentWithWrongHead(Segment);
@@ -842,6 +916,114 @@ mod tests {
         );
     }
 
+    fn test_remove_high_level_segments(store: &mut dyn IdDagStore) {
+        store.remove_high_level_segments().unwrap();
+
+        assert_eq!(store.max_level().unwrap(), 0);
+        assert!(
+            store
+                .find_segment_by_head_and_level(Id(13), 1 as Level)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(store.next_free_id(1 as Level, M).unwrap(), Id(0));
+        assert_eq!(store.next_free_id(1 as Level, N).unwrap(), nid(0));
+
+        // Flat segments and indexes are kept.
+        assert_eq!(
+            store.find_flat_segment_including_id(Id(7)).unwrap(),
+            Some(LEVEL0_HEAD9.clone())
+        );
+        assert_eq!(
+            fmt(store.all_ids_in_groups(&[M, N]).unwrap()),
+            "0..=13 N0..=N6"
+        );
+        let children = fmt_iter(store.iter_flat_segments_with_parent(Id(2)).unwrap());
+        assert_eq!(children.len(), 1);
+
+        // High-level segments can be inserted again.
+        insert_segments(store, vec![&LEVEL1_HEAD13]);
+        assert_eq!(store.max_level().unwrap(), 1);
+        assert_eq!(store.next_free_id(1 as Level, M).unwrap(), Id(14));
+    }
+
+    fn test_remove_high_level_segment(store: &mut dyn IdDagStore) {
+        store.remove_high_level_segment(&LEVEL1_HEADN6).unwrap();
+
+        assert!(store
+            .find_segment_by_head_and_level(nid(6), 1 as Level)
+            .unwrap()
+            .is_none());
+        assert_eq!(store.next_free_id(1 as Level, N).unwrap(), nid(0));
+
+        // Other segments are kept.
+        assert_eq!(store.max_level().unwrap(), 1);
+        assert_eq!(
+            store
+                .find_segment_by_head_and_level(Id(13), 1 as Level)
+                .unwrap(),
+            Some(LEVEL1_HEAD13.clone())
+        );
+        assert_eq!(
+            fmt(store.all_ids_in_groups(&[M, N]).unwrap()),
+            "0..=13 N0..=N6"
+        );
+        let children = fmt_iter(store.iter_flat_segments_with_parent(nid(4)).unwrap());
+        assert_eq!(children, ["N5-x[N2, N4]"]);
+
+        // Removing a missing or flat segment is an error.
+        assert!(store.remove_high_level_segment(&LEVEL1_HEADN6).is_err());
+        assert!(store.remove_high_level_segment(&LEVEL0_HEAD13).is_err());
+
+        store.remove_high_level_segment(&LEVEL1_HEAD13).unwrap();
+        assert_eq!(store.max_level().unwrap(), 0);
+
+        // High-level segments can be inserted again.
+        insert_segments(store, vec![&LEVEL1_HEAD13]);
+        assert_eq!(store.max_level().unwrap(), 1);
+        assert_eq!(store.next_free_id(1 as Level, M).unwrap(), Id(14));
+    }
+
+    fn test_remove_flat_segment(store: &mut dyn IdDagStore) {
+        store.remove_high_level_segments().unwrap();
+        store.remove_flat_segment(&LEVEL0_HEADN6).unwrap();
+        store.remove_flat_segment(&LEVEL0_HEAD13).unwrap();
+
+        assert!(store
+            .find_segment_by_head_and_level(Id(13), 0 as Level)
+            .unwrap()
+            .is_none());
+        assert!(store
+            .find_flat_segment_including_id(Id(11))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            fmt(store.all_ids_in_groups(&[M, N]).unwrap()),
+            "0..=9 N0..=N4"
+        );
+        assert_eq!(store.next_free_id(0 as Level, M).unwrap(), Id(10));
+        assert_eq!(store.next_free_id(0 as Level, N).unwrap(), nid(5));
+        let children = fmt_iter(store.iter_flat_segments_with_parent(Id(9)).unwrap());
+        assert_eq!(children, ["N3-x[N0, 9]"]);
+        assert!(store
+            .iter_flat_segments_with_parent(Id(5))
+            .unwrap()
+            .next()
+            .is_none());
+
+        // Removing a missing segment is an error.
+        assert!(store.remove_flat_segment(&LEVEL0_HEAD13).is_err());
+
+        // Flat segments can be inserted again.
+        insert_segments(store, vec![&LEVEL0_HEAD13]);
+        assert_eq!(
+            store.find_flat_segment_including_id(Id(11)).unwrap(),
+            Some(LEVEL0_HEAD13.clone())
+        );
+        let children = fmt_iter(store.iter_flat_segments_with_parent(Id(5)).unwrap());
+        assert_eq!(children, ["10-x[5, 9]"]);
+    }
+
     fn for_each_empty_store(f: impl Fn(&mut dyn IdDagStore)) {
         let mut store = InProcessStore::new();
         tracing::debug!("testing InProcessStore");
@@ -853,6 +1035,11 @@ mod tests {
             let mut store = IndexedLogStore::open(&dir.path()).unwrap();
             tracing::debug!("testing IndexedLogStore");
             f(&mut store);
+
+            let dir = tempfile::tempdir().unwrap();
+            let mut store = IndexedLogStore::open_with_parent_bloom(&dir.path()).unwrap();
+            tracing::debug!("testing IndexedLogStore with parent bloom");
+            f(&mut store);
         }
     }
 
@@ -933,8 +1120,109 @@ mod tests {
         for_each_store(|store| test_remove_non_master(store));
     }
 
+    #[test]
+    fn test_multi_stores_remove_high_level_segments() {
+        for_each_store(|store| test_remove_high_level_segments(store));
+    }
+
+    #[test]
+    fn test_multi_stores_remove_high_level_segment() {
+        for_each_store(|store| test_remove_high_level_segment(store));
+    }
+
+    #[test]
+    fn test_multi_stores_remove_flat_segment() {
+        for_each_store(|store| test_remove_flat_segment(store));
+    }
+
     #[test]
     fn test_multi_stores_discontinuous_merges() {
         for_each_empty_store(|store| test_discontinuous_merges(store));
     }
+
+    #[test]
+    fn test_in_process_store_format_marker() {
+        let serialize = |store: &InProcessStore| mincode::serialize(store).unwrap();
+        let deserialize = |bytes: &[u8]| mincode::deserialize::<InProcessStore>(bytes);
+
+        // No markers without HIDDEN segments.
+        let mut store = InProcessStore::new();
+        insert_segments(&mut store, get_segments());
+        let bytes = serialize(&store);
+        let segments: Vec<Segment> = mincode::deserialize(&bytes).unwrap();
+        assert!(parse_format_marker(&segments[0].0).is_none());
+
+        // A marker goes first with HIDDEN segments, and is skipped.
+        let low = Group::HIDDEN.min_id();
+        let hidden = Segment::new(SegmentFlags::HAS_ROOT, 0, low, low + 2, &[]);
+        store.insert_segment(hidden).unwrap();
+        let bytes = serialize(&store);
+        let segments: Vec<Segment> = mincode::deserialize(&bytes).unwrap();
+        assert_eq!(
+            parse_format_marker(&segments[0].0),
+            Some(FORMAT_HIDDEN_GROUP)
+        );
+        let store = deserialize(&bytes).unwrap();
+        let hidden_ids = store.all_ids_in_groups(&[Group::HIDDEN]).unwrap();
+        assert_eq!(
+            hidden_ids.as_spans(),
+            IdSet::from(low..=(low + 2)).as_spans()
+        );
+
+        // Newer formats are refused.
+        let mut segments = segments;
+        segments[0] = Segment(format_marker(FORMAT_VERSION + 1).to_vec().into());
+        let bytes = mincode::serialize(&segments).unwrap();
+        let err = deserialize(&bytes).err().unwrap();
+        assert!(err.to_string().contains("newer than supported"), "{}", err);
+    }
+
+    #[cfg(feature = "indexedlog-backend")]
+    #[test]
+    fn test_indexedlog_store_parent_bloom() {
+        use crate::ops::Persist;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = IndexedLogStore::open_with_parent_bloom(&dir.path()).unwrap();
+        insert_segments(&mut store, get_segments());
+        let lock = store.lock().unwrap();
+        store.persist(&lock).unwrap();
+        drop(lock);
+
+        let count = |store: &IndexedLogStore, id: Id| {
+            store.iter_flat_segments_with_parent(id).unwrap().count()
+        };
+
+        // Id(4) has no children in any group.
+        let group_count = Group::ALL.len() as u64;
+        assert_eq!(count(&store, Id(4)), 0);
+        let stats = store.parent_bloom_stats().unwrap();
+        assert_eq!(stats.lookups, group_count);
+        assert_eq!(stats.skipped, group_count);
+        assert_eq!(stats.false_positive_rate(), 0.0);
+
+        // Children are not skipped.
+        assert_eq!(count(&store, Id(9)), 2);
+        let stats = store.parent_bloom_stats().unwrap();
+        assert_eq!(stats.lookups, group_count * 2);
+        assert!(stats.skipped > group_count);
+
+        // The filter is rebuilt if its state is lost or corrupted.
+        for content in [None, Some(&b"corrupted"[..])] {
+            let path = dir.path().join("fold-parent-bloom");
+            match content {
+                None => std::fs::remove_file(&path).unwrap(),
+                Some(content) => std::fs::write(&path, content).unwrap(),
+            }
+            let store = IndexedLogStore::open_with_parent_bloom(&dir.path()).unwrap();
+            test_store_iter_flat_segments_with_parent(&store);
+            assert_eq!(count(&store, Id(4)), 0);
+            assert!(store.parent_bloom_stats().unwrap().skipped > 0);
+        }
+
+        // Without the filter.
+        let store = IndexedLogStore::open(&dir.path()).unwrap();
+        assert_eq!(store.parent_bloom_stats(), None);
+        test_store_iter_flat_segments_with_parent(&store);
+    }
 }
@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A bloom filter that grows with the number of keys.
+//!
+//! Used to skip index lookups that would find nothing. Keys cannot be
+//! removed. A removed key only makes the filter a bit less useful.
+
+use std::hash::Hasher;
+
+use serde::Deserialize;
+use serde::Serialize;
+use twox_hash::XxHash64;
+
+/// Capacity of the first filter. Each following filter doubles it.
+const INITIAL_CAPACITY: u64 = 1024;
+
+/// False positive rate of the first filter. Each following filter halves
+/// it, so the rate of all filters together stays below twice this.
+const INITIAL_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// A chain of bloom filters. Keys are added to the last filter, and a new,
+/// larger filter is started once it is full.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ScalableBloomFilter {
+    filters: Vec<BloomFilter>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+    capacity: u64,
+    len: u64,
+}
+
+impl ScalableBloomFilter {
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        let hash = hash_key(key);
+        if self.filters.iter().any(|filter| filter.contains(hash)) {
+            return;
+        }
+        let full = match self.filters.last() {
+            Some(filter) => filter.len >= filter.capacity,
+            None => true,
+        };
+        if full {
+            let n = self.filters.len() as u32;
+            let capacity = INITIAL_CAPACITY.saturating_mul(1 << n.min(32));
+            let false_positive_rate = INITIAL_FALSE_POSITIVE_RATE * 0.5f64.powi(n as i32);
+            self.filters
+                .push(BloomFilter::new(capacity, false_positive_rate));
+        }
+        if let Some(filter) = self.filters.last_mut() {
+            filter.insert(hash);
+        }
+    }
+
+    /// Whether `key` might have been inserted. `false` means it was not.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let hash = hash_key(key);
+        self.filters.iter().any(|filter| filter.contains(hash))
+    }
+}
+
+impl BloomFilter {
+    fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = -(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2);
+        let num_bytes = ((num_bits / 8.0).ceil() as usize).max(1);
+        let num_hashes = (-false_positive_rate.log2()).ceil().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bytes],
+            num_hashes,
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Positions of the bits of a key, by double hashing.
+    fn bit_positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() as u64 * 8;
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for pos in self.bit_positions(hash).collect::<Vec<_>>() {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+        self.len += 1;
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.bit_positions(hash)
+            .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+/// Two independent hashes of `key`. The filters are persisted, so the hash
+/// function must not change.
+fn hash_key(key: &[u8]) -> (u64, u64) {
+    let hash = |seed| {
+        let mut hasher = XxHash64::with_seed(seed);
+        hasher.write(key);
+        hasher.finish()
+    };
+    (hash(0), hash(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u64) -> [u8; 8] {
+        i.to_be_bytes()
+    }
+
+    #[test]
+    fn test_scalable_bloom_filter() {
+        let mut filter = ScalableBloomFilter::default();
+        assert!(!filter.may_contain(&key(0)));
+
+        let count = 10 * INITIAL_CAPACITY;
+        for i in 0..count {
+            filter.insert(&key(i));
+        }
+        // The filter grew, and has no false negatives.
+        assert!(filter.filters.len() > 1);
+        assert!((0..count).all(|i| filter.may_contain(&key(i))));
+
+        let false_positives = (count..count * 2)
+            .filter(|&i| filter.may_contain(&key(i)))
+            .count();
+        assert!(
+            false_positives as f64 <= count as f64 * INITIAL_FALSE_POSITIVE_RATE * 2.0,
+            "too many false positives: {}",
+            false_positives
+        );
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let mut filter = ScalableBloomFilter::default();
+        for _ in 0..INITIAL_CAPACITY * 2 {
+            filter.insert(&key(1));
+        }
+        assert_eq!(filter.filters.len(), 1);
+        assert_eq!(filter.filters[0].len, 1);
+    }
+}
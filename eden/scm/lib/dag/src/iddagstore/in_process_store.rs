@@ -10,7 +10,9 @@ use std::collections::BTreeSet;
 use std::fmt;
 use std::iter;
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
+use minibytes::Bytes;
 use serde::de::Error;
 use serde::de::SeqAccess;
 use serde::de::Visitor;
@@ -20,8 +22,13 @@ use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
 
+use super::format_marker;
+use super::parse_format_marker;
 use super::IdDagStore;
 use super::StoreId;
+use super::FORMAT_HIDDEN_GROUP;
+use super::FORMAT_VERSION;
+use crate::budget::SegmentMeter;
 use crate::errors::bug;
 use crate::id::Group;
 use crate::id::Id;
@@ -31,6 +38,7 @@ use crate::segment::Segment;
 use crate::spanset::Span;
 use crate::IdSet;
 use crate::Level;
+use crate::QueryBudget;
 use crate::Result;
 
 #[derive(Clone)]
@@ -43,6 +51,8 @@ pub struct InProcessStore {
     parent_index: BTreeMap<(Group, Id), BTreeSet<StoreId>>,
     // IdSet covered by flat segments in specified groups.
     id_set_by_group: [IdSet; Group::COUNT],
+    // Charges segments read to the query budget. Not serialized.
+    meter: SegmentMeter,
 }
 
 impl IdDagStore for InProcessStore {
@@ -55,6 +65,7 @@ impl IdDagStore for InProcessStore {
             .get_head_index(level)
             .and_then(|head_index| head_index.get(&head))
             .map(|store_id| self.get_segment(store_id));
+        self.meter.charge(usize::from(answer.is_some()))?;
         Ok(answer)
     }
 
@@ -64,6 +75,7 @@ impl IdDagStore for InProcessStore {
             .get_head_index(level)
             .and_then(|head_index| head_index.range(id..).next())
             .map(|(_, store_id)| self.get_segment(store_id));
+        self.meter.charge(usize::from(answer.is_some()))?;
         if let Some(ref seg) = &answer {
             if seg.span()?.low > id {
                 return Ok(None);
@@ -148,18 +160,59 @@ impl IdDagStore for InProcessStore {
                 .get_mut(level as usize)
                 .map(|head_index| head_index.remove(&head));
         }
-        let group = Group::NON_MASTER;
-        for (_key, children) in self
-            .parent_index
-            .range_mut((group, group.min_id())..=(group, group.max_id()))
-        {
-            children.clear();
+        for group in Group::non_master() {
+            for (_key, children) in self
+                .parent_index
+                .range_mut((group, Id::MIN)..=(group, Id::MAX))
+            {
+                children.clear();
+            }
+            self.id_set_by_group[group.0] = IdSet::empty();
         }
         self.non_master_segments = Vec::new();
-        self.id_set_by_group[Group::NON_MASTER.0] = IdSet::empty();
         Ok(())
     }
 
+    fn remove_high_level_segments(&mut self) -> Result<()> {
+        self.level_head_index.truncate(1);
+        self.rebuild_segment_lists();
+        Ok(())
+    }
+
+    fn remove_high_level_segment(&mut self, segment: &Segment) -> Result<()> {
+        let level = segment.level()?;
+        let head = segment.head()?;
+        let removed = match self.level_head_index.get_mut(level as usize) {
+            Some(head_index) if level > 0 => head_index.remove(&head),
+            _ => None,
+        };
+        if removed.is_none() {
+            return bug(format!("{:?} does not exist in store", segment));
+        }
+        // Drop empty levels so max_level does not include them.
+        while let Some(head_index) = self.level_head_index.last() {
+            if self.level_head_index.len() == 1 || !head_index.is_empty() {
+                break;
+            }
+            self.level_head_index.pop();
+        }
+        self.rebuild_segment_lists();
+        Ok(())
+    }
+
+    fn remove_flat_segment(&mut self, segment: &Segment) -> Result<()> {
+        let span = segment.span()?;
+        let group = span.low.group();
+        match self.get_head_index_mut(0).remove(&span.high) {
+            Some(_) => {}
+            None => return bug(format!("{:?} does not exist in store", segment)),
+        }
+        self.id_set_by_group[group.0] = self.id_set_by_group[group.0].difference(&span.into());
+        // Rebuild the segment lists and the "parents" index so the removed
+        // segment is no longer referred.
+        self.remove_high_level_segments()
+    }
+
     fn all_ids_in_groups(&self, groups: &[Group]) -> Result<IdSet> {
         let mut result = IdSet::empty();
         for group in groups {
@@ -187,10 +240,11 @@ impl IdDagStore for InProcessStore {
         match self.get_head_index(level) {
             None => Ok(vec![]),
             Some(head_index) => {
-                let segments = head_index
+                let segments: Vec<Segment> = head_index
                     .range(id..id.group().max_id())
                     .map(|(_, store_id)| self.get_segment(store_id))
                     .collect();
+                self.meter.charge(segments.len())?;
                 Ok(segments)
             }
         }
@@ -208,7 +262,7 @@ impl IdDagStore for InProcessStore {
                     .range(Id::MIN..=max_high_id)
                     .rev()
                     .map(move |(_, store_id)| Ok(self.get_segment(store_id)));
-                Ok(Box::new(iter))
+                Ok(Box::new(self.meter.charge_each(iter)))
             }
         }
     }
@@ -224,7 +278,7 @@ impl IdDagStore for InProcessStore {
                 let iter = head_index
                     .range(min_high_id..=Id::MAX)
                     .map(move |(_, store_id)| Ok(self.get_segment(store_id)));
-                Ok(Box::new(iter))
+                Ok(Box::new(self.meter.charge_each(iter)))
             }
         }
     }
@@ -243,7 +297,7 @@ impl IdDagStore for InProcessStore {
                         Ok((parent_id, SegmentWithWrongHead(self.get_segment(store_id))))
                     })
                 });
-        Ok(Box::new(iter))
+        Ok(Box::new(self.meter.charge_each(iter)))
     }
 
     fn iter_flat_segments_with_parent<'a>(
@@ -261,8 +315,18 @@ impl IdDagStore for InProcessStore {
                 }
             }
         };
-        let iter = get_iter(Group::MASTER)?.chain(get_iter(Group::NON_MASTER)?);
-        Ok(Box::new(iter))
+        // Children are in the same group as `parent`, or a higher one.
+        let iters = Group::ALL
+            .into_iter()
+            .filter(|&group| group >= parent.group())
+            .map(get_iter)
+            .collect::<Result<Vec<_>>>()?;
+        let iter = iters.into_iter().flatten();
+        Ok(Box::new(self.meter.charge_each(iter)))
+    }
+
+    fn set_query_budget(&mut self, budget: Option<Arc<QueryBudget>>) {
+        self.meter.set_budget(budget);
     }
 }
 
@@ -308,6 +372,43 @@ impl InProcessStore {
             &StoreId::NonMaster(offset) => self.non_master_segments[offset] = segment,
         }
     }
+
+    /// Rebuild the segment lists from the head indexes so removed segments
+    /// are not serialized.
+    fn rebuild_segment_lists(&mut self) {
+        let mut master_segments = Vec::new();
+        let mut non_master_segments = Vec::new();
+        let mut store_id_map = BTreeMap::new();
+        let mut level_head_index = Vec::with_capacity(self.level_head_index.len());
+        for head_index in self.level_head_index.iter() {
+            let mut new_head_index = BTreeMap::new();
+            for (&head, store_id) in head_index.iter() {
+                let segment = self.get_segment(store_id);
+                let new_store_id = match store_id {
+                    StoreId::Master(_) => {
+                        master_segments.push(segment);
+                        StoreId::Master(master_segments.len() - 1)
+                    }
+                    StoreId::NonMaster(_) => {
+                        non_master_segments.push(segment);
+                        StoreId::NonMaster(non_master_segments.len() - 1)
+                    }
+                };
+                new_head_index.insert(head, new_store_id);
+                store_id_map.insert(*store_id, new_store_id);
+            }
+            level_head_index.push(new_head_index);
+        }
+        for children in self.parent_index.values_mut() {
+            *children = children
+                .iter()
+                .filter_map(|store_id| store_id_map.get(store_id).copied())
+                .collect();
+        }
+        self.master_segments = master_segments;
+        self.non_master_segments = non_master_segments;
+        self.level_head_index = level_head_index;
+    }
 }
 
 impl InProcessStore {
@@ -317,7 +418,8 @@ impl InProcessStore {
             non_master_segments: Vec::new(),
             level_head_index: Vec::new(),
             parent_index: BTreeMap::new(),
-            id_set_by_group: [IdSet::empty(), IdSet::empty()],
+            id_set_by_group: Default::default(),
+            meter: Default::default(),
         }
     }
 }
@@ -327,9 +429,20 @@ impl Serialize for InProcessStore {
     where
         S: Serializer,
     {
+        // Older deserializers would misread HIDDEN segments. Refuse them.
+        // See FORMAT_VERSION.
+        let marker = if self.id_set_by_group[Group::HIDDEN.0].is_empty() {
+            None
+        } else {
+            let bytes = format_marker(FORMAT_HIDDEN_GROUP);
+            Some(Segment(Bytes::copy_from_slice(&bytes)))
+        };
         let mut seq = serializer.serialize_seq(Some(
-            self.master_segments.len() + self.non_master_segments.len(),
+            self.master_segments.len() + self.non_master_segments.len() + marker.iter().count(),
         ))?;
+        if let Some(marker) = &marker {
+            seq.serialize_element(marker)?;
+        }
         for e in &self.master_segments {
             seq.serialize_element(e)?;
         }
@@ -356,7 +469,16 @@ impl<'de> Deserialize<'de> for InProcessStore {
                 A: SeqAccess<'de>,
             {
                 let mut store = InProcessStore::new();
-                while let Some(segment) = access.next_element()? {
+                while let Some(segment) = access.next_element::<Segment>()? {
+                    if let Some(version) = parse_format_marker(&segment.0) {
+                        if version > FORMAT_VERSION {
+                            return Err(A::Error::custom(format!(
+                                "IdDag format {} is newer than supported ({})",
+                                version, FORMAT_VERSION
+                            )));
+                        }
+                        continue;
+                    }
                     store.insert_segment(segment).map_err(|e| {
                         A::Error::custom(format!("failed to deserialize IdDagStore: {} ", e))
                     })?;
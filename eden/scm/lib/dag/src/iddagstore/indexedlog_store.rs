@@ -12,10 +12,13 @@ use std::io;
 use std::io::Cursor;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering::AcqRel;
 use std::sync::atomic::Ordering::Acquire;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::Ordering::Release;
+use std::sync::Arc;
 
 use byteorder::BigEndian;
 use byteorder::ReadBytesExt;
@@ -25,7 +28,16 @@ use indexedlog::log;
 use indexedlog::log::Fold;
 use minibytes::Bytes;
 
+use super::bloom::ScalableBloomFilter;
+use super::format_marker;
+use super::parse_format_marker;
 use super::IdDagStore;
+use super::FORMAT_CLEAR_HIGH_LEVEL;
+use super::FORMAT_HIDDEN_GROUP;
+use super::FORMAT_REMOVE_FLAT;
+use super::FORMAT_REMOVE_HIGH_LEVEL;
+use super::FORMAT_VERSION;
+use crate::budget::SegmentMeter;
 use crate::errors::bug;
 use crate::id::Group;
 use crate::id::Id;
@@ -38,12 +50,17 @@ use crate::segment::SegmentFlags;
 use crate::spanset::Span;
 use crate::IdSet;
 use crate::Level;
+use crate::QueryBudget;
 use crate::Result;
 
 pub struct IndexedLogStore {
     log: log::Log,
     path: PathBuf,
     cached_max_level: AtomicU8,
+    // Charges segments read to the query budget.
+    meter: SegmentMeter,
+    // `Some` if the log has the parent bloom filter fold.
+    parent_bloom: Option<Arc<ParentBloomCounters>>,
 }
 
 /// Fold (accumulator) that tracks IdSet covered in groups.
@@ -69,8 +86,33 @@ impl Fold for CoveredIdSetFold {
 
     fn accumulate(&mut self, data: &[u8]) -> indexedlog::Result<()> {
         // See log_open_options for how other index functions read the entry.
+        if parse_format_marker(data).is_some() {
+            return Ok(());
+        }
         if data == IndexedLogStore::MAGIC_CLEAR_NON_MASTER {
-            self.id_set_by_group[Group::NON_MASTER.0] = IdSet::empty();
+            for group in Group::non_master() {
+                self.id_set_by_group[group.0] = IdSet::empty();
+            }
+            return Ok(());
+        }
+        if data == IndexedLogStore::MAGIC_CLEAR_HIGH_LEVEL
+            || data.starts_with(IndexedLogStore::MAGIC_REMOVE_HIGH_LEVEL)
+        {
+            // Flat segments are unchanged.
+            return Ok(());
+        }
+        if data.starts_with(IndexedLogStore::MAGIC_REMOVE_FLAT) {
+            // See MAGIC_REMOVE_FLAT for format.
+            let seg = Segment(Bytes::copy_from_slice(
+                &data[IndexedLogStore::MAGIC_REMOVE_FLAT.len()..],
+            ));
+            let span = match seg.span() {
+                Ok(s) => s,
+                Err(e) => return Err(("cannot parse segment in CoveredIdSetFold", e).into()),
+            };
+            if let Some(set) = self.id_set_by_group.get_mut(span.low.group().0) {
+                *set = set.difference(&span.into());
+            }
             return Ok(());
         }
         let data = if data.starts_with(IndexedLogStore::MAGIC_REWRITE_LAST_FLAT) {
@@ -101,6 +143,140 @@ impl Fold for CoveredIdSetFold {
     }
 }
 
+/// Fold that tracks the newest format marker in `log`, and refuses markers
+/// newer than `FORMAT_VERSION`. See `FORMAT_VERSION`.
+#[derive(Debug, Clone, Default)]
+struct FormatFold {
+    // 0 if there are no markers.
+    version: u8,
+}
+
+impl Fold for FormatFold {
+    fn load(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.version = mincode::deserialize(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(())
+    }
+
+    fn dump(&self) -> io::Result<Vec<u8>> {
+        mincode::serialize(&self.version).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn accumulate(&mut self, data: &[u8]) -> indexedlog::Result<()> {
+        if let Some(version) = parse_format_marker(data) {
+            if version > FORMAT_VERSION {
+                let message = format!(
+                    "IdDag format {} is newer than supported ({})",
+                    version, FORMAT_VERSION
+                );
+                return Err(message.as_str().into());
+            }
+            self.version = self.version.max(version);
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_boxed(&self) -> Box<dyn Fold> {
+        Box::new(self.clone())
+    }
+}
+
+/// Fold that tracks a bloom filter of the `(child-group, parent)` prefixes
+/// in the parent index, so lookups of parents without children can skip the
+/// index. Only defined by `log_open_options_with_parent_bloom`.
+///
+/// The state is stored as part in `log`, and is rebuilt from the log if it
+/// is missing or corrupted, for example after `repair`.
+#[derive(Debug, Clone, Default)]
+struct ParentBloomFold {
+    filter: ScalableBloomFilter,
+}
+
+impl Fold for ParentBloomFold {
+    fn load(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.filter = mincode::deserialize(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(())
+    }
+
+    fn dump(&self) -> io::Result<Vec<u8>> {
+        mincode::serialize(&self.filter).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn accumulate(&mut self, data: &[u8]) -> indexedlog::Result<()> {
+        // Mirrors the "group-parent-child" index in log_open_options. Keys
+        // cannot be removed from the filter, so removed segments and cleared
+        // groups only cause false positives.
+        if parse_format_marker(data).is_some()
+            || data == IndexedLogStore::MAGIC_CLEAR_NON_MASTER
+            || data == IndexedLogStore::MAGIC_CLEAR_HIGH_LEVEL
+            || data.starts_with(IndexedLogStore::MAGIC_REMOVE_FLAT)
+            || data.starts_with(IndexedLogStore::MAGIC_REMOVE_HIGH_LEVEL)
+            || data.starts_with(IndexedLogStore::MAGIC_REWRITE_LAST_FLAT)
+        {
+            return Ok(());
+        }
+        let seg = Segment(Bytes::copy_from_slice(data));
+        if seg.level().ok() == Some(0) {
+            let (parents, span) = match (seg.parents(), seg.span()) {
+                (Ok(parents), Ok(span)) => (parents, span),
+                (Err(e), _) | (_, Err(e)) => {
+                    return Err(("cannot parse segment in ParentBloomFold", e).into());
+                }
+            };
+            for id in parents {
+                self.filter.insert(&index_parent_key(span.low.group(), id));
+            }
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_boxed(&self) -> Box<dyn Fold> {
+        Box::new(self.clone())
+    }
+}
+
+/// Counters of lookups in the parent bloom filter. Shared by clones of a
+/// store.
+#[derive(Debug, Default)]
+struct ParentBloomCounters {
+    lookups: AtomicU64,
+    skipped: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+/// Statistics of the parent bloom filter of an [`IndexedLogStore`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParentBloomStats {
+    /// `(group, parent)` pairs checked against the filter.
+    pub lookups: u64,
+    /// Lookups the filter ruled out, skipping the parent index.
+    pub skipped: u64,
+    /// Lookups the filter let through, but found nothing in the index.
+    pub false_positives: u64,
+}
+
+impl ParentBloomStats {
+    /// Portion of lookups of parents without children that were not ruled
+    /// out by the filter.
+    pub fn false_positive_rate(&self) -> f64 {
+        let negatives = self.skipped + self.false_positives;
+        if negatives == 0 {
+            0.0
+        } else {
+            self.false_positives as f64 / negatives as f64
+        }
+    }
+}
+
 // Required functionality
 impl IdDagStore for IndexedLogStore {
     fn max_level(&self) -> Result<Level> {
@@ -125,7 +301,10 @@ impl IdDagStore for IndexedLogStore {
         let key = Self::serialize_head_level_lookup_key(head, level);
         match self.log.lookup(Self::INDEX_LEVEL_HEAD, &key)?.nth(0) {
             None => Ok(None),
-            Some(bytes) => Ok(Some(self.segment_from_slice(bytes?))),
+            Some(bytes) => {
+                self.meter.charge(1)?;
+                Ok(Some(self.segment_from_slice(bytes?)))
+            }
         }
     }
 
@@ -140,6 +319,7 @@ impl IdDagStore for IndexedLogStore {
             let (_, entries) = entry?;
             for entry in entries {
                 let entry = entry?;
+                self.meter.charge(1)?;
                 let seg = self.segment_from_slice(entry);
                 if seg.span()?.low > id {
                     return Ok(None);
@@ -168,6 +348,9 @@ impl IdDagStore for IndexedLogStore {
         //    (removed)
         //   [(new, merged) segment       ]
         //    (in memory)
+        if segment.high()?.group() == Group::HIDDEN {
+            self.require_format(FORMAT_HIDDEN_GROUP)?;
+        }
         if level == 0 {
             if self.maybe_insert_merged_flat_segment(&segment)? {
                 return Ok(());
@@ -232,6 +415,7 @@ impl IdDagStore for IndexedLogStore {
                 result.push(self.segment_from_slice(value?));
             }
         }
+        self.meter.charge(result.len())?;
         Ok(result)
     }
 
@@ -258,7 +442,7 @@ impl IdDagStore for IndexedLogStore {
                 Err(err) => vec![Err(err.into())],
             }
         });
-        Ok(Box::new(iter))
+        Ok(Box::new(self.meter.charge_each(iter)))
     }
 
     fn iter_segments_ascending<'a>(
@@ -282,22 +466,22 @@ impl IdDagStore for IndexedLogStore {
                 Err(err) => vec![Err(err.into())],
             }
         });
-        Ok(Box::new(iter))
+        Ok(Box::new(self.meter.charge_each(iter)))
     }
 
     fn iter_master_flat_segments_with_parent_span<'a>(
         &'a self,
         parent_span: Span,
     ) -> Result<Box<dyn Iterator<Item = Result<(Id, SegmentWithWrongHead)>> + 'a>> {
-        let low = index_parent_key(Group::MASTER, parent_span.low);
-        let high = index_parent_key(Group::MASTER, parent_span.high);
+        let low = index_parent_child_key(Group::MASTER, parent_span.low, Id::MIN);
+        let high = index_parent_child_key(Group::MASTER, parent_span.high, Id::MAX);
         let range = &low[..]..=&high[..];
         let range_iter = self.log.lookup_range(Self::INDEX_PARENT, range)?;
         let mut result: Vec<(Id, SegmentWithWrongHead)> = Vec::new();
         for entry in range_iter {
             let (key, segments) = entry?;
             let parent_id = {
-                let bytes: [u8; 8] = key[1..].try_into().unwrap();
+                let bytes: [u8; 8] = key[1..9].try_into().unwrap();
                 Id(u64::from_be_bytes(bytes))
             };
             for segment in segments {
@@ -307,6 +491,7 @@ impl IdDagStore for IndexedLogStore {
                 ));
             }
         }
+        self.meter.charge(result.len())?;
         Ok(Box::new(result.into_iter().map(Ok)))
     }
 
@@ -316,21 +501,36 @@ impl IdDagStore for IndexedLogStore {
     ) -> Result<Box<dyn Iterator<Item = Result<SegmentWithWrongHead>> + 'a>> {
         let get_iter = |group: Group| -> Result<_> {
             let key = index_parent_key(group, parent);
-            let iter = self.log.lookup(Self::INDEX_PARENT, &key)?;
-            let iter = iter.map(move |result| {
-                match result {
-                    Ok(bytes) => Ok(SegmentWithWrongHead(self.segment_from_slice(bytes))),
-                    Err(err) => Err(err.into()),
+            if !self.check_parent_bloom(&key)? {
+                return Ok(None);
+            }
+            let mut iter = self.log.lookup_prefix(Self::INDEX_PARENT, &key)?.peekable();
+            if iter.peek().is_none() {
+                if let Some(counters) = &self.parent_bloom {
+                    counters.false_positives.fetch_add(1, Relaxed);
+                }
+            }
+            let iter = iter.flat_map(move |entry| {
+                match entry {
+                    Ok((_key, values)) => values
+                        .map(|value| {
+                            let value = value?;
+                            Ok(SegmentWithWrongHead(self.segment_from_slice(value)))
+                        })
+                        .collect(),
+                    Err(err) => vec![Err(err.into())],
                 }
             });
-            Ok(iter)
-        };
-        let iter: Box<dyn Iterator<Item = Result<_>> + 'a> = if parent.group() == Group::MASTER {
-            Box::new(get_iter(Group::MASTER)?.chain(get_iter(Group::NON_MASTER)?))
-        } else {
-            Box::new(get_iter(Group::NON_MASTER)?)
+            Ok(Some(iter))
         };
-        Ok(iter)
+        // Children are in the same group as `parent`, or a higher one.
+        let iters = Group::ALL
+            .into_iter()
+            .filter(|&group| group >= parent.group())
+            .filter_map(|group| get_iter(group).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        let iter = iters.into_iter().flatten();
+        Ok(Box::new(self.meter.charge_each(iter)))
     }
 
     /// Mark non-master ids as "removed".
@@ -339,12 +539,60 @@ impl IdDagStore for IndexedLogStore {
         // As an optimization, we could pass a max_level hint from iddag.
         // Doesn't seem necessary though.
         for level in 0..=self.max_level()? {
-            if self.next_free_id(level, Group::NON_MASTER)? != Group::NON_MASTER.min_id() {
-                return bug("remove_non_master did not take effect");
+            for group in Group::non_master() {
+                if self.next_free_id(level, group)? != group.min_id() {
+                    return bug("remove_non_master did not take effect");
+                }
             }
         }
         Ok(())
     }
+
+    /// Mark high-level segments as "removed".
+    fn remove_high_level_segments(&mut self) -> Result<()> {
+        self.require_format(FORMAT_CLEAR_HIGH_LEVEL)?;
+        self.log.append(Self::MAGIC_CLEAR_HIGH_LEVEL)?;
+        self.cached_max_level.store(MAX_LEVEL_UNKNOWN, Release);
+        if self.max_level()? != 0 {
+            return bug("remove_high_level_segments did not take effect");
+        }
+        Ok(())
+    }
+
+    /// Mark a high-level segment as "removed".
+    fn remove_high_level_segment(&mut self, segment: &Segment) -> Result<()> {
+        let level = segment.level()?;
+        let high = segment.high()?;
+        if level == 0 || self.find_segment_by_head_and_level(high, level)?.as_ref() != Some(segment)
+        {
+            return bug(format!("{:?} does not exist in store", segment));
+        }
+        self.require_format(FORMAT_REMOVE_HIGH_LEVEL)?;
+        let mut bytes = Vec::with_capacity(segment.0.len() + Self::MAGIC_REMOVE_HIGH_LEVEL.len());
+        bytes.extend_from_slice(Self::MAGIC_REMOVE_HIGH_LEVEL);
+        bytes.extend_from_slice(&segment.0);
+        self.log.append(&bytes)?;
+        self.cached_max_level.store(MAX_LEVEL_UNKNOWN, Release);
+        Ok(())
+    }
+
+    /// Mark a flat segment as "removed".
+    fn remove_flat_segment(&mut self, segment: &Segment) -> Result<()> {
+        let high = segment.high()?;
+        if self.find_segment_by_head_and_level(high, 0)?.as_ref() != Some(segment) {
+            return bug(format!("{:?} does not exist in store", segment));
+        }
+        self.require_format(FORMAT_REMOVE_FLAT)?;
+        let mut bytes = Vec::with_capacity(segment.0.len() + Self::MAGIC_REMOVE_FLAT.len());
+        bytes.extend_from_slice(Self::MAGIC_REMOVE_FLAT);
+        bytes.extend_from_slice(&segment.0);
+        self.log.append(&bytes)?;
+        Ok(())
+    }
+
+    fn set_query_budget(&mut self, budget: Option<Arc<QueryBudget>>) {
+        self.meter.set_budget(budget);
+    }
 }
 
 impl Persist for IndexedLogStore {
@@ -370,6 +618,8 @@ impl Persist for IndexedLogStore {
     fn reload(&mut self, _lock: &Self::Lock) -> Result<()> {
         self.log.clear_dirty()?;
         self.log.sync()?;
+        // High-level segments might have been removed by another process.
+        self.cached_max_level.store(MAX_LEVEL_UNKNOWN, Release);
         Ok(())
     }
 
@@ -380,6 +630,27 @@ impl Persist for IndexedLogStore {
 }
 
 impl IndexedLogStore {
+    /// Append a format marker of `version`, unless the log has one of
+    /// `version` or newer. Must be called before appending entries that
+    /// readers before `version` would misread.
+    fn require_format(&mut self, version: u8) -> Result<()> {
+        if self.format_version()? < version {
+            self.log.append(format_marker(version))?;
+        }
+        Ok(())
+    }
+
+    /// The newest format marker in the log, or 1 if there are no markers.
+    fn format_version(&self) -> Result<u8> {
+        let fold = self
+            .log
+            .fold(Self::FOLD_FORMAT)?
+            .as_any()
+            .downcast_ref::<FormatFold>()
+            .expect("should downcast to FormatFold defined by OpenOptions");
+        Ok(fold.version.max(1))
+    }
+
     /// Attempt to merge the flat `segment` with the last flat segment to reduce
     /// fragmentation. Insert the merged segment.
     ///
@@ -429,8 +700,12 @@ impl IndexedLogStore {
 /// This is only for troubleshooting purpose.
 pub fn describe_indexedlog_entry(data: &[u8]) -> String {
     let mut message = String::new();
-    if data == IndexedLogStore::MAGIC_CLEAR_NON_MASTER {
+    if let Some(version) = parse_format_marker(data) {
+        message += &format!("# {}: FORMAT_MARKER (Version = {})\n", hex(data), version);
+    } else if data == IndexedLogStore::MAGIC_CLEAR_NON_MASTER {
         message += &format!("# {}: MAGIC_CLEAR_NON_MASTER\n", hex(data),);
+    } else if data == IndexedLogStore::MAGIC_CLEAR_HIGH_LEVEL {
+        message += &format!("# {}: MAGIC_CLEAR_HIGH_LEVEL\n", hex(data),);
     } else if data.starts_with(IndexedLogStore::MAGIC_REWRITE_LAST_FLAT) {
         message += &format!(
             "# {}: MAGIC_REWRITE_LAST_FLAT\n",
@@ -450,6 +725,20 @@ pub fn describe_indexedlog_entry(data: &[u8]) -> String {
         );
 
         message += &describe_indexedlog_entry(&data[end..]);
+    } else if data.starts_with(IndexedLogStore::MAGIC_REMOVE_FLAT) {
+        message += &format!(
+            "# {}: MAGIC_REMOVE_FLAT\n",
+            hex(IndexedLogStore::MAGIC_REMOVE_FLAT)
+        );
+        let start = IndexedLogStore::MAGIC_REMOVE_FLAT.len();
+        message += &describe_segment_bytes(&data[start..]);
+    } else if data.starts_with(IndexedLogStore::MAGIC_REMOVE_HIGH_LEVEL) {
+        message += &format!(
+            "# {}: MAGIC_REMOVE_HIGH_LEVEL\n",
+            hex(IndexedLogStore::MAGIC_REMOVE_HIGH_LEVEL)
+        );
+        let start = IndexedLogStore::MAGIC_REMOVE_HIGH_LEVEL.len();
+        message += &describe_segment_bytes(&data[start..]);
     } else {
         message += &describe_segment_bytes(data);
     }
@@ -461,6 +750,8 @@ impl IndexedLogStore {
     const INDEX_LEVEL_HEAD: usize = 0;
     const INDEX_PARENT: usize = 1;
     const FOLD_COVERED_ID_SET: usize = 0;
+    const FOLD_FORMAT: usize = 1;
+    const FOLD_PARENT_BLOOM: usize = 2;
     const KEY_LEVEL_HEAD_LEN: usize = Segment::OFFSET_DELTA - Segment::OFFSET_LEVEL;
 
     /// Magic bytes in `Log` that indicates "remove all non-master segments".
@@ -468,6 +759,12 @@ impl IndexedLogStore {
     /// not conflict with this.
     const MAGIC_CLEAR_NON_MASTER: &'static [u8] = b"CLRNM";
 
+    /// Magic bytes in `Log` that indicates "remove all high-level segments".
+    /// Like `MAGIC_CLEAR_NON_MASTER`, it is shorter than a Segment entry.
+    ///
+    /// Only written after a `FORMAT_CLEAR_HIGH_LEVEL` marker.
+    const MAGIC_CLEAR_HIGH_LEVEL: &'static [u8] = b"CLRHL";
+
     /// Magic bytes in `Log` that indicates this entry replaces a previous flat
     /// segment.
     ///
@@ -481,12 +778,41 @@ impl IndexedLogStore {
     /// `(level, head)` index.
     const MAGIC_REWRITE_LAST_FLAT: &'static [u8] = &[0xf0];
 
+    /// Magic bytes in `Log` that indicates the flat segment is removed.
+    ///
+    /// Format:
+    ///
+    /// ```plain,ignore
+    /// MAGIC_REMOVE_FLAT + SEGMENT
+    /// ```
+    ///
+    /// The `SEGMENT` is the removed segment. It is used to remove the segment
+    /// from indexes.
+    ///
+    /// The second byte is read as the level by index functions of readers
+    /// before format markers. It is not 0, so they skip the entry instead of
+    /// indexing garbage before the marker refuses the log.
+    const MAGIC_REMOVE_FLAT: &'static [u8] = &[0xf1, 0xff];
+
+    /// Magic bytes in `Log` that indicates the high-level segment is removed.
+    ///
+    /// Format:
+    ///
+    /// ```plain,ignore
+    /// MAGIC_REMOVE_HIGH_LEVEL + SEGMENT
+    /// ```
+    ///
+    /// Like `MAGIC_REMOVE_FLAT`, but only the `(level, head)` index refers
+    /// to high-level segments.
+    const MAGIC_REMOVE_HIGH_LEVEL: &'static [u8] = &[0xf3, 0xff];
+
     pub fn log_open_options() -> log::OpenOptions {
         log::OpenOptions::new()
             .create(true)
             .index("level-head", |data| {
                 // (level, high)
                 assert!(Self::MAGIC_CLEAR_NON_MASTER.len() < Segment::OFFSET_DELTA);
+                assert!(Self::MAGIC_CLEAR_HIGH_LEVEL.len() < Segment::OFFSET_DELTA);
                 assert!(Group::BITS == 8);
                 assert_ne!(
                     SegmentFlags::all().bits()
@@ -494,16 +820,49 @@ impl IndexedLogStore {
                     Self::MAGIC_REWRITE_LAST_FLAT[Segment::OFFSET_FLAGS],
                     "MAGIC_REWRITE_LAST_FLAT should not conflict with possible flags"
                 );
-                if data == Self::MAGIC_CLEAR_NON_MASTER {
+                assert_ne!(
+                    SegmentFlags::all().bits() & Self::MAGIC_REMOVE_FLAT[Segment::OFFSET_FLAGS],
+                    Self::MAGIC_REMOVE_FLAT[Segment::OFFSET_FLAGS],
+                    "MAGIC_REMOVE_FLAT should not conflict with possible flags"
+                );
+                assert_ne!(
+                    SegmentFlags::all().bits()
+                        & Self::MAGIC_REMOVE_HIGH_LEVEL[Segment::OFFSET_FLAGS],
+                    Self::MAGIC_REMOVE_HIGH_LEVEL[Segment::OFFSET_FLAGS],
+                    "MAGIC_REMOVE_HIGH_LEVEL should not conflict with possible flags"
+                );
+                let marker = format_marker(FORMAT_VERSION);
+                assert_ne!(
+                    SegmentFlags::all().bits() & marker[Segment::OFFSET_FLAGS],
+                    marker[Segment::OFFSET_FLAGS],
+                    "format markers should not conflict with possible flags"
+                );
+                if parse_format_marker(data).is_some() {
+                    Vec::new()
+                } else if data == Self::MAGIC_CLEAR_NON_MASTER {
                     let max_level = 255;
                     (0..=max_level)
-                        .map(|level| {
-                            log::IndexOutput::RemovePrefix(Box::new([
-                                level,
-                                Group::NON_MASTER.0 as u8,
-                            ]))
+                        .flat_map(|level| {
+                            Group::non_master().map(move |group| {
+                                log::IndexOutput::RemovePrefix(Box::new([level, group.0 as u8]))
+                            })
                         })
                         .collect()
+                } else if data == Self::MAGIC_CLEAR_HIGH_LEVEL {
+                    let max_level = 255;
+                    (1..=max_level)
+                        .map(|level| log::IndexOutput::RemovePrefix(Box::new([level])))
+                        .collect()
+                } else if data.starts_with(Self::MAGIC_REMOVE_FLAT) {
+                    // See MAGIC_REMOVE_FLAT for format.
+                    let start = Self::MAGIC_REMOVE_FLAT.len();
+                    let index = &data[start + Segment::OFFSET_LEVEL..start + Segment::OFFSET_DELTA];
+                    vec![log::IndexOutput::Remove(index.to_vec().into_boxed_slice())]
+                } else if data.starts_with(Self::MAGIC_REMOVE_HIGH_LEVEL) {
+                    // See MAGIC_REMOVE_HIGH_LEVEL for format.
+                    let start = Self::MAGIC_REMOVE_HIGH_LEVEL.len();
+                    let index = &data[start + Segment::OFFSET_LEVEL..start + Segment::OFFSET_DELTA];
+                    vec![log::IndexOutput::Remove(index.to_vec().into_boxed_slice())]
                 } else if data.starts_with(Self::MAGIC_REWRITE_LAST_FLAT) {
                     // See MAGIC_REWRITE_LAST_FLAT for format.
                     let start = Self::MAGIC_REWRITE_LAST_FLAT.len();
@@ -522,18 +881,47 @@ impl IndexedLogStore {
                     )]
                 }
             })
-            .index("group-parent", |data| {
-                //  child-group parent -> child for flat segments
-                //  ^^^^^^^^^^^ ^^^^^^
-                //  u8          u64 BE
+            .index("group-parent-child", |data| {
+                //  child-group parent child-low -> child for flat segments
+                //  ^^^^^^^^^^^ ^^^^^^ ^^^^^^^^^
+                //  u8          u64 BE u64 BE
                 //
                 //  The "child-group" prefix is used for invalidating index when
-                //  non-master Ids get re-assigned.
+                //  non-master Ids get re-assigned. The "child-low" suffix is
+                //  used for removing a single flat segment.
+                //
+                //  This index was "group-parent", without "child-low". The
+                //  rename makes logs written before get re-indexed on open.
+                if parse_format_marker(data).is_some() {
+                    return Vec::new();
+                }
+
                 if data == Self::MAGIC_CLEAR_NON_MASTER {
-                    // Invalidate child-group == 1 entries
-                    return vec![log::IndexOutput::RemovePrefix(Box::new([
-                        Group::NON_MASTER.0 as u8,
-                    ]))];
+                    // Invalidate child-group != 0 entries
+                    return Group::non_master()
+                        .map(|group| log::IndexOutput::RemovePrefix(Box::new([group.0 as u8])))
+                        .collect();
+                }
+
+                if data == Self::MAGIC_CLEAR_HIGH_LEVEL
+                    || data.starts_with(Self::MAGIC_REMOVE_HIGH_LEVEL)
+                {
+                    // Only flat segments are indexed.
+                    return Vec::new();
+                }
+
+                if data.starts_with(Self::MAGIC_REMOVE_FLAT) {
+                    let start = Self::MAGIC_REMOVE_FLAT.len();
+                    let seg = Segment(Bytes::copy_from_slice(&data[start..]));
+                    let mut result = Vec::new();
+                    if let (Ok(parents), Ok(span)) = (seg.parents(), seg.span()) {
+                        let group = span.low.group();
+                        for id in parents {
+                            let bytes = index_parent_child_key(group, id, span.low);
+                            result.push(log::IndexOutput::Remove(bytes.into()));
+                        }
+                    }
+                    return result;
                 }
 
                 if data.starts_with(Self::MAGIC_REWRITE_LAST_FLAT) {
@@ -569,23 +957,48 @@ impl IndexedLogStore {
                             "Cross-group segment is unexpected"
                         );
                         for id in parents {
-                            let bytes = index_parent_key(group, id);
+                            let bytes = index_parent_child_key(group, id, span.low);
                             result.push(log::IndexOutput::Owned(bytes.into()));
                         }
                     }
                 }
                 result
             })
-            .fold_def("cover", || Box::new(CoveredIdSetFold::default()))
+            // Not "cover", which readers before format markers use. They
+            // would trust a "cover" state that includes markers, instead of
+            // reading the markers and refusing the log.
+            .fold_def("covered-id-set", || Box::new(CoveredIdSetFold::default()))
+            .fold_def("format", || Box::new(FormatFold::default()))
+    }
+
+    /// Like `log_open_options`, with a bloom filter over the keys of the
+    /// parent index. The filter is persisted with the log. It speeds up
+    /// lookups of parents without children (ex. heads), at the cost of
+    /// some memory and disk space.
+    pub fn log_open_options_with_parent_bloom() -> log::OpenOptions {
+        Self::log_open_options().fold_def("parent-bloom", || Box::new(ParentBloomFold::default()))
     }
 
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, Self::log_open_options())
+    }
+
+    /// Open with the parent bloom filter. See
+    /// `log_open_options_with_parent_bloom`.
+    pub fn open_with_parent_bloom(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, Self::log_open_options_with_parent_bloom())
+    }
+
+    fn open_with_options(path: impl AsRef<Path>, opts: log::OpenOptions) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
-        let log = Self::log_open_options().open(path.clone())?;
+        let log = opts.open(path.clone())?;
+        let parent_bloom = Self::new_parent_bloom_counters(&log);
         let iddag = Self {
             log,
             path,
             cached_max_level: AtomicU8::new(MAX_LEVEL_UNKNOWN),
+            meter: Default::default(),
+            parent_bloom,
         };
         Ok(iddag)
     }
@@ -595,10 +1008,13 @@ impl IndexedLogStore {
         if log.iter_dirty().next().is_some() {
             return bug("open_from_clean_log got a dirty log");
         }
+        let parent_bloom = Self::new_parent_bloom_counters(&log);
         let iddag = Self {
             log,
             path,
             cached_max_level: AtomicU8::new(MAX_LEVEL_UNKNOWN),
+            meter: Default::default(),
+            parent_bloom,
         };
         Ok(iddag)
     }
@@ -609,6 +1025,8 @@ impl IndexedLogStore {
             log,
             path: self.path.clone(),
             cached_max_level: AtomicU8::new(self.cached_max_level.load(Acquire)),
+            meter: self.meter.clone(),
+            parent_bloom: self.parent_bloom.clone(),
         };
         Ok(store)
     }
@@ -619,16 +1037,58 @@ impl IndexedLogStore {
             log,
             path: self.path.clone(),
             cached_max_level: AtomicU8::new(MAX_LEVEL_UNKNOWN),
+            meter: self.meter.clone(),
+            parent_bloom: self.parent_bloom.clone(),
         };
         Ok(store)
     }
+
+    /// Statistics of the parent bloom filter. `None` if the log was not
+    /// opened with it.
+    pub fn parent_bloom_stats(&self) -> Option<ParentBloomStats> {
+        self.parent_bloom.as_ref().map(|counters| ParentBloomStats {
+            lookups: counters.lookups.load(Relaxed),
+            skipped: counters.skipped.load(Relaxed),
+            false_positives: counters.false_positives.load(Relaxed),
+        })
+    }
+
+    fn new_parent_bloom_counters(log: &log::Log) -> Option<Arc<ParentBloomCounters>> {
+        match log.fold(Self::FOLD_PARENT_BLOOM) {
+            Ok(fold) if fold.as_any().is::<ParentBloomFold>() => Some(Default::default()),
+            _ => None,
+        }
+    }
+
+    /// Check `key` of the parent index against the parent bloom filter.
+    /// Returns `false` if the key is not in the index. Always `true` without
+    /// the filter.
+    fn check_parent_bloom(&self, key: &[u8]) -> Result<bool> {
+        let counters = match &self.parent_bloom {
+            Some(counters) => counters,
+            None => return Ok(true),
+        };
+        let fold = self
+            .log
+            .fold(Self::FOLD_PARENT_BLOOM)?
+            .as_any()
+            .downcast_ref::<ParentBloomFold>()
+            .expect("should downcast to ParentBloomFold defined by OpenOptions");
+        counters.lookups.fetch_add(1, Relaxed);
+        if fold.filter.may_contain(key) {
+            Ok(true)
+        } else {
+            counters.skipped.fetch_add(1, Relaxed);
+            Ok(false)
+        }
+    }
 }
 
 fn default_next_free_ids_without_dirty() -> (Id, Id) {
     (Group::MASTER.min_id(), Group::NON_MASTER.min_id())
 }
 
-// Build index key for the INDEX_PARENT (group-parent) index.
+// Build index key prefix for the INDEX_PARENT (group-parent-child) index.
 fn index_parent_key(group: Group, id: Id) -> [u8; 9] {
     let mut result = [0u8; 9];
     debug_assert!(group.0 <= 0xff);
@@ -637,6 +1097,14 @@ fn index_parent_key(group: Group, id: Id) -> [u8; 9] {
     result
 }
 
+// Build index key for the INDEX_PARENT (group-parent-child) index.
+fn index_parent_child_key(group: Group, id: Id, child_low: Id) -> [u8; 17] {
+    let mut result = [0u8; 17];
+    result[..9].copy_from_slice(&index_parent_key(group, id));
+    result[9..].copy_from_slice(&child_low.0.to_be_bytes());
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -736,6 +1204,162 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_format_marker() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut iddag = IndexedLogStore::open(tmp.path())?;
+        let locked = iddag.lock()?;
+
+        // No markers without cleared high-level segments.
+        let seg1 = Segment::new(SegmentFlags::HAS_ROOT, 0, Id(0), Id(5), &[]);
+        let seg2 = Segment::new(SegmentFlags::HAS_ROOT, 1, Id(0), Id(5), &[]);
+        iddag.insert_segment(seg1)?;
+        iddag.insert_segment(seg2)?;
+        iddag.persist(&locked)?;
+        assert_eq!(iddag.format_version()?, 1);
+        assert_eq!(iddag.log.iter().count(), 2);
+
+        // A marker is written before clearing high-level segments.
+        iddag.remove_high_level_segments()?;
+        iddag.persist(&locked)?;
+        assert_eq!(iddag.format_version()?, FORMAT_CLEAR_HIGH_LEVEL);
+        let entries = iddag.log.iter().collect::<indexedlog::Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            parse_format_marker(entries[2]),
+            Some(FORMAT_CLEAR_HIGH_LEVEL)
+        );
+
+        // Readers before markers fail to parse it as a segment.
+        let marker = Segment(Bytes::copy_from_slice(entries[2]));
+        assert!(marker.span().is_err());
+
+        // Markers are skipped by indexes and folds.
+        drop(locked);
+        let iddag = IndexedLogStore::open(tmp.path())?;
+        assert_eq!(iddag.format_version()?, FORMAT_CLEAR_HIGH_LEVEL);
+        assert_eq!(
+            dbg(iddag.all_ids_in_groups(&[Group::MASTER])?),
+            dbg(IdSet::from(Id(0)..=Id(5)))
+        );
+        assert_eq!(iddag.max_level()?, 0);
+
+        // Newer formats are refused.
+        let mut log = log::OpenOptions::new().open(tmp.path())?;
+        log.append(format_marker(FORMAT_VERSION + 1))?;
+        log.sync()?;
+        let err = IndexedLogStore::open(tmp.path()).err().unwrap();
+        assert!(err.to_string().contains("newer than supported"), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_store_before_remove_flat() -> Result<()> {
+        // A log written before MAGIC_REMOVE_FLAT, with its "group-parent"
+        // index keyed by (child-group, parent).
+        let tmp = tempfile::tempdir()?;
+        let old_open_options = || {
+            log::OpenOptions::new()
+                .create(true)
+                .index("level-head", |_| {
+                    vec![log::IndexOutput::Reference(
+                        Segment::OFFSET_LEVEL as u64..Segment::OFFSET_DELTA as u64,
+                    )]
+                })
+                .index("group-parent", |data| {
+                    let seg = Segment(Bytes::copy_from_slice(data));
+                    let mut result = Vec::new();
+                    if seg.level().ok() == Some(0) {
+                        let span = seg.span().unwrap();
+                        for id in seg.parents().unwrap() {
+                            let key = index_parent_key(span.low.group(), id);
+                            result.push(log::IndexOutput::Owned(key.into()));
+                        }
+                    }
+                    result
+                })
+        };
+        let mut log = old_open_options().open(tmp.path())?;
+        let seg1 = Segment::new(SegmentFlags::HAS_ROOT, 0, Id(0), Id(5), &[]);
+        let seg2 = Segment::new(SegmentFlags::empty(), 0, Id(6), Id(10), &[Id(3)]);
+        let seg3 = Segment::new(SegmentFlags::empty(), 0, Id(11), Id(12), &[Id(3)]);
+        for seg in [&seg1, &seg2, &seg3] {
+            log.append(&seg.0)?;
+        }
+        log.sync()?;
+
+        // The "group-parent-child" index is built on open.
+        let mut iddag = IndexedLogStore::open(tmp.path())?;
+        assert_eq!(iddag.format_version()?, 1);
+        assert_eq!(
+            dbg_iter(iddag.iter_flat_segments_with_parent(Id(3))?),
+            "[6-x[3], 11-x[3]]"
+        );
+
+        // Removing a flat segment uses the new keys, and requires a newer
+        // format. Old index functions skip both entries.
+        let locked = iddag.lock()?;
+        iddag.remove_flat_segment(&seg3)?;
+        iddag.persist(&locked)?;
+        assert_eq!(iddag.format_version()?, FORMAT_REMOVE_FLAT);
+        let iddag = IndexedLogStore::open(tmp.path())?;
+        assert_eq!(
+            dbg_iter(iddag.iter_flat_segments_with_parent(Id(3))?),
+            "[6-x[3]]"
+        );
+        assert_eq!(
+            dbg(iddag.all_ids_in_groups(&[Group::MASTER])?),
+            dbg(IdSet::from(Id(0)..=Id(10)))
+        );
+        let log = old_open_options().open(tmp.path())?;
+        let key = index_parent_key(Group::MASTER, Id(3));
+        assert_eq!(log.lookup(1, key)?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_marker_hidden_group() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut iddag = IndexedLogStore::open(tmp.path())?;
+        let locked = iddag.lock()?;
+
+        // No markers without HIDDEN segments.
+        let seg1 = Segment::new(SegmentFlags::HAS_ROOT, 0, Id(0), Id(5), &[]);
+        iddag.insert_segment(seg1)?;
+        iddag.persist(&locked)?;
+        assert_eq!(iddag.format_version()?, 1);
+        assert_eq!(iddag.log.iter().count(), 1);
+
+        // A marker is written before the first HIDDEN segment.
+        let low = Group::HIDDEN.min_id();
+        let seg2 = Segment::new(SegmentFlags::HAS_ROOT, 0, low, low + 2, &[]);
+        let seg3 = Segment::new(SegmentFlags::empty(), 0, low + 3, low + 4, &[low]);
+        iddag.insert_segment(seg2)?;
+        iddag.insert_segment(seg3)?;
+        iddag.persist(&locked)?;
+        assert_eq!(iddag.format_version()?, FORMAT_HIDDEN_GROUP);
+        let entries = iddag.log.iter().collect::<indexedlog::Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(parse_format_marker(entries[1]), Some(FORMAT_HIDDEN_GROUP));
+
+        // The marker is skipped by indexes and folds.
+        drop(locked);
+        let iddag = IndexedLogStore::open(tmp.path())?;
+        assert_eq!(iddag.format_version()?, FORMAT_HIDDEN_GROUP);
+        assert_eq!(
+            dbg(iddag.all_ids_in_groups(&[Group::HIDDEN])?),
+            dbg(IdSet::from(low..=(low + 4)))
+        );
+        assert_eq!(
+            dbg_iter(iddag.iter_flat_segments_with_parent(low)?),
+            format!("[{:?}-x[{:?}]]", low + 3, low)
+        );
+
+        Ok(())
+    }
+
     fn dbg_iter<'a, T: std::fmt::Debug>(iter: Box<dyn Iterator<Item = Result<T>> + 'a>) -> String {
         let v = iter.map(|s| s.unwrap()).collect::<Vec<_>>();
         dbg(v)
@@ -25,7 +25,6 @@ mod mem_idmap;
 
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use indexedlog_idmap::IdMap;
-pub(crate) use mem_idmap::CoreMemIdMap;
 pub use mem_idmap::MemIdMap;
 
 /// DAG-aware write operations.
@@ -200,10 +199,18 @@ pub trait IdMapAssignHead: IdConvert + IdMapWrite {
 
 impl<T> IdMapAssignHead for T where T: IdConvert + IdMapWrite {}
 
+/// Enumerate mappings stored locally.
+pub trait IdMapEntries {
+    /// Return `(id, name)` pairs with ids in `low..=high`, sorted by id.
+    fn local_entries_in_range(&self, low: Id, high: Id) -> Result<Vec<(Id, VertexName)>>;
+}
+
 /// Write operations for IdMap.
 #[async_trait::async_trait]
 pub trait IdMapWrite {
     async fn insert(&mut self, id: Id, name: &[u8]) -> Result<()>;
+    /// Remove mappings of ids in `low..=high`. Return the removed names.
+    async fn remove_range(&mut self, low: Id, high: Id) -> Result<Vec<VertexName>>;
     async fn remove_non_master(&mut self) -> Result<()>;
     async fn need_rebuild_non_master(&self) -> bool;
 }
@@ -290,4 +297,49 @@ mod tests {
 "#
         );
     }
+
+    #[cfg(all(test, feature = "indexedlog-backend"))]
+    #[test]
+    fn test_remove_range() {
+        let dir = tempdir().unwrap();
+        let mut map = IdMap::open(dir.path()).unwrap();
+        for (i, name) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            map.insert(Id(i as u64), &name[..]).unwrap();
+        }
+        let has_marker = |map: &IdMap| map.log.iter().any(|e| e.unwrap() == b"FMT\x02");
+        assert!(!has_marker(&map));
+        let removed = map.remove_range(Id(1), Id(2)).unwrap();
+        // Older readers are refused by a format marker.
+        assert!(has_marker(&map));
+        assert_eq!(
+            removed,
+            [VertexName::from(&b"b"[..]), VertexName::from(&b"c"[..])]
+        );
+        assert!(map.find_name_by_id(Id(1)).unwrap().is_none());
+        assert!(map.find_id_by_name(b"c").unwrap().is_none());
+        assert_eq!(map.find_id_by_name(b"d").unwrap(), Some(Id(3)));
+        assert_eq!(
+            map.local_entries_in_range(Id::MIN, Id(10)).unwrap(),
+            [
+                (Id(0), VertexName::from(&b"a"[..])),
+                (Id(3), VertexName::from(&b"d"[..]))
+            ]
+        );
+
+        // Removed names can be inserted again.
+        map.remove_range(Id(3), Id(3)).unwrap();
+        assert_eq!(map.next_free_id(Group::MASTER).unwrap(), Id(1));
+        map.insert(Id(1), b"d").unwrap();
+        assert_eq!(format!("{:?}", &map), "IdMap {\n  a: 0,\n  d: 1,\n}\n");
+
+        // Newer formats are refused.
+        drop(map);
+        let mut log = indexedlog::log::OpenOptions::new()
+            .open(dir.path())
+            .unwrap();
+        log.append(b"FMT\x03").unwrap();
+        log.sync().unwrap();
+        let err = IdMap::open(dir.path()).err().unwrap();
+        assert!(err.to_string().contains("newer than supported"), "{}", err);
+    }
 }
@@ -5,9 +5,11 @@
  * GNU General Public License version 2.
  */
 
+use std::any::Any;
 use std::fmt;
 use std::fs::File;
 use std::fs::{self};
+use std::io;
 use std::io::Cursor;
 use std::io::Read;
 use std::path::Path;
@@ -19,7 +21,9 @@ use byteorder::BigEndian;
 use byteorder::ReadBytesExt;
 use fs2::FileExt;
 use indexedlog::log;
+use indexedlog::log::Fold;
 
+use super::IdMapEntries;
 use super::IdMapWrite;
 use crate::errors::bug;
 use crate::errors::programming;
@@ -34,6 +38,63 @@ use crate::ops::TryClone;
 use crate::Result;
 use crate::VerLink;
 
+/// Format version of the `IdMap` log.
+///
+/// Entries that older readers would misread are only written after a format
+/// marker (see `IdMap::MAGIC_FORMAT_PREFIX`) of the version that introduced
+/// them. Readers refuse markers of versions newer than `FORMAT_VERSION`.
+/// Readers that predate markers fail to index them, as they are shorter than
+/// a valid entry, and refuse them too.
+///
+/// - 1: No markers.
+/// - 2: Removed mappings (`IdMap::MAGIC_DELETION_PREFIX`).
+const FORMAT_VERSION: u8 = 2;
+
+/// The format version that introduced removed mappings.
+const FORMAT_REMOVE: u8 = 2;
+
+/// Fold that tracks the newest format marker in `log`, and refuses markers
+/// newer than `FORMAT_VERSION`. See `FORMAT_VERSION`.
+#[derive(Debug, Clone, Default)]
+struct FormatFold {
+    // 0 if there are no markers.
+    version: u8,
+}
+
+impl Fold for FormatFold {
+    fn load(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.version = mincode::deserialize(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(())
+    }
+
+    fn dump(&self) -> io::Result<Vec<u8>> {
+        mincode::serialize(&self.version).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn accumulate(&mut self, data: &[u8]) -> indexedlog::Result<()> {
+        if let Some(version) = IdMap::parse_format_marker(data) {
+            if version > FORMAT_VERSION {
+                let message = format!(
+                    "IdMap format {} is newer than supported ({})",
+                    version, FORMAT_VERSION
+                );
+                return Err(message.as_str().into());
+            }
+            self.version = self.version.max(version);
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_boxed(&self) -> Box<dyn Fold> {
+        Box::new(self.clone())
+    }
+}
+
 /// Bi-directional mapping between an integer id and a name (`[u8]`).
 ///
 /// Backed by the filesystem.
@@ -49,12 +110,24 @@ pub struct IdMap {
 impl IdMap {
     const INDEX_ID_TO_NAME: usize = 0;
     const INDEX_GROUP_NAME_TO_ID: usize = 1;
+    const FOLD_FORMAT: usize = 0;
 
     /// Magic bytes in `Log` that indicates "remove all non-master id->name
     /// mappings". A valid entry has at least 8 bytes so does not conflict
     /// with this.
     const MAGIC_CLEAR_NON_MASTER: &'static [u8] = b"CLRNM";
 
+    /// Magic prefix in `Log` that indicates "remove the id->name mapping
+    /// that follows". The first byte of a valid entry is a group, which is
+    /// never 0xff, so this does not conflict with valid entries.
+    ///
+    /// Only written after a `FORMAT_REMOVE` marker.
+    const MAGIC_DELETION_PREFIX: &'static [u8] = b"\xffD";
+
+    /// Magic prefix in `Log` of a format marker, followed by the version.
+    /// Like `MAGIC_CLEAR_NON_MASTER`, it is shorter than a valid entry.
+    const MAGIC_FORMAT_PREFIX: &'static [u8] = b"FMT";
+
     /// Start offset in an entry for "name".
     const NAME_OFFSET: usize = 8 + Group::BYTES;
 
@@ -102,12 +175,19 @@ impl IdMap {
             .create(true)
             .index("id", |data| {
                 assert!(Self::MAGIC_CLEAR_NON_MASTER.len() < 8);
+                assert!(Self::MAGIC_FORMAT_PREFIX.len() + 1 < 8);
                 assert!(Group::BITS == 8);
-                if data.len() < 8 {
+                if Self::parse_format_marker(data).is_some() {
+                    Vec::new()
+                } else if data.starts_with(Self::MAGIC_DELETION_PREFIX) {
+                    let start = Self::MAGIC_DELETION_PREFIX.len();
+                    let key = &data[start..start + 8];
+                    vec![log::IndexOutput::Remove(key.to_vec().into_boxed_slice())]
+                } else if data.len() < 8 {
                     if data == Self::MAGIC_CLEAR_NON_MASTER {
-                        vec![log::IndexOutput::RemovePrefix(Box::new([
-                            Group::NON_MASTER.0 as u8,
-                        ]))]
+                        Group::non_master()
+                            .map(|group| log::IndexOutput::RemovePrefix(Box::new([group.0 as u8])))
+                            .collect()
                     } else {
                         panic!("bug: invalid segment {:?}", &data);
                     }
@@ -116,23 +196,69 @@ impl IdMap {
                 }
             })
             .index("group-name", |data| {
-                if data.len() >= 8 {
+                if Self::parse_format_marker(data).is_some() {
+                    Vec::new()
+                } else if data.starts_with(Self::MAGIC_DELETION_PREFIX) {
+                    let start = Self::MAGIC_DELETION_PREFIX.len() + 8;
+                    let key = &data[start..];
+                    vec![log::IndexOutput::Remove(key.to_vec().into_boxed_slice())]
+                } else if data.len() >= 8 {
                     vec![log::IndexOutput::Reference(8..(data.len() as u64))]
                 } else {
                     if data == Self::MAGIC_CLEAR_NON_MASTER {
-                        vec![log::IndexOutput::RemovePrefix(Box::new([
-                            Group::NON_MASTER.0 as u8,
-                        ]))]
+                        Group::non_master()
+                            .map(|group| log::IndexOutput::RemovePrefix(Box::new([group.0 as u8])))
+                            .collect()
                     } else {
                         panic!("bug: invalid segment {:?}", &data);
                     }
                 }
             })
+            .fold_def("format", || Box::new(FormatFold::default()))
             .flush_filter(Some(|_, _| {
                 panic!("programming error: idmap changed by other process")
             }))
     }
 
+    /// The format marker of `version`.
+    fn format_marker(version: u8) -> Vec<u8> {
+        let mut marker = Self::MAGIC_FORMAT_PREFIX.to_vec();
+        marker.push(version);
+        marker
+    }
+
+    /// The version of a format marker, or `None` if `data` is not a marker.
+    fn parse_format_marker(data: &[u8]) -> Option<u8> {
+        if data.len() == Self::MAGIC_FORMAT_PREFIX.len() + 1
+            && data.starts_with(Self::MAGIC_FORMAT_PREFIX)
+        {
+            Some(data[Self::MAGIC_FORMAT_PREFIX.len()])
+        } else {
+            None
+        }
+    }
+
+    /// Append a format marker of `version`, unless the log has one of
+    /// `version` or newer. Must be called before appending entries that
+    /// readers before `version` would misread.
+    fn require_format(&mut self, version: u8) -> Result<()> {
+        if self.format_version()? < version {
+            self.log.append(Self::format_marker(version))?;
+        }
+        Ok(())
+    }
+
+    /// The newest format marker in the log, or 1 if there are no markers.
+    fn format_version(&self) -> Result<u8> {
+        let fold = self
+            .log
+            .fold(Self::FOLD_FORMAT)?
+            .as_any()
+            .downcast_ref::<FormatFold>()
+            .expect("should downcast to FormatFold defined by OpenOptions");
+        Ok(fold.version.max(1))
+    }
+
     /// Find name by a specified integer id.
     pub fn find_name_by_id(&self, id: Id) -> Result<Option<&[u8]>> {
         let key = id.0.to_be_bytes();
@@ -285,6 +411,31 @@ impl IdMap {
         Ok(result)
     }
 
+    /// Remove mappings of ids in `low..=high`. Return the removed names.
+    pub fn remove_range(&mut self, low: Id, high: Id) -> Result<Vec<VertexName>> {
+        let entries = self.local_entries_in_range(low, high)?;
+        if !entries.is_empty() {
+            self.require_format(FORMAT_REMOVE)?;
+        }
+        for (id, name) in &entries {
+            let mut data = Vec::with_capacity(
+                Self::MAGIC_DELETION_PREFIX.len() + Self::NAME_OFFSET + name.as_ref().len(),
+            );
+            data.extend_from_slice(Self::MAGIC_DELETION_PREFIX);
+            data.extend_from_slice(&id.0.to_be_bytes());
+            data.extend_from_slice(&id.group().bytes());
+            data.extend_from_slice(name.as_ref());
+            self.log.append(data)?;
+        }
+        if !entries.is_empty() {
+            // Removal is not an append-only change.
+            self.map_version = VerLink::new();
+            // Invalidate the next free id cache.
+            self.cached_next_free_ids = Default::default();
+        }
+        Ok(entries.into_iter().map(|(_, name)| name).collect())
+    }
+
     // Find an unused id that is bigger than existing ids.
     // Used internally. It should match `next_free_id`.
     fn get_next_free_id(log: &log::Log, group: Group) -> Result<Id> {
@@ -311,10 +462,17 @@ impl fmt::Debug for IdMap {
         write!(f, "IdMap {{\n")?;
         for data in self.log.iter() {
             if let Ok(mut data) = data {
+                if data.len() < 8 || data.starts_with(Self::MAGIC_DELETION_PREFIX) {
+                    continue;
+                }
                 let id = data.read_u64::<BigEndian>().unwrap();
                 let _group = data.read_u8().unwrap();
                 let mut name = Vec::with_capacity(20);
                 data.read_to_end(&mut name).unwrap();
+                // Skip removed mappings.
+                if self.find_name_by_id(Id(id)).ok().flatten() != Some(&name[..]) {
+                    continue;
+                }
                 let name = if name.len() >= 20 {
                     VertexName::from(name).to_hex()
                 } else {
@@ -373,19 +531,43 @@ impl IdConvert for IdMap {
     }
 }
 
+impl IdMapEntries for IdMap {
+    fn local_entries_in_range(&self, low: Id, high: Id) -> Result<Vec<(Id, VertexName)>> {
+        let low = low.to_bytearray();
+        let high = high.to_bytearray();
+        let range = &low[..]..=&high[..];
+        let mut entries = Vec::new();
+        for item in self.log.lookup_range(Self::INDEX_ID_TO_NAME, range)? {
+            let (key, mut values) = item?;
+            let id = Id(Cursor::new(key).read_u64::<BigEndian>()?);
+            if let Some(entry) = values.next() {
+                let entry = entry?;
+                let name = VertexName(self.log.slice_to_bytes(&entry[Self::NAME_OFFSET..]));
+                entries.push((id, name));
+            }
+        }
+        Ok(entries)
+    }
+}
+
 #[async_trait::async_trait]
 impl IdMapWrite for IdMap {
     async fn insert(&mut self, id: Id, name: &[u8]) -> Result<()> {
         IdMap::insert(self, id, name)
     }
+    async fn remove_range(&mut self, low: Id, high: Id) -> Result<Vec<VertexName>> {
+        IdMap::remove_range(self, low, high)
+    }
     async fn remove_non_master(&mut self) -> Result<()> {
         self.log.append(IdMap::MAGIC_CLEAR_NON_MASTER)?;
         self.map_version = VerLink::new();
         self.need_rebuild_non_master = false;
         // Invalidate the next free id cache.
         self.cached_next_free_ids = Default::default();
-        if self.next_free_id(Group::NON_MASTER)? != Group::NON_MASTER.min_id() {
-            return bug("remove_non_master did not take effect");
+        for group in Group::non_master() {
+            if self.next_free_id(group)? != group.min_id() {
+                return bug("remove_non_master did not take effect");
+            }
         }
         Ok(())
     }
@@ -10,6 +10,7 @@ use std::collections::HashMap;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::{self};
 
+use super::IdMapEntries;
 use super::IdMapWrite;
 use crate::errors::NotFoundError;
 use crate::id::Group;
@@ -57,10 +58,9 @@ impl Clone for MemIdMap {
             core: self.core.clone(),
             map_id: self.map_id.clone(),
             map_version: self.map_version.clone(),
-            cached_next_free_ids: [
-                AtomicU64::new(self.cached_next_free_ids[0].load(atomic::Ordering::SeqCst)),
-                AtomicU64::new(self.cached_next_free_ids[1].load(atomic::Ordering::SeqCst)),
-            ],
+            cached_next_free_ids: Group::ALL.map(|group| {
+                AtomicU64::new(self.cached_next_free_ids[group.0].load(atomic::Ordering::SeqCst))
+            }),
         }
     }
 }
@@ -105,6 +105,25 @@ impl CoreMemIdMap {
         self.name2id.insert(vertex_name.clone(), id);
         self.id2name.insert(id, vertex_name);
     }
+
+    pub fn entries_in_range(&self, low: Id, high: Id) -> Vec<(Id, VertexName)> {
+        let mut entries: Vec<(Id, VertexName)> = self
+            .id2name
+            .iter()
+            .filter(|(&id, _)| id >= low && id <= high)
+            .map(|(&id, name)| (id, name.clone()))
+            .collect();
+        entries.sort_unstable_by_key(|(id, _)| *id);
+        entries
+    }
+
+    pub fn remove_vertex_id_name(&mut self, id: Id) -> Option<VertexName> {
+        let name = self.id2name.remove(&id)?;
+        if self.name2id.get(&name) == Some(&id) {
+            self.name2id.remove(&name);
+        }
+        Some(name)
+    }
 }
 
 #[async_trait::async_trait]
@@ -160,6 +179,12 @@ impl IdConvert for MemIdMap {
     }
 }
 
+impl IdMapEntries for MemIdMap {
+    fn local_entries_in_range(&self, low: Id, high: Id) -> Result<Vec<(Id, VertexName)>> {
+        Ok(self.core.entries_in_range(low, high))
+    }
+}
+
 // TODO: Reconsider re-assign master cases. Currently they are ignored.
 #[async_trait::async_trait]
 impl IdMapWrite for MemIdMap {
@@ -176,6 +201,16 @@ impl IdMapWrite for MemIdMap {
         self.map_version.bump();
         Ok(())
     }
+    async fn remove_range(&mut self, low: Id, high: Id) -> Result<Vec<VertexName>> {
+        let mut names = Vec::new();
+        for (id, _) in self.core.entries_in_range(low, high) {
+            names.extend(self.core.remove_vertex_id_name(id));
+        }
+        if !names.is_empty() {
+            self.map_version = VerLink::new();
+        }
+        Ok(names)
+    }
     async fn remove_non_master(&mut self) -> Result<()> {
         self.map_version = VerLink::new();
         Ok(())
@@ -7,14 +7,21 @@
 
 //! Integrity checks.
 
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hasher;
 
 use futures::StreamExt;
 use futures::TryStreamExt;
+use twox_hash::XxHash64;
 
+use crate::errors::bug;
 use crate::iddag::IdDag;
 use crate::iddagstore::IdDagStore;
 use crate::idmap::IdMapAssignHead;
+use crate::idmap::IdMapEntries;
 use crate::namedag::AbstractNameDag;
 use crate::nameset::NameSet;
 use crate::ops::CheckIntegrity;
@@ -22,12 +29,31 @@ use crate::ops::DagAlgorithm;
 use crate::ops::IdConvert;
 use crate::ops::Persist;
 use crate::ops::TryClone;
+use crate::segment::FlatSegment;
+use crate::segment::PreparedFlatSegments;
 use crate::segment::SegmentFlags;
+use crate::CloneData;
+use crate::Error;
 use crate::Group;
 use crate::Id;
+use crate::IdSet;
 use crate::Result;
 use crate::VertexName;
 
+/// A problem found by `check_consistency`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsistencyProblem {
+    /// The id is covered by segments, but has no name.
+    MissingName(Id),
+
+    /// The name maps to an id that is not covered by segments.
+    OrphanedName(Id, VertexName),
+
+    /// The id maps to the name, but the name maps to another id, or to
+    /// nothing.
+    MismatchedName(Id, VertexName, Option<Id>),
+}
+
 #[async_trait::async_trait]
 impl<IS, M, P, S> CheckIntegrity for AbstractNameDag<IdDag<IS>, M, P, S>
 where
@@ -287,3 +313,205 @@ where
         Ok(problems)
     }
 }
+
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore + Persist + 'static,
+    IdDag<IS>: TryClone,
+    M: TryClone + IdMapAssignHead + IdMapEntries + Persist + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Persist + Send + Sync + 'static,
+{
+    /// Cross-check the IdMap with the IdDag segments.
+    ///
+    /// Every id covered by segments should have a name, unless the graph is
+    /// lazy and the id is in the master group but not universally known.
+    /// Every name should map to an id covered by segments, and back.
+    ///
+    /// No problems indicates the IdMap and the IdDag are consistent.
+    pub async fn check_consistency(&self) -> Result<Vec<ConsistencyProblem>> {
+        let mut problems = Vec::new();
+        let lazy = self.is_vertex_lazy();
+        for &group in Group::ALL.iter() {
+            let covered = self.dag.all_ids_in_groups(&[group])?;
+            let entries = self
+                .map
+                .local_entries_in_range(group.min_id(), group.max_id())?;
+            tracing::debug!(
+                "checking {} ids and {} names in group {}",
+                covered.count(),
+                entries.len(),
+                group
+            );
+
+            let named = IdSet::from_spans(entries.iter().map(|(id, _)| *id));
+            let mut missing = covered.difference(&named);
+            if lazy && group == Group::MASTER {
+                let universal = IdSet::from_spans(self.dag.universal_ids()?);
+                missing = missing.intersection(&universal);
+            }
+            let mut group_problems: Vec<(Id, ConsistencyProblem)> = missing
+                .iter()
+                .map(|id| (id, ConsistencyProblem::MissingName(id)))
+                .collect();
+
+            for (id, name) in entries {
+                let problem = if !covered.contains(id) {
+                    ConsistencyProblem::OrphanedName(id, name)
+                } else {
+                    match self.map.vertex_id(name.clone()).await {
+                        Ok(name_id) if name_id == id => continue,
+                        Ok(name_id) => ConsistencyProblem::MismatchedName(id, name, Some(name_id)),
+                        Err(Error::VertexNotFound(_)) => {
+                            ConsistencyProblem::MismatchedName(id, name, None)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                };
+                group_problems.push((id, problem));
+            }
+
+            group_problems.sort_by_key(|(id, _)| *id);
+            problems.extend(group_problems.into_iter().map(|(_, p)| p));
+        }
+        Ok(problems)
+    }
+
+    /// Export all vertexes as flat segments, with ids assigned in an order
+    /// that only depends on the graph. Two graphs with the same vertexes and
+    /// parents export the same `CloneData`, however their own ids and
+    /// segments were assigned, which makes them easy to diff.
+    ///
+    /// Ids are assigned depth-first from heads sorted by name, visiting
+    /// parents in order before their children, all in the master group.
+    /// Unlike `export_clone_data`, `idmap` has the names of all vertexes.
+    pub async fn canonical_flat_segments(&self) -> Result<CloneData<VertexName>> {
+        let vertexes = self.all_vertexes_with_parents().await?;
+
+        let parent_ids: HashSet<Id> = vertexes
+            .values()
+            .flat_map(|(_, parents)| parents.iter().copied())
+            .collect();
+        let mut heads: Vec<Id> = vertexes
+            .keys()
+            .filter(|id| !parent_ids.contains(id))
+            .copied()
+            .collect();
+        heads.sort_unstable_by(|a, b| vertexes[a].0.cmp(&vertexes[b].0));
+
+        let mut canonical_ids: HashMap<Id, Id> = HashMap::with_capacity(vertexes.len());
+        let mut order: Vec<Id> = Vec::with_capacity(vertexes.len());
+        for head in heads {
+            let mut to_visit = vec![(head, false)];
+            while let Some((id, parents_visited)) = to_visit.pop() {
+                if canonical_ids.contains_key(&id) {
+                    continue;
+                }
+                if parents_visited {
+                    canonical_ids.insert(id, Id(order.len() as u64));
+                    order.push(id);
+                } else {
+                    to_visit.push((id, true));
+                    for &parent in vertexes[&id].1.iter().rev() {
+                        if !canonical_ids.contains_key(&parent) {
+                            to_visit.push((parent, false));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut segments: Vec<FlatSegment> = Vec::new();
+        let mut idmap = HashMap::with_capacity(order.len());
+        for (id, old_id) in order.iter().enumerate() {
+            let id = Id(id as u64);
+            let (name, parents) = &vertexes[old_id];
+            let parents: Vec<Id> = parents.iter().map(|p| canonical_ids[p]).collect();
+            idmap.insert(id, name.clone());
+            match segments.last_mut() {
+                Some(seg) if parents.len() == 1 && parents[0] + 1 == id => seg.high = id,
+                _ => segments.push(FlatSegment {
+                    low: id,
+                    high: id,
+                    parents,
+                }),
+            }
+        }
+
+        Ok(CloneData {
+            flat_segments: PreparedFlatSegments { segments },
+            idmap,
+        })
+    }
+
+    /// Hash the (vertex, parents) pairs of all vertexes, independently of how
+    /// ids were assigned. Replicas of the same graph have the same
+    /// fingerprint. The hash is stable, but not cryptographic.
+    pub async fn graph_fingerprint(&self) -> Result<u64> {
+        let vertexes = self.all_vertexes_with_parents().await?;
+        let mut rows: Vec<(&VertexName, &[Id])> = vertexes
+            .values()
+            .map(|(name, parents)| (name, parents.as_slice()))
+            .collect();
+        rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        let mut hasher = XxHash64::with_seed(0);
+        let write_bytes = |hasher: &mut XxHash64, bytes: &[u8]| {
+            hasher.write(&(bytes.len() as u64).to_le_bytes());
+            hasher.write(bytes);
+        };
+        for (name, parents) in rows {
+            write_bytes(&mut hasher, name.as_ref());
+            hasher.write(&(parents.len() as u64).to_le_bytes());
+            for parent in parents {
+                write_bytes(&mut hasher, vertexes[parent].0.as_ref());
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Names and parents of all vertexes, by id. Names of lazy vertexes are
+    /// resolved remotely.
+    async fn all_vertexes_with_parents(&self) -> Result<BTreeMap<Id, (VertexName, Vec<Id>)>> {
+        let mut parents: Vec<(Id, Vec<Id>)> = Vec::new();
+        for seg in self.dag.iter_segments_ascending(Id::MIN, 0)? {
+            let seg = seg?;
+            let span = seg.span()?;
+            parents.push((span.low, seg.parents()?));
+            for id in span.low.to(span.high).skip(1) {
+                parents.push((id, vec![id - 1]));
+            }
+        }
+        let ids: Vec<Id> = parents.iter().map(|(id, _)| *id).collect();
+        let names = self.vertex_name_batch(&ids).await?;
+        let mut vertexes = BTreeMap::new();
+        for ((id, parents), name) in parents.into_iter().zip(names) {
+            vertexes.insert(id, (name?, parents));
+        }
+        for (id, (_, parents)) in &vertexes {
+            if let Some(p) = parents.iter().find(|p| !vertexes.contains_key(p)) {
+                return bug(format!("parent {:?} of {:?} is not in the graph", p, id));
+            }
+        }
+        Ok(vertexes)
+    }
+
+    /// Check consistency like `check_consistency`, and remove orphaned
+    /// names from the IdMap on disk. Other problems are not repaired.
+    ///
+    /// Returns the problems found, including the repaired ones.
+    pub async fn repair_consistency(&mut self) -> Result<Vec<ConsistencyProblem>> {
+        self.check_writable("repair_consistency")?;
+        let (lock, map_lock, dag_lock) = self.reload()?;
+        let problems = self.check_consistency().await?;
+        for problem in &problems {
+            if let ConsistencyProblem::OrphanedName(id, name) = problem {
+                tracing::debug!("removing orphaned name {:?} ({:?})", name, id);
+                self.map.remove_range(*id, *id).await?;
+            }
+        }
+        self.persist(lock, map_lock, dag_lock)?;
+        self.invalidate_snapshot();
+        Ok(problems)
+    }
+}
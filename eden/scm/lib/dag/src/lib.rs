@@ -13,8 +13,10 @@
 //! Building blocks for the commit graph used by source control.
 
 mod bsearch;
+mod budget;
 mod default_impl;
 mod delegate;
+pub mod dump;
 pub mod errors;
 mod fmt;
 mod iddag;
@@ -31,6 +33,9 @@ mod spanset;
 pub mod utils;
 mod verlink;
 
+pub use budget::BudgetExceeded;
+pub use budget::BudgetResource;
+pub use budget::QueryBudget;
 pub use dag_types::clone;
 pub use dag_types::id;
 pub use dag_types::CloneData;
@@ -42,6 +47,7 @@ pub use iddag::FirstAncestorConstraint;
 pub use iddag::IdDag;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use idmap::IdMap;
+pub use integrity::ConsistencyProblem;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use namedag::NameDag;
 pub use nameset::NameSet;
@@ -68,6 +74,8 @@ pub type Vertex = VertexName;
 
 #[cfg(feature = "indexedlog-backend")]
 pub use iddagstore::indexedlog_store::describe_indexedlog_entry;
+#[cfg(feature = "indexedlog-backend")]
+pub use iddagstore::indexedlog_store::ParentBloomStats;
 
 #[cfg(feature = "indexedlog-backend")]
 pub mod tests;
@@ -10,6 +10,7 @@
 //! Combination of IdMap and IdDag.
 
 use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env::var;
@@ -17,16 +18,21 @@ use std::fmt;
 use std::io;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 
 use dag_types::FlatSegment;
 use futures::future::join_all;
 use futures::future::BoxFuture;
 use futures::StreamExt;
 use futures::TryStreamExt;
-use nonblocking::non_blocking_result;
 use parking_lot::Mutex;
 use parking_lot::RwLock;
+use tracing::Instrument;
 
+use self::cache::MissingVertexes;
+use self::cache::OverlayIdMap;
+use self::cache::RemoteHexPrefixes;
+use self::vertex_meta::VertexMeta;
 use crate::clone::CloneData;
 use crate::errors::programming;
 use crate::errors::DagError;
@@ -37,11 +43,11 @@ use crate::id::VertexName;
 use crate::iddag::IdDag;
 use crate::iddag::IdDagAlgorithm;
 use crate::iddagstore::IdDagStore;
-use crate::idmap::CoreMemIdMap;
 use crate::idmap::IdMapAssignHead;
 use crate::idmap::IdMapWrite;
 use crate::nameset::hints::Flags;
 use crate::nameset::hints::Hints;
+use crate::nameset::BoxVertexStream;
 use crate::nameset::NameSet;
 use crate::ops::CheckIntegrity;
 use crate::ops::DagAddHeads;
@@ -66,22 +72,57 @@ use crate::protocol::AncestorPath;
 use crate::protocol::Process;
 use crate::protocol::RemoteIdConvertProtocol;
 use crate::segment::PreparedFlatSegments;
-use crate::segment::SegmentFlags;
 use crate::IdSet;
-use crate::Level;
+use crate::QueryBudget;
 use crate::Result;
 use crate::VerLink;
 
+mod cache;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 mod indexedlog_namedag;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+mod journal;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+mod lease;
 mod mem_namedag;
+mod metrics;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+mod missing_log;
+mod portable;
+mod vertex_meta;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+mod vertex_meta_log;
+
+pub use cache::CacheLimits;
+pub use cache::CacheStats;
+pub use cache::LruStats;
 
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use indexedlog_namedag::IndexedLogNameDagPath;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use indexedlog_namedag::NameDag;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+pub use lease::LeaseHolder;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+pub use lease::LeaseOptions;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+pub use lease::LeaseTimeout;
+#[cfg(any(test, feature = "indexedlog-backend"))]
+pub use lease::LockTimeout;
 pub use mem_namedag::MemNameDag;
 pub use mem_namedag::MemNameDagPath;
+pub use metrics::DagMetrics;
+pub use portable::PortableDag;
+pub use portable::PORTABLE_DAG_VERSION;
+pub use vertex_meta::VertexFlags;
+
+/// Hex prefixes shorter than this are not resolved remotely. They match
+/// too many names for the server to filter.
+const MIN_REMOTE_HEX_PREFIX_LEN: usize = 4;
+
+/// The server looks at no more than this many names to resolve a hex
+/// prefix.
+const MAX_REMOTE_HEX_PREFIX_CANDIDATES: usize = 1024;
 
 pub struct AbstractNameDag<I, M, P, S>
 where
@@ -100,6 +141,9 @@ where
     /// Heads added via `add_heads` that are not flushed yet.
     pending_heads: Vec<VertexName>,
 
+    /// Heads added via `add_hidden_heads` that are not flushed yet.
+    pending_hidden_heads: Vec<VertexName>,
+
     /// Path used to open this `NameDag`.
     path: P,
 
@@ -114,7 +158,7 @@ where
 
     /// Overlay IdMap. Used to store IdMap results resolved using remote
     /// protocols.
-    overlay_map: Arc<RwLock<CoreMemIdMap>>,
+    overlay_map: Arc<Mutex<OverlayIdMap>>,
 
     /// Max ID + 1 in the `overlay_map`. A protection. The `overlay_map` is
     /// shared (Arc) and its ID should not exceed the existing maximum ID at
@@ -134,7 +178,24 @@ where
 
     /// A negative cache. Vertexes that are looked up remotely, and the remote
     /// confirmed the vertexes are outside the master group.
-    missing_vertexes_confirmed_by_remote: Arc<RwLock<HashSet<VertexName>>>,
+    missing_vertexes_confirmed_by_remote: Arc<Mutex<MissingVertexes>>,
+
+    /// Names in the master group matching hex prefixes, as resolved by the
+    /// remote. Cleared together with `missing_vertexes_confirmed_by_remote`,
+    /// as both depend on the master group.
+    hex_prefixes_resolved_by_remote: Arc<Mutex<RemoteHexPrefixes>>,
+
+    /// Receives counters about remote lookups and the overlay map.
+    metrics: Arc<dyn DagMetrics>,
+
+    /// Limits the segments read and remote round-trips of operations.
+    query_budget: Option<Arc<QueryBudget>>,
+
+    /// Flags of vertexes, like "public" or "obsolete".
+    vertex_meta: VertexMeta,
+
+    /// Whether mutations are rejected. See `NameDag::open_read_only`.
+    read_only: bool,
 }
 
 #[async_trait::async_trait]
@@ -156,61 +217,8 @@ where
         master_names: &[VertexName],
         non_master_names: &[VertexName],
     ) -> Result<()> {
-        if !self.pending_heads.is_empty() {
-            return programming(format!(
-                "ProgrammingError: add_heads_and_flush called with pending heads ({:?})",
-                &self.pending_heads,
-            ));
-        }
-
-        // Take lock.
-        //
-        // Reload meta and logs. This drops in-memory changes, which is fine because we have
-        // checked there are no in-memory changes at the beginning.
-        //
-        // Also see comments in `NameDagState::lock()`.
-        let old_version = self.state.int_version();
-        let lock = self.state.lock()?;
-        let map_lock = self.map.lock()?;
-        let dag_lock = self.dag.lock()?;
-        self.state.reload(&lock)?;
-        let new_version = self.state.int_version();
-        if old_version != new_version {
-            self.invalidate_snapshot();
-            self.invalidate_missing_vertex_cache();
-            self.invalidate_overlay_map()?;
-        }
-
-        self.map.reload(&map_lock)?;
-        self.dag.reload(&dag_lock)?;
-
-        // Populate vertex negative cache to reduce round-trips doing remote lookups.
-        // Release `self` from being mut borrowed while keeping the lock.
-        if self.is_vertex_lazy() {
-            let heads: Vec<VertexName> = master_names
-                .iter()
-                .cloned()
-                .chain(non_master_names.iter().cloned())
-                .collect();
-            self.populate_missing_vertexes_for_add_heads(parent_names_func, &heads)
-                .await?;
-        }
-
-        // Build.
-        self.build(parent_names_func, master_names, non_master_names)
-            .await?;
-
-        // Write to disk.
-        self.map.persist(&map_lock)?;
-        self.dag.persist(&dag_lock)?;
-        self.state.persist(&lock)?;
-        drop(dag_lock);
-        drop(map_lock);
-        drop(lock);
-
-        self.persisted_id_set = self.dag.all_ids_in_groups(&Group::ALL)?;
-        debug_assert_eq!(self.dirty().await?.count().await?, 0);
-        Ok(())
+        self.add_heads_and_flush_with_hidden(parent_names_func, master_names, non_master_names, &[])
+            .await
     }
 
     /// Write in-memory DAG to disk. This will also pick up changes to
@@ -222,6 +230,8 @@ where
     /// lazy vertexes, then avoid this function. Instead, lock and
     /// flush directly (see `add_heads_and_flush`, `import_clone_data`).
     async fn flush(&mut self, master_heads: &[VertexName]) -> Result<()> {
+        self.check_writable("flush")?;
+
         // Sanity check.
         for result in self.vertex_id_batch(&master_heads).await? {
             result?;
@@ -230,17 +240,32 @@ where
         // Write cached IdMap to disk.
         self.flush_cached_idmap().await?;
 
+        // Ids might be re-assigned. Carry unwritten flags by name.
+        let mut vertex_flags = Vec::new();
+        for (id, flags) in self.vertex_meta.pending().collect::<Vec<_>>() {
+            vertex_flags.push((self.vertex_name(id).await?, flags));
+        }
+
         // Constructs a new graph so we can copy pending data from the existing graph.
         let mut new_name_dag: Self = self.path.open()?;
+        new_name_dag.state.reopened_from(&self.state);
+        new_name_dag.vertex_meta.set_pending_by_name(vertex_flags);
 
         let parents: &(dyn DagAlgorithm + Send + Sync) = self;
         let non_master_heads = &self.pending_heads;
+        let hidden_heads = &self.pending_hidden_heads;
         let seg_size = self.dag.get_new_segment_size();
         new_name_dag.dag.set_new_segment_size(seg_size);
+        let incremental = self.dag.get_incremental_high_level_segments();
+        new_name_dag
+            .dag
+            .set_incremental_high_level_segments(incremental);
         new_name_dag.set_remote_protocol(self.remote_protocol.clone());
+        new_name_dag.set_metrics(self.metrics.clone());
+        new_name_dag.set_query_budget(self.query_budget.clone());
         new_name_dag.maybe_reuse_caches_from(self);
         new_name_dag
-            .add_heads_and_flush(&parents, master_heads, non_master_heads)
+            .add_heads_and_flush_with_hidden(&parents, master_heads, non_master_heads, hidden_heads)
             .await?;
         *self = new_name_dag;
         Ok(())
@@ -250,6 +275,8 @@ where
     /// ask remote service for IdMap translation.
     #[tracing::instrument(skip(self))]
     async fn flush_cached_idmap(&self) -> Result<()> {
+        self.check_writable("flush_cached_idmap")?;
+
         // The map might have changed on disk. We cannot use the ids in overlay_map
         // directly. Instead, re-translate the paths.
 
@@ -263,6 +290,7 @@ where
         // Lock, reload from disk. Use a new state so the existing dag is not affected.
         tracing::debug!(target: "dag::cache", "flushing cached idmap ({} items)", to_insert.len());
         let mut new: Self = self.path.open()?;
+        new.state.reopened_from(&self.state);
         let lock = new.state.lock()?;
         let map_lock = new.map.lock()?;
         let dag_lock = new.dag.lock()?;
@@ -310,6 +338,96 @@ where
     }
 }
 
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore + Persist,
+    IdDag<IS>: TryClone + 'static,
+    M: TryClone + IdMapAssignHead + Persist + Send + Sync + 'static,
+    P: Open<OpenTarget = Self> + Send + Sync + 'static,
+    S: TryClone + IntVersion + Persist + Send + Sync + 'static,
+{
+    /// Similar to `add_heads_and_flush`, but also adds `hidden_names` to the
+    /// HIDDEN group. See `add_hidden_heads`.
+    pub async fn add_heads_and_flush_with_hidden(
+        &mut self,
+        parent_names_func: &dyn Parents,
+        master_names: &[VertexName],
+        non_master_names: &[VertexName],
+        hidden_names: &[VertexName],
+    ) -> Result<()> {
+        self.check_writable("add_heads_and_flush")?;
+        if self.has_pending_heads() {
+            return programming(format!(
+                "ProgrammingError: add_heads_and_flush called with pending heads ({:?})",
+                self.all_pending_heads(),
+            ));
+        }
+
+        // Take lock.
+        //
+        // Reload meta and logs. This drops in-memory changes, which is fine because we have
+        // checked there are no in-memory changes at the beginning.
+        //
+        // Also see comments in `NameDagState::lock()`.
+        let old_version = self.state.int_version();
+        let lock = self.state.lock()?;
+        let map_lock = self.map.lock()?;
+        let dag_lock = self.dag.lock()?;
+        self.state.reload(&lock)?;
+        let new_version = self.state.int_version();
+        if old_version != new_version {
+            self.invalidate_snapshot();
+            self.invalidate_missing_vertex_cache();
+            self.invalidate_overlay_map()?;
+        }
+
+        self.map.reload(&map_lock)?;
+        self.dag.reload(&dag_lock)?;
+
+        // Populate vertex negative cache to reduce round-trips doing remote lookups.
+        // Release `self` from being mut borrowed while keeping the lock.
+        if self.is_vertex_lazy() {
+            let heads: Vec<VertexName> = master_names
+                .iter()
+                .cloned()
+                .chain(non_master_names.iter().cloned())
+                .chain(hidden_names.iter().cloned())
+                .collect();
+            self.populate_missing_vertexes_for_add_heads(parent_names_func, &heads)
+                .await?;
+        }
+
+        // Build.
+        self.build(
+            parent_names_func,
+            master_names,
+            non_master_names,
+            hidden_names,
+        )
+        .await?;
+
+        // Flags set before `flush` re-assigned ids.
+        for (name, flags) in self.vertex_meta.take_pending_by_name() {
+            if let Some(id) = self.map.vertex_id_with_max_group(&name, Group::MAX).await? {
+                self.vertex_meta.set(id, flags);
+            }
+        }
+
+        // Write to disk.
+        self.vertex_meta.persist()?;
+        self.map.persist(&map_lock)?;
+        self.dag.persist(&dag_lock)?;
+        self.state.persist(&lock)?;
+        drop(dag_lock);
+        drop(map_lock);
+        drop(lock);
+
+        self.persisted_id_set = self.dag.all_ids_in_groups(&Group::ALL)?;
+        debug_assert_eq!(self.dirty().await?.count().await?, 0);
+        Ok(())
+    }
+}
+
 impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
 where
     IS: Send + Sync + 'static,
@@ -324,14 +442,21 @@ where
             || self.overlay_map_next_id != other.overlay_map_next_id
         {
             tracing::debug!(target: "dag::cache", "cannot reuse cache");
+            // Keep the limits and stats of the caches, but not their content.
+            self.overlay_map = Arc::new(Mutex::new(other.overlay_map.lock().cleared()));
+            self.missing_vertexes_confirmed_by_remote = Arc::new(Mutex::new(
+                other.missing_vertexes_confirmed_by_remote.lock().cleared(),
+            ));
+            self.hex_prefixes_resolved_by_remote = Default::default();
             return;
         }
         tracing::debug!(
             target: "dag::cache", "reusing cache ({} missing)",
-            other.missing_vertexes_confirmed_by_remote.read().len(),
+            other.missing_vertexes_confirmed_by_remote.lock().len(),
         );
         self.missing_vertexes_confirmed_by_remote =
             other.missing_vertexes_confirmed_by_remote.clone();
+        self.hex_prefixes_resolved_by_remote = other.hex_prefixes_resolved_by_remote.clone();
         self.overlay_map = other.overlay_map.clone();
         self.overlay_map_paths = other.overlay_map_paths.clone();
     }
@@ -341,7 +466,7 @@ where
 impl<IS, M, P, S> DagAddHeads for AbstractNameDag<IdDag<IS>, M, P, S>
 where
     IS: IdDagStore,
-    IdDag<IS>: TryClone,
+    IdDag<IS>: TryClone + 'static,
     M: TryClone + IdMapAssignHead + Send + Sync + 'static,
     P: TryClone + Send + Sync + 'static,
     S: TryClone + Send + Sync + 'static,
@@ -355,6 +480,7 @@ where
     /// assigned to the NON_MASTER group internally. The `flush` function
     /// can re-assign Ids to the MASTER group.
     async fn add_heads(&mut self, parents: &dyn Parents, heads: &[VertexName]) -> Result<()> {
+        self.check_writable("add_heads")?;
         self.invalidate_snapshot();
 
         // Populate vertex negative cache to reduce round-trips doing remote lookups.
@@ -376,27 +502,84 @@ where
         // - The callsite is trying some temporary graph changes, and does
         //   not want to pollute the on-disk DAG. For example, calculating
         //   a preview of a rebase.
-        let group = Group::NON_MASTER;
+        self.add_heads_to_group(parents, heads, Group::NON_MASTER)
+            .await
+    }
+}
+
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore,
+    IdDag<IS>: TryClone + 'static,
+    M: TryClone + IdMapAssignHead + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Send + Sync + 'static,
+{
+    /// Similar to `add_heads`, but the added vertexes get Ids in the HIDDEN
+    /// group, so they are kept apart from visible non-master vertexes.
+    ///
+    /// `flush` keeps them in the HIDDEN group, unless they become ancestors
+    /// of master or (visible) non-master heads.
+    pub async fn add_hidden_heads(
+        &mut self,
+        parents: &dyn Parents,
+        heads: &[VertexName],
+    ) -> Result<()> {
+        self.check_writable("add_hidden_heads")?;
+        self.invalidate_snapshot();
+        self.populate_missing_vertexes_for_add_heads(parents, heads)
+            .await?;
+        self.add_heads_to_group(parents, heads, Group::HIDDEN).await
+    }
 
+    async fn add_heads_to_group(
+        &mut self,
+        parents: &dyn Parents,
+        heads: &[VertexName],
+        group: Group,
+    ) -> Result<()> {
         // Update IdMap. Keep track of what heads are added.
         let mut outcome = PreparedFlatSegments::default();
         let mut covered = self.dag().all_ids_in_groups(&Group::ALL)?;
         for head in heads.iter() {
-            if !self.contains_vertex_name(head).await? {
+            // Heads in a higher group are re-assigned to `group`.
+            if self.vertex_id_with_max_group(head, group).await?.is_none() {
                 let prepared_segments = self
                     .assign_head(head.clone(), parents, group, &mut covered, &IdSet::empty())
                     .await?;
                 outcome.merge(prepared_segments);
-                self.pending_heads.push(head.clone());
+                if group == Group::HIDDEN {
+                    self.pending_hidden_heads.push(head.clone());
+                } else {
+                    self.pending_heads.push(head.clone());
+                }
             }
         }
 
-        // Update segments in the NON_MASTER group.
+        // Update segments in the group.
         self.dag
             .build_segments_volatile_from_prepared_flat_segments(&outcome)?;
 
+        // Hidden vertexes that became ancestors of non-master heads got new
+        // ids. Drop their old ids.
+        if self.need_rebuild_non_master().await {
+            self.rebuild_non_master().await?;
+        }
+
         Ok(())
     }
+
+    fn has_pending_heads(&self) -> bool {
+        !self.pending_heads.is_empty() || !self.pending_hidden_heads.is_empty()
+    }
+
+    /// Heads added by `add_heads` and `add_hidden_heads`, not flushed yet.
+    fn all_pending_heads(&self) -> Vec<&VertexName> {
+        self.pending_heads
+            .iter()
+            .chain(self.pending_hidden_heads.iter())
+            .collect()
+    }
 }
 
 #[async_trait::async_trait]
@@ -409,10 +592,17 @@ where
     S: TryClone + Send + Sync,
 {
     async fn insert(&mut self, id: Id, name: &[u8]) -> Result<()> {
+        self.check_writable("insert")?;
         self.map.insert(id, name).await
     }
 
+    async fn remove_range(&mut self, low: Id, high: Id) -> Result<Vec<VertexName>> {
+        self.check_writable("remove_range")?;
+        self.map.remove_range(low, high).await
+    }
+
     async fn remove_non_master(&mut self) -> Result<()> {
+        self.check_writable("remove_non_master")?;
         self.map.remove_non_master().await
     }
 
@@ -431,6 +621,8 @@ where
     S: TryClone + Persist + Send + Sync + 'static,
 {
     async fn import_clone_data(&mut self, clone_data: CloneData<VertexName>) -> Result<()> {
+        self.check_writable("import_clone_data")?;
+
         // Write directly to disk. Bypassing "flush()" that re-assigns Ids
         // using parent functions.
         let (lock, map_lock, dag_lock) = self.reload()?;
@@ -449,6 +641,138 @@ where
 
         self.persist(lock, map_lock, dag_lock)
     }
+
+    async fn import_clone_data_incremental(
+        &mut self,
+        clone_data: CloneData<VertexName>,
+    ) -> Result<()> {
+        self.check_writable("import_clone_data_incremental")?;
+        if self.has_pending_heads() {
+            return programming(format!(
+                "import_clone_data_incremental called with pending heads ({:?})",
+                self.all_pending_heads(),
+            ));
+        }
+
+        let (lock, map_lock, dag_lock) = self.reload()?;
+
+        let master = self.dag.master_group()?;
+        if !self.dag.all()?.difference(&master).is_empty() {
+            return programming(
+                "import_clone_data_incremental cannot import with non-master vertexes",
+            );
+        }
+
+        let mut segments = clone_data.flat_segments.segments.clone();
+        segments.sort_unstable_by_key(|s| s.low);
+        split_pull_ranges(&segments)?;
+        let clone_ids = IdSet::from_spans(segments.iter().map(|s| s.low..=s.high));
+        let missing = master.difference(&clone_ids);
+        if !missing.is_empty() {
+            return programming(format!(
+                "clone data does not contain local master vertexes {:?}",
+                missing
+            ));
+        }
+
+        // Parents are the same if they are the same at the start of every
+        // segment, since other vertexes have the previous id as the parent.
+        let local_segments = self.dag.idset_to_flat_segments(master.clone())?.segments;
+        let mut boundaries: Vec<Id> = local_segments.iter().map(|s| s.low).collect();
+        boundaries.extend(
+            segments
+                .iter()
+                .map(|s| s.low)
+                .filter(|&id| master.contains(id)),
+        );
+        for id in boundaries {
+            let local_parents = flat_segment_parents(&local_segments, id);
+            let clone_parents = flat_segment_parents(&segments, id);
+            if local_parents != clone_parents {
+                return programming(format!(
+                    "clone data has parents {:?} for {:?}, but the local graph has {:?}",
+                    clone_parents, id, local_parents
+                ));
+            }
+        }
+
+        let mut to_insert = Vec::new();
+        for (&id, name) in clone_data.idmap.iter() {
+            match self
+                .map
+                .vertex_id_with_max_group(name, Group::MASTER)
+                .await?
+            {
+                Some(local_id) if local_id != id => {
+                    return programming(format!(
+                        "clone data has {:?} for {:?}, but the local graph has {:?}",
+                        id, name, local_id
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    if master.contains(id) && self.map.contains_vertex_id_locally(&[id]).await?[0] {
+                        return programming(format!(
+                            "clone data has {:?} for {:?}, but the local graph has {:?}",
+                            name,
+                            id,
+                            self.map.vertex_name(id).await?
+                        ));
+                    }
+                    to_insert.push((id, name));
+                }
+            }
+        }
+        to_insert.sort_unstable_by_key(|(id, _)| *id);
+        for (id, name) in to_insert {
+            tracing::debug!(target: "dag::clone", "insert IdMap: {:?}-{:?}", &name, id);
+            self.map.insert(id, name.as_ref()).await?;
+        }
+
+        // Segments that are partly in the master group are cut at its end.
+        let next_id = match master.max() {
+            Some(max) => max + 1,
+            None => Id::MIN,
+        };
+        let new_segments = segments
+            .into_iter()
+            .filter(|s| s.high >= next_id)
+            .map(|s| {
+                if s.low >= next_id {
+                    s
+                } else {
+                    FlatSegment {
+                        low: next_id,
+                        high: s.high,
+                        parents: vec![next_id - 1],
+                    }
+                }
+            })
+            .collect();
+        self.dag
+            .build_segments_volatile_from_prepared_flat_segments(&PreparedFlatSegments {
+                segments: new_segments,
+            })?;
+
+        self.verify_missing().await?;
+
+        self.persist(lock, map_lock, dag_lock)?;
+        self.invalidate_snapshot();
+        Ok(())
+    }
+}
+
+/// Parents of `id` according to `segments`, which are sorted by `low`.
+fn flat_segment_parents(segments: &[FlatSegment], id: Id) -> Option<Vec<Id>> {
+    let index = segments.partition_point(|s| s.low <= id).checked_sub(1)?;
+    let seg = &segments[index];
+    if id > seg.high {
+        None
+    } else if id == seg.low {
+        Some(seg.parents.clone())
+    } else {
+        Some(vec![id - 1])
+    }
 }
 
 impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
@@ -476,7 +800,80 @@ where
         Ok(())
     }
 
-    fn reload(&mut self) -> Result<(S::Lock, M::Lock, IS::Lock)> {
+    /// Rebuild high-level segments on disk to reduce fragmentation.
+    /// See `IdDag::defragment`.
+    ///
+    /// Return number of high-level segments inserted.
+    pub fn defragment(&mut self) -> Result<usize> {
+        self.check_writable("defragment")?;
+        if self.has_pending_heads() {
+            return programming(format!(
+                "ProgrammingError: defragment called with pending heads ({:?})",
+                self.all_pending_heads(),
+            ));
+        }
+
+        let (lock, map_lock, dag_lock) = self.reload()?;
+        let count = self.dag.defragment()?;
+        self.persist(lock, map_lock, dag_lock)?;
+        self.invalidate_snapshot();
+        Ok(count)
+    }
+
+    /// Merge small flat segments at the end of the master group on disk.
+    /// See `IdDag::compact_master_tail`.
+    ///
+    /// Return number of flat segments removed by merging.
+    pub fn compact_master_tail(&mut self, max_segments: usize) -> Result<usize> {
+        self.check_writable("compact_master_tail")?;
+        if self.has_pending_heads() {
+            return programming(format!(
+                "compact_master_tail called with pending heads ({:?})",
+                self.all_pending_heads(),
+            ));
+        }
+
+        let (lock, map_lock, dag_lock) = self.reload()?;
+        let count = self.dag.compact_master_tail(max_segments)?;
+        self.persist(lock, map_lock, dag_lock)?;
+        self.invalidate_snapshot();
+        Ok(count)
+    }
+
+    /// Remove vertexes in `set` and their descendants from the graph on disk.
+    /// See `IdDag::strip`.
+    ///
+    /// Removing master vertexes from a lazy graph is not supported, since
+    /// the server might still refer to them.
+    pub async fn strip(&mut self, set: NameSet) -> Result<()> {
+        self.check_writable("strip")?;
+        if self.has_pending_heads() {
+            return programming(format!(
+                "strip called with pending heads ({:?})",
+                self.all_pending_heads(),
+            ));
+        }
+
+        let (lock, map_lock, dag_lock) = self.reload()?;
+        let ids = self.to_id_set(&set).await?;
+        let to_remove = self.dag.descendants(ids)?;
+        if self.is_vertex_lazy() && !to_remove.intersection(&self.dag.master_group()?).is_empty() {
+            return programming("strip cannot remove master vertexes from a lazy graph");
+        }
+
+        let removed = self.dag.strip(to_remove)?;
+        for span in removed.as_spans() {
+            self.map.remove_range(span.low, span.high).await?;
+        }
+        self.vertex_meta.remove(&removed)?;
+        self.persist(lock, map_lock, dag_lock)?;
+        self.invalidate_snapshot();
+        self.invalidate_missing_vertex_cache();
+        Ok(())
+    }
+
+    pub(crate) fn reload(&mut self) -> Result<(S::Lock, M::Lock, IS::Lock)> {
+        self.check_writable("reload")?;
         let lock = self.state.lock()?;
         let map_lock = self.map.lock()?;
         let dag_lock = self.dag.lock()?;
@@ -487,7 +884,13 @@ where
         Ok((lock, map_lock, dag_lock))
     }
 
-    fn persist(&mut self, lock: S::Lock, map_lock: M::Lock, dag_lock: IS::Lock) -> Result<()> {
+    pub(crate) fn persist(
+        &mut self,
+        lock: S::Lock,
+        map_lock: M::Lock,
+        dag_lock: IS::Lock,
+    ) -> Result<()> {
+        self.vertex_meta.persist()?;
         self.map.persist(&map_lock)?;
         self.dag.persist(&dag_lock)?;
         self.state.persist(&lock)?;
@@ -499,6 +902,56 @@ where
     }
 }
 
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore,
+    IdDag<IS>: TryClone + 'static,
+    M: TryClone + IdConvert + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Send + Sync + 'static,
+{
+    /// Flags of a vertex. Empty if none were set.
+    pub async fn vertex_flags(&self, name: &VertexName) -> Result<VertexFlags> {
+        let id = self.vertex_id(name.clone()).await?;
+        self.vertex_meta.get(id)
+    }
+
+    /// Add `flags` to vertexes in `set`, or remove `flags` from them if
+    /// `value` is false.
+    ///
+    /// Changes are written to disk by `flush` or `add_heads_and_flush`.
+    /// Flags of non-master vertexes are dropped when the non-master group is
+    /// re-assigned, for example, when some of them become ancestors of the
+    /// master group.
+    pub async fn set_vertex_flags(
+        &mut self,
+        set: NameSet,
+        flags: VertexFlags,
+        value: bool,
+    ) -> Result<()> {
+        self.check_writable("set_vertex_flags")?;
+        let ids = self.to_id_set(&set).await?;
+        for id in ids.iter() {
+            let mut id_flags = self.vertex_meta.get(id)?;
+            id_flags.set(flags, value);
+            self.vertex_meta.set(id, id_flags);
+        }
+        Ok(())
+    }
+
+    /// Vertexes in `set` that have all of `flags`. If `flags` is empty,
+    /// vertexes in `set` that have any flag.
+    pub async fn filter_by_vertex_flags(
+        &self,
+        set: NameSet,
+        flags: VertexFlags,
+    ) -> Result<NameSet> {
+        let ids = self.to_id_set(&set).await?;
+        let ids = self.vertex_meta.query(&ids, flags)?;
+        NameSet::from_spans_dag(ids, self)
+    }
+}
+
 #[async_trait::async_trait]
 impl<IS, M, P, S> DagImportPullData for AbstractNameDag<IdDag<IS>, M, P, S>
 where
@@ -509,15 +962,20 @@ where
     S: IntVersion + TryClone + Persist + Send + Sync + 'static,
 {
     async fn import_pull_data(&mut self, clone_data: CloneData<VertexName>) -> Result<()> {
-        if !self.pending_heads.is_empty() {
+        self.check_writable("import_pull_data")?;
+        if self.has_pending_heads() {
             return programming(format!(
                 "import_pull_data called with pending heads ({:?})",
-                &self.pending_heads,
+                self.all_pending_heads(),
             ));
         }
 
-        if let Some(highest_seg) = clone_data.flat_segments.segments.last() {
-            let id = highest_seg.high;
+        // The pulled segments might form several disjoint ranges, for example,
+        // when catching up multiple heads at once. Each range is remapped to
+        // client ids independently.
+        let ranges = split_pull_ranges(&clone_data.flat_segments.segments)?;
+        for range in &ranges {
+            let id = range.last().unwrap().high;
             if !clone_data.idmap.contains_key(&id) {
                 return programming(format!("server does not provide name for head {:?}", id));
             }
@@ -525,8 +983,11 @@ where
 
         // Constructs a new graph so we don't expose a broken `self` state on error.
         let mut new: Self = self.path.open()?;
+        new.state.reopened_from(&self.state);
         let (lock, map_lock, dag_lock) = new.reload()?;
         new.set_remote_protocol(self.remote_protocol.clone());
+        new.set_metrics(self.metrics.clone());
+        new.set_query_budget(self.query_budget.clone());
         new.maybe_reuse_caches_from(self);
 
         // Parents that should exist in the local graph. Look them up in 1 round-trip
@@ -544,21 +1005,41 @@ where
             let mut parent_ids: Vec<Id> = Vec::new();
             let segments = &clone_data.flat_segments.segments;
             let id_set = IdSet::from_spans(segments.iter().map(|s| s.low..=s.high));
-            for seg in segments {
-                let pids: Vec<Id> = seg.parents.iter().copied().collect();
-                // Parents that are not part of the pull vertexes should exist
-                // in the local graph.
-                let connected_pids: Vec<Id> = pids
-                    .iter()
-                    .copied()
-                    .filter(|&p| !id_set.contains(p))
-                    .collect();
-                if connected_pids.len() == pids.len() {
-                    // The "low" of the segment is a root (of vertexes to insert).
-                    // It needs an overlap check.
-                    root_ids.push(seg.low);
+            for range in &ranges {
+                let mut range_root_ids = Vec::new();
+                for seg in range.iter() {
+                    let pids: Vec<Id> = seg.parents.iter().copied().collect();
+                    // Parents that are part of the pull vertexes must be
+                    // inserted before the segment, either by an earlier
+                    // segment in this range, or by an earlier range.
+                    if let Some(&p) = pids.iter().find(|&&p| id_set.contains(p) && p >= seg.low) {
+                        return programming(format!(
+                            "server returned segment {:?} with parent {:?} not pulled before it",
+                            seg, p
+                        ));
+                    }
+                    // Parents that are not part of the pull vertexes should exist
+                    // in the local graph.
+                    let connected_pids: Vec<Id> = pids
+                        .iter()
+                        .copied()
+                        .filter(|&p| !id_set.contains(p))
+                        .collect();
+                    if connected_pids.len() == pids.len() {
+                        // The "low" of the segment is a root (of vertexes to insert).
+                        // It needs an overlap check.
+                        range_root_ids.push(seg.low);
+                    }
+                    parent_ids.extend(connected_pids);
                 }
-                parent_ids.extend(connected_pids);
+                let (low, high) = (range[0].low, range.last().unwrap().high);
+                tracing::trace!(
+                    "pull: range {:?}..={:?}, roots: {:?}",
+                    low,
+                    high,
+                    &range_root_ids
+                );
+                root_ids.extend(range_root_ids);
             }
 
             let to_names = |ids: &[Id], hint: &str| -> Result<Vec<VertexName>> {
@@ -612,63 +1093,60 @@ where
         let mut next_free_client_id = new.dag.next_free_id(0, Group::MASTER)?;
         let mut new_client_segments = vec![];
         let server_idmap_tree: BTreeMap<_, _> = clone_data.idmap.clone().into_iter().collect();
-        let mut last_server_id = None; // Can't use 0 since server might return segment starting from 0 (for example if pulling from empty repo)
 
-        for server_segment in clone_data.flat_segments.segments {
-            if server_segment.low > server_segment.high {
-                return programming(format!(
-                    "server returned incorrect segment {:?}",
-                    server_segment
-                ));
-            }
-            match last_server_id {
-                Some(last_server_id) if server_segment.low <= last_server_id => {
-                    return programming(format!(
-                        "server returned non sorted segment {:?}, previous segment high {}",
-                        server_segment, last_server_id
-                    ));
-                }
-                _ => {}
-            }
-            last_server_id = Some(server_segment.high);
-            let mut parent_names = vec![];
-            for server_parent in server_segment.parents {
-                let parent_name = clone_data.idmap.get(&server_parent);
-                // all parents should be in server's id_map
-                let parent_name = parent_name.ok_or_else(|| {
-                    DagError::Programming(format!(
-                        "server does not provide name for id {}",
-                        server_parent
-                    ))
-                })?;
-                parent_names.push(parent_name.clone());
-            }
-            // Parents should exist in the local graph and can be resolved without looking
-            // up remotely. Either looked up above, or inserted by the `new.map.insert`
-            // loop below.
-            let client_parents = new.map.vertex_id_batch(&parent_names).await?;
-            let client_parents = client_parents.into_iter().collect::<Result<Vec<Id>>>()?;
-
-            let new_client_id_low = next_free_client_id;
-            let new_client_id_high =
-                new_client_id_low + server_segment.high.0 - server_segment.low.0;
-            next_free_client_id = new_client_id_high + 1;
-            new_client_segments.push(FlatSegment {
-                low: new_client_id_low,
-                high: new_client_id_high,
-                parents: client_parents,
-            });
+        for range in ranges {
+            let server_low = range[0].low;
+            let server_high = range.last().unwrap().high;
 
             // this can be negative becase we generally don't know if client id's are greater or lower then server id's
-            let server_to_client_offset = new_client_id_low.0 as i64 - server_segment.low.0 as i64;
+            let server_to_client_offset = next_free_client_id.0 as i64 - server_low.0 as i64;
+            let to_client_id =
+                |server_id: Id| Id((server_id.0 as i64 + server_to_client_offset) as u64);
+            tracing::debug!(
+                target: "dag::pull",
+                "remap range {:?}..={:?} to {:?}..={:?}",
+                server_low,
+                server_high,
+                to_client_id(server_low),
+                to_client_id(server_high)
+            );
+
+            for server_segment in range {
+                let mut parent_names = vec![];
+                for server_parent in &server_segment.parents {
+                    let parent_name = clone_data.idmap.get(server_parent);
+                    // all parents should be in server's id_map
+                    let parent_name = parent_name.ok_or_else(|| {
+                        DagError::Programming(format!(
+                            "server does not provide name for id {}",
+                            server_parent
+                        ))
+                    })?;
+                    parent_names.push(parent_name.clone());
+                }
+                // Parents should exist in the local graph and can be resolved without looking
+                // up remotely. Either looked up above, or inserted by the `new.map.insert`
+                // loop below (for parents in this or previous ranges).
+                let client_parents = new.map.vertex_id_batch(&parent_names).await?;
+                let client_parents = client_parents.into_iter().collect::<Result<Vec<Id>>>()?;
+
+                new_client_segments.push(FlatSegment {
+                    low: to_client_id(server_segment.low),
+                    high: to_client_id(server_segment.high),
+                    parents: client_parents,
+                });
 
-            let new_server_ids = server_idmap_tree.range(server_segment.low..=server_segment.high);
+                let new_server_ids =
+                    server_idmap_tree.range(server_segment.low..=server_segment.high);
 
-            for (server_id, name) in new_server_ids {
-                let client_id = Id((server_id.0 as i64 + server_to_client_offset) as u64);
-                tracing::debug!(target: "dag::pull", "insert IdMap: {:?}-{:?}", &name, client_id);
-                new.map.insert(client_id, name.as_ref()).await?;
+                for (server_id, name) in new_server_ids {
+                    let client_id = to_client_id(*server_id);
+                    tracing::debug!(target: "dag::pull", "insert IdMap: {:?}-{:?}", &name, client_id);
+                    new.map.insert(client_id, name.as_ref()).await?;
+                }
             }
+
+            next_free_client_id = to_client_id(server_high) + 1;
         }
 
         let new_client_segments = PreparedFlatSegments {
@@ -688,6 +1166,34 @@ where
     }
 }
 
+/// Split sorted pulled segments into ranges of adjacent segments.
+fn split_pull_ranges(segments: &[FlatSegment]) -> Result<Vec<&[FlatSegment]>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for (i, seg) in segments.iter().enumerate() {
+        if seg.low > seg.high {
+            return programming(format!("server returned incorrect segment {:?}", seg));
+        }
+        if i > 0 {
+            let last_server_id = segments[i - 1].high;
+            if seg.low <= last_server_id {
+                return programming(format!(
+                    "server returned non sorted segment {:?}, previous segment high {}",
+                    seg, last_server_id
+                ));
+            }
+            if seg.low != last_server_id + 1 {
+                ranges.push(&segments[start..i]);
+                start = i;
+            }
+        }
+    }
+    if start < segments.len() {
+        ranges.push(&segments[start..]);
+    }
+    Ok(ranges)
+}
+
 #[async_trait::async_trait]
 impl<IS, M, P, S> DagExportCloneData for AbstractNameDag<IdDag<IS>, M, P, S>
 where
@@ -804,17 +1310,28 @@ where
     ///
     /// Forgetting to call this function might hurt performance a bit, but does
     /// not affect correctness.
-    fn invalidate_snapshot(&mut self) {
+    pub(crate) fn invalidate_snapshot(&mut self) {
         *self.snapshot.write() = None;
     }
 
+    /// Return an error if this graph was opened as read-only.
+    pub(crate) fn check_writable(&self, op: &str) -> Result<()> {
+        if self.read_only {
+            return programming(format!("{} called on read-only NameDag {}", op, &self.id));
+        }
+        Ok(())
+    }
+
     fn invalidate_missing_vertex_cache(&mut self) {
         tracing::debug!(target: "dag::cache", "cleared missing cache");
-        *self.missing_vertexes_confirmed_by_remote.write() = Default::default();
+        let cleared = self.missing_vertexes_confirmed_by_remote.lock().cleared();
+        self.missing_vertexes_confirmed_by_remote = Arc::new(Mutex::new(cleared));
+        self.hex_prefixes_resolved_by_remote = Default::default();
     }
 
     fn invalidate_overlay_map(&mut self) -> Result<()> {
-        self.overlay_map = Default::default();
+        let cleared = self.overlay_map.lock().cleared();
+        self.overlay_map = Arc::new(Mutex::new(cleared));
         self.update_overlay_map_next_id()?;
         tracing::debug!(target: "dag::cache", "cleared overlay map cache");
         Ok(())
@@ -843,6 +1360,7 @@ where
                     map: self.map.try_clone()?,
                     snapshot: Default::default(),
                     pending_heads: self.pending_heads.clone(),
+                    pending_hidden_heads: self.pending_hidden_heads.clone(),
                     persisted_id_set: self.persisted_id_set.clone(),
                     path: self.path.try_clone()?,
                     state: self.state.try_clone()?,
@@ -856,6 +1374,13 @@ where
                     missing_vertexes_confirmed_by_remote: Arc::clone(
                         &self.missing_vertexes_confirmed_by_remote,
                     ),
+                    hex_prefixes_resolved_by_remote: Arc::clone(
+                        &self.hex_prefixes_resolved_by_remote,
+                    ),
+                    metrics: self.metrics.clone(),
+                    query_budget: self.query_budget.clone(),
+                    vertex_meta: self.vertex_meta.clone(),
+                    read_only: self.read_only,
                 };
                 let result = Arc::new(cloned);
                 *snapshot = Some(Arc::clone(&result));
@@ -864,24 +1389,95 @@ where
         }
     }
 
-    pub fn dag(&self) -> &IdDag<IS> {
-        &self.dag
+    pub fn dag(&self) -> &IdDag<IS> {
+        &self.dag
+    }
+
+    pub fn map(&self) -> &M {
+        &self.map
+    }
+
+    /// Set the remote protocol for converting between Id and Vertex remotely.
+    ///
+    /// This is usually used on "sparse" ("lazy") Dag where the IdMap is incomplete
+    /// for vertexes in the master groups.
+    pub fn set_remote_protocol(&mut self, protocol: Arc<dyn RemoteIdConvertProtocol>) {
+        self.remote_protocol = protocol;
+    }
+
+    pub(crate) fn get_remote_protocol(&self) -> Arc<dyn RemoteIdConvertProtocol> {
+        self.remote_protocol.clone()
+    }
+
+    /// Set the receiver of counters about remote IdMap resolution.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn DagMetrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Limit the cost of operations on this graph. Operations reading more
+    /// segments, or making more remote round-trips, than `budget` allows fail
+    /// with `BudgetExceeded` instead of running unbounded.
+    ///
+    /// The budget is shared by all operations until it is replaced. Servers
+    /// usually set a new budget for each request. `None` removes the limit.
+    pub fn set_query_budget(&mut self, budget: Option<Arc<QueryBudget>>) {
+        self.dag.set_query_budget(budget.clone());
+        self.query_budget = budget;
+        // The snapshot has the previous budget.
+        self.invalidate_snapshot();
+    }
+
+    /// Count a round-trip to the remote protocol against the query budget.
+    fn charge_remote_round_trip(&self) -> Result<()> {
+        match &self.query_budget {
+            Some(budget) if !self.remote_protocol.is_local() => budget.charge_remote_round_trip(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Look up the overlay map. Report the lookup to `metrics`.
+    fn overlay_map_lookup_vertex_id(&self, name: &VertexName) -> Option<Id> {
+        let id = self.overlay_map.lock().lookup_vertex_id(name);
+        self.metrics
+            .overlay_map_lookups(id.is_some() as usize, id.is_none() as usize);
+        id
     }
 
-    pub fn map(&self) -> &M {
-        &self.map
+    /// Look up the overlay map. Report the lookup to `metrics`.
+    fn overlay_map_lookup_vertex_name(&self, id: Id) -> Option<VertexName> {
+        let name = self.overlay_map.lock().lookup_vertex_name(id);
+        self.metrics
+            .overlay_map_lookups(name.is_some() as usize, name.is_none() as usize);
+        name
     }
 
-    /// Set the remote protocol for converting between Id and Vertex remotely.
+    /// Bound the caches populated by the remote protocol. Entries exceeding
+    /// the limits are evicted, least recently used first.
     ///
-    /// This is usually used on "sparse" ("lazy") Dag where the IdMap is incomplete
-    /// for vertexes in the master groups.
-    pub fn set_remote_protocol(&mut self, protocol: Arc<dyn RemoteIdConvertProtocol>) {
-        self.remote_protocol = protocol;
+    /// Long-lived processes should set limits so the caches do not grow
+    /// without bound.
+    pub fn set_cache_limits(&mut self, limits: CacheLimits) {
+        self.overlay_map.lock().set_capacity(limits.overlay_map);
+        self.missing_vertexes_confirmed_by_remote
+            .lock()
+            .set_capacity(limits.missing_vertexes);
     }
 
-    pub(crate) fn get_remote_protocol(&self) -> Arc<dyn RemoteIdConvertProtocol> {
-        self.remote_protocol.clone()
+    /// Get the limits set by `set_cache_limits`.
+    pub fn cache_limits(&self) -> CacheLimits {
+        CacheLimits {
+            overlay_map: self.overlay_map.lock().capacity(),
+            missing_vertexes: self.missing_vertexes_confirmed_by_remote.lock().capacity(),
+        }
+    }
+
+    /// Hits, misses and evictions of the caches populated by the remote
+    /// protocol.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            overlay_map: self.overlay_map.lock().stats(),
+            missing_vertexes: self.missing_vertexes_confirmed_by_remote.lock().stats(),
+        }
     }
 }
 
@@ -900,7 +1496,7 @@ where
     ) -> Result<()> {
         if self.is_vertex_lazy() {
             let unassigned = calculate_definitely_unassigned_vertexes(self, parents, heads).await?;
-            let mut missing = self.missing_vertexes_confirmed_by_remote.write();
+            let mut missing = self.missing_vertexes_confirmed_by_remote.lock();
             for v in unassigned {
                 if missing.insert(v.clone()) {
                     tracing::trace!(target: "dag::cache", "cached missing {:?} (definitely missing)", &v);
@@ -1010,7 +1606,7 @@ where
             // as a remote "contains" check.
             if root_parents_id_set
                 .iter()
-                .all(|i| i.group() == Group::NON_MASTER)
+                .all(|i| i.group() != Group::MASTER)
             {
                 tracing::debug!(target: "dag::definitelymissing", "root {:?} is not assigned (non-lazy parent)", &root);
                 unassigned_roots.push(root);
@@ -1129,9 +1725,59 @@ where
     P: TryClone + Send + Sync,
     S: TryClone + Send + Sync,
 {
+    /// Resolve names in the master group starting with `hex_prefix`
+    /// remotely. The result is cached until the master group changes.
+    async fn resolve_hex_prefix_remotely(
+        &self,
+        hex_prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        if let Some(names) = self
+            .hex_prefixes_resolved_by_remote
+            .lock()
+            .get(hex_prefix, limit)
+        {
+            return Ok(names);
+        }
+        if is_remote_protocol_disabled() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "resolving hex prefixes remotely disabled",
+            )
+            .into());
+        }
+        self.charge_remote_round_trip()?;
+        let request: protocol::RequestNameToLocation =
+            (self.map(), self.dag()).process(Vec::new()).await?;
+        let span = tracing::debug_span!(
+            target: "dag::protocol",
+            "resolve_hex_prefix",
+            hex_prefix = %String::from_utf8_lossy(hex_prefix),
+            limit,
+        );
+        let names = self
+            .remote_protocol
+            .resolve_hex_prefix(request.heads, hex_prefix.to_vec(), limit)
+            .instrument(span)
+            .await?;
+        tracing::debug!(target: "dag::protocol", "resolved {} names by hex prefix remotely", names.len());
+        self.hex_prefixes_resolved_by_remote.lock().insert(
+            hex_prefix.to_vec(),
+            limit,
+            names.clone(),
+        );
+        Ok(names)
+    }
+
     /// Resolve vertexes remotely and cache the result in the overlay map.
     /// Return the resolved ids in the given order. Not all names are resolved.
-    async fn resolve_vertexes_remotely(&self, names: &[VertexName]) -> Result<Vec<Option<Id>>> {
+    ///
+    /// `operation` names the caller, for tracing.
+    async fn resolve_vertexes_remotely(
+        &self,
+        names: &[VertexName],
+        operation: &'static str,
+    ) -> Result<Vec<Option<Id>>> {
         if names.is_empty() {
             return Ok(Vec::new());
         }
@@ -1148,31 +1794,80 @@ where
             tracing::debug!(target: "dag::protocol", "resolve names ({}) remotely", names.len());
         }
         crate::failpoint!("dag-resolve-vertexes-remotely");
-        let request: protocol::RequestNameToLocation =
+        self.charge_remote_round_trip()?;
+        let mut request: protocol::RequestNameToLocation =
             (self.map(), self.dag()).process(names.to_vec()).await?;
+        let request_id = protocol::new_request_id();
+        request.request_id = Some(request_id);
+        let span = tracing::debug_span!(
+            target: "dag::protocol",
+            "resolve_names_to_relative_paths",
+            request_id,
+            size = request.names.len(),
+            operation,
+        );
+        let start = Instant::now();
         let path_names = self
             .remote_protocol
-            .resolve_names_to_relative_paths(request.heads, request.names)
+            .resolve_names_to_relative_paths(request.heads, request.names, request.request_id)
+            .instrument(span)
             .await?;
-        self.insert_relative_paths(path_names).await?;
-        let overlay = self.overlay_map.read();
+        let duration = start.elapsed();
+        let inserted: HashMap<VertexName, Id> = self
+            .insert_relative_paths(path_names)
+            .await?
+            .into_iter()
+            .map(|(id, name)| (name, id))
+            .collect();
+        // Prefer the inserted mappings. They might be evicted from the overlay
+        // map already if it is bounded.
         let mut ids = Vec::with_capacity(names.len());
-        let mut missing = self.missing_vertexes_confirmed_by_remote.write();
-        for name in names {
-            if let Some(id) = overlay.lookup_vertex_id(name) {
-                ids.push(Some(id));
-            } else {
-                tracing::trace!(target: "dag::cache", "cached missing {:?} (server confirmed)", &name);
-                missing.insert(name.clone());
-                ids.push(None);
+        let mut confirmed_missing = Vec::new();
+        let store = {
+            let mut overlay = self.overlay_map.lock();
+            let mut missing = self.missing_vertexes_confirmed_by_remote.lock();
+            for name in names {
+                let id = match inserted.get(name) {
+                    Some(&id) => Some(id),
+                    None => overlay.lookup_vertex_id(name),
+                };
+                if let Some(id) = id {
+                    ids.push(Some(id));
+                } else {
+                    tracing::trace!(target: "dag::cache", "cached missing {:?} (server confirmed)", &name);
+                    missing.insert(name.clone());
+                    confirmed_missing.push(name.clone());
+                    ids.push(None);
+                }
+            }
+            missing.store()
+        };
+        if let Some(store) = store {
+            // The persisted cache is only an optimization. Do not fail the
+            // lookup if it cannot be written.
+            let result = self
+                .dag
+                .next_free_id(0, Group::MASTER)
+                .and_then(|master_next_id| store.append(&confirmed_missing, master_next_id));
+            if let Err(e) = result {
+                tracing::warn!(target: "dag::cache", "cannot persist missing vertexes: {}", e);
             }
         }
+        let resolved = ids.iter().filter(|id| id.is_some()).count();
+        self.metrics
+            .remote_names_to_ids(names.len(), resolved, duration);
         Ok(ids)
     }
 
     /// Resolve ids remotely and cache the result in the overlay map.
     /// Return the resolved ids in the given order. All ids must be resolved.
-    async fn resolve_ids_remotely(&self, ids: &[Id]) -> Result<Vec<VertexName>> {
+    ///
+    /// `operation` names the caller, for tracing.
+    async fn resolve_ids_remotely(
+        &self,
+        ids: &[Id],
+        operation: &'static str,
+    ) -> Result<Vec<VertexName>> {
         if ids.is_empty() {
             return Ok(Vec::new());
         }
@@ -1189,18 +1884,39 @@ where
             tracing::debug!(target: "dag::protocol", "resolve ids ({}) remotely", ids.len());
         }
         crate::failpoint!("dag-resolve-ids-remotely");
-        let request: protocol::RequestLocationToName = (self.map(), self.dag())
+        self.charge_remote_round_trip()?;
+        let mut request: protocol::RequestLocationToName = (self.map(), self.dag())
             .process(IdSet::from_spans(ids.iter().copied()))
             .await?;
+        let request_id = protocol::new_request_id();
+        request.request_id = Some(request_id);
+        let span = tracing::debug_span!(
+            target: "dag::protocol",
+            "resolve_relative_paths_to_names",
+            request_id,
+            size = request.paths.len(),
+            operation,
+        );
+        let start = Instant::now();
         let path_names = self
             .remote_protocol
-            .resolve_relative_paths_to_names(request.paths)
+            .resolve_relative_paths_to_names(request.paths, request.request_id)
+            .instrument(span)
             .await?;
-        self.insert_relative_paths(path_names).await?;
-        let overlay = self.overlay_map.read();
+        self.metrics.remote_ids_to_names(ids.len(), start.elapsed());
+        let mut inserted: HashMap<Id, VertexName> = self
+            .insert_relative_paths(path_names)
+            .await?
+            .into_iter()
+            .collect();
+        let mut overlay = self.overlay_map.lock();
         let mut names = Vec::with_capacity(ids.len());
         for &id in ids {
-            if let Some(name) = overlay.lookup_vertex_name(id) {
+            let name = match inserted.remove(&id) {
+                Some(name) => Some(name),
+                None => overlay.lookup_vertex_name(id),
+            };
+            if let Some(name) = name {
                 names.push(name);
             } else {
                 return id.not_found();
@@ -1210,12 +1926,13 @@ where
     }
 
     /// Insert `x~n` relative paths to the overlay IdMap.
+    /// Return the inserted (id, name) pairs.
     async fn insert_relative_paths(
         &self,
         path_names: Vec<(AncestorPath, Vec<VertexName>)>,
-    ) -> Result<()> {
+    ) -> Result<Vec<(Id, VertexName)>> {
         if path_names.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
         }
         let to_insert: Vec<(Id, VertexName)> = calculate_id_name_from_paths(
             self.map(),
@@ -1229,13 +1946,13 @@ where
         paths.extend(path_names);
         drop(paths);
 
-        let mut overlay = self.overlay_map.write();
-        for (id, name) in to_insert {
+        let mut overlay = self.overlay_map.lock();
+        for (id, name) in &to_insert {
             tracing::trace!(target: "dag::cache", "cached mapping {:?} <=> {:?}", id, &name);
-            overlay.insert_vertex_id_name(id, name);
+            overlay.insert_vertex_id_name(*id, name.clone());
         }
 
-        Ok(())
+        Ok(to_insert)
     }
 }
 
@@ -1351,8 +2068,13 @@ where
         &self,
         heads: Vec<VertexName>,
         names: Vec<VertexName>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
-        let request = protocol::RequestNameToLocation { names, heads };
+        let request = protocol::RequestNameToLocation {
+            names,
+            heads,
+            request_id,
+        };
         let response: protocol::ResponseIdNamePair =
             (self.map(), self.dag()).process(request).await?;
         Ok(response.path_names)
@@ -1361,12 +2083,69 @@ where
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
-        let request = protocol::RequestLocationToName { paths };
+        let request = protocol::RequestLocationToName { paths, request_id };
         let response: protocol::ResponseIdNamePair =
             (self.map(), self.dag()).process(request).await?;
         Ok(response.path_names)
     }
+
+    async fn resolve_hex_prefix(
+        &self,
+        heads: Vec<VertexName>,
+        hex_prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        let mut head_ids = Vec::with_capacity(heads.len());
+        for id in self.map().vertex_id_batch(&heads).await? {
+            head_ids.push(id?);
+        }
+        if hex_prefix.len() < MIN_REMOTE_HEX_PREFIX_LEN {
+            return programming(format!(
+                "hex prefix {} is shorter than {} digits",
+                String::from_utf8_lossy(&hex_prefix),
+                MIN_REMOTE_HEX_PREFIX_LEN
+            ));
+        }
+        let ancestors = self.dag().ancestors(IdSet::from_spans(head_ids))?;
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        // Names outside "::heads" do not count towards `limit`. Look at more
+        // candidates until there are enough names, or no more candidates, so
+        // that a short result tells the client that there are no more names.
+        let mut candidate_limit = limit.min(MAX_REMOTE_HEX_PREFIX_CANDIDATES);
+        loop {
+            let candidates = self
+                .vertexes_by_hex_prefix(&hex_prefix, candidate_limit)
+                .await?;
+            let exhausted = candidates.len() < candidate_limit;
+            let mut names = Vec::new();
+            for name in candidates {
+                if ancestors.contains(self.map().vertex_id(name.clone()).await?) {
+                    names.push(name);
+                    if names.len() >= limit {
+                        return Ok(names);
+                    }
+                }
+            }
+            if exhausted {
+                return Ok(names);
+            }
+            if candidate_limit >= MAX_REMOTE_HEX_PREFIX_CANDIDATES {
+                // A short result would claim there are no more names.
+                return programming(format!(
+                    "hex prefix {} is ambiguous: it matches more than {} names",
+                    String::from_utf8_lossy(&hex_prefix),
+                    MAX_REMOTE_HEX_PREFIX_CANDIDATES
+                ));
+            }
+            candidate_limit = candidate_limit
+                .saturating_mul(2)
+                .min(MAX_REMOTE_HEX_PREFIX_CANDIDATES);
+        }
+    }
 }
 
 // On "snapshot".
@@ -1383,17 +2162,32 @@ where
         &self,
         heads: Vec<VertexName>,
         names: Vec<VertexName>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
         self.deref()
-            .resolve_names_to_relative_paths(heads, names)
+            .resolve_names_to_relative_paths(heads, names, request_id)
             .await
     }
 
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
-        self.deref().resolve_relative_paths_to_names(paths).await
+        self.deref()
+            .resolve_relative_paths_to_names(paths, request_id)
+            .await
+    }
+
+    async fn resolve_hex_prefix(
+        &self,
+        heads: Vec<VertexName>,
+        hex_prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        self.deref()
+            .resolve_hex_prefix(heads, hex_prefix, limit)
+            .await
     }
 }
 
@@ -1417,7 +2211,10 @@ where
         {
             Ok(set.clone())
         } else {
-            let flags = extract_ancestor_flag_if_compatible(set.hints(), self.dag_version());
+            let mut flags = extract_ancestor_flag_if_compatible(set.hints(), self.dag_version());
+            if is_full_if_compatible(set.hints(), self.dag_version()) {
+                flags |= Flags::FULL;
+            }
             let mut spans = IdSet::empty();
             let mut iter = set.iter().await?.chunks(1 << 17);
             while let Some(names) = iter.next().await {
@@ -1465,6 +2262,11 @@ where
         if set.hints().contains(Flags::ANCESTORS)
             && set.hints().dag_version() <= Some(self.dag_version())
         {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&set).await?;
+                assert_hints_hold(self.dag(), set.hints(), self.dag_version(), &ids);
+            }
             return Ok(set);
         }
         let spans = self.to_id_set(&set).await?;
@@ -1474,12 +2276,44 @@ where
         Ok(result)
     }
 
+    /// Yield ancestors lazily by walking ids from the highest down.
+    async fn ancestors_stream(&self, set: NameSet) -> Result<BoxVertexStream> {
+        let heads = self.dag().heads_ancestors(self.to_id_set(&set).await?)?;
+        let frontier: BinaryHeap<Id> = heads.iter().collect();
+        let snapshot = self.try_snapshot()?;
+        // Parents have smaller ids than their children, so visiting the
+        // highest id in the frontier first yields children before parents.
+        // An id is only pushed again by its children, which are all visited
+        // before it, so duplicates are always at the top of the heap.
+        let stream = futures::stream::try_unfold(
+            (snapshot, frontier),
+            |(snapshot, mut frontier)| async move {
+                let id = match frontier.pop() {
+                    Some(id) => id,
+                    None => return Ok(None),
+                };
+                while frontier.peek() == Some(&id) {
+                    frontier.pop();
+                }
+                frontier.extend(snapshot.dag().parent_ids(id)?);
+                let name = snapshot.vertex_name(id).await?;
+                Ok(Some((name, (snapshot, frontier))))
+            },
+        );
+        Ok(Box::pin(stream))
+    }
+
     /// Like `ancestors` but follows only the first parents.
     async fn first_ancestors(&self, set: NameSet) -> Result<NameSet> {
         // If set == ancestors(set), then first_ancestors(set) == set.
         if set.hints().contains(Flags::ANCESTORS)
             && set.hints().dag_version() <= Some(self.dag_version())
         {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&set).await?;
+                assert_hints_hold(self.dag(), set.hints(), self.dag_version(), &ids);
+            }
             return Ok(set);
         }
         let spans = self.to_id_set(&set).await?;
@@ -1544,6 +2378,11 @@ where
         if set.hints().contains(Flags::ANCESTORS)
             && set.hints().dag_version() <= Some(self.dag_version())
         {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&set).await?;
+                assert_hints_hold(self.dag(), set.hints(), self.dag_version(), &ids);
+            }
             // heads_ancestors is faster.
             return self.heads_ancestors(set).await;
         }
@@ -1632,6 +2471,28 @@ where
         Ok(result)
     }
 
+    /// Tests `is_ancestor` for each `(ancestor, descendant)` pair.
+    async fn is_ancestor_batch(&self, pairs: &[(VertexName, VertexName)]) -> Result<Vec<bool>> {
+        #[cfg(test)]
+        let result2 = crate::default_impl::is_ancestor_batch(self, pairs).await?;
+        let names: Vec<VertexName> = pairs
+            .iter()
+            .flat_map(|(ancestor, descendant)| vec![ancestor.clone(), descendant.clone()])
+            .collect();
+        let ids = self
+            .vertex_id_batch(&names)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<Id>>>()?;
+        let id_pairs: Vec<(Id, Id)> = ids.chunks(2).map(|ids| (ids[0], ids[1])).collect();
+        let result = self.dag().is_ancestor_batch(&id_pairs)?;
+        #[cfg(test)]
+        {
+            assert_eq!(&result, &result2);
+        }
+        Ok(result)
+    }
+
     /// Calculates "heads" of the ancestors of the given set. That is,
     /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
     /// `ancestors(X)`.
@@ -1655,8 +2516,41 @@ where
         Ok(result)
     }
 
+    /// Calculates heads of `ancestors(heads) - (descendants(hidden) -
+    /// ancestors(heads - hidden))`.
+    async fn visible_heads(&self, heads: NameSet, hidden: NameSet) -> Result<NameSet> {
+        let spans = self.dag().visible_heads(
+            self.to_id_set(&heads).await?,
+            self.to_id_set(&hidden).await?,
+        )?;
+        let result = NameSet::from_spans_dag(spans, self)?;
+        #[cfg(test)]
+        {
+            result.assert_eq(crate::default_impl::visible_heads(self, heads, hidden).await?);
+        }
+        Ok(result)
+    }
+
     /// Calculates the "dag range" - vertexes reachable from both sides.
     async fn range(&self, roots: NameSet, heads: NameSet) -> Result<NameSet> {
+        // Every ancestor of heads is a descendant of itself, which is a root.
+        if is_full_if_compatible(roots.hints(), self.dag_version()) {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&roots).await?;
+                assert_hints_hold(self.dag(), roots.hints(), self.dag_version(), &ids);
+            }
+            return self.ancestors(heads).await;
+        }
+        // Every descendant of roots is an ancestor of itself, which is a head.
+        if is_full_if_compatible(heads.hints(), self.dag_version()) {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&heads).await?;
+                assert_hints_hold(self.dag(), heads.hints(), self.dag_version(), &ids);
+            }
+            return self.descendants(roots).await;
+        }
         let roots = self.to_id_set(&roots).await?;
         let heads = self.to_id_set(&heads).await?;
         let spans = self.dag().range(roots, heads)?;
@@ -1666,11 +2560,40 @@ where
 
     /// Calculates the descendants of the given set.
     async fn descendants(&self, set: NameSet) -> Result<NameSet> {
+        if is_full_if_compatible(set.hints(), self.dag_version()) {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&set).await?;
+                assert_hints_hold(self.dag(), set.hints(), self.dag_version(), &ids);
+            }
+            return Ok(set);
+        }
         let spans = self.dag().descendants(self.to_id_set(&set).await?)?;
         let result = NameSet::from_spans_dag(spans, self)?;
         Ok(result)
     }
 
+    /// Calculates the descendants of the given set, up to `depth` generations.
+    async fn descendants_within(&self, set: NameSet, depth: u64) -> Result<NameSet> {
+        if is_full_if_compatible(set.hints(), self.dag_version()) {
+            #[cfg(test)]
+            {
+                let ids = self.to_id_set(&set).await?;
+                assert_hints_hold(self.dag(), set.hints(), self.dag_version(), &ids);
+            }
+            return Ok(set);
+        }
+        let spans = self
+            .dag()
+            .descendants_within(self.to_id_set(&set).await?, depth)?;
+        let result = NameSet::from_spans_dag(spans, self)?;
+        #[cfg(test)]
+        {
+            result.assert_eq(crate::default_impl::descendants_within(self, set, depth).await?);
+        }
+        Ok(result)
+    }
+
     /// Vertexes buffered in memory, not yet written to disk.
     async fn dirty(&self) -> Result<NameSet> {
         let all = self.dag().all()?;
@@ -1707,14 +2630,78 @@ fn extract_ancestor_flag_if_compatible(hints: &Hints, dag_version: &VerLink) ->
     }
 }
 
+/// Test if the set with the `hints` covers all vertexes of the DAG with
+/// `dag_version`. Unlike ANCESTORS, FULL only holds at the version it was
+/// calculated at: older versions miss vertexes added since, and newer
+/// versions might have vertexes this DAG does not.
+fn is_full_if_compatible(hints: &Hints, dag_version: &VerLink) -> bool {
+    hints.contains(Flags::FULL) && hints.dag_version() == Some(dag_version)
+}
+
+#[cfg(test)]
+thread_local! {
+    static SKIP_HINT_CHECKS: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/// Skip checking hints in fast paths until the returned guard is dropped.
+/// For tests that set incorrect hints on purpose to see which fast paths
+/// are taken.
+#[cfg(test)]
+pub(crate) fn skip_hint_checks() -> impl Drop {
+    struct Guard(bool);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            SKIP_HINT_CHECKS.with(|skip| skip.set(self.0));
+        }
+    }
+    Guard(SKIP_HINT_CHECKS.with(|skip| skip.replace(true)))
+}
+
+/// Check that the FULL and ANCESTORS hints of a set with the `ids` hold in
+/// `iddag`, so fast paths that skip work based on them do not hide wrong
+/// hints. Only used in tests, as checking costs the work that is skipped.
+#[cfg(test)]
+fn assert_hints_hold<IS: IdDagStore>(
+    iddag: &IdDag<IS>,
+    hints: &Hints,
+    dag_version: &VerLink,
+    ids: &IdSet,
+) {
+    if SKIP_HINT_CHECKS.with(|skip| skip.get()) {
+        return;
+    }
+    if is_full_if_compatible(hints, dag_version) {
+        let all = iddag.all().unwrap();
+        assert!(
+            all.difference(ids).is_empty() && ids.difference(&all).is_empty(),
+            "set {:?} has the FULL hint but all() is {:?}",
+            ids,
+            all
+        );
+    }
+    if !extract_ancestor_flag_if_compatible(hints, dag_version).is_empty() {
+        let ancestors = iddag.ancestors(ids.clone()).unwrap();
+        assert!(
+            ancestors.difference(ids).is_empty(),
+            "set {:?} has the ANCESTORS hint but misses ancestors {:?}",
+            ids,
+            ancestors.difference(ids)
+        );
+    }
+}
+
 #[async_trait::async_trait]
-impl<I, M, P, S> PrefixLookup for AbstractNameDag<I, M, P, S>
+impl<IS, M, P, S> PrefixLookup for AbstractNameDag<IdDag<IS>, M, P, S>
 where
-    I: Send + Sync,
-    M: PrefixLookup + Send + Sync,
-    P: Send + Sync,
-    S: Send + Sync,
+    IS: IdDagStore,
+    IdDag<IS>: TryClone,
+    M: IdConvert + TryClone + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Send + Sync + 'static,
 {
+    /// Lazy graphs also ask the remote for names in the master group that
+    /// are not known locally, unless `limit` names are known locally, or
+    /// the prefix is shorter than `MIN_REMOTE_HEX_PREFIX_LEN`.
     async fn vertexes_by_hex_prefix(
         &self,
         hex_prefix: &[u8],
@@ -1723,11 +2710,20 @@ where
         let mut list = self.map.vertexes_by_hex_prefix(hex_prefix, limit).await?;
         let overlay_list = self
             .overlay_map
-            .read()
+            .lock()
             .lookup_vertexes_by_hex_prefix(hex_prefix, limit)?;
         list.extend(overlay_list);
         list.sort_unstable();
         list.dedup();
+        if list.len() < limit
+            && self.is_vertex_lazy()
+            && hex_prefix.len() >= MIN_REMOTE_HEX_PREFIX_LEN
+        {
+            let remote_list = self.resolve_hex_prefix_remotely(hex_prefix, limit).await?;
+            list.extend(remote_list);
+        }
+        list.sort_unstable();
+        list.dedup();
         list.truncate(limit);
         Ok(list)
     }
@@ -1746,17 +2742,19 @@ where
         match self.map.vertex_id(name.clone()).await {
             Ok(id) => Ok(id),
             Err(crate::Error::VertexNotFound(_)) if self.is_vertex_lazy() => {
-                if let Some(id) = self.overlay_map.read().lookup_vertex_id(&name) {
+                if let Some(id) = self.overlay_map_lookup_vertex_id(&name) {
                     return Ok(id);
                 }
                 if self
                     .missing_vertexes_confirmed_by_remote
-                    .read()
+                    .lock()
                     .contains(&name)
                 {
                     return name.not_found();
                 }
-                let ids = self.resolve_vertexes_remotely(&[name.clone()]).await?;
+                let ids = self
+                    .resolve_vertexes_remotely(&[name.clone()], "vertex_id")
+                    .await?;
                 if let Some(Some(id)) = ids.first() {
                     Ok(*id)
                 } else {
@@ -1777,12 +2775,12 @@ where
             Ok(Some(id)) => Ok(Some(id)),
             Err(err) => Err(err),
             Ok(None) if self.is_vertex_lazy() => {
-                if let Some(id) = self.overlay_map.read().lookup_vertex_id(&name) {
+                if let Some(id) = self.overlay_map_lookup_vertex_id(name) {
                     return Ok(Some(id));
                 }
                 if self
                     .missing_vertexes_confirmed_by_remote
-                    .read()
+                    .lock()
                     .contains(&name)
                 {
                     return Ok(None);
@@ -1790,7 +2788,7 @@ where
                 if max_group == Group::MASTER
                     && self
                         .map
-                        .vertex_id_with_max_group(name, Group::NON_MASTER)
+                        .vertex_id_with_max_group(name, Group::MAX)
                         .await?
                         .is_some()
                 {
@@ -1798,7 +2796,10 @@ where
                     // master group.
                     return Ok(None);
                 }
-                match self.resolve_vertexes_remotely(&[name.clone()]).await {
+                match self
+                    .resolve_vertexes_remotely(&[name.clone()], "vertex_id_with_max_group")
+                    .await
+                {
                     Ok(ids) => match ids.first() {
                         Some(Some(id)) => Ok(Some(*id)),
                         Some(None) | None => Ok(None),
@@ -1814,7 +2815,7 @@ where
         match self.map.vertex_name(id).await {
             Ok(name) => Ok(name),
             Err(crate::Error::IdNotFound(_)) if self.is_vertex_lazy() => {
-                if let Some(name) = self.overlay_map.read().lookup_vertex_name(id) {
+                if let Some(name) = self.overlay_map_lookup_vertex_name(id) {
                     return Ok(name);
                 }
                 // Only ids <= max(MASTER group) can be lazy.
@@ -1822,7 +2823,7 @@ where
                 if Some(id) > max_master_id {
                     return id.not_found();
                 }
-                let names = self.resolve_ids_remotely(&[id]).await?;
+                let names = self.resolve_ids_remotely(&[id], "vertex_name").await?;
                 if let Some(name) = names.into_iter().next() {
                     Ok(name)
                 } else {
@@ -1837,17 +2838,20 @@ where
         match self.map.contains_vertex_name(name).await {
             Ok(true) => Ok(true),
             Ok(false) if self.is_vertex_lazy() => {
-                if self.overlay_map.read().lookup_vertex_id(name).is_some() {
+                if self.overlay_map_lookup_vertex_id(name).is_some() {
                     return Ok(true);
                 }
                 if self
                     .missing_vertexes_confirmed_by_remote
-                    .read()
+                    .lock()
                     .contains(&name)
                 {
                     return Ok(false);
                 }
-                match self.resolve_vertexes_remotely(&[name.clone()]).await {
+                match self
+                    .resolve_vertexes_remotely(&[name.clone()], "contains_vertex_name")
+                    .await
+                {
                     Ok(ids) => match ids.first() {
                         Some(Some(_)) => Ok(true),
                         Some(None) | None => Ok(false),
@@ -1862,7 +2866,7 @@ where
 
     async fn contains_vertex_id_locally(&self, ids: &[Id]) -> Result<Vec<bool>> {
         let mut list = self.map.contains_vertex_id_locally(ids).await?;
-        let map = self.overlay_map.read();
+        let mut map = self.overlay_map.lock();
         for (b, id) in list.iter_mut().zip(ids.iter().copied()) {
             if !*b {
                 *b = *b || map.has_vertex_id(id);
@@ -1876,7 +2880,7 @@ where
         let mut list = self.map.contains_vertex_name_locally(names).await?;
         tracing::trace!("contains_vertex_name_locally list (local): {:?}", &list);
         assert_eq!(list.len(), names.len());
-        let map = self.overlay_map.read();
+        let mut map = self.overlay_map.lock();
         for (b, name) in list.iter_mut().zip(names.iter()) {
             if !*b && map.has_vertex_name(name) {
                 tracing::trace!("contains_vertex_name_locally overlay has {:?}", &name);
@@ -1891,12 +2895,15 @@ where
         if self.is_vertex_lazy() {
             // Read from overlay map cache.
             {
-                let map = self.overlay_map.read();
+                let mut map = self.overlay_map.lock();
+                let mut hits = 0;
                 for (r, id) in list.iter_mut().zip(ids) {
                     if let Some(name) = map.lookup_vertex_name(*id) {
                         *r = Ok(name);
+                        hits += 1;
                     }
                 }
+                self.metrics.overlay_map_lookups(hits, ids.len() - hits);
             }
             // Read from missing_vertexes_confirmed_by_remote cache.
             let missing_indexes: Vec<usize> = {
@@ -1911,7 +2918,9 @@ where
                     .collect()
             };
             let missing_ids: Vec<Id> = missing_indexes.iter().map(|i| ids[*i]).collect();
-            let resolved = self.resolve_ids_remotely(&missing_ids).await?;
+            let resolved = self
+                .resolve_ids_remotely(&missing_ids, "vertex_name_batch")
+                .await?;
             for (i, name) in missing_indexes.into_iter().zip(resolved.into_iter()) {
                 list[i] = Ok(name);
             }
@@ -1924,16 +2933,19 @@ where
         if self.is_vertex_lazy() {
             // Read from overlay map cache.
             {
-                let map = self.overlay_map.read();
+                let mut map = self.overlay_map.lock();
+                let mut hits = 0;
                 for (r, name) in list.iter_mut().zip(names) {
                     if let Some(id) = map.lookup_vertex_id(name) {
                         *r = Ok(id);
+                        hits += 1;
                     }
                 }
+                self.metrics.overlay_map_lookups(hits, names.len() - hits);
             }
             // Read from missing_vertexes_confirmed_by_remote cache.
             let missing_indexes: Vec<usize> = {
-                let known_missing = self.missing_vertexes_confirmed_by_remote.read();
+                let mut known_missing = self.missing_vertexes_confirmed_by_remote.lock();
                 list.iter()
                     .enumerate()
                     .filter_map(|(i, r)| {
@@ -1948,7 +2960,9 @@ where
             if !missing_indexes.is_empty() {
                 let missing_names: Vec<VertexName> =
                     missing_indexes.iter().map(|i| names[*i].clone()).collect();
-                let resolved = self.resolve_vertexes_remotely(&missing_names).await?;
+                let resolved = self
+                    .resolve_vertexes_remotely(&missing_names, "vertex_id_batch")
+                    .await?;
                 for (i, id) in missing_indexes.into_iter().zip(resolved.into_iter()) {
                     if let Some(id) = id {
                         list[i] = Ok(id);
@@ -1959,6 +2973,50 @@ where
         Ok(list)
     }
 
+    async fn prefetch_names(&self, names: &[VertexName]) -> Result<()> {
+        if !self.is_vertex_lazy() {
+            return Ok(());
+        }
+        let local = self.contains_vertex_name_locally(names).await?;
+        let missing_names: Vec<VertexName> = {
+            let mut known_missing = self.missing_vertexes_confirmed_by_remote.lock();
+            let mut seen = HashSet::new();
+            names
+                .iter()
+                .zip(local)
+                .filter(|(name, local)| !local && !known_missing.contains(name))
+                .filter(|(name, _)| seen.insert(*name))
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        tracing::debug!(target: "dag::protocol", "prefetch {} names", missing_names.len());
+        self.resolve_vertexes_remotely(&missing_names, "prefetch_names")
+            .await?;
+        Ok(())
+    }
+
+    async fn prefetch_ids(&self, ids: &[Id]) -> Result<()> {
+        if !self.is_vertex_lazy() {
+            return Ok(());
+        }
+        let local = self.contains_vertex_id_locally(ids).await?;
+        let mut missing_ids: Vec<Id> = {
+            // Only resolve ids that are <= max(master) remotely.
+            let max_master_id = self.dag.master_group()?.max();
+            ids.iter()
+                .zip(local)
+                .filter(|(&id, local)| !local && Some(id) <= max_master_id)
+                .map(|(&id, _)| id)
+                .collect()
+        };
+        missing_ids.sort_unstable();
+        missing_ids.dedup();
+        tracing::debug!(target: "dag::protocol", "prefetch {} ids", missing_ids.len());
+        self.resolve_ids_remotely(&missing_ids, "prefetch_ids")
+            .await?;
+        Ok(())
+    }
+
     fn map_id(&self) -> &str {
         self.map.map_id()
     }
@@ -1968,6 +3026,137 @@ where
     }
 }
 
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore,
+    IdDag<IS>: TryClone + 'static,
+    M: IdConvert + TryClone + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Send + Sync + 'static,
+{
+    /// Calculates the "dag range", like `range`, but stops once more than
+    /// `max_count` vertexes are found.
+    ///
+    /// Return the range, and whether it was truncated. A truncated range
+    /// contains the `max_count` vertexes with the smallest ids.
+    pub async fn range_limited(
+        &self,
+        roots: NameSet,
+        heads: NameSet,
+        max_count: u64,
+    ) -> Result<(NameSet, bool)> {
+        let roots = self.to_id_set(&roots).await?;
+        let heads = self.to_id_set(&heads).await?;
+        let (spans, truncated) = self.dag().range_limited(roots, heads, max_count)?;
+        let result = NameSet::from_spans_dag(spans, self)?;
+        Ok((result, truncated))
+    }
+
+    /// Calculates "heads" of the ancestors of the given set, like
+    /// `heads_ancestors`, and the number of ancestors each head contributes.
+    ///
+    /// Heads are in descending id order. An ancestor shared by several heads
+    /// is only counted for the first of them.
+    pub async fn heads_ancestors_with_counts(
+        &self,
+        set: NameSet,
+    ) -> Result<Vec<(VertexName, u64)>> {
+        let id_counts = self
+            .dag()
+            .heads_ancestors_with_counts(self.to_id_set(&set).await?)?;
+        let ids: Vec<Id> = id_counts.iter().map(|(id, _)| *id).collect();
+        let names = self
+            .vertex_name_batch(&ids)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<VertexName>>>()?;
+        let result = names
+            .into_iter()
+            .zip(id_counts)
+            .map(|(name, (_, count))| (name, count))
+            .collect();
+        Ok(result)
+    }
+
+    /// Calculate children of each of the given vertexes.
+    ///
+    /// Unlike calling `children` for each vertex, this resolves all vertexes
+    /// in one batch, then all children in one batch. So a lazy graph needs
+    /// at most two remote lookups. Children are in descending id order.
+    pub async fn children_batch(&self, names: &[VertexName]) -> Result<Vec<Vec<VertexName>>> {
+        let ids = self
+            .vertex_id_batch(names)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<Id>>>()?;
+        let mut lens = Vec::with_capacity(ids.len());
+        let mut child_ids = Vec::new();
+        for id in ids {
+            let children = self.dag().children_id(id)?;
+            lens.push(children.count() as usize);
+            child_ids.extend(children.iter());
+        }
+        let mut child_names = self
+            .vertex_name_batch(&child_ids)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<VertexName>>>()?
+            .into_iter();
+        let result = lens
+            .into_iter()
+            .map(|len| child_names.by_ref().take(len).collect())
+            .collect();
+        Ok(result)
+    }
+
+    /// Like `gca_one`, but also explain how each vertex in `set` reaches
+    /// the chosen "greatest common ancestor".
+    ///
+    /// Return `None` if there are no common ancestors.
+    pub async fn gca_with_paths(&self, set: NameSet) -> Result<Option<GcaWithPaths>> {
+        let ids = self.to_id_set(&set).await?;
+        let gca_id = match self.dag().gca_one(ids.clone())? {
+            None => return Ok(None),
+            Some(id) => id,
+        };
+        let mut paths = Vec::with_capacity(ids.count() as usize);
+        for id in ids.iter() {
+            let path = self.dag().path(gca_id, id)?;
+            paths.push(GcaPath {
+                head: self.vertex_name(id).await?,
+                first_parent_distance: self.dag().first_parent_distance(gca_id, id)?,
+                segments: self.dag().idset_to_flat_segments(path)?,
+            });
+        }
+        let result = GcaWithPaths {
+            gca: self.vertex_name(gca_id).await?,
+            paths,
+        };
+        Ok(Some(result))
+    }
+}
+
+/// A "greatest common ancestor" with paths reaching it, returned by
+/// [`AbstractNameDag::gca_with_paths`].
+#[derive(Debug, Clone)]
+pub struct GcaWithPaths {
+    pub gca: VertexName,
+    /// One path per input vertex, in descending id order.
+    pub paths: Vec<GcaPath>,
+}
+
+/// How an input vertex reaches the "greatest common ancestor".
+#[derive(Debug, Clone)]
+pub struct GcaPath {
+    pub head: VertexName,
+    /// Number of first parents to follow from `head` to reach the
+    /// ancestor, or `None` if the ancestor is not a first ancestor.
+    pub first_parent_distance: Option<u64>,
+    /// One path from `head` to the ancestor, including both ends, as flat
+    /// segments. First parents are followed where possible.
+    pub segments: PreparedFlatSegments,
+}
+
 impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
 where
     IS: IdDagStore,
@@ -2020,9 +3209,22 @@ where
             heads.sort_unstable();
             tracing::debug!(target: "dag::reassign", "non-master heads: {} entries", heads.len());
 
+            // Keep hidden heads hidden. Hidden vertexes that are also ancestors
+            // of visible heads have a NON_MASTER id, which `vertex_id` prefers.
+            let mut hidden_heads = Vec::new();
+            let mut non_master_heads = Vec::new();
+            for head in heads.iter() {
+                if self.vertex_id(head.clone()).await?.group() == Group::HIDDEN {
+                    hidden_heads.push(head.clone());
+                } else {
+                    non_master_heads.push(head.clone());
+                }
+            }
+
             // Remove existing non-master data.
             self.dag.remove_non_master()?;
             self.map.remove_non_master().await?;
+            self.vertex_meta.remove_non_master();
 
             // Populate vertex negative cache to reduce round-trips doing remote lookups.
             if self.is_vertex_lazy() {
@@ -2031,7 +3233,8 @@ where
             }
 
             // Rebuild them.
-            self.build(&parents, &[], &heads[..]).await?;
+            self.build(&parents, &[], &non_master_heads, &hidden_heads)
+                .await?;
 
             Ok(())
         };
@@ -2044,6 +3247,7 @@ where
         parent_names_func: &dyn Parents,
         master_heads: &[VertexName],
         non_master_heads: &[VertexName],
+        hidden_heads: &[VertexName],
     ) -> Result<()> {
         // Update IdMap.
         let mut outcome = PreparedFlatSegments::default();
@@ -2052,6 +3256,7 @@ where
         for (nodes, group) in [
             (master_heads, Group::MASTER),
             (non_master_heads, Group::NON_MASTER),
+            (hidden_heads, Group::HIDDEN),
         ] {
             for node in nodes.iter() {
                 // Important: do not call self.map.assign_head. It does not trigger
@@ -2114,94 +3319,6 @@ where
     S: Send + Sync,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        debug(&self.dag, &self.map, f)
-    }
-}
-
-pub(crate) fn debug_segments_by_level_group<S: IdDagStore>(
-    iddag: &IdDag<S>,
-    idmap: &dyn IdConvert,
-    level: Level,
-    group: Group,
-) -> Vec<String> {
-    let mut result = Vec::new();
-    // Show Id, with optional hash.
-    let show = |id: Id| DebugId {
-        id,
-        name: non_blocking_result(idmap.vertex_name(id)).ok(),
-    };
-    let show_flags = |flags: SegmentFlags| -> String {
-        let mut result = Vec::new();
-        if flags.contains(SegmentFlags::HAS_ROOT) {
-            result.push("Root");
-        }
-        if flags.contains(SegmentFlags::ONLY_HEAD) {
-            result.push("OnlyHead");
-        }
-        result.join(" ")
-    };
-
-    if let Ok(segments) = iddag.next_segments(group.min_id(), level) {
-        for segment in segments.into_iter().rev() {
-            if let (Ok(span), Ok(parents), Ok(flags)) =
-                (segment.span(), segment.parents(), segment.flags())
-            {
-                let mut line = format!(
-                    "{:.12?} : {:.12?} {:.12?}",
-                    show(span.low),
-                    show(span.high),
-                    parents.into_iter().map(show).collect::<Vec<_>>(),
-                );
-                let flags = show_flags(flags);
-                if !flags.is_empty() {
-                    line += &format!(" {}", flags);
-                }
-                result.push(line);
-            }
-        }
-    }
-    result
-}
-
-fn debug<S: IdDagStore>(
-    iddag: &IdDag<S>,
-    idmap: &dyn IdConvert,
-    f: &mut fmt::Formatter,
-) -> fmt::Result {
-    if let Ok(max_level) = iddag.max_level() {
-        writeln!(f, "Max Level: {}", max_level)?;
-        for lv in (0..=max_level).rev() {
-            writeln!(f, " Level {}", lv)?;
-            for group in Group::ALL.iter().cloned() {
-                writeln!(f, "  {}:", group)?;
-                if let Ok(id) = iddag.next_free_id(0, group) {
-                    writeln!(f, "   Next Free Id: {}", id)?;
-                }
-                if let Ok(segments) = iddag.next_segments(group.min_id(), lv) {
-                    writeln!(f, "   Segments: {}", segments.len())?;
-                    for line in debug_segments_by_level_group(iddag, idmap, lv, group) {
-                        writeln!(f, "    {}", line)?;
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(())
-}
-
-struct DebugId {
-    id: Id,
-    name: Option<VertexName>,
-}
-
-impl fmt::Debug for DebugId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(name) = &self.name {
-            fmt::Debug::fmt(&name, f)?;
-            f.write_str("+")?;
-        }
-        write!(f, "{:?}", self.id)?;
-        Ok(())
+        crate::dump::debug(&self.dag, &self.map, f)
     }
 }
@@ -0,0 +1,430 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Caches of `NameDag` that are populated by remote lookups.
+//!
+//! The overlay IdMap and the negative cache can be bounded. When a bounded
+//! cache is full, the least recently used entries are evicted. Evicted
+//! entries are looked up remotely again if they are needed.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::id::Id;
+use crate::id::VertexName;
+use crate::Result;
+
+/// Capacity limits of the caches of a `NameDag`. `None` means unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheLimits {
+    /// Max number of (id, name) pairs in the overlay IdMap.
+    pub overlay_map: Option<usize>,
+    /// Max number of vertexes in the negative cache.
+    pub missing_vertexes: Option<usize>,
+}
+
+/// Statistics of one cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LruStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of entries currently in the cache.
+    pub len: usize,
+}
+
+/// Statistics of the caches of a `NameDag`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub overlay_map: LruStats,
+    pub missing_vertexes: LruStats,
+}
+
+/// Tracks the recency of keys.
+struct Recency<K> {
+    ticks: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+    next_tick: u64,
+}
+
+impl<K> Default for Recency<K> {
+    fn default() -> Self {
+        Self {
+            ticks: Default::default(),
+            order: Default::default(),
+            next_tick: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Recency<K> {
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    /// Mark `key` as the most recently used. Insert it if it is missing.
+    fn touch(&mut self, key: K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(old_tick) = self.ticks.insert(key.clone(), tick) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, key);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Remove and return the least recently used key.
+    fn pop_oldest(&mut self) -> Option<K> {
+        let (&tick, _) = self.order.iter().next()?;
+        let key = self.order.remove(&tick)?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+/// Overlay IdMap. Stores IdMap results resolved using remote protocols.
+#[derive(Default)]
+pub(crate) struct OverlayIdMap {
+    id2name: HashMap<Id, VertexName>,
+    name2id: BTreeMap<VertexName, Id>,
+    recency: Recency<Id>,
+    capacity: Option<usize>,
+    stats: LruStats,
+}
+
+impl OverlayIdMap {
+    /// An empty map with the same capacity and statistics.
+    pub(crate) fn cleared(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            stats: self.stats,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn lookup_vertex_id(&mut self, name: &VertexName) -> Option<Id> {
+        let id = self.name2id.get(name).copied();
+        self.record_lookup(id);
+        id
+    }
+
+    pub(crate) fn lookup_vertex_name(&mut self, id: Id) -> Option<VertexName> {
+        let name = self.id2name.get(&id).cloned();
+        self.record_lookup(name.as_ref().map(|_| id));
+        name
+    }
+
+    pub(crate) fn has_vertex_name(&mut self, name: &VertexName) -> bool {
+        self.lookup_vertex_id(name).is_some()
+    }
+
+    pub(crate) fn has_vertex_id(&mut self, id: Id) -> bool {
+        self.lookup_vertex_name(id).is_some()
+    }
+
+    /// Prefix lookups scan the map, so they do not affect recency or stats.
+    pub(crate) fn lookup_vertexes_by_hex_prefix(
+        &self,
+        hex_prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        let start = VertexName::from_hex(hex_prefix)?;
+        let mut result = Vec::new();
+        for (vertex, _) in self.name2id.range(start..) {
+            if !vertex.to_hex().as_bytes().starts_with(hex_prefix) {
+                break;
+            }
+            result.push(vertex.clone());
+            if result.len() >= limit {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn insert_vertex_id_name(&mut self, id: Id, name: VertexName) {
+        if let Some(old_name) = self.id2name.insert(id, name.clone()) {
+            if old_name != name {
+                self.name2id.remove(&old_name);
+            }
+        }
+        if let Some(old_id) = self.name2id.insert(name, id) {
+            if old_id != id {
+                self.id2name.remove(&old_id);
+                self.recency.remove(&old_id);
+            }
+        }
+        self.recency.touch(id);
+        self.evict();
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub(crate) fn stats(&self) -> LruStats {
+        LruStats {
+            len: self.id2name.len(),
+            ..self.stats
+        }
+    }
+
+    fn record_lookup(&mut self, found: Option<Id>) {
+        match found {
+            Some(id) => {
+                self.stats.hits += 1;
+                self.recency.touch(id);
+            }
+            None => self.stats.misses += 1,
+        }
+    }
+
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.recency.len() > capacity {
+            let id = match self.recency.pop_oldest() {
+                Some(id) => id,
+                None => break,
+            };
+            if let Some(name) = self.id2name.remove(&id) {
+                self.name2id.remove(&name);
+                self.stats.evictions += 1;
+                tracing::trace!(target: "dag::cache", "evicted mapping {:?} <=> {:?}", id, &name);
+            }
+        }
+    }
+}
+
+/// Persistent storage of a `MissingVertexes`, so the negative cache survives
+/// process restarts.
+pub(crate) trait MissingVertexesStore: Send + Sync {
+    /// Record that the remote confirmed `names` are missing, when the next
+    /// free id of the master group was `master_next_id`.
+    fn append(&self, names: &[VertexName], master_next_id: Id) -> Result<()>;
+}
+
+/// A negative cache. Vertexes that are looked up remotely, and the remote
+/// confirmed the vertexes are outside the master group.
+#[derive(Default)]
+pub(crate) struct MissingVertexes {
+    recency: Recency<VertexName>,
+    capacity: Option<usize>,
+    stats: LruStats,
+    store: Option<Arc<dyn MissingVertexesStore>>,
+}
+
+impl MissingVertexes {
+    /// An empty cache with the same capacity, statistics and store.
+    pub(crate) fn cleared(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            stats: self.stats,
+            store: self.store.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_store(&mut self, store: Arc<dyn MissingVertexesStore>) {
+        self.store = Some(store);
+    }
+
+    /// Where vertexes confirmed missing by the remote should be persisted.
+    pub(crate) fn store(&self) -> Option<Arc<dyn MissingVertexesStore>> {
+        self.store.clone()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.recency.len()
+    }
+
+    pub(crate) fn contains(&mut self, name: &VertexName) -> bool {
+        if self.recency.contains(name) {
+            self.stats.hits += 1;
+            self.recency.touch(name.clone());
+            true
+        } else {
+            self.stats.misses += 1;
+            false
+        }
+    }
+
+    /// Insert a vertex. Return `true` if it was not in the cache.
+    pub(crate) fn insert(&mut self, name: VertexName) -> bool {
+        let inserted = !self.recency.contains(&name);
+        self.recency.touch(name);
+        self.evict();
+        inserted
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub(crate) fn stats(&self) -> LruStats {
+        LruStats {
+            len: self.recency.len(),
+            ..self.stats
+        }
+    }
+
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.recency.len() > capacity {
+            match self.recency.pop_oldest() {
+                Some(name) => {
+                    self.stats.evictions += 1;
+                    tracing::trace!(target: "dag::cache", "evicted missing {:?}", &name);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Results of hex prefix lookups resolved remotely, keyed by prefix.
+///
+/// Prefix lookups are user-driven, like resolving a short hash typed on the
+/// command line, so the cache is not bounded.
+#[derive(Default)]
+pub(crate) struct RemoteHexPrefixes {
+    /// The limit of the lookup, and the names it returned.
+    entries: HashMap<Vec<u8>, (usize, Vec<VertexName>)>,
+}
+
+impl RemoteHexPrefixes {
+    /// Names starting with `hex_prefix`, if a previous lookup answers a
+    /// lookup with `limit`. That is the case if the previous lookup had a
+    /// limit at least as large, or returned all matching names.
+    pub(crate) fn get(&self, hex_prefix: &[u8], limit: usize) -> Option<Vec<VertexName>> {
+        let (cached_limit, names) = self.entries.get(hex_prefix)?;
+        if limit <= *cached_limit || names.len() < *cached_limit {
+            Some(names.iter().take(limit).cloned().collect())
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn insert(&mut self, hex_prefix: Vec<u8>, limit: usize, names: Vec<VertexName>) {
+        self.entries.insert(hex_prefix, (limit, names));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_map_eviction() {
+        let mut map = OverlayIdMap::default();
+        map.set_capacity(Some(2));
+        map.insert_vertex_id_name(Id(1), "A".into());
+        map.insert_vertex_id_name(Id(2), "B".into());
+
+        // Using A makes B the least recently used.
+        assert_eq!(map.lookup_vertex_id(&"A".into()), Some(Id(1)));
+        map.insert_vertex_id_name(Id(3), "C".into());
+        assert_eq!(map.lookup_vertex_name(Id(2)), None);
+        assert!(map.has_vertex_name(&"A".into()));
+        assert!(map.has_vertex_id(Id(3)));
+
+        assert_eq!(
+            map.stats(),
+            LruStats {
+                hits: 3,
+                misses: 1,
+                evictions: 1,
+                len: 2,
+            }
+        );
+
+        // Shrinking evicts immediately. Clearing keeps stats.
+        map.set_capacity(Some(1));
+        assert_eq!(map.stats().evictions, 2);
+        assert_eq!(map.lookup_vertexes_by_hex_prefix(b"", 10).unwrap().len(), 1);
+        let map = map.cleared();
+        assert_eq!(map.stats().len, 0);
+        assert_eq!(map.stats().evictions, 2);
+    }
+
+    #[test]
+    fn test_overlay_map_reassign() {
+        let mut map = OverlayIdMap::default();
+        map.set_capacity(Some(2));
+        map.insert_vertex_id_name(Id(1), "A".into());
+        map.insert_vertex_id_name(Id(1), "B".into());
+        assert_eq!(map.lookup_vertex_id(&"A".into()), None);
+        assert_eq!(map.lookup_vertex_name(Id(1)), Some("B".into()));
+        map.insert_vertex_id_name(Id(2), "B".into());
+        assert_eq!(map.lookup_vertex_name(Id(1)), None);
+        assert_eq!(map.stats().len, 1);
+        assert_eq!(map.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_missing_vertexes_eviction() {
+        let mut missing = MissingVertexes::default();
+        missing.set_capacity(Some(2));
+        assert!(missing.insert("A".into()));
+        assert!(!missing.insert("A".into()));
+        assert!(missing.insert("B".into()));
+        assert!(missing.contains(&"A".into()));
+        assert!(missing.insert("C".into()));
+        assert!(!missing.contains(&"B".into()));
+        assert!(missing.contains(&"A".into()));
+        assert_eq!(
+            missing.stats(),
+            LruStats {
+                hits: 2,
+                misses: 1,
+                evictions: 1,
+                len: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_remote_hex_prefixes() {
+        let mut prefixes = RemoteHexPrefixes::default();
+        prefixes.insert(b"ab".to_vec(), 2, vec!["A".into(), "B".into()]);
+        assert_eq!(prefixes.get(b"ab", 1), Some(vec!["A".into()]));
+        assert_eq!(prefixes.get(b"ab", 2).map(|names| names.len()), Some(2));
+        // There might be more matches than the lookup returned.
+        assert_eq!(prefixes.get(b"ab", 3), None);
+        assert_eq!(prefixes.get(b"a", 1), None);
+
+        // A lookup that returned fewer names than its limit is complete.
+        prefixes.insert(b"cd".to_vec(), 2, vec!["C".into()]);
+        assert_eq!(prefixes.get(b"cd", 10), Some(vec!["C".into()]));
+    }
+}
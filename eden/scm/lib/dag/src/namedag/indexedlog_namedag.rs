@@ -8,10 +8,21 @@
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
+use indexedlog::lock::ScopedDirLock;
 use indexedlog::multi;
 use indexedlog::DefaultOpenOptions;
+use indexedlog::Repair;
+use parking_lot::Mutex;
 
+use super::cache::MissingVertexes;
+use super::journal::FlushJournal;
+use super::lease::Lease;
+use super::lease::LeaseOptions;
+use super::missing_log::MissingVertexLog;
+use super::vertex_meta::VertexMeta;
+use super::vertex_meta_log::VertexMetaLog;
 use super::AbstractNameDag;
 use crate::errors::bug;
 use crate::iddag::IdDag;
@@ -22,6 +33,7 @@ use crate::ops::Open;
 use crate::ops::Persist;
 use crate::ops::TryClone;
 use crate::Group;
+use crate::Id;
 use crate::Result;
 
 /// A DAG that uses VertexName instead of ids as vertexes.
@@ -35,6 +47,18 @@ pub struct NameDagState {
     /// `MultiLog` controls on-disk metadata.
     /// `None` for read-only `NameDag`,
     mlog: Option<multi::MultiLog>,
+
+    /// Marks a flush in progress. See `journal.rs`.
+    journal: FlushJournal,
+
+    /// Take the write lease before the lock. See `lease.rs`.
+    lease: Option<(PathBuf, LeaseOptions)>,
+}
+
+/// Lock of [`NameDagState`]. The lease, if any, is released after the lock.
+pub struct NameDagLock {
+    mlog: multi::LockGuard,
+    _lease: Option<Lease>,
 }
 
 /// Address to on-disk NameDag based on indexedlog.
@@ -45,25 +69,68 @@ impl Open for IndexedLogNameDagPath {
     type OpenTarget = NameDag;
 
     fn open(&self) -> Result<Self::OpenTarget> {
+        self.open_with_options(NameDag::default_open_options(), false, None)
+    }
+}
+
+impl IndexedLogNameDagPath {
+    fn open_with_options(
+        &self,
+        opts: multi::OpenOptions,
+        read_only: bool,
+        lease: Option<LeaseOptions>,
+    ) -> Result<NameDag> {
         crate::failpoint!("dag-namedag-open");
         let path = &self.0;
-        let opts = NameDag::default_open_options();
-        tracing::debug!(target: "dag::open",  "open at {:?}", path.display());
-        let mut mlog = opts.open(path)?;
+        tracing::debug!(
+            target: "dag::open",
+            "open at {:?} (read_only: {})",
+            path.display(),
+            read_only
+        );
+        let journal = FlushJournal::new(path);
+        let mut mlog = match opts.open(path) {
+            Ok(mlog) => mlog,
+            Err(e) if !read_only && journal.pending()?.is_some() => {
+                repair_interrupted_flush(path, &journal, &lease, e)?;
+                opts.open(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !read_only {
+            recover_interrupted_flush(&mut mlog, &journal)?;
+        }
+        let lease = lease.map(|opts| (path.clone(), opts));
         let mut logs = mlog.detach_logs();
+        // Read-only `NameDag`s written before flags existed have no flags.
+        let vertex_meta_log = if logs.len() > 2 { logs.pop() } else { None };
         let dag_log = logs.pop().unwrap();
         let map_log = logs.pop().unwrap();
         let map = IdMap::open_from_log(map_log)?;
         let dag = IdDag::open_from_store(IndexedLogStore::open_from_clean_log(dag_log)?)?;
-        let state = NameDagState { mlog: Some(mlog) };
+        let state = NameDagState {
+            mlog: Some(mlog),
+            journal,
+            lease,
+        };
         let overlay_map_next_id = map.next_free_id(Group::MASTER)?;
         let persisted_id_set = dag.all_ids_in_groups(&Group::ALL)?;
+        let missing_vertexes = open_missing_vertexes(
+            &path.join("missing"),
+            dag.next_free_id(0, Group::MASTER)?,
+            read_only,
+        );
+        let mut vertex_meta = VertexMeta::default();
+        if let Some(log) = vertex_meta_log {
+            vertex_meta.set_store(Arc::new(VertexMetaLog::open_from_log(log)));
+        }
         Ok(AbstractNameDag {
             dag,
             map,
             path: self.clone(),
             snapshot: Default::default(),
             pending_heads: Default::default(),
+            pending_hidden_heads: Default::default(),
             persisted_id_set,
             state,
             id: format!("ilog:{}", self.0.display()),
@@ -71,13 +138,121 @@ impl Open for IndexedLogNameDagPath {
             overlay_map_next_id,
             overlay_map_paths: Default::default(),
             remote_protocol: Arc::new(()),
-            missing_vertexes_confirmed_by_remote: Default::default(),
+            missing_vertexes_confirmed_by_remote: Arc::new(Mutex::new(missing_vertexes)),
+            hex_prefixes_resolved_by_remote: Default::default(),
+            metrics: Arc::new(()),
+            query_budget: None,
+            vertex_meta,
+            read_only,
         })
     }
 }
 
+/// Repair the logs left half-written by a writer that died mid-flush, which
+/// made opening fail with `error`.
+///
+/// A live writer holds the lock until its flush is finished, so the journal
+/// is checked again with the lock held, and nothing is repaired if the flush
+/// finished meanwhile. `repair` takes the lock itself, so the lock is
+/// released before it. Writers with a lease are kept out for the whole
+/// repair by the lease.
+fn repair_interrupted_flush(
+    path: &Path,
+    journal: &FlushJournal,
+    lease: &Option<LeaseOptions>,
+    error: indexedlog::Error,
+) -> Result<()> {
+    let _lease = match lease {
+        Some(opts) => Some(Lease::acquire(path, opts)?),
+        None => None,
+    };
+    {
+        let _lock = ScopedDirLock::new(path)?;
+        if journal.pending()?.is_none() {
+            return Ok(());
+        }
+    }
+    tracing::warn!(
+        target: "dag::journal",
+        "repairing after interrupted flush: {}",
+        error
+    );
+    let message = NameDag::repair(path)?;
+    tracing::debug!(target: "dag::journal", "repaired:\n{}", message);
+    Ok(())
+}
+
+/// Clear the journal left by a writer that died mid-flush. The flush is
+/// rolled back if the MultiMeta was not written, and committed otherwise.
+fn recover_interrupted_flush(mlog: &mut multi::MultiLog, journal: &FlushJournal) -> Result<()> {
+    if journal.pending()?.is_none() {
+        return Ok(());
+    }
+    // A live writer holds the lock until the journal is finished. So if the
+    // journal is still there once the lock is taken, the writer is gone.
+    // The lock is not waited for: if it is taken, the journal is left to
+    // its holder, and a writer taking the lock later replaces it anyway.
+    let _lock = match mlog.try_lock()? {
+        Some(lock) => lock,
+        None => return Ok(()),
+    };
+    if let Some(version) = journal.pending()? {
+        let outcome = if mlog.version() == version {
+            "rolled back"
+        } else {
+            "committed"
+        };
+        tracing::warn!(
+            target: "dag::journal",
+            "found interrupted flush from version {:?}, which was {}",
+            version,
+            outcome
+        );
+        journal.finish()?;
+    }
+    Ok(())
+}
+
+/// Load the persisted negative cache. Read-only `NameDag`s use it, but do not
+/// add to it. Errors are not fatal: the cache just starts empty.
+fn open_missing_vertexes(path: &Path, master_next_id: Id, read_only: bool) -> MissingVertexes {
+    let mut missing = MissingVertexes::default();
+    let log = match MissingVertexLog::open(path, !read_only) {
+        Ok(log) => log,
+        Err(e) => {
+            tracing::debug!(target: "dag::cache", "cannot open missing vertexes: {}", e);
+            return missing;
+        }
+    };
+    match log.load(master_next_id) {
+        Ok(names) => {
+            tracing::debug!(target: "dag::cache", "loaded {} missing vertexes", names.len());
+            for name in names {
+                missing.insert(name);
+            }
+        }
+        Err(e) => tracing::warn!(target: "dag::cache", "cannot load missing vertexes: {}", e),
+    }
+    if !read_only {
+        missing.set_store(Arc::new(log));
+    }
+    missing
+}
+
 impl DefaultOpenOptions<multi::OpenOptions> for NameDag {
     fn default_open_options() -> multi::OpenOptions {
+        multi::OpenOptions::from_name_opts(vec![
+            ("idmap2", IdMap::log_open_options()),
+            ("iddag", IndexedLogStore::log_open_options()),
+            ("vertexmeta", VertexMetaLog::log_open_options()),
+        ])
+    }
+}
+
+impl NameDag {
+    /// The `MultiLog` of a `NameDag` written before vertex flags were part
+    /// of it, which can't be opened with all the logs in read-only mode.
+    pub(crate) fn open_options_without_vertex_meta() -> multi::OpenOptions {
         multi::OpenOptions::from_name_opts(vec![
             ("idmap2", IdMap::log_open_options()),
             ("iddag", IndexedLogStore::log_open_options()),
@@ -91,10 +266,59 @@ impl NameDag {
         let path = IndexedLogNameDagPath(path);
         path.open()
     }
+
+    /// Open a `NameDag` shared by writers that might not be able to wait
+    /// for each other indefinitely, for example several services.
+    ///
+    /// Writes take the write lease first. They fail with
+    /// [`DagError::LeaseTimeout`](crate::errors::DagError::LeaseTimeout),
+    /// naming the holder, if another writer holds it for longer than the
+    /// timeout in `lease`. All writers of the directory should use a lease.
+    pub fn open_with_lease(path: impl AsRef<Path>, lease: LeaseOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let path = IndexedLogNameDagPath(path);
+        path.open_with_options(NameDag::default_open_options(), false, Some(lease))
+    }
+
+    /// Open an existing `NameDag` for reading only.
+    ///
+    /// Unlike `open`, this never takes the write lock, and does not create
+    /// missing files. So it can be used to inspect a live repo without
+    /// blocking writers. Mutations like `add_heads`, `flush`, or
+    /// `import_pull_data` fail with a programming error.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let path = IndexedLogNameDagPath(path);
+        let opts = NameDag::default_open_options().read_only(true);
+        match path.open_with_options(opts, true, None) {
+            Ok(dag) => Ok(dag),
+            Err(_) => {
+                let opts = NameDag::open_options_without_vertex_meta().read_only(true);
+                path.open_with_options(opts, true, None)
+            }
+        }
+    }
+}
+
+/// Take the lease, if any, then the MultiLog lock. With a lease, waiting for
+/// both is bounded by the lease timeout.
+fn lock_with_lease(
+    mlog: &mut multi::MultiLog,
+    lease: &Option<(PathBuf, LeaseOptions)>,
+) -> Result<(Option<Lease>, multi::LockGuard)> {
+    match lease {
+        Some((path, opts)) => {
+            let start = Instant::now();
+            let lease = Lease::acquire(path, opts)?;
+            let lock = lease.lock_multilog(mlog, opts, start)?;
+            Ok((Some(lease), lock))
+        }
+        None => Ok((None, mlog.lock()?)),
+    }
 }
 
 impl Persist for NameDagState {
-    type Lock = indexedlog::multi::LockGuard;
+    type Lock = NameDagLock;
 
     fn lock(&mut self) -> Result<Self::Lock> {
         if self.mlog.is_none() {
@@ -111,7 +335,14 @@ impl Persist for NameDagState {
         //
         // The `NameDagState` does not control the `map` or `dag` Logs so it cannot reload
         // them here, or in `reload()`.
-        Ok(mlog.lock()?)
+        let (lease, lock) = lock_with_lease(mlog, &self.lease)?;
+        // The caller is about to write the Logs, which only become visible
+        // when `persist()` writes the MultiMeta.
+        self.journal.begin(mlog.version())?;
+        Ok(NameDagLock {
+            mlog: lock,
+            _lease: lease,
+        })
     }
 
     fn reload(&mut self, _lock: &Self::Lock) -> Result<()> {
@@ -120,9 +351,14 @@ impl Persist for NameDagState {
     }
 
     fn persist(&mut self, lock: &Self::Lock) -> Result<()> {
-        self.mlog.as_mut().unwrap().write_meta(&lock)?;
+        self.mlog.as_mut().unwrap().write_meta(&lock.mlog)?;
+        self.journal.finish()?;
         Ok(())
     }
+
+    fn reopened_from(&mut self, other: &Self) {
+        self.lease = other.lease.clone();
+    }
 }
 
 impl IntVersion for NameDagState {
@@ -139,6 +375,8 @@ impl TryClone for NameDagState {
         Ok(Self {
             // mlog cannot be cloned.
             mlog: None,
+            journal: self.journal.clone(),
+            lease: self.lease.clone(),
         })
     }
 }
@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Flush journal of `NameDag`.
+//!
+//! A flush writes the IdMap log, then the IdDag log, then the MultiMeta.
+//! The MultiMeta is the commit point: logs are opened at the lengths it
+//! records, so a flush interrupted before it is written is rolled back just
+//! by opening again. However, a crash can leave the logs half-written on
+//! disk, and opening them fails until they are repaired.
+//!
+//! The journal records that a flush started. It is written after taking the
+//! lock, before the logs are changed, and removed once the MultiMeta is
+//! written. If `open` finds it with nobody holding the lock, the previous
+//! writer died mid-flush, and `open` repairs the logs if needed instead of
+//! failing.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use indexedlog::utils;
+
+use crate::Result;
+
+const JOURNAL_FILE: &str = "journal";
+
+/// Content is the MultiMeta version before the flush, as two big-endian
+/// integers.
+const JOURNAL_LEN: usize = 16;
+
+#[derive(Clone, Debug)]
+pub(crate) struct FlushJournal {
+    path: PathBuf,
+}
+
+impl FlushJournal {
+    pub(crate) fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(JOURNAL_FILE),
+        }
+    }
+
+    /// Record that a flush starting from MultiMeta `version` is in progress.
+    pub(crate) fn begin(&self, version: (u64, u64)) -> Result<()> {
+        let mut buf = Vec::with_capacity(JOURNAL_LEN);
+        buf.extend_from_slice(&version.0.to_be_bytes());
+        buf.extend_from_slice(&version.1.to_be_bytes());
+        utils::atomic_write(&self.path, &buf, utils::get_global_fsync())?;
+        Ok(())
+    }
+
+    /// Record that the flush has completed.
+    pub(crate) fn finish(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The MultiMeta version before the flush in progress, if there is one.
+    /// A malformed journal is ignored.
+    pub(crate) fn pending(&self) -> Result<Option<(u64, u64)>> {
+        let buf = match utils::atomic_read(&self.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if buf.len() != JOURNAL_LEN {
+            return Ok(None);
+        }
+        let major = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+        let minor = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+        Ok(Some((major, minor)))
+    }
+}
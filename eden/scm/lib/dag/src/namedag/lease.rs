@@ -0,0 +1,486 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Write leases of `NameDag`.
+//!
+//! `Persist::lock` waits for the filesystem locks for as long as it takes,
+//! and nothing tells a waiting writer who it waits for. With a lease, a
+//! writer first records itself in the lease file. Other writers wait for at
+//! most a timeout, then fail with an error naming the holder.
+//!
+//! Leases expire, so the holder renews its lease from a background thread
+//! for as long as it holds it. A lease left behind by a writer that crashed
+//! is taken over once it expired, right away if the writer was on the same
+//! host and its process is gone, or right away if the file is unreadable.
+//! The filesystem locks are still taken after the lease, so a writer that
+//! outlives its lease is not corrupted by the next one. The next one waits
+//! for the locks, but for no longer than the lease timeout either.
+//!
+//! The lease file is only read and written while holding a short-lived
+//! filesystem lock on another file, which makes taking over an expired
+//! lease race-free.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use fs2::FileExt;
+use indexedlog::multi;
+use indexedlog::utils;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::errors::DagError;
+use crate::Result;
+
+const LEASE_FILE: &str = "lease";
+const LEASE_LOCK_FILE: &str = "lease.lock";
+
+/// How a writer takes the write lease. See `NameDag::open_with_lease`.
+#[derive(Clone, Debug)]
+pub struct LeaseOptions {
+    holder: String,
+    timeout: Duration,
+    duration: Duration,
+    poll_interval: Duration,
+}
+
+impl Default for LeaseOptions {
+    fn default() -> Self {
+        Self {
+            holder: "unknown".to_string(),
+            timeout: Duration::from_secs(30),
+            duration: Duration::from_secs(120),
+            poll_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+impl LeaseOptions {
+    /// Lease options with `holder` as the name shown to other writers.
+    pub fn new(holder: impl ToString) -> Self {
+        Self {
+            holder: holder.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait for a lease held by another writer. Default: 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long a lease lasts before other writers consider it stale. The
+    /// holder renews it every third of this. Default: 120s.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+}
+
+/// Who holds a lease, as recorded in the lease file.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LeaseHolder {
+    pub holder: String,
+    pub pid: u32,
+    /// Host of the process `pid`. Empty in leases written before hosts
+    /// were recorded.
+    #[serde(default)]
+    pub hostname: String,
+    /// Milliseconds since the Unix epoch.
+    pub acquired_at: u64,
+    /// Milliseconds since the Unix epoch.
+    pub expires_at: u64,
+    /// Distinguishes leases of the same holder and process.
+    token: u64,
+}
+
+impl fmt::Display for LeaseHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.hostname.is_empty() {
+            write!(f, "{} (pid {})", self.holder, self.pid)
+        } else {
+            write!(f, "{} (pid {} on {})", self.holder, self.pid, self.hostname)
+        }
+    }
+}
+
+/// A writer gave up waiting for a lease held by another writer.
+#[derive(Clone, Debug, Error)]
+#[error(
+    "timed out after {waited:?} waiting for the write lease of {path:?} held by {holder}, which expires in {expires_in:?}"
+)]
+pub struct LeaseTimeout {
+    pub path: PathBuf,
+    pub waited: Duration,
+    pub holder: LeaseHolder,
+    pub expires_in: Duration,
+}
+
+/// The MultiLog lock could not be taken in time, although the lease was.
+/// The lock is held by a writer that does not use leases, or that outlived
+/// its lease.
+#[derive(Clone, Debug, Error)]
+#[error(
+    "timed out after {waited:?} waiting for the write lock of {path:?}, held by a writer without a current lease"
+)]
+pub struct LockTimeout {
+    pub path: PathBuf,
+    pub waited: Duration,
+}
+
+/// A write lease. Renewed until it is dropped, and released on drop.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    dir: PathBuf,
+    token: u64,
+    heartbeat: Option<Heartbeat>,
+}
+
+/// Renews a lease from a background thread. Stops when dropped.
+#[derive(Debug)]
+struct Heartbeat {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // Disconnecting wakes up the thread.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Lease {
+    /// Take the write lease of the `NameDag` at `dir`.
+    pub(crate) fn acquire(dir: &Path, opts: &LeaseOptions) -> Result<Self> {
+        let start = Instant::now();
+        let token = rand::random();
+        let hostname = hostname();
+        loop {
+            let now = now_millis();
+            let held = with_lease_file_locked(dir, || match read_holder(dir) {
+                Some(holder) if holder.expires_at > now && !holder_is_gone(&holder, &hostname) => {
+                    Ok(Some(holder))
+                }
+                stale => {
+                    if let Some(stale) = stale {
+                        tracing::warn!(target: "dag::lease", "taking over stale lease of {}", stale);
+                    }
+                    let holder = LeaseHolder {
+                        holder: opts.holder.clone(),
+                        pid: std::process::id(),
+                        hostname: hostname.clone(),
+                        acquired_at: now,
+                        expires_at: now.saturating_add(opts.duration.as_millis() as u64),
+                        token,
+                    };
+                    write_holder(dir, &holder)?;
+                    Ok(None)
+                }
+            })?;
+            let holder = match held {
+                None => {
+                    tracing::debug!(target: "dag::lease", "acquired lease of {:?}", dir);
+                    let heartbeat = Heartbeat::start(dir.to_path_buf(), token, opts.duration);
+                    return Ok(Self {
+                        dir: dir.to_path_buf(),
+                        token,
+                        heartbeat: Some(heartbeat),
+                    });
+                }
+                Some(holder) => holder,
+            };
+            let waited = start.elapsed();
+            if waited >= opts.timeout {
+                let expires_in = Duration::from_millis(holder.expires_at.saturating_sub(now));
+                return Err(DagError::LeaseTimeout(Box::new(LeaseTimeout {
+                    path: dir.to_path_buf(),
+                    waited,
+                    holder,
+                    expires_in,
+                })));
+            }
+            std::thread::sleep(opts.poll_interval.min(opts.timeout - waited));
+        }
+    }
+
+    /// Take the MultiLog lock, after taking the lease at `start`. Like the
+    /// lease, this waits for no longer than the timeout from `start`.
+    pub(crate) fn lock_multilog(
+        &self,
+        mlog: &mut multi::MultiLog,
+        opts: &LeaseOptions,
+        start: Instant,
+    ) -> Result<multi::LockGuard> {
+        loop {
+            if let Some(lock) = mlog.try_lock()? {
+                return Ok(lock);
+            }
+            let waited = start.elapsed();
+            if waited >= opts.timeout {
+                return Err(DagError::LockTimeout(LockTimeout {
+                    path: self.dir.clone(),
+                    waited,
+                }));
+            }
+            std::thread::sleep(opts.poll_interval.min(opts.timeout - waited));
+        }
+    }
+
+    fn release(&self) -> Result<()> {
+        with_lease_file_locked(&self.dir, || {
+            // The lease might have expired and been taken over.
+            if matches!(read_holder(&self.dir), Some(holder) if holder.token == self.token) {
+                fs::remove_file(self.dir.join(LEASE_FILE))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Stop renewing before releasing, so the lease is not written back.
+        self.heartbeat.take();
+        if let Err(e) = self.release() {
+            tracing::warn!(target: "dag::lease", "cannot release lease of {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+impl Heartbeat {
+    fn start(dir: PathBuf, token: u64, duration: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let interval = (duration / 3).max(Duration::from_millis(1));
+        let thread = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match renew(&dir, token, duration) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        tracing::warn!(target: "dag::lease", "lease of {:?} was taken over", dir);
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::warn!(target: "dag::lease", "cannot renew lease of {:?}: {}", dir, e);
+                    }
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Extend the lease with `token` by `duration` from now. Returns false if
+/// the lease is no longer held with `token`.
+fn renew(dir: &Path, token: u64, duration: Duration) -> Result<bool> {
+    with_lease_file_locked(dir, || match read_holder(dir) {
+        Some(mut holder) if holder.token == token => {
+            holder.expires_at = now_millis().saturating_add(duration.as_millis() as u64);
+            write_holder(dir, &holder)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    })
+}
+
+/// Whether the holder's process is known to be gone. Only processes on this
+/// host can be checked.
+fn holder_is_gone(holder: &LeaseHolder, hostname: &str) -> bool {
+    !holder.hostname.is_empty() && holder.hostname == hostname && !is_pid_alive(holder.pid)
+}
+
+#[cfg(unix)]
+fn is_pid_alive(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists. EPERM means it exists,
+    // but belongs to another user.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_pid_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// The current holder of the lease. An unreadable lease file, for example
+/// written by a newer version, is treated as no lease.
+fn read_holder(dir: &Path) -> Option<LeaseHolder> {
+    let data = utils::atomic_read(&dir.join(LEASE_FILE)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+fn write_holder(dir: &Path, holder: &LeaseHolder) -> Result<()> {
+    let data =
+        serde_json::to_vec(holder).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    utils::atomic_write(dir.join(LEASE_FILE), &data, utils::get_global_fsync())?;
+    Ok(())
+}
+
+fn with_lease_file_locked<T>(dir: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let path = dir.join(LEASE_LOCK_FILE);
+    let file = File::open(&path).or_else(|_| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+    })?;
+    file.lock_exclusive()?;
+    let result = f();
+    file.unlock()?;
+    result
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(holder: &str) -> LeaseOptions {
+        LeaseOptions::new(holder).with_timeout(Duration::from_millis(100))
+    }
+
+    #[test]
+    fn test_lease_timeout_names_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let lease = Lease::acquire(dir.path(), &opts("writer-a")).unwrap();
+
+        let err = Lease::acquire(dir.path(), &opts("writer-b")).unwrap_err();
+        let holder = match &err {
+            DagError::LeaseTimeout(e) => e.holder.clone(),
+            _ => panic!("unexpected error: {}", err),
+        };
+        assert_eq!(holder.holder, "writer-a");
+        assert_eq!(holder.pid, std::process::id());
+        assert!(
+            err.to_string().contains("held by writer-a (pid "),
+            "{}",
+            err
+        );
+
+        // Released on drop.
+        drop(lease);
+        assert!(read_holder(dir.path()).is_none());
+        let lease = Lease::acquire(dir.path(), &opts("writer-b")).unwrap();
+        assert_eq!(read_holder(dir.path()).unwrap().holder, "writer-b");
+        drop(lease);
+    }
+
+    /// Write the lease of a writer that crashed.
+    fn write_crashed_holder(dir: &Path, pid: u32, hostname: &str, expires_at: u64) {
+        let holder = LeaseHolder {
+            holder: "crashed".to_string(),
+            pid,
+            hostname: hostname.to_string(),
+            acquired_at: 0,
+            expires_at,
+            token: 0,
+        };
+        write_holder(dir, &holder).unwrap();
+    }
+
+    #[test]
+    fn test_lease_takeover() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // An expired lease of a crashed writer is taken over.
+        write_crashed_holder(dir.path(), std::process::id(), "", 0);
+        let lease = Lease::acquire(dir.path(), &opts("writer")).unwrap();
+        assert_eq!(read_holder(dir.path()).unwrap().holder, "writer");
+
+        // The lease of the crashed writer is not released over the new one.
+        let stale = Lease {
+            dir: dir.path().to_path_buf(),
+            token: 0,
+            heartbeat: None,
+        };
+        drop(stale);
+        assert_eq!(read_holder(dir.path()).unwrap().holder, "writer");
+        drop(lease);
+
+        // So is an unreadable lease file.
+        fs::write(dir.path().join(LEASE_FILE), b"garbage").unwrap();
+        drop(Lease::acquire(dir.path(), &opts("writer")).unwrap());
+
+        // So is a lease of a process on this host that is gone, before it
+        // expires. A lease of a live process, or one on another host, is not.
+        let gone_pid = {
+            let mut child = std::process::Command::new("true").spawn().unwrap();
+            let pid = child.id();
+            child.wait().unwrap();
+            pid
+        };
+        let expires_at = now_millis() + 60_000;
+        write_crashed_holder(dir.path(), gone_pid, &hostname(), expires_at);
+        drop(Lease::acquire(dir.path(), &opts("writer")).unwrap());
+        write_crashed_holder(dir.path(), gone_pid, "elsewhere", expires_at);
+        assert!(Lease::acquire(dir.path(), &opts("writer")).is_err());
+        write_crashed_holder(dir.path(), std::process::id(), &hostname(), expires_at);
+        assert!(Lease::acquire(dir.path(), &opts("writer")).is_err());
+    }
+
+    #[test]
+    fn test_lease_renewal() {
+        let dir = tempfile::tempdir().unwrap();
+        let short = opts("writer").with_duration(Duration::from_millis(300));
+        let lease = Lease::acquire(dir.path(), &short).unwrap();
+        let expires_at = read_holder(dir.path()).unwrap().expires_at;
+
+        // The lease outlives its duration while it is held.
+        std::thread::sleep(Duration::from_millis(600));
+        let holder = read_holder(dir.path()).unwrap();
+        assert_eq!(holder.holder, "writer");
+        assert!(holder.expires_at > expires_at);
+        assert!(Lease::acquire(dir.path(), &opts("other")).is_err());
+
+        // Renewal stops once it is released.
+        drop(lease);
+        assert!(read_holder(dir.path()).is_none());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(read_holder(dir.path()).is_none());
+    }
+}
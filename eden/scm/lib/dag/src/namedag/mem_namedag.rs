@@ -57,6 +57,7 @@ impl Open for MemNameDagPath {
             path: self.clone(),
             snapshot: Default::default(),
             pending_heads: Default::default(),
+            pending_hidden_heads: Default::default(),
             persisted_id_set,
             state: MemNameDagState::default(),
             id: format!("mem:{}", next_id()),
@@ -65,6 +66,11 @@ impl Open for MemNameDagPath {
             overlay_map_paths: Default::default(),
             remote_protocol: Arc::new(()),
             missing_vertexes_confirmed_by_remote: Default::default(),
+            hex_prefixes_resolved_by_remote: Default::default(),
+            metrics: Arc::new(()),
+            query_budget: None,
+            vertex_meta: Default::default(),
+            read_only: false,
         };
         Ok(result)
     }
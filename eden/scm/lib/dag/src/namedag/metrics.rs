@@ -0,0 +1,38 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Instrumentation of how a lazy `NameDag` resolves its IdMap.
+
+use std::time::Duration;
+
+/// Receives counters about remote IdMap resolution and the overlay map.
+///
+/// Useful to quantify how often lazy graphs need the server. All methods do
+/// nothing by default. Implementations should be cheap, since they are
+/// called on hot paths.
+pub trait DagMetrics: Send + Sync {
+    /// `requested` names were sent to the server in one round-trip that
+    /// took `duration`. `resolved` of them are in the master group.
+    fn remote_names_to_ids(&self, requested: usize, resolved: usize, duration: Duration) {
+        let _ = (requested, resolved, duration);
+    }
+
+    /// `requested` ids were resolved to names by the server in one
+    /// round-trip that took `duration`.
+    fn remote_ids_to_names(&self, requested: usize, duration: Duration) {
+        let _ = (requested, duration);
+    }
+
+    /// Lookups answered by the overlay map (`hits`), or not (`misses`)
+    /// before falling back to the server.
+    fn overlay_map_lookups(&self, hits: usize, misses: usize) {
+        let _ = (hits, misses);
+    }
+}
+
+/// No instrumentation.
+impl DagMetrics for () {}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! On-disk negative cache of `NameDag`.
+//!
+//! Without it, every process asks the server again about vertexes (usually
+//! draft commits) that an earlier process already learned are not in the
+//! master group.
+
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use indexedlog::rotate;
+use parking_lot::Mutex;
+
+use super::cache::MissingVertexesStore;
+use crate::id::Id;
+use crate::id::VertexName;
+use crate::Result;
+
+/// Entries older than this are not loaded.
+const MISSING_VERTEX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_BYTES_PER_LOG: u64 = 1 << 20;
+const MAX_LOG_COUNT: u8 = 3;
+
+/// Each entry is the time it was written, in seconds since the epoch, and
+/// the next free master id at that time, both big-endian, followed by the
+/// vertex.
+const HEADER_LEN: usize = 16;
+
+pub(crate) struct MissingVertexLog {
+    log: Mutex<rotate::RotateLog>,
+}
+
+impl MissingVertexLog {
+    pub(crate) fn open(dir: &Path, create: bool) -> Result<Self> {
+        let log = rotate::OpenOptions::new()
+            .max_bytes_per_log(MAX_BYTES_PER_LOG)
+            .max_log_count(MAX_LOG_COUNT)
+            .create(create)
+            .open(dir)?;
+        Ok(Self {
+            log: Mutex::new(log),
+        })
+    }
+
+    /// Vertexes that were confirmed missing less than `MISSING_VERTEX_TTL`
+    /// ago, when the next free master id was `master_next_id`. Entries from
+    /// before the master group moved are skipped, as the vertexes might be
+    /// in the master group now. Oldest first.
+    pub(crate) fn load(&self, master_next_id: Id) -> Result<Vec<VertexName>> {
+        let min_time = now_secs().saturating_sub(MISSING_VERTEX_TTL.as_secs());
+        let log = self.log.lock();
+        let mut names = Vec::new();
+        for entry in log.iter() {
+            let entry = entry?;
+            if entry.len() < HEADER_LEN {
+                continue;
+            }
+            let time = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+            let next_id = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+            if time >= min_time && next_id == master_next_id.0 {
+                names.push(VertexName::copy_from(&entry[HEADER_LEN..]));
+            }
+        }
+        Ok(names)
+    }
+}
+
+impl MissingVertexesStore for MissingVertexLog {
+    fn append(&self, names: &[VertexName], master_next_id: Id) -> Result<()> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(&now_secs().to_be_bytes());
+        header.extend_from_slice(&master_next_id.0.to_be_bytes());
+        let mut log = self.log.lock();
+        for name in names {
+            let mut entry = header.clone();
+            entry.extend_from_slice(name.as_ref());
+            log.append(entry)?;
+        }
+        log.flush()?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Portable snapshot of a `NameDag`.
+//!
+//! The snapshot does not depend on the storage backend, so it can be used to
+//! move a graph between indexedlog-backed and in-process dags, or be handed
+//! to other tools. It only uses plain serde types. Pick any serde format
+//! (ex. CBOR or JSON) to encode it.
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::AbstractNameDag;
+use crate::errors::programming;
+use crate::id::Group;
+use crate::id::Id;
+use crate::id::VertexName;
+use crate::iddag::IdDag;
+use crate::iddagstore::IdDagStore;
+use crate::idmap::IdMapAssignHead;
+use crate::ops::IdConvert;
+use crate::ops::Persist;
+use crate::ops::TryClone;
+use crate::segment::FlatSegment;
+use crate::segment::PreparedFlatSegments;
+use crate::Result;
+
+/// Format version written by `export_portable`.
+pub const PORTABLE_DAG_VERSION: u32 = 1;
+
+/// Backend independent snapshot of a `NameDag`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct PortableDag {
+    /// Format version. See `PORTABLE_DAG_VERSION`.
+    pub version: u32,
+    /// Flat segments in all groups, sorted by id. High-level segments are
+    /// not included. They are rebuilt on import.
+    pub segments: Vec<FlatSegment>,
+    /// `(id, name)` pairs for all vertexes, sorted by id.
+    pub idmap: Vec<(Id, VertexName)>,
+}
+
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore,
+    IdDag<IS>: TryClone,
+    M: IdConvert + TryClone + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Send + Sync + 'static,
+{
+    /// Export the graph, including non-master vertexes, as a [`PortableDag`].
+    ///
+    /// Names missing locally are resolved remotely.
+    pub async fn export_portable(&self) -> Result<PortableDag> {
+        let mut segments = Vec::new();
+        for group in Group::ALL {
+            for seg in self.dag.next_segments(group.min_id(), 0)? {
+                let span = seg.span()?;
+                segments.push(FlatSegment {
+                    low: span.low,
+                    high: span.high,
+                    parents: seg.parents()?,
+                });
+            }
+        }
+
+        let mut ids: Vec<Id> = self.dag.all()?.iter().collect();
+        ids.sort_unstable();
+        tracing::debug!(target: "dag::portable", "export: {} vertexes", ids.len());
+        let names = self.vertex_name_batch(&ids).await?;
+        let idmap = ids
+            .into_iter()
+            .zip(names)
+            .map(|(id, name)| Ok((id, name?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PortableDag {
+            version: PORTABLE_DAG_VERSION,
+            segments,
+            idmap,
+        })
+    }
+}
+
+impl<IS, M, P, S> AbstractNameDag<IdDag<IS>, M, P, S>
+where
+    IS: IdDagStore + Persist + 'static,
+    IdDag<IS>: TryClone,
+    M: TryClone + IdMapAssignHead + Persist + Send + Sync + 'static,
+    P: TryClone + Send + Sync + 'static,
+    S: TryClone + Persist + Send + Sync + 'static,
+{
+    /// Import a [`PortableDag`] into an empty graph.
+    pub async fn import_portable(&mut self, data: PortableDag) -> Result<()> {
+        self.check_writable("import_portable")?;
+        if data.version != PORTABLE_DAG_VERSION {
+            return programming(format!(
+                "unsupported portable dag version {} (expected {})",
+                data.version, PORTABLE_DAG_VERSION
+            ));
+        }
+        check_portable_segments(&data.segments)?;
+
+        let (lock, map_lock, dag_lock) = self.reload()?;
+
+        if !self.dag.all()?.is_empty() {
+            return programming("Cannot import portable dag for non-empty graph");
+        }
+        for (id, name) in data.idmap {
+            tracing::debug!(target: "dag::portable", "insert IdMap: {:?}-{:?}", &name, id);
+            self.map.insert(id, name.as_ref()).await?;
+        }
+        let segments = PreparedFlatSegments {
+            segments: data.segments,
+        };
+        self.dag
+            .build_segments_volatile_from_prepared_flat_segments(&segments)?;
+
+        self.verify_missing().await?;
+
+        self.persist(lock, map_lock, dag_lock)
+    }
+}
+
+/// Check that segments are sorted, and each group has no gaps.
+fn check_portable_segments(segments: &[FlatSegment]) -> Result<()> {
+    let mut next_id_per_group = [None; Group::COUNT];
+    for seg in segments {
+        let group = seg.low.group();
+        if seg.low > seg.high || seg.high.group() != group {
+            return programming(format!("invalid portable dag segment {:?}", seg));
+        }
+        if seg.parents.iter().any(|&p| p >= seg.low) {
+            return programming(format!("invalid parents in portable dag segment {:?}", seg));
+        }
+        let expected = next_id_per_group[group.0].unwrap_or_else(|| group.min_id());
+        if seg.low != expected {
+            return programming(format!(
+                "portable dag segment {:?} does not start at {:?}",
+                seg, expected
+            ));
+        }
+        next_id_per_group[group.0] = Some(seg.high + 1);
+    }
+    Ok(())
+}
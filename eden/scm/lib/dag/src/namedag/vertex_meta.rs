@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-vertex flags of `NameDag`, like "public" or "obsolete".
+//!
+//! Flags are keyed by `Id`, so they can be looked up and queried by id
+//! ranges without translating names. Master ids are stable. Non-master ids
+//! can be re-assigned, and flags of non-master ids are dropped when that
+//! happens.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use bitflags::bitflags;
+
+use crate::id::Group;
+use crate::id::Id;
+use crate::id::VertexName;
+use crate::IdSet;
+use crate::IdSpan;
+use crate::Result;
+
+bitflags! {
+    /// Flags of a vertex. See `NameDag::set_vertex_flags`.
+    pub struct VertexFlags: u64 {
+        /// The vertex is public, and should not be rewritten.
+        const PUBLIC = 0b1;
+
+        /// The vertex was rewritten, and is replaced by other vertexes.
+        const OBSOLETE = 0b10;
+    }
+}
+
+/// Persistent storage of `VertexMeta`.
+pub(crate) trait VertexMetaStore: Send + Sync {
+    /// Flags of `id`. Empty if not set.
+    fn get(&self, id: Id) -> Result<VertexFlags>;
+
+    /// Ids in `span` with non-empty flags, and their flags.
+    fn range(&self, span: IdSpan) -> Result<Vec<(Id, VertexFlags)>>;
+
+    /// Drop flags of non-master ids if `clear_non_master` is set, then
+    /// write `changes`. Empty flags remove the entry.
+    fn write(&self, clear_non_master: bool, changes: &BTreeMap<Id, VertexFlags>) -> Result<()>;
+}
+
+/// Flags of vertexes, keyed by `Id`.
+///
+/// Without a store, flags only live in memory.
+#[derive(Clone, Default)]
+pub(crate) struct VertexMeta {
+    /// Changes not written to `store` yet. Empty flags remove the entry.
+    pending: BTreeMap<Id, VertexFlags>,
+
+    /// Flags of vertexes whose ids are about to be re-assigned. Applied
+    /// after the new ids are known. See `NameDag::flush`.
+    pending_by_name: Vec<(VertexName, VertexFlags)>,
+
+    /// Whether flags of non-master ids in `store` are dropped.
+    clear_non_master: bool,
+
+    store: Option<Arc<dyn VertexMetaStore>>,
+}
+
+impl VertexMeta {
+    pub(crate) fn set_store(&mut self, store: Arc<dyn VertexMetaStore>) {
+        self.store = Some(store);
+    }
+
+    /// Flags of `id`. Empty if not set.
+    pub(crate) fn get(&self, id: Id) -> Result<VertexFlags> {
+        if let Some(flags) = self.pending.get(&id) {
+            return Ok(*flags);
+        }
+        match &self.store {
+            Some(store) if self.is_visible_in_store(id) => store.get(id),
+            _ => Ok(VertexFlags::empty()),
+        }
+    }
+
+    pub(crate) fn set(&mut self, id: Id, flags: VertexFlags) {
+        self.pending.insert(id, flags);
+    }
+
+    /// Ids in `set` with all of `flags`, and at least one flag.
+    pub(crate) fn query(&self, set: &IdSet, flags: VertexFlags) -> Result<IdSet> {
+        let mut found = BTreeMap::new();
+        for span in set.as_spans() {
+            if let Some(store) = &self.store {
+                for (id, id_flags) in store.range(*span)? {
+                    if self.is_visible_in_store(id) {
+                        found.insert(id, id_flags);
+                    }
+                }
+            }
+            for (&id, &id_flags) in self.pending.range(span.low..=span.high) {
+                found.insert(id, id_flags);
+            }
+        }
+        let ids = found
+            .into_iter()
+            .filter(|(_, id_flags)| !id_flags.is_empty() && id_flags.contains(flags))
+            .map(|(id, _)| id);
+        Ok(IdSet::from_spans(ids))
+    }
+
+    /// Drop flags of ids in `set`.
+    pub(crate) fn remove(&mut self, set: &IdSet) -> Result<()> {
+        for id in self.query(set, VertexFlags::empty())?.iter() {
+            self.pending.insert(id, VertexFlags::empty());
+        }
+        Ok(())
+    }
+
+    /// Drop flags of all non-master ids. Called when non-master ids are
+    /// re-assigned.
+    pub(crate) fn remove_non_master(&mut self) {
+        self.pending.retain(|id, _| id.group() == Group::MASTER);
+        if self.store.is_some() {
+            self.clear_non_master = true;
+        }
+    }
+
+    /// Changes that are not written yet.
+    pub(crate) fn pending(&self) -> impl Iterator<Item = (Id, VertexFlags)> + '_ {
+        self.pending.iter().map(|(id, flags)| (*id, *flags))
+    }
+
+    pub(crate) fn set_pending_by_name(&mut self, pending: Vec<(VertexName, VertexFlags)>) {
+        self.pending_by_name = pending;
+    }
+
+    pub(crate) fn take_pending_by_name(&mut self) -> Vec<(VertexName, VertexFlags)> {
+        std::mem::take(&mut self.pending_by_name)
+    }
+
+    /// Write pending changes to the store. No-op without a store.
+    pub(crate) fn persist(&mut self) -> Result<()> {
+        if let Some(store) = &self.store {
+            if self.clear_non_master || !self.pending.is_empty() {
+                store.write(self.clear_non_master, &self.pending)?;
+            }
+            self.pending.clear();
+            self.clear_non_master = false;
+        }
+        Ok(())
+    }
+
+    fn is_visible_in_store(&self, id: Id) -> bool {
+        !self.clear_non_master || id.group() == Group::MASTER
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! On-disk storage of vertex flags of `NameDag`. See `vertex_meta.rs`.
+//!
+//! The log is part of the `MultiLog` of the `NameDag`, so flags are written
+//! under the same lock as the map and the dag, and only become visible
+//! together with them.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use indexedlog::log;
+use parking_lot::Mutex;
+
+use super::vertex_meta::VertexFlags;
+use super::vertex_meta::VertexMetaStore;
+use crate::errors::BackendError;
+use crate::id::Group;
+use crate::id::Id;
+use crate::IdSpan;
+use crate::Result;
+
+pub(crate) struct VertexMetaLog {
+    log: Mutex<log::Log>,
+}
+
+impl VertexMetaLog {
+    const INDEX_ID: usize = 0;
+
+    /// Magic bytes in `Log` that indicates "remove flags of all non-master
+    /// ids". A valid entry has 16 bytes so does not conflict with this.
+    const MAGIC_CLEAR_NON_MASTER: &'static [u8] = b"CLRNM";
+
+    /// Each entry is the id followed by its flags, both big-endian.
+    const ENTRY_LEN: usize = 16;
+
+    pub(crate) fn open_from_log(log: log::Log) -> Self {
+        Self {
+            log: Mutex::new(log),
+        }
+    }
+
+    pub(crate) fn log_open_options() -> log::OpenOptions {
+        log::OpenOptions::new().create(true).index("id", |data| {
+            if data == Self::MAGIC_CLEAR_NON_MASTER {
+                Group::non_master()
+                    .map(|group| log::IndexOutput::RemovePrefix(Box::new(group.bytes())))
+                    .collect()
+            } else if data.len() != Self::ENTRY_LEN {
+                // Not indexed, so never returned by lookups.
+                Vec::new()
+            } else if data[8..Self::ENTRY_LEN].iter().all(|&b| b == 0) {
+                let key = data[0..8].to_vec().into_boxed_slice();
+                vec![log::IndexOutput::Remove(key)]
+            } else {
+                vec![log::IndexOutput::Reference(0..8)]
+            }
+        })
+    }
+}
+
+impl VertexMetaStore for VertexMetaLog {
+    fn get(&self, id: Id) -> Result<VertexFlags> {
+        let log = self.log.lock();
+        let entry = log.lookup(Self::INDEX_ID, id.0.to_be_bytes())?.next();
+        match entry {
+            Some(entry) => parse_flags(entry?),
+            None => Ok(VertexFlags::empty()),
+        }
+    }
+
+    fn range(&self, span: IdSpan) -> Result<Vec<(Id, VertexFlags)>> {
+        let low = span.low.0.to_be_bytes();
+        let high = span.high.0.to_be_bytes();
+        let log = self.log.lock();
+        let mut result = Vec::new();
+        for item in log.lookup_range(Self::INDEX_ID, &low[..]..=&high[..])? {
+            let (key, mut entries) = item?;
+            let id = Id(u64::from_be_bytes(key.as_ref().try_into().unwrap()));
+            if let Some(entry) = entries.next() {
+                result.push((id, parse_flags(entry?)?));
+            }
+        }
+        Ok(result)
+    }
+
+    /// The changes only become visible once the `MultiLog` meta is written.
+    fn write(&self, clear_non_master: bool, changes: &BTreeMap<Id, VertexFlags>) -> Result<()> {
+        let mut log = self.log.lock();
+        if clear_non_master {
+            log.append(Self::MAGIC_CLEAR_NON_MASTER)?;
+        }
+        for (id, flags) in changes {
+            let mut entry = Vec::with_capacity(Self::ENTRY_LEN);
+            entry.extend_from_slice(&id.0.to_be_bytes());
+            entry.extend_from_slice(&flags.bits().to_be_bytes());
+            log.append(entry)?;
+        }
+        log.sync()?;
+        Ok(())
+    }
+}
+
+/// Flags of an entry. Unknown flags, written by newer versions, are ignored.
+fn parse_flags(entry: &[u8]) -> Result<VertexFlags> {
+    if entry.len() != VertexMetaLog::ENTRY_LEN {
+        let message = format!("corrupted vertex flags entry: {:?}", entry);
+        return Err(BackendError::Generic(message).into());
+    }
+    let bits = u64::from_be_bytes(entry[8..].try_into().unwrap());
+    Ok(VertexFlags::from_bits_truncate(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrupted_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = VertexMetaLog::log_open_options().open(dir.path()).unwrap();
+        // A truncated entry is skipped by the index instead of panicking.
+        log.append(&Id(1).0.to_be_bytes()[..]).unwrap();
+        log.sync().unwrap();
+        let store = VertexMetaLog::open_from_log(log);
+        assert_eq!(store.get(Id(1)).unwrap(), VertexFlags::empty());
+
+        let changes = [(Id(1), VertexFlags::PUBLIC), (Id(2), VertexFlags::OBSOLETE)];
+        store.write(false, &changes.into_iter().collect()).unwrap();
+        assert_eq!(store.get(Id(1)).unwrap(), VertexFlags::PUBLIC);
+        assert_eq!(store.range(IdSpan::new(Id(0), Id(9))).unwrap(), changes);
+
+        assert!(parse_flags(&[0; 12]).is_err());
+    }
+}
@@ -216,6 +216,24 @@ impl IdLazySet {
         ))
     }
 
+    /// Convert to an IdStaticSet if all ids were already loaded, without
+    /// loading more.
+    pub(crate) fn to_static_if_complete(&self) -> Option<IdStaticSet> {
+        let inner = self.inner.lock().unwrap();
+        if inner.state != State::Complete {
+            return None;
+        }
+        let mut spans = IdSet::empty();
+        for &id in inner.visited.iter() {
+            spans.push(id);
+        }
+        Some(IdStaticSet::from_spans_idmap_dag(
+            spans,
+            self.map.clone(),
+            self.dag.clone(),
+        ))
+    }
+
     fn load_all(&self) -> Result<MutexGuard<Inner>> {
         let mut inner = self.inner.lock().unwrap();
         inner.load_more(usize::max_value(), None)?;
@@ -277,11 +295,7 @@ impl AsyncNameSetQuery for IdLazySet {
     }
 
     async fn contains(&self, name: &VertexName) -> Result<bool> {
-        let id = match self
-            .map
-            .vertex_id_with_max_group(name, Group::NON_MASTER)
-            .await?
-        {
+        let id = match self.map.vertex_id_with_max_group(name, Group::MAX).await? {
             None => {
                 return Ok(false);
             }
@@ -321,11 +335,7 @@ impl AsyncNameSetQuery for IdLazySet {
     }
 
     async fn contains_fast(&self, name: &VertexName) -> Result<Option<bool>> {
-        let id = match self
-            .map
-            .vertex_id_with_max_group(name, Group::NON_MASTER)
-            .await?
-        {
+        let id = match self.map.vertex_id_with_max_group(name, Group::MAX).await? {
             None => {
                 return Ok(Some(false));
             }
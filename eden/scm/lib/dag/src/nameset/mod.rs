@@ -10,6 +10,7 @@
 //! See [`NameSet`] for the main structure.
 
 use std::any::Any;
+use std::borrow::Cow;
 use std::cmp;
 use std::fmt;
 use std::fmt::Debug;
@@ -48,6 +49,7 @@ pub mod union;
 
 use self::hints::Flags;
 use self::hints::Hints;
+use self::id_lazy::IdLazySet;
 use self::id_static::IdStaticSet;
 use self::meta::MetaSet;
 use self::r#static::StaticSet;
@@ -192,26 +194,20 @@ impl NameSet {
             );
             return self.clone();
         }
-        if let (Some(this), Some(other)) = (
-            self.as_any().downcast_ref::<IdStaticSet>(),
-            other.as_any().downcast_ref::<IdStaticSet>(),
-        ) {
-            let order = this.map.map_version().partial_cmp(other.map.map_version());
-            if order.is_some() {
-                // Fast path for IdStaticSet
-                let result = Self::from_spans_idmap_dag(
-                    this.spans.difference(&other.spans),
-                    this.map.clone(),
-                    this.dag.clone(),
-                );
-                tracing::debug!(
-                    "difference(x={:.6?}, y={:.6?}) = {:.6?} (fast path 3)",
-                    self,
-                    other,
-                    &result
-                );
-                return result;
-            }
+        if let Some((x, y, _order)) = self.compatible_id_spans(other) {
+            // Fast path for id-backed sets
+            let result = Self::from_spans_idmap_dag(
+                x.spans.difference(&y.spans),
+                x.map.clone(),
+                x.dag.clone(),
+            );
+            tracing::debug!(
+                "difference(x={:.6?}, y={:.6?}) = {:.6?} (fast path 3)",
+                self,
+                other,
+                &result
+            );
+            return result;
         }
         tracing::debug!("difference(x={:.6?}, y={:.6?}) (slow path)", self, other);
         Self::from_query(difference::DifferenceSet::new(self.clone(), other.clone()))
@@ -249,26 +245,20 @@ impl NameSet {
             );
             return Self::empty();
         }
-        if let (Some(this), Some(other)) = (
-            self.as_any().downcast_ref::<IdStaticSet>(),
-            other.as_any().downcast_ref::<IdStaticSet>(),
-        ) {
-            let order = this.map.map_version().partial_cmp(other.map.map_version());
-            if let Some(order) = order {
-                // Fast path for IdStaticSet
-                let result = Self::from_spans_idmap_dag(
-                    this.spans.intersection(&other.spans),
-                    pick(order, &this.map, &other.map).clone(),
-                    pick(order, &this.dag, &other.dag).clone(),
-                );
-                tracing::debug!(
-                    "intersection(x={:.6?}, y={:.6?}) = {:?} (IdStatic fast path)",
-                    self,
-                    other,
-                    &result
-                );
-                return result;
-            }
+        if let Some((x, y, order)) = self.compatible_id_spans(other) {
+            // Fast path for id-backed sets
+            let result = Self::from_spans_idmap_dag(
+                x.spans.intersection(&y.spans),
+                pick(order, x.map, y.map).clone(),
+                pick(order, x.dag, y.dag).clone(),
+            );
+            tracing::debug!(
+                "intersection(x={:.6?}, y={:.6?}) = {:?} (fast path 4)",
+                self,
+                other,
+                &result
+            );
+            return result;
         }
         tracing::debug!("intersection(x={:.6?}, y={:.6?}) (slow path)", self, other,);
         Self::from_query(intersection::IntersectionSet::new(
@@ -295,31 +285,78 @@ impl NameSet {
             tracing::debug!("union(x={:.6?}, y={:.6?}) = y (fast path 2)", self, other);
             return other.clone();
         }
-        if let (Some(this), Some(other)) = (
-            self.as_any().downcast_ref::<IdStaticSet>(),
-            other.as_any().downcast_ref::<IdStaticSet>(),
-        ) {
-            let order = this.map.map_version().partial_cmp(other.map.map_version());
-            if let Some(order) = order {
-                // Fast path for IdStaticSet
-                let result = Self::from_spans_idmap_dag(
-                    this.spans.union(&other.spans),
-                    pick(order, &this.map, &other.map).clone(),
-                    pick(order, &this.dag, &other.dag).clone(),
-                );
-                tracing::debug!(
-                    "union(x={:.6?}, y={:.6?}) = {:.6?} (fast path 3)",
-                    self,
-                    other,
-                    &result
-                );
-                return result;
-            }
+        if let Some((x, y, order)) = self.compatible_id_spans(other) {
+            // Fast path for id-backed sets
+            let result = Self::from_spans_idmap_dag(
+                x.spans.union(&y.spans),
+                pick(order, x.map, y.map).clone(),
+                pick(order, x.dag, y.dag).clone(),
+            );
+            tracing::debug!(
+                "union(x={:.6?}, y={:.6?}) = {:.6?} (fast path 3)",
+                self,
+                other,
+                &result
+            );
+            return result;
         }
         tracing::debug!("union(x={:.6?}, y={:.6?}) (slow path)", self, other);
         Self::from_query(union::UnionSet::new(self.clone(), other.clone()))
     }
 
+    /// If both sets are backed by [`IdSet`]s from compatible IdMaps and dags,
+    /// return their spans and how the versions compare, so set operations
+    /// can be calculated on the spans without resolving vertex names.
+    fn compatible_id_spans<'a>(
+        &'a self,
+        other: &'a NameSet,
+    ) -> Option<(IdSpans<'a>, IdSpans<'a>, cmp::Ordering)> {
+        let this = self.id_spans()?;
+        let other = other.id_spans()?;
+        let map_order = this
+            .map
+            .map_version()
+            .partial_cmp(other.map.map_version())?;
+        let dag_order = this
+            .dag
+            .dag_version()
+            .partial_cmp(other.dag.dag_version())?;
+        let order = match (map_order, dag_order) {
+            (cmp::Ordering::Equal, order) | (order, cmp::Ordering::Equal) => order,
+            (map_order, dag_order) if map_order == dag_order => map_order,
+            // The newer IdMap and the newer dag are from different sides.
+            // Neither side can be used for the result.
+            _ => return None,
+        };
+        Some((this, other, order))
+    }
+
+    /// The spans of an id-backed set. An [`IdLazySet`] only qualifies if it
+    /// was already fully loaded, and is known to iterate in descending id
+    /// order, so converting it to spans does not change the iteration order.
+    fn id_spans(&self) -> Option<IdSpans<'_>> {
+        if let Some(set) = self.as_any().downcast_ref::<IdStaticSet>() {
+            return Some(IdSpans {
+                spans: Cow::Borrowed(&set.spans),
+                map: &set.map,
+                dag: &set.dag,
+            });
+        }
+        if let Some(set) = self.as_any().downcast_ref::<IdLazySet>() {
+            if self.hints().contains(Flags::ID_DESC) {
+                // Loading the ids here could be as slow as the lazy set
+                // it replaces, so sets that are not loaded yet stay lazy.
+                let spans = set.to_static_if_complete()?.spans;
+                return Some(IdSpans {
+                    spans: Cow::Owned(spans),
+                    map: &set.map,
+                    dag: &set.dag,
+                });
+            }
+        }
+        None
+    }
+
     /// Filter using the given async function. If `filter_func` returns `true`
     /// for a vertex, then the vertex will be taken, other it will be skipped.
     pub fn filter(
@@ -459,6 +496,13 @@ impl NameSet {
     }
 }
 
+/// The [`IdSet`], IdMap and dag backing an id-backed [`NameSet`].
+struct IdSpans<'a> {
+    spans: Cow<'a, IdSet>,
+    map: &'a Arc<dyn IdConvert + Send + Sync>,
+    dag: &'a Arc<dyn DagAlgorithm + Send + Sync>,
+}
+
 impl BitAnd for NameSet {
     type Output = Self;
 
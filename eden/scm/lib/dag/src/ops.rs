@@ -21,7 +21,9 @@ use crate::id::VertexName;
 use crate::namedag::MemNameDag;
 use crate::nameset::id_lazy::IdLazySet;
 use crate::nameset::id_static::IdStaticSet;
+use crate::nameset::BoxVertexStream;
 use crate::nameset::NameSet;
+use crate::render::TopoRow;
 use crate::IdSet;
 use crate::Result;
 use crate::VerLink;
@@ -49,6 +51,16 @@ pub trait DagAlgorithm: Send + Sync {
     /// Calculates all ancestors reachable from any name from the given set.
     async fn ancestors(&self, set: NameSet) -> Result<NameSet>;
 
+    /// Like `ancestors`, but yields the ancestors lazily, sorted
+    /// topologically with children before parents.
+    ///
+    /// Unlike `ancestors`, the cost is proportional to how much of the stream
+    /// is consumed, not to the size of the ancestry. This suits queries that
+    /// only need the first few ancestors of a large history.
+    async fn ancestors_stream(&self, set: NameSet) -> Result<BoxVertexStream> {
+        default_impl::ancestors_stream(self, set).await
+    }
+
     /// Calculates parents of the given set.
     ///
     /// Note: Parent order is not preserved. Use [`NameDag::parent_names`]
@@ -110,6 +122,14 @@ pub trait DagAlgorithm: Send + Sync {
         default_impl::is_ancestor(self, ancestor, descendant).await
     }
 
+    /// Tests `is_ancestor` for each `(ancestor, descendant)` pair.
+    ///
+    /// This is faster than calling `is_ancestor` for each pair when pairs
+    /// share descendants.
+    async fn is_ancestor_batch(&self, pairs: &[(VertexName, VertexName)]) -> Result<Vec<bool>> {
+        default_impl::is_ancestor_batch(self, pairs).await
+    }
+
     /// Calculates "heads" of the ancestors of the given set. That is,
     /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
     /// `ancestors(X)`.
@@ -124,6 +144,17 @@ pub trait DagAlgorithm: Send + Sync {
         default_impl::heads_ancestors(self, set).await
     }
 
+    /// Calculates heads of `ancestors(heads) - (descendants(hidden) -
+    /// ancestors(heads - hidden))`. That is, the heads that remain after
+    /// removing `hidden` and their descendants, except those that are still
+    /// reachable from heads not in `hidden`, which is what smartlog shows.
+    ///
+    /// This is faster than calculating the sets separately in certain
+    /// implementations like segmented changelog.
+    async fn visible_heads(&self, heads: NameSet, hidden: NameSet) -> Result<NameSet> {
+        default_impl::visible_heads(self, heads, hidden).await
+    }
+
     /// Calculates the "dag range" - vertexes reachable from both sides.
     async fn range(&self, roots: NameSet, heads: NameSet) -> Result<NameSet>;
 
@@ -147,6 +178,15 @@ pub trait DagAlgorithm: Send + Sync {
     /// Calculates the descendants of the given set.
     async fn descendants(&self, set: NameSet) -> Result<NameSet>;
 
+    /// Calculates the descendants of the given set that are at most `depth`
+    /// generations away from it. `depth = 0` returns the set itself.
+    ///
+    /// This is cheaper than `descendants` if `depth` is small, even if the
+    /// set has lots of descendants.
+    async fn descendants_within(&self, set: NameSet, depth: u64) -> Result<NameSet> {
+        default_impl::descendants_within(self, set, depth).await
+    }
+
     /// Calculates `roots` that are reachable from `heads` without going
     /// through other `roots`. For example, given the following graph:
     ///
@@ -177,6 +217,13 @@ pub trait DagAlgorithm: Send + Sync {
         default_impl::reachable_roots(self, roots, heads).await
     }
 
+    /// Calculates `range(roots, heads)`, sorted topologically with children
+    /// before parents. Each vertex comes with its parents in the range and a
+    /// column hint, which is what graph renderers need.
+    async fn iter_topo_between(&self, roots: NameSet, heads: NameSet) -> Result<Vec<TopoRow>> {
+        default_impl::iter_topo_between(self, roots, heads).await
+    }
+
     /// Vertexes buffered in memory, not yet written to disk.
     async fn dirty(&self) -> Result<NameSet>;
 
@@ -278,6 +325,18 @@ pub trait DagAddHeads {
 pub trait DagImportCloneData {
     /// Updates the DAG using a `CloneData` object.
     async fn import_clone_data(&mut self, clone_data: CloneData<VertexName>) -> Result<()>;
+
+    /// Updates a DAG that might not be empty using a `CloneData` object.
+    ///
+    /// The `CloneData` must be a superset of the local master group, with the
+    /// same ids, names and parents. Only names included in the `CloneData`
+    /// can be checked. The master group is fast-forwarded to the `CloneData`.
+    /// This allows repairing a partial graph by cloning again without
+    /// removing it first.
+    async fn import_clone_data_incremental(
+        &mut self,
+        clone_data: CloneData<VertexName>,
+    ) -> Result<()>;
 }
 
 /// Import a generated incremental `CloneData` object into an existing DAG.
@@ -390,7 +449,7 @@ pub trait IdConvert: PrefixLookup + Sync {
     async fn contains_vertex_name_locally(&self, name: &[VertexName]) -> Result<Vec<bool>>;
 
     async fn vertex_id_optional(&self, name: &VertexName) -> Result<Option<Id>> {
-        self.vertex_id_with_max_group(name, Group::NON_MASTER).await
+        self.vertex_id_with_max_group(name, Group::MAX).await
     }
 
     /// Convert [`Id`]s to [`VertexName`]s in batch.
@@ -413,6 +472,22 @@ pub trait IdConvert: PrefixLookup + Sync {
         Ok(ids)
     }
 
+    /// Resolve `names` in batch so later lookups of them do not need remote
+    /// round-trips. Names that do not exist are not errors.
+    async fn prefetch_names(&self, names: &[VertexName]) -> Result<()> {
+        self.vertex_id_batch(names).await?;
+        Ok(())
+    }
+
+    /// Resolve `ids` in batch so later lookups of them do not need remote
+    /// round-trips.
+    async fn prefetch_ids(&self, ids: &[Id]) -> Result<()> {
+        for name in self.vertex_name_batch(ids).await? {
+            name?;
+        }
+        Ok(())
+    }
+
     /// Identity of the map.
     fn map_id(&self) -> &str;
 
@@ -525,6 +600,10 @@ pub trait Persist {
     ///
     /// This requires a lock.
     fn persist(&mut self, _lock: &Self::Lock) -> Result<()>;
+
+    /// Called on a newly opened `self` that replaces `other`, so `self` is
+    /// locked the same way, e.g. with a lease.
+    fn reopened_from(&mut self, _other: &Self) {}
 }
 
 /// Address that can be used to open things.
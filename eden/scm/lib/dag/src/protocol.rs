@@ -17,15 +17,23 @@
 //! - Name -> Id: Name -> RequestNameToLocation -> ResponseIdNamePair -> Id
 
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt;
+use std::sync::Arc;
 use std::thread_local;
 
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::future::Shared;
 use futures::stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
+use parking_lot::Mutex;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::errors::BackendError;
+use crate::errors::DagError;
 use crate::id::VertexName;
 use crate::iddag::FirstAncestorConstraint;
 use crate::iddag::IdDag;
@@ -49,6 +57,10 @@ pub struct RequestNameToLocation {
 
     #[serde(rename = "h")]
     pub heads: Vec<VertexName>,
+
+    /// Identifies the request in client and server logs.
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
 }
 
 /// Request for converting locations to names (commit hashes).
@@ -57,6 +69,10 @@ pub struct RequestNameToLocation {
 pub struct RequestLocationToName {
     #[serde(rename = "p")]
     pub paths: Vec<AncestorPath>,
+
+    /// Identifies the request in client and server logs.
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
 }
 
 /// Response for converting names to ids or converting names to ids.
@@ -104,6 +120,12 @@ impl fmt::Debug for AncestorPath {
     }
 }
 
+/// A new id for a request to the server, so that the client and server logs
+/// of the request can be matched up.
+pub(crate) fn new_request_id() -> u64 {
+    rand::random()
+}
+
 // Async Remote Protocols ----------------------------------------------------
 
 /// Abstraction of network protocols.
@@ -118,10 +140,14 @@ pub trait RemoteIdConvertProtocol: Send + Sync + 'static {
     /// to the server's IdMap, or because "name" is known in the server's IdMap,
     /// but the matching Id is outside "::heads"), this method should skip it in
     /// the resulting list (instead of returning an error).
+    ///
+    /// `request_id`, if set, identifies the request in client and server
+    /// logs.
     async fn resolve_names_to_relative_paths(
         &self,
         heads: Vec<VertexName>,
         names: Vec<VertexName>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>>;
 
     /// Ask the server to convert "x~n" relative paths back to commit hashes.
@@ -131,8 +157,23 @@ pub trait RemoteIdConvertProtocol: Send + Sync + 'static {
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>>;
 
+    /// Ask the server for names in "::heads" starting with the given hex
+    /// prefix. Return at most `limit` names.
+    ///
+    /// The default implementation does not support prefix lookups and
+    /// returns nothing, so only locally known names match short hashes.
+    async fn resolve_hex_prefix(
+        &self,
+        _heads: Vec<VertexName>,
+        _hex_prefix: Vec<u8>,
+        _limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        Ok(Vec::new())
+    }
+
     /// Return `true` if the protocol is local and queries do not need to
     /// optimize for batching or latency.
     fn is_local(&self) -> bool {
@@ -146,6 +187,7 @@ impl RemoteIdConvertProtocol for () {
         &self,
         _heads: Vec<VertexName>,
         _names: Vec<VertexName>,
+        _request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
         Ok(Default::default())
     }
@@ -153,6 +195,7 @@ impl RemoteIdConvertProtocol for () {
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<AncestorPath>,
+        _request_id: Option<u64>,
     ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
         let msg = format!(
             "Asked to resolve {:?} in graph but remote protocol is not configured",
@@ -166,6 +209,200 @@ impl RemoteIdConvertProtocol for () {
     }
 }
 
+// Request coalescing --------------------------------------------------------
+
+type PathNames = Vec<(AncestorPath, Vec<VertexName>)>;
+type SharedPathNames =
+    Shared<BoxFuture<'static, std::result::Result<Arc<PathNames>, Arc<DagError>>>>;
+
+/// Server-side front-end of a [`RemoteIdConvertProtocol`] that coalesces
+/// concurrent `resolve_names_to_relative_paths` requests.
+///
+/// A request with the same heads as an in-flight request does not resolve
+/// names that the in-flight request is already resolving. It waits for that
+/// result and only sends the other names to the inner protocol. This avoids
+/// repeating the same work when many clients ask for the same names at once,
+/// for example after a push.
+///
+/// `resolve_relative_paths_to_names` and `resolve_hex_prefix` are passed
+/// through as-is.
+pub struct CoalescingProtocol<P> {
+    inner: Arc<P>,
+    state: Arc<Mutex<CoalescingState>>,
+}
+
+#[derive(Default)]
+struct CoalescingState {
+    next_id: u64,
+    in_flight: Vec<InFlightResolve>,
+}
+
+struct InFlightResolve {
+    id: u64,
+    // Sorted, so requests listing the same heads in a different order match.
+    heads: Vec<VertexName>,
+    names: HashSet<VertexName>,
+    result: SharedPathNames,
+}
+
+/// Removes an in-flight entry once the request that started it completes or
+/// is dropped. Requests that joined it keep their clone of the result.
+struct InFlightGuard {
+    state: Arc<Mutex<CoalescingState>>,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.in_flight.retain(|f| f.id != self.id);
+    }
+}
+
+impl<P: RemoteIdConvertProtocol> CoalescingProtocol<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            state: Default::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: RemoteIdConvertProtocol> RemoteIdConvertProtocol for CoalescingProtocol<P> {
+    async fn resolve_names_to_relative_paths(
+        &self,
+        heads: Vec<VertexName>,
+        names: Vec<VertexName>,
+        request_id: Option<u64>,
+    ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        let mut sorted_heads = heads.clone();
+        sorted_heads.sort();
+        sorted_heads.dedup();
+        let requested: HashSet<VertexName> = names.iter().cloned().collect();
+
+        let (waits, guard) = {
+            let mut state = self.state.lock();
+            let mut waits: Vec<(u64, SharedPathNames)> = Vec::new();
+            let mut remaining: Vec<VertexName> = Vec::new();
+            for name in names {
+                let found = state
+                    .in_flight
+                    .iter()
+                    .find(|f| f.heads == sorted_heads && f.names.contains(&name));
+                match found {
+                    Some(f) => {
+                        if !waits.iter().any(|(id, _)| *id == f.id) {
+                            waits.push((f.id, f.result.clone()));
+                        }
+                    }
+                    None => {
+                        if !remaining.contains(&name) {
+                            remaining.push(name);
+                        }
+                    }
+                }
+            }
+
+            let guard = if remaining.is_empty() {
+                None
+            } else {
+                let id = state.next_id;
+                state.next_id += 1;
+                let inner = self.inner.clone();
+                let names: HashSet<VertexName> = remaining.iter().cloned().collect();
+                let result = async move {
+                    inner
+                        .resolve_names_to_relative_paths(heads, remaining, request_id)
+                        .await
+                        .map(Arc::new)
+                        .map_err(Arc::new)
+                }
+                .boxed()
+                .shared();
+                state.in_flight.push(InFlightResolve {
+                    id,
+                    heads: sorted_heads,
+                    names,
+                    result: result.clone(),
+                });
+                waits.push((id, result));
+                Some(InFlightGuard {
+                    state: self.state.clone(),
+                    id,
+                })
+            };
+            (waits, guard)
+        };
+
+        let joined = waits.len() - guard.iter().count();
+        if joined > 0 {
+            tracing::debug!(
+                target: "dag::protocol",
+                ?request_id,
+                "resolve_names_to_relative_paths: joined {} in-flight requests",
+                joined
+            );
+        }
+
+        let mut result = Vec::new();
+        for (_, wait) in waits {
+            let path_names = wait.await.map_err(|e| clone_error(&e))?;
+            result.extend(
+                path_names
+                    .iter()
+                    .filter(|(_, names)| names.iter().any(|n| requested.contains(n)))
+                    .cloned(),
+            );
+        }
+        Ok(result)
+    }
+
+    async fn resolve_relative_paths_to_names(
+        &self,
+        paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
+    ) -> Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        self.inner
+            .resolve_relative_paths_to_names(paths, request_id)
+            .await
+    }
+
+    async fn resolve_hex_prefix(
+        &self,
+        heads: Vec<VertexName>,
+        hex_prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        self.inner
+            .resolve_hex_prefix(heads, hex_prefix, limit)
+            .await
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+}
+
+/// Copy an error shared by coalesced requests. Backend errors are not
+/// cloneable, so they are copied as their message.
+fn clone_error(err: &DagError) -> DagError {
+    match err {
+        DagError::VertexNotFound(name) => DagError::VertexNotFound(name.clone()),
+        DagError::IdNotFound(id) => DagError::IdNotFound(*id),
+        DagError::NeedSlowPath(msg) => DagError::NeedSlowPath(msg.clone()),
+        DagError::Programming(msg) => DagError::Programming(msg.clone()),
+        DagError::Bug(msg) => DagError::Bug(msg.clone()),
+        DagError::Backend(e) => BackendError::Generic(e.to_string()).into(),
+        DagError::IdOverflow(group) => DagError::IdOverflow(*group),
+        DagError::BudgetExceeded(e) => DagError::BudgetExceeded(*e),
+        #[cfg(any(test, feature = "indexedlog-backend"))]
+        DagError::LeaseTimeout(e) => DagError::LeaseTimeout(e.clone()),
+        #[cfg(any(test, feature = "indexedlog-backend"))]
+        DagError::LockTimeout(e) => DagError::LockTimeout(e.clone()),
+    }
+}
+
 // Traits --------------------------------------------------------------------
 
 /// Similar to `From::from(I) -> O`, but with `self` as context.
@@ -210,7 +447,11 @@ impl<M: IdConvert, DagStore: IdDagStore> Process<Vec<VertexName>, RequestNameToL
                 );
                 crate::Error::Programming(msg)
             })?;
-        Ok(RequestNameToLocation { names, heads })
+        Ok(RequestNameToLocation {
+            names,
+            heads,
+            request_id: None,
+        })
     }
 }
 
@@ -290,7 +531,10 @@ impl<M: IdConvert, DagStore: IdDagStore> Process<IdSet, RequestLocationToName>
             .try_collect::<Vec<_>>()
             .await?;
 
-        Ok(RequestLocationToName { paths })
+        Ok(RequestLocationToName {
+            paths,
+            request_id: None,
+        })
     }
 }
 
@@ -303,6 +547,14 @@ impl<M: IdConvert, DagStore: IdDagStore> Process<RequestNameToLocation, Response
     async fn process(self, request: RequestNameToLocation) -> Result<ResponseIdNamePair> {
         let map = &self.0;
         let dag = &self.1;
+        if let Some(request_id) = request.request_id {
+            tracing::debug!(
+                target: "dag::protocol",
+                request_id,
+                size = request.names.len(),
+                "RequestNameToLocation"
+            );
+        }
 
         let heads: IdSet = {
             let heads = stream::iter(request.heads.into_iter());
@@ -388,6 +640,14 @@ impl<M: IdConvert, DagStore: IdDagStore> Process<RequestLocationToName, Response
     async fn process(self, request: RequestLocationToName) -> Result<ResponseIdNamePair> {
         let map = &self.0;
         let dag = &self.1;
+        if let Some(request_id) = request.request_id {
+            tracing::debug!(
+                target: "dag::protocol",
+                request_id,
+                size = request.paths.len(),
+                "RequestLocationToName"
+            );
+        }
 
         let path_names: Vec<(AncestorPath, Vec<VertexName>)> =
             stream::iter(request.paths.into_iter())
@@ -13,6 +13,7 @@ mod output;
 #[allow(clippy::module_inception)]
 mod render;
 mod render_utils;
+mod topo;
 
 #[cfg(test)]
 mod test_fixtures;
@@ -32,3 +33,5 @@ pub use self::render::Renderer;
 pub use self::render_utils::render_namedag;
 #[cfg(any(test, feature = "indexedlog-backend"))]
 pub use self::render_utils::render_segment_dag;
+pub(crate) use self::topo::TopoColumns;
+pub use self::topo::TopoRow;
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use crate::VertexName;
+
+/// A row of a graph, as returned by `DagAlgorithm::iter_topo_between`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopoRow {
+    /// The vertex of this row.
+    pub vertex: VertexName,
+
+    /// Parents of `vertex` that are in the window, in parent order.
+    pub parents: Vec<VertexName>,
+
+    /// Number of parents of `vertex` that are outside the window.
+    pub parents_outside: usize,
+
+    /// Suggested column of `vertex`. A vertex takes the column of its first
+    /// child in the window if possible, so linear history stays in one
+    /// column.
+    pub column: usize,
+}
+
+/// Assigns column hints for `TopoRow`s, children before parents.
+#[derive(Default)]
+pub(crate) struct TopoColumns {
+    /// The vertex each column is waiting for.
+    columns: Vec<Option<VertexName>>,
+}
+
+impl TopoColumns {
+    /// Assign a column to `vertex`, and reserve columns for its `parents`.
+    pub(crate) fn assign(&mut self, vertex: &VertexName, parents: &[VertexName]) -> usize {
+        let column = match self.find(vertex) {
+            Some(column) => column,
+            None => self.first_empty(),
+        };
+
+        // Other children waiting for the same vertex merge into its column.
+        for other in self.columns.iter_mut() {
+            if other.as_ref() == Some(vertex) {
+                *other = None;
+            }
+        }
+
+        for (i, parent) in parents.iter().enumerate() {
+            if self.find(parent).is_some() {
+                continue;
+            }
+            let parent_column = if i == 0 { column } else { self.first_empty() };
+            self.columns[parent_column] = Some(parent.clone());
+        }
+
+        while let Some(None) = self.columns.last() {
+            self.columns.pop();
+        }
+        column
+    }
+
+    fn find(&self, vertex: &VertexName) -> Option<usize> {
+        self.columns.iter().position(|c| c.as_ref() == Some(vertex))
+    }
+
+    fn first_empty(&mut self) -> usize {
+        match self.columns.iter().position(|c| c.is_none()) {
+            Some(column) => column,
+            None => {
+                self.columns.push(None);
+                self.columns.len() - 1
+            }
+        }
+    }
+}
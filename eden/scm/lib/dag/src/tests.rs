@@ -25,6 +25,9 @@ use crate::Result;
 
 mod test_dag;
 
+#[cfg(test)]
+mod test_dump;
+
 #[cfg(test)]
 mod test_integrity;
 
@@ -40,13 +43,30 @@ pub mod dummy_dag;
 #[cfg(test)]
 pub(crate) use test_dag::ProtocolMonitor;
 
+#[cfg(test)]
+use indexedlog::DefaultOpenOptions;
+
+#[cfg(test)]
+use crate::errors::DagError;
 #[cfg(test)]
 use crate::iddag::FirstAncestorConstraint;
 #[cfg(test)]
+use crate::namedag::LeaseOptions;
+#[cfg(test)]
 use crate::namedag::MemNameDag;
 #[cfg(test)]
+use crate::namedag::PortableDag;
+#[cfg(test)]
+use crate::namedag::VertexFlags;
+#[cfg(test)]
+use crate::nameset::hints::Flags;
+#[cfg(test)]
+use crate::ops::CheckIntegrity;
+#[cfg(test)]
 use crate::ops::IdConvert;
 #[cfg(test)]
+use crate::ops::Persist;
+#[cfg(test)]
 use crate::protocol::Process;
 #[cfg(test)]
 use crate::protocol::RequestLocationToName;
@@ -167,6 +187,55 @@ fn test_generic_dag_beautify<D: DagAlgorithm + DagAddHeads>(new_dag: impl Fn() -
     Ok(())
 }
 
+fn test_generic_dag_iter_topo_between(dag: impl DagAlgorithm + DagAddHeads) -> Result<()> {
+    let ascii = r#"
+         Z
+         |\
+         D |
+         | F
+         C |
+         | E
+         B |
+         |/
+         A
+         "#;
+    let dag = from_ascii_with_heads(dag, ascii, Some(&["Z"][..]));
+
+    let topo = |roots, heads| -> Result<Vec<String>> {
+        let rows = r(dag.iter_topo_between(nameset(roots), nameset(heads)))?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                format!(
+                    "{:?} {:?} +{} @{}",
+                    row.vertex, row.parents, row.parents_outside, row.column
+                )
+            })
+            .collect())
+    };
+
+    // Parents outside the window are counted, but not listed.
+    assert_eq!(
+        topo("B E", "Z")?,
+        [
+            "Z [D, F] +0 @0",
+            "F [E] +0 @1",
+            "E [] +1 @1",
+            "D [C] +0 @0",
+            "C [B] +0 @0",
+            "B [] +1 @0"
+        ]
+    );
+
+    // Branches merge back to the column of the first child.
+    assert_eq!(
+        topo("A", "C E")?,
+        ["E [A] +0 @0", "C [B] +0 @1", "B [A] +0 @1", "A [] +0 @0"]
+    );
+
+    Ok(())
+}
+
 fn test_generic_dag_reachable_roots(dag: impl DagAlgorithm + DagAddHeads) -> Result<()> {
     let ascii = r#"
          Z
@@ -302,6 +371,16 @@ fn test_generic_dag2<T: DagAlgorithm + DagAddHeads>(dag: T) -> Result<T> {
 
     assert_eq!(expand(r(dag.all())?), "A B C D E F G H I J K");
     assert_eq!(expand(r(dag.ancestors(nameset("H I")))?), "A B C D E F H I");
+    let streamed = r(ancestors_stream(&dag, "H I", usize::MAX))?;
+    assert_eq!(sorted_join(&streamed), "A B C D E F H I");
+    for (i, name) in streamed.iter().enumerate() {
+        // Parents are yielded after their children.
+        let parents = expand(r(dag.parents(nameset(name)))?);
+        for parent in parents.split_whitespace() {
+            assert!(streamed[i..].iter().any(|n| n == parent));
+        }
+    }
+    assert_eq!(r(ancestors_stream(&dag, "H I", 2))?, &streamed[..2]);
     assert_eq!(expand(r(dag.first_ancestors(nameset("H I")))?), "A D E H I");
     assert_eq!(
         expand(r(dag.first_ancestors(nameset("J G D")))?),
@@ -325,15 +404,59 @@ fn test_generic_dag2<T: DagAlgorithm + DagAddHeads>(dag: T) -> Result<T> {
         "D F G"
     );
     assert_eq!(expand(r(dag.range(nameset("A"), nameset("K")))?), "A E H K");
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("K")))?),
+        "H J"
+    );
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("H J K")))?),
+        "G I"
+    );
+    // Hidden vertexes reachable from heads not in `hidden` are kept.
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("H")))?),
+        "J K"
+    );
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("I K")))?),
+        "H J"
+    );
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("G K")))?),
+        "H J"
+    );
+    assert_eq!(
+        expand(r(dag.visible_heads(nameset("J K"), nameset("")))?),
+        "J K"
+    );
     assert_eq!(expand(r(dag.only(nameset("I"), nameset("G")))?), "C D F I");
     let (reachable, unreachable) = r(dag.only_both(nameset("I"), nameset("G")))?;
     assert_eq!(expand(reachable), "C D F I");
     assert_eq!(expand(unreachable), expand(r(dag.ancestors(nameset("G")))?));
     assert_eq!(expand(r(dag.descendants(nameset("F E")))?), "E F G H I J K");
+    assert_eq!(expand(r(dag.descendants_within(nameset("A"), 0))?), "A");
+    assert_eq!(
+        expand(r(dag.descendants_within(nameset("A"), 2))?),
+        "A E G H"
+    );
+    assert_eq!(expand(r(dag.descendants_within(nameset("F"), 1))?), "F H I");
+    assert_eq!(
+        expand(r(dag.descendants_within(nameset("F D"), 9))?),
+        expand(r(dag.descendants(nameset("F D")))?)
+    );
 
     assert!(r(dag.is_ancestor(v("B"), v("J")))?);
     assert!(r(dag.is_ancestor(v("F"), v("F")))?);
     assert!(!r(dag.is_ancestor(v("K"), v("I")))?);
+    assert_eq!(
+        r(dag.is_ancestor_batch(&[
+            (v("B"), v("J")),
+            (v("F"), v("F")),
+            (v("K"), v("I")),
+            (v("C"), v("J")),
+        ]))?,
+        vec![true, true, false, true]
+    );
 
     Ok(dag)
 }
@@ -363,6 +486,9 @@ fn test_mem_namedag() {
     C+N2 : C+N2 [] Root
     B+N1 : B+N1 [A+N0]
     A+N0 : A+N0 [] Root
+  Group Hidden:
+   Next Free Id: H0
+   Segments: 0
 "#
     );
 }
@@ -372,6 +498,11 @@ fn test_dag_reachable_roots() {
     test_generic_dag_reachable_roots(MemNameDag::new()).unwrap()
 }
 
+#[test]
+fn test_dag_iter_topo_between() {
+    test_generic_dag_iter_topo_between(MemNameDag::new()).unwrap()
+}
+
 #[test]
 fn test_dag_import() {
     test_generic_dag_import(MemNameDag::new()).unwrap()
@@ -398,6 +529,9 @@ fn test_namedag() {
    Next Free Id: N11
    Segments: 1
     A+N0 : J+N8 [] Root
+  Group Hidden:
+   Next Free Id: H0
+   Segments: 0
  Level 0
   Group Master:
    Next Free Id: 0
@@ -415,10 +549,288 @@ fn test_namedag() {
     E+N2 : G+N3 [A+N0, B+N1]
     B+N1 : B+N1 [] Root
     A+N0 : A+N0 [] Root
+  Group Hidden:
+   Next Free Id: H0
+   Segments: 0
 "#
     );
 }
 
+#[test]
+fn test_portable_roundtrip() {
+    let mut dag = TestDag::new();
+    dag.drawdag(ASCII_DAG2, &["W"]);
+    dag.drawdag("W-X-Y", &[]);
+    let data = r(dag.dag.export_portable()).unwrap();
+    assert_eq!(data.segments.len(), 12);
+    assert_eq!(data.idmap.len(), 25);
+
+    // The snapshot can be encoded using serde.
+    let bytes = mincode::serialize(&data).unwrap();
+    let data: PortableDag = mincode::deserialize(&bytes).unwrap();
+
+    let expected = format!("{:?}", &dag.dag);
+    let mut mem_dag = MemNameDag::new();
+    mem_dag.dag.set_new_segment_size(dag.seg_size);
+    r(mem_dag.import_portable(data.clone())).unwrap();
+    assert_eq!(format!("{:?}", &mem_dag), expected);
+
+    let dir = tempdir().unwrap();
+    let mut name_dag = NameDag::open(dir.path().join("n")).unwrap();
+    name_dag.dag.set_new_segment_size(dag.seg_size);
+    r(name_dag.import_portable(data.clone())).unwrap();
+    let name_dag = NameDag::open(dir.path().join("n")).unwrap();
+    assert_eq!(format!("{:?}", &name_dag), expected);
+
+    // Import requires an empty graph and a known version.
+    let e = r(mem_dag.import_portable(data.clone())).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: Cannot import portable dag for non-empty graph"
+    );
+    let e = r(MemNameDag::new().import_portable(PortableDag { version: 0, ..data })).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: unsupported portable dag version 0 (expected 1)"
+    );
+}
+
+#[test]
+fn test_namedag_open_read_only() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C", &["C"]);
+    let path = dag.dir.path().join("n");
+
+    // Opening does not wait for the lock held by writers.
+    let lock = indexedlog::lock::ScopedDirLock::new(&path).unwrap();
+    let mut ro_dag = NameDag::open_read_only(&path).unwrap();
+    drop(lock);
+    assert_eq!(expand(r(ro_dag.all()).unwrap()), "A B C");
+
+    // Mutations are rejected.
+    let parents = TestDag::draw("C-D").dag.dag_snapshot().unwrap();
+    let e = r(ro_dag.add_heads(&parents, &["D".into()])).unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("ProgrammingError: add_heads called on read-only NameDag"));
+    let e = r(ro_dag.flush(&["C".into()])).unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("ProgrammingError: flush called on read-only NameDag"));
+    assert!(ro_dag.defragment().is_err());
+
+    // Missing dags are not created.
+    let missing = dag.dir.path().join("missing");
+    assert!(NameDag::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+}
+
+#[test]
+fn test_namedag_open_without_vertex_meta() {
+    // A NameDag written before vertex flags were part of its MultiLog.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("n");
+    NameDag::open_options_without_vertex_meta()
+        .open(&path)
+        .unwrap();
+
+    let ro_dag = NameDag::open_read_only(&path).unwrap();
+    assert_eq!(expand(r(ro_dag.all()).unwrap()), "");
+
+    // Writers add the log of flags.
+    let mut dag = NameDag::open(&path).unwrap();
+    let parents = TestDag::draw("A-B").dag.dag_snapshot().unwrap();
+    r(dag.add_heads_and_flush(&parents, &["B".into()], &[])).unwrap();
+    r(dag.set_vertex_flags(nameset("A"), VertexFlags::PUBLIC, true)).unwrap();
+    r(dag.flush(&[])).unwrap();
+    let ro_dag = NameDag::open_read_only(&path).unwrap();
+    assert_eq!(
+        r(ro_dag.vertex_flags(&"A".into())).unwrap(),
+        VertexFlags::PUBLIC
+    );
+}
+
+#[test]
+fn test_namedag_strip() {
+    let mut dag = TestDag::new();
+    dag.drawdag(
+        r#"
+        A-B-C-D-E
+           \   \
+            F-G H"#,
+        &["E"],
+    );
+
+    // Descendants are removed. Master segments are truncated.
+    r(dag.dag.strip(nameset("D"))).unwrap();
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C F G");
+    assert_eq!(r(dag.dag.check_segments()).unwrap(), [] as [String; 0]);
+    assert!(r(dag.dag.vertex_id("E".into())).is_err());
+    assert!(r(dag.dag.vertex_id("H".into())).is_err());
+
+    // Non-master vertexes. Changes are on disk.
+    r(dag.dag.strip(nameset("G"))).unwrap();
+    dag.reopen();
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C F");
+    assert_eq!(r(dag.dag.check_consistency()).unwrap(), []);
+
+    // Stripped vertexes can be added back.
+    dag.drawdag("C-D-E", &["E"]);
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C D E F");
+    assert_eq!(r(dag.dag.check_consistency()).unwrap(), []);
+}
+
+#[test]
+fn test_namedag_gca_with_paths() {
+    let mut dag = TestDag::new();
+    dag.drawdag(
+        r#"
+        A-B-C-D-G
+           \   /
+            E-F   X-Y"#,
+        &["G"],
+    );
+    let paths = |names: &str| -> Vec<String> {
+        let result = r(dag.dag.gca_with_paths(nameset(names))).unwrap().unwrap();
+        let mut paths = vec![format!("{:?}", result.gca)];
+        for path in result.paths {
+            let ids = IdSet::from_spans(path.segments.segments.iter().map(|s| s.low..=s.high));
+            let names = expand(NameSet::from_spans_dag(ids, &dag.dag).unwrap());
+            paths.push(format!(
+                "{:?}: {:?} {}",
+                path.head, path.first_parent_distance, names
+            ));
+        }
+        paths
+    };
+
+    assert_eq!(paths("D F"), ["B", "F: Some(2) B E F", "D: Some(2) B C D"]);
+    assert_eq!(paths("G E"), ["E", "G: None E F G", "E: Some(0) E"]);
+    assert_eq!(paths("C"), ["C", "C: Some(0) C"]);
+    assert!(r(dag.dag.gca_with_paths(nameset("D Y"))).unwrap().is_none());
+}
+
+#[test]
+fn test_namedag_heads_ancestors_with_counts() {
+    let mut dag = TestDag::new();
+    dag.drawdag(
+        r#"
+        A-B-C-D-G
+           \   /
+            E-F   X-Y"#,
+        &["G"],
+    );
+    let counts = |names: &str| -> Vec<String> {
+        r(dag.dag.heads_ancestors_with_counts(nameset(names)))
+            .unwrap()
+            .into_iter()
+            .map(|(name, count)| format!("{:?}: {}", name, count))
+            .collect()
+    };
+
+    assert_eq!(counts("G"), ["G: 7"]);
+    assert_eq!(counts("C F"), ["F: 4", "C: 1"]);
+    assert_eq!(counts("A D G Y"), ["Y: 2", "G: 7"]);
+    assert_eq!(counts(""), [] as [String; 0]);
+}
+
+#[test]
+fn test_namedag_interrupted_flush() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C", &["C"]);
+    let path = dag.dir.path().join("n");
+
+    // Write the IdMap, but die before writing the MultiMeta.
+    let (lock, map_lock, dag_lock) = dag.dag.reload().unwrap();
+    dag.dag.map.insert(Id(100), b"X").unwrap();
+    dag.dag.map.persist(&map_lock).unwrap();
+    drop((dag_lock, map_lock, lock));
+    assert!(path.join("journal").exists());
+
+    // Opening rolls the flush back.
+    dag.reopen();
+    assert!(!path.join("journal").exists());
+    assert!(r(dag.dag.vertex_id("X".into())).is_err());
+    assert_eq!(r(dag.dag.check_consistency()).unwrap(), []);
+
+    // Completed flushes leave no journal.
+    dag.drawdag("C-D", &["D"]);
+    assert!(!path.join("journal").exists());
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C D");
+}
+
+#[test]
+fn test_namedag_repair_interrupted_flush() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C", &["C"]);
+    dag.drawdag("C-D", &["D"]);
+    let path = dag.dir.path().join("n");
+
+    // Start a flush, and die leaving the IdDag log shorter than the
+    // MultiMeta says, so it can't be opened as is.
+    let locks = dag.dag.reload().unwrap();
+    drop(locks);
+    assert!(path.join("journal").exists());
+    let log_path = path.join("iddag").join("log");
+    let len = std::fs::metadata(&log_path).unwrap().len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)
+        .unwrap();
+    file.set_len(len - 1).unwrap();
+    drop(file);
+    assert!(NameDag::default_open_options().open(&path).is_err());
+
+    // Opening repairs the logs, and rolls back to a consistent version.
+    dag.reopen();
+    assert!(!path.join("journal").exists());
+    assert_eq!(r(dag.dag.check_consistency()).unwrap(), []);
+    dag.drawdag("C-D", &["D"]);
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C D");
+}
+
+#[test]
+fn test_namedag_lease() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C", &["C"]);
+    let path = dag.dir.path().join("n");
+    let opts = |holder: &str| {
+        LeaseOptions::new(holder).with_timeout(std::time::Duration::from_millis(100))
+    };
+    let mut writer_a = NameDag::open_with_lease(&path, opts("writer-a")).unwrap();
+    let mut writer_b = NameDag::open_with_lease(&path, opts("writer-b")).unwrap();
+
+    // A writer gives up waiting for the lease, and names its holder.
+    let locks = writer_a.reload().unwrap();
+    let parents = TestDag::draw("C-D").dag.dag_snapshot().unwrap();
+    r(writer_b.add_heads(&parents, &["D".into()])).unwrap();
+    let e = r(writer_b.flush(&["D".into()])).unwrap_err();
+    match &e {
+        DagError::LeaseTimeout(e) => assert_eq!(e.holder.holder, "writer-a"),
+        _ => panic!("unexpected error: {}", e),
+    }
+    assert!(e.to_string().contains("held by writer-a"), "{}", e);
+
+    // Once the lease is released, the flush can be retried.
+    drop(locks);
+    r(writer_b.flush(&["D".into()])).unwrap();
+    assert!(!path.join("lease").exists());
+
+    // A writer without a lease holding the lock is not waited for forever
+    // either.
+    let mut writer_c = NameDag::open(&path).unwrap();
+    let locks = writer_c.reload().unwrap();
+    let parents = TestDag::draw("D-E").dag.dag_snapshot().unwrap();
+    r(writer_b.add_heads(&parents, &["E".into()])).unwrap();
+    let e = r(writer_b.flush(&["E".into()])).unwrap_err();
+    assert!(matches!(e, DagError::LockTimeout(_)), "{}", e);
+    assert!(!path.join("lease").exists());
+    drop(locks);
+    r(writer_b.flush(&["E".into()])).unwrap();
+    dag.reopen();
+    assert_eq!(expand(r(dag.dag.all()).unwrap()), "A B C D E");
+}
+
 #[test]
 fn test_protocols() {
     let mut built = build_segments(ASCII_DAG1, "A C E L", 3);
@@ -450,7 +862,7 @@ Lv1: R0-0[] R1-1[] 2-7[0, 1]"#
         r((&built.name_dag.map, &built.name_dag.dag).process(ids)).unwrap();
     assert_eq!(
         replace(format!("{:?}", &request1)),
-        "RequestLocationToName { paths: [J~1, L~2(+5), D~1, B~1] }"
+        "RequestLocationToName { paths: [J~1, L~2(+5), D~1, B~1], request_id: None }"
     );
 
     // [name] -> RequestNameToLocation (useful for getting ids from commit hashes).
@@ -462,7 +874,7 @@ Lv1: R0-0[] R1-1[] 2-7[0, 1]"#
         r((&built.name_dag.map, &built.name_dag.dag).process(names)).unwrap();
     assert_eq!(
         replace(format!("{:?}", &request2)),
-        "RequestNameToLocation { names: [A, B, C, E, F, G, H, I], heads: [L] }"
+        "RequestNameToLocation { names: [A, B, C, E, F, G, H, I], heads: [L], request_id: None }"
     );
 
     // RequestLocationToName -> ResponseIdNamePair
@@ -524,6 +936,22 @@ Lv1: R0-0[] R1-1[] 2-7[0, 1]"#
     );
 }
 
+#[test]
+fn test_protocol_request_id() {
+    // Requests without an id, like those from older clients, are accepted.
+    let request: RequestNameToLocation = serde_json::from_str(r#"{"n":[],"h":[]}"#).unwrap();
+    assert_eq!(request.request_id, None);
+
+    let request = RequestLocationToName {
+        paths: Vec::new(),
+        request_id: Some(42),
+    };
+    let json = serde_json::to_string(&request).unwrap();
+    assert_eq!(json, r#"{"p":[],"r":42}"#);
+    let request: RequestLocationToName = serde_json::from_str(&json).unwrap();
+    assert_eq!(request.request_id, Some(42));
+}
+
 #[test]
 fn test_segment_non_master() {
     let ascii = r#"
@@ -667,6 +1095,41 @@ Lv2: R0-14[]"#
     );
 }
 
+#[test]
+fn test_segment_defragment() {
+    // Adding heads one by one fragments Lv1 segments.
+    let heads = "C F H K M O Q R T W";
+    let fragmented = build_segments(ASCII_DAG2, heads, 3);
+    let lv1 = fragmented.ascii.last().unwrap().lines().rev().nth(1);
+    assert_eq!(
+        lv1.unwrap(),
+        "Lv1: R0-2[] 3-10[1, 2] 11-12[7] 13-17[4, 9, 12, 10] R18-20[3]"
+    );
+
+    // Defragment rebuilds Lv1 segments.
+    let mut name_dag = fragmented.name_dag;
+    assert_eq!(name_dag.defragment().unwrap(), 7);
+    let defragmented = format!("{:?}", &name_dag.dag);
+    assert_eq!(
+        defragmented,
+        r#"Lv0: RH0-2[] 3-4[1] 5-5[2] H6-10[5, 4] 11-12[7] 13-14[4, 9] 15-15[12, 14] H16-17[10, 15] 18-18[3] R19-19[] 20-20[19, 18] H21-22[17, 20]
+Lv1: R0-2[] 3-10[1, 2] 11-15[7, 4, 9] 16-17[10, 15] R18-20[3]
+Lv2: R0-10[] 11-17[7, 4, 9, 10]"#
+    );
+
+    // The incremental mode does not fragment segments in the first place.
+    let mut dag = TestDag::new_with_segment_size(3);
+    dag.dag.dag.set_incremental_high_level_segments(true);
+    for head in heads.split(' ') {
+        dag.drawdag_with_limited_heads(ASCII_DAG2, &[head], Some(&[head]));
+    }
+    assert_eq!(dag.render_segments(), defragmented);
+
+    // The changes are persisted.
+    let name_dag = NameDag::open(fragmented.dir.path().join("n")).unwrap();
+    assert_eq!(format!("{:?}", &name_dag.dag), defragmented);
+}
+
 #[test]
 fn test_segment_groups() {
     let dag = r#"
@@ -819,6 +1282,96 @@ fn test_namedag_reassign_non_master() {
     assert_eq!(format!("{:?}", z_vertex), "Z");
 }
 
+#[test]
+fn test_namedag_hidden_group() {
+    let mut t = TestDag::new();
+    t.drawdag("A-B-C", &["C"]);
+    let parents = TestDag::draw(
+        r#"
+        A-B-C-D-E-G
+             \
+              F"#,
+    )
+    .dag
+    .dag_snapshot()
+    .unwrap();
+    let group = |t: &TestDag, name: &'static str| r(t.dag.vertex_id(name.into())).unwrap().group();
+
+    // D, E: hidden; F: non-master.
+    r(t.dag.add_hidden_heads(&parents, &["E".into()])).unwrap();
+    r(t.dag.add_heads(&parents, &["F".into()])).unwrap();
+    assert_eq!(group(&t, "E"), Group::HIDDEN);
+    assert_eq!(group(&t, "F"), Group::NON_MASTER);
+
+    // Groups are kept by flush.
+    r(t.dag.flush(&["C".into()])).unwrap();
+    t.reopen();
+    assert_eq!(group(&t, "C"), Group::MASTER);
+    assert_eq!(group(&t, "D"), Group::HIDDEN);
+    assert_eq!(group(&t, "E"), Group::HIDDEN);
+    assert_eq!(group(&t, "F"), Group::NON_MASTER);
+    assert_eq!(expand(r(t.dag.all()).unwrap()), "A B C D E F");
+    assert_eq!(
+        format!("{:?}", r(t.dag.vertex_id("E".into())).unwrap()),
+        "H1"
+    );
+
+    // Ancestors of visible heads are no longer hidden.
+    r(t.dag.add_heads(&parents, &["G".into()])).unwrap();
+    assert_eq!(group(&t, "D"), Group::NON_MASTER);
+    assert_eq!(group(&t, "E"), Group::NON_MASTER);
+    assert_eq!(group(&t, "G"), Group::NON_MASTER);
+    assert_eq!(expand(r(t.dag.all()).unwrap()), "A B C D E F G");
+    r(t.dag.flush(&[])).unwrap();
+    t.reopen();
+    assert_eq!(group(&t, "E"), Group::NON_MASTER);
+    assert_eq!(r(t.dag.check_consistency()).unwrap(), []);
+}
+
+#[test]
+fn test_namedag_vertex_flags() {
+    let mut t = TestDag::new();
+    t.drawdag("A-B-C", &["C"]);
+    t.drawdag("C-D-E", &[]);
+    let flags = |t: &TestDag, name: &'static str| r(t.dag.vertex_flags(&name.into())).unwrap();
+    let filter = |t: &TestDag, flags: VertexFlags| {
+        let all = r(t.dag.all()).unwrap();
+        expand(r(t.dag.filter_by_vertex_flags(all, flags)).unwrap())
+    };
+    let set = |t: &mut TestDag, names: &'static str, flags: VertexFlags, value: bool| {
+        r(t.dag.set_vertex_flags(nameset(names), flags, value)).unwrap()
+    };
+
+    set(&mut t, "A B C", VertexFlags::PUBLIC, true);
+    set(&mut t, "C D", VertexFlags::OBSOLETE, true);
+    assert_eq!(flags(&t, "C"), VertexFlags::PUBLIC | VertexFlags::OBSOLETE);
+    assert_eq!(flags(&t, "E"), VertexFlags::empty());
+    assert_eq!(filter(&t, VertexFlags::PUBLIC), "A B C");
+    assert_eq!(filter(&t, VertexFlags::empty()), "A B C D");
+
+    // Flags are written by flush, including flags of non-master vertexes.
+    r(t.dag.flush(&[])).unwrap();
+    t.reopen();
+    assert_eq!(filter(&t, VertexFlags::OBSOLETE), "C D");
+    set(&mut t, "A C", VertexFlags::PUBLIC, false);
+    r(t.dag.flush(&[])).unwrap();
+    t.reopen();
+    assert_eq!(filter(&t, VertexFlags::PUBLIC), "B");
+    assert_eq!(flags(&t, "C"), VertexFlags::OBSOLETE);
+
+    // Flags of non-master vertexes are dropped when their ids are re-assigned.
+    t.drawdag("", &["D"]);
+    t.reopen();
+    assert_eq!(flags(&t, "D"), VertexFlags::empty());
+    assert_eq!(filter(&t, VertexFlags::empty()), "B C");
+
+    // Flags of stripped vertexes are dropped.
+    r(t.dag.strip(nameset("C"))).unwrap();
+    t.drawdag("B-C", &["C"]);
+    assert_eq!(flags(&t, "C"), VertexFlags::empty());
+    assert_eq!(filter(&t, VertexFlags::empty()), "B");
+}
+
 #[test]
 fn test_segment_ancestors_example1() {
     // DAG from segmented-changelog.pdf
@@ -863,6 +1416,10 @@ fn test_segment_ancestors_example1() {
         assert_eq!(dag.gca_all((a, b).into()).unwrap().iter().nth(1), None);
         assert_eq!(dag.is_ancestor(b, a).unwrap(), ancestor == Some(b));
         assert_eq!(dag.is_ancestor(a, b).unwrap(), ancestor == Some(a));
+        assert_eq!(
+            dag.is_ancestor_batch(&[(b, a), (a, b)]).unwrap(),
+            vec![ancestor == Some(b), ancestor == Some(a)]
+        );
     }
 
     for (spans, ancestors) in vec![
@@ -1129,6 +1686,10 @@ Lv1: R0-0[] R1-3[0] R4-4[] R5-6[1, 4] 7-7[2, 6]
 Lv2: R0-3[] R4-6[1]"#
     );
 
+    let (set, truncated) = r(result.name_dag.range_limited(nameset("A"), nameset("J"), 2)).unwrap();
+    assert_eq!(expand(set), "A E");
+    assert!(truncated);
+
     let dag = result.name_dag.dag;
     let range = |roots, heads| -> String {
         format_set(
@@ -1219,9 +1780,71 @@ Lv2: R0-3[] R4-6[1]"#
             dag.range(all.clone(), set.clone()).unwrap().as_spans(),
             dag.ancestors(set.clone()).unwrap().as_spans(),
         );
+
+        // Test heads_ancestors_with_counts() against heads_ancestors().
+        let with_counts = dag.heads_ancestors_with_counts(set.clone()).unwrap();
+        let heads = IdSet::from_spans(with_counts.iter().map(|(id, _)| *id));
+        assert_eq!(
+            heads.as_spans(),
+            dag.heads_ancestors(set.clone()).unwrap().as_spans()
+        );
+        assert_eq!(
+            with_counts.iter().map(|(_, count)| count).sum::<u64>(),
+            dag.ancestors(set.clone()).unwrap().count()
+        );
+
+        // Test range_limited() against range().
+        let full = dag.range(set.clone(), all.clone()).unwrap();
+        for max_count in 0..=full.count() {
+            let (limited, truncated) = dag
+                .range_limited(set.clone(), all.clone(), max_count)
+                .unwrap();
+            assert_eq!(truncated, max_count < full.count());
+            assert_eq!(
+                limited.as_spans(),
+                full.skip(full.count() - max_count).as_spans()
+            );
+        }
     }
 }
 
+#[test]
+fn test_full_set_fast_paths() {
+    let mut t = TestDag::draw("A-B-C D-E");
+    let all = r(t.dag.all()).unwrap();
+    assert!(all.hints().contains(Flags::FULL));
+
+    assert_eq!(
+        expand(r(t.dag.descendants(all.clone())).unwrap()),
+        "A B C D E"
+    );
+    assert_eq!(
+        expand(r(t.dag.descendants_within(all.clone(), 1)).unwrap()),
+        "A B C D E"
+    );
+    assert_eq!(
+        expand(r(t.dag.range(all.clone(), nameset("B"))).unwrap()),
+        "A B"
+    );
+    assert_eq!(
+        expand(r(t.dag.range(nameset("D"), all.clone())).unwrap()),
+        "D E"
+    );
+    assert!(r(t.dag.sort(&all)).unwrap().hints().contains(Flags::FULL));
+
+    // After the graph changes, the old set no longer covers all vertexes.
+    t.drawdag("C-F G-H", &[]);
+    assert_eq!(
+        expand(r(t.dag.descendants(all.clone())).unwrap()),
+        "A B C D E F"
+    );
+    assert_eq!(
+        expand(r(t.dag.range(all.clone(), nameset("H"))).unwrap()),
+        ""
+    );
+    assert_eq!(expand(r(t.dag.range(nameset("G"), all)).unwrap()), "");
+}
+
 #[test]
 fn test_render_segment_dag() {
     // For reference in below graphs.
@@ -1324,6 +1947,28 @@ fn expand(set: NameSet) -> String {
     names.join(" ")
 }
 
+fn sorted_join(names: &[String]) -> String {
+    let mut names = names.to_vec();
+    names.sort();
+    names.join(" ")
+}
+
+/// Take up to `limit` names from `ancestors_stream`.
+async fn ancestors_stream(
+    dag: &impl DagAlgorithm,
+    names: &str,
+    limit: usize,
+) -> Result<Vec<String>> {
+    use futures::StreamExt;
+    use futures::TryStreamExt;
+    dag.ancestors_stream(nameset(names))
+        .await?
+        .take(limit)
+        .map_ok(|n| String::from_utf8_lossy(n.as_ref()).to_string())
+        .try_collect()
+        .await
+}
+
 fn nameset(names: &str) -> NameSet {
     let names: Vec<VertexName> = names
         .split_whitespace()
@@ -229,12 +229,8 @@ impl TestDag {
 
     /// Describe segments at the given level and group as a string.
     pub fn debug_segments(&self, level: Level, group: Group) -> String {
-        let lines = crate::namedag::debug_segments_by_level_group(
-            &self.dag.dag,
-            &self.dag.map,
-            level,
-            group,
-        );
+        let lines =
+            crate::dump::debug_segments_by_level_group(&self.dag.dag, &self.dag.map, level, group);
         lines
             .iter()
             .map(|l| format!("\n        {}", l))
@@ -278,21 +274,42 @@ impl RemoteIdConvertProtocol for ProtocolMonitor {
         &self,
         heads: Vec<Vertex>,
         names: Vec<Vertex>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(protocol::AncestorPath, Vec<Vertex>)>> {
         let msg = format!("resolve names: {:?}, heads: {:?}", &names, &heads);
         self.output.lock().push(msg);
         self.inner
-            .resolve_names_to_relative_paths(heads, names)
+            .resolve_names_to_relative_paths(heads, names, request_id)
             .await
     }
 
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<protocol::AncestorPath>,
+        request_id: Option<u64>,
     ) -> Result<Vec<(protocol::AncestorPath, Vec<Vertex>)>> {
         let msg = format!("resolve paths: {:?}", &paths);
         self.output.lock().push(msg);
-        self.inner.resolve_relative_paths_to_names(paths).await
+        self.inner
+            .resolve_relative_paths_to_names(paths, request_id)
+            .await
+    }
+
+    async fn resolve_hex_prefix(
+        &self,
+        heads: Vec<Vertex>,
+        hex_prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<Vertex>> {
+        let msg = format!(
+            "resolve hex prefix: {}, heads: {:?}",
+            String::from_utf8_lossy(&hex_prefix),
+            &heads
+        );
+        self.output.lock().push(msg);
+        self.inner
+            .resolve_hex_prefix(heads, hex_prefix, limit)
+            .await
     }
 }
 
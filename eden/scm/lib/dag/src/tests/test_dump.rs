@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use super::TestDag;
+use crate::dump::DumpOptions;
+use crate::Group;
+
+#[test]
+fn test_dump_json() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C-D", &["C"]);
+
+    let options = DumpOptions {
+        groups: vec![Group::NON_MASTER],
+        levels: Some(vec![0]),
+    };
+    let dump = dag.dag.dump(&options).unwrap();
+    assert_eq!(
+        dump.to_json(),
+        r#"{
+  "lazy": false,
+  "max_level": 0,
+  "groups": [
+    {
+      "group": "Group Non-Master",
+      "next_free_id": "N1",
+      "levels": [
+        {
+          "level": 0,
+          "segments": [
+            {
+              "low": "N0",
+              "high": "N0",
+              "low_name": "44",
+              "high_name": "44",
+              "parents": [
+                "2"
+              ],
+              "flags": [],
+              "lazy": false
+            }
+          ]
+        }
+      ],
+      "named": [
+        {
+          "low": "N0",
+          "high": "N0"
+        }
+      ],
+      "lazy": []
+    }
+  ]
+}"#
+    );
+}
+
+#[tokio::test]
+async fn test_dump_dot_lazy() {
+    let mut server = TestDag::new();
+    server.drawdag("A-B-C-D-E", &["E"]);
+    let mut client = server.client_cloned_data().await;
+    client.drawdag("E-F", &[]);
+
+    let dump = client.dag.dump(&DumpOptions::default()).unwrap();
+    assert!(dump.lazy);
+    let master = &dump.groups[0];
+    assert!(master.levels[0].segments[0].lazy);
+    assert!(!master.lazy.is_empty());
+    assert!(dump.groups[1].lazy.is_empty());
+
+    let dot = dump.to_dot();
+    assert!(dot.starts_with("digraph dag {"));
+    assert!(dot.contains("style=dashed"));
+    assert!(dot.contains("\"L0:N0\" -> \"L0:0\";"));
+}
@@ -8,7 +8,11 @@
 use super::TestDag;
 use crate::ops::CheckIntegrity;
 use crate::ops::DagAlgorithm;
+use crate::ops::IdConvert;
+use crate::ConsistencyProblem;
 use crate::Group;
+use crate::Id;
+use crate::NameDag;
 
 #[tokio::test]
 async fn test_isomorphic_graph_with_different_segments() {
@@ -88,6 +92,88 @@ async fn test_non_isomorphic_graphs() {
     );
 }
 
+#[tokio::test]
+async fn test_check_consistency() {
+    let mut dag = TestDag::new();
+    dag.drawdag("A-B-C-D", &["D"]);
+    assert_eq!(dag.dag.check_consistency().await.unwrap(), []);
+
+    // Add an orphaned name that is not covered by segments.
+    let (lock, map_lock, dag_lock) = dag.dag.reload().unwrap();
+    dag.dag.map.insert(Id(100), b"X").unwrap();
+    dag.dag.persist(lock, map_lock, dag_lock).unwrap();
+    let problems = dag.dag.check_consistency().await.unwrap();
+    assert_eq!(
+        problems,
+        [ConsistencyProblem::OrphanedName(Id(100), "X".into())]
+    );
+
+    // Repair removes the orphaned name on disk.
+    assert_eq!(dag.dag.repair_consistency().await.unwrap(), problems);
+    assert_eq!(dag.dag.check_consistency().await.unwrap(), []);
+    let reopened = NameDag::open(dag.dir.path().join("n")).unwrap();
+    assert_eq!(reopened.check_consistency().await.unwrap(), []);
+    assert!(reopened.vertex_id("X".into()).await.is_err());
+    assert_eq!(reopened.vertex_id("D".into()).await.unwrap(), Id(3));
+
+    // Missing names are reported, but not repaired.
+    let (lock, map_lock, dag_lock) = dag.dag.reload().unwrap();
+    dag.dag.map.remove_range(Id(1), Id(1)).unwrap();
+    dag.dag.persist(lock, map_lock, dag_lock).unwrap();
+    let problems = dag.dag.repair_consistency().await.unwrap();
+    assert_eq!(problems, [ConsistencyProblem::MissingName(Id(1))]);
+    assert_eq!(dag.dag.check_consistency().await.unwrap(), problems);
+}
+
+#[tokio::test]
+async fn test_canonical_flat_segments_and_fingerprint() {
+    let ascii = r#"
+        A--B--C--D--E    K--L--M
+         \     \     \
+          F--G--H--I--J"#;
+    let mut dag1 = TestDag::new();
+    let mut dag2 = TestDag::new();
+    dag1.drawdag(ascii, &["J", "M"]);
+    dag2.drawdag(ascii, &["K", "F", "B", "H", "D", "I", "E", "J", "M"]);
+
+    // Different segments, same canonical layout.
+    let canonical1 = dag1.dag.canonical_flat_segments().await.unwrap();
+    let canonical2 = dag2.dag.canonical_flat_segments().await.unwrap();
+    let segments: Vec<(u64, u64, Vec<u64>)> = canonical1
+        .flat_segments
+        .segments
+        .iter()
+        .map(|s| (s.low.0, s.high.0, s.parents.iter().map(|p| p.0).collect()))
+        .collect();
+    assert_eq!(
+        segments,
+        [
+            (0, 4, vec![]),
+            (5, 6, vec![0]),
+            (7, 8, vec![2, 6]),
+            (9, 9, vec![4, 8]),
+            (10, 12, vec![])
+        ]
+    );
+    assert_eq!(canonical1.idmap.len(), 13);
+    assert_eq!(canonical1.idmap[&Id(9)], "J".into());
+    assert_eq!(canonical1.flat_segments, canonical2.flat_segments);
+    assert_eq!(canonical1.idmap, canonical2.idmap);
+
+    let fingerprint = dag1.dag.graph_fingerprint().await.unwrap();
+    assert_eq!(dag2.dag.graph_fingerprint().await.unwrap(), fingerprint);
+
+    // Different graphs have different fingerprints.
+    let dag3 = TestDag::draw("A--B--C--D--E K--L--M F--G");
+    assert_ne!(dag3.dag.graph_fingerprint().await.unwrap(), fingerprint);
+    let dag4 = TestDag::draw("Z--A--B--C");
+    let dag5 = TestDag::draw("A--B--C");
+    assert_ne!(
+        dag4.dag.graph_fingerprint().await.unwrap(),
+        dag5.dag.graph_fingerprint().await.unwrap()
+    );
+}
+
 async fn quick_check_graphs(ascii1: &str, ascii2: &str) -> Vec<String> {
     let dag1 = TestDag::draw(ascii1);
     let dag2 = TestDag::draw(ascii2);
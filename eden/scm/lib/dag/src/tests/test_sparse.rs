@@ -6,19 +6,33 @@
  */
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::TryStreamExt;
+use parking_lot::Mutex;
 
 use super::ProtocolMonitor;
 use super::TestDag;
+use crate::errors::DagError;
+use crate::namedag::CacheLimits;
+use crate::namedag::DagMetrics;
 use crate::ops::DagAddHeads;
 use crate::ops::DagAlgorithm;
+use crate::ops::DagExportCloneData;
+use crate::ops::DagImportCloneData;
 use crate::ops::DagImportPullData;
 use crate::ops::DagPersistent;
 use crate::ops::DagPullFastForwardMasterData;
 use crate::ops::IdConvert;
+use crate::ops::PrefixLookup;
+use crate::protocol::AncestorPath;
+use crate::protocol::CoalescingProtocol;
+use crate::protocol::RemoteIdConvertProtocol;
+use crate::BudgetResource;
 use crate::Group;
 use crate::Id;
+use crate::NameSet;
+use crate::QueryBudget;
 use crate::VertexName;
 
 #[tokio::test]
@@ -67,6 +81,9 @@ async fn test_sparse_dag() {
   Group Non-Master:
    Next Free Id: N0
    Segments: 0
+  Group Hidden:
+   Next Free Id: H0
+   Segments: 0
 "#
         );
 
@@ -124,6 +141,103 @@ async fn test_negative_cache() {
     assert!(client.dag.vertex_id("C".into()).await.is_ok());
 }
 
+#[tokio::test]
+async fn test_persisted_negative_cache() {
+    let server = TestDag::draw("A-B-C  # master: C");
+
+    let mut client = TestDag::draw("A-B  # master: B").with_remote(&server);
+    assert!(client.dag.vertex_id("X".into()).await.is_err());
+    assert_eq!(client.output(), ["resolve names: [X], heads: [B]"]);
+
+    // The negative cache is loaded on open.
+    client.reopen();
+    assert!(client.dag.vertex_id("X".into()).await.is_err());
+    assert_eq!(client.output(), Vec::<String>::new());
+
+    // It is dropped once the master group moves.
+    client.drawdag("B-C", &["C"]);
+    client.output();
+    client.reopen();
+    assert!(client.dag.vertex_id("X".into()).await.is_err());
+    assert_eq!(client.output(), ["resolve names: [X], heads: [C]"]);
+}
+
+#[tokio::test]
+async fn test_hex_prefix_lookup() {
+    let server = TestDag::draw("A-BB-C-D-E  # master: E");
+    let mut client = server.client_cloned_data().await;
+
+    // "BB" (hex 4242) is lazy, so it is resolved remotely.
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 2).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("BB")]);
+    assert_eq!(client.output(), ["resolve hex prefix: 4242, heads: [E]"]);
+
+    // The result is cached, and answers lookups with a smaller limit.
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 1).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("BB")]);
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 5).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("BB")]);
+    assert_eq!(client.output(), Vec::<String>::new());
+
+    // Short prefixes are only resolved locally.
+    let names = client.dag.vertexes_by_hex_prefix(b"43", 1).await.unwrap();
+    assert!(names.is_empty());
+    assert_eq!(client.output(), Vec::<String>::new());
+
+    // No remote lookup is needed if enough names are known locally.
+    let names = client.dag.vertexes_by_hex_prefix(b"45", 1).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("E")]);
+    assert_eq!(client.output(), Vec::<String>::new());
+
+    // Names outside the master group of the client are not returned.
+    let names = client.dag.vertexes_by_hex_prefix(b"4646", 1).await.unwrap();
+    assert!(names.is_empty());
+    assert_eq!(client.output(), ["resolve hex prefix: 4646, heads: [E]"]);
+
+    // The server refuses short prefixes, and prefixes matching too many
+    // names outside "::heads".
+    let e = server
+        .dag
+        .resolve_hex_prefix(vec!["E".into()], b"42".to_vec(), 1)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: hex prefix 42 is shorter than 4 digits"
+    );
+    let mut server = TestDag::new();
+    let names: Vec<String> = (0..=1024).map(|i| format!("X0{:04}", i)).collect();
+    let heads: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    server.drawdag(&names.join(" "), &heads);
+    server.drawdag("A", &["A"]);
+    let e = server
+        .dag
+        .resolve_hex_prefix(vec!["A".into()], b"5830".to_vec(), 1)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: hex prefix 5830 is ambiguous: it matches more than 1024 names"
+    );
+}
+
+#[tokio::test]
+async fn test_hex_prefix_lookup_skips_non_ancestors() {
+    let mut server = TestDag::draw("BBY-BBZ-C  # master: C");
+    let mut client = server.client_cloned_data().await;
+    // "BBA" and "BBB" sort before "BBY", but are not known to the client.
+    server.drawdag("C-BBA-BBB", &["BBB"]);
+    client.set_remote(&server);
+
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 1).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("BBY")]);
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 5).await.unwrap();
+    assert_eq!(
+        names,
+        vec![VertexName::from("BBY"), VertexName::from("BBZ")]
+    );
+}
+
 #[tokio::test]
 async fn test_add_heads() {
     let server = TestDag::draw("A-B  # master: B");
@@ -267,6 +381,89 @@ async fn test_pull_overlap() {
     );
 }
 
+#[tokio::test]
+async fn test_pull_disjoint_ranges() {
+    let mut server = TestDag::new();
+    server.drawdag("A-B", &["B"]);
+    let mut client = server.client_cloned_data().await;
+    server.drawdag("B-C-D", &["D"]);
+    server.drawdag("A-E-F-G", &["G"]);
+    client.pull_ff_master(&server, "A", "G").await.unwrap();
+    server.drawdag("G-H-I", &["I"]);
+
+    // Catch up two heads at once. The server ranges C..D and H..I are not
+    // adjacent, because E..G are between them.
+    let mut pull_data = server
+        .dag
+        .pull_fast_forward_master("B".into(), "D".into())
+        .await
+        .unwrap();
+    let other_pull_data = server
+        .dag
+        .pull_fast_forward_master("G".into(), "I".into())
+        .await
+        .unwrap();
+    pull_data
+        .flat_segments
+        .segments
+        .extend(other_pull_data.flat_segments.segments);
+    pull_data.idmap.extend(other_pull_data.idmap);
+    let spans: Vec<(u64, u64)> = (pull_data.flat_segments.segments.iter())
+        .map(|s| (s.low.0, s.high.0))
+        .collect();
+    assert_eq!(spans, [(2, 3), (7, 8)]);
+
+    // Each range needs a name for its head.
+    let mut bad_pull_data = pull_data.clone();
+    bad_pull_data.idmap.remove(&Id(3));
+    let e = client
+        .dag
+        .import_pull_data(bad_pull_data)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: server does not provide name for head 3"
+    );
+
+    // A range cannot be rooted at vertexes pulled after it.
+    let mut bad_pull_data = pull_data.clone();
+    bad_pull_data.flat_segments.segments[0].parents = vec![Id(8)];
+    let e = client
+        .dag
+        .import_pull_data(bad_pull_data)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "ProgrammingError: server returned segment FlatSegment { low: 2, high: 3, parents: [8] } with parent 8 not pulled before it"
+    );
+
+    client.set_remote(&server);
+    client.dag.import_pull_data(pull_data).await.unwrap();
+    assert_eq!(
+        client.render_graph(),
+        "
+            I  8
+            │
+            H  7
+            │
+            │ D  6
+            │ │
+            │ C  5
+            │ │
+            G │  4
+            │ │
+            F │  3
+            │ │
+            E │  2
+            │ │
+            │ B  1
+            ├─╯
+            A  0"
+    );
+}
+
 #[tokio::test]
 async fn test_pull_lazy_with_merges() {
     // Test fast-forward pull on a lazy graph with merges.
@@ -323,6 +520,97 @@ async fn test_pull_lazy_with_merges() {
     assert_eq!(client.output(), ["resolve names: [C, D, F, L], heads: [E]"]);
 }
 
+#[tokio::test]
+async fn test_import_clone_data_incremental() {
+    let mut server = TestDag::new();
+    server.drawdag("A-B-C", &["C"]);
+    let mut client = server.client().await;
+    client.drawdag("A-B", &["B"]);
+
+    server.drawdag("C-D-E B-F-E", &["E"]);
+    client.set_remote(&server);
+    let data = server.dag.export_clone_data().await.unwrap();
+    client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap();
+    assert_eq!(server.render_graph(), client.render_graph());
+
+    // Importing the same data again changes nothing.
+    let data = server.dag.export_clone_data().await.unwrap();
+    client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap();
+    assert_eq!(server.render_graph(), client.render_graph());
+
+    // An empty graph is the same as `import_clone_data`.
+    let mut client = server.client().await;
+    let data = server.dag.export_clone_data().await.unwrap();
+    client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap();
+    assert_eq!(server.render_graph(), client.render_graph());
+}
+
+#[tokio::test]
+async fn test_import_clone_data_incremental_inconsistent() {
+    let server = TestDag::draw("A-B-C-D # master: D");
+
+    // Different name for the same id.
+    let mut client = server.client().await;
+    client.drawdag("A-B-C-X", &["X"]);
+    let data = server.dag.export_clone_data().await.unwrap();
+    let e = client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("the local graph has X"), "{}", e);
+
+    // Different id for the same name.
+    let mut client = server.client().await;
+    client.drawdag("A-B-D", &["D"]);
+    let data = server.dag.export_clone_data().await.unwrap();
+    let e = client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("the local graph has"), "{}", e);
+
+    // Different parents for the same id.
+    let mut client = server.client().await;
+    client.drawdag("A B", &["A", "B"]);
+    let data = server.dag.export_clone_data().await.unwrap();
+    let e = client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("clone data has parents"), "{}", e);
+
+    // The local master group is not in the clone data.
+    let mut client = server.client().await;
+    client.drawdag("A-B-C-D-E", &["E"]);
+    let data = server.dag.export_clone_data().await.unwrap();
+    let e = client
+        .dag
+        .import_clone_data_incremental(data)
+        .await
+        .unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("clone data does not contain local master vertexes"),
+        "{}",
+        e
+    );
+}
+
 #[tokio::test]
 async fn test_pull_no_pending_changes() {
     let mut server = TestDag::draw("A # master: A");
@@ -629,3 +917,345 @@ async fn test_local_cache_missing_vertex_to_id() {
     ));
     assert!(client.output().is_empty());
 }
+
+#[tokio::test]
+async fn test_prefetch() {
+    let client = client_for_local_cache_test().await;
+
+    // Names are resolved in one batch. Duplicated or local names are skipped.
+    let names: Vec<VertexName> = ["B", "C", "Z", "C", "G"]
+        .iter()
+        .map(|s| VertexName::copy_from(s.as_bytes()))
+        .collect();
+    client.dag.prefetch_names(&names).await.unwrap();
+    assert_eq!(client.output(), ["resolve names: [B, C, Z], heads: [G]"]);
+
+    // Later lookups, including missing names, do not need the server.
+    client.dag.prefetch_names(&names).await.unwrap();
+    assert!(client.dag.vertex_id("B".into()).await.is_ok());
+    assert!(client.dag.vertex_id("Z".into()).await.is_err());
+    assert!(client.output().is_empty());
+
+    // Ids are resolved in one batch too.
+    client
+        .dag
+        .prefetch_ids(&[Id(0), Id(3), Id(4), Id(3), Id(6)])
+        .await
+        .unwrap();
+    assert_eq!(client.output(), ["resolve paths: [G~2(+2), G~6]"]);
+    assert_eq!(client.dag.vertex_name(Id(4)).await.unwrap(), "E".into());
+    assert!(client.output().is_empty());
+}
+
+#[tokio::test]
+async fn test_bounded_caches() {
+    let mut client = client_for_local_cache_test().await;
+    client.dag.set_cache_limits(CacheLimits {
+        overlay_map: Some(1),
+        missing_vertexes: Some(1),
+    });
+
+    // Resolving D evicts C from the overlay map.
+    let c_id = client.dag.vertex_id("C".into()).await.unwrap();
+    let d_id = client.dag.vertex_id("D".into()).await.unwrap();
+    assert_eq!(client.dag.vertex_id("D".into()).await.unwrap(), d_id);
+    assert_eq!(
+        client.output(),
+        [
+            "resolve names: [C], heads: [G]",
+            "resolve names: [D], heads: [G]"
+        ]
+    );
+    assert_eq!(client.dag.vertex_id("C".into()).await.unwrap(), c_id);
+    assert_eq!(client.output(), ["resolve names: [C], heads: [G]"]);
+
+    // Confirming Y is missing evicts Z from the negative cache.
+    assert!(client.dag.vertex_id("Z".into()).await.is_err());
+    assert!(client.dag.vertex_id("Y".into()).await.is_err());
+    assert!(client.dag.vertex_id("Y".into()).await.is_err());
+    assert!(client.dag.vertex_id("Z".into()).await.is_err());
+    assert_eq!(
+        client.output(),
+        [
+            "resolve names: [Z], heads: [G]",
+            "resolve names: [Y], heads: [G]",
+            "resolve names: [Z], heads: [G]"
+        ]
+    );
+
+    let stats = client.dag.cache_stats();
+    assert_eq!(stats.overlay_map.len, 1);
+    assert_eq!(stats.overlay_map.evictions, 2);
+    assert_eq!(stats.missing_vertexes.len, 1);
+    assert_eq!(stats.missing_vertexes.evictions, 2);
+    assert_eq!(stats.missing_vertexes.hits, 1);
+}
+
+#[derive(Default)]
+struct RecordingMetrics(Mutex<Vec<String>>);
+
+impl DagMetrics for RecordingMetrics {
+    fn remote_names_to_ids(&self, requested: usize, resolved: usize, _duration: Duration) {
+        let msg = format!(
+            "names to ids: {} requested, {} resolved",
+            requested, resolved
+        );
+        self.0.lock().push(msg);
+    }
+
+    fn remote_ids_to_names(&self, requested: usize, _duration: Duration) {
+        let msg = format!("ids to names: {} requested", requested);
+        self.0.lock().push(msg);
+    }
+
+    fn overlay_map_lookups(&self, hits: usize, misses: usize) {
+        let msg = format!("overlay map: {} hits, {} misses", hits, misses);
+        self.0.lock().push(msg);
+    }
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let mut client = client_for_local_cache_test().await;
+    let metrics = Arc::new(RecordingMetrics::default());
+    client.dag.set_metrics(metrics.clone());
+    let take = || std::mem::take(&mut *metrics.0.lock());
+
+    let c_id = client.dag.vertex_id("C".into()).await.unwrap();
+    assert_eq!(client.dag.vertex_id("C".into()).await.unwrap(), c_id);
+    assert!(client.dag.vertex_id("Z".into()).await.is_err());
+    assert_eq!(
+        take(),
+        [
+            "overlay map: 0 hits, 1 misses",
+            "names to ids: 1 requested, 1 resolved",
+            "overlay map: 1 hits, 0 misses",
+            "overlay map: 0 hits, 1 misses",
+            "names to ids: 1 requested, 0 resolved"
+        ]
+    );
+
+    let ids: Vec<Id> = vec![c_id, c_id + 1, c_id + 2];
+    let names = client.dag.vertex_name_batch(&ids).await.unwrap();
+    assert_eq!(names.len(), 3);
+    assert_eq!(
+        take(),
+        ["overlay map: 1 hits, 2 misses", "ids to names: 2 requested"]
+    );
+}
+
+#[tokio::test]
+async fn test_query_budget() {
+    let server = TestDag::draw("A-BB-CC-D-E  # master: E");
+    let mut client = server.client_cloned_data().await;
+
+    // Without limits, the budget counts the cost of operations.
+    let budget = Arc::new(QueryBudget::new());
+    client.dag.set_query_budget(Some(budget.clone()));
+    let ancestors = client.dag.ancestors("E".into()).await.unwrap();
+    assert_eq!(ancestors.count().await.unwrap(), 5);
+    assert!(budget.segments() > 0);
+    assert_eq!(budget.remote_round_trips(), 0);
+
+    // Segments read over the limit stop the operation.
+    let budget = Arc::new(QueryBudget::new().with_max_segments(0));
+    client.dag.set_query_budget(Some(budget));
+    match client.dag.ancestors("E".into()).await {
+        Err(DagError::BudgetExceeded(e)) => {
+            assert_eq!(e.resource, BudgetResource::Segments);
+            assert_eq!(e.limit, 0);
+        }
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+
+    // So do remote round-trips over the limit.
+    let budget = Arc::new(QueryBudget::new().with_max_remote_round_trips(1));
+    client.dag.set_query_budget(Some(budget.clone()));
+    let names = client.dag.vertexes_by_hex_prefix(b"4242", 1).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("BB")]);
+    match client.dag.vertexes_by_hex_prefix(b"4343", 1).await {
+        Err(DagError::BudgetExceeded(e)) => {
+            assert_eq!(e.resource, BudgetResource::RemoteRoundTrips);
+            assert_eq!(e.limit, 1);
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(client.output(), ["resolve hex prefix: 4242, heads: [E]"]);
+
+    // Operations are unbounded again once the budget is removed.
+    client.dag.set_query_budget(None);
+    let names = client.dag.vertexes_by_hex_prefix(b"4343", 1).await.unwrap();
+    assert_eq!(names, vec![VertexName::from("CC")]);
+}
+
+#[tokio::test]
+async fn test_strip_lazy() {
+    let server = TestDag::draw("A-B-C  # master: C");
+    let mut client = server.client_cloned_data().await;
+    client.drawdag("C-D", &[]);
+    client.dag.flush(&[]).await.unwrap();
+
+    // Non-master vertexes can be removed.
+    let set = NameSet::from_static_names(vec!["D".into()]);
+    client.dag.strip(set).await.unwrap();
+    assert!(client.dag.vertex_id("D".into()).await.is_err());
+
+    // Master vertexes cannot be removed.
+    let set = NameSet::from_static_names(vec!["C".into()]);
+    let err = client.dag.strip(set).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "ProgrammingError: strip cannot remove master vertexes from a lazy graph"
+    );
+}
+
+#[tokio::test]
+async fn test_children_batch() {
+    let server = TestDag::draw("A-B-C-D-E B-F-G # master: E G");
+    let client = server.client_cloned_data().await;
+
+    let children = client
+        .dag
+        .children_batch(&["B".into(), "C".into(), "E".into()])
+        .await
+        .unwrap();
+    assert_eq!(format!("{:?}", children), "[[F, C], [D], []]");
+
+    // One round-trip to resolve the names, one to resolve the children.
+    assert_eq!(
+        client.output(),
+        [
+            "resolve names: [B, C], heads: [G, E]",
+            "resolve paths: [G~1, E~1]"
+        ]
+    );
+}
+
+/// Holds requests until the test releases them, so that they overlap.
+struct GatedProtocol {
+    inner: ProtocolMonitor,
+    gate: Arc<tokio::sync::Semaphore>,
+}
+
+#[async_trait::async_trait]
+impl RemoteIdConvertProtocol for GatedProtocol {
+    async fn resolve_names_to_relative_paths(
+        &self,
+        heads: Vec<VertexName>,
+        names: Vec<VertexName>,
+        request_id: Option<u64>,
+    ) -> crate::Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        let _permit = self.gate.acquire().await.unwrap();
+        self.inner
+            .resolve_names_to_relative_paths(heads, names, request_id)
+            .await
+    }
+
+    async fn resolve_relative_paths_to_names(
+        &self,
+        paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
+    ) -> crate::Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        self.inner
+            .resolve_relative_paths_to_names(paths, request_id)
+            .await
+    }
+}
+
+#[tokio::test]
+async fn test_coalescing_protocol() {
+    let server = TestDag::draw("A-B-C-D-E  # master: E");
+    let output: Arc<Mutex<Vec<String>>> = Default::default();
+    let gate = Arc::new(tokio::sync::Semaphore::new(0));
+    let protocol = CoalescingProtocol::new(GatedProtocol {
+        inner: ProtocolMonitor {
+            inner: Box::new(server.dag.try_snapshot().unwrap()),
+            output: output.clone(),
+        },
+        gate: gate.clone(),
+    });
+
+    let resolve = |names: &str| {
+        let names = names
+            .split_whitespace()
+            .map(|n| VertexName::copy_from(n.as_bytes()))
+            .collect();
+        protocol.resolve_names_to_relative_paths(vec!["E".into()], names, None)
+    };
+    let (r1, r2, r3, ()) = futures::join!(resolve("B C"), resolve("C D"), resolve("C B"), async {
+        tokio::task::yield_now().await;
+        gate.add_permits(2);
+    });
+
+    assert_eq!(format!("{:?}", r1.unwrap()), "[(E~3, [B]), (E~2, [C])]");
+    assert_eq!(format!("{:?}", r2.unwrap()), "[(E~2, [C]), (E~1, [D])]");
+    assert_eq!(format!("{:?}", r3.unwrap()), "[(E~3, [B]), (E~2, [C])]");
+
+    // "C" is only resolved once. The third request is served by the first.
+    assert_eq!(
+        std::mem::take(&mut *output.lock()),
+        [
+            "resolve names: [B, C], heads: [E]",
+            "resolve names: [D], heads: [E]"
+        ]
+    );
+
+    // Once the requests complete, the names are resolved again.
+    gate.add_permits(1);
+    resolve("C").await.unwrap();
+    assert_eq!(
+        std::mem::take(&mut *output.lock()),
+        ["resolve names: [C], heads: [E]"]
+    );
+}
+
+/// Records the request ids that reach the server.
+struct RequestIdRecorder {
+    inner: Box<dyn RemoteIdConvertProtocol>,
+    request_ids: Arc<Mutex<Vec<Option<u64>>>>,
+}
+
+#[async_trait::async_trait]
+impl RemoteIdConvertProtocol for RequestIdRecorder {
+    async fn resolve_names_to_relative_paths(
+        &self,
+        heads: Vec<VertexName>,
+        names: Vec<VertexName>,
+        request_id: Option<u64>,
+    ) -> crate::Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        self.request_ids.lock().push(request_id);
+        self.inner
+            .resolve_names_to_relative_paths(heads, names, request_id)
+            .await
+    }
+
+    async fn resolve_relative_paths_to_names(
+        &self,
+        paths: Vec<AncestorPath>,
+        request_id: Option<u64>,
+    ) -> crate::Result<Vec<(AncestorPath, Vec<VertexName>)>> {
+        self.request_ids.lock().push(request_id);
+        self.inner
+            .resolve_relative_paths_to_names(paths, request_id)
+            .await
+    }
+}
+
+#[tokio::test]
+async fn test_request_id_reaches_server() {
+    let server = TestDag::draw("A-B-C-D-E  # master: E");
+    let mut client = server.client_cloned_data().await;
+    let request_ids: Arc<Mutex<Vec<Option<u64>>>> = Default::default();
+    client.dag.set_remote_protocol(Arc::new(RequestIdRecorder {
+        inner: Box::new(server.dag.try_snapshot().unwrap()),
+        request_ids: request_ids.clone(),
+    }));
+
+    client.dag.vertex_id("C".into()).await.unwrap();
+    client.dag.vertex_name(Id(1)).await.unwrap();
+
+    let request_ids = std::mem::take(&mut *request_ids.lock());
+    assert_eq!(request_ids.len(), 2);
+    assert!(request_ids.iter().all(|id| id.is_some()));
+    assert_ne!(request_ids[0], request_ids[1]);
+}
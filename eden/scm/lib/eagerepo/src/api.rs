@@ -276,7 +276,7 @@ impl EdenApi for EagerRepo {
                 })
                 .collect();
             self.dag()
-                .resolve_relative_paths_to_names(paths)
+                .resolve_relative_paths_to_names(paths, None)
                 .await
                 .map_err(map_dag_err)?
         };
@@ -322,7 +322,7 @@ impl EdenApi for EagerRepo {
                 .map(|i| Vertex::copy_from(i.as_ref()))
                 .collect();
             self.dag()
-                .resolve_names_to_relative_paths(heads, names)
+                .resolve_names_to_relative_paths(heads, names, None)
                 .await
                 .map_err(map_dag_err)?
         };
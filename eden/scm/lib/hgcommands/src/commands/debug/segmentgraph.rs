@@ -21,7 +21,7 @@ define_flags! {
         #[short('l')]
         level: i64 = 0,
 
-        /// segment group (master|non_master)
+        /// segment group (master|non_master|hidden)
         #[short('g')]
         group: String = "master",
     }
@@ -31,6 +31,7 @@ pub fn run(opts: GraphOpts, io: &IO, repo: Repo) -> Result<u8> {
     let group = match opts.group.as_ref() {
         "master" => dag::Group::MASTER,
         "non_master" => dag::Group::NON_MASTER,
+        "hidden" => dag::Group::HIDDEN,
         _ => return Err(errors::Abort("invalid group".into()).into()),
     };
 
@@ -96,6 +96,9 @@ impl RemoteIdConvertProtocol for EdenApiProtocol {
         &self,
         heads: Vec<Vertex>,
         names: Vec<Vertex>,
+        // Already recorded on the caller's tracing span. EdenAPI requests
+        // have their own correlation ids.
+        _request_id: Option<u64>,
     ) -> dag::Result<Vec<(AncestorPath, Vec<Vertex>)>> {
         let mut pairs = Vec::with_capacity(names.len());
         let response_vec = {
@@ -151,6 +154,7 @@ impl RemoteIdConvertProtocol for EdenApiProtocol {
     async fn resolve_relative_paths_to_names(
         &self,
         paths: Vec<AncestorPath>,
+        _request_id: Option<u64>,
     ) -> dag::Result<Vec<(AncestorPath, Vec<Vertex>)>> {
         if let Some(threshold) = self.remote_id_threshold {
             let current = self.remote_id_current.fetch_add(1, SeqCst);
@@ -66,6 +66,20 @@ impl ScopedDirLock {
         Ok(result)
     }
 
+    /// Lock the given directory if no one else has it locked. Returns `None`
+    /// instead of waiting for the lock.
+    pub fn try_new(path: &Path) -> crate::Result<Option<Self>> {
+        let file = crate::utils::open_dir(path).context(path, "cannot open for locking")?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(Self {
+                file,
+                path: path.to_path_buf(),
+            })),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(None),
+            Err(e) => Err(e).context(path, "cannot lock"),
+        }
+    }
+
     /// Get the path to the directory being locked.
     pub fn path(&self) -> &Path {
         &self.path
@@ -148,6 +162,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dir_try_lock() {
+        let dir = tempdir().unwrap();
+        let lock = ScopedDirLock::try_new(dir.path()).unwrap();
+        assert!(lock.is_some());
+        assert!(ScopedDirLock::try_new(dir.path()).unwrap().is_none());
+        drop(lock);
+        assert!(ScopedDirLock::try_new(dir.path()).unwrap().is_some());
+    }
+
     #[test]
     fn test_dir_lock() {
         let dir = tempdir().unwrap();
@@ -325,6 +325,17 @@ impl OpenOptions {
         self
     }
 
+    /// Never write lagging indexes back to disk at open time.
+    ///
+    /// Lagging indexes are still built in memory. This avoids taking the
+    /// directory lock in [`OpenOptions::open`].
+    pub(crate) fn with_unlimited_index_lag(mut self) -> Self {
+        for def in self.index_defs.iter_mut() {
+            def.lag_threshold = u64::MAX;
+        }
+        self
+    }
+
     /// Construct [`Log`] at given directory. Incrementally build up specified
     /// indexes.
     ///
@@ -39,6 +39,9 @@ pub struct OpenOptions {
     /// true: use "multimeta" file; false: use "multimeta_log" Log.
     /// For testing purpose only.
     leacy_multimeta_source: bool,
+
+    /// Whether to open existing Logs without taking the directory lock.
+    read_only: bool,
 }
 
 /// A [`MultiLog`] contains multiple [`Log`]s with a centric metadata file.
@@ -107,9 +110,20 @@ impl OpenOptions {
         Self {
             name_open_options: name_opts,
             leacy_multimeta_source: false,
+            read_only: false,
         }
     }
 
+    /// Open existing [`Log`]s without taking the directory lock.
+    ///
+    /// If set to `true`, [`OpenOptions::open`] fails if some [`Log`]s do not
+    /// exist, instead of creating them. Lagging indexes are built in memory,
+    /// and are not written back to disk.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
     /// Open [`MultiLog`] at the given directory.
     ///
     /// This ignores the `create` option per [`Log`]. [`Log`] and their metadata
@@ -120,7 +134,14 @@ impl OpenOptions {
             // logs.
             let meta_log_path = multi_meta_log_path(&path);
             let meta_path = multi_meta_path(path);
-            let mut multimeta_log = multi_meta_log_open_options().open(&meta_log_path)?;
+            let mut multimeta_log = if self.read_only {
+                multi_meta_log_open_options()
+                    .create(false)
+                    .with_unlimited_index_lag()
+                    .open(&meta_log_path)?
+            } else {
+                multi_meta_log_open_options().open(&meta_log_path)?
+            };
             let multimeta_log_is_empty = multimeta_log.iter().next().is_none();
 
             // Read meltimeta from the multimeta log.
@@ -143,6 +164,11 @@ impl OpenOptions {
             {
                 // Not using legacy format. All keys exist. No need to write files on disk.
                 None
+            } else if self.read_only {
+                return Err(crate::Error::path(
+                    path,
+                    "cannot open incomplete MultiLog in read-only mode",
+                ));
             } else {
                 // Need to create some Logs and rewrite the multimeta.
                 utils::mkdir_p(path)?;
@@ -164,7 +190,11 @@ impl OpenOptions {
                     path: Box::new(fspath.as_path().into()),
                     meta: multimeta.metas[name_ref].clone(),
                 };
-                let log = opts.open(path)?;
+                let log = if self.read_only {
+                    opts.clone().with_unlimited_index_lag().open(path)?
+                } else {
+                    opts.open(path)?
+                };
                 logs.push(log);
             }
 
@@ -205,6 +235,20 @@ impl MultiLog {
         result.context("in MultiLog::lock")
     }
 
+    /// Like [`MultiLog::lock`], but returns `None` instead of waiting if the
+    /// directory is locked by someone else.
+    pub fn try_lock(&mut self) -> crate::Result<Option<LockGuard>> {
+        let result: crate::Result<_> = (|| {
+            let lock = match ScopedDirLock::try_new(&self.path)? {
+                Some(lock) => LockGuard(lock),
+                None => return Ok(None),
+            };
+            self.read_meta(&lock)?;
+            Ok(Some(lock))
+        })();
+        result.context("in MultiLog::try_lock")
+    }
+
     /// Write meta to disk so they become visible to other processes.
     ///
     /// A lock must be provided to prove that there is no race condition.
@@ -790,6 +834,31 @@ mod tests {
         assert!(mlog2.write_meta(&lock1).is_err());
     }
 
+    #[test]
+    fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let ro_opts = simple_open_opts().read_only(true);
+
+        // Missing MultiLog is not created.
+        assert!(ro_opts.open(path).is_err());
+        assert!(!multi_meta_log_path(path).exists());
+
+        let mut mlog = simple_multilog(path);
+        mlog[0].append(b"1").unwrap();
+        mlog.sync().unwrap();
+
+        // Opening does not wait for the lock held by the writer.
+        let _lock = mlog.lock().unwrap();
+        let ro_mlog = ro_opts.open(path).unwrap();
+        assert_eq!(ro_mlog[0].iter().count(), 1);
+
+        // Missing Logs are not created.
+        let ro_opts = OpenOptions::from_name_opts(vec![("c", log::OpenOptions::new())]);
+        assert!(ro_opts.read_only(true).open(path).is_err());
+        assert!(!path.join("c").exists());
+    }
+
     #[test]
     fn test_repair() {
         let dir = tempfile::tempdir().unwrap();